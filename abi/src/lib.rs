@@ -0,0 +1,127 @@
+//! The interface between compiled programs and the runtime modules alloc,
+//! dalloc and shadow: the modules they import from, and the words of memory
+//! both sides read and write. The compiler and each runtime crate build
+//! against this crate, so a layout can't change on one side only.
+//!
+//! Any change here changes [`VERSION`]. A program hands the version it was
+//! compiled against to each runtime's init, which traps when it was built
+//! against one it can't serve, so a stale runtime fails at startup instead
+//! of corrupting memory.
+
+#![no_std]
+
+/// Bumped when programs compiled before can't run against the runtime, such
+/// as when a word moves or an export changes its signature.
+pub const MAJOR: u32 = 1;
+/// Bumped when the runtime adds something, such as an export, that programs
+/// compiled before don't use.
+pub const MINOR: u32 = 10;
+/// `MAJOR` in the high half and `MINOR` in the low half, as inits take it.
+pub const VERSION: u32 = MAJOR << 16 | MINOR;
+
+/// Whether a runtime built against this crate can run a program compiled
+/// against `version`: the same major version, and a minor one no newer.
+pub const fn compatible(version: u32) -> bool {
+    version >> 16 == MAJOR && version <= VERSION
+}
+
+/// The import modules of the runtime, in the order they're instantiated,
+/// each of which may import those before it.
+pub const ALLOC: &str = "alloc";
+pub const DALLOC: &str = "dalloc";
+pub const SHADOW: &str = "shadow";
+pub const RUNTIME_MODULES: [&str; 3] = [ALLOC, DALLOC, SHADOW];
+
+/// Alloc memory, where structs live.
+pub mod alloc {
+    /// Address of the type table `init` sets aside, with one record per
+    /// struct type: its size, the head of its free list, how many of its
+    /// fields point at structs and at lists, and its queue of structs whose
+    /// drop method is due.
+    pub const TYPE_TABLE: u32 = 24;
+    pub const TYPE_RECORD_SIZE: u32 = 20;
+}
+
+/// Block types passed to `dalloc`, telling the collector what elements hold.
+pub mod dtype {
+    /// u64 values that are never traced.
+    pub const PRIMITIVE: u32 = 1;
+    /// Pointers into alloc memory.
+    pub const STRUCTS: u32 = 2;
+    /// Pointers to other dalloc blocks.
+    pub const LISTS: u32 = 3;
+    /// One byte per element, as strings hold.
+    pub const BYTES: u32 = 4;
+}
+
+/// The header in front of a dalloc block's elements: its mark, a word
+/// holding its size and type, and its length, which programs read for `#`.
+/// Offsets count back from the pointer to the elements.
+pub mod block {
+    pub const HEADER: u32 = 12;
+    /// Unmarked, marked, pinned, or where compaction is moving the block.
+    pub const MARK: u32 = 12;
+    pub const INFO: u32 = 8;
+    pub const LENGTH: u32 = 4;
+    /// Where the info word keeps the block's type, above its size in bytes.
+    /// Its lowest two bits are left to dalloc.
+    pub const TYPE_SHIFT: u32 = 28;
+    pub const TYPE_MASK: u32 = 7 << TYPE_SHIFT;
+    pub const SIZE_MASK: u32 = (1 << TYPE_SHIFT) - 4;
+    /// Set in the info word while the collector rewrites pointers, once it
+    /// has reached the block.
+    pub const VISITED: u32 = 1 << 31;
+}
+
+/// How dalloc picks among the free blocks that fit a request, as passed to
+/// its `dpolicy`.
+pub mod policy {
+    /// The first block on the free list for the request's size.
+    pub const FIRST_FIT: u32 = 0;
+    /// The smallest block that fits, leaving the biggest ones whole.
+    pub const BEST_FIT: u32 = 1;
+    /// The first block that fits walking the heap from where the last
+    /// request was placed.
+    pub const NEXT_FIT: u32 = 2;
+}
+
+/// The struct behind nullable and errorable values, and the tags saying
+/// what its value slot holds.
+pub mod tag {
+    /// Type id of the tagged union.
+    pub const TAGGED_UNION: u32 = 0;
+    pub const NULL: u32 = 0;
+    pub const ERROR: u32 = 1;
+    pub const PRIMITIVE: u32 = 2;
+    pub const STRUCT: u32 = 3;
+    pub const LIST: u32 = 4;
+}
+
+/// Shadow memory, holding the roots the collector starts from.
+pub mod shadow {
+    /// Words generated code parks operands in while it collects and
+    /// retries. They may hold dalloc pointers, or plain numbers that merely
+    /// look like one.
+    pub const SCRATCHPAD: [u32; 3] = [4, 8, 12];
+    pub const STACK_POINTER: u32 = 16;
+    /// Word holding the address of the current frame.
+    pub const FRAME_POINTER: u32 = 20;
+    /// Collections run since `init`.
+    pub const GC_COUNT: u32 = 24;
+    /// Bytes either heap may hold before a collection, set by
+    /// `set_gc_threshold`. 0 leaves collections to when a heap is full.
+    pub const GC_THRESHOLD: u32 = 28;
+}
+
+/// The heap snapshot the shadow module's `heap_snapshot` writes: a word
+/// holding the number of objects, then a record of three words for each,
+/// its type, the bytes it takes with its header, and its flags. A struct's
+/// type is its type id, and a list or string's one of `dtype`.
+pub mod snapshot {
+    pub const RECORD_SIZE: u32 = 12;
+    /// Set when the object is reachable from the roots, or pinned. The rest
+    /// are garbage the next collection frees.
+    pub const MARKED: u32 = 1;
+    /// Set when the object is a dalloc block rather than a struct.
+    pub const DALLOC: u32 = 2;
+}
@@ -11,22 +11,26 @@ fn main() -> Result<()> {
     let instance = Instance::new(&mut store, &module, &[])?;
 
     // Get exported functions
-    let init = instance.get_typed_func::<(), ()>(&mut store, "init")?;
-    let register = instance.get_typed_func::<u32, ()>(&mut store, "register")?;
+    let init = instance.get_typed_func::<(u32, u32), ()>(&mut store, "init")?;
+    let register = instance.get_typed_func::<(u32, u32, u32, u32), ()>(&mut store, "register")?;
     let falloc = instance.get_typed_func::<u32, u32>(&mut store, "falloc")?;
 
-    // Initialize allocator
-    init.call(&mut store, ())?;
-    println!("init() done");
+    // Initialize allocator with room for three types
+    init.call(&mut store, (3, abi::VERSION))?;
+    println!("init(3) done");
 
     // Register type 0 with size 16 (e.g., a struct with 4 i32 fields)
-    register.call(&mut store, 16)?;
+    register.call(&mut store, (0, 16, 0, 0))?;
     println!("register(16) done - type 0");
 
     // Register type 1 with size 8 (e.g., a struct with 2 i32 fields)
-    register.call(&mut store, 8)?;
+    register.call(&mut store, (1, 8, 0, 0))?;
     println!("register(8) done - type 1");
 
+    // Register type 2 with size 6000, too large for more than one per slab
+    register.call(&mut store, (2, 6000, 0, 0))?;
+    println!("register(6000) done - type 2");
+
     // Allocate some objects
     let ptr1 = falloc.call(&mut store, 0)?;
     println!("falloc(0) = {} (type 0, first alloc)", ptr1);
@@ -58,5 +62,13 @@ fn main() -> Result<()> {
     let ptr_new_slab = falloc.call(&mut store, 0)?;
     println!("falloc(0) = {} (33rd allocation, new slab!)", ptr_new_slab);
 
+    // Each large object gets a slab of its own, behind an 8-byte slab header
+    let large1 = falloc.call(&mut store, 2)?;
+    let large2 = falloc.call(&mut store, 2)?;
+    println!("\nExpected:");
+    println!("  large2 - large1 = 6016 (slab header + block header + data)");
+    println!("Actual:");
+    println!("  large2 - large1 = {}", large2 - large1);
+
     Ok(())
 }
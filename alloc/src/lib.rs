@@ -1,10 +1,39 @@
 #![no_std]
 
-const TYPE_TABLE_INDEX: u32 = 12;
-const TYPE_TABLE_RECORD_SIZE: u32 = 16;
+const TYPE_TABLE_INDEX: u32 = abi::alloc::TYPE_TABLE;
+const TYPE_TABLE_RECORD_SIZE: u32 = abi::alloc::TYPE_RECORD_SIZE;
 const HEADER_SIZE: u32 = 8;
+/// Bytes before the blocks of every slab: the type id of its structs and
+/// how many blocks it holds.
+const SLAB_HEADER_SIZE: u32 = 8;
+/// Most blocks a slab holds.
+const SLAB_BLOCKS: u32 = 32;
+/// Most bytes of blocks a slab takes, unless a single block is larger.
+const SLAB_BYTES: u32 = 4096;
 const BUMP_PTR_ADDR: u32 = 8;
 const DATA_START_ADDR: u32 = 4;
+/// Set by `sweep` and cleared by a successful `falloc`, so memory only grows
+/// once a collection has failed to free a block of the right type.
+const COLLECTED_ADDR: u32 = 12;
+/// Bytes handed out by `falloc` since the last `sweep`, headers included.
+const ALLOCATED_ADDR: u32 = 16;
+/// Bytes `falloc` may hand out after a sweep before it asks for another
+/// collection, see `alloc_budget`. 0 means no limit.
+const BUDGET_ADDR: u32 = 20;
+const PAGE_SIZE: u32 = 65536;
+/// Where a type's record keeps its queue of structs whose drop method is
+/// due: 0 when the type has none, `QUEUE_END` while none is due, and
+/// otherwise the first struct.
+const QUEUE_OFFSET: u32 = 16;
+/// Ends a queue, both as its head and as the link of its last struct.
+const QUEUE_END: u32 = 1;
+/// The last word of a struct with a drop method that `enqueue` hasn't
+/// found unreachable yet. Queued structs hold the link to the next one
+/// there instead, and those whose drop has run, like free blocks, hold 0.
+const UNDROPPED: u32 = 2;
+/// Mark of a block on its type's free list, so walking a slab tells the
+/// blocks handed out from the rest. Marking only ever sets 1.
+const FREED: u32 = 2;
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -13,7 +42,7 @@ fn panic(_: &core::panic::PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn alloc_memory_size() -> u32 {
-    (core::arch::wasm32::memory_size(0) as u32) * 65336
+    (core::arch::wasm32::memory_size(0) as u32) * PAGE_SIZE
 }
 
 unsafe fn read_u32(addr: u32) -> u32 {
@@ -34,27 +63,110 @@ pub extern "C" fn write_alloc(addr: u32, val: u32) {
     unsafe { write_u32(addr, val) }
 }
 
+/// Sets aside a type table of `type_count` records, with the heap starting
+/// right after it. Traps when the program was compiled against an ABI
+/// `version` this build can't serve.
 #[no_mangle]
-pub extern "C" fn init() {
+pub extern "C" fn init(type_count: u32, version: u32) {
+    if !abi::compatible(version) {
+        core::arch::wasm32::unreachable()
+    }
+    unsafe {
+        let data_start = TYPE_TABLE_INDEX + type_count * TYPE_TABLE_RECORD_SIZE;
+        write_u32(BUMP_PTR_ADDR, data_start);
+        write_u32(DATA_START_ADDR, data_start);
+        write_u32(COLLECTED_ADDR, 0);
+        write_u32(ALLOCATED_ADDR, 0);
+        write_u32(BUDGET_ADDR, 0);
+    }
+}
+
+/// Limits how many bytes `falloc` hands out after a sweep before it returns
+/// 0 to ask for a collection, as it does when the heap is full, with 0
+/// lifting the limit. The collector sets this to keep the heap under the
+/// threshold given to `set_gc_threshold`.
+#[no_mangle]
+pub extern "C" fn alloc_budget(bytes: u32) {
+    unsafe { write_u32(BUDGET_ADDR, bytes) }
+}
+
+/// Whether handing out `bytes` more would go over the budget, before a
+/// collection has had the chance to free some.
+unsafe fn over_budget(bytes: u32) -> bool {
+    let budget = read_u32(BUDGET_ADDR);
+    budget != 0
+        && read_u32(COLLECTED_ADDR) == 0
+        && read_u32(ALLOCATED_ADDR) + bytes > budget
+}
+
+/// Fills in the record of type `id`, which must be below the count `init`
+/// was given. A free block keeps the link to the next one where its first
+/// field goes, so even a struct without fields gets room for it.
+#[no_mangle]
+pub extern "C" fn register(id: u32, size: u32, struct_count: u32, list_count: u32) {
     unsafe {
-        write_u32(BUMP_PTR_ADDR, TYPE_TABLE_INDEX);
+        let record = TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE;
+        write_u32(record, if size < 8 { 8 } else { size });
+        write_u32(record + 4, 0);
+        write_u32(record + 8, struct_count);
+        write_u32(record + 12, list_count);
+        write_u32(record + QUEUE_OFFSET, 0);
     }
 }
 
+/// Gives type `id` a drop method, to be called through `dequeue` on each of
+/// its structs that a collection finds unreachable before it's freed. The
+/// structs get a trailing slot to keep track, so this must come before the
+/// first `falloc` of the type.
 #[no_mangle]
-pub extern "C" fn register(size: u32, struct_count: u32, list_count: u32) {
+pub extern "C" fn finalizable(id: u32) {
     unsafe {
-        let bump = read_u32(BUMP_PTR_ADDR);
-        write_u32(BUMP_PTR_ADDR, bump + TYPE_TABLE_RECORD_SIZE);
-        write_u32(DATA_START_ADDR, bump + TYPE_TABLE_RECORD_SIZE);
+        let record = TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE;
+        write_u32(record, read_u32(record) + 8);
+        write_u32(record + QUEUE_OFFSET, QUEUE_END);
+    }
+}
+
+/// The address of the word tracking the drop of the struct at `pointer`,
+/// whose type has a drop method.
+unsafe fn drop_word(pointer: u32) -> u32 {
+    let id = read_u32(pointer - HEADER_SIZE);
+    pointer + read_u32(TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE) - 4
+}
+
+/// A run of blocks of one type, carved out of memory at once.
+struct Slab {
+    id: u32,
+    /// Address of the first block, right after the slab's header.
+    blocks: u32,
+    block_size: u32,
+    count: u32,
+}
+
+impl Slab {
+    /// Reads the header of the slab at `addr`.
+    unsafe fn at(addr: u32) -> Slab {
+        let id = read_u32(addr);
+        Slab {
+            id,
+            blocks: addr + SLAB_HEADER_SIZE,
+            block_size: HEADER_SIZE + read_u32(TYPE_TABLE_INDEX + (id * TYPE_TABLE_RECORD_SIZE)),
+            count: read_u32(addr + 4),
+        }
+    }
 
-        write_u32(bump, size);
-        write_u32(bump + 4, 0);
-        write_u32(bump + 8, struct_count);
-        write_u32(bump + 12, list_count);
+    /// Address of the next slab, or of the bump pointer after the last.
+    fn end(&self) -> u32 {
+        self.blocks + self.count * self.block_size
     }
 }
 
+/// Blocks of `block_size` bytes in each new slab: as many as fit in
+/// `SLAB_BYTES`, up to `SLAB_BLOCKS`, and at least one.
+fn slab_blocks(block_size: u32) -> u32 {
+    (SLAB_BYTES / block_size).clamp(1, SLAB_BLOCKS)
+}
+
 #[no_mangle]
 pub extern "C" fn falloc(id: u32) -> u32 {
     unsafe {
@@ -62,33 +174,58 @@ pub extern "C" fn falloc(id: u32) -> u32 {
         let size: u32 = read_u32(start);
         let mut free: u32 = read_u32(start + 4);
 
+        if over_budget(HEADER_SIZE + size) {
+            return 0;
+        }
         if free == 0 {
             let bump = read_u32(BUMP_PTR_ADDR);
 
             let block_size = HEADER_SIZE + size;
-            let slab_size = 32 * block_size;
+            let count = slab_blocks(block_size);
+            let slab_size = SLAB_HEADER_SIZE + count * block_size;
 
             if bump + slab_size > alloc_memory_size() {
-                return 0;
+                if read_u32(COLLECTED_ADDR) == 0 {
+                    return 0;
+                }
+                let needed = bump + slab_size - alloc_memory_size();
+                let pages = (needed + PAGE_SIZE - 1) / PAGE_SIZE;
+                if core::arch::wasm32::memory_grow(0, pages as usize) == usize::MAX {
+                    return 0;
+                }
             }
 
             write_u32(BUMP_PTR_ADDR, bump + slab_size);
+            write_u32(bump, id);
+            write_u32(bump + 4, count);
 
-            for i in 0..31 {
-                let addr = bump + (i * block_size);
+            let blocks = bump + SLAB_HEADER_SIZE;
+            for i in 0..count {
+                let addr = blocks + (i * block_size);
+                let next = if i + 1 < count { addr + block_size } else { 0 };
                 write_u32(addr, id);
-                write_u32(addr + HEADER_SIZE, addr + block_size);
+                write_u32(addr + 4, FREED);
+                write_u32(addr + HEADER_SIZE, next);
             }
 
-            let addr = bump + (31 * block_size);
-            write_u32(addr, id);
-            write_u32(addr + HEADER_SIZE, 0);
-
-            free = bump;
+            free = blocks;
         }
 
         let next: u32 = read_u32(free + HEADER_SIZE);
         write_u32(start + 4, next);
+        write_u32(free + 4, 0);
+        write_u32(COLLECTED_ADDR, 0);
+        write_u32(ALLOCATED_ADDR, read_u32(ALLOCATED_ADDR) + HEADER_SIZE + size);
+
+        // The collector can see a struct before all of its fields are
+        // written, so clear out whatever the last occupant left behind.
+        for i in 0..(size / 4) {
+            write_u32(free + HEADER_SIZE + (i * 4), 0);
+        }
+        if read_u32(start + QUEUE_OFFSET) != 0 {
+            write_u32(drop_word(free + HEADER_SIZE), UNDROPPED);
+        }
+
         free + HEADER_SIZE
     }
 }
@@ -102,6 +239,7 @@ pub extern "C" fn ffree(pointer: u32) -> u32 {
         let start: u32 = TYPE_TABLE_INDEX + (id * TYPE_TABLE_RECORD_SIZE);
         let free: u32 = read_u32(start + 4);
 
+        write_u32(addr + 4, FREED);
         write_u32(addr + HEADER_SIZE, free);
         write_u32(start + 4, addr);
 
@@ -123,11 +261,10 @@ pub extern "C" fn sweep() -> u32 {
         let bump_ptr = read_u32(BUMP_PTR_ADDR);
 
         while current_addr < bump_ptr {
-            let ty = read_u32(current_addr);
-            let current_size = read_u32(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE));
+            let slab = Slab::at(current_addr);
 
-            for i in 0..32 {
-                let block_addr = current_addr + (i * (HEADER_SIZE + current_size));
+            for i in 0..slab.count {
+                let block_addr = slab.blocks + (i * slab.block_size);
                 let is_marked = read_u32(block_addr + 4);
 
                 if is_marked == 1 {
@@ -137,9 +274,200 @@ pub extern "C" fn sweep() -> u32 {
                 }
             }
 
-            current_addr += 32 * (HEADER_SIZE + current_size);
+            current_addr = slab.end();
+        }
+
+        write_u32(COLLECTED_ADDR, 1);
+        write_u32(ALLOCATED_ADDR, 0);
+
+        0
+    }
+}
+
+/// Queues every struct with a drop method still to run that the collector
+/// left unmarked, instead of letting `sweep` free it, and returns how many
+/// it queued. Queued structs are roots until `dequeue` hands them out, so
+/// the collector marks them and everything they reach before sweeping.
+#[no_mangle]
+pub extern "C" fn enqueue() -> u32 {
+    unsafe {
+        let mut queued = 0;
+        let mut current_addr = read_u32(DATA_START_ADDR);
+        let bump_ptr = read_u32(BUMP_PTR_ADDR);
+
+        while current_addr < bump_ptr {
+            let slab = Slab::at(current_addr);
+            let queue = TYPE_TABLE_INDEX + slab.id * TYPE_TABLE_RECORD_SIZE + QUEUE_OFFSET;
+
+            if read_u32(queue) != 0 {
+                for i in 0..slab.count {
+                    let block_addr = slab.blocks + (i * slab.block_size);
+                    let pointer = block_addr + HEADER_SIZE;
+                    let word = drop_word(pointer);
+                    if read_u32(block_addr + 4) != 1 && read_u32(word) == UNDROPPED {
+                        write_u32(word, read_u32(queue));
+                        write_u32(queue, pointer);
+                        queued += 1;
+                    }
+                }
+            }
+
+            current_addr = slab.end();
+        }
+
+        queued
+    }
+}
+
+/// Takes the next struct of type `id` whose drop method is due off its
+/// queue, or returns 0 when there is none. Once handed out, a struct is
+/// freed like any other when nothing refers to it, without being queued
+/// again.
+#[no_mangle]
+pub extern "C" fn dequeue(id: u32) -> u32 {
+    unsafe {
+        let queue = TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE + QUEUE_OFFSET;
+        let pointer = read_u32(queue);
+        if pointer <= QUEUE_END {
+            return 0;
+        }
+        let word = drop_word(pointer);
+        write_u32(queue, read_u32(word));
+        write_u32(word, 0);
+        pointer
+    }
+}
+
+/// The queued struct after the one at `pointer`, across the queues of every
+/// type, starting from the first when `pointer` is 0. Returns 0 after the
+/// last, so the collector can treat each as a root.
+#[no_mangle]
+pub extern "C" fn queued_after(pointer: u32) -> u32 {
+    unsafe {
+        let data_start = read_u32(DATA_START_ADDR);
+        let num_types = (data_start - TYPE_TABLE_INDEX) / TYPE_TABLE_RECORD_SIZE;
+        // The type whose queue to look at next.
+        let (mut id, mut next) = match pointer {
+            0 => (0, QUEUE_END),
+            _ => (read_u32(pointer - HEADER_SIZE) + 1, read_u32(drop_word(pointer))),
+        };
+        while next <= QUEUE_END {
+            if id >= num_types {
+                return 0;
+            }
+            next = read_u32(TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE + QUEUE_OFFSET);
+            id += 1;
+        }
+        next
+    }
+}
+
+/// The struct after the one at `pointer` that hasn't been freed, in the
+/// order the slabs were carved out, starting from the first when `pointer`
+/// is 0. Returns 0 after the last, so the host can walk the heap.
+#[no_mangle]
+pub extern "C" fn alloc_next(pointer: u32) -> u32 {
+    unsafe {
+        let mut current_addr = read_u32(DATA_START_ADDR);
+        let bump_ptr = read_u32(BUMP_PTR_ADDR);
+
+        while current_addr < bump_ptr {
+            let slab = Slab::at(current_addr);
+            if pointer < slab.end() {
+                let first = if pointer > slab.blocks {
+                    (pointer - slab.blocks) / slab.block_size + 1
+                } else {
+                    0
+                };
+                for i in first..slab.count {
+                    let block_addr = slab.blocks + (i * slab.block_size);
+                    if read_u32(block_addr + 4) != FREED {
+                        return block_addr + HEADER_SIZE;
+                    }
+                }
+            }
+            current_addr = slab.end();
         }
 
         0
     }
 }
+
+/// Traps unless the `size` bytes at `offset` past `pointer` lie inside a
+/// struct that hasn't been freed. Programs built with `--sanitize=memory`
+/// call this before every access to this memory, and carry on with the
+/// `pointer` it returns.
+#[no_mangle]
+pub extern "C" fn fcheck(pointer: u32, offset: u32, size: u32) -> u32 {
+    unsafe {
+        let start = pointer.wrapping_add(offset);
+        let mut current_addr = read_u32(DATA_START_ADDR);
+        let bump_ptr = read_u32(BUMP_PTR_ADDR);
+
+        while start >= current_addr && current_addr < bump_ptr {
+            let slab = Slab::at(current_addr);
+            if start < slab.end() {
+                if start < slab.blocks {
+                    break;
+                }
+                let block_size = slab.block_size;
+                let block = slab.blocks + (start - slab.blocks) / block_size * block_size;
+                let inside = start >= block + HEADER_SIZE && start + size <= block + block_size;
+                if inside && !is_free(slab.id, block) {
+                    return pointer;
+                }
+                break;
+            }
+            current_addr = slab.end();
+        }
+
+        core::arch::wasm32::unreachable()
+    }
+}
+
+/// Whether the block at `addr` is on the free list of type `id`.
+unsafe fn is_free(id: u32, addr: u32) -> bool {
+    let mut free = read_u32(TYPE_TABLE_INDEX + (id * TYPE_TABLE_RECORD_SIZE) + 4);
+    while free != 0 {
+        if free == addr {
+            return true;
+        }
+        free = read_u32(free + HEADER_SIZE);
+    }
+    false
+}
+
+/// Bytes taken by structs that haven't been freed, with their headers and
+/// those of their slabs. Until the next sweep that counts garbage too.
+#[no_mangle]
+pub extern "C" fn alloc_used() -> u32 {
+    unsafe {
+        let data_start = read_u32(DATA_START_ADDR);
+        let num_types = (data_start - TYPE_TABLE_INDEX) / TYPE_TABLE_RECORD_SIZE;
+        let mut used = read_u32(BUMP_PTR_ADDR) - data_start;
+
+        for t in 0..num_types {
+            let start = TYPE_TABLE_INDEX + (t * TYPE_TABLE_RECORD_SIZE);
+            let block_size = HEADER_SIZE + read_u32(start);
+            let mut free = read_u32(start + 4);
+
+            while free != 0 {
+                used -= block_size;
+                free = read_u32(free + HEADER_SIZE);
+            }
+        }
+
+        used
+    }
+}
+
+/// Bytes past the type table that no struct takes up.
+#[no_mangle]
+pub extern "C" fn alloc_free() -> u32 {
+    unsafe { alloc_memory_size() - read_u32(DATA_START_ADDR) - alloc_used() }
+}
+
+#[no_mangle]
+pub extern "C" fn alloc_allocated() -> u32 {
+    unsafe { read_u32(ALLOCATED_ADDR) }
+}
@@ -11,14 +11,14 @@ fn main() -> Result<()> {
     let instance = Instance::new(&mut store, &module, &[])?;
 
     // Get exported functions
-    let dinit = instance.get_typed_func::<(), ()>(&mut store, "dinit")?;
+    let dinit = instance.get_typed_func::<u32, ()>(&mut store, "dinit")?;
     let dalloc = instance.get_typed_func::<(u32, u32), u32>(&mut store, "dalloc")?;
 
     // Get memory for inspection
     let memory = instance.get_memory(&mut store, "memory").expect("memory export");
 
     // Initialize allocator
-    dinit.call(&mut store, ())?;
+    dinit.call(&mut store, abi::VERSION)?;
     println!("dinit() done");
     println!("Memory size: {} bytes ({} pages)", memory.data_size(&store), memory.size(&store));
 
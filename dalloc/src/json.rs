@@ -0,0 +1,568 @@
+//! Reading and quoting JSON for the helpers the compiler generates behind
+//! `to_json` and `from_json`.
+//!
+//! Readers take the document, a string, on every call, so a collection that
+//! moves it between calls is harmless. The position within it and a failure
+//! flag live in the header words. Once a read fails every later read fails
+//! too and returns a placeholder, so decoders only check the flag at the end.
+
+use super::{dalloc, read_u32, write_u32, write_u8, BYTES, JSON_CURSOR_ADDR, JSON_FAILED_ADDR};
+
+/// Returned by `peek` past the end of the document.
+const END: u32 = 0x100;
+
+unsafe fn cursor() -> u32 {
+    read_u32(JSON_CURSOR_ADDR)
+}
+
+unsafe fn set_cursor(at: u32) {
+    write_u32(JSON_CURSOR_ADDR, at);
+}
+
+unsafe fn failed() -> bool {
+    read_u32(JSON_FAILED_ADDR) != 0
+}
+
+unsafe fn fail() {
+    write_u32(JSON_FAILED_ADDR, 1);
+}
+
+unsafe fn byte_at(s: u32, at: u32) -> u32 {
+    if at < read_u32(s - 4) {
+        *((s + at) as *const u8) as u32
+    } else {
+        END
+    }
+}
+
+unsafe fn peek(s: u32) -> u32 {
+    byte_at(s, cursor())
+}
+
+unsafe fn skip_whitespace(s: u32) {
+    let mut at = cursor();
+    while matches!(byte_at(s, at), 0x20 | 0x09 | 0x0a | 0x0d) {
+        at += 1;
+    }
+    set_cursor(at);
+}
+
+/// Consumes `word` if the document continues with it. `word` is packed
+/// little-endian into an integer rather than kept as a byte string, which
+/// would live in the data section, inside the memory the heap hands out.
+unsafe fn eat_word(s: u32, word: u64, len: u32) -> bool {
+    let at = cursor();
+    for i in 0..len {
+        if byte_at(s, at + i) != ((word >> (i * 8)) & 0xff) as u32 {
+            return false;
+        }
+    }
+    set_cursor(at + len);
+    true
+}
+
+fn is_digit(c: u32) -> bool {
+    (b'0' as u32..=b'9' as u32).contains(&c)
+}
+
+fn hex_value(c: u32) -> Option<u32> {
+    match c {
+        0x30..=0x39 => Some(c - 0x30),
+        0x41..=0x46 => Some(c - 0x41 + 10),
+        0x61..=0x66 => Some(c - 0x61 + 10),
+        _ => None,
+    }
+}
+
+fn hex_digit(value: u32) -> u8 {
+    if value < 10 {
+        b'0' + value as u8
+    } else {
+        b'a' + (value - 10) as u8
+    }
+}
+
+/// Starts reading a new document.
+#[no_mangle]
+pub extern "C" fn djson_reset() -> u32 {
+    unsafe {
+        set_cursor(0);
+        write_u32(JSON_FAILED_ADDR, 0);
+    }
+    1
+}
+
+/// Marks the document as invalid.
+#[no_mangle]
+pub extern "C" fn djson_fail() -> u32 {
+    unsafe { fail() };
+    0
+}
+
+/// Whether every read succeeded and only whitespace is left.
+#[no_mangle]
+pub extern "C" fn djson_finish(s: u32) -> u32 {
+    unsafe {
+        skip_whitespace(s);
+        (!failed() && peek(s) == END) as u32
+    }
+}
+
+/// Consumes `c` if it is the next character after whitespace.
+#[no_mangle]
+pub extern "C" fn djson_eat(s: u32, c: u32) -> u32 {
+    unsafe {
+        if failed() {
+            return 0;
+        }
+        skip_whitespace(s);
+        if peek(s) != c {
+            return 0;
+        }
+        set_cursor(cursor() + 1);
+        1
+    }
+}
+
+/// Like `djson_eat`, but fails the document when `c` is missing.
+#[no_mangle]
+pub extern "C" fn djson_expect(s: u32, c: u32) -> u32 {
+    if djson_eat(s, c) == 1 {
+        return 1;
+    }
+    djson_fail()
+}
+
+/// Consumes a `null`, reporting whether there was one.
+#[no_mangle]
+pub extern "C" fn djson_null(s: u32) -> u32 {
+    unsafe {
+        if failed() {
+            return 0;
+        }
+        skip_whitespace(s);
+        eat_word(s, u64::from_le_bytes(*b"null\0\0\0\0"), 4) as u32
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn djson_boolean(s: u32) -> u32 {
+    unsafe {
+        if failed() {
+            return 0;
+        }
+        skip_whitespace(s);
+        if eat_word(s, u64::from_le_bytes(*b"true\0\0\0\0"), 4) {
+            return 1;
+        }
+        if !eat_word(s, u64::from_le_bytes(*b"false\0\0\0"), 5) {
+            fail();
+        }
+        0
+    }
+}
+
+/// Reads a number without a fraction or exponent that fits in an i64,
+/// starting at `at`. Returns it with the position after it.
+pub(super) unsafe fn read_integer(s: u32, at: u32) -> Option<(i64, u32)> {
+    let mut at = at;
+    let negative = byte_at(s, at) == b'-' as u32;
+    if negative {
+        at += 1;
+    }
+    if !is_digit(byte_at(s, at)) {
+        return None;
+    }
+    // Accumulate below zero so that i64::MIN fits.
+    let mut value: i64 = 0;
+    while is_digit(byte_at(s, at)) {
+        let digit = (byte_at(s, at) - b'0' as u32) as i64;
+        value = value.checked_mul(10)?.checked_sub(digit)?;
+        at += 1;
+    }
+    if matches!(byte_at(s, at), 0x2e | 0x45 | 0x65) {
+        // A fraction or exponent: not an integer.
+        return None;
+    }
+    let value = if negative {
+        value
+    } else {
+        value.checked_neg()?
+    };
+    Some((value, at))
+}
+
+/// Reads any number starting at `at`, returning it with the position after
+/// it. Digits past the nineteenth only scale the result, so very long
+/// mantissas lose precision.
+pub(super) unsafe fn read_float(s: u32, at: u32) -> Option<(f64, u32)> {
+    let mut at = at;
+    let negative = byte_at(s, at) == b'-' as u32;
+    if negative {
+        at += 1;
+    }
+    if !is_digit(byte_at(s, at)) {
+        return None;
+    }
+
+    let mut mantissa: u64 = 0;
+    let mut digits = 0;
+    let mut exponent: i32 = 0;
+    while is_digit(byte_at(s, at)) {
+        if digits < 19 {
+            mantissa = mantissa * 10 + (byte_at(s, at) - b'0' as u32) as u64;
+            digits += 1;
+        } else {
+            exponent += 1;
+        }
+        at += 1;
+    }
+    if byte_at(s, at) == b'.' as u32 {
+        at += 1;
+        if !is_digit(byte_at(s, at)) {
+            return None;
+        }
+        while is_digit(byte_at(s, at)) {
+            if digits < 19 {
+                mantissa = mantissa * 10 + (byte_at(s, at) - b'0' as u32) as u64;
+                digits += 1;
+                exponent -= 1;
+            }
+            at += 1;
+        }
+    }
+    if matches!(byte_at(s, at), 0x45 | 0x65) {
+        at += 1;
+        let negative_exponent = byte_at(s, at) == b'-' as u32;
+        if negative_exponent || byte_at(s, at) == b'+' as u32 {
+            at += 1;
+        }
+        if !is_digit(byte_at(s, at)) {
+            return None;
+        }
+        let mut written: i32 = 0;
+        while is_digit(byte_at(s, at)) {
+            if written < 10000 {
+                written = written * 10 + (byte_at(s, at) - b'0' as u32) as i32;
+            }
+            at += 1;
+        }
+        exponent += if negative_exponent { -written } else { written };
+    }
+
+    let mut value = mantissa as f64;
+    let mut scale = 1.0;
+    for _ in 0..exponent.unsigned_abs().min(400) {
+        scale *= 10.0;
+    }
+    if exponent < 0 {
+        value /= scale;
+    } else {
+        value *= scale;
+    }
+    Some((if negative { -value } else { value }, at))
+}
+
+#[no_mangle]
+pub extern "C" fn djson_integer(s: u32) -> i64 {
+    unsafe {
+        if failed() {
+            return 0;
+        }
+        skip_whitespace(s);
+        match read_integer(s, cursor()) {
+            Some((value, at)) => {
+                set_cursor(at);
+                value
+            }
+            None => {
+                fail();
+                0
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn djson_float(s: u32) -> f64 {
+    unsafe {
+        if failed() {
+            return 0.0;
+        }
+        skip_whitespace(s);
+        match read_float(s, cursor()) {
+            Some((value, at)) => {
+                set_cursor(at);
+                value
+            }
+            None => {
+                fail();
+                0.0
+            }
+        }
+    }
+}
+
+/// Reads four hex digits at `at`.
+unsafe fn read_hex4(s: u32, at: u32) -> Option<u32> {
+    let mut value = 0;
+    for i in 0..4 {
+        value = value * 16 + hex_value(byte_at(s, at + i))?;
+    }
+    Some(value)
+}
+
+/// Decodes the escape whose backslash is at `at`, returning the code point
+/// (or byte) it stands for and the position after it.
+unsafe fn read_escape(s: u32, at: u32) -> Option<(u32, u32)> {
+    let c = byte_at(s, at + 1);
+    let simple = match c {
+        0x22 | 0x5c | 0x2f => c,
+        0x62 => 0x08,
+        0x66 => 0x0c,
+        0x6e => 0x0a,
+        0x72 => 0x0d,
+        0x74 => 0x09,
+        0x75 => {
+            let high = read_hex4(s, at + 2)?;
+            if !(0xd800..0xe000).contains(&high) {
+                return Some((high, at + 6));
+            }
+            // A surrogate pair, spelled as two escapes.
+            if high >= 0xdc00 || byte_at(s, at + 6) != 0x5c || byte_at(s, at + 7) != 0x75 {
+                return None;
+            }
+            let low = read_hex4(s, at + 8)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return None;
+            }
+            return Some((0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00), at + 12));
+        }
+        _ => return None,
+    };
+    Some((simple, at + 2))
+}
+
+fn utf8_length(code_point: u32) -> u32 {
+    match code_point {
+        0..=0x7f => 1,
+        0x80..=0x7ff => 2,
+        0x800..=0xffff => 3,
+        _ => 4,
+    }
+}
+
+unsafe fn write_utf8(addr: u32, code_point: u32) -> u32 {
+    let len = utf8_length(code_point);
+    if len == 1 {
+        write_u8(addr, code_point as u8);
+        return 1;
+    }
+    let lead = match len {
+        2 => 0xc0,
+        3 => 0xe0,
+        _ => 0xf0,
+    };
+    write_u8(addr, (lead | (code_point >> (6 * (len - 1)))) as u8);
+    for i in 1..len {
+        write_u8(
+            addr + i,
+            (0x80 | ((code_point >> (6 * (len - 1 - i))) & 0x3f)) as u8,
+        );
+    }
+    len
+}
+
+/// The decoded length of the string whose opening quote is at `start`, and
+/// the position of its closing quote, or `None` when it is malformed.
+unsafe fn measure_string(s: u32, start: u32) -> Option<(u32, u32)> {
+    let mut at = start + 1;
+    let mut length = 0;
+    loop {
+        match byte_at(s, at) {
+            0x22 => return Some((length, at)),
+            0x5c => {
+                let (code_point, next) = read_escape(s, at)?;
+                length += utf8_length(code_point);
+                at = next;
+            }
+            c if c < 0x20 || c == END => return None,
+            _ => {
+                length += 1;
+                at += 1;
+            }
+        }
+    }
+}
+
+/// Reads a string. Returns 0 when the heap is full, leaving the position
+/// alone so the call can be retried after a collection.
+#[no_mangle]
+pub extern "C" fn djson_string(s: u32) -> u32 {
+    unsafe {
+        if !failed() {
+            skip_whitespace(s);
+            if peek(s) != b'"' as u32 {
+                fail();
+            }
+        }
+        let measured = if failed() {
+            None
+        } else {
+            measure_string(s, cursor())
+        };
+        let Some((length, end)) = measured else {
+            fail();
+            return dalloc(BYTES, 0);
+        };
+
+        let addr = dalloc(BYTES, length);
+        if addr == 0 {
+            return 0;
+        }
+        let mut at = cursor() + 1;
+        let mut out = addr;
+        while at < end {
+            let c = byte_at(s, at);
+            if c == 0x5c {
+                // Already validated by `measure_string`.
+                let (code_point, next) = read_escape(s, at).unwrap_or((0, at + 2));
+                out += write_utf8(out, code_point);
+                at = next;
+            } else {
+                write_u8(out, c as u8);
+                out += 1;
+                at += 1;
+            }
+        }
+        set_cursor(end + 1);
+        addr
+    }
+}
+
+/// Skips the string whose opening quote is at the cursor.
+unsafe fn skip_string(s: u32) {
+    match measure_string(s, cursor()) {
+        Some((_, end)) => set_cursor(end + 1),
+        None => fail(),
+    }
+}
+
+/// Skips the comma separated values or members up to `close`.
+unsafe fn skip_items(s: u32, close: u32, members: bool) {
+    if djson_eat(s, close) == 1 {
+        return;
+    }
+    loop {
+        if members {
+            skip_whitespace(s);
+            if peek(s) != b'"' as u32 {
+                fail();
+                return;
+            }
+            skip_string(s);
+            djson_expect(s, b':' as u32);
+        }
+        djson_skip(s);
+        if failed() || djson_eat(s, b',' as u32) == 0 {
+            break;
+        }
+    }
+    djson_expect(s, close);
+}
+
+/// Skips over one value of any kind, such as a member no field asks for.
+#[no_mangle]
+pub extern "C" fn djson_skip(s: u32) -> u32 {
+    unsafe {
+        if failed() {
+            return 0;
+        }
+        skip_whitespace(s);
+        match peek(s) {
+            0x22 => skip_string(s),
+            0x5b => {
+                set_cursor(cursor() + 1);
+                skip_items(s, b']' as u32, false);
+            }
+            0x7b => {
+                set_cursor(cursor() + 1);
+                skip_items(s, b'}' as u32, true);
+            }
+            0x74 | 0x66 => {
+                djson_boolean(s);
+            }
+            0x6e => {
+                if djson_null(s) == 0 {
+                    fail();
+                }
+            }
+            _ => {
+                djson_float(s);
+            }
+        }
+        (!failed()) as u32
+    }
+}
+
+/// The escape that stands for `c` inside a JSON string, if it needs one
+/// shorter than `\u00XX`.
+fn short_escape(c: u32) -> Option<u8> {
+    match c {
+        0x22 => Some(b'"'),
+        0x5c => Some(b'\\'),
+        0x08 => Some(b'b'),
+        0x0c => Some(b'f'),
+        0x0a => Some(b'n'),
+        0x0d => Some(b'r'),
+        0x09 => Some(b't'),
+        _ => None,
+    }
+}
+
+/// Writes `s` as a quoted JSON string. Returns 0 when the heap is full.
+#[no_mangle]
+pub extern "C" fn djson_quote(s: u32) -> u32 {
+    unsafe {
+        let len = read_u32(s - 4);
+        let mut quoted = 2;
+        for i in 0..len {
+            let c = byte_at(s, i);
+            quoted += if short_escape(c).is_some() {
+                2
+            } else if c < 0x20 {
+                6
+            } else {
+                1
+            };
+        }
+
+        let addr = dalloc(BYTES, quoted);
+        if addr == 0 {
+            return 0;
+        }
+        write_u8(addr, b'"');
+        let mut out = addr + 1;
+        for i in 0..len {
+            let c = byte_at(s, i);
+            if let Some(escape) = short_escape(c) {
+                write_u8(out, b'\\');
+                write_u8(out + 1, escape);
+                out += 2;
+            } else if c < 0x20 {
+                write_u8(out, b'\\');
+                write_u8(out + 1, b'u');
+                write_u8(out + 2, b'0');
+                write_u8(out + 3, b'0');
+                write_u8(out + 4, hex_digit(c >> 4));
+                write_u8(out + 5, hex_digit(c & 0xf));
+                out += 6;
+            } else {
+                write_u8(out, c as u8);
+                out += 1;
+            }
+        }
+        write_u8(out, b'"');
+        addr
+    }
+}
@@ -1,6 +1,72 @@
 #![no_std]
 
-const START: u32 = 4;
+mod json;
+mod math;
+mod strings;
+
+/// Set by `sweep` and cleared by a successful `dalloc`, so the heap only grows
+/// once a collection has failed to free enough space.
+const COLLECTED_ADDR: u32 = 4;
+/// Size of the last request no free block could hold, cleared once one
+/// does. The collector compacts when it fits the free space but no block.
+const REQUEST_ADDR: u32 = 8;
+/// Bytes handed out by `dalloc` since the last `sweep`, headers included.
+const ALLOCATED_ADDR: u32 = 12;
+/// Position and failure flag of the JSON reader, see `json`.
+const JSON_CURSOR_ADDR: u32 = 16;
+const JSON_FAILED_ADDR: u32 = 20;
+/// Whether the last `datoi` or `datof` was handed something other than a
+/// number.
+const PARSE_FAILED_ADDR: u32 = 24;
+/// How `find_block` picks a block, one of `abi::policy`.
+const POLICY_ADDR: u32 = 28;
+/// The block after the last one handed out, where next-fit starts looking.
+const ROVER_ADDR: u32 = 32;
+/// Whether the last block in the heap is free, standing in for the
+/// `PREV_FREE` flag of a block past the end.
+const LAST_FREE_ADDR: u32 = 36;
+/// Head of the list of vacant large blocks, see `dalloc_large`, linked
+/// through their length words.
+const VACANT_ADDR: u32 = 40;
+/// Bytes `dalloc` may hand out after a sweep before it asks for another
+/// collection, see `dbudget`. 0 means no limit.
+const BUDGET_ADDR: u32 = 44;
+/// Heads of the free lists, a word per size class, see `size_class`. A free
+/// block links to the next and previous blocks on its list from its mark
+/// and length words, 0 ending the list. Free blocks of no bytes keep their
+/// footer in the length word and stay off the lists until a neighbour is
+/// freed.
+const FREE_LISTS_ADDR: u32 = 48;
+const SIZE_CLASSES: u32 = 32;
+const START: u32 = FREE_LISTS_ADDR + SIZE_CLASSES * 4;
+const PAGE_SIZE: u32 = 65536;
+/// Pages added at a time, matching the initial heap.
+const MIN_GROWTH: u32 = 16;
+/// Requests for this many bytes or more get pages of their own, see
+/// `dalloc_large`.
+const LARGE: u32 = PAGE_SIZE;
+/// Every block starts with a header of a mark, an info word and a length,
+/// see `abi::block`. The info word holds the block's size in bytes, a
+/// multiple of 4, with flags in its low bits. A live block keeps its type
+/// above the size; a free block has the whole word for it, and also ends in
+/// a footer repeating its size, so the block after it can find its start.
+const HEADER: u32 = abi::block::HEADER;
+const SIZE_MASK: u32 = abi::block::SIZE_MASK;
+const TYPE_SHIFT: u32 = abi::block::TYPE_SHIFT;
+/// Set in the info word of a free block.
+const FREE: u32 = 1;
+/// Set in the info word of a live block when the block before it is free.
+const PREV_FREE: u32 = 2;
+/// The most bytes of elements a block can hold.
+const LARGEST: u32 = SIZE_MASK - HEADER;
+/// Mark value of blocks that live for the whole program.
+const PINNED: u32 = 2;
+/// Block type of lists whose elements point at structs.
+const STRUCTS: u32 = abi::dtype::STRUCTS;
+/// Block type of lists whose elements point at other blocks.
+const LISTS: u32 = abi::dtype::LISTS;
+/// Block type of strings, which hold one byte per element instead of a u64.
+const BYTES: u32 = abi::dtype::BYTES;
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -9,7 +75,7 @@ fn panic(_: &core::panic::PanicInfo) -> ! {
 
 #[no_mangle]
 pub extern "C" fn dalloc_memory_size() -> u32 {
-    (core::arch::wasm32::memory_size(0) as u32) * 65336
+    (core::arch::wasm32::memory_size(0) as u32) * PAGE_SIZE
 }
 
 fn memory_size() -> u32 {
@@ -34,6 +100,83 @@ pub extern "C" fn write_dalloc(addr: u32, val: u32) {
     unsafe { write_u32(addr, val) }
 }
 
+/// Bytes taken by each element of a block of type `ty`.
+fn element_size(ty: u32) -> u32 {
+    if ty == BYTES {
+        1
+    } else {
+        8
+    }
+}
+
+/// Bytes a block needs for `length` elements of type `ty`, rounded up to a
+/// whole word so the header after it stays aligned. Traps when that's more
+/// than a block can hold.
+fn block_bytes(ty: u32, length: u32) -> u32 {
+    let bytes = length as u64 * element_size(ty) as u64;
+    if bytes > LARGEST as u64 {
+        core::arch::wasm32::unreachable()
+    }
+    (bytes as u32 + 3) & !3
+}
+
+/// Whether the block at `addr` is free.
+unsafe fn is_free(addr: u32) -> bool {
+    read_u32(addr + 4) & FREE != 0
+}
+
+/// Bytes after the header of the block at `addr`.
+unsafe fn block_size(addr: u32) -> u32 {
+    let info = read_u32(addr + 4);
+    if info & FREE != 0 {
+        info & !3
+    } else {
+        info & SIZE_MASK
+    }
+}
+
+/// Whether the block at `addr` takes pages of its own, see `dalloc_large`.
+/// Every other block that isn't free stays under `LARGE + HEADER` bytes.
+unsafe fn is_large(addr: u32) -> bool {
+    !is_free(addr) && block_size(addr) >= LARGE + HEADER
+}
+
+/// Whether the block at `addr` is handed out. Large blocks the collector
+/// has freed keep their pages, but lose their type.
+unsafe fn is_live(addr: u32) -> bool {
+    !is_free(addr) && block_type(addr + HEADER) != 0
+}
+
+/// Changes the size of the live block at `addr`, keeping its type and flags.
+unsafe fn set_size(addr: u32, size: u32) {
+    write_u32(addr + 4, read_u32(addr + 4) & !SIZE_MASK | size);
+}
+
+/// The type of the live block whose elements start at `ptr`.
+unsafe fn block_type(ptr: u32) -> u32 {
+    (read_u32(ptr - abi::block::INFO) & abi::block::TYPE_MASK) >> TYPE_SHIFT
+}
+
+/// Records whether the block ending at `end` is free, in the block after
+/// it, or in `LAST_FREE_ADDR` when it ends the heap.
+unsafe fn set_prev_free(end: u32, free: bool) {
+    if end >= memory_size() {
+        write_u32(LAST_FREE_ADDR, free as u32);
+        return;
+    }
+    let info = read_u32(end + 4);
+    write_u32(end + 4, if free { info | PREV_FREE } else { info & !PREV_FREE });
+}
+
+/// Copies `len` bytes between blocks that don't overlap.
+unsafe fn copy(dst: u32, src: u32, len: u32) {
+    core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len as usize);
+}
+
+unsafe fn write_u8(addr: u32, val: u8) {
+    *(addr as *mut u8) = val;
+}
+
 unsafe fn read_u64(addr: u32) -> u64 {
     *(addr as *const u64)
 }
@@ -41,131 +184,912 @@ unsafe fn read_u64(addr: u32) -> u64 {
 unsafe fn write_u64(addr: u32, val: u64) {
     *(addr as *mut u64) = val;
 }
-
+/// Makes the whole heap one free block. Traps when the program was compiled
+/// against an ABI `version` this build can't serve.
 #[no_mangle]
-pub extern "C" fn dinit() {
+pub extern "C" fn dinit(version: u32) {
+    if !abi::compatible(version) {
+        core::arch::wasm32::unreachable()
+    }
     unsafe {
-        write_u32(START, 0);
-        write_u32(START + 4, 0);
+        write_u32(COLLECTED_ADDR, 0);
+        write_u32(REQUEST_ADDR, 0);
+        write_u32(ALLOCATED_ADDR, 0);
+        write_u32(POLICY_ADDR, abi::policy::FIRST_FIT);
+        write_u32(ROVER_ADDR, START);
+        write_u32(VACANT_ADDR, 0);
+        write_u32(BUDGET_ADDR, 0);
+        for class in 0..SIZE_CLASSES {
+            write_u32(FREE_LISTS_ADDR + class * 4, 0);
+        }
 
-        let size = memory_size();
-        write_u32(START + 8, size - 24);
-        write_u32(START + 12, size - 24);
-        write_u32(size - 4, size - 24);
+        write_free(START, memory_size() - START - HEADER);
     }
 }
 
+/// Chooses how later requests pick among the free blocks that fit them, one
+/// of `abi::policy`. Programs built with a policy call this just after
+/// `dinit`. Traps on a policy this build doesn't know.
+#[no_mangle]
+pub extern "C" fn dpolicy(policy: u32) {
+    if policy > abi::policy::NEXT_FIT {
+        core::arch::wasm32::unreachable()
+    }
+    unsafe { write_u32(POLICY_ADDR, policy) }
+}
+
+/// Limits how many bytes `dalloc` hands out after a sweep before it returns
+/// 0 to ask for a collection, as it does when no block fits, with 0 lifting
+/// the limit. The collector sets this to keep the heap under the threshold
+/// given to `set_gc_threshold`.
+#[no_mangle]
+pub extern "C" fn dbudget(bytes: u32) {
+    unsafe { write_u32(BUDGET_ADDR, bytes) }
+}
+
+/// Whether handing out `bytes` more would go over the budget, before a
+/// collection has had the chance to free some.
+unsafe fn over_budget(bytes: u32) -> bool {
+    let budget = read_u32(BUDGET_ADDR);
+    budget != 0
+        && read_u32(COLLECTED_ADDR) == 0
+        && read_u32(ALLOCATED_ADDR) + bytes > budget
+}
+
+/// Extends the heap by enough pages for a block of `size` bytes, merging the
+/// new space into the last block when that one is free. Returns false when
+/// the host refuses to grow the memory.
+unsafe fn grow(size: u32) -> bool {
+    let needed = (size + HEADER + PAGE_SIZE - 1) / PAGE_SIZE;
+    let pages = if needed > MIN_GROWTH { needed } else { MIN_GROWTH };
+
+    let old_end = memory_size();
+    if core::arch::wasm32::memory_grow(0, pages as usize) == usize::MAX {
+        return false;
+    }
+    let added = pages * PAGE_SIZE;
+
+    if read_u32(LAST_FREE_ADDR) != 0 {
+        let last_size = read_u32(old_end - 4);
+        let last_addr = old_end - HEADER - last_size;
+        unlink_free(last_addr);
+        write_free(last_addr, last_size + added);
+    } else {
+        write_free(old_end, added - HEADER);
+    }
+
+    true
+}
+
+/// Allocates a block for `length` elements. When no free block is large
+/// enough, or the block would go over the budget, this returns 0 so the
+/// caller can collect and retry; if the retry still finds nothing, the heap
+/// grows instead. Traps when the elements need more than a block can hold.
 #[no_mangle]
 pub extern "C" fn dalloc(ty: u32, length: u32) -> u32 {
     unsafe {
-        let size = length * 8;
+        let size = block_bytes(ty, length);
+        if over_budget(size + HEADER) {
+            return 0;
+        }
+        if size >= LARGE {
+            let addr = dalloc_large(ty, size, length);
+            clear_pointers(addr, ty, length);
+            return addr;
+        }
+
+        let mut addr = find_block(ty, size, length);
+        if addr == 0 {
+            write_u32(REQUEST_ADDR, size);
+            if read_u32(COLLECTED_ADDR) == 0 || !grow(size) {
+                return 0;
+            }
+            addr = find_block(ty, size, length);
+            if addr == 0 {
+                return 0;
+            }
+        }
+
+        write_u32(COLLECTED_ADDR, 0);
+        write_u32(REQUEST_ADDR, 0);
+        write_u32(
+            ALLOCATED_ADDR,
+            read_u32(ALLOCATED_ADDR) + block_size(addr - HEADER) + HEADER,
+        );
+        clear_pointers(addr, ty, length);
+        addr
+    }
+}
+
+/// Zeroes the elements of a fresh block of pointers. The collector can see
+/// a list before all of its elements are written, so whatever the block's
+/// last occupant left behind must not look like pointers to follow.
+unsafe fn clear_pointers(addr: u32, ty: u32, length: u32) {
+    if addr == 0 || (ty != STRUCTS && ty != LISTS) {
+        return;
+    }
+    core::ptr::write_bytes(addr as *mut u8, 0, (length * 8) as usize);
+}
+
+/// Allocates a block of `size` bytes or more in whole pages of its own,
+/// away from the free lists. Large blocks never move or merge with the
+/// blocks around them. Once the collector frees one its pages stay vacant
+/// for the next large request, and until a collection has had the chance to
+/// vacate some, a request no vacant block fits returns 0 rather than grow
+/// the heap.
+unsafe fn dalloc_large(ty: u32, size: u32, length: u32) -> u32 {
+    let mut addr = take_vacant(size);
+    if addr == 0 {
+        if read_u32(COLLECTED_ADDR) == 0 {
+            return 0;
+        }
+        addr = grow_large(size);
+        if addr == 0 {
+            return 0;
+        }
+    }
+
+    write_u32(addr, 0);
+    write_u32(addr + 4, read_u32(addr + 4) | ty << TYPE_SHIFT);
+    write_u32(addr + 8, length);
+
+    write_u32(COLLECTED_ADDR, 0);
+    write_u32(
+        ALLOCATED_ADDR,
+        read_u32(ALLOCATED_ADDR) + block_size(addr) + HEADER,
+    );
+    addr + HEADER
+}
+
+/// Takes the smallest vacant block with room for `size` bytes off the
+/// vacant list, leaving the pages it doesn't need vacant when there are
+/// enough of them for a large block. Returns 0 when none fits.
+unsafe fn take_vacant(size: u32) -> u32 {
+    let mut best = 0;
+    let mut best_prev = 0;
+    let mut prev = 0;
+    let mut current_addr = read_u32(VACANT_ADDR);
+    while current_addr != 0 {
+        let current_size = block_size(current_addr);
+        if size <= current_size && (best == 0 || current_size < block_size(best)) {
+            best = current_addr;
+            best_prev = prev;
+        }
+        prev = current_addr;
+        current_addr = read_u32(current_addr + 8);
+    }
+    if best == 0 {
+        return 0;
+    }
+
+    let next = read_u32(best + 8);
+    if best_prev == 0 {
+        write_u32(VACANT_ADDR, next);
+    } else {
+        write_u32(best_prev + 8, next);
+    }
+
+    let pages = (block_size(best) + HEADER) / PAGE_SIZE;
+    let needed = (size + HEADER + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages - needed >= 2 {
+        let rest = best + needed * PAGE_SIZE;
+        set_size(best, needed * PAGE_SIZE - HEADER);
+        write_u32(rest, 0);
+        write_u32(rest + 4, (pages - needed) * PAGE_SIZE - HEADER);
+        write_u32(rest + 8, read_u32(VACANT_ADDR));
+        write_u32(VACANT_ADDR, rest);
+    }
+    best
+}
+
+/// Grows the heap by the pages a large block of `size` bytes needs and
+/// puts the block in them, without a type yet. Returns 0 when the host
+/// refuses to grow the memory.
+unsafe fn grow_large(size: u32) -> u32 {
+    let pages = (size + HEADER + PAGE_SIZE - 1) / PAGE_SIZE;
+    let addr = memory_size();
+    if core::arch::wasm32::memory_grow(0, pages as usize) == usize::MAX {
+        return 0;
+    }
+
+    let prev_free = if read_u32(LAST_FREE_ADDR) != 0 { PREV_FREE } else { 0 };
+    write_u32(addr + 4, (pages * PAGE_SIZE - HEADER) | prev_free);
+    write_u32(LAST_FREE_ADDR, 0);
+    addr
+}
+
+/// Hands out a free block for `size` bytes, picked by the heap's policy, and
+/// frees what it doesn't need. Returns 0 when nothing fits.
+unsafe fn find_block(ty: u32, size: u32, length: u32) -> u32 {
+    let addr = match read_u32(POLICY_ADDR) {
+        abi::policy::BEST_FIT => best_fit(size),
+        abi::policy::NEXT_FIT => next_fit(size),
+        _ => first_fit(size),
+    };
+    if addr == 0 {
+        return 0;
+    }
+
+    let available = block_size(addr);
+    unlink_free(addr);
+    write_u32(addr, 0);
+    write_u32(addr + 8, length);
+    if size + HEADER <= available {
+        write_u32(addr + 4, ty << TYPE_SHIFT | size);
+        write_free(addr + HEADER + size, available - size - HEADER);
+    } else {
+        write_u32(addr + 4, ty << TYPE_SHIFT | available);
+        set_prev_free(addr + HEADER + available, false);
+    }
+
+    let next = addr + block_size(addr) + HEADER;
+    write_u32(ROVER_ADDR, if next < memory_size() { next } else { START });
+    addr + HEADER
+}
+
+/// The first block that fits on the free list for `size`, or the head of the
+/// first nonempty list after it, where every block fits.
+unsafe fn first_fit(size: u32) -> u32 {
+    for class in size_class(size)..SIZE_CLASSES {
+        let mut current_addr = read_u32(FREE_LISTS_ADDR + class * 4);
+        while current_addr != 0 {
+            if size <= block_size(current_addr) {
+                return current_addr;
+            }
+            current_addr = read_u32(current_addr);
+        }
+    }
+    0
+}
+
+/// The smallest free block that fits. Lists hold larger blocks the later
+/// they come, so it's on the first list with one that fits.
+unsafe fn best_fit(size: u32) -> u32 {
+    for class in size_class(size)..SIZE_CLASSES {
+        let mut best = 0;
+        let mut best_size = u32::MAX;
+        let mut current_addr = read_u32(FREE_LISTS_ADDR + class * 4);
+        while current_addr != 0 {
+            let current_size = block_size(current_addr);
+            if size <= current_size && current_size < best_size {
+                best = current_addr;
+                best_size = current_size;
+            }
+            current_addr = read_u32(current_addr);
+        }
+        if best != 0 {
+            return best;
+        }
+    }
+    0
+}
+
+/// The first free block that fits walking the heap from the rover, coming
+/// back round to it from the start.
+unsafe fn next_fit(size: u32) -> u32 {
+    let rover = read_u32(ROVER_ADDR);
+    let end = memory_size();
+    for (from, to) in [(rover, end), (START, rover)] {
+        let mut current_addr = from;
+        while current_addr < to {
+            let current_size = block_size(current_addr);
+            if is_free(current_addr) && size <= current_size {
+                return current_addr;
+            }
+            current_addr = current_addr + current_size + HEADER;
+        }
+    }
+    0
+}
+
+/// The free list holding blocks of `size` bytes. Up to 128 bytes there is a
+/// list for every 8 bytes, so a request takes the head of its own list, or
+/// the block after it; larger blocks share a list per power of two.
+fn size_class(size: u32) -> u32 {
+    if size < 136 {
+        return size.saturating_sub(8) / 8;
+    }
+    let class = 9 + (31 - size.leading_zeros());
+    if class < SIZE_CLASSES {
+        class
+    } else {
+        SIZE_CLASSES - 1
+    }
+}
+
+/// Takes the free block at `addr` off its free list, before it's handed out
+/// or merged into a neighbour.
+unsafe fn unlink_free(addr: u32) {
+    let size = block_size(addr);
+    if size == 0 {
+        return;
+    }
+
+    let next = read_u32(addr);
+    let prev = read_u32(addr + 8);
+    if prev == 0 {
+        write_u32(FREE_LISTS_ADDR + size_class(size) * 4, next);
+    } else {
+        write_u32(prev, next);
+    }
+    if next != 0 {
+        write_u32(next + 8, prev);
+    }
+}
+
+/// Frees a block, merging it with the free blocks on either side. Returns
+/// the address of the free block it ends up in.
+#[no_mangle]
+pub extern "C" fn dfree(pointer: u32) -> u32 {
+    unsafe {
+        let mut addr = pointer - HEADER;
+        let mut size = block_size(addr);
+
+        let end = addr + HEADER + size;
+        if end < memory_size() && is_free(end) {
+            unlink_free(end);
+            size += HEADER + block_size(end);
+        }
+
+        if read_u32(addr + 4) & PREV_FREE != 0 {
+            let prev_size = read_u32(addr - 4);
+            let prev_addr = addr - HEADER - prev_size;
+            unlink_free(prev_addr);
+            addr = prev_addr;
+            size += prev_size + HEADER;
+        }
 
+        // The rover may have pointed at a block merged into this one.
+        let rover = read_u32(ROVER_ADDR);
+        if addr < rover && rover < addr + size + HEADER {
+            write_u32(ROVER_ADDR, addr);
+        }
+
+        write_free(addr, size);
+        addr
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn sweep() -> u32 {
+    unsafe {
+        // Vacant blocks are listed afresh, so that neighbours can merge.
+        write_u32(VACANT_ADDR, 0);
+        let mut vacant = 0;
         let mut current_addr = START;
 
         while current_addr < memory_size() {
-            let current_ty = read_u32(current_addr);
-            let current_size = read_u32(current_addr + 8);
-
-            if current_ty == 0 {
-                if size + 20 <= current_size {
-                    write_u32(current_addr, ty);
-                    write_u32(current_addr + 8, size);
-                    write_u32(current_addr + 12, length);
-                    write_u32(current_addr + 16 + size, size);
-
-                    let left = current_size - size - 20;
-                    let new_start = current_addr + 20 + size;
-
-                    write_u32(new_start, 0);
-                    write_u32(new_start + 4, 0);
-                    write_u32(new_start + 8, left);
-                    write_u32(new_start + 12, left);
-                    write_u32(new_start + 16 + left, left);
-
-                    return current_addr + 16;
-                } else if size <= current_size {
-                    write_u32(current_addr, ty);
-                    return current_addr + 16;
-                } else {
-                    current_addr = current_addr + current_size + 20;
+            let mut new_addr = current_addr;
+
+            if !is_free(current_addr) {
+                match read_u32(current_addr) {
+                    0 if is_large(current_addr) => new_addr = vacate(current_addr, vacant),
+                    0 => new_addr = dfree(current_addr + HEADER),
+                    1 => write_u32(current_addr, 0),
+                    _ => {}
                 }
-            } else {
-                current_addr = current_addr + current_size + 20;
             }
+
+            vacant = if is_large(new_addr) && !is_live(new_addr) {
+                new_addr
+            } else {
+                0
+            };
+            current_addr = new_addr + block_size(new_addr) + HEADER;
         }
+
+        write_u32(COLLECTED_ADDR, 1);
+        write_u32(ALLOCATED_ADDR, 0);
     }
 
     0
 }
 
+/// Leaves the pages of the large block at `addr` vacant for another large
+/// block, merged into `prev` when that's the vacant block just before it.
+/// Returns the vacant block they end up in.
+unsafe fn vacate(addr: u32, prev: u32) -> u32 {
+    if prev != 0 {
+        let merged = block_size(prev) + HEADER + block_size(addr);
+        if merged <= SIZE_MASK {
+            set_size(prev, merged);
+            return prev;
+        }
+    }
+
+    write_u32(addr + 4, read_u32(addr + 4) & !abi::block::TYPE_MASK);
+    write_u32(addr + 8, read_u32(VACANT_ADDR));
+    write_u32(VACANT_ADDR, addr);
+    addr
+}
+
+/// Bytes taken by blocks that haven't been freed, headers included. Until
+/// the next sweep that counts garbage too.
 #[no_mangle]
-pub extern "C" fn dfree(pointer: u32) -> u32 {
+pub extern "C" fn dalloc_used() -> u32 {
+    memory_size() - START - dalloc_free()
+}
+
+/// Bytes in free and vacant blocks, headers included.
+#[no_mangle]
+pub extern "C" fn dalloc_free() -> u32 {
     unsafe {
-        let addr = pointer - 16;
-        write_u32(addr, 0);
-        let mut ret = addr;
-
-        let size = read_u32(addr + 8);
-        let end = addr + 20 + size;
-
-        if end < memory_size() {
-            let next_ty = read_u32(end);
-            if next_ty == 0 {
-                let next_size = read_u32(end + 8);
-                let combined_size = size + 20 + next_size;
-                write_u32(addr + 8, combined_size);
-                write_u32(addr + 12, combined_size);
-                write_u32(addr + 16 + combined_size, combined_size);
+        let mut free = 0;
+        let mut current_addr = START;
+
+        while current_addr < memory_size() {
+            let current_size = block_size(current_addr);
+            if !is_live(current_addr) {
+                free += current_size + HEADER;
             }
+            current_addr = current_addr + current_size + HEADER;
         }
 
-        if addr > START {
-            let prev_size = read_u32(addr - 4);
-            let prev_addr = addr - 20 - prev_size;
-            let prev_ty = read_u32(prev_addr);
-            if prev_ty == 0 {
-                let combined_size = prev_size + 20 + read_u32(addr + 8);
-                write_u32(prev_addr + 8, combined_size);
-                write_u32(prev_addr + 12, combined_size);
-                write_u32(prev_addr + 16 + combined_size, combined_size);
-
-                ret = prev_addr;
+        free
+    }
+}
+
+/// Size of the largest free block, which bounds the longest list or string
+/// that fits without a collection.
+#[no_mangle]
+pub extern "C" fn dalloc_largest_free() -> u32 {
+    unsafe {
+        let mut largest = 0;
+        let mut current_addr = START;
+
+        while current_addr < memory_size() {
+            let current_size = block_size(current_addr);
+            if !is_live(current_addr) && current_size > largest {
+                largest = current_size;
             }
+            current_addr = current_addr + current_size + HEADER;
         }
 
-        ret
+        largest
     }
 }
 
 #[no_mangle]
-pub extern "C" fn sweep() -> u32 {
+pub extern "C" fn dalloc_allocated() -> u32 {
+    unsafe { read_u32(ALLOCATED_ADDR) }
+}
+
+/// Whether `ptr` points at the elements of a live block. Roots that may not
+/// hold a pointer at all are checked here before being followed.
+#[no_mangle]
+pub extern "C" fn dblock(ptr: u32) -> u32 {
     unsafe {
         let mut current_addr = START;
 
+        while current_addr < memory_size() && current_addr + HEADER <= ptr {
+            if current_addr + HEADER == ptr {
+                return is_live(current_addr) as u32;
+            }
+            current_addr = current_addr + block_size(current_addr) + HEADER;
+        }
+
+        0
+    }
+}
+
+/// The live block after the one whose elements start at `ptr`, in address
+/// order, starting from the first when `ptr` is 0. Returns 0 after the
+/// last, so the host can walk the heap.
+#[no_mangle]
+pub extern "C" fn dnext(ptr: u32) -> u32 {
+    unsafe {
+        let mut current_addr = match ptr {
+            0 => START,
+            _ => ptr + block_size(ptr - HEADER),
+        };
+
         while current_addr < memory_size() {
-            let current_ty = read_u32(current_addr);
-            let current_mark = read_u32(current_addr + 4);
-            let mut new_addr = current_addr;
+            if is_live(current_addr) {
+                return current_addr + HEADER;
+            }
+            current_addr = current_addr + block_size(current_addr) + HEADER;
+        }
+
+        0
+    }
+}
+
+/// Traps unless the `size` bytes at `offset` past `ptr` lie inside a live
+/// block, between its length and the end of its elements. Programs built
+/// with `--sanitize=memory` call this before every access to this memory,
+/// and carry on with the `ptr` it returns.
+#[no_mangle]
+pub extern "C" fn dcheck(ptr: u32, offset: u32, size: u32) -> u32 {
+    unsafe {
+        let start = ptr.wrapping_add(offset);
+        let mut current_addr = START;
 
-            if current_ty != 0 && current_mark == 0 {
-                new_addr = dfree(current_addr + 16);
+        while current_addr < memory_size() {
+            let end = current_addr + HEADER + block_size(current_addr);
+            if start < end {
+                let live = is_live(current_addr);
+                if live && start >= current_addr + 8 && start + size <= end {
+                    return ptr;
+                }
+                break;
             }
+            current_addr = end;
+        }
+
+        core::arch::wasm32::unreachable()
+    }
+}
+
+/// Whether the last failed request would fit in the free space if it were
+/// contiguous, though no single free block can hold it.
+#[no_mangle]
+pub extern "C" fn dfragmented() -> u32 {
+    unsafe {
+        let request = read_u32(REQUEST_ADDR);
+        if request == 0 {
+            return 0;
+        }
+
+        let mut free = 0;
+        let mut current_addr = START;
 
-            if current_mark == 1 {
-                write_u32(current_addr + 4, 0);
+        while current_addr < memory_size() {
+            let current_size = block_size(current_addr);
+            if is_free(current_addr) {
+                if request <= current_size {
+                    return 0;
+                }
+                free += current_size + HEADER;
             }
+            current_addr = current_addr + current_size + HEADER;
+        }
 
-            current_addr = new_addr + read_u32(new_addr + 8) + 20;
+        (request + HEADER <= free) as u32
+    }
+}
+
+/// Gives every movable block the address it will slide down to, kept in its
+/// mark field until `dcompact`. Blocks with any other mark, held by a root
+/// or pinned, stay put, so blocks only slide into the free space between
+/// them. Expects a swept heap, where every other mark is 0.
+#[no_mangle]
+pub extern "C" fn dplan() {
+    unsafe {
+        let mut current_addr = START;
+        let mut free_addr = START;
+
+        while current_addr < memory_size() {
+            let current_size = block_size(current_addr);
+
+            if !is_free(current_addr) {
+                if read_u32(current_addr) == 0 && !is_large(current_addr) {
+                    write_u32(current_addr, free_addr + HEADER);
+                    free_addr += current_size + HEADER;
+                } else {
+                    free_addr = current_addr + current_size + HEADER;
+                }
+            }
+
+            current_addr = current_addr + current_size + HEADER;
         }
     }
+}
 
-    0
+/// Slides blocks to the addresses `dplan` gave them and turns the gaps left
+/// between held blocks, and at the end of the heap, into free blocks. Every
+/// pointer to a moved block must already have been rewritten.
+#[no_mangle]
+pub extern "C" fn dcompact() {
+    unsafe {
+        write_u32(ROVER_ADDR, START);
+        for class in 0..SIZE_CLASSES {
+            write_u32(FREE_LISTS_ADDR + class * 4, 0);
+        }
+
+        let end = memory_size();
+        let mut current_addr = START;
+        let mut free_addr = START;
+
+        while current_addr < end {
+            let current_size = block_size(current_addr);
+            let next_addr = current_addr + current_size + HEADER;
+
+            if !is_free(current_addr) {
+                let mark = read_u32(current_addr);
+                let target = if mark > PINNED { mark - HEADER } else { current_addr };
+
+                if target != current_addr {
+                    core::ptr::copy(
+                        current_addr as *const u8,
+                        target as *mut u8,
+                        (current_size + HEADER) as usize,
+                    );
+                }
+                if mark != PINNED {
+                    write_u32(target, 0);
+                }
+
+                // Every gap is made of whole free or vacated blocks, so it
+                // always has room for a free block's header.
+                if target > free_addr {
+                    write_free(free_addr, target - free_addr - HEADER);
+                } else {
+                    set_prev_free(target, false);
+                }
+
+                free_addr = target + current_size + HEADER;
+            }
+
+            current_addr = next_addr;
+        }
+
+        if free_addr < end {
+            write_free(free_addr, end - free_addr - HEADER);
+        } else {
+            write_u32(LAST_FREE_ADDR, 0);
+        }
+    }
+}
+
+/// Writes the header and footer of a free block of `size` bytes at `addr`,
+/// flags it in the block after, and puts it at the head of its free list.
+unsafe fn write_free(addr: u32, size: u32) {
+    write_u32(addr, 0);
+    write_u32(addr + 4, size | FREE);
+    write_u32(addr + 8, 0);
+    write_u32(addr + 8 + size, size);
+    set_prev_free(addr + HEADER + size, true);
+
+    if size > 0 {
+        let head = FREE_LISTS_ADDR + size_class(size) * 4;
+        let next = read_u32(head);
+        write_u32(addr, next);
+        if next != 0 {
+            write_u32(next + 8, addr);
+        }
+        write_u32(head, addr);
+    }
+}
+
+/// How many copies of a block of `len` elements `times` asks for. `None`
+/// when it's negative or they'd hold more than `i32::MAX` elements.
+fn repeat_count(len: u32, times: i64) -> Option<u32> {
+    if times < 0 {
+        return None;
+    }
+    if len == 0 {
+        return Some(0);
+    }
+    let total = (len as i64).checked_mul(times)?;
+    (total <= i32::MAX as i64).then_some(times as u32)
+}
+
+/// Elements `start..end` of a block of `len` elements, where a negative
+/// index counts back from the end. `None` unless they resolve to
+/// `0 <= start <= end <= len`.
+fn slice_range(len: u32, start: i32, end: i32) -> Option<(u32, u32)> {
+    let resolve = |index: i32| {
+        if index < 0 {
+            len as i64 + index as i64
+        } else {
+            index as i64
+        }
+    };
+    let (start, end) = (resolve(start), resolve(end));
+    if start < 0 || start > end || end > len as i64 {
+        return None;
+    }
+    Some((start as u32, end as u32))
+}
+
+/// Overwrites elements `start..end` of `ptr` with the elements of `source`,
+/// in place, a negative index counting back from the end. Returns
+/// `source`, or 0 when the range is out of bounds or `source` doesn't have
+/// exactly `end - start` elements.
+#[no_mangle]
+pub extern "C" fn dsplice(ptr: u32, start: i32, end: i32, source: u32) -> u32 {
+    unsafe {
+        let Some((start, end)) = slice_range(read_u32(ptr - 4), start, end) else {
+            return 0;
+        };
+        if read_u32(source - 4) != end - start {
+            return 0;
+        }
+
+        // The source may be the list itself, so the ranges can overlap.
+        let element = element_size(block_type(ptr));
+        core::ptr::copy(
+            source as *const u8,
+            (ptr + start * element) as *mut u8,
+            ((end - start) * element) as usize,
+        );
+
+        source
+    }
+}
+
+/// Pins a block so the collector never frees it. Pinned blocks are not
+/// traced, so they must not hold pointers to collectable objects.
+#[no_mangle]
+pub extern "C" fn dpin(ptr: u32) {
+    unsafe {
+        write_u32(ptr - abi::block::MARK, PINNED);
+    }
+}
+
+/// Writes the low `count` bytes of `bytes`, lowest first, into the block at
+/// `ptr` starting `offset` bytes in. Hosts that can't address dalloc memory
+/// directly fill blocks from `host_alloc_string` and `host_alloc_list` this
+/// way, up to eight bytes a call. Returns 0 without writing anything when
+/// `count` is over 8 or the bytes would run past the end of the block.
+#[no_mangle]
+pub extern "C" fn host_write_bytes(ptr: u32, offset: u32, bytes: u64, count: u32) -> u32 {
+    unsafe {
+        let capacity = read_u32(ptr - 4) * element_size(block_type(ptr));
+        if count > 8 || offset > capacity || count > capacity - offset {
+            return 0;
+        }
+        for i in 0..count {
+            write_u8(ptr + offset + i, (bytes >> (i * 8)) as u8);
+        }
+        1
+    }
+}
+
+/// Resizes a block to hold `new_len` elements, keeping the existing ones.
+/// A block grows in place when it has room to spare or the block after it
+/// is free. Otherwise it moves, and the old block is left for the
+/// collector, since other references to it may still be live.
+#[no_mangle]
+pub extern "C" fn drealloc(ptr: u32, new_len: u32) -> u32 {
+    unsafe {
+        let ty = block_type(ptr);
+        let old_len = read_u32(ptr - 4);
+
+        let old_size = block_size(ptr - HEADER);
+        let size = block_bytes(ty, new_len);
+        if new_len >= old_len && extend(ptr - HEADER, size) {
+            write_u32(ptr - 4, new_len);
+            clear_pointers(ptr + old_len * 8, ty, new_len - old_len);
+            write_u32(COLLECTED_ADDR, 0);
+            write_u32(REQUEST_ADDR, 0);
+            write_u32(
+                ALLOCATED_ADDR,
+                read_u32(ALLOCATED_ADDR) + block_size(ptr - HEADER) - old_size,
+            );
+            return ptr;
+        }
+
+        let new_addr = dalloc(ty, new_len);
+        if new_addr == 0 {
+            return 0;
+        }
+
+        let kept = if old_len < new_len { old_len } else { new_len };
+        copy(new_addr, ptr, kept * element_size(ty));
+
+        new_addr
+    }
+}
+
+/// Grows the live block at `addr` to at least `size` bytes without moving
+/// it, taking what it needs from the free block after it. Returns false,
+/// changing nothing, when that block isn't free or is too small.
+unsafe fn extend(addr: u32, size: u32) -> bool {
+    let current_size = block_size(addr);
+    if size <= current_size {
+        return true;
+    }
+    // Blocks only get as large as that by moving to pages of their own.
+    if size >= LARGE {
+        return false;
+    }
+
+    let next = addr + current_size + HEADER;
+    if next >= memory_size() || !is_free(next) {
+        return false;
+    }
+    let available = current_size + HEADER + block_size(next);
+    if size > available {
+        return false;
+    }
+
+    unlink_free(next);
+    if size + HEADER <= available {
+        set_size(addr, size);
+        write_free(addr + HEADER + size, available - size - HEADER);
+    } else {
+        set_size(addr, available);
+        set_prev_free(addr + HEADER + available, false);
+    }
+
+    // The rover may have pointed at the block just taken.
+    if read_u32(ROVER_ADDR) == next {
+        let after = addr + block_size(addr) + HEADER;
+        write_u32(ROVER_ADDR, if after < memory_size() { after } else { START });
+    }
+    true
+}
+
+/// Appends `value` to a list. The block's size field doubles as its capacity,
+/// so the list grows in place while the block has room and otherwise moves
+/// to a block of twice the capacity. Returns the (possibly moved) list, or 0
+/// when growing failed.
+#[no_mangle]
+pub extern "C" fn dappend(ptr: u32, value: u64) -> u32 {
+    unsafe {
+        let length = read_u32(ptr - 4);
+        let capacity = block_size(ptr - HEADER) / 8;
+
+        let mut target = ptr;
+        if length == capacity {
+            let new_capacity = if capacity == 0 { 4 } else { capacity * 2 };
+            target = drealloc(ptr, new_capacity);
+            if target == 0 {
+                return 0;
+            }
+        }
+
+        write_u64(target + length * 8, value);
+        write_u32(target - 4, length + 1);
+
+        target
+    }
+}
+
+/// Allocates a block of `length` elements that all hold `value`. Returns 0
+/// when no block is free.
+#[no_mangle]
+pub extern "C" fn dfill(ty: u32, value: u64, length: u32) -> u32 {
+    unsafe {
+        let ptr = dalloc(ty, length);
+        if ptr == 0 {
+            return 0;
+        }
+
+        for i in 0..length {
+            write_u64(ptr + i * 8, value);
+        }
+
+        ptr
+    }
+}
+
+/// Appends `piece` to a string builder buffer whose first `used` elements are
+/// in use. The buffer's length is its capacity; when it runs out the buffer
+/// doubles through `drealloc`, so repeated appends copy each byte a constant
+/// number of times on average. Returns the (possibly moved) buffer, or 0 when
+/// growing failed.
+#[no_mangle]
+pub extern "C" fn dbuild(buffer: u32, used: u32, piece: u32) -> u32 {
+    unsafe {
+        let capacity = read_u32(buffer - 4);
+        let piece_len = read_u32(piece - 4);
+        let needed = used + piece_len;
+
+        let mut target = buffer;
+        if needed > capacity {
+            let mut new_capacity = if capacity == 0 { 8 } else { capacity * 2 };
+            while new_capacity < needed {
+                new_capacity *= 2;
+            }
+            target = drealloc(buffer, new_capacity);
+            if target == 0 {
+                return 0;
+            }
+        }
+
+        let element = element_size(block_type(buffer));
+        copy(target + used * element, piece, piece_len * element);
+
+        target
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn dconcat(first: u32, second: u32) -> u32 {
     unsafe {
-        let ty = read_u32(first - 16);
+        let ty = block_type(first);
         let first_len = read_u32(first - 4);
         let second_len = read_u32(second - 4);
 
@@ -176,24 +1100,49 @@ pub extern "C" fn dconcat(first: u32, second: u32) -> u32 {
             return 0;
         }
 
-        for i in 0..first_len {
-            let val = read_u64(first + (i * 8));
-            write_u64(new_addr + (i * 8), val);
+        let element = element_size(ty);
+        copy(new_addr, first, first_len * element);
+        copy(new_addr + first_len * element, second, second_len * element);
+
+        new_addr
+    }
+}
+
+/// The elements of `ptr` over and over, `times` times. Traps when `times`
+/// is negative or that's more elements than a block can hold, and returns
+/// 0 when no block is free.
+#[no_mangle]
+pub extern "C" fn drepeat(ptr: u32, times: i64) -> u32 {
+    unsafe {
+        let ty = block_type(ptr);
+        let len = read_u32(ptr - 4);
+        let Some(times) = repeat_count(len, times) else {
+            core::arch::wasm32::unreachable()
+        };
+
+        let new_addr = dalloc(ty, len * times);
+        if new_addr == 0 {
+            return 0;
         }
 
-        for i in 0..second_len {
-            let val = read_u64(second + (i * 8));
-            write_u64(new_addr + ((first_len + i) * 8), val);
+        let element = element_size(ty);
+        for i in 0..times {
+            copy(new_addr + i * len * element, ptr, len * element);
         }
 
         new_addr
     }
 }
 
+/// A copy of elements `start..end` of `ptr`, a negative index counting
+/// back from the end. Traps when the range is out of bounds.
 #[no_mangle]
-pub extern "C" fn dslice(ptr: u32, start: u32, end: u32) -> u32 {
+pub extern "C" fn dslice(ptr: u32, start: i32, end: i32) -> u32 {
     unsafe {
-        let ty = read_u32(ptr - 16);
+        let Some((start, end)) = slice_range(read_u32(ptr - 4), start, end) else {
+            core::arch::wasm32::unreachable()
+        };
+        let ty = block_type(ptr);
         let new_len = end - start;
 
         let new_addr = dalloc(ty, new_len);
@@ -201,23 +1150,24 @@ pub extern "C" fn dslice(ptr: u32, start: u32, end: u32) -> u32 {
             return 0;
         }
 
-        for i in 0..new_len {
-            let val = read_u64(ptr + ((start + i) * 8));
-            write_u64(new_addr + (i * 8), val);
-        }
+        let element = element_size(ty);
+        copy(new_addr, ptr + start * element, new_len * element);
 
         new_addr
     }
 }
 
+/// Whether `list` has an element equal to `elem`. Strings and lists in it
+/// are compared by their contents, as `deq` does.
 #[no_mangle]
 pub extern "C" fn din_u64(elem: u64, list: u32) -> u32 {
     unsafe {
         let length = read_u32(list - 4);
+        let deep = block_type(list) == LISTS;
 
         for i in 0..length {
             let val = read_u64(list + (i * 8));
-            if val == elem {
+            if val == elem || (deep && deq(val as u32, elem as u32) == 1) {
                 return 1;
             }
         }
@@ -226,9 +1176,16 @@ pub extern "C" fn din_u64(elem: u64, list: u32) -> u32 {
     }
 }
 
+/// Whether two strings or lists have the same elements. Elements that are
+/// strings or lists themselves are compared the same way, and the rest bit
+/// for bit.
 #[no_mangle]
 pub extern "C" fn deq(first: u32, second: u32) -> u32 {
     unsafe {
+        if first == second {
+            return 1;
+        }
+
         let firstl = read_u32(first - 4);
         let secondl = read_u32(second - 4);
 
@@ -236,10 +1193,21 @@ pub extern "C" fn deq(first: u32, second: u32) -> u32 {
             return 0;
         }
 
-        for i in 0..firstl {
-            let vala = read_u64(first + (i * 8));
-            let valb = read_u64(second + (i * 8));
-            if vala != valb {
+        let ty = block_type(first);
+        if ty == LISTS {
+            for i in 0..firstl {
+                let a = read_u64(first + i * 8) as u32;
+                let b = read_u64(second + i * 8) as u32;
+                if deq(a, b) == 0 {
+                    return 0;
+                }
+            }
+            return 1;
+        }
+
+        let len = firstl * element_size(ty);
+        for i in 0..len {
+            if *((first + i) as *const u8) != *((second + i) as *const u8) {
                 return 0;
             }
         }
@@ -266,7 +1234,7 @@ pub extern "C" fn ditoa(i: i64) -> u32 {
             }
         }
 
-        let str_addr = dalloc(2, digits);
+        let str_addr = dalloc(BYTES, digits);
         if str_addr == 0 {
             return 0;
         }
@@ -276,11 +1244,11 @@ pub extern "C" fn ditoa(i: i64) -> u32 {
         let num_digits = digits - offset;
 
         if i < 0 {
-            write_u64(str_addr, b'-' as u64);
+            write_u8(str_addr, b'-');
         }
         for j in 0..num_digits {
             let digit = (num % 10) as u8 + b'0';
-            write_u64(str_addr + ((offset + num_digits - j - 1) * 8), digit as u64);
+            write_u8(str_addr + offset + num_digits - j - 1, digit);
             num /= 10;
         }
 
@@ -291,22 +1259,21 @@ pub extern "C" fn ditoa(i: i64) -> u32 {
 #[no_mangle]
 pub extern "C" fn dbtoa(i: u32) -> u32 {
     unsafe {
-        if i == 0 {
-            let str_addr = dalloc(2, 5);
-            write_u64(str_addr, b'f' as u64);
-            write_u64(str_addr + 8, b'a' as u64);
-            write_u64(str_addr + 16, b'l' as u64);
-            write_u64(str_addr + 24, b's' as u64);
-            write_u64(str_addr + 32, b'e' as u64);
-            return str_addr;
+        // Kept in an immediate rather than a byte string, which would live in
+        // this module's data section, inside the memory the heap hands out.
+        let (text, len) = if i == 0 {
+            (u64::from_le_bytes(*b"false\0\0\0"), 5)
         } else {
-            let str_addr = dalloc(2, 4);
-            write_u64(str_addr, b't' as u64);
-            write_u64(str_addr + 8, b'r' as u64);
-            write_u64(str_addr + 16, b'u' as u64);
-            write_u64(str_addr + 24, b'e' as u64);
-            return str_addr;
+            (u64::from_le_bytes(*b"true\0\0\0\0"), 4)
+        };
+        let str_addr = dalloc(BYTES, len);
+        if str_addr == 0 {
+            return 0;
         }
+        for at in 0..len {
+            write_u8(str_addr + at, (text >> (at * 8)) as u8);
+        }
+        str_addr
     }
 }
 
@@ -319,17 +1286,17 @@ pub extern "C" fn dftoa(value: f64) -> u32 {
         let frac_part = (frac_abs * 1000000.0 + 0.5) as u64;
 
         let int_str = ditoa(int_part);
-        let dot_str = dalloc(2, 1);
-        write_u64(dot_str, b'.' as u64);
+        let dot_str = dalloc(BYTES, 1);
+        write_u8(dot_str, b'.');
 
         let frac_str = ditoa(frac_part as i64);
         let frac_len = read_u32(frac_str - 4);
 
         let zeros_needed = 6 - frac_len;
         let padded_frac = if zeros_needed > 0 {
-            let zeros = dalloc(2, zeros_needed);
+            let zeros = dalloc(BYTES, zeros_needed);
             for i in 0..zeros_needed {
-                write_u64(zeros + i * 8, b'0' as u64);
+                write_u8(zeros + i, b'0');
             }
             dconcat(zeros, frac_str)
         } else {
@@ -340,3 +1307,40 @@ pub extern "C" fn dftoa(value: f64) -> u32 {
         dconcat(with_dot, padded_frac)
     }
 }
+
+/// The number `s` spells, with nothing else around it, recording in the
+/// header whether it did.
+unsafe fn parse<T: Default>(s: u32, read: unsafe fn(u32, u32) -> Option<(T, u32)>) -> T {
+    let len = read_u32(s - 4);
+    // A `+` may lead, though not ahead of a `-`.
+    let at = (len > 0 && *(s as *const u8) == b'+') as u32;
+    let parsed = match read(s, at) {
+        Some((value, end)) if end == len && (at == 0 || *((s + 1) as *const u8) != b'-') => {
+            Some(value)
+        }
+        _ => None,
+    };
+    write_u32(PARSE_FAILED_ADDR, parsed.is_none() as u32);
+    parsed.unwrap_or_default()
+}
+
+/// Reads the integer `s` spells, the way `ditoa` writes it, with an optional
+/// leading `+`. Returns 0 and sets the flag `dparse_failed` reads when it
+/// isn't one, or doesn't fit.
+#[no_mangle]
+pub extern "C" fn datoi(s: u32) -> i64 {
+    unsafe { parse(s, json::read_integer) }
+}
+
+/// Reads the number `s` spells, with an optional fraction and exponent.
+/// Returns 0 and sets the flag `dparse_failed` reads when it isn't one.
+#[no_mangle]
+pub extern "C" fn datof(s: u32) -> f64 {
+    unsafe { parse(s, json::read_float) }
+}
+
+/// Whether the last `datoi` or `datof` failed.
+#[no_mangle]
+pub extern "C" fn dparse_failed() -> u32 {
+    unsafe { read_u32(PARSE_FAILED_ADDR) }
+}
@@ -0,0 +1,46 @@
+//! The math functions wasm has no instruction for. They live here, beside
+//! the number formatting, so programs needn't bring their own.
+
+/// `x` rounded to the nearest integer, halves away from zero, where wasm's
+/// `f64.nearest` rounds them to even.
+#[no_mangle]
+pub extern "C" fn dround(x: f64) -> f64 {
+    libm::round(x)
+}
+
+#[no_mangle]
+pub extern "C" fn dsin(x: f64) -> f64 {
+    libm::sin(x)
+}
+
+#[no_mangle]
+pub extern "C" fn dcos(x: f64) -> f64 {
+    libm::cos(x)
+}
+
+#[no_mangle]
+pub extern "C" fn dtan(x: f64) -> f64 {
+    libm::tan(x)
+}
+
+#[no_mangle]
+pub extern "C" fn dasin(x: f64) -> f64 {
+    libm::asin(x)
+}
+
+#[no_mangle]
+pub extern "C" fn dacos(x: f64) -> f64 {
+    libm::acos(x)
+}
+
+#[no_mangle]
+pub extern "C" fn datan(x: f64) -> f64 {
+    libm::atan(x)
+}
+
+/// The angle of the point (`x`, `y`) from the positive x axis, in
+/// `-pi..=pi`.
+#[no_mangle]
+pub extern "C" fn datan2(y: f64, x: f64) -> f64 {
+    libm::atan2(y, x)
+}
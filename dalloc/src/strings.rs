@@ -0,0 +1,300 @@
+//! The string methods: `length`, `find`, `substring`, `split`, `to_upper`,
+//! `to_lower`, `trim` and `replace`, the `ord` and `chr` conversions, and
+//! the comparison `sort` orders strings with.
+//!
+//! Strings hold UTF-8, and the methods count and index in characters rather
+//! than bytes. Changing case and trimming only know about ASCII, leaving
+//! every other character as it is. Methods that make a new string return 0
+//! when the heap is full, so the call can be retried after a collection.
+
+use super::{
+    copy, dalloc, dfree, read_u32, read_u64, write_u32, write_u64, write_u8, BYTES, COLLECTED_ADDR,
+    LISTS,
+};
+
+unsafe fn len(s: u32) -> u32 {
+    read_u32(s - 4)
+}
+
+unsafe fn byte_at(s: u32, at: u32) -> u8 {
+    *((s + at) as *const u8)
+}
+
+/// Whether `byte` continues a character rather than starting one.
+fn continues(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r' | 0x0c)
+}
+
+/// Whether `needle` occurs in `s` at byte `at`.
+unsafe fn occurs(s: u32, at: u32, needle: u32) -> bool {
+    let needle_len = len(needle);
+    if at + needle_len > len(s) {
+        return false;
+    }
+    (0..needle_len).all(|i| byte_at(s, at + i) == byte_at(needle, i))
+}
+
+/// The byte at which `needle` next occurs in `s` from byte `from`, if it
+/// does.
+unsafe fn next_occurrence(s: u32, from: u32, needle: u32) -> Option<u32> {
+    let end = len(s).checked_sub(len(needle))?;
+    (from..=end).find(|&at| occurs(s, at, needle))
+}
+
+/// The byte at which character `index` of `s` starts, or the length of `s`
+/// for the index just past its last character. Traps past that.
+unsafe fn char_offset(s: u32, index: i64) -> u32 {
+    if index < 0 {
+        core::arch::wasm32::unreachable()
+    }
+    let mut chars = 0;
+    for at in 0..len(s) {
+        if !continues(byte_at(s, at)) {
+            if chars == index {
+                return at;
+            }
+            chars += 1;
+        }
+    }
+    if chars != index {
+        core::arch::wasm32::unreachable()
+    }
+    len(s)
+}
+
+/// The characters in `s`.
+#[no_mangle]
+pub extern "C" fn dchars(s: u32) -> u32 {
+    unsafe { (0..len(s)).filter(|&at| !continues(byte_at(s, at))).count() as u32 }
+}
+
+/// The index of the character where `needle` first occurs in `s`, or -1
+/// when it doesn't.
+#[no_mangle]
+pub extern "C" fn dfind(s: u32, needle: u32) -> i64 {
+    unsafe {
+        match next_occurrence(s, 0, needle) {
+            Some(at) => (0..at).filter(|&i| !continues(byte_at(s, i))).count() as i64,
+            None => -1,
+        }
+    }
+}
+
+/// The characters of `s` from `start` up to `end`. Traps when they aren't
+/// a range of its characters.
+#[no_mangle]
+pub extern "C" fn dsubstring(s: u32, start: i64, end: i64) -> u32 {
+    unsafe {
+        if start > end {
+            core::arch::wasm32::unreachable()
+        }
+        let first = char_offset(s, start);
+        let last = char_offset(s, end);
+        let addr = dalloc(BYTES, last - first);
+        if addr == 0 {
+            return 0;
+        }
+        copy(addr, s + first, last - first);
+        addr
+    }
+}
+
+/// The code point of `s`, which must hold exactly one character.
+#[no_mangle]
+pub extern "C" fn dord(s: u32) -> i64 {
+    unsafe {
+        let bytes = core::slice::from_raw_parts(s as *const u8, len(s) as usize);
+        let mut chars = core::str::from_utf8(bytes).unwrap_or_default().chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => c as i64,
+            _ => core::arch::wasm32::unreachable(),
+        }
+    }
+}
+
+/// The one-character string for the code point `code`. Traps when it isn't
+/// one.
+#[no_mangle]
+pub extern "C" fn dchr(code: i64) -> u32 {
+    let Some(c) = u32::try_from(code).ok().and_then(char::from_u32) else {
+        core::arch::wasm32::unreachable()
+    };
+    let mut buffer = [0; 4];
+    let encoded = c.encode_utf8(&mut buffer);
+    unsafe {
+        let addr = dalloc(BYTES, encoded.len() as u32);
+        if addr == 0 {
+            return 0;
+        }
+        for (i, byte) in encoded.bytes().enumerate() {
+            write_u8(addr + i as u32, byte);
+        }
+        addr
+    }
+}
+
+/// How many times `needle`, which isn't empty, occurs in `s` without
+/// overlapping.
+unsafe fn occurrences(s: u32, needle: u32) -> u32 {
+    let mut count = 0;
+    let mut at = 0;
+    while let Some(found) = next_occurrence(s, at, needle) {
+        count += 1;
+        at = found + len(needle);
+    }
+    count
+}
+
+/// The bytes of the piece of `s` starting at byte `at`, up to the next
+/// occurrence of `sep`. An empty `sep` makes every character a piece.
+unsafe fn piece_len(s: u32, at: u32, sep: u32) -> u32 {
+    if len(sep) == 0 {
+        let mut end = at + 1;
+        while end < len(s) && continues(byte_at(s, end)) {
+            end += 1;
+        }
+        return end - at;
+    }
+    next_occurrence(s, at, sep).unwrap_or(len(s)) - at
+}
+
+/// A list of the pieces of `s` between the occurrences of `sep`.
+#[no_mangle]
+pub extern "C" fn dsplit(s: u32, sep: u32) -> u32 {
+    unsafe {
+        let step = len(sep);
+        let count = if step == 0 {
+            dchars(s)
+        } else {
+            occurrences(s, sep) + 1
+        };
+
+        // Every piece is a block of its own. A failed `dalloc` only grows the
+        // heap right after a collection, so each allocation here gets the
+        // same chance as the first.
+        let collected = read_u32(COLLECTED_ADDR);
+        let list = dalloc(LISTS, count);
+        if list == 0 {
+            return 0;
+        }
+        let mut at = 0;
+        for i in 0..count {
+            let piece = piece_len(s, at, sep);
+            write_u32(COLLECTED_ADDR, collected);
+            let addr = dalloc(BYTES, piece);
+            if addr == 0 {
+                for j in 0..i {
+                    dfree(read_u64(list + j * 8) as u32);
+                }
+                dfree(list);
+                return 0;
+            }
+            copy(addr, s + at, piece);
+            write_u64(list + i * 8, addr as u64);
+            at += piece + step;
+        }
+        list
+    }
+}
+
+/// Negative when `a` comes before `b` byte by byte, as a prefix does, 0
+/// when they're equal and positive otherwise.
+#[no_mangle]
+pub extern "C" fn dcompare(a: u32, b: u32) -> i32 {
+    unsafe {
+        let shared = len(a).min(len(b));
+        for at in 0..shared {
+            let (x, y) = (byte_at(a, at), byte_at(b, at));
+            if x != y {
+                return x as i32 - y as i32;
+            }
+        }
+        len(a) as i32 - len(b) as i32
+    }
+}
+
+/// A copy of `s` with its ASCII letters shifted by `shift` when they fall
+/// in `from..=to`.
+unsafe fn change_case(s: u32, from: u8, to: u8, shift: u8) -> u32 {
+    let addr = dalloc(BYTES, len(s));
+    if addr == 0 {
+        return 0;
+    }
+    for at in 0..len(s) {
+        let c = byte_at(s, at);
+        write_u8(
+            addr + at,
+            if (from..=to).contains(&c) {
+                c ^ shift
+            } else {
+                c
+            },
+        );
+    }
+    addr
+}
+
+#[no_mangle]
+pub extern "C" fn dupper(s: u32) -> u32 {
+    unsafe { change_case(s, b'a', b'z', 0x20) }
+}
+
+#[no_mangle]
+pub extern "C" fn dlower(s: u32) -> u32 {
+    unsafe { change_case(s, b'A', b'Z', 0x20) }
+}
+
+/// `s` without the ASCII whitespace at either end.
+#[no_mangle]
+pub extern "C" fn dtrim(s: u32) -> u32 {
+    unsafe {
+        let mut first = 0;
+        let mut last = len(s);
+        while first < last && is_space(byte_at(s, first)) {
+            first += 1;
+        }
+        while last > first && is_space(byte_at(s, last - 1)) {
+            last -= 1;
+        }
+        let addr = dalloc(BYTES, last - first);
+        if addr == 0 {
+            return 0;
+        }
+        copy(addr, s + first, last - first);
+        addr
+    }
+}
+
+/// `s` with every occurrence of `from` replaced by `to`, left to right. An
+/// empty `from` replaces nothing.
+#[no_mangle]
+pub extern "C" fn dreplace(s: u32, from: u32, to: u32) -> u32 {
+    unsafe {
+        let count = if len(from) == 0 {
+            0
+        } else {
+            occurrences(s, from)
+        };
+
+        let addr = dalloc(BYTES, len(s) - count * len(from) + count * len(to));
+        if addr == 0 {
+            return 0;
+        }
+        let mut at = 0;
+        let mut out = addr;
+        for _ in 0..count {
+            let found = next_occurrence(s, at, from).unwrap_or(len(s));
+            copy(out, s + at, found - at);
+            out += found - at;
+            copy(out, to, len(to));
+            out += len(to);
+            at = found + len(from);
+        }
+        copy(out, s + at, len(s) - at);
+        addr
+    }
+}
@@ -265,6 +265,62 @@ interface CompilerExports {
   wasm_error_len: () => number;
 }
 
+interface ShadowExports {
+  heap_used: () => number;
+  heap_free: () => number;
+  gc_count: () => number;
+  largest_free_block: () => number;
+  host_alloc_string: (length: number) => number;
+  heap_snapshot: () => number;
+}
+
+// Globals a program that awaits exports; see src/host.rs.
+interface AsyncExports {
+  async_state: WebAssembly.Global;
+  async_request: WebAssembly.Global;
+  async_argument: WebAssembly.Global;
+  async_result: WebAssembly.Global;
+}
+
+const ASYNC_UNWINDING = 1;
+const ASYNC_REWINDING = 2;
+const ASYNC_EXITING = 3;
+const REQUEST_SLEEP = 1;
+const REQUEST_FETCH = 2;
+
+// What the lists and strings in a heap snapshot hold, by dalloc block type;
+// see abi::dtype.
+const BLOCK_KINDS: Record<number, string> = {
+  1: "lists of values",
+  2: "lists of structs",
+  3: "lists of lists",
+  4: "strings",
+};
+
+// Sums up the snapshot shadow's heap_snapshot writes (see abi::snapshot):
+// how many objects of each type there are, their bytes, and how many of
+// them nothing reaches any more.
+function heapProfile(shadow: ShadowExports, memory: WebAssembly.Memory): string[] {
+  const address = shadow.heap_snapshot();
+  const view = new DataView(memory.buffer);
+  const count = view.getUint32(address, true);
+  const groups = new Map<string, { objects: number; bytes: number; garbage: number }>();
+  for (let i = 0; i < count; i++) {
+    const record = address + 4 + i * 12;
+    const type = view.getUint32(record, true);
+    const flags = view.getUint32(record + 8, true);
+    const name = flags & 2 ? BLOCK_KINDS[type] ?? `block type ${type}` : `struct type ${type}`;
+    const group = groups.get(name) ?? { objects: 0, bytes: 0, garbage: 0 };
+    group.objects += 1;
+    group.bytes += view.getUint32(record + 4, true);
+    if (!(flags & 1)) group.garbage += 1;
+    groups.set(name, group);
+  }
+  return [...groups]
+    .sort(([, a], [, b]) => b.bytes - a.bytes)
+    .map(([name, g]) => `//   ${name}: ${g.objects} (${g.bytes} bytes), ${g.garbage} unreachable`);
+}
+
 interface RuntimeModules {
   alloc: WebAssembly.Instance;
   dalloc: WebAssembly.Instance;
@@ -443,11 +499,8 @@ fn main(): integer {
         // Length is stored at ptr - 4
         const length = new DataView(dallocMemory.buffer).getUint32(ptr - 4, true);
 
-        // Each char is stored as 8 bytes (only first byte used)
-        const chars: number[] = [];
-        for (let i = 0; i < length; i++) {
-          chars.push(data[ptr + i * 8]);
-        }
+        // Strings are packed, one byte per char
+        const chars = Array.from(data.subarray(ptr, ptr + length));
 
         const str = String.fromCharCode(...chars);
         printOutput.push(str);
@@ -455,7 +508,16 @@ fn main(): integer {
 
       // Instantiate the compiled program with runtime imports
       const programModule = await WebAssembly.instantiate(wasmBytes, {
-        env: { print: printFn },
+        // The playground has no command line, so main(args) gets an empty list,
+        // and no environment, so env(name) is always null.
+        env: {
+          print: printFn,
+          arg_count: () => 0,
+          arg_length: () => 0,
+          arg_copy: () => {},
+          env_length: () => -1,
+          env_copy: () => {},
+        },
         alloc: runtime.alloc.exports,
         dalloc: runtime.dalloc.exports,
         shadow: runtime.shadow.exports,
@@ -464,10 +526,57 @@ fn main(): integer {
       // Run main with (0, 0, 0) args
       const programExports = programModule.instance.exports as { main?: (a: number, b: bigint, c: number) => bigint };
       if (programExports.main) {
-        const result = programExports.main(0, BigInt(0), 0);
+        const shadow = runtime.shadow.exports as unknown as ShadowExports;
+        let result = programExports.main(0, BigInt(0), 0);
+
+        // An await returns to us with a request; answer it and resume main.
+        const asyncExports = programModule.instance.exports as unknown as Partial<AsyncExports>;
+        while (asyncExports.async_state?.value === ASYNC_UNWINDING) {
+          const { async_request, async_argument, async_result, async_state } = asyncExports as AsyncExports;
+          const argument = async_argument.value as bigint;
+          if (async_request.value === REQUEST_SLEEP) {
+            const started = performance.now();
+            await new Promise((resolve) => setTimeout(resolve, Number(argument)));
+            async_result.value = BigInt(Math.round(performance.now() - started));
+          } else if (async_request.value === REQUEST_FETCH) {
+            const ptr = Number(argument);
+            const length = new DataView(dallocMemory.buffer).getUint32(ptr - 4, true);
+            const url = new TextDecoder().decode(new Uint8Array(dallocMemory.buffer, ptr, length));
+            let body: string;
+            try {
+              body = await (await fetch(url)).text();
+            } catch (err) {
+              body = `${err}`;
+            }
+            const bytes = new TextEncoder().encode(body);
+            const string = shadow.host_alloc_string(bytes.length);
+            if (string === 0) {
+              throw new Error("out of memory for the fetched body");
+            }
+            new Uint8Array(dallocMemory.buffer).set(bytes, string);
+            async_result.value = BigInt(string);
+          } else {
+            throw new Error(`unknown async request ${async_request.value}`);
+          }
+          async_state.value = ASYNC_REWINDING;
+          result = programExports.main(0, BigInt(0), 0);
+        }
+
+        // exit(code) unwinds out of main and leaves its code behind.
+        const exited = asyncExports.async_state?.value === ASYNC_EXITING;
+        if (exited) {
+          result = (asyncExports as AsyncExports).async_argument.value as bigint;
+        }
+
+
+        const memoryText = `// heap: ${shadow.heap_used()} bytes used, ${shadow.heap_free()} free ` +
+          `(largest block ${shadow.largest_free_block()}), ${shadow.gc_count()} collections`;
+        const profile = heapProfile(shadow, runtime.shadow.exports.memory as WebAssembly.Memory);
+        const profileText = profile.length > 0 ? `\n${profile.join("\n")}` : "";
+        const returned = exited ? `Exited with: ${result}` : `Main returned: ${result}`;
         const outputText = printOutput.length > 0
-          ? printOutput.join("\n") + `\n\nMain returned: ${result}`
-          : `Main returned: ${result}`;
+          ? printOutput.join("\n") + `\n\n${returned}\n${memoryText}${profileText}`
+          : `${returned}\n${memoryText}${profileText}`;
         setOutput(outputText);
       } else {
         setOutput("// error: no main function exported");
@@ -5,7 +5,14 @@ extern "C" {
     fn read_alloc(addr: u32) -> u32;
     fn write_alloc(addr: u32, val: u32);
     fn sweep() -> u32;
+    fn enqueue() -> u32;
+    fn queued_after(pointer: u32) -> u32;
     fn alloc_memory_size() -> u32;
+    fn alloc_used() -> u32;
+    fn alloc_free() -> u32;
+    fn alloc_allocated() -> u32;
+    fn alloc_budget(bytes: u32);
+    fn alloc_next(pointer: u32) -> u32;
 }
 
 #[link(wasm_import_module = "dalloc")]
@@ -15,15 +22,65 @@ extern "C" {
     #[link_name = "sweep"]
     fn dsweep() -> u32;
     fn dalloc_memory_size() -> u32;
+    fn dblock(ptr: u32) -> u32;
+    fn dfragmented() -> u32;
+    fn dplan();
+    fn dcompact();
+    fn dalloc_used() -> u32;
+    fn dalloc_free() -> u32;
+    fn dalloc_largest_free() -> u32;
+    fn dalloc_allocated() -> u32;
+    fn dalloc(ty: u32, length: u32) -> u32;
+    fn dpin(ptr: u32);
+    fn dbudget(bytes: u32);
+    fn dnext(ptr: u32) -> u32;
 }
 
-const TYPE_TABLE_INDEX: u32 = 12;
-const TYPE_TABLE_RECORD_SIZE: u32 = 16;
+const TYPE_TABLE_INDEX: u32 = abi::alloc::TYPE_TABLE;
+const TYPE_TABLE_RECORD_SIZE: u32 = abi::alloc::TYPE_RECORD_SIZE;
 
-const STACK_POINTER: u32 = 24;
-const FRAME_POINTER: u32 = 24;
-const STACK_POINTER_ADDR: u32 = 16;
-const FRAME_POINTER_ADDR: u32 = 20;
+/// Type id of the tagged union behind nullable and errorable values, and the
+/// tags that say its value slot holds a pointer.
+const TAGGED_UNION: u32 = abi::tag::TAGGED_UNION;
+const TAG_ERROR: u32 = abi::tag::ERROR;
+const TAG_STRUCT: u32 = abi::tag::STRUCT;
+const TAG_LIST: u32 = abi::tag::LIST;
+
+/// Dalloc block types whose elements are pointers.
+const STRUCT_POINTERS: u32 = abi::dtype::STRUCTS;
+const LIST_POINTERS: u32 = abi::dtype::LISTS;
+/// Dalloc block types the host can allocate.
+const PRIMITIVES: u32 = abi::dtype::PRIMITIVE;
+const BYTES: u32 = abi::dtype::BYTES;
+
+/// Dalloc mark of a block a root refers to, which compaction leaves in
+/// place so the root stays valid.
+const HELD: u32 = 1;
+/// Dalloc mark of blocks that live for the whole program.
+const PINNED: u32 = 2;
+/// Set in a dalloc block's info word once pointer fix-up has visited it.
+const VISITED: u32 = abi::block::VISITED;
+/// Offsets back from a dalloc pointer to its block's header words.
+const MARK: u32 = abi::block::MARK;
+const INFO: u32 = abi::block::INFO;
+const LENGTH: u32 = abi::block::LENGTH;
+
+const PAGE_SIZE: u32 = 65536;
+
+/// Words generated code parks operands in while it collects and retries.
+const SCRATCHPAD: [u32; 3] = abi::shadow::SCRATCHPAD;
+
+/// Roots the host holds through `pin`, as `(memory, pointer)` slots laid
+/// out like stack slots. Memory 0 marks a free slot.
+const HOST_ROOTS: u32 = 32;
+const HOST_ROOT_SLOTS: u32 = 256;
+
+const STACK_POINTER: u32 = HOST_ROOTS + HOST_ROOT_SLOTS * 8;
+const FRAME_POINTER: u32 = STACK_POINTER;
+const STACK_POINTER_ADDR: u32 = abi::shadow::STACK_POINTER;
+const FRAME_POINTER_ADDR: u32 = abi::shadow::FRAME_POINTER;
+const GC_COUNT_ADDR: u32 = abi::shadow::GC_COUNT;
+const GC_THRESHOLD_ADDR: u32 = abi::shadow::GC_THRESHOLD;
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -38,18 +95,63 @@ unsafe fn write_u32(addr: u32, val: u32) {
     *(addr as *mut u32) = val;
 }
 
+/// Empties the stack and the host's roots. Traps when the program was
+/// compiled against an ABI `version` this build can't serve.
 #[no_mangle]
-pub extern "C" fn init() {
+pub extern "C" fn init(version: u32) {
+    if !abi::compatible(version) {
+        core::arch::wasm32::unreachable()
+    }
     unsafe {
         write_u32(STACK_POINTER_ADDR, STACK_POINTER);
         write_u32(FRAME_POINTER_ADDR, FRAME_POINTER);
+        write_u32(GC_COUNT_ADDR, 0);
+        write_u32(GC_THRESHOLD_ADDR, 0);
+        for slot in 0..HOST_ROOT_SLOTS {
+            write_u32(HOST_ROOTS + slot * 8, 0);
+        }
+    }
+}
+
+/// Keeps the object at `pointer` alive, and where it is, until `unpin` is
+/// called with the returned handle, so the host can hold on to a result
+/// across calls that may collect. `memory` is 1 for a struct and 2 for a
+/// list or string, as in `set`. Returns 0 when every slot is taken.
+#[no_mangle]
+pub extern "C" fn pin(pointer: u32, memory: u32) -> u32 {
+    unsafe {
+        for slot in 0..HOST_ROOT_SLOTS {
+            let addr = HOST_ROOTS + slot * 8;
+            if read_u32(addr) == 0 {
+                write_u32(addr, memory);
+                write_u32(addr + 4, pointer);
+                return slot + 1;
+            }
+        }
+        0
+    }
+}
+
+/// Releases a handle from `pin`. The object is collected once nothing else
+/// refers to it.
+#[no_mangle]
+pub extern "C" fn unpin(handle: u32) {
+    unsafe {
+        if handle == 0 || handle > HOST_ROOT_SLOTS {
+            return;
+        }
+        let addr = HOST_ROOTS + (handle - 1) * 8;
+        write_u32(addr, 0);
+        write_u32(addr + 4, 0);
     }
 }
 
 #[no_mangle]
 pub extern "C" fn push(size: u32) {
     unsafe {
-        let offset = size * 8 + 4;
+        // The saved frame pointer takes a whole untyped slot so every frame
+        // stays aligned with the 8-byte stride `mark` walks the stack in.
+        let offset = size * 8 + 8;
         let sp = read_u32(STACK_POINTER_ADDR);
         let fp = read_u32(FRAME_POINTER_ADDR);
 
@@ -58,6 +160,7 @@ pub extern "C" fn push(size: u32) {
             write_u32(sp + (i * 8) + 4, 0);
         }
 
+        write_u32(sp + offset - 8, 0);
         write_u32(sp + offset - 4, fp);
         write_u32(FRAME_POINTER_ADDR, sp);
         write_u32(STACK_POINTER_ADDR, sp + offset);
@@ -75,6 +178,34 @@ pub extern "C" fn pop() {
     }
 }
 
+/// Saves the scratchpad in a frame of its own, rooting the words that point
+/// at live dalloc blocks, so code run between a collection and the retry of
+/// the allocation that triggered it can use the scratchpad too. `unpark`
+/// puts it back.
+#[no_mangle]
+pub extern "C" fn park() {
+    unsafe {
+        push(SCRATCHPAD.len() as u32);
+        for (index, addr) in SCRATCHPAD.into_iter().enumerate() {
+            let val = read_u32(addr);
+            let ty = if dblock(val) == 1 { 2 } else { 0 };
+            set(val, index as u32, ty);
+        }
+    }
+}
+
+/// Restores the scratchpad `park` saved and pops its frame.
+#[no_mangle]
+pub extern "C" fn unpark() {
+    unsafe {
+        let fp = read_u32(FRAME_POINTER_ADDR);
+        for (index, addr) in SCRATCHPAD.into_iter().enumerate() {
+            write_u32(addr, read_u32(fp + (index as u32 * 8) + 4));
+        }
+    }
+    pop();
+}
+
 #[no_mangle]
 pub extern "C" fn set(value: u32, index: u32, ty: u32) {
     unsafe {
@@ -84,78 +215,483 @@ pub extern "C" fn set(value: u32, index: u32, ty: u32) {
     }
 }
 
+/// Roots `value` in slot `index` of the frame `main` pushes for the
+/// program's globals before its own. Being the first frame on the stack, it
+/// stays there after `main` returns, while hosts call exported functions.
+#[no_mangle]
+pub extern "C" fn set_global(value: u32, index: u32, ty: u32) {
+    unsafe {
+        write_u32(STACK_POINTER + (index * 8), ty);
+        write_u32(STACK_POINTER + (index * 8) + 4, value);
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn mark() {
     unsafe {
         let sp = read_u32(STACK_POINTER_ADDR);
-        let start = STACK_POINTER;
-        let size = (sp - start) / 8;
+        let mut worklist = Worklist { base: sp, top: sp };
 
-        for i in 0..size {
-            let ty = read_u32(start + (i * 8));
-            let val = read_u32(start + (i * 8) + 4);
+        for_each_root(|pointer, memory| worklist.visit(pointer, memory));
 
-            if ty == 1 {
-                mark_pointer(val, 1);
-            } else if ty == 2 {
-                mark_pointer(val, 2);
-            }
+        while let Some((pointer, memory)) = worklist.pop() {
+            trace(pointer, memory, &mut worklist);
         }
     }
 }
 
-#[no_mangle]
-pub extern "C" fn mark_pointer(pointer: u32, memory: u32) {
-    unsafe {
+/// Calls `f` with every `(pointer, memory)` pair in a shadow stack slot or a
+/// host root, with each scratchpad word that points at a live dalloc block,
+/// and with each struct queued for its drop method.
+unsafe fn for_each_root(mut f: impl FnMut(u32, u32)) {
+    for slot in 0..HOST_ROOT_SLOTS {
+        let memory = read_u32(HOST_ROOTS + slot * 8);
+        if memory == 1 || memory == 2 {
+            f(read_u32(HOST_ROOTS + slot * 8 + 4), memory);
+        }
+    }
+
+    let sp = read_u32(STACK_POINTER_ADDR);
+    let start = STACK_POINTER;
+    let size = (sp - start) / 8;
+
+    for i in 0..size {
+        let ty = read_u32(start + (i * 8));
+        let val = read_u32(start + (i * 8) + 4);
+
+        if ty == 1 || ty == 2 {
+            f(val, ty);
+        }
+    }
+
+    for addr in SCRATCHPAD {
+        let val = read_u32(addr);
+        if dblock(val) == 1 {
+            f(val, 2);
+        }
+    }
+
+    let mut queued = queued_after(0);
+    while queued != 0 {
+        f(queued, 1);
+        queued = queued_after(queued);
+    }
+}
+
+/// Objects that are marked but whose fields haven't been traced yet, kept as
+/// `(pointer, memory)` pairs in shadow memory just above the stack. Tracing
+/// pops from here rather than recursing, so deep object graphs can't
+/// overflow the host stack.
+struct Worklist {
+    base: u32,
+    top: u32,
+}
+
+impl Worklist {
+    /// Marks an object and queues it for tracing, unless it is null or
+    /// already marked. Marking on the way in means each object is queued at
+    /// most once.
+    unsafe fn visit(&mut self, pointer: u32, memory: u32) {
         if pointer == 0 {
             return;
         }
         if memory == 1 {
-            if pointer < alloc_memory_size() && read_alloc(pointer - 4) != 1 {
-                let ty = read_alloc(pointer - 8);
-
-                write_alloc(pointer - 4, 1);
-
-                let scount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 8);
-                for i in 0..scount {
-                    let field_addr = pointer + (i * 8);
-                    let field_ptr = read_alloc(field_addr);
-                    mark_pointer(field_ptr, 1);
-                }
-
-                let lcount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 12);
-                for i in 0..lcount {
-                    let list_addr = pointer + (scount * 8) + (i * 8);
-                    let list_ptr = read_alloc(list_addr);
-                    mark_pointer(list_ptr, 2);
-                }
+            if pointer >= alloc_memory_size() || read_alloc(pointer - 4) == 1 {
+                return;
             }
+            write_alloc(pointer - 4, 1);
+        } else {
+            // Skip blocks that are already marked or pinned.
+            if pointer >= dalloc_memory_size() || read_dalloc(pointer - MARK) != 0 {
+                return;
+            }
+            write_dalloc(pointer - MARK, 1);
+        }
+
+        self.push(pointer, memory);
+    }
+
+    /// Like `visit`, for the walk that rewrites pointers after `dplan`. Dalloc
+    /// marks hold forwarding addresses by then, so blocks are flagged through
+    /// their type instead.
+    unsafe fn reach(&mut self, pointer: u32, memory: u32) {
+        if pointer == 0 {
+            return;
+        }
+        if memory == 1 {
+            if pointer >= alloc_memory_size() || read_alloc(pointer - 4) == 1 {
+                return;
+            }
+            write_alloc(pointer - 4, 1);
+        } else {
+            if pointer >= dalloc_memory_size() {
+                return;
+            }
+            let info = read_dalloc(pointer - INFO);
+            if info & VISITED != 0 {
+                return;
+            }
+            write_dalloc(pointer - INFO, info | VISITED);
+        }
+
+        self.push(pointer, memory);
+    }
+
+    unsafe fn push(&mut self, pointer: u32, memory: u32) {
+        if self.top + 8 > shadow_memory_size()
+            && core::arch::wasm32::memory_grow(0, 1) == usize::MAX
+        {
+            core::arch::wasm32::unreachable();
+        }
+        write_u32(self.top, pointer);
+        write_u32(self.top + 4, memory);
+        self.top += 8;
+    }
+
+    unsafe fn pop(&mut self) -> Option<(u32, u32)> {
+        if self.top == self.base {
+            return None;
+        }
+        self.top -= 8;
+        Some((read_u32(self.top), read_u32(self.top + 4)))
+    }
+}
+
+/// The type of the dalloc block at `pointer`, kept in its info word.
+unsafe fn block_type(pointer: u32) -> u32 {
+    (read_dalloc(pointer - INFO) & abi::block::TYPE_MASK) >> abi::block::TYPE_SHIFT
+}
+
+/// Visits everything a marked object points to.
+unsafe fn trace(pointer: u32, memory: u32, worklist: &mut Worklist) {
+    if memory == 1 {
+        let ty = read_alloc(pointer - 8);
+
+        if ty == TAGGED_UNION {
+            let tag = read_alloc(pointer);
+            let value = read_alloc(pointer + 8);
+            if tag == TAG_ERROR || tag == TAG_STRUCT {
+                worklist.visit(value, 1);
+            } else if tag == TAG_LIST {
+                worklist.visit(value, 2);
+            }
+            return;
+        }
+
+        let scount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 8);
+        for i in 0..scount {
+            worklist.visit(read_alloc(pointer + (i * 8)), 1);
+        }
+
+        let lcount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 12);
+        for i in 0..lcount {
+            worklist.visit(read_alloc(pointer + (scount * 8) + (i * 8)), 2);
+        }
+    } else {
+        // Only lists of pointers have anything to follow; strings are packed
+        // bytes, so reading them as u64 slots would run off the block.
+        let memory = match block_type(pointer) {
+            STRUCT_POINTERS => 1,
+            LIST_POINTERS => 2,
+            _ => return,
+        };
+
+        let length = read_dalloc(pointer - LENGTH);
+        for i in 0..length {
+            worklist.visit(read_dalloc(pointer + (i * 8)), memory);
+        }
+    }
+}
+
+/// Slides live dalloc blocks together so a request that fits the free space
+/// as a whole can be served. Blocks a root refers to stay where they are, so
+/// the roots themselves never change; every other pointer is found by
+/// walking from the roots again and rewritten before anything moves.
+unsafe fn compact() {
+    for_each_root(|pointer, memory| {
+        if memory == 2 && pointer != 0 && read_dalloc(pointer - MARK) == 0 {
+            write_dalloc(pointer - MARK, HELD);
+        }
+    });
+    dplan();
+
+    let sp = read_u32(STACK_POINTER_ADDR);
+    let mut worklist = Worklist { base: sp, top: sp };
+    for_each_root(|pointer, memory| worklist.reach(pointer, memory));
+
+    // Walk the worklist without popping, so every visited object is still
+    // listed when its flag needs clearing.
+    let mut next = worklist.base;
+    while next < worklist.top {
+        relocate(read_u32(next), read_u32(next + 4), &mut worklist);
+        next += 8;
+    }
+
+    for entry in (worklist.base..worklist.top).step_by(8) {
+        let pointer = read_u32(entry);
+        if read_u32(entry + 4) == 1 {
+            write_alloc(pointer - 4, 0);
         } else {
-            if pointer < dalloc_memory_size() && read_dalloc(pointer - 12) != 1 {
-                let length = read_dalloc(pointer - 4);
-                let ty = read_dalloc(pointer - 16);
-
-                write_dalloc(pointer - 12, 1);
-
-                for i in 0..length {
-                    let element_addr = pointer + (i * 8);
-                    let element_ptr = read_dalloc(element_addr);
-                    if ty == 2 {
-                        mark_pointer(element_ptr, 1);
-                    } else if ty == 3 {
-                        mark_pointer(element_ptr, 2);
-                    }
-                }
+            write_dalloc(pointer - INFO, read_dalloc(pointer - INFO) & !VISITED);
+        }
+    }
+
+    dcompact();
+}
+
+/// Rewrites the dalloc pointers in an object to where `dplan` is moving
+/// their blocks, and reaches everything the object points to.
+unsafe fn relocate(pointer: u32, memory: u32, worklist: &mut Worklist) {
+    if memory == 1 {
+        let ty = read_alloc(pointer - 8);
+
+        if ty == TAGGED_UNION {
+            let tag = read_alloc(pointer);
+            let value = read_alloc(pointer + 8);
+            if tag == TAG_ERROR || tag == TAG_STRUCT {
+                worklist.reach(value, 1);
+            } else if tag == TAG_LIST {
+                write_alloc(pointer + 8, forward(value));
+                worklist.reach(value, 2);
+            }
+            return;
+        }
+
+        let scount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 8);
+        for i in 0..scount {
+            worklist.reach(read_alloc(pointer + (i * 8)), 1);
+        }
+
+        let lcount = read_alloc(TYPE_TABLE_INDEX + (ty * TYPE_TABLE_RECORD_SIZE) + 12);
+        for i in 0..lcount {
+            let addr = pointer + (scount * 8) + (i * 8);
+            let value = read_alloc(addr);
+            write_alloc(addr, forward(value));
+            worklist.reach(value, 2);
+        }
+    } else {
+        let memory = match block_type(pointer) {
+            STRUCT_POINTERS => 1,
+            LIST_POINTERS => 2,
+            _ => return,
+        };
+
+        let length = read_dalloc(pointer - LENGTH);
+        for i in 0..length {
+            let addr = pointer + (i * 8);
+            let value = read_dalloc(addr);
+            if memory == 2 {
+                write_dalloc(addr, forward(value));
             }
+            worklist.reach(value, memory);
         }
     }
 }
 
+/// Where `dplan` is moving the block at `pointer`. Blocks that stay put
+/// keep their held or pinned mark instead of an address.
+unsafe fn forward(pointer: u32) -> u32 {
+    if pointer == 0 {
+        return 0;
+    }
+    let mark = read_dalloc(pointer - MARK);
+    if mark > PINNED {
+        mark
+    } else {
+        pointer
+    }
+}
+
+fn shadow_memory_size() -> u32 {
+    (core::arch::wasm32::memory_size(0) as u32) * PAGE_SIZE
+}
+
 #[no_mangle]
 pub extern "C" fn gc() {
     unsafe {
         mark();
+        // Structs with a drop method to run live on until it has, along
+        // with everything they reach.
+        if enqueue() != 0 {
+            mark();
+        }
         sweep();
         dsweep();
+
+        if dfragmented() == 1 {
+            compact();
+        }
+
+        write_u32(GC_COUNT_ADDR, read_u32(GC_COUNT_ADDR) + 1);
+        set_budgets();
+    }
+}
+
+/// Starts a collection whenever an allocation would leave either heap
+/// holding more than `bytes` bytes, headers included, rather than only once
+/// one is full. A heap whose live objects alone come near the threshold
+/// still gets to allocate a quarter of it between collections, so it isn't
+/// collected on every allocation. 0 turns the threshold off again.
+#[no_mangle]
+pub extern "C" fn set_gc_threshold(bytes: u32) {
+    unsafe {
+        write_u32(GC_THRESHOLD_ADDR, bytes);
+        set_budgets();
+    }
+}
+
+/// Tells each heap how many more bytes it may hand out before asking for a
+/// collection, going by the threshold and what it holds now.
+unsafe fn set_budgets() {
+    let threshold = read_u32(GC_THRESHOLD_ADDR);
+    alloc_budget(budget(threshold, alloc_used(), alloc_allocated()));
+    dbudget(budget(threshold, dalloc_used(), dalloc_allocated()));
+}
+
+/// The budget for a heap holding `used` bytes, `allocated` of them handed
+/// out since the last collection, which the budget counts against.
+fn budget(threshold: u32, used: u32, allocated: u32) -> u32 {
+    if threshold == 0 {
+        return 0;
+    }
+    let room = threshold.saturating_sub(used).max(threshold / 4).max(1);
+    allocated.saturating_add(room)
+}
+
+/// Bytes taken by objects in both heaps that haven't been freed, headers
+/// included. Between collections that counts garbage too.
+#[no_mangle]
+pub extern "C" fn heap_used() -> u32 {
+    unsafe { alloc_used() + dalloc_used() }
+}
+
+/// Bytes in both heaps that are free to allocate from without growing.
+#[no_mangle]
+pub extern "C" fn heap_free() -> u32 {
+    unsafe { alloc_free() + dalloc_free() }
+}
+
+#[no_mangle]
+pub extern "C" fn gc_count() -> u32 {
+    unsafe { read_u32(GC_COUNT_ADDR) }
+}
+
+/// Bytes allocated in both heaps since the last collection.
+#[no_mangle]
+pub extern "C" fn bytes_allocated_since_gc() -> u32 {
+    unsafe { alloc_allocated() + dalloc_allocated() }
+}
+
+/// The longest run of free bytes for lists and strings. Structs come in
+/// fixed sizes, so fragmentation only shows up here.
+#[no_mangle]
+pub extern "C" fn largest_free_block() -> u32 {
+    unsafe { dalloc_largest_free() }
+}
+
+/// Writes a snapshot of both heaps to shadow memory just above the stack,
+/// laid out as `abi::snapshot` describes, and returns its address, so a
+/// host can profile the heaps or look for leaks. It stays there until Star
+/// code runs again. Marks the heaps to tell which objects are still
+/// reachable, and clears the marks again afterwards.
+#[no_mangle]
+pub extern "C" fn heap_snapshot() -> u32 {
+    unsafe {
+        mark();
+
+        let mut snapshot = Snapshot::new(read_u32(STACK_POINTER_ADDR));
+        let mut pointer = alloc_next(0);
+        while pointer != 0 {
+            let id = read_alloc(pointer - 8);
+            let size = read_alloc(TYPE_TABLE_INDEX + id * TYPE_TABLE_RECORD_SIZE);
+            let marked = read_alloc(pointer - 4) == 1;
+            if marked {
+                write_alloc(pointer - 4, 0);
+            }
+            snapshot.record(id, 8 + size, if marked { abi::snapshot::MARKED } else { 0 });
+            pointer = alloc_next(pointer);
+        }
+
+        pointer = dnext(0);
+        while pointer != 0 {
+            let mark = read_dalloc(pointer - MARK);
+            if mark == HELD {
+                write_dalloc(pointer - MARK, 0);
+            }
+            let bytes = (read_dalloc(pointer - INFO) & abi::block::SIZE_MASK) + abi::block::HEADER;
+            let flags = if mark != 0 { abi::snapshot::MARKED } else { 0 };
+            snapshot.record(block_type(pointer), bytes, flags | abi::snapshot::DALLOC);
+            pointer = dnext(pointer);
+        }
+
+        snapshot.finish()
+    }
+}
+
+/// A heap snapshot being written, starting at `base` with the number of
+/// records so far.
+struct Snapshot {
+    base: u32,
+    top: u32,
+}
+
+impl Snapshot {
+    fn new(base: u32) -> Snapshot {
+        Snapshot {
+            base,
+            top: base + 4,
+        }
+    }
+
+    unsafe fn record(&mut self, ty: u32, bytes: u32, flags: u32) {
+        if self.top + abi::snapshot::RECORD_SIZE > shadow_memory_size()
+            && core::arch::wasm32::memory_grow(0, 1) == usize::MAX
+        {
+            core::arch::wasm32::unreachable();
+        }
+        write_u32(self.top, ty);
+        write_u32(self.top + 4, bytes);
+        write_u32(self.top + 8, flags);
+        self.top += abi::snapshot::RECORD_SIZE;
+    }
+
+    /// Writes the number of records and returns where the snapshot starts.
+    unsafe fn finish(self) -> u32 {
+        write_u32(
+            self.base,
+            (self.top - self.base - 4) / abi::snapshot::RECORD_SIZE,
+        );
+        self.base
+    }
+}
+
+/// Allocates a string of `length` bytes for the host to fill in with
+/// `host_write_bytes` and pass to Star code, collecting first if the heap is
+/// full. The string is pinned, so no collection frees or moves it. Returns 0
+/// if the heap can't grow to fit it.
+#[no_mangle]
+pub extern "C" fn host_alloc_string(length: u32) -> u32 {
+    host_alloc(BYTES, length)
+}
+
+/// Like `host_alloc_string`, for a list of `length` integers, floats or
+/// booleans in 8-byte slots. Pinned blocks aren't traced, so lists of
+/// strings, structs or lists can't be made this way.
+#[no_mangle]
+pub extern "C" fn host_alloc_list(length: u32) -> u32 {
+    host_alloc(PRIMITIVES, length)
+}
+
+fn host_alloc(ty: u32, length: u32) -> u32 {
+    unsafe {
+        let mut ptr = dalloc(ty, length);
+        if ptr == 0 {
+            gc();
+            ptr = dalloc(ty, length);
+        }
+        if ptr != 0 {
+            dpin(ptr);
+        }
+        ptr
     }
 }
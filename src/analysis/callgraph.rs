@@ -0,0 +1,469 @@
+use crate::ast::tast::{Expr, TypedExpr, TypedProgram, TypedStatement};
+use std::collections::HashMap;
+
+/// Bytes a value takes on the shadow stack. Every frame also saves the
+/// frame pointer before it in a slot of its own.
+const SLOT_SIZE: usize = 8;
+
+/// Which functions call which, found from the names calls use.
+#[derive(Debug)]
+pub struct CallGraph {
+    pub functions: Vec<CallNode>,
+    /// Each group of functions that can call each other, as indices into
+    /// `functions`.
+    pub cycles: Vec<Vec<usize>>,
+}
+
+#[derive(Debug)]
+pub struct CallNode {
+    /// The function's name after those of the functions it is nested in,
+    /// as in `main.depth`.
+    pub name: String,
+    /// The functions it calls by name, as indices into `functions`.
+    pub calls: Vec<usize>,
+    /// Whether it also calls a function held in a variable, field or list,
+    /// which the graph can't follow.
+    pub calls_indirectly: bool,
+    /// Shadow stack its frame takes: a slot for its environment, each
+    /// parameter and local, and the saved frame pointer. Temporaries for
+    /// structs under construction can add a few slots. A `@noalloc`
+    /// function has no frame.
+    pub frame_bytes: usize,
+    /// Whether it is part of a cycle, so one call can nest any number of
+    /// frames.
+    pub recursive: bool,
+    /// Shadow stack a call to it takes at most, along the deepest chain of
+    /// calls by name. `None` when the chain reaches a recursive function.
+    pub stack_bytes: Option<usize>,
+}
+
+impl CallGraph {
+    pub fn new(program: &TypedProgram) -> Self {
+        let mut builder = Builder {
+            functions: vec![],
+            scopes: vec![HashMap::new()],
+            enclosing: vec![],
+        };
+        builder.block(&program.statements);
+
+        let mut graph = CallGraph {
+            functions: builder.functions,
+            cycles: vec![],
+        };
+        graph.find_cycles();
+        let mut stack = vec![None; graph.functions.len()];
+        for i in 0..graph.functions.len() {
+            graph.functions[i].stack_bytes = graph.stack_bytes(i, &mut stack);
+        }
+        graph
+    }
+
+    /// Marks every function in a strongly connected component that loops,
+    /// with Tarjan's algorithm.
+    fn find_cycles(&mut self) {
+        let mut tarjan = Tarjan {
+            index: vec![None; self.functions.len()],
+            low: vec![0; self.functions.len()],
+            stack: vec![],
+            on_stack: vec![false; self.functions.len()],
+            next: 0,
+            components: vec![],
+        };
+        for i in 0..self.functions.len() {
+            if tarjan.index[i].is_none() {
+                tarjan.visit(self, i);
+            }
+        }
+        for mut component in tarjan.components {
+            let first = component[0];
+            if component.len() == 1 && !self.functions[first].calls.contains(&first) {
+                continue;
+            }
+            component.sort();
+            for &i in &component {
+                self.functions[i].recursive = true;
+            }
+            self.cycles.push(component);
+        }
+        self.cycles.sort();
+    }
+
+    fn stack_bytes(&self, i: usize, known: &mut Vec<Option<Option<usize>>>) -> Option<usize> {
+        if let Some(bytes) = known[i] {
+            return bytes;
+        }
+        let function = &self.functions[i];
+        let bytes = if function.recursive {
+            None
+        } else {
+            // Callees of a function outside every cycle are outside its
+            // cycles too, so this terminates.
+            let mut deepest = Some(0);
+            for &callee in &function.calls {
+                deepest = deepest
+                    .zip(self.stack_bytes(callee, known))
+                    .map(|(a, b)| a.max(b));
+            }
+            deepest.map(|deepest| function.frame_bytes + deepest)
+        };
+        known[i] = Some(bytes);
+        bytes
+    }
+
+    /// The graph in Graphviz's DOT language. Recursive functions are red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+        for (i, function) in self.functions.iter().enumerate() {
+            let stack = match function.stack_bytes {
+                Some(bytes) => format!("stack {} bytes", bytes),
+                None => "stack unbounded".to_string(),
+            };
+            let indirect = if function.calls_indirectly {
+                "\\ncalls indirectly"
+            } else {
+                ""
+            };
+            let color = if function.recursive {
+                ", color=red"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "    f{} [label=\"{}\\nframe {} bytes\\n{}{}\"{}];\n",
+                i, function.name, function.frame_bytes, stack, indirect, color
+            ));
+        }
+        for (i, function) in self.functions.iter().enumerate() {
+            for callee in &function.calls {
+                dot.push_str(&format!("    f{} -> f{};\n", i, callee));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as JSON, naming functions by their nested names.
+    pub fn to_json(&self) -> String {
+        let names = |indices: &[usize]| {
+            let names: Vec<String> = indices
+                .iter()
+                .map(|&i| format!("\"{}\"", self.functions[i].name))
+                .collect();
+            format!("[{}]", names.join(", "))
+        };
+        let functions: Vec<String> = self
+            .functions
+            .iter()
+            .map(|function| {
+                let stack = match function.stack_bytes {
+                    Some(bytes) => bytes.to_string(),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "    {{\"name\": \"{}\", \"calls\": {}, \"calls_indirectly\": {}, \"frame_bytes\": {}, \"recursive\": {}, \"stack_bytes\": {}}}",
+                    function.name,
+                    names(&function.calls),
+                    function.calls_indirectly,
+                    function.frame_bytes,
+                    function.recursive,
+                    stack
+                )
+            })
+            .collect();
+        let cycles: Vec<String> = self.cycles.iter().map(|cycle| names(cycle)).collect();
+        format!(
+            "{{\n  \"functions\": [\n{}\n  ],\n  \"cycles\": [{}]\n}}\n",
+            functions.join(",\n"),
+            cycles.join(", ")
+        )
+    }
+}
+
+/// Walks the program in order, resolving each name the way the type checker
+/// does: functions are visible from their own declaration on, and a later
+/// variable of the same name hides them.
+struct Builder {
+    functions: Vec<CallNode>,
+    /// What each name in scope refers to: a function, or `None` for a
+    /// variable.
+    scopes: Vec<HashMap<String, Option<usize>>>,
+    /// The functions being walked, innermost last.
+    enclosing: Vec<usize>,
+}
+
+impl Builder {
+    fn declare(&mut self, name: &str, function: Option<usize>) {
+        self.scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name.to_string(), function);
+    }
+
+    /// Declares a local of the function being walked, which takes a slot in
+    /// its frame.
+    fn declare_local(&mut self, name: &str, function: Option<usize>) {
+        if let Some(&current) = self.enclosing.last() {
+            self.functions[current].frame_bytes += SLOT_SIZE;
+        }
+        self.declare(name, function);
+    }
+
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .copied()
+            .flatten()
+    }
+
+    fn call(&mut self, callee: Option<usize>) {
+        let Some(&current) = self.enclosing.last() else {
+            return;
+        };
+        let caller = &mut self.functions[current];
+        match callee {
+            Some(callee) if !caller.calls.contains(&callee) => caller.calls.push(callee),
+            Some(_) => {}
+            None => caller.calls_indirectly = true,
+        }
+    }
+
+    fn scoped(&mut self, statements: &[TypedStatement]) {
+        self.scopes.push(HashMap::new());
+        self.block(statements);
+        self.scopes.pop();
+    }
+
+    /// `scoped` with `name` bound by an `if let` or `while let`, next to
+    /// the hidden local holding the value it unwraps.
+    fn let_scoped(&mut self, name: &str, statements: &[TypedStatement]) {
+        self.scopes.push(HashMap::new());
+        self.declare_local("let", None);
+        self.declare_local(name, None);
+        self.block(statements);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, statements: &[TypedStatement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &TypedStatement) {
+        match statement {
+            TypedStatement::Expr(expr)
+            | TypedStatement::Print(expr)
+            | TypedStatement::Produce(expr)
+            | TypedStatement::Defer(expr)
+            | TypedStatement::Raise(expr)
+            | TypedStatement::Return(Some(expr)) => self.expr(expr),
+            TypedStatement::Return(None)
+            | TypedStatement::Break(_)
+            | TypedStatement::Continue(_)
+            | TypedStatement::Struct { .. }
+            | TypedStatement::Error { .. } => {}
+            TypedStatement::Let { name, value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+                self.declare_local(name, None);
+            }
+            TypedStatement::Const { name, value, .. } => {
+                self.expr(value);
+                self.declare_local(name, None);
+            }
+            TypedStatement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition);
+                self.scoped(then_block);
+                if let Some(else_block) = else_block {
+                    self.scoped(else_block);
+                }
+            }
+            TypedStatement::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                self.scopes.push(HashMap::new());
+                self.statement(init);
+                self.expr(condition);
+                self.scoped(body);
+                self.statement(update);
+                self.scopes.pop();
+            }
+            TypedStatement::While { condition, body } => {
+                self.expr(condition);
+                self.scoped(body);
+            }
+            TypedStatement::IfLet {
+                name,
+                value,
+                then_block,
+                else_block,
+            } => {
+                self.expr(value);
+                self.let_scoped(name, then_block);
+                if let Some(else_block) = else_block {
+                    self.scoped(else_block);
+                }
+            }
+            TypedStatement::WhileLet { name, value, body } => {
+                self.expr(value);
+                self.let_scoped(name, body);
+            }
+            TypedStatement::Unchecked { body } => self.scoped(body),
+            TypedStatement::Function {
+                name,
+                params,
+                body,
+                noalloc,
+                ..
+            } => {
+                let index = self.functions.len();
+                let qualified = match self.enclosing.last() {
+                    Some(&outer) => format!("{}.{}", self.functions[outer].name, name),
+                    None => name.clone(),
+                };
+                self.functions.push(CallNode {
+                    name: qualified,
+                    calls: vec![],
+                    calls_indirectly: false,
+                    frame_bytes: (2 + params.len()) * SLOT_SIZE,
+                    recursive: false,
+                    stack_bytes: None,
+                });
+                self.declare_local(name, Some(index));
+
+                self.enclosing.push(index);
+                self.scopes.push(HashMap::new());
+                for (param, _) in params {
+                    self.declare(param, None);
+                }
+                self.block(body);
+                self.scopes.pop();
+                self.enclosing.pop();
+                if *noalloc {
+                    // It runs without a frame of its own.
+                    self.functions[index].frame_bytes = 0;
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &TypedExpr) {
+        match &expr.expr {
+            Expr::Null
+            | Expr::Integer(_)
+            | Expr::Float(_)
+            | Expr::String(_)
+            | Expr::Boolean(_)
+            | Expr::Identifier(_) => {}
+            Expr::List(items) | Expr::Array(items) => self.exprs(items),
+            Expr::Builtin { args, .. } | Expr::ExternCall { args, .. } => self.exprs(args),
+            Expr::Field { object, .. } => self.expr(object),
+            Expr::Index { object, key } => {
+                self.expr(object);
+                self.expr(key);
+            }
+            Expr::New { fields, .. } => {
+                for (_, value) in fields {
+                    self.expr(value);
+                }
+            }
+            Expr::Binary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Unary { expr, .. } | Expr::UnwrapError(expr) | Expr::UnwrapNull(expr) => {
+                self.expr(expr)
+            }
+            Expr::Call { callee, args } => {
+                match &callee.expr {
+                    Expr::Identifier(name) => self.call(self.resolve(name)),
+                    _ => {
+                        self.call(None);
+                        self.expr(callee);
+                    }
+                }
+                self.exprs(args);
+            }
+            Expr::Match {
+                expr,
+                binding,
+                arms,
+            } => {
+                self.expr(expr);
+                for (condition, body) in arms {
+                    self.scopes.push(HashMap::new());
+                    self.declare_local(binding, None);
+                    if let Some(condition) = condition {
+                        self.expr(condition);
+                    }
+                    self.block(body);
+                    self.scopes.pop();
+                }
+            }
+            Expr::Slice { expr, start, end } => {
+                self.expr(expr);
+                self.expr(start);
+                self.expr(end);
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[TypedExpr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+}
+
+struct Tarjan {
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    next: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan {
+    fn visit(&mut self, graph: &CallGraph, v: usize) {
+        self.index[v] = Some(self.next);
+        self.low[v] = self.next;
+        self.next += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        for &w in &graph.functions[v].calls {
+            match self.index[w] {
+                None => {
+                    self.visit(graph, w);
+                    self.low[v] = self.low[v].min(self.low[w]);
+                }
+                Some(index) if self.on_stack[w] => self.low[v] = self.low[v].min(index),
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.low[v]) == self.index[v] {
+            let mut component = vec![];
+            loop {
+                let w = self.stack.pop().expect("v is still on the stack");
+                self.on_stack[w] = false;
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
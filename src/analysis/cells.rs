@@ -0,0 +1,444 @@
+//! Closures copy the variables they capture when they're created, so a
+//! variable that is both captured and assigned is moved into a cell, a
+//! struct with one `value` field, that the closures and the function that
+//! declared it share.
+//!
+//! This runs on the type checked program, before the locals are indexed,
+//! and resolves names the way `LocalsIndexer` does. A first walk finds the
+//! variables that need a cell and a second rewrites them: the declaration
+//! allocates the cell and every read or assignment goes through `value`.
+
+use crate::ast::tast::{self, TypedExpr, TypedProgram, TypedStatement};
+use crate::ast::{BinaryOp, Type, TypeKind};
+use std::collections::{HashMap, HashSet};
+
+/// Moves the variables that closures capture and that are assigned after
+/// their declaration into cells.
+pub fn box_mutable_captures(program: &TypedProgram) -> TypedProgram {
+    let mut scan = Cells::new(HashSet::new());
+    scan.walk_program(&program.statements);
+    let boxed = scan
+        .bindings
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.boxable.is_some() && b.captured && b.assigned)
+        .map(|(id, _)| id)
+        .collect::<HashSet<_>>();
+    if boxed.is_empty() {
+        return TypedProgram {
+            statements: program.statements.clone(),
+            globals: program.globals.clone(),
+        };
+    }
+
+    let mut rewrite = Cells::new(boxed);
+    let mut statements = rewrite.walk_program(&program.statements);
+    for (index, ty) in rewrite.cell_types.iter().enumerate() {
+        statements.push(TypedStatement::Struct {
+            name: cell_name(index),
+            fields: vec![(VALUE.to_string(), ty.clone())],
+        });
+    }
+    TypedProgram {
+        statements,
+        globals: program.globals.clone(),
+    }
+}
+
+const VALUE: &str = "value";
+
+fn cell_name(index: usize) -> String {
+    format!("cell.{}", index)
+}
+
+struct Binding {
+    /// How many functions enclose the declaration.
+    depth: usize,
+    /// The declared type of a `let` binding, the only bindings moved into
+    /// cells. Parameters and `const`s are never assigned.
+    boxable: Option<Type>,
+    captured: bool,
+    assigned: bool,
+}
+
+struct Cells {
+    /// The bindings each function's blocks declare, innermost last.
+    scopes: Vec<Vec<HashMap<String, usize>>>,
+    bindings: Vec<Binding>,
+    /// The bindings to move into cells, found by an earlier walk.
+    boxed: HashSet<usize>,
+    /// The type each cell struct holds, by index.
+    cell_types: Vec<Type>,
+}
+
+impl Cells {
+    fn new(boxed: HashSet<usize>) -> Self {
+        Cells {
+            scopes: vec![],
+            bindings: vec![],
+            boxed,
+            cell_types: vec![],
+        }
+    }
+
+    fn walk_program(&mut self, statements: &[TypedStatement]) -> Vec<TypedStatement> {
+        // `main` is indexed first, as `LocalsIndexer` does.
+        let mut order: Vec<usize> = (0..statements.len()).collect();
+        if let Some(main) = statements
+            .iter()
+            .position(|s| matches!(s, TypedStatement::Function { name, .. } if name == "main"))
+        {
+            order.remove(main);
+            order.insert(0, main);
+        }
+
+        self.scopes.push(vec![HashMap::new()]);
+        let mut walked: Vec<Option<TypedStatement>> = vec![None; statements.len()];
+        for i in order {
+            walked[i] = Some(self.walk_stmt(&statements[i]));
+        }
+        self.scopes.pop();
+        walked
+            .into_iter()
+            .map(|s| s.expect("every statement is walked"))
+            .collect()
+    }
+
+    fn declare(&mut self, name: &str, boxable: Option<&Type>) -> usize {
+        let id = self.bindings.len();
+        self.bindings.push(Binding {
+            depth: self.scopes.len(),
+            boxable: boxable.cloned(),
+            captured: false,
+            assigned: false,
+        });
+        let blocks = self.scopes.last_mut().expect("a function is being walked");
+        blocks
+            .last_mut()
+            .expect("a block is being walked")
+            .insert(name.to_string(), id);
+        id
+    }
+
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|blocks| blocks.iter().rev())
+            .find_map(|block| block.get(name).copied())
+    }
+
+    fn push_block(&mut self) {
+        if let Some(blocks) = self.scopes.last_mut() {
+            blocks.push(HashMap::new());
+        }
+    }
+
+    fn pop_block(&mut self) {
+        if let Some(blocks) = self.scopes.last_mut() {
+            blocks.pop();
+        }
+    }
+
+    fn block(&mut self, body: &[TypedStatement]) -> Vec<TypedStatement> {
+        self.push_block();
+        let body = self.statements(body);
+        self.pop_block();
+        body
+    }
+
+    fn statements(&mut self, body: &[TypedStatement]) -> Vec<TypedStatement> {
+        body.iter().map(|s| self.walk_stmt(s)).collect()
+    }
+
+    /// The struct type of a cell holding a `ty`.
+    fn cell_type(&mut self, ty: &Type) -> Type {
+        let index = match self.cell_types.iter().position(|t| t == ty) {
+            Some(index) => index,
+            None => {
+                self.cell_types.push(ty.clone());
+                self.cell_types.len() - 1
+            }
+        };
+        Type {
+            kind: TypeKind::Struct {
+                name: cell_name(index),
+            },
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        }
+    }
+
+    /// A new cell holding `value`, as a variable of type `ty`.
+    fn new_cell(&mut self, ty: &Type, value: TypedExpr) -> (Type, TypedExpr) {
+        let cell = self.cell_type(ty);
+        let TypeKind::Struct { name } = &cell.kind else {
+            unreachable!("cells are structs")
+        };
+        let value = TypedExpr {
+            expr: tast::Expr::New {
+                name: name.clone(),
+                fields: vec![(VALUE.to_string(), value)],
+            },
+            ty: cell.clone(),
+        };
+        (cell, value)
+    }
+
+    fn walk_stmt(&mut self, stmt: &TypedStatement) -> TypedStatement {
+        match stmt {
+            TypedStatement::Let { name, ty, value } => {
+                let value = value.as_ref().map(|v| self.walk_expr(v));
+                let id = self.declare(name, Some(ty));
+                if !self.boxed.contains(&id) {
+                    return TypedStatement::Let {
+                        name: name.clone(),
+                        ty: ty.clone(),
+                        value,
+                    };
+                }
+                let value = value.unwrap_or(TypedExpr {
+                    expr: tast::Expr::Null,
+                    ty: Type {
+                        kind: TypeKind::Null,
+                        nullable: true,
+                        errorable: false,
+                        errors: vec![],
+                    },
+                });
+                let (cell, value) = self.new_cell(ty, value);
+                TypedStatement::Let {
+                    name: name.clone(),
+                    ty: cell,
+                    value: Some(value),
+                }
+            }
+            TypedStatement::Const { name, ty, value } => {
+                let value = self.walk_expr(value);
+                self.declare(name, None);
+                TypedStatement::Const {
+                    name: name.clone(),
+                    ty: ty.clone(),
+                    value,
+                }
+            }
+            TypedStatement::Function {
+                name,
+                params,
+                returns,
+                body,
+                exported,
+                noalloc,
+            } => {
+                self.declare(name, None);
+                self.scopes.push(vec![HashMap::new()]);
+                for (param, _) in params {
+                    self.declare(param, None);
+                }
+                let body = self.statements(body);
+                self.scopes.pop();
+                TypedStatement::Function {
+                    name: name.clone(),
+                    params: params.clone(),
+                    returns: returns.clone(),
+                    body,
+                    exported: *exported,
+                    noalloc: *noalloc,
+                }
+            }
+            TypedStatement::If {
+                condition,
+                then_block,
+                else_block,
+            } => TypedStatement::If {
+                condition: self.walk_expr(condition),
+                then_block: self.block(then_block),
+                else_block: else_block.as_ref().map(|b| self.block(b)),
+            },
+            TypedStatement::While { condition, body } => TypedStatement::While {
+                condition: self.walk_expr(condition),
+                body: self.block(body),
+            },
+            TypedStatement::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                self.push_block();
+                let init = Box::new(self.walk_stmt(init));
+                let condition = self.walk_expr(condition);
+                let update = Box::new(self.walk_stmt(update));
+                let body = self.statements(body);
+                self.pop_block();
+                TypedStatement::For {
+                    init,
+                    condition,
+                    update,
+                    body,
+                }
+            }
+            TypedStatement::IfLet {
+                name,
+                value,
+                then_block,
+                else_block,
+            } => {
+                let value = self.walk_expr(value);
+                self.push_block();
+                self.declare(name, None);
+                let then_block = self.statements(then_block);
+                self.pop_block();
+                TypedStatement::IfLet {
+                    name: name.clone(),
+                    value,
+                    then_block,
+                    else_block: else_block.as_ref().map(|b| self.block(b)),
+                }
+            }
+            TypedStatement::WhileLet { name, value, body } => {
+                let value = self.walk_expr(value);
+                self.push_block();
+                self.declare(name, None);
+                let body = self.statements(body);
+                self.pop_block();
+                TypedStatement::WhileLet {
+                    name: name.clone(),
+                    value,
+                    body,
+                }
+            }
+            TypedStatement::Unchecked { body } => TypedStatement::Unchecked {
+                body: self.block(body),
+            },
+            TypedStatement::Expr(e) => TypedStatement::Expr(self.walk_expr(e)),
+            TypedStatement::Return(e) => {
+                TypedStatement::Return(e.as_ref().map(|e| self.walk_expr(e)))
+            }
+            TypedStatement::Defer(e) => TypedStatement::Defer(self.walk_expr(e)),
+            TypedStatement::Print(e) => TypedStatement::Print(self.walk_expr(e)),
+            TypedStatement::Produce(e) => TypedStatement::Produce(self.walk_expr(e)),
+            TypedStatement::Raise(e) => TypedStatement::Raise(self.walk_expr(e)),
+            TypedStatement::Break(_)
+            | TypedStatement::Continue(_)
+            | TypedStatement::Struct { .. }
+            | TypedStatement::Error { .. } => stmt.clone(),
+        }
+    }
+
+    fn walk_exprs(&mut self, exprs: &[TypedExpr]) -> Vec<TypedExpr> {
+        exprs.iter().map(|e| self.walk_expr(e)).collect()
+    }
+
+    fn walk_expr(&mut self, expr: &TypedExpr) -> TypedExpr {
+        let walked = match &expr.expr {
+            tast::Expr::Identifier(name) => {
+                let Some(id) = self.resolve(name) else {
+                    return expr.clone();
+                };
+                if self.bindings[id].depth < self.scopes.len() {
+                    self.bindings[id].captured = true;
+                }
+                if !self.boxed.contains(&id) {
+                    return expr.clone();
+                }
+                let ty = self.bindings[id]
+                    .boxable
+                    .clone()
+                    .expect("boxed bindings have a type");
+                let cell = self.cell_type(&ty);
+                tast::Expr::Field {
+                    object: Box::new(TypedExpr {
+                        expr: tast::Expr::Identifier(name.clone()),
+                        ty: cell,
+                    }),
+                    field: VALUE.to_string(),
+                }
+            }
+            tast::Expr::Binary { left, op, right } => {
+                if let (BinaryOp::Is, tast::Expr::Identifier(name)) = (op, &left.expr) {
+                    if let Some(id) = self.resolve(name) {
+                        self.bindings[id].assigned = true;
+                    }
+                }
+                tast::Expr::Binary {
+                    left: Box::new(self.walk_expr(left)),
+                    op: op.clone(),
+                    right: Box::new(self.walk_expr(right)),
+                }
+            }
+            tast::Expr::Unary { op, expr } => tast::Expr::Unary {
+                op: op.clone(),
+                expr: Box::new(self.walk_expr(expr)),
+            },
+            tast::Expr::Call { callee, args } => tast::Expr::Call {
+                callee: Box::new(self.walk_expr(callee)),
+                args: self.walk_exprs(args),
+            },
+            tast::Expr::Builtin { builtin, args } => tast::Expr::Builtin {
+                builtin: *builtin,
+                args: self.walk_exprs(args),
+            },
+            tast::Expr::ExternCall { function, args } => tast::Expr::ExternCall {
+                function: function.clone(),
+                args: self.walk_exprs(args),
+            },
+            tast::Expr::List(items) => tast::Expr::List(self.walk_exprs(items)),
+            tast::Expr::Array(items) => tast::Expr::Array(self.walk_exprs(items)),
+            tast::Expr::Field { object, field } => tast::Expr::Field {
+                object: Box::new(self.walk_expr(object)),
+                field: field.clone(),
+            },
+            tast::Expr::Index { object, key } => tast::Expr::Index {
+                object: Box::new(self.walk_expr(object)),
+                key: Box::new(self.walk_expr(key)),
+            },
+            tast::Expr::Slice { expr, start, end } => tast::Expr::Slice {
+                expr: Box::new(self.walk_expr(expr)),
+                start: Box::new(self.walk_expr(start)),
+                end: Box::new(self.walk_expr(end)),
+            },
+            tast::Expr::New { name, fields } => tast::Expr::New {
+                name: name.clone(),
+                fields: fields
+                    .iter()
+                    .map(|(n, e)| (n.clone(), self.walk_expr(e)))
+                    .collect(),
+            },
+            tast::Expr::Match {
+                expr: matched,
+                binding,
+                arms,
+            } => {
+                let matched = self.walk_expr(matched);
+                self.push_block();
+                self.declare(binding, None);
+                let arms = arms
+                    .iter()
+                    .map(|(condition, body)| {
+                        let condition = condition.as_ref().map(|c| self.walk_expr(c));
+                        (condition, self.block(body))
+                    })
+                    .collect();
+                self.pop_block();
+                tast::Expr::Match {
+                    expr: Box::new(matched),
+                    binding: binding.clone(),
+                    arms,
+                }
+            }
+            tast::Expr::UnwrapError(e) => tast::Expr::UnwrapError(Box::new(self.walk_expr(e))),
+            tast::Expr::UnwrapNull(e) => tast::Expr::UnwrapNull(Box::new(self.walk_expr(e))),
+            tast::Expr::Null
+            | tast::Expr::Integer(_)
+            | tast::Expr::Float(_)
+            | tast::Expr::String(_)
+            | tast::Expr::Boolean(_) => return expr.clone(),
+        };
+        TypedExpr {
+            expr: walked,
+            ty: expr.ty.clone(),
+        }
+    }
+}
@@ -6,41 +6,51 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 pub struct LocalsIndexer {
     scopes: Vec<Vec<HashMap<String, (u32, Rc<RefCell<Option<String>>>)>>>,
-    locals_types_stack: Vec<Vec<Type>>,
+    locals_stack: Vec<Vec<(String, Type)>>,
     pub fn_count: u32,
     free_var_count: u32,
     fn_names: Vec<String>,
     current_param_count: u32,
+    /// The index of each top-level variable, which every function reaches
+    /// directly rather than capturing it.
+    globals: HashMap<String, u32>,
+    /// The index of each top-level function, given out before any body is
+    /// analyzed so functions can name those declared after them.
+    functions: HashMap<String, u32>,
 }
 
 enum VariableKind {
     Local(u32),
     Captured(String),
+    Global(u32),
+    Function(u32),
 }
 
 impl LocalsIndexer {
     pub fn new() -> Self {
         LocalsIndexer {
             scopes: vec![],
-            locals_types_stack: vec![],
+            locals_stack: vec![],
             fn_count: 1,
             free_var_count: 0,
             fn_names: vec![],
             current_param_count: 0,
+            globals: HashMap::new(),
+            functions: HashMap::new(),
         }
     }
 
     pub fn push_fn(&mut self, name: String) {
         self.scopes.push(vec![HashMap::new()]);
-        self.locals_types_stack.push(vec![]);
+        self.locals_stack.push(vec![]);
         self.fn_names.push(name);
     }
 
-    pub fn pop_fn(&mut self) -> Vec<Type> {
+    pub fn pop_fn(&mut self) -> Vec<(String, Type)> {
         self.scopes.pop();
         self.fn_names.pop();
         self.current_param_count = 0;
-        self.locals_types_stack.pop().unwrap_or_default()
+        self.locals_stack.pop().unwrap_or_default()
     }
 
     pub fn push_scope(&mut self) {
@@ -72,7 +82,7 @@ impl LocalsIndexer {
                 }
 
                 current_scope.insert(name, (index, captured));
-                // Don't push to locals_types_stack
+                // Don't push to locals_stack
                 return Ok(index);
             }
         }
@@ -89,7 +99,7 @@ impl LocalsIndexer {
     ) -> Result<u32, CompilerError> {
         if let Some(scopes) = self.scopes.last_mut() {
             if let Some(current_scope) = scopes.last_mut() {
-                let locals = self.locals_types_stack.last_mut().unwrap();
+                let locals = self.locals_stack.last_mut().unwrap();
                 let num_params = self.current_param_count;
                 let index = locals.len() as u32 + 3 + num_params; // offset by params
 
@@ -99,8 +109,8 @@ impl LocalsIndexer {
                     });
                 }
 
-                current_scope.insert(name, (index, captured));
-                locals.push(typ);
+                current_scope.insert(name.clone(), (index, captured));
+                locals.push((name, typ));
                 return Ok(index);
             }
         }
@@ -109,6 +119,25 @@ impl LocalsIndexer {
         })
     }
 
+    /// Defines the locals of an `if let` or `while let`: one holding the
+    /// value of type `ty`, and `name` for what it holds.
+    fn define_let(
+        &mut self,
+        name: &str,
+        ty: &Type,
+        captured: &Rc<RefCell<Option<String>>>,
+    ) -> Result<(u32, u32), CompilerError> {
+        let holder = self.define("let".to_string(), ty.clone(), Rc::new(RefCell::new(None)))?;
+        let unwrapped = Type {
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+            ..ty.clone()
+        };
+        let index = self.define(name.to_string(), unwrapped, Rc::clone(captured))?;
+        Ok((holder, index))
+    }
+
     pub fn lookup(&mut self, name: &str) -> Result<VariableKind, CompilerError> {
         if let Some(scope) = self.scopes.last() {
             for local_scope in scope.iter().rev() {
@@ -117,7 +146,9 @@ impl LocalsIndexer {
                 }
             }
         }
-        for fn_scope in self.scopes.iter().rev().skip(1) {
+        // The root scope only holds top-level functions, which are named by
+        // index below rather than captured.
+        for fn_scope in self.scopes.iter().skip(1).rev().skip(1) {
             for local_scope in fn_scope.iter().rev() {
                 if let Some((_, captured)) = local_scope.get(name) {
                     let mut borrowed = captured.borrow_mut();
@@ -132,6 +163,12 @@ impl LocalsIndexer {
                 }
             }
         }
+        if let Some(index) = self.functions.get(name) {
+            return Ok(VariableKind::Function(*index));
+        }
+        if let Some(index) = self.globals.get(name) {
+            return Ok(VariableKind::Global(*index));
+        }
         Err(CompilerError::Locals {
             message: format!("Undefined local variable '{}'", name),
         })
@@ -174,6 +211,8 @@ impl LocalsIndexer {
                 params,
                 returns,
                 body,
+                exported,
+                noalloc,
             } => {
                 let captured = Rc::new(RefCell::new(None));
                 let index = self.define(
@@ -185,6 +224,7 @@ impl LocalsIndexer {
                         },
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     },
                     Rc::clone(&captured),
                 )?;
@@ -205,13 +245,15 @@ impl LocalsIndexer {
                     analyzed_body.push(self.analyze_stmt(stmt)?);
                 }
 
-                let locals = self.pop_fn();
+                let (local_names, locals) = self.pop_fn().into_iter().unzip();
 
-                let mut fn_index = self.fn_count;
-                if name == "main" {
-                    fn_index = 0;
-                }
-                self.fn_count += 1;
+                let fn_index = match self.fn_names.len() {
+                    1 => self.functions[name],
+                    _ => {
+                        self.fn_count += 1;
+                        self.fn_count - 1
+                    }
+                };
 
                 Ok(AnalyzedStatement::Function {
                     name: name.clone(),
@@ -222,6 +264,9 @@ impl LocalsIndexer {
                     index: Some(index),
                     fn_index: Some(fn_index),
                     locals,
+                    local_names,
+                    exported: *exported,
+                    noalloc: *noalloc,
                 })
             }
             TypedStatement::If {
@@ -267,6 +312,73 @@ impl LocalsIndexer {
                     body: analyzed_body,
                 })
             }
+            TypedStatement::IfLet {
+                name,
+                value,
+                then_block,
+                else_block,
+            } => {
+                let analyzed_value = self.analyze_expr(value)?;
+                self.push_scope();
+                let captured = Rc::new(RefCell::new(None));
+                let (holder, index) = self.define_let(name, &value.ty, &captured)?;
+                let mut analyzed_then = Vec::new();
+                for s in then_block {
+                    analyzed_then.push(self.analyze_stmt(s)?);
+                }
+                self.pop_scope();
+                let analyzed_else = match else_block {
+                    Some(stmts) => {
+                        self.push_scope();
+                        let mut result = Vec::new();
+                        for s in stmts {
+                            result.push(self.analyze_stmt(s)?);
+                        }
+                        self.pop_scope();
+                        Some(result)
+                    }
+                    None => None,
+                };
+                Ok(AnalyzedStatement::IfLet {
+                    name: name.clone(),
+                    value: analyzed_value,
+                    captured,
+                    holder,
+                    index,
+                    then_block: analyzed_then,
+                    else_block: analyzed_else,
+                })
+            }
+            TypedStatement::WhileLet { name, value, body } => {
+                let analyzed_value = self.analyze_expr(value)?;
+                self.push_scope();
+                let captured = Rc::new(RefCell::new(None));
+                let (holder, index) = self.define_let(name, &value.ty, &captured)?;
+                let mut analyzed_body = Vec::new();
+                for s in body {
+                    analyzed_body.push(self.analyze_stmt(s)?);
+                }
+                self.pop_scope();
+                Ok(AnalyzedStatement::WhileLet {
+                    name: name.clone(),
+                    value: analyzed_value,
+                    captured,
+                    holder,
+                    index,
+                    body: analyzed_body,
+                })
+            }
+            TypedStatement::Unchecked { body } => {
+                self.push_scope();
+                let mut analyzed_body = Vec::new();
+                for s in body {
+                    analyzed_body.push(self.analyze_stmt(s)?);
+                }
+                self.pop_scope();
+                Ok(AnalyzedStatement::Unchecked {
+                    body: analyzed_body,
+                })
+            }
             TypedStatement::For {
                 init,
                 condition,
@@ -301,13 +413,19 @@ impl LocalsIndexer {
             TypedStatement::Produce(expr) => {
                 Ok(AnalyzedStatement::Produce(self.analyze_expr(expr)?))
             }
-            TypedStatement::Break => Ok(AnalyzedStatement::Break),
-            TypedStatement::Continue => Ok(AnalyzedStatement::Continue),
+            TypedStatement::Defer(expr) => Ok(AnalyzedStatement::Defer {
+                body: vec![AnalyzedStatement::Expr(self.analyze_expr(expr)?)],
+            }),
+            TypedStatement::Break(out) => Ok(AnalyzedStatement::Break(*out)),
+            TypedStatement::Continue(out) => Ok(AnalyzedStatement::Continue(*out)),
             TypedStatement::Struct { name, fields } => Ok(AnalyzedStatement::Struct {
                 name: name.clone(),
                 fields: fields.clone(),
             }),
-            TypedStatement::Error { name } => Ok(AnalyzedStatement::Error { name: name.clone() }),
+            TypedStatement::Error { name, fields } => Ok(AnalyzedStatement::Error {
+                name: name.clone(),
+                fields: fields.clone(),
+            }),
             TypedStatement::Raise(expr) => Ok(AnalyzedStatement::Raise(self.analyze_expr(expr)?)),
         }
     }
@@ -319,6 +437,14 @@ impl LocalsIndexer {
                     name: name.clone(),
                     index: Some(index),
                 },
+                VariableKind::Global(index) => aast::Expr::Global {
+                    name: name.clone(),
+                    index,
+                },
+                VariableKind::Function(fn_index) => aast::Expr::Function {
+                    name: name.clone(),
+                    fn_index,
+                },
                 VariableKind::Captured(field) => aast::Expr::Field {
                     object: Box::new(AnalyzedExpr {
                         expr: aast::Expr::Identifier {
@@ -331,6 +457,7 @@ impl LocalsIndexer {
                             },
                             nullable: false,
                             errorable: false,
+                            errors: vec![],
                         },
                     }),
                     field,
@@ -355,6 +482,26 @@ impl LocalsIndexer {
                     args: analyzed_args,
                 }
             }
+            tast::Expr::Builtin { builtin, args } => {
+                let mut analyzed_args = Vec::new();
+                for a in args {
+                    analyzed_args.push(self.analyze_expr(a)?);
+                }
+                aast::Expr::Builtin {
+                    builtin: *builtin,
+                    args: analyzed_args,
+                }
+            }
+            tast::Expr::ExternCall { function, args } => {
+                let mut analyzed_args = Vec::new();
+                for a in args {
+                    analyzed_args.push(self.analyze_expr(a)?);
+                }
+                aast::Expr::ExternCall {
+                    function: function.clone(),
+                    args: analyzed_args,
+                }
+            }
             tast::Expr::List(items) => {
                 let mut analyzed_items = Vec::new();
                 for i in items {
@@ -362,6 +509,13 @@ impl LocalsIndexer {
                 }
                 aast::Expr::List(analyzed_items)
             }
+            tast::Expr::Array(items) => {
+                let mut analyzed_items = Vec::new();
+                for i in items {
+                    analyzed_items.push(self.analyze_expr(i)?);
+                }
+                aast::Expr::Array(analyzed_items)
+            }
             tast::Expr::Field { object, field } => aast::Expr::Field {
                 object: Box::new(self.analyze_expr(object)?),
                 field: field.clone(),
@@ -386,23 +540,37 @@ impl LocalsIndexer {
                 }
             }
             tast::Expr::Match {
-                expr,
+                expr: matched,
                 binding,
                 arms,
             } => {
+                let analyzed_matched = self.analyze_expr(matched)?;
+                self.push_scope();
+                let captured = Rc::new(RefCell::new(None));
+                let index = self.define(binding.clone(), matched.ty.clone(), Rc::clone(&captured))?;
+                let result =
+                    self.define("match".to_string(), expr.ty.clone(), Rc::new(RefCell::new(None)))?;
                 let mut analyzed_arms = Vec::new();
-                for (pattern, stmts) in arms {
+                for (condition, stmts) in arms {
+                    let analyzed_condition = match condition {
+                        Some(c) => Some(self.analyze_expr(c)?),
+                        None => None,
+                    };
                     self.push_scope();
                     let mut analyzed_stmts = Vec::new();
                     for s in stmts {
                         analyzed_stmts.push(self.analyze_stmt(s)?);
                     }
                     self.pop_scope();
-                    analyzed_arms.push((pattern.clone(), analyzed_stmts));
+                    analyzed_arms.push((analyzed_condition, analyzed_stmts));
                 }
+                self.pop_scope();
                 aast::Expr::Match {
-                    expr: Box::new(self.analyze_expr(expr)?),
+                    expr: Box::new(analyzed_matched),
                     binding: binding.clone(),
+                    captured,
+                    index,
+                    result,
                     arms: analyzed_arms,
                 }
             }
@@ -433,6 +601,21 @@ impl LocalsIndexer {
             statements.insert(0, main_fn);
         }
 
+        self.globals = (program.globals.iter().enumerate())
+            .map(|(index, (name, _))| (name.clone(), index as u32))
+            .collect();
+        for stmt in &statements {
+            if let TypedStatement::Function { name, .. } = stmt {
+                let fn_index = match name.as_str() {
+                    "main" => 0,
+                    _ => {
+                        self.fn_count += 1;
+                        self.fn_count - 1
+                    }
+                };
+                self.functions.insert(name.clone(), fn_index);
+            }
+        }
         self.push_fn("root".to_string());
         let mut analyzed = Vec::new();
         for s in &statements {
@@ -441,6 +624,7 @@ impl LocalsIndexer {
         self.pop_fn();
         Ok(AnalyzedProgram {
             statements: analyzed,
+            globals: program.globals.clone(),
         })
     }
 }
@@ -1,5 +1,10 @@
+mod callgraph;
+mod cells;
 mod locals;
+mod noalloc;
 pub mod types;
 
+pub use callgraph::{CallGraph, CallNode};
+pub use cells::box_mutable_captures;
 pub use locals::LocalsIndexer;
-pub use types::TypeChecker;
+pub use types::{LanguageOptions, TypeChecker};
@@ -0,0 +1,335 @@
+use super::callgraph::CallGraph;
+use super::types::TypeError;
+use crate::ast::tast::{Expr, TypedExpr, TypedProgram, TypedStatement};
+use crate::ast::{BinaryOp, Builtin, Type, TypeKind, UnaryOp};
+
+/// A function as the walk finds it, in the order [`CallGraph`] lists them.
+struct Function {
+    name: String,
+    noalloc: bool,
+    returns: Type,
+    /// The first thing its own body does that allocates.
+    allocates: Option<&'static str>,
+}
+
+/// Checks that nothing a function marked `@noalloc` runs can allocate,
+/// neither in its own body nor in the functions it calls, which it must
+/// call by name. Such a function can't trigger a collection, so it needs
+/// no shadow stack frame to root its values in.
+pub fn check(program: &TypedProgram) -> Vec<TypeError> {
+    let graph = CallGraph::new(program);
+    let mut walker = Walker {
+        functions: vec![],
+        enclosing: vec![],
+    };
+    walker.block(&program.statements);
+    let functions = walker.functions;
+
+    // Why each function may allocate, spreading from callees to their
+    // callers until nothing changes.
+    let mut reasons: Vec<Option<String>> = functions
+        .iter()
+        .zip(&graph.functions)
+        .map(|(function, node)| match function.allocates {
+            Some(reason) => Some(reason.to_string()),
+            None if node.calls_indirectly => {
+                Some("calls a function held in a variable, field or list".to_string())
+            }
+            None => None,
+        })
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, node) in graph.functions.iter().enumerate() {
+            if reasons[i].is_some() {
+                continue;
+            }
+            if let Some(&callee) = node.calls.iter().find(|&&callee| reasons[callee].is_some()) {
+                reasons[i] = Some(format!(
+                    "calls '{}', which may allocate",
+                    functions[callee].name
+                ));
+                changed = true;
+            }
+        }
+    }
+
+    functions
+        .iter()
+        .zip(reasons)
+        .filter(|(function, _)| function.noalloc)
+        .filter_map(|(function, reason)| {
+            reason.map(|reason| {
+                TypeError::new(format!(
+                    "Function '{}' is marked @noalloc but {}",
+                    function.name, reason
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Whether storing a `value` where `expected` is wanted puts it in a new
+/// box, as wrapping does for null and for values that become nullable or
+/// errorable.
+fn boxes(value: &Type, expected: &Type) -> bool {
+    value.kind == TypeKind::Null
+        || (!value.nullable && !value.errorable && (expected.nullable || expected.errorable))
+}
+
+struct Walker {
+    functions: Vec<Function>,
+    /// The functions being walked, innermost last.
+    enclosing: Vec<usize>,
+}
+
+impl Walker {
+    /// Notes that the function being walked allocates, keeping the first
+    /// reason found.
+    fn allocates(&mut self, reason: &'static str) {
+        if let Some(&current) = self.enclosing.last() {
+            self.functions[current].allocates.get_or_insert(reason);
+        }
+    }
+
+    fn stores(&mut self, value: &TypedExpr, expected: &Type) {
+        if boxes(&value.ty, expected) {
+            self.allocates("boxes a value that becomes nullable or errorable");
+        }
+    }
+
+    fn block(&mut self, statements: &[TypedStatement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &TypedStatement) {
+        match statement {
+            TypedStatement::Expr(expr)
+            | TypedStatement::Print(expr)
+            | TypedStatement::Produce(expr)
+            | TypedStatement::Defer(expr) => self.expr(expr),
+            TypedStatement::Raise(expr) => {
+                self.expr(expr);
+                self.allocates("raises an error");
+            }
+            TypedStatement::Return(Some(expr)) => {
+                self.expr(expr);
+                if let Some(&current) = self.enclosing.last() {
+                    let returns = self.functions[current].returns.clone();
+                    self.stores(expr, &returns);
+                }
+            }
+            TypedStatement::Return(None) => self.allocates("returns a boxed null"),
+            TypedStatement::Break(_)
+            | TypedStatement::Continue(_)
+            | TypedStatement::Struct { .. }
+            | TypedStatement::Error { .. } => {}
+            TypedStatement::Let { ty, value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                    self.stores(value, ty);
+                }
+            }
+            TypedStatement::Const { ty, value, .. } => {
+                self.expr(value);
+                self.stores(value, ty);
+            }
+            TypedStatement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            TypedStatement::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                self.statement(init);
+                self.expr(condition);
+                self.block(body);
+                self.statement(update);
+            }
+            TypedStatement::While { condition, body } => {
+                self.expr(condition);
+                self.block(body);
+            }
+            TypedStatement::IfLet {
+                value,
+                then_block,
+                else_block,
+                ..
+            } => {
+                self.expr(value);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            TypedStatement::WhileLet { value, body, .. } => {
+                self.expr(value);
+                self.block(body);
+            }
+            TypedStatement::Unchecked { body } => self.block(body),
+            TypedStatement::Function {
+                name,
+                returns,
+                body,
+                noalloc,
+                ..
+            } => {
+                self.allocates("declares a function, whose captures are allocated");
+                let index = self.functions.len();
+                self.functions.push(Function {
+                    name: name.clone(),
+                    noalloc: *noalloc,
+                    returns: returns.clone(),
+                    allocates: None,
+                });
+                self.enclosing.push(index);
+                self.block(body);
+                self.enclosing.pop();
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &TypedExpr) {
+        match &expr.expr {
+            Expr::Null
+            | Expr::Integer(_)
+            | Expr::Float(_)
+            | Expr::Boolean(_)
+            | Expr::Identifier(_) => {}
+            Expr::String(_) => self.allocates("builds a string"),
+            Expr::List(items) | Expr::Array(items) => {
+                self.allocates("builds a list");
+                self.exprs(items);
+            }
+            Expr::New { fields, .. } => {
+                self.allocates("creates a struct");
+                for (_, value) in fields {
+                    self.expr(value);
+                }
+            }
+            Expr::Builtin { builtin, args } => {
+                let allocates = !matches!(
+                    builtin,
+                    Builtin::ToInteger
+                        | Builtin::ToFloat
+                        | Builtin::Abs
+                        | Builtin::Min
+                        | Builtin::Max
+                        | Builtin::Sqrt
+                        | Builtin::Floor
+                        | Builtin::Ceil
+                        | Builtin::Round
+                        | Builtin::Sin
+                        | Builtin::Cos
+                        | Builtin::Tan
+                        | Builtin::Asin
+                        | Builtin::Acos
+                        | Builtin::Atan
+                        | Builtin::Atan2
+                        | Builtin::StringLength
+                        | Builtin::StringFind
+                        | Builtin::Ord
+                        | Builtin::ParseInteger
+                        | Builtin::ParseFloat
+                        | Builtin::ParseFailed
+                        | Builtin::CompareStrings
+                        | Builtin::Present
+                        | Builtin::Holds
+                        | Builtin::HoldsError
+                        | Builtin::HeldError
+                        | Builtin::HeapUsed
+                        | Builtin::HeapFree
+                        | Builtin::GcCount
+                        | Builtin::AllocatedSinceGc
+                        | Builtin::LargestFreeBlock
+                        | Builtin::SetGcThreshold
+                );
+                if allocates {
+                    self.allocates("calls a builtin that allocates or suspends");
+                }
+                self.exprs(args);
+            }
+            Expr::ExternCall { args, .. } => {
+                // The host builds the strings and lists it returns.
+                if matches!(expr.ty.kind, TypeKind::String | TypeKind::List { .. })
+                    || expr.ty.nullable
+                    || expr.ty.errorable
+                {
+                    self.allocates("calls an extern function that returns a pointer");
+                }
+                self.exprs(args);
+            }
+            Expr::Field { object, .. } => self.expr(object),
+            Expr::Index { object, key } => {
+                if object.ty.kind == TypeKind::String {
+                    self.allocates("builds a string");
+                }
+                self.expr(object);
+                self.expr(key);
+            }
+            Expr::Binary { left, op, right } => {
+                let sequence = matches!(expr.ty.kind, TypeKind::String | TypeKind::List { .. });
+                if sequence && *op == BinaryOp::Plus {
+                    self.allocates("concatenates");
+                } else if sequence && *op == BinaryOp::Multiply {
+                    self.allocates("repeats a string or list");
+                }
+                self.expr(left);
+                self.expr(right);
+                if *op == BinaryOp::Is {
+                    self.stores(right, &left.ty);
+                }
+            }
+            Expr::Unary { op, expr } => {
+                if *op == UnaryOp::Stringify {
+                    self.allocates("builds a string");
+                }
+                self.expr(expr);
+            }
+            Expr::UnwrapError(expr) | Expr::UnwrapNull(expr) => self.expr(expr),
+            Expr::Call { callee, args } => {
+                self.expr(callee);
+                self.exprs(args);
+                if let TypeKind::Function { params, .. } = &callee.ty.kind {
+                    for (arg, param) in args.iter().zip(params) {
+                        self.stores(arg, param);
+                    }
+                }
+            }
+            Expr::Match { expr, arms, .. } => {
+                self.expr(expr);
+                for (condition, body) in arms {
+                    if let Some(condition) = condition {
+                        self.expr(condition);
+                    }
+                    self.block(body);
+                }
+            }
+            Expr::Slice { expr, start, end } => {
+                self.allocates("slices");
+                self.expr(expr);
+                self.expr(start);
+                self.expr(end);
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[TypedExpr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+}
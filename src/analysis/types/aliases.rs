@@ -0,0 +1,370 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::{self, Expr, Pattern, Statement, Type, TypeKind};
+use std::collections::{HashMap, HashSet};
+
+impl TypeChecker {
+    /// The program with every `type` alias replaced by the type it names,
+    /// and the aliases themselves dropped. Modules share one set of
+    /// aliases, the way they share struct names.
+    pub(super) fn resolve_aliases(&mut self, program: &ast::Program) -> ast::Program {
+        let mut order = Vec::new();
+        let mut taken = HashSet::new();
+        collect(&program.statements, &mut order, &mut taken);
+
+        let mut declared = HashMap::new();
+        for (name, ty) in &order {
+            if declared.insert(name.clone(), ty.clone()).is_some() {
+                self.diagnostics.push(TypeError::new(format!(
+                    "Type alias '{}' is declared more than once",
+                    name
+                )));
+            }
+        }
+        for (name, ty) in &order {
+            if taken.contains(name) {
+                self.diagnostics.push(TypeError::new(format!(
+                    "Type alias '{}' has the name of a struct or error",
+                    name
+                )));
+                continue;
+            }
+            match expand(ty, &declared, &mut vec![name.clone()]) {
+                Ok(ty) => {
+                    self.aliases.insert(name.clone(), ty);
+                }
+                Err(e) => self.diagnostics.push(e),
+            }
+        }
+
+        let mut statements = program.statements.clone();
+        self.resolve_block(&mut statements);
+        ast::Program { statements }
+    }
+
+    fn resolve_block(&self, statements: &mut Vec<Statement>) {
+        statements.retain(|stmt| !matches!(stmt, Statement::TypeAlias { .. }));
+        for stmt in statements {
+            self.resolve_stmt(stmt);
+        }
+    }
+
+    fn resolve_stmt(&self, stmt: &mut Statement) {
+        match stmt {
+            Statement::Expr(expr)
+            | Statement::Defer(expr)
+            | Statement::Print(expr)
+            | Statement::Produce(expr)
+            | Statement::Raise(expr)
+            | Statement::Return(Some(expr)) => self.resolve_expr(expr),
+            Statement::Let { ty, value, .. } => {
+                self.resolve_type(ty);
+                if let Some(value) = value {
+                    self.resolve_expr(value);
+                }
+            }
+            Statement::Const { ty, value, .. } => {
+                self.resolve_type(ty);
+                self.resolve_expr(value);
+            }
+            Statement::If {
+                condition: value,
+                then_block,
+                else_block,
+            }
+            | Statement::IfLet {
+                value,
+                then_block,
+                else_block,
+                ..
+            } => {
+                self.resolve_expr(value);
+                self.resolve_block(then_block);
+                if let Some(else_block) = else_block {
+                    self.resolve_block(else_block);
+                }
+            }
+            Statement::For {
+                init,
+                condition,
+                update,
+                body,
+                ..
+            } => {
+                self.resolve_stmt(init);
+                self.resolve_expr(condition);
+                self.resolve_stmt(update);
+                self.resolve_block(body);
+            }
+            Statement::While {
+                condition: value,
+                body,
+                ..
+            }
+            | Statement::WhileLet { value, body, .. }
+            | Statement::ForIn {
+                iterable: value,
+                body,
+                ..
+            } => {
+                self.resolve_expr(value);
+                self.resolve_block(body);
+            }
+            Statement::Unchecked { body } | Statement::Module { body, .. } => {
+                self.resolve_block(body)
+            }
+            Statement::Function {
+                params,
+                returns,
+                body,
+                ..
+            } => {
+                self.resolve_signature(params, returns);
+                self.resolve_block(body);
+            }
+            Statement::Extern {
+                params, returns, ..
+            } => self.resolve_signature(params, returns),
+            Statement::Struct { fields, .. } | Statement::Error { fields, .. } => {
+                for (_, ty) in fields {
+                    self.resolve_type(ty);
+                }
+            }
+            Statement::Assert {
+                condition, message, ..
+            } => {
+                self.resolve_expr(condition);
+                if let Some(message) = message {
+                    self.resolve_expr(message);
+                }
+            }
+            Statement::Println(exprs) => {
+                for expr in exprs {
+                    self.resolve_expr(expr);
+                }
+            }
+            Statement::Return(None)
+            | Statement::Break(_)
+            | Statement::Continue(_)
+            | Statement::Import { .. }
+            | Statement::TypeAlias { .. } => {}
+        }
+    }
+
+    fn resolve_signature(&self, params: &mut [(String, Type)], returns: &mut Type) {
+        for (_, ty) in params {
+            self.resolve_type(ty);
+        }
+        self.resolve_type(returns);
+    }
+
+    fn resolve_expr(&self, expr: &mut Expr) {
+        match expr {
+            Expr::List(items) | Expr::Array(items) => {
+                for item in items {
+                    self.resolve_expr(item);
+                }
+            }
+            Expr::Field { object: expr, .. }
+            | Expr::Unary { expr, .. }
+            | Expr::UnwrapError(expr)
+            | Expr::UnwrapNull(expr)
+            | Expr::Await(expr) => self.resolve_expr(expr),
+            Expr::Index {
+                object: left,
+                key: right,
+            }
+            | Expr::Binary { left, right, .. }
+            | Expr::Range {
+                start: left,
+                end: right,
+            } => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            Expr::Slice { expr, start, end }
+            | Expr::If {
+                condition: expr,
+                then_value: start,
+                else_value: end,
+            } => {
+                self.resolve_expr(expr);
+                self.resolve_expr(start);
+                self.resolve_expr(end);
+            }
+            Expr::New { fields, .. } => {
+                for (_, value) in fields {
+                    self.resolve_expr(value);
+                }
+            }
+            Expr::Call { callee, args } => {
+                self.resolve_expr(callee);
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            Expr::Match { expr, arms, .. } => {
+                self.resolve_expr(expr);
+                for (pattern, body) in arms {
+                    self.resolve_pattern(pattern);
+                    self.resolve_block(body);
+                }
+            }
+            Expr::Lambda {
+                params,
+                returns,
+                body,
+            } => {
+                self.resolve_signature(params, returns);
+                self.resolve_block(body);
+            }
+            Expr::Null
+            | Expr::Integer(_)
+            | Expr::Float(_)
+            | Expr::String(_)
+            | Expr::Boolean(_)
+            | Expr::Identifier(_) => {}
+        }
+    }
+
+    fn resolve_pattern(&self, pattern: &mut Pattern) {
+        match pattern {
+            Pattern::MatchType(ty) => self.resolve_type(ty),
+            Pattern::Literal(expr) => self.resolve_expr(expr),
+            Pattern::Struct { fields, .. } => {
+                for (_, pattern) in fields {
+                    self.resolve_pattern(pattern);
+                }
+            }
+            Pattern::MatchNull | Pattern::MatchError | Pattern::MatchAll | Pattern::Binding(_) => {}
+        }
+    }
+
+    fn resolve_type(&self, ty: &mut Type) {
+        if let Some(resolved) = substitute(ty, &self.aliases) {
+            *ty = resolved;
+        }
+    }
+}
+
+/// Gathers the aliases declared at the top of the program and its modules,
+/// and the struct and error names they may not reuse.
+fn collect(
+    statements: &[Statement],
+    declared: &mut Vec<(String, Type)>,
+    taken: &mut HashSet<String>,
+) {
+    for stmt in statements {
+        match stmt {
+            Statement::TypeAlias { name, ty } => declared.push((name.clone(), ty.clone())),
+            Statement::Struct { name, .. } | Statement::Error { name, .. } => {
+                taken.insert(name.clone());
+            }
+            Statement::Module { body, .. } => collect(body, declared, taken),
+            _ => {}
+        }
+    }
+}
+
+/// `ty` with the aliases it names expanded, themselves expanded in turn.
+/// `expanding` holds the aliases being expanded, to catch one that names
+/// itself.
+fn expand(
+    ty: &Type,
+    declared: &HashMap<String, Type>,
+    expanding: &mut Vec<String>,
+) -> Result<Type, TypeError> {
+    let mut ty = ty.clone();
+    match &mut ty.kind {
+        TypeKind::Struct { name } => {
+            if let Some(target) = declared.get(name) {
+                if expanding.contains(name) {
+                    return Err(TypeError::new(format!(
+                        "Type alias '{}' refers to itself",
+                        expanding[0]
+                    )));
+                }
+                expanding.push(name.clone());
+                let target = expand(target, declared, expanding)?;
+                expanding.pop();
+                return Ok(qualified(target, &ty));
+            }
+        }
+        TypeKind::List { element } | TypeKind::Array { element, .. } => {
+            **element = expand(element, declared, expanding)?;
+        }
+        TypeKind::Function { params, returns } => {
+            for param in params {
+                *param = expand(param, declared, expanding)?;
+            }
+            **returns = expand(returns, declared, expanding)?;
+        }
+        _ => {}
+    }
+    Ok(ty)
+}
+
+/// `ty` with the aliases in `aliases`, already expanded, put in place of
+/// their names, or `None` when it names none.
+fn substitute(ty: &Type, aliases: &HashMap<String, Type>) -> Option<Type> {
+    match &ty.kind {
+        TypeKind::Struct { name } => aliases
+            .get(name)
+            .map(|target| qualified(target.clone(), ty)),
+        TypeKind::List { element } => Some(Type {
+            kind: TypeKind::List {
+                element: Box::new(substitute(element, aliases)?),
+            },
+            ..ty.clone()
+        }),
+        TypeKind::Array { element, length } => Some(Type {
+            kind: TypeKind::Array {
+                element: Box::new(substitute(element, aliases)?),
+                length: *length,
+            },
+            ..ty.clone()
+        }),
+        TypeKind::Function { params, returns } => {
+            let mut changed = false;
+            let mut swap = |ty: &Type| match substitute(ty, aliases) {
+                Some(ty) => {
+                    changed = true;
+                    ty
+                }
+                None => ty.clone(),
+            };
+            let params = params.iter().map(&mut swap).collect();
+            let returns = Box::new(swap(returns));
+            changed.then(|| Type {
+                kind: TypeKind::Function { params, returns },
+                ..ty.clone()
+            })
+        }
+        _ => None,
+    }
+}
+
+/// An alias's type as named by `usage`, which may add `?` or `!` to it.
+fn qualified(target: Type, usage: &Type) -> Type {
+    let errors = match (target.errorable, usage.errorable) {
+        (true, true) if target.errors.is_empty() || usage.errors.is_empty() => vec![],
+        (true, true) => {
+            let mut errors = target.errors.clone();
+            errors.extend(
+                usage
+                    .errors
+                    .iter()
+                    .filter(|e| !target.errors.contains(e))
+                    .cloned(),
+            );
+            errors
+        }
+        (true, false) => target.errors.clone(),
+        (false, _) => usage.errors.clone(),
+    };
+    Type {
+        nullable: target.nullable || usage.nullable,
+        errorable: target.errorable || usage.errorable,
+        errors,
+        kind: target.kind,
+    }
+}
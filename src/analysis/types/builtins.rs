@@ -0,0 +1,551 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{self, Builtin, Type, TypeKind};
+
+impl TypeChecker {
+    /// Checks a call to a free-standing builtin such as `builder()`.
+    /// Returns `None` when `name` is not a builtin, so the caller can report
+    /// the usual undefined identifier error.
+    pub fn check_builtin_call(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        match name {
+            "builder" => {
+                if !args.is_empty() {
+                    return Err(TypeError::new("builder() takes no arguments"));
+                }
+                Ok(Some(self.new_builder()))
+            }
+            "gcstats" => {
+                if !args.is_empty() {
+                    return Err(TypeError::new("gcstats() takes no arguments"));
+                }
+                let stat = |name: &str, builtin| {
+                    (
+                        name.to_string(),
+                        TypedExpr {
+                            expr: tast::Expr::Builtin {
+                                builtin,
+                                args: vec![],
+                            },
+                            ty: plain(TypeKind::Integer),
+                        },
+                    )
+                };
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::New {
+                        name: "GcStats".to_string(),
+                        fields: vec![
+                            stat("used", Builtin::HeapUsed),
+                            stat("free", Builtin::HeapFree),
+                            stat("collections", Builtin::GcCount),
+                            stat("allocated", Builtin::AllocatedSinceGc),
+                            stat("largest_free", Builtin::LargestFreeBlock),
+                        ],
+                    },
+                    ty: plain(TypeKind::Struct {
+                        name: "GcStats".to_string(),
+                    }),
+                }))
+            }
+            "gc" => {
+                if !args.is_empty() {
+                    return Err(TypeError::new("gc() takes no arguments"));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Collect,
+                        args: vec![],
+                    },
+                    ty: plain(TypeKind::Integer),
+                }))
+            }
+            "set_gc_threshold" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("set_gc_threshold() takes a number of bytes"));
+                }
+                let bytes = self.check_expr(&args[0])?;
+                if !self.is_assignable(&bytes.ty, &plain(TypeKind::Integer)) {
+                    return Err(self.mismatch(
+                        "Incompatible argument type in call to 'set_gc_threshold'",
+                        &bytes.ty,
+                        &plain(TypeKind::Integer),
+                    ));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::SetGcThreshold,
+                        args: vec![bytes],
+                    },
+                    ty: plain(TypeKind::Integer),
+                }))
+            }
+            "int" | "float" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new(format!("{}() takes one number", name)));
+                }
+                let value = self.check_expr(&args[0])?;
+                if !self.is_numeric(&value.ty) || value.ty.nullable || value.ty.errorable {
+                    return Err(TypeError::new(format!(
+                        "{}() takes a non-nullable, non-errorable number",
+                        name
+                    )));
+                }
+                let (builtin, kind) = if name == "int" {
+                    (Builtin::ToInteger, TypeKind::Integer)
+                } else {
+                    (Builtin::ToFloat, TypeKind::Float)
+                };
+                if value.ty.kind == kind {
+                    return Ok(Some(value));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin,
+                        args: vec![value],
+                    },
+                    ty: plain(kind),
+                }))
+            }
+            "type_name" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("type_name() takes one value"));
+                }
+                // Only the static type matters, so the value is never run.
+                let value = self.check_expr(&args[0])?;
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::String(value.ty.to_string()),
+                    ty: plain(TypeKind::String),
+                }))
+            }
+            "fields_of" => {
+                let fields = match args {
+                    [ast::Expr::Identifier(name)] => self.structs.get(name).map(|s| &s.0),
+                    _ => None,
+                };
+                let Some(fields) = fields else {
+                    return Err(TypeError::new("fields_of() takes the name of a struct"));
+                };
+                let names = fields
+                    .iter()
+                    .map(|(field, _)| TypedExpr {
+                        expr: tast::Expr::String(field.clone()),
+                        ty: plain(TypeKind::String),
+                    })
+                    .collect();
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::List(names),
+                    ty: plain(TypeKind::List {
+                        element: Box::new(plain(TypeKind::String)),
+                    }),
+                }))
+            }
+            "repeat" => {
+                if args.len() != 2 {
+                    return Err(TypeError::new("repeat() takes a value and a count"));
+                }
+                let value = self.check_expr(&args[0])?;
+                let count = self.check_expr(&args[1])?;
+                if !self.is_assignable(&count.ty, &plain(TypeKind::Integer)) {
+                    return Err(TypeError::new("repeat() count must be an integer"));
+                }
+                let ty = plain(TypeKind::List {
+                    element: Box::new(value.ty.clone()),
+                });
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Repeat,
+                        args: vec![value, count],
+                    },
+                    ty,
+                }))
+            }
+            "ord" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("ord() takes a one-character string"));
+                }
+                let c = self.check_expr(&args[0])?;
+                if !self.is_assignable(&c.ty, &plain(TypeKind::String)) {
+                    return Err(self.mismatch(
+                        "Incompatible argument type in call to 'ord'",
+                        &c.ty,
+                        &plain(TypeKind::String),
+                    ));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Ord,
+                        args: vec![c],
+                    },
+                    ty: plain(TypeKind::Integer),
+                }))
+            }
+            "chr" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("chr() takes a code point"));
+                }
+                let code = self.check_expr(&args[0])?;
+                if !self.is_assignable(&code.ty, &plain(TypeKind::Integer)) {
+                    return Err(self.mismatch(
+                        "Incompatible argument type in call to 'chr'",
+                        &code.ty,
+                        &plain(TypeKind::Integer),
+                    ));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Chr,
+                        args: vec![code],
+                    },
+                    ty: plain(TypeKind::String),
+                }))
+            }
+            "env" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("env() takes the name of a variable"));
+                }
+                let name = self.check_expr(&args[0])?;
+                if !self.is_assignable(&name.ty, &plain(TypeKind::String)) {
+                    return Err(self.mismatch(
+                        "Incompatible argument type in call to 'env'",
+                        &name.ty,
+                        &plain(TypeKind::String),
+                    ));
+                }
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Env,
+                        args: vec![name],
+                    },
+                    ty: Type {
+                        kind: TypeKind::String,
+                        nullable: true,
+                        errorable: false,
+                        errors: vec![],
+                    },
+                }))
+            }
+            "exit" => {
+                if args.len() != 1 {
+                    return Err(TypeError::new("exit() takes the code to exit with"));
+                }
+                let code = self.check_expr(&args[0])?;
+                if !self.is_assignable(&code.ty, &plain(TypeKind::Integer)) {
+                    return Err(self.mismatch(
+                        "Incompatible argument type in call to 'exit'",
+                        &code.ty,
+                        &plain(TypeKind::Integer),
+                    ));
+                }
+                // The call never evaluates to anything; an integer lets it
+                // stand anywhere an expression can.
+                Ok(Some(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Exit,
+                        args: vec![code],
+                    },
+                    ty: plain(TypeKind::Integer),
+                }))
+            }
+            "sleep" | "fetch" => Err(TypeError::new(format!(
+                "{}() suspends the program, so it must be called with await",
+                name
+            ))),
+            "format" => self.check_format(args).map(Some),
+            "to_json" => self.check_to_json(args).map(Some),
+            "parse_int" | "parse_float" => self.check_parse(name, args).map(Some),
+            "sort" | "sort_by" => self.check_sort(name, args).map(Some),
+            "abs" | "min" | "max" | "sqrt" | "floor" | "ceil" | "round" | "sin" | "cos" | "tan"
+            | "asin" | "acos" | "atan" | "atan2" => self.check_math(name, args).map(Some),
+            "from_json" => Err(TypeError::new(
+                "from_json() needs to know what to read; assign it to a variable declared as T!",
+            )),
+            _ => match self.check_json_builtin(name, args)? {
+                Some(typed) => Ok(Some(typed)),
+                None => match self.check_parse_builtin(name, args)? {
+                    Some(typed) => Ok(Some(typed)),
+                    None => self.check_compare_builtin(name, args),
+                },
+            },
+        }
+    }
+
+    /// Checks `await call`, where `call` is one of the async host calls:
+    /// `sleep(ms)` waits and returns how many milliseconds passed, and
+    /// `fetch(url)` returns the body of the response.
+    pub(super) fn check_await(&mut self, call: &ast::Expr) -> Result<TypedExpr, TypeError> {
+        let (name, args) = match call {
+            ast::Expr::Call { callee, args } => match callee.as_ref() {
+                ast::Expr::Identifier(name) if self.lookup(name).is_none() => (name, args),
+                _ => return Err(not_awaitable()),
+            },
+            _ => return Err(not_awaitable()),
+        };
+        let (builtin, param, returns) = match name.as_str() {
+            "sleep" => (Builtin::Sleep, TypeKind::Integer, TypeKind::Integer),
+            "fetch" => (Builtin::Fetch, TypeKind::String, TypeKind::String),
+            _ => return Err(not_awaitable()),
+        };
+        let param = plain(param);
+        if args.len() != 1 {
+            return Err(TypeError::new(format!("{}() takes one {}", name, param)));
+        }
+        let arg = self.check_expr(&args[0])?;
+        if !self.is_assignable(&arg.ty, &param) {
+            return Err(self.mismatch(
+                format!("Incompatible argument type in call to '{}'", name),
+                &arg.ty,
+                &param,
+            ));
+        }
+        Ok(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin,
+                args: vec![arg],
+            },
+            ty: plain(returns),
+        })
+    }
+
+    /// An empty `Builder`, as made by `builder()`.
+    pub(super) fn new_builder(&self) -> TypedExpr {
+        TypedExpr {
+            expr: tast::Expr::New {
+                name: "Builder".to_string(),
+                fields: vec![
+                    (
+                        "buffer".to_string(),
+                        TypedExpr {
+                            expr: tast::Expr::String(String::new()),
+                            ty: plain(TypeKind::String),
+                        },
+                    ),
+                    (
+                        "length".to_string(),
+                        TypedExpr {
+                            expr: tast::Expr::Integer(0),
+                            ty: plain(TypeKind::Integer),
+                        },
+                    ),
+                ],
+            },
+            ty: plain(TypeKind::Struct {
+                name: "Builder".to_string(),
+            }),
+        }
+    }
+
+    /// Whether `ty` is a struct declaring a field called `field`.
+    pub fn has_field(&self, ty: &Type, field: &str) -> bool {
+        match &ty.kind {
+            TypeKind::Struct { name } => self
+                .structs
+                .get(name)
+                .is_some_and(|fields| fields.0.iter().any(|(fname, _)| fname == field)),
+            _ => false,
+        }
+    }
+
+    /// Checks `object.method(args)` where `method` is a builtin on the
+    /// object's type rather than a field holding a function. Returns `None`
+    /// when the type has no such builtin.
+    pub fn check_method_call(
+        &mut self,
+        object: TypedExpr,
+        method: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        if matches!(method, "map" | "filter" | "reduce" | "get") {
+            return self.check_list_function(object, method, args);
+        }
+        let builtin = match (&object.ty.kind, method) {
+            (TypeKind::Struct { name }, "append") if name == "Builder" => Builtin::BuilderAppend,
+            (TypeKind::Struct { name }, "to_string") if name == "Builder" => {
+                Builtin::BuilderToString
+            }
+            (TypeKind::List { .. }, "push") => Builtin::ListPush,
+            (TypeKind::String, "length") => Builtin::StringLength,
+            (TypeKind::String, "find") => Builtin::StringFind,
+            (TypeKind::String, "substring") => Builtin::StringSubstring,
+            (TypeKind::String, "split") => Builtin::StringSplit,
+            (TypeKind::String, "to_upper") => Builtin::StringToUpper,
+            (TypeKind::String, "to_lower") => Builtin::StringToLower,
+            (TypeKind::String, "trim") => Builtin::StringTrim,
+            (TypeKind::String, "replace") => Builtin::StringReplace,
+            _ => return Ok(None),
+        };
+
+        if object.ty.nullable || object.ty.errorable {
+            return Err(TypeError::new("Method call on nullable or errorable type"));
+        }
+
+        if builtin == Builtin::ListPush && !is_place(&object.expr) {
+            return Err(TypeError::new(
+                "push() needs a variable or field to store the list in",
+            ));
+        }
+
+        let (params, returns) = builtin_signature(builtin, &object.ty);
+        if params.len() != args.len() {
+            return Err(TypeError::new(format!(
+                "Method '{}' expects {} arguments, got {}",
+                method,
+                params.len(),
+                args.len()
+            )));
+        }
+
+        let mut typed_args = vec![object];
+        for (arg, param) in args.iter().zip(params.iter()) {
+            let typed_arg = self.check_expr(arg)?;
+            let typed_arg = self.widen(typed_arg, param);
+            if !self.is_assignable(&typed_arg.ty, param) {
+                return Err(self.mismatch(
+                    format!("Incompatible argument type in call to '{}'", method),
+                    &typed_arg.ty,
+                    param,
+                ));
+            }
+            typed_args.push(typed_arg);
+        }
+
+        let call = TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin,
+                args: typed_args,
+            },
+            ty: returns,
+        };
+
+        if builtin == Builtin::ListPush {
+            // Growing may move the list, so store it back where it came from.
+            let place = match &call.expr {
+                tast::Expr::Builtin { args, .. } => args[0].clone(),
+                _ => unreachable!(),
+            };
+            let ty = place.ty.clone();
+            return Ok(Some(TypedExpr {
+                expr: tast::Expr::Binary {
+                    left: Box::new(place),
+                    op: ast::BinaryOp::Is,
+                    right: Box::new(call),
+                },
+                ty,
+            }));
+        }
+
+        Ok(Some(call))
+    }
+}
+
+/// Whether `expr` names storage that can be assigned to and is free to read
+/// twice.
+fn is_place(expr: &tast::Expr) -> bool {
+    match expr {
+        tast::Expr::Identifier(_) => true,
+        tast::Expr::Field { object, .. } => is_place(&object.expr),
+        _ => false,
+    }
+}
+
+/// Parameter and return types of a builtin called on `receiver`, excluding
+/// the receiver itself.
+fn builtin_signature(builtin: Builtin, receiver: &Type) -> (Vec<Type>, Type) {
+    match builtin {
+        Builtin::BuilderAppend => (
+            vec![plain(TypeKind::String)],
+            plain(TypeKind::Struct {
+                name: "Builder".to_string(),
+            }),
+        ),
+        Builtin::BuilderToString => (vec![], plain(TypeKind::String)),
+        Builtin::ListPush => match &receiver.kind {
+            TypeKind::List { element } => (vec![(**element).clone()], receiver.clone()),
+            _ => unreachable!("push is only looked up on lists"),
+        },
+        Builtin::StringLength => (vec![], plain(TypeKind::Integer)),
+        Builtin::StringFind => (vec![plain(TypeKind::String)], plain(TypeKind::Integer)),
+        Builtin::StringSubstring => (
+            vec![plain(TypeKind::Integer), plain(TypeKind::Integer)],
+            plain(TypeKind::String),
+        ),
+        Builtin::StringSplit => (
+            vec![plain(TypeKind::String)],
+            plain(TypeKind::List {
+                element: Box::new(plain(TypeKind::String)),
+            }),
+        ),
+        Builtin::StringToUpper | Builtin::StringToLower | Builtin::StringTrim => {
+            (vec![], plain(TypeKind::String))
+        }
+        Builtin::StringReplace => (
+            vec![plain(TypeKind::String), plain(TypeKind::String)],
+            plain(TypeKind::String),
+        ),
+        Builtin::Repeat
+        | Builtin::ToInteger
+        | Builtin::ToFloat
+        | Builtin::Abs
+        | Builtin::Min
+        | Builtin::Max
+        | Builtin::Sqrt
+        | Builtin::Floor
+        | Builtin::Ceil
+        | Builtin::Round
+        | Builtin::Sin
+        | Builtin::Cos
+        | Builtin::Tan
+        | Builtin::Asin
+        | Builtin::Acos
+        | Builtin::Atan
+        | Builtin::Atan2
+        | Builtin::Present
+        | Builtin::Holds
+        | Builtin::HoldsError
+        | Builtin::HeldError
+        | Builtin::HeapUsed
+        | Builtin::HeapFree
+        | Builtin::GcCount
+        | Builtin::AllocatedSinceGc
+        | Builtin::LargestFreeBlock
+        | Builtin::Collect
+        | Builtin::SetGcThreshold
+        | Builtin::JsonReset
+        | Builtin::JsonFail
+        | Builtin::JsonFinish
+        | Builtin::JsonEat
+        | Builtin::JsonExpect
+        | Builtin::JsonNull
+        | Builtin::JsonBoolean
+        | Builtin::JsonInteger
+        | Builtin::JsonFloat
+        | Builtin::JsonString
+        | Builtin::JsonSkip
+        | Builtin::JsonQuote
+        | Builtin::ParseInteger
+        | Builtin::ParseFloat
+        | Builtin::ParseFailed
+        | Builtin::CompareStrings
+        | Builtin::Ord
+        | Builtin::Chr
+        | Builtin::Sleep
+        | Builtin::Fetch
+        | Builtin::Env
+        | Builtin::Exit => unreachable!("not a method"),
+    }
+}
+
+fn not_awaitable() -> TypeError {
+    TypeError::new("Only async host calls can be awaited: sleep(ms) or fetch(url)")
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
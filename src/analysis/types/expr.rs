@@ -1,4 +1,4 @@
-use super::{TypeChecker, TypeError};
+use super::{Narrowing, TypeChecker, TypeError};
 use crate::ast::{self, Type, TypeKind};
 use crate::ast::tast::{self, TypedExpr};
 
@@ -11,6 +11,7 @@ impl TypeChecker {
                     kind: TypeKind::Null,
                     nullable: true,
                     errorable: false,
+                    errors: vec![],
                 },
             }),
 
@@ -20,6 +21,7 @@ impl TypeChecker {
                     kind: TypeKind::Integer,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 },
             }),
 
@@ -29,6 +31,7 @@ impl TypeChecker {
                     kind: TypeKind::Float,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 },
             }),
 
@@ -38,6 +41,7 @@ impl TypeChecker {
                     kind: TypeKind::String,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 },
             }),
 
@@ -47,16 +51,20 @@ impl TypeChecker {
                     kind: TypeKind::Boolean,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 },
             }),
 
-            ast::Expr::Identifier(name) => match self.lookup(name) {
-                Some(ty) => Ok(TypedExpr {
-                    expr: tast::Expr::Identifier(name.clone()),
-                    ty: ty.clone(),
-                }),
-                None => Err(TypeError::new(format!("Undefined identifier '{}'", name))),
-            },
+            ast::Expr::Identifier(name) => {
+                match self.lookup(name) {
+                    Some(ty) => Ok(self.read_variable(name, ty.clone())),
+                    None if self.externs.contains_key(name) => Err(TypeError::new(format!(
+                        "Extern function '{}' can only be called",
+                        name
+                    ))),
+                    None => Err(self.undefined("Identifier", name)),
+                }
+            }
 
             ast::Expr::List(elements) => {
                 if elements.is_empty() {
@@ -66,10 +74,12 @@ impl TypeChecker {
                                 kind: TypeKind::Unknown,
                                 nullable: false,
                                 errorable: false,
+                                errors: vec![],
                             }),
                         },
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     };
                     Ok(TypedExpr {
                         expr: tast::Expr::List(vec![]),
@@ -86,7 +96,11 @@ impl TypeChecker {
                         if self.is_assignable(&element_type, &typed_elem.ty) {
                             element_type = typed_elem.ty.clone();
                         } else if !self.is_assignable(&typed_elem.ty, &element_type) {
-                            return Err(TypeError::new("Incompatible types in list literal"));
+                            return Err(self.mismatch(
+                                "Incompatible types in list literal",
+                                &typed_elem.ty,
+                                &element_type,
+                            ));
                         }
                         typed_elements.push(typed_elem);
                     }
@@ -99,11 +113,47 @@ impl TypeChecker {
                             },
                             nullable: false,
                             errorable: false,
+                            errors: vec![],
                         },
                     })
                 }
             }
 
+            ast::Expr::Array(elements) => {
+                let mut typed_elements = Vec::new();
+                let mut element_type: Option<Type> = None;
+
+                for elem in elements {
+                    let typed_elem = self.check_expr(elem)?;
+                    match &element_type {
+                        None => element_type = Some(typed_elem.ty.clone()),
+                        Some(ty) => {
+                            if !self.is_assignable(&typed_elem.ty, ty) {
+                                return Err(self.mismatch(
+                                    "Incompatible types in array literal",
+                                    &typed_elem.ty,
+                                    ty,
+                                ));
+                            }
+                        }
+                    }
+                    typed_elements.push(typed_elem);
+                }
+
+                Ok(TypedExpr {
+                    ty: Type {
+                        kind: TypeKind::Array {
+                            element: Box::new(element_type.unwrap()),
+                            length: typed_elements.len() as u32,
+                        },
+                        nullable: false,
+                        errorable: false,
+                        errors: vec![],
+                    },
+                    expr: tast::Expr::Array(typed_elements),
+                })
+            }
+
             ast::Expr::Field { object, field } => {
                 let typed_object = self.check_expr(object)?;
 
@@ -115,7 +165,7 @@ impl TypeChecker {
                         .structs
                         .get(name)
                         .and_then(|fields| fields.0.iter().find(|(fname, _)| fname == field))
-                        .map(|(_, ftype)| ftype.clone())
+                        .map(|(_, ftype)| self.field_value_type(ftype))
                         .ok_or_else(|| {
                             TypeError::new(format!("Type '{}' has no field '{}'", name, field))
                         })?;
@@ -155,6 +205,31 @@ impl TypeChecker {
                     } else {
                         Err(TypeError::new("List index must be of type integer"))
                     }
+                } else if let TypeKind::Array { element, length } = &typed_object.ty.kind {
+                    if typed_key.ty.kind != TypeKind::Integer
+                        || typed_key.ty.nullable
+                        || typed_key.ty.errorable
+                    {
+                        return Err(TypeError::new("Array index must be of type integer"));
+                    }
+                    if let tast::Expr::Integer(n) = typed_key.expr {
+                        if n < 0 || n >= *length as i64 {
+                            return Err(TypeError::new(format!(
+                                "Index {} is out of bounds for array of length {}",
+                                n, length
+                            )));
+                        }
+                    }
+                    let elem_type = element.as_ref().clone();
+                    Ok(TypedExpr {
+                        expr: tast::Expr::Index {
+                            object: Box::new(typed_object),
+                            key: Box::new(typed_key),
+                        },
+                        ty: elem_type,
+                    })
+                } else if typed_object.ty.kind == TypeKind::String {
+                    self.check_character(typed_object, typed_key)
                 } else {
                     Err(TypeError::new("Index access on non-list type"))
                 }
@@ -189,6 +264,7 @@ impl TypeChecker {
                                 },
                                 nullable: false,
                                 errorable: false,
+                                errors: vec![],
                             },
                         })
                     } else {
@@ -203,7 +279,8 @@ impl TypeChecker {
                 let struct_fields = self
                     .structs
                     .get(name)
-                    .ok_or_else(|| TypeError::new(format!("Undefined struct '{}'", name)))?
+                    .filter(|_| self.hidden_in(name).is_none())
+                    .ok_or_else(|| self.undefined("Struct", name))?
                     .clone();
 
                 if struct_fields.0.len() != fields.len() {
@@ -223,13 +300,20 @@ impl TypeChecker {
                         .find(|(fname, _)| fname == field_name);
                     match expected_field {
                         Some((_, expected_type)) => {
+                            let expected_type = self.field_value_type(expected_type);
                             let typed_expr = self.check_expr(field_expr)?;
-                            if !self.is_assignable(&typed_expr.ty, expected_type) {
-                                return Err(TypeError::new(format!(
-                                    "Incompatible type for field '{}' in struct '{}'",
-                                    field_name, name
-                                )));
+                            let typed_expr = self.widen(typed_expr, &expected_type);
+                            if !self.is_assignable(&typed_expr.ty, &expected_type) {
+                                return Err(self.mismatch(
+                                    format!(
+                                        "Incompatible type for field '{}' in struct '{}'",
+                                        field_name, name
+                                    ),
+                                    &typed_expr.ty,
+                                    &expected_type,
+                                ));
                             }
+                            self.check_array_source(&typed_expr)?;
                             typed_fields.push((field_name.clone(), typed_expr));
                         }
                         None => {
@@ -250,14 +334,57 @@ impl TypeChecker {
                         kind: TypeKind::Struct { name: name.clone() },
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     },
                 })
             }
 
             ast::Expr::Binary { left, op, right } => {
-                let typed_left = self.check_expr(left)?;
-                let typed_right = self.check_expr(right)?;
+                if matches!(op, ast::BinaryOp::Eq | ast::BinaryOp::Neq)
+                    && (matches!(**left, ast::Expr::Null) || matches!(**right, ast::Expr::Null))
+                {
+                    return self.check_null_comparison(left, op, right);
+                }
+                let (typed_left, typed_right) = match (op, left.as_ref()) {
+                    (ast::BinaryOp::Is, ast::Expr::Identifier(name)) => {
+                        self.check_not_constant(name)?;
+                        // The value is read while `name` is still narrowed.
+                        let typed_right = self.check_expr(right)?;
+                        self.end_narrowing(name);
+                        (self.check_expr(left)?, typed_right)
+                    }
+                    (
+                        ast::BinaryOp::Is,
+                        ast::Expr::Field { .. } | ast::Expr::Index { .. } | ast::Expr::Slice { .. },
+                    ) => {
+                        let typed_left = self.check_expr(left)?;
+                        if let ast::Expr::Index { .. } = left.as_ref() {
+                            if typed_left.ty.kind == TypeKind::String
+                                && !matches!(typed_left.expr, tast::Expr::Index { .. })
+                            {
+                                return Err(TypeError::new(
+                                    "Strings cannot be changed, so their characters cannot be assigned",
+                                ));
+                            }
+                        }
+                        (typed_left, self.check_expr(right)?)
+                    }
+                    (ast::BinaryOp::Is, _) => {
+                        return Err(TypeError::new(
+                            "Only variables, fields, list elements and slices can be assigned to",
+                        ))
+                    }
+                    (ast::BinaryOp::And | ast::BinaryOp::Or, _) => {
+                        let typed_left = self.check_expr(left)?;
+                        (typed_left, self.check_guarded(left, op, right)?)
+                    }
+                    _ => (self.check_expr(left)?, self.check_expr(right)?),
+                };
+                let (typed_left, typed_right) = self.match_numbers(op, typed_left, typed_right)?;
                 let result_ty = self.check_binary_types(&typed_left.ty, op, &typed_right.ty)?;
+                if *op == ast::BinaryOp::Is {
+                    self.check_array_source(&typed_right)?;
+                }
 
                 Ok(TypedExpr {
                     expr: tast::Expr::Binary {
@@ -271,6 +398,9 @@ impl TypeChecker {
 
             ast::Expr::Unary { op, expr } => {
                 let typed_expr = self.check_expr(expr)?;
+                if *op == ast::UnaryOp::Stringify {
+                    return self.check_stringify(typed_expr);
+                }
                 let result_ty = self.check_unary_types(op, &typed_expr.ty)?;
 
                 Ok(TypedExpr {
@@ -283,6 +413,26 @@ impl TypeChecker {
             }
 
             ast::Expr::Call { callee, args } => {
+                match callee.as_ref() {
+                    ast::Expr::Identifier(name) if self.lookup(name).is_none() => {
+                        if let Some(typed) = self.check_extern_call(name, args)? {
+                            return Ok(typed);
+                        }
+                        if let Some(typed) = self.check_builtin_call(name, args)? {
+                            return Ok(typed);
+                        }
+                    }
+                    ast::Expr::Field { object, field } => {
+                        let typed_object = self.check_expr(object)?;
+                        if !self.has_field(&typed_object.ty, field) {
+                            if let Some(typed) = self.check_method_call(typed_object, field, args)? {
+                                return Ok(typed);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+
                 let typed_callee = self.check_expr(callee)?;
 
                 if let TypeKind::Function { params, returns } = &typed_callee.ty.kind {
@@ -301,9 +451,12 @@ impl TypeChecker {
                     let mut typed_args = Vec::new();
                     for (i, arg) in args.iter().enumerate() {
                         let typed_arg = self.check_expr(arg)?;
+                        let typed_arg = self.widen(typed_arg, &params[i]);
                         if !self.is_assignable(&typed_arg.ty, &params[i]) {
-                            return Err(TypeError::new(
+                            return Err(self.mismatch(
                                 "Incompatible argument type in function call",
+                                &typed_arg.ty,
+                                &params[i],
                             ));
                         }
                         typed_args.push(typed_arg);
@@ -325,8 +478,24 @@ impl TypeChecker {
                 expr,
                 binding,
                 arms,
-            } => {
-                todo!()
+            } => self.check_match(expr, binding, arms),
+
+            ast::Expr::Await(call) => self.check_await(call),
+
+            ast::Expr::Lambda {
+                params,
+                returns,
+                body,
+            } => self.check_lambda(params, returns, body),
+
+            ast::Expr::If {
+                condition,
+                then_value,
+                else_value,
+            } => self.check_if(condition, then_value, else_value),
+
+            ast::Expr::Range { .. } => {
+                Err(TypeError::new("Ranges can only be looped over with for-in"))
             }
 
             ast::Expr::UnwrapNull(inner) => {
@@ -336,11 +505,10 @@ impl TypeChecker {
                         kind: typed_inner.ty.kind.clone(),
                         nullable: false,
                         errorable: typed_inner.ty.errorable,
+                        errors: typed_inner.ty.errors.clone(),
                     };
-                    Ok(TypedExpr {
-                        expr: tast::Expr::UnwrapNull(Box::new(typed_inner)),
-                        ty: result_ty,
-                    })
+                    self.narrow_unwrapped(inner, Narrowing::NOT_NULL);
+                    self.check_unwrap(typed_inner, true, result_ty)
                 } else {
                     Err(TypeError::new("Expression is not nullable"))
                 }
@@ -353,11 +521,10 @@ impl TypeChecker {
                         kind: typed_inner.ty.kind.clone(),
                         nullable: typed_inner.ty.nullable,
                         errorable: false,
+                        errors: vec![],
                     };
-                    Ok(TypedExpr {
-                        expr: tast::Expr::UnwrapError(Box::new(typed_inner)),
-                        ty: result_ty,
-                    })
+                    self.narrow_unwrapped(inner, Narrowing::NOT_ERROR);
+                    self.check_unwrap(typed_inner, false, result_ty)
                 } else {
                     Err(TypeError::new("Expression is not errorable"))
                 }
@@ -365,6 +532,54 @@ impl TypeChecker {
         }
     }
 
+    /// Numeric operators need both operands to be the same kind of number,
+    /// and an assignment needs a value of the target's kind. An integer
+    /// meeting a float is widened when the options allow it and is an error
+    /// that names the conversion otherwise.
+    fn match_numbers(
+        &self,
+        op: &ast::BinaryOp,
+        left: TypedExpr,
+        right: TypedExpr,
+    ) -> Result<(TypedExpr, TypedExpr), TypeError> {
+        if *op == ast::BinaryOp::Is {
+            let right = self.widen(right, &left.ty);
+            return Ok((left, right));
+        }
+
+        let numeric = matches!(
+            op,
+            ast::BinaryOp::Plus
+                | ast::BinaryOp::Minus
+                | ast::BinaryOp::Multiply
+                | ast::BinaryOp::Divide
+                | ast::BinaryOp::Power
+                | ast::BinaryOp::Modulo
+                | ast::BinaryOp::Lt
+                | ast::BinaryOp::Gt
+                | ast::BinaryOp::Lte
+                | ast::BinaryOp::Gte
+                | ast::BinaryOp::Eq
+                | ast::BinaryOp::Neq
+        );
+        let mixed = matches!(
+            (&left.ty.kind, &right.ty.kind),
+            (TypeKind::Integer, TypeKind::Float) | (TypeKind::Float, TypeKind::Integer)
+        );
+        if !numeric || !mixed {
+            return Ok((left, right));
+        }
+
+        let left = self.widen(left, &right.ty);
+        let right = self.widen(right, &left.ty);
+        if left.ty.kind != right.ty.kind {
+            return Err(TypeError::new(
+                "Cannot mix integer and float operands: convert the integer with float(...) or the float with int(...)",
+            ));
+        }
+        Ok((left, right))
+    }
+
     fn check_binary_types(
         &self,
         left_ty: &Type,
@@ -387,6 +602,7 @@ impl TypeChecker {
                         kind: TypeKind::String,
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     });
                 }
                 if let (
@@ -416,6 +632,7 @@ impl TypeChecker {
                         },
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     });
                 }
                 if !self.is_numeric(left_ty) || left_ty.nullable || left_ty.errorable {
@@ -437,6 +654,27 @@ impl TypeChecker {
                     },
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
+                })
+            }
+            ast::BinaryOp::Multiply
+                if matches!(left_ty.kind, TypeKind::String | TypeKind::List { .. }) =>
+            {
+                if left_ty.nullable || left_ty.errorable {
+                    return Err(TypeError::new(
+                        "Repeated strings and lists must be non-nullable and non-errorable",
+                    ));
+                }
+                if right_ty.kind != TypeKind::Integer || right_ty.nullable || right_ty.errorable {
+                    return Err(TypeError::new(
+                        "Strings and lists can only be repeated an integer number of times",
+                    ));
+                }
+                Ok(Type {
+                    kind: left_ty.kind.clone(),
+                    nullable: false,
+                    errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::Minus
@@ -463,6 +701,7 @@ impl TypeChecker {
                     },
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::And | ast::BinaryOp::Or => {
@@ -480,9 +719,13 @@ impl TypeChecker {
                     kind: TypeKind::Boolean,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::Eq | ast::BinaryOp::Neq => {
+                if matches!(left_ty.kind, TypeKind::Array { .. }) {
+                    return Err(TypeError::new("Cannot compare fixed arrays"));
+                }
                 if left_ty.nullable || left_ty.errorable || right_ty.nullable || right_ty.errorable
                 {
                     return Err(TypeError::new("Cannot compare nullable or errorable types"));
@@ -494,6 +737,7 @@ impl TypeChecker {
                     kind: TypeKind::Boolean,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::Lt | ast::BinaryOp::Gt | ast::BinaryOp::Lte | ast::BinaryOp::Gte => {
@@ -511,12 +755,14 @@ impl TypeChecker {
                     kind: TypeKind::Boolean,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::BitwiseAnd
             | ast::BinaryOp::BitwiseOr
             | ast::BinaryOp::Xor
             | ast::BinaryOp::Sll
+            | ast::BinaryOp::Sra
             | ast::BinaryOp::Srl => {
                 if left_ty.kind != TypeKind::Integer || left_ty.nullable || left_ty.errorable {
                     return Err(TypeError::new(
@@ -532,11 +778,16 @@ impl TypeChecker {
                     kind: TypeKind::Integer,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::BinaryOp::Is => {
                 if !self.is_assignable(right_ty, left_ty) {
-                    return Err(TypeError::new("Cannot assign: incompatible types"));
+                    return Err(self.mismatch(
+                        "Cannot assign: incompatible types",
+                        right_ty,
+                        left_ty,
+                    ));
                 }
                 Ok(left_ty.clone())
             }
@@ -556,6 +807,7 @@ impl TypeChecker {
                         kind: TypeKind::Boolean,
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     })
                 } else {
                     Err(TypeError::new("Right operand must be a list"))
@@ -564,7 +816,7 @@ impl TypeChecker {
         }
     }
 
-    fn check_unary_types(&self, op: &ast::UnaryOp, expr_ty: &Type) -> Result<Type, TypeError> {
+    pub(super) fn check_unary_types(&self, op: &ast::UnaryOp, expr_ty: &Type) -> Result<Type, TypeError> {
         match op {
             ast::UnaryOp::Not => {
                 if !self.is_boolean(expr_ty) || expr_ty.nullable || expr_ty.errorable {
@@ -576,6 +828,7 @@ impl TypeChecker {
                     kind: TypeKind::Boolean,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
             ast::UnaryOp::Minus => {
@@ -597,23 +850,168 @@ impl TypeChecker {
                         kind: TypeKind::Integer,
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
+                    })
+                } else if let TypeKind::Array { .. } = &expr_ty.kind {
+                    Ok(Type {
+                        kind: TypeKind::Integer,
+                        nullable: false,
+                        errorable: false,
+                        errors: vec![],
                     })
                 } else {
                     Err(TypeError::new("Operand must be a list"))
                 }
             }
             &ast::UnaryOp::Stringify => {
-                if expr_ty.nullable || expr_ty.errorable {
-                    return Err(TypeError::new(
-                        "Operand must be non-nullable and non-errorable",
-                    ));
+                if matches!(expr_ty.kind, TypeKind::Array { .. }) {
+                    return Err(TypeError::new("Cannot stringify a fixed array"));
+                }
+                if matches!(expr_ty.kind, TypeKind::Function { .. }) {
+                    return Err(TypeError::new("Cannot stringify a function"));
                 }
                 Ok(Type {
                     kind: TypeKind::String,
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 })
             }
         }
     }
+
+    /// Arrays are copied slot by slot into their struct, so a value stored
+    /// into an array field must be spelled out as a literal.
+    fn check_array_source(&self, value: &TypedExpr) -> Result<(), TypeError> {
+        if matches!(value.ty.kind, TypeKind::Array { .. })
+            && !matches!(value.expr, tast::Expr::Array(_))
+        {
+            return Err(TypeError::new(
+                "Fixed arrays can only be assigned from array literals",
+            ));
+        }
+        Ok(())
+    }
+
+    /// A lambda is a nested `fn` with a name no program can spell, declared
+    /// in a match arm that produces it, so it becomes a closure the same way.
+    fn check_lambda(
+        &mut self,
+        params: &[(String, Type)],
+        returns: &Type,
+        body: &[ast::Statement],
+    ) -> Result<TypedExpr, TypeError> {
+        let name = format!("lambda.{}", self.lambdas);
+        self.lambdas += 1;
+        self.push_scope();
+        let function = self.check_stmt(&ast::Statement::Function {
+            name: name.clone(),
+            params: params.to_vec(),
+            returns: returns.clone(),
+            body: body.to_vec(),
+            exported: false,
+            noalloc: false,
+        });
+        let ty = self.lookup(&name).cloned();
+        self.pop_scope();
+        let (function, ty) = (function?, ty.expect("the lambda was just declared"));
+
+        let boolean = Type {
+            kind: TypeKind::Boolean,
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+        let lambda = TypedExpr {
+            expr: tast::Expr::Identifier(name),
+            ty: ty.clone(),
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Match {
+                expr: Box::new(TypedExpr {
+                    expr: tast::Expr::Boolean(true),
+                    ty: boolean,
+                }),
+                binding: "lambda".to_string(),
+                arms: vec![(None, vec![function, tast::TypedStatement::Produce(lambda)])],
+            },
+            ty,
+        })
+    }
+
+    /// Checks `if condition { then_value } else { else_value }` as a match
+    /// on the condition whose arms produce the two values. Each value is
+    /// checked with what its branch proves about the condition, and the
+    /// result takes whichever branch's type holds the other's.
+    fn check_if(
+        &mut self,
+        condition: &ast::Expr,
+        then_value: &ast::Expr,
+        else_value: &ast::Expr,
+    ) -> Result<TypedExpr, TypeError> {
+        let facts = self.condition_facts(condition);
+        let mut typed_condition = self.check_expr(condition)?;
+        if typed_condition.ty.nullable && !typed_condition.ty.errorable {
+            typed_condition = self.check_presence(typed_condition)?;
+        } else if !self.is_boolean(&typed_condition.ty)
+            || typed_condition.ty.nullable
+            || typed_condition.ty.errorable
+        {
+            return Err(TypeError::new(
+                "If condition must be a non-nullable, non-errorable boolean",
+            ));
+        }
+
+        self.push_scope();
+        self.narrow_all(&facts.when_true);
+        let typed_then = self.check_expr(then_value);
+        self.pop_scope();
+        self.push_scope();
+        self.narrow_all(&facts.when_false);
+        let typed_else = self.check_expr(else_value);
+        self.pop_scope();
+        let (typed_then, typed_else) = (typed_then?, typed_else?);
+
+        let ty = match (&typed_then.ty.kind, &typed_else.ty.kind) {
+            (TypeKind::Null, TypeKind::Null) => typed_then.ty.clone(),
+            (TypeKind::Null, _) => Type {
+                nullable: true,
+                ..typed_else.ty.clone()
+            },
+            (_, TypeKind::Null) => Type {
+                nullable: true,
+                ..typed_then.ty.clone()
+            },
+            _ if self.is_assignable(&typed_else.ty, &typed_then.ty) => typed_then.ty.clone(),
+            _ if self.is_assignable(&typed_then.ty, &typed_else.ty) => typed_else.ty.clone(),
+            _ => {
+                return Err(self.mismatch(
+                    "If expression branches have different types",
+                    &typed_else.ty,
+                    &typed_then.ty,
+                ))
+            }
+        };
+        let typed_then = self.widen(typed_then, &ty);
+        let typed_else = self.widen(typed_else, &ty);
+
+        let boolean = typed_condition.ty.clone();
+        Ok(TypedExpr {
+            expr: tast::Expr::Match {
+                expr: Box::new(typed_condition),
+                binding: "if".to_string(),
+                arms: vec![
+                    (
+                        Some(TypedExpr {
+                            expr: tast::Expr::Identifier("if".to_string()),
+                            ty: boolean,
+                        }),
+                        vec![tast::TypedStatement::Produce(typed_then)],
+                    ),
+                    (None, vec![tast::TypedStatement::Produce(typed_else)]),
+                ],
+            },
+            ty,
+        })
+    }
 }
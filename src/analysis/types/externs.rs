@@ -0,0 +1,96 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{self, Extern, Type, TypeKind};
+
+/// What an `extern fn` was declared with.
+#[derive(Debug, Clone)]
+pub struct ExternSignature {
+    pub function: Extern,
+    pub params: Vec<Type>,
+    pub returns: Type,
+}
+
+impl TypeChecker {
+    /// Records an `extern fn`, whose calls become imports from the host.
+    pub fn declare_extern(
+        &mut self,
+        function: &Extern,
+        params: &[(String, Type)],
+        returns: &Type,
+    ) -> Result<(), TypeError> {
+        let name = &function.name;
+        if self.externs.contains_key(name) || self.scopes[0].contains_key(name) {
+            return Err(TypeError::new(format!("'{}' is already declared", name)));
+        }
+        for ty in params.iter().map(|(_, ty)| ty).chain([returns]) {
+            if !crosses_to_host(ty) {
+                return Err(TypeError::new(format!(
+                    "Extern function '{}' can only take and return integers, floats, booleans and strings, not {}",
+                    name, ty
+                )));
+            }
+        }
+        self.externs.insert(
+            name.clone(),
+            ExternSignature {
+                function: function.clone(),
+                params: params.iter().map(|(_, ty)| ty.clone()).collect(),
+                returns: returns.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Checks a call to an `extern fn`. Returns `None` when `name` is not
+    /// one, so the caller can try the builtins.
+    pub fn check_extern_call(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        let Some(signature) = self.externs.get(name).cloned() else {
+            return Ok(None);
+        };
+        if self.hidden_in(name).is_some() {
+            return Err(self.undefined("Function", name));
+        }
+        if signature.params.len() != args.len() {
+            return Err(TypeError::new(format!(
+                "{}() takes {} arguments",
+                name,
+                signature.params.len()
+            )));
+        }
+
+        let mut typed_args = Vec::new();
+        for (arg, param) in args.iter().zip(&signature.params) {
+            let typed_arg = self.check_expr(arg)?;
+            let typed_arg = self.widen(typed_arg, param);
+            if !self.is_assignable(&typed_arg.ty, param) {
+                return Err(self.mismatch(
+                    format!("Incompatible argument type in call to '{}'", name),
+                    &typed_arg.ty,
+                    param,
+                ));
+            }
+            typed_args.push(typed_arg);
+        }
+
+        Ok(Some(TypedExpr {
+            expr: tast::Expr::ExternCall {
+                function: signature.function,
+                args: typed_args,
+            },
+            ty: signature.returns,
+        }))
+    }
+}
+
+/// Whether values of `ty` can be handed to the host and back as they are.
+fn crosses_to_host(ty: &Type) -> bool {
+    matches!(
+        ty.kind,
+        TypeKind::Integer | TypeKind::Float | TypeKind::Boolean | TypeKind::String
+    ) && !ty.nullable
+        && !ty.errorable
+}
@@ -0,0 +1,257 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr, TypedStatement};
+use crate::ast::{self, Builtin, Type, TypeKind};
+use crate::frontend::Parser;
+use crate::stdlib::json;
+
+impl TypeChecker {
+    /// `to_json(x)` becomes a call to the writer generated for the type of
+    /// `x`, filling a fresh builder.
+    pub(super) fn check_to_json(&mut self, args: &[ast::Expr]) -> Result<TypedExpr, TypeError> {
+        if args.len() != 1 {
+            return Err(TypeError::new("to_json() takes one value"));
+        }
+        let value = self.check_expr(&args[0])?;
+        if let Some(reason) = json::unsupported(&value.ty, &self.structs) {
+            return Err(TypeError::new(format!(
+                "to_json() cannot write {}: {}",
+                value.ty, reason
+            )));
+        }
+        let index = position_or_push(&mut self.json_writes, &value.ty);
+        let builder = self.new_builder();
+        let writer = TypedExpr {
+            expr: tast::Expr::Identifier(json::writer_name(index)),
+            ty: plain(TypeKind::Function {
+                params: vec![value.ty.clone(), builder.ty.clone()],
+                returns: Box::new(builder.ty.clone()),
+            }),
+        };
+        let filled = TypedExpr {
+            expr: tast::Expr::Call {
+                callee: Box::new(writer),
+                args: vec![value, builder.clone()],
+            },
+            ty: builder.ty,
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin: Builtin::BuilderToString,
+                args: vec![filled],
+            },
+            ty: plain(TypeKind::String),
+        })
+    }
+
+    /// `from_json(s)` reads whichever type it is bound to, so it is only
+    /// checked against the declared type of a `let`, `const` or `return`.
+    pub(super) fn check_from_json(
+        &mut self,
+        args: &[ast::Expr],
+        expected: &Type,
+    ) -> Result<TypedExpr, TypeError> {
+        if args.len() != 1 {
+            return Err(TypeError::new("from_json() takes one string"));
+        }
+        let document = self.check_expr(&args[0])?;
+        if !self.is_assignable(&document.ty, &plain(TypeKind::String)) {
+            return Err(TypeError::new("from_json() takes one string"));
+        }
+        if !expected.errorable {
+            return Err(TypeError::new(format!(
+                "from_json() can fail, so its result must be stored as {}!, not {}",
+                expected, expected
+            )));
+        }
+        let ty = Type {
+            errorable: false,
+            errors: vec![],
+            ..expected.clone()
+        };
+        if let Some(reason) = json::unsupported(&ty, &self.structs) {
+            return Err(TypeError::new(format!(
+                "from_json() cannot read {}: {}",
+                ty, reason
+            )));
+        }
+        let index = position_or_push(&mut self.json_reads, &ty);
+        let reader = TypedExpr {
+            expr: tast::Expr::Identifier(json::reader_name(index)),
+            ty: plain(TypeKind::Function {
+                params: vec![plain(TypeKind::String)],
+                returns: Box::new(expected.clone()),
+            }),
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Call {
+                callee: Box::new(reader),
+                args: vec![document],
+            },
+            ty: expected.clone(),
+        })
+    }
+
+    /// Checks a value about to be stored as `expected`. Only `from_json`
+    /// looks at the type; anything else is checked as usual.
+    pub(super) fn check_expr_as(
+        &mut self,
+        expr: &ast::Expr,
+        expected: &Type,
+    ) -> Result<TypedExpr, TypeError> {
+        if let ast::Expr::Call { callee, args } = expr {
+            if let ast::Expr::Identifier(name) = callee.as_ref() {
+                if name == "from_json" && self.lookup(name).is_none() {
+                    return self.check_from_json(args, expected);
+                }
+            }
+        }
+        self.check_expr(expr)
+    }
+
+    /// The JSON reader's builtins, which only generated helpers may call.
+    pub(super) fn check_json_builtin(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        let (builtin, params, returns) = match name {
+            "json_reset" => (Builtin::JsonReset, 0, TypeKind::Boolean),
+            "json_fail" => (Builtin::JsonFail, 0, TypeKind::Boolean),
+            "json_finish" => (Builtin::JsonFinish, 1, TypeKind::Boolean),
+            "json_eat" => (Builtin::JsonEat, 2, TypeKind::Boolean),
+            "json_expect" => (Builtin::JsonExpect, 2, TypeKind::Boolean),
+            "json_null" => (Builtin::JsonNull, 1, TypeKind::Boolean),
+            "json_boolean" => (Builtin::JsonBoolean, 1, TypeKind::Boolean),
+            "json_integer" => (Builtin::JsonInteger, 1, TypeKind::Integer),
+            "json_float" => (Builtin::JsonFloat, 1, TypeKind::Float),
+            "json_string" => (Builtin::JsonString, 1, TypeKind::String),
+            "json_skip" => (Builtin::JsonSkip, 1, TypeKind::Boolean),
+            "json_quote" => (Builtin::JsonQuote, 1, TypeKind::String),
+            _ => return Ok(None),
+        };
+        if !self.generating {
+            return Ok(None);
+        }
+        // The document or string comes first, then a character code.
+        assert_eq!(args.len(), params, "{}() in generated JSON helper", name);
+        let mut typed_args = Vec::new();
+        for arg in args {
+            typed_args.push(self.check_expr(arg)?);
+        }
+        Ok(Some(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin,
+                args: typed_args,
+            },
+            ty: plain(returns),
+        }))
+    }
+
+    /// Generates and checks the helpers that `to_json` and `from_json`
+    /// calls refer to, so they can go at the top of `main`. Struct helpers
+    /// come before the helpers that call them.
+    pub(super) fn json_helpers(&mut self) -> Result<Vec<TypedStatement>, TypeError> {
+        let mut source = String::new();
+
+        let mut encoded = Vec::new();
+        for ty in &self.json_writes {
+            json::structs_in(ty, &mut encoded);
+        }
+        for name in self.dependency_order(encoded, "JSON helpers")? {
+            source.push_str(&json::struct_encoder(&name, &self.structs));
+        }
+        let mut decoded = Vec::new();
+        for ty in &self.json_reads {
+            json::structs_in(ty, &mut decoded);
+        }
+        for name in self.dependency_order(decoded, "JSON helpers")? {
+            source.push_str(&json::struct_decoder(&name, &self.structs));
+        }
+        for (index, ty) in self.json_writes.iter().enumerate() {
+            source.push_str(&json::writer(index, ty, &self.structs));
+        }
+        for (index, ty) in self.json_reads.iter().enumerate() {
+            source.push_str(&json::reader(index, ty, &self.structs));
+        }
+
+        let program = Parser::new(&source)
+            .parse_program()
+            .expect("generated JSON helpers should parse");
+        self.generating = true;
+        self.push_scope();
+        let helpers = self.check_block(&program.statements);
+        self.pop_scope();
+        self.generating = false;
+        Ok(helpers)
+    }
+
+    /// Orders `roots` and every struct reachable from them so that each
+    /// comes after the structs its fields hold. Helpers can call themselves
+    /// but not helpers defined after them, so structs that hold each other
+    /// are rejected. `what` names the helpers in that error.
+    pub(super) fn dependency_order(
+        &self,
+        roots: Vec<String>,
+        what: &str,
+    ) -> Result<Vec<String>, TypeError> {
+        let mut order = Vec::new();
+        let mut path = Vec::new();
+        for root in roots {
+            self.visit_struct(root, what, &mut order, &mut path)?;
+        }
+        Ok(order)
+    }
+
+    fn visit_struct(
+        &self,
+        name: String,
+        what: &str,
+        order: &mut Vec<String>,
+        path: &mut Vec<String>,
+    ) -> Result<(), TypeError> {
+        if order.contains(&name) {
+            return Ok(());
+        }
+        if path.contains(&name) {
+            return Err(TypeError::new(format!(
+                "{} cannot handle structs that hold each other: {}",
+                what,
+                path.join(", ")
+            )));
+        }
+        let mut held = Vec::new();
+        if let Some((fields, _)) = self.structs.get(&name) {
+            for (_, ty) in fields {
+                json::structs_in(ty, &mut held);
+            }
+        }
+        path.push(name.clone());
+        for inner in held {
+            if inner != name {
+                self.visit_struct(inner, what, order, path)?;
+            }
+        }
+        path.pop();
+        order.push(name);
+        Ok(())
+    }
+}
+
+pub(super) fn position_or_push(types: &mut Vec<Type>, ty: &Type) -> usize {
+    match types.iter().position(|seen| seen == ty) {
+        Some(index) => index,
+        None => {
+            types.push(ty.clone());
+            types.len() - 1
+        }
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
@@ -0,0 +1,280 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr, TypedStatement};
+use crate::ast::{self, Builtin, Type, TypeKind};
+use crate::frontend::Parser;
+use crate::stdlib::lists::ListFunction;
+
+impl TypeChecker {
+    /// `xs.map(f)`, `xs.filter(f)`, `xs.reduce(initial, f)` and `xs.get(i)`
+    /// on a list become calls to the helper generated for their types. Returns `None`
+    /// when `method` isn't one of them.
+    pub(super) fn check_list_function(
+        &mut self,
+        list: TypedExpr,
+        method: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        let TypeKind::List { element } = &list.ty.kind else {
+            return Ok(None);
+        };
+        let element = (**element).clone();
+        let arity = match method {
+            "map" | "filter" | "get" => 1,
+            "reduce" => 2,
+            _ => return Ok(None),
+        };
+        if list.ty.nullable || list.ty.errorable {
+            return Err(TypeError::new("Method call on nullable or errorable type"));
+        }
+        if args.len() != arity {
+            return Err(TypeError::new(format!(
+                "Method '{}' expects {} arguments, got {}",
+                method,
+                arity,
+                args.len()
+            )));
+        }
+
+        if method == "get" {
+            let index = self.check_expr(&args[0])?;
+            if index.ty != plain(TypeKind::Integer) {
+                return Err(TypeError::new(format!(
+                    "get() takes an integer index, found {}",
+                    index.ty
+                )));
+            }
+            return Ok(Some(self.call_list_function(
+                ListFunction::Get { element },
+                vec![list, index],
+            )));
+        }
+
+        let f = self.check_expr(&args[arity - 1])?;
+        let TypeKind::Function { params, returns } = &f.ty.kind else {
+            return Err(TypeError::new(format!(
+                "{}() takes a function, found {}",
+                method, f.ty
+            )));
+        };
+        let (params, returns) = (params.clone(), (**returns).clone());
+        if f.ty.nullable || f.ty.errorable || returns.errorable {
+            return Err(TypeError::new(format!(
+                "{}() takes a function that can't be null or raise",
+                method
+            )));
+        }
+
+        let mut typed_args = vec![list];
+        let (function, expected) = match method {
+            "map" => (
+                ListFunction::Map {
+                    element: element.clone(),
+                    to: returns.clone(),
+                },
+                vec![element.clone()],
+            ),
+            "filter" => (
+                ListFunction::Filter {
+                    element: element.clone(),
+                },
+                vec![element.clone()],
+            ),
+            _ => {
+                let initial = self.check_expr(&args[0])?;
+                let initial = self.widen(initial, &returns);
+                if !self.is_assignable(&initial.ty, &returns) {
+                    return Err(self.mismatch(
+                        "Incompatible initial value in call to 'reduce'",
+                        &initial.ty,
+                        &returns,
+                    ));
+                }
+                typed_args.push(initial);
+                (
+                    ListFunction::Reduce {
+                        element: element.clone(),
+                        accumulator: returns.clone(),
+                    },
+                    vec![returns.clone(), element.clone()],
+                )
+            }
+        };
+        if params != expected || (method == "filter" && returns != plain(TypeKind::Boolean)) {
+            let wanted = Type {
+                kind: TypeKind::Function {
+                    params: expected,
+                    returns: Box::new(match method {
+                        "filter" => plain(TypeKind::Boolean),
+                        _ => returns.clone(),
+                    }),
+                },
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            };
+            return Err(TypeError::new(format!(
+                "{}() on a {} takes a function of type {}, found {}",
+                method, typed_args[0].ty, wanted, f.ty
+            )));
+        }
+        typed_args.push(f);
+        Ok(Some(self.call_list_function(function, typed_args)))
+    }
+
+    /// `sort(xs)` and `sort_by(xs, cmp)`, which give a sorted copy of `xs`
+    /// through a generated helper too. `sort` orders numbers and strings,
+    /// strings byte by byte; `sort_by` puts `a` before `b` when `cmp(a, b)`
+    /// is negative. Both keep equal elements in order.
+    pub(super) fn check_sort(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<TypedExpr, TypeError> {
+        let arity = if name == "sort" { 1 } else { 2 };
+        if args.len() != arity {
+            return Err(TypeError::new(if arity == 1 {
+                "sort() takes a list".to_string()
+            } else {
+                "sort_by() takes a list and a function comparing two of its elements".to_string()
+            }));
+        }
+        let list = self.check_expr(&args[0])?;
+        let element = match &list.ty.kind {
+            TypeKind::List { element } if !list.ty.nullable && !list.ty.errorable => {
+                (**element).clone()
+            }
+            _ => {
+                return Err(TypeError::new(format!(
+                    "{}() takes a non-nullable, non-errorable list, found {}",
+                    name, list.ty
+                )))
+            }
+        };
+
+        if name == "sort" {
+            let comparable = matches!(
+                element.kind,
+                TypeKind::Integer | TypeKind::Float | TypeKind::String
+            ) && !element.nullable
+                && !element.errorable;
+            if !comparable {
+                return Err(TypeError::new(format!(
+                    "sort() orders numbers and strings, not {}; use sort_by() with a function comparing them",
+                    element
+                )));
+            }
+            return Ok(self.call_list_function(ListFunction::Sort { element }, vec![list]));
+        }
+
+        let cmp = self.check_expr(&args[1])?;
+        let wanted = plain(TypeKind::Function {
+            params: vec![element.clone(), element.clone()],
+            returns: Box::new(plain(TypeKind::Integer)),
+        });
+        if cmp.ty != wanted {
+            return Err(TypeError::new(format!(
+                "sort_by() on a {} takes a function of type {}, found {}",
+                list.ty, wanted, cmp.ty
+            )));
+        }
+        Ok(self.call_list_function(ListFunction::SortBy { element }, vec![list, cmp]))
+    }
+
+    /// The `compare` builtin, which orders two strings for the generated
+    /// `sort` helpers.
+    pub(super) fn check_compare_builtin(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        if name != "compare" || !self.generating {
+            return Ok(None);
+        }
+        assert_eq!(args.len(), 2, "compare() in generated sort helper");
+        let mut typed_args = Vec::new();
+        for arg in args {
+            typed_args.push(self.check_expr(arg)?);
+        }
+        Ok(Some(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin: Builtin::CompareStrings,
+                args: typed_args,
+            },
+            ty: plain(TypeKind::Integer),
+        }))
+    }
+
+    /// A call to the helper for `function` with `args`, which generates it
+    /// unless an earlier call with the same types did.
+    fn call_list_function(&mut self, function: ListFunction, args: Vec<TypedExpr>) -> TypedExpr {
+        let index = match self
+            .list_functions
+            .iter()
+            .position(|seen| *seen == function)
+        {
+            Some(index) => index,
+            None => {
+                self.list_functions.push(function.clone());
+                self.list_functions.len() - 1
+            }
+        };
+        let returns = match &function {
+            ListFunction::Map { to, .. } => plain(TypeKind::List {
+                element: Box::new(to.clone()),
+            }),
+            ListFunction::Reduce { accumulator, .. } => accumulator.clone(),
+            ListFunction::Get { element } => Type {
+                errorable: true,
+                errors: vec![],
+                ..element.clone()
+            },
+            ListFunction::Filter { .. }
+            | ListFunction::Sort { .. }
+            | ListFunction::SortBy { .. } => args[0].ty.clone(),
+        };
+        let helper = TypedExpr {
+            expr: tast::Expr::Identifier(function.helper_name(index)),
+            ty: plain(TypeKind::Function {
+                params: args.iter().map(|arg| arg.ty.clone()).collect(),
+                returns: Box::new(returns.clone()),
+            }),
+        };
+        TypedExpr {
+            expr: tast::Expr::Call {
+                callee: Box::new(helper),
+                args,
+            },
+            ty: returns,
+        }
+    }
+
+    /// Generates and checks the helpers that `map`, `filter`, `reduce`,
+    /// `get`, `sort` and `sort_by` calls refer to, so they can go at the top of
+    /// `main`.
+    pub(super) fn list_helpers(&mut self) -> Vec<TypedStatement> {
+        let source: String = self
+            .list_functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| function.helper(index))
+            .collect();
+        let program = Parser::new(&source)
+            .parse_program()
+            .expect("generated list helpers should parse");
+        self.generating = true;
+        self.push_scope();
+        let helpers = self.check_block(&program.statements);
+        self.pop_scope();
+        self.generating = false;
+        helpers
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
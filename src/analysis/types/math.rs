@@ -0,0 +1,94 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{self, Builtin, Type, TypeKind};
+
+impl TypeChecker {
+    /// Checks a call to a math function. `abs`, `min` and `max` give an
+    /// integer when every number they're given is one, and the rest widen
+    /// integers to floats.
+    pub(super) fn check_math(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<TypedExpr, TypeError> {
+        let builtin = match name {
+            "abs" => Builtin::Abs,
+            "min" => Builtin::Min,
+            "max" => Builtin::Max,
+            "sqrt" => Builtin::Sqrt,
+            "floor" => Builtin::Floor,
+            "ceil" => Builtin::Ceil,
+            "round" => Builtin::Round,
+            "sin" => Builtin::Sin,
+            "cos" => Builtin::Cos,
+            "tan" => Builtin::Tan,
+            "asin" => Builtin::Asin,
+            "acos" => Builtin::Acos,
+            "atan" => Builtin::Atan,
+            "atan2" => Builtin::Atan2,
+            _ => unreachable!("{} is not a math function", name),
+        };
+        let arity = if matches!(builtin, Builtin::Min | Builtin::Max | Builtin::Atan2) {
+            2
+        } else {
+            1
+        };
+        if args.len() != arity {
+            return Err(TypeError::new(if arity == 1 {
+                format!("{}() takes one number", name)
+            } else {
+                format!("{}() takes two numbers", name)
+            }));
+        }
+
+        let mut values = Vec::new();
+        for arg in args {
+            let value = self.check_expr(arg)?;
+            if !self.is_numeric(&value.ty) || value.ty.nullable || value.ty.errorable {
+                return Err(TypeError::new(format!(
+                    "{}() takes non-nullable, non-errorable numbers",
+                    name
+                )));
+            }
+            values.push(value);
+        }
+        let integers = matches!(builtin, Builtin::Abs | Builtin::Min | Builtin::Max)
+            && values
+                .iter()
+                .all(|value| value.ty.kind == TypeKind::Integer);
+        let ty = plain(if integers {
+            TypeKind::Integer
+        } else {
+            TypeKind::Float
+        });
+
+        let mut typed_args = Vec::new();
+        for value in values {
+            let value = self.widen(value, &ty);
+            if !self.is_assignable(&value.ty, &ty) {
+                return Err(self.mismatch(
+                    format!("Incompatible argument type in call to '{}'", name),
+                    &value.ty,
+                    &ty,
+                ));
+            }
+            typed_args.push(value);
+        }
+        Ok(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin,
+                args: typed_args,
+            },
+            ty,
+        })
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
@@ -1,7 +1,22 @@
+mod aliases;
+mod builtins;
 mod expr;
+mod externs;
+mod json;
+mod lists;
+mod math;
+mod narrowing;
+mod parse;
+mod patterns;
+mod returns;
+mod show;
 mod stmt;
+mod strings;
 
-use crate::ast::{Type, TypeKind};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{Builtin, Type, TypeKind};
+use crate::stdlib::lists::ListFunction;
+use externs::ExternSignature;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Debug)]
@@ -17,48 +32,265 @@ impl TypeError {
     }
 }
 
+/// Language options that change which programs type check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LanguageOptions {
+    /// Converts integers to floats wherever a float is expected, instead of
+    /// asking for `float(x)`.
+    pub implicit_widening: bool,
+    /// Leaves `assert` statements out of the program. Their conditions are
+    /// still checked, but never run.
+    pub release: bool,
+}
+
+/// Qualifiers a variable is known not to carry at some point in the
+/// program, so reads of it can be unwrapped without `??` or `!!`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Narrowing {
+    pub not_null: bool,
+    pub not_error: bool,
+}
+
+impl Narrowing {
+    pub const NOT_NULL: Narrowing = Narrowing {
+        not_null: true,
+        not_error: false,
+    };
+    pub const NOT_ERROR: Narrowing = Narrowing {
+        not_null: false,
+        not_error: true,
+    };
+
+    fn merge(&mut self, other: Narrowing) {
+        self.not_null |= other.not_null;
+        self.not_error |= other.not_error;
+    }
+}
+
 pub struct TypeChecker {
     scopes: Vec<HashMap<String, Type>>,
+    /// What each scope knows about its variables' qualifiers, from the
+    /// conditions and unwraps that guard it.
+    narrowed: Vec<HashMap<String, Narrowing>>,
+    /// The variables each scope declares that cannot be assigned, its
+    /// `const`s and its function's parameters, with what makes each so.
+    immutable: Vec<HashMap<String, &'static str>>,
+    /// How many `and`/`or` right operands enclose the expression being
+    /// checked, since unwraps there may not run.
+    conditional: usize,
     pub structs: HashMap<String, (Vec<(String, Type)>, i32)>,
     pub errors: HashSet<String>,
     pub next_struct_index: i32,
     pub current_return_type: Option<Type>,
+    /// What the arms of each match being checked produce, innermost last,
+    /// once one of them has.
+    producing: Vec<Option<Type>>,
+    /// How many blocks of the function being checked enclose the statement
+    /// being checked, its body being the first.
+    blocks: usize,
+    pub diagnostics: Vec<TypeError>,
+    pub options: LanguageOptions,
+    /// Types passed to `to_json` and read by `from_json`, each of which
+    /// gets a generated helper at the end of the program.
+    json_writes: Vec<Type>,
+    json_reads: Vec<Type>,
+    /// Lists and structs passed to `$`, each of which gets a generated
+    /// helper too.
+    show_writes: Vec<Type>,
+    /// The kinds of number `parse_int` and `parse_float` read, each of
+    /// which gets a generated helper too.
+    parses: Vec<TypeKind>,
+    /// The `map`, `filter` and `reduce` calls on lists, one for each
+    /// combination of types, each of which gets a generated helper too.
+    list_functions: Vec<ListFunction>,
+    /// Set while checking generated helpers, which may call the `json_*`
+    /// scanner builtins and the number readers.
+    generating: bool,
+    /// The module being checked, when the program was loaded from files.
+    module: Option<String>,
+    /// The modules each module imports.
+    module_imports: HashMap<String, Vec<String>>,
+    /// The module that declared each top-level name. The prelude's names,
+    /// and those of a program parsed from a single source, belong to none.
+    declared_in: HashMap<String, String>,
+    /// Every `extern fn`, by name.
+    externs: HashMap<String, ExternSignature>,
+    /// How many lambdas have been checked, to name each one.
+    lambdas: usize,
+    /// The labels of the loops enclosing the statement being checked,
+    /// innermost last, with `None` for those without one.
+    loops: Vec<Option<String>>,
+    /// The type each `type` alias stands for.
+    aliases: HashMap<String, Type>,
 }
 
 impl TypeChecker {
     pub fn new() -> Self {
         TypeChecker {
             scopes: vec![HashMap::new()],
+            narrowed: vec![HashMap::new()],
+            immutable: vec![HashMap::new()],
+            conditional: 0,
             structs: HashMap::new(),
             errors: HashSet::new(),
             current_return_type: None,
+            producing: Vec::new(),
+            blocks: 0,
             next_struct_index: 0,
+            diagnostics: Vec::new(),
+            options: LanguageOptions::default(),
+            json_writes: Vec::new(),
+            json_reads: Vec::new(),
+            show_writes: Vec::new(),
+            parses: Vec::new(),
+            list_functions: Vec::new(),
+            generating: false,
+            module: None,
+            module_imports: HashMap::new(),
+            declared_in: HashMap::new(),
+            externs: HashMap::new(),
+            lambdas: 0,
+            loops: Vec::new(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    pub fn with_options(options: LanguageOptions) -> Self {
+        TypeChecker {
+            options,
+            ..TypeChecker::new()
         }
     }
 
     pub fn push_scope(&mut self) {
         self.scopes.push(HashMap::new());
+        self.narrowed.push(HashMap::new());
+        self.immutable.push(HashMap::new());
     }
 
     pub fn pop_scope(&mut self) {
         self.scopes.pop();
+        self.narrowed.pop();
+        self.immutable.pop();
+    }
+
+    /// Treats `name` as free of the qualifiers in `narrowing` until the end
+    /// of the current scope or until it is assigned.
+    pub fn narrow(&mut self, name: &str, narrowing: Narrowing) {
+        if let Some(narrowed) = self.narrowed.last_mut() {
+            narrowed
+                .entry(name.to_string())
+                .or_default()
+                .merge(narrowing);
+        }
+    }
+
+    /// Everything known about `name` in the scopes it is visible in.
+    pub fn narrowing(&self, name: &str) -> Narrowing {
+        let mut known = Narrowing::default();
+        for (scope, narrowed) in self.scopes.iter().zip(&self.narrowed).rev() {
+            if let Some(narrowing) = narrowed.get(name) {
+                known.merge(*narrowing);
+            }
+            if scope.contains_key(name) {
+                break;
+            }
+        }
+        known
+    }
+
+    /// Forgets every narrowing of `name` once it is assigned, since the new
+    /// value may be null or an error.
+    pub fn end_narrowing(&mut self, name: &str) {
+        for (scope, narrowed) in self.scopes.iter().zip(self.narrowed.iter_mut()).rev() {
+            narrowed.remove(name);
+            if scope.contains_key(name) {
+                return;
+            }
+        }
     }
 
     pub fn define(&mut self, name: String, ty: Type) {
+        if let Some(narrowed) = self.narrowed.last_mut() {
+            narrowed.remove(&name);
+        }
+        if let Some(immutable) = self.immutable.last_mut() {
+            immutable.remove(&name);
+        }
         if let Some(scope) = self.scopes.last_mut() {
             scope.insert(name, ty);
         }
     }
 
+    pub fn define_constant(&mut self, name: String, ty: Type) {
+        self.define_immutable(name, ty, "is declared with const");
+    }
+
+    pub fn define_parameter(&mut self, name: String, ty: Type) {
+        self.define_immutable(name, ty, "is a parameter");
+    }
+
+    pub fn define_function(&mut self, name: String, ty: Type) {
+        self.define_immutable(name, ty, "is a top-level function");
+    }
+
+    fn define_immutable(&mut self, name: String, ty: Type, reason: &'static str) {
+        self.define(name.clone(), ty);
+        if let Some(immutable) = self.immutable.last_mut() {
+            immutable.insert(name, reason);
+        }
+    }
+
+    /// Rejects an assignment to `name` when the variable it names is a
+    /// `const`, a parameter or a top-level function.
+    pub fn check_not_constant(&self, name: &str) -> Result<(), TypeError> {
+        for (scope, immutable) in self.scopes.iter().zip(&self.immutable).rev() {
+            if scope.contains_key(name) {
+                if let Some(reason) = immutable.get(name) {
+                    return Err(TypeError::new(format!(
+                        "Cannot assign to '{}', which {}",
+                        name, reason
+                    )));
+                }
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
     pub fn lookup(&self, name: &str) -> Option<&Type> {
-        for scope in self.scopes.iter().rev() {
+        for (depth, scope) in self.scopes.iter().enumerate().rev() {
             if let Some(ty) = scope.get(name) {
+                if depth == 0 && self.hidden_in(name).is_some() {
+                    return None;
+                }
                 return Some(ty);
             }
         }
         None
     }
 
+    /// The module that declared the top-level `name`, when the module being
+    /// checked neither is nor imports it.
+    pub fn hidden_in(&self, name: &str) -> Option<&str> {
+        let module = self.module.as_ref()?;
+        let owner = self.declared_in.get(name)?;
+        let visible = owner == module || self.module_imports[module].contains(owner);
+        (!visible).then_some(owner.as_str())
+    }
+
+    /// The error for a use of `name`, which is not defined anywhere the
+    /// module being checked can see.
+    pub fn undefined(&self, kind: &str, name: &str) -> TypeError {
+        match self.hidden_in(name) {
+            Some(owner) => TypeError::new(format!(
+                "{} '{}' is defined in {}, which this module does not import",
+                kind, name, owner
+            )),
+            None => TypeError::new(format!("Undefined {} '{}'", kind.to_lowercase(), name)),
+        }
+    }
+
     pub fn types_equal(&self, a: &Type, b: &Type) -> bool {
         return a == b;
     }
@@ -70,12 +302,120 @@ impl TypeChecker {
 
         if from.kind == TypeKind::Unknown {
             return (from.nullable == to.nullable || to.nullable)
-                && (from.errorable == to.errorable || to.errorable);
+                && (from.errorable == to.errorable || to.errorable)
+                && errors_fit(from, to);
         }
 
         from.kind == to.kind
             && (from.nullable == to.nullable || to.nullable)
             && (from.errorable == to.errorable || to.errorable)
+            && errors_fit(from, to)
+    }
+
+    /// Converts an integer to a float when a float is expected and implicit
+    /// widening is on. Anything else comes back unchanged, for the caller to
+    /// check.
+    pub fn widen(&self, expr: TypedExpr, to: &Type) -> TypedExpr {
+        let widens = self.options.implicit_widening
+            && to.kind == TypeKind::Float
+            && expr.ty.kind == TypeKind::Integer
+            && !expr.ty.nullable
+            && !expr.ty.errorable;
+        if !widens {
+            return expr;
+        }
+        TypedExpr {
+            ty: Type {
+                kind: TypeKind::Float,
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            },
+            expr: tast::Expr::Builtin {
+                builtin: Builtin::ToFloat,
+                args: vec![expr],
+            },
+        }
+    }
+
+    /// An error for a `from` value where `to` was expected. Mixing up
+    /// integers and floats gets a hint naming the conversion.
+    pub fn mismatch(&self, message: impl Into<String>, from: &Type, to: &Type) -> TypeError {
+        let message = message.into();
+        match conversion(&from.kind, &to.kind) {
+            Some(hint) => TypeError::new(format!("{}: {}", message, hint)),
+            None => TypeError::new(message),
+        }
+    }
+
+    /// Fixed arrays live inline inside structs, so they cannot be held by a
+    /// local, passed around, or nested inside other types.
+    pub fn check_not_array(&self, ty: &Type) -> Result<(), TypeError> {
+        match &ty.kind {
+            TypeKind::Array { .. } => Err(TypeError::new(
+                "Fixed arrays can only be used as struct fields",
+            )),
+            TypeKind::List { element } => self.check_not_array(element),
+            TypeKind::Function { params, returns } => {
+                for param in params {
+                    self.check_not_array(param)?;
+                }
+                self.check_not_array(returns)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The errors a type names must be declared with `error`.
+    pub fn check_error_set(&self, ty: &Type) -> Result<(), TypeError> {
+        for name in &ty.errors {
+            if !self.errors.contains(name) {
+                return Err(TypeError::new(format!("'{}' is not an error type", name)));
+            }
+        }
+        match &ty.kind {
+            TypeKind::List { element } | TypeKind::Array { element, .. } => {
+                self.check_error_set(element)
+            }
+            TypeKind::Function { params, returns } => {
+                for param in params {
+                    self.check_error_set(param)?;
+                }
+                self.check_error_set(returns)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_field_type(&self, ty: &Type) -> Result<(), TypeError> {
+        match &ty.kind {
+            TypeKind::Array { element, length } => {
+                if ty.nullable || ty.errorable || element.nullable || element.errorable {
+                    return Err(TypeError::new(
+                        "Fixed arrays and their elements cannot be nullable or errorable",
+                    ));
+                }
+                if *length == 0 {
+                    return Err(TypeError::new("Fixed array length must be positive"));
+                }
+                self.check_not_array(element)
+            }
+            _ => self.check_not_array(ty),
+        }
+    }
+
+    /// The type a field holds as a value. Bit-fields read and write as
+    /// plain integers.
+    pub fn field_value_type(&self, ty: &Type) -> Type {
+        match ty.kind {
+            TypeKind::BitField { .. } => Type {
+                kind: TypeKind::Integer,
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            },
+            _ => ty.clone(),
+        }
     }
 
     pub fn is_numeric(&self, ty: &Type) -> bool {
@@ -86,3 +426,25 @@ impl TypeChecker {
         matches!(ty.kind, TypeKind::Boolean)
     }
 }
+
+/// How to turn a `from` into a `to` when they are different kinds of number.
+fn conversion(from: &TypeKind, to: &TypeKind) -> Option<&'static str> {
+    match (from, to) {
+        (TypeKind::Integer, TypeKind::Float) => {
+            Some("expected a float but found an integer, convert it with float(...)")
+        }
+        (TypeKind::Float, TypeKind::Integer) => Some(
+            "expected an integer but found a float, convert it with int(...), which truncates",
+        ),
+        _ => None,
+    }
+}
+
+/// Whether the errors `from` may hold are all among those `to` names. A
+/// type that names none may hold any error, so it only fits one that names
+/// none either.
+pub(super) fn errors_fit(from: &Type, to: &Type) -> bool {
+    !from.errorable
+        || to.errors.is_empty()
+        || (!from.errors.is_empty() && from.errors.iter().all(|e| to.errors.contains(e)))
+}
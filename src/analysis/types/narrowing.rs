@@ -0,0 +1,276 @@
+use super::{Narrowing, TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{self, Builtin, Type, TypeKind};
+
+/// What a condition tells us about variables when it is true and when it is
+/// false.
+#[derive(Debug, Default)]
+pub(super) struct Facts {
+    pub when_true: Vec<(String, Narrowing)>,
+    pub when_false: Vec<(String, Narrowing)>,
+}
+
+impl Facts {
+    fn negate(self) -> Facts {
+        Facts {
+            when_true: self.when_false,
+            when_false: self.when_true,
+        }
+    }
+}
+
+impl TypeChecker {
+    /// Reads what `condition` proves. `x`, `x != null`, `null != x` and
+    /// `x!! != null` prove that `x` holds a value when true; `==` proves it
+    /// when false. `and` keeps what both sides prove when true, `or` what
+    /// both prove when false, and `not` swaps the two.
+    pub(super) fn condition_facts(&self, condition: &ast::Expr) -> Facts {
+        match condition {
+            ast::Expr::Identifier(name) => match self.lookup(name) {
+                Some(ty) if ty.nullable && !ty.errorable => Facts {
+                    when_true: vec![(name.clone(), Narrowing::NOT_NULL)],
+                    when_false: vec![],
+                },
+                _ => Facts::default(),
+            },
+            ast::Expr::Binary { left, op, right } => match op {
+                ast::BinaryOp::Eq | ast::BinaryOp::Neq => {
+                    let name = match (left.as_ref(), right.as_ref()) {
+                        (value, ast::Expr::Null) | (ast::Expr::Null, value) => variable(value),
+                        _ => None,
+                    };
+                    let Some(name) = name else {
+                        return Facts::default();
+                    };
+                    let facts = Facts {
+                        when_true: vec![(name.clone(), Narrowing::NOT_NULL)],
+                        when_false: vec![],
+                    };
+                    if *op == ast::BinaryOp::Eq {
+                        facts.negate()
+                    } else {
+                        facts
+                    }
+                }
+                ast::BinaryOp::And => {
+                    let mut when_true = self.condition_facts(left).when_true;
+                    when_true.extend(self.condition_facts(right).when_true);
+                    Facts {
+                        when_true,
+                        when_false: vec![],
+                    }
+                }
+                ast::BinaryOp::Or => {
+                    let mut when_false = self.condition_facts(left).when_false;
+                    when_false.extend(self.condition_facts(right).when_false);
+                    Facts {
+                        when_true: vec![],
+                        when_false,
+                    }
+                }
+                _ => Facts::default(),
+            },
+            ast::Expr::Unary {
+                op: ast::UnaryOp::Not,
+                expr,
+            } => self.condition_facts(expr).negate(),
+            _ => Facts::default(),
+        }
+    }
+
+    /// Narrows every variable in `facts` for the rest of the current scope.
+    pub(super) fn narrow_all(&mut self, facts: &[(String, Narrowing)]) {
+        for (name, narrowing) in facts {
+            self.narrow(name, *narrowing);
+        }
+    }
+
+    /// A read of `name`, unwrapped as far as its narrowing allows. The
+    /// unwraps cannot trap, since the narrowing proves the qualifier absent.
+    pub(super) fn read_variable(&self, name: &str, ty: Type) -> TypedExpr {
+        let narrowing = self.narrowing(name);
+        let mut value = TypedExpr {
+            expr: tast::Expr::Identifier(name.to_string()),
+            ty,
+        };
+        if narrowing.not_error && value.ty.errorable {
+            let ty = Type {
+                errorable: false,
+                errors: vec![],
+                ..value.ty.clone()
+            };
+            value = TypedExpr {
+                expr: tast::Expr::UnwrapError(Box::new(value)),
+                ty,
+            };
+        }
+        if narrowing.not_null && value.ty.nullable {
+            let ty = Type {
+                nullable: false,
+                ..value.ty.clone()
+            };
+            value = TypedExpr {
+                expr: tast::Expr::UnwrapNull(Box::new(value)),
+                ty,
+            };
+        }
+        value
+    }
+
+    /// Narrows the variable `inner` after `inner??` or `inner!!`, since the
+    /// unwrap traps otherwise. Unwraps that may not run, such as those on the
+    /// right of `and`, prove nothing.
+    pub(super) fn narrow_unwrapped(&mut self, inner: &ast::Expr, narrowing: Narrowing) {
+        if let ast::Expr::Identifier(name) = inner {
+            if self.conditional == 0 {
+                self.narrow(name, narrowing);
+            }
+        }
+    }
+
+    /// Checks the right side of `and` or `or` knowing what the left side
+    /// proved for it to run.
+    pub(super) fn check_guarded(
+        &mut self,
+        left: &ast::Expr,
+        op: &ast::BinaryOp,
+        right: &ast::Expr,
+    ) -> Result<TypedExpr, TypeError> {
+        let facts = self.condition_facts(left);
+        let known = if *op == ast::BinaryOp::And {
+            facts.when_true
+        } else {
+            facts.when_false
+        };
+        self.push_scope();
+        self.narrow_all(&known);
+        self.conditional += 1;
+        let typed_right = self.check_expr(right);
+        self.conditional -= 1;
+        self.pop_scope();
+        typed_right
+    }
+
+    /// `x == null` and `x != null` test the tag of a nullable value.
+    pub(super) fn check_null_comparison(
+        &mut self,
+        left: &ast::Expr,
+        op: &ast::BinaryOp,
+        right: &ast::Expr,
+    ) -> Result<TypedExpr, TypeError> {
+        let value = match (left, right) {
+            (ast::Expr::Null, ast::Expr::Null) => {
+                return Err(TypeError::new("Cannot compare null with null"));
+            }
+            (ast::Expr::Null, value) | (value, _) => self.check_expr(value)?,
+        };
+        if !value.ty.nullable {
+            return Err(TypeError::new(format!(
+                "Only nullable values can be compared with null, not {}",
+                value.ty
+            )));
+        }
+        let boolean = Type {
+            kind: TypeKind::Boolean,
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+        let present = TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin: Builtin::Present,
+                args: vec![value],
+            },
+            ty: boolean.clone(),
+        };
+        if *op == ast::BinaryOp::Neq {
+            return Ok(present);
+        }
+        Ok(TypedExpr {
+            expr: tast::Expr::Unary {
+                op: ast::UnaryOp::Not,
+                expr: Box::new(present),
+            },
+            ty: boolean,
+        })
+    }
+}
+
+/// The variable a null comparison tests, seeing through `!!`, which keeps
+/// the null.
+fn variable(expr: &ast::Expr) -> Option<&String> {
+    match expr {
+        ast::Expr::Identifier(name) => Some(name),
+        ast::Expr::UnwrapError(inner) => variable(inner),
+        _ => None,
+    }
+}
+
+/// Whether running `block` never reaches the statement after it.
+pub(super) fn always_exits(block: &[ast::Statement]) -> bool {
+    match block.last() {
+        Some(
+            ast::Statement::Return(_)
+            | ast::Statement::Raise(_)
+            | ast::Statement::Break(_)
+            | ast::Statement::Continue(_),
+        ) => true,
+        Some(ast::Statement::If {
+            then_block,
+            else_block: Some(else_block),
+            ..
+        })
+        | Some(ast::Statement::IfLet {
+            then_block,
+            else_block: Some(else_block),
+            ..
+        }) => always_exits(then_block) && always_exits(else_block),
+        _ => false,
+    }
+}
+
+/// Collects the variables that `block` assigns, so a loop can forget their
+/// narrowings before checking a body that may run after the assignment.
+/// Nested functions are skipped since they capture by value.
+pub(super) fn assigned_in(block: &[ast::Statement], names: &mut Vec<String>) {
+    for stmt in block {
+        match stmt {
+            ast::Statement::Expr(ast::Expr::Binary {
+                left,
+                op: ast::BinaryOp::Is,
+                ..
+            }) => {
+                if let ast::Expr::Identifier(name) = left.as_ref() {
+                    names.push(name.clone());
+                }
+            }
+            ast::Statement::If {
+                then_block,
+                else_block,
+                ..
+            }
+            | ast::Statement::IfLet {
+                then_block,
+                else_block,
+                ..
+            } => {
+                assigned_in(then_block, names);
+                if let Some(else_block) = else_block {
+                    assigned_in(else_block, names);
+                }
+            }
+            ast::Statement::For {
+                init, update, body, ..
+            } => {
+                assigned_in(std::slice::from_ref(init.as_ref()), names);
+                assigned_in(std::slice::from_ref(update.as_ref()), names);
+                assigned_in(body, names);
+            }
+            ast::Statement::While { body, .. }
+            | ast::Statement::WhileLet { body, .. }
+            | ast::Statement::ForIn { body, .. }
+            | ast::Statement::Unchecked { body } => assigned_in(body, names),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr, TypedStatement};
+use crate::ast::{self, Builtin, Type, TypeKind};
+use crate::frontend::Parser;
+use crate::stdlib::parse;
+
+impl TypeChecker {
+    /// `parse_int(s)` and `parse_float(s)` become calls to the helper
+    /// generated for the kind of number they read, which raises a
+    /// `ParseError` when `s` isn't one.
+    pub(super) fn check_parse(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<TypedExpr, TypeError> {
+        if args.len() != 1 {
+            return Err(TypeError::new(format!("{}() takes one string", name)));
+        }
+        let text = self.check_expr(&args[0])?;
+        if !self.is_assignable(&text.ty, &plain(TypeKind::String)) {
+            return Err(self.mismatch(
+                format!("Incompatible argument type in call to '{}'", name),
+                &text.ty,
+                &plain(TypeKind::String),
+            ));
+        }
+        let kind = if name == "parse_int" {
+            TypeKind::Integer
+        } else {
+            TypeKind::Float
+        };
+        if !self.parses.contains(&kind) {
+            self.parses.push(kind.clone());
+        }
+        let returns = Type {
+            kind: kind.clone(),
+            nullable: false,
+            errorable: true,
+            errors: vec![],
+        };
+        let helper = TypedExpr {
+            expr: tast::Expr::Identifier(parse::helper_name(&kind)),
+            ty: plain(TypeKind::Function {
+                params: vec![plain(TypeKind::String)],
+                returns: Box::new(returns.clone()),
+            }),
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Call {
+                callee: Box::new(helper),
+                args: vec![text],
+            },
+            ty: returns,
+        })
+    }
+
+    /// The runtime's number readers, which only generated helpers may call.
+    pub(super) fn check_parse_builtin(
+        &mut self,
+        name: &str,
+        args: &[ast::Expr],
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        let (builtin, params, returns) = match name {
+            "atoi" => (Builtin::ParseInteger, 1, TypeKind::Integer),
+            "atof" => (Builtin::ParseFloat, 1, TypeKind::Float),
+            "parse_failed" => (Builtin::ParseFailed, 0, TypeKind::Boolean),
+            _ => return Ok(None),
+        };
+        if !self.generating {
+            return Ok(None);
+        }
+        assert_eq!(args.len(), params, "{}() in generated parse helper", name);
+        let mut typed_args = Vec::new();
+        for arg in args {
+            typed_args.push(self.check_expr(arg)?);
+        }
+        Ok(Some(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin,
+                args: typed_args,
+            },
+            ty: plain(returns),
+        }))
+    }
+
+    /// Generates and checks the helpers that `parse_int` and `parse_float`
+    /// calls refer to, so they can go at the top of `main`.
+    pub(super) fn parse_helpers(&mut self) -> Vec<TypedStatement> {
+        let source: String = self.parses.iter().map(parse::helper).collect();
+        let program = Parser::new(&source)
+            .parse_program()
+            .expect("generated parse helpers should parse");
+        self.generating = true;
+        self.push_scope();
+        let helpers = self.check_block(&program.statements);
+        self.pop_scope();
+        self.generating = false;
+        helpers
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
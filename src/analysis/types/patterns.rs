@@ -0,0 +1,516 @@
+use super::narrowing::always_exits;
+use super::{errors_fit, Narrowing, TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr, TypedStatement};
+use crate::ast::{self, Builtin, Pattern, Type, TypeKind};
+
+/// A match arm's condition and body.
+type Arm = (Option<TypedExpr>, Vec<TypedStatement>);
+
+impl TypeChecker {
+    /// Checks `match expr as binding { ... }`. Each arm's pattern becomes a
+    /// condition on `binding`, and the fields it binds become `let`s at the
+    /// start of its body. The arms must cover every value, and if any of
+    /// them produces a value, they all must.
+    pub(super) fn check_match(
+        &mut self,
+        expr: &ast::Expr,
+        binding: &str,
+        arms: &[(Pattern, Vec<ast::Statement>)],
+    ) -> Result<TypedExpr, TypeError> {
+        let typed_expr = self.check_expr(expr)?;
+        let matched = typed_expr.ty.clone();
+
+        self.push_scope();
+        self.define(binding.to_string(), matched.clone());
+        self.producing.push(None);
+        let typed_arms = self.check_arms(binding, &matched, arms);
+        let produced = self.producing.pop().unwrap();
+        self.pop_scope();
+        let typed_arms = typed_arms?;
+
+        if produced.is_some() {
+            for (_, body) in arms {
+                if !matches!(body.last(), Some(ast::Statement::Produce(_))) && !always_exits(body) {
+                    return Err(TypeError::new(
+                        "Every arm of a match that produces a value must end by producing one",
+                    ));
+                }
+            }
+        }
+
+        Ok(TypedExpr {
+            expr: tast::Expr::Match {
+                expr: Box::new(typed_expr),
+                binding: binding.to_string(),
+                arms: typed_arms,
+            },
+            ty: produced.unwrap_or(Type {
+                kind: TypeKind::Null,
+                nullable: true,
+                errorable: false,
+                errors: vec![],
+            }),
+        })
+    }
+
+    /// `inner??` or `inner!!` giving a `ty`. In a function that can raise,
+    /// a failed unwrap passes the failure on rather than stopping the
+    /// program: `!!` returns the error it found and `??` raises a
+    /// `NullError`. This is a match on the value, so the function's
+    /// deferred blocks still run. A match runs before the statement it's
+    /// in, so unwraps that may not run, such as those on the right of
+    /// `and`, still stop the program. A function that names its errors
+    /// can only pass on those.
+    pub(super) fn check_unwrap(
+        &self,
+        inner: TypedExpr,
+        null: bool,
+        ty: Type,
+    ) -> Result<TypedExpr, TypeError> {
+        let unwrap = |value: TypedExpr| TypedExpr {
+            expr: if null {
+                tast::Expr::UnwrapNull(Box::new(value))
+            } else {
+                tast::Expr::UnwrapError(Box::new(value))
+            },
+            ty: ty.clone(),
+        };
+        let returns = match &self.current_return_type {
+            Some(returns) if returns.errorable && self.conditional == 0 && !self.generating => {
+                returns.clone()
+            }
+            _ => return Ok(unwrap(inner)),
+        };
+        if null && !returns.errors.is_empty() && !returns.errors.iter().any(|e| e == "NullError") {
+            return Err(TypeError::new(format!(
+                "'??' raises a NullError, which {} does not name",
+                returns
+            )));
+        }
+        if !null && !errors_fit(&inner.ty, &returns) {
+            return Err(TypeError::new(format!(
+                "'!!' passes on the errors of {}, which {} does not name",
+                inner.ty, returns
+            )));
+        }
+
+        let binding = "unwrap".to_string();
+        let held = |ty: &Type| TypedExpr {
+            expr: tast::Expr::Identifier(binding.clone()),
+            ty: ty.clone(),
+        };
+        let test = |builtin: Builtin| TypedExpr {
+            expr: tast::Expr::Unary {
+                op: ast::UnaryOp::Not,
+                expr: Box::new(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin,
+                        args: vec![held(&inner.ty)],
+                    },
+                    ty: boolean(),
+                }),
+            },
+            ty: boolean(),
+        };
+        let (failed, exit) = if null {
+            let error = TypedExpr {
+                expr: tast::Expr::New {
+                    name: "NullError".to_string(),
+                    fields: vec![(
+                        "message".to_string(),
+                        TypedExpr {
+                            expr: tast::Expr::String("unwrapped a null value".to_string()),
+                            ty: plain(TypeKind::String),
+                        },
+                    )],
+                },
+                ty: plain(TypeKind::Struct {
+                    name: "NullError".to_string(),
+                }),
+            };
+            (test(Builtin::Present), TypedStatement::Raise(error))
+        } else {
+            // Holding no value, a nullable one is null or an error.
+            let mut failed = test(Builtin::Holds);
+            if inner.ty.nullable {
+                failed = TypedExpr {
+                    expr: tast::Expr::Binary {
+                        left: Box::new(failed),
+                        op: ast::BinaryOp::And,
+                        right: Box::new(TypedExpr {
+                            expr: tast::Expr::Builtin {
+                                builtin: Builtin::Present,
+                                args: vec![held(&inner.ty)],
+                            },
+                            ty: boolean(),
+                        }),
+                    },
+                    ty: boolean(),
+                };
+            }
+            // An error reads the same whatever type it was raised as.
+            (failed, TypedStatement::Return(Some(held(&returns))))
+        };
+        let unwrapped = unwrap(held(&inner.ty));
+        Ok(TypedExpr {
+            expr: tast::Expr::Match {
+                expr: Box::new(inner),
+                binding,
+                arms: vec![
+                    (Some(failed), vec![exit]),
+                    (None, vec![TypedStatement::Produce(unwrapped)]),
+                ],
+            },
+            ty,
+        })
+    }
+
+    fn check_arms(
+        &mut self,
+        binding: &str,
+        matched: &Type,
+        arms: &[(Pattern, Vec<ast::Statement>)],
+    ) -> Result<Vec<Arm>, TypeError> {
+        let mut typed_arms = vec![];
+        let mut exhaustive = false;
+        let mut null_matched = !matched.nullable;
+        let mut error_matched = !matched.errorable;
+        // Naming its errors lets arms for each of them stand in for `!`.
+        let mut errors_left = matched.errors.clone();
+        let mut booleans_matched = [false, false];
+        for (pattern, body) in arms {
+            if exhaustive {
+                return Err(TypeError::new(
+                    "Unreachable match arm: the arms before it match every value",
+                ));
+            }
+            let mut bindings = vec![];
+            let condition = match pattern {
+                Pattern::MatchNull => {
+                    if null_matched {
+                        return Err(TypeError::new(
+                            "Only a nullable value not yet matched against null can match '?'",
+                        ));
+                    }
+                    Some(self.check_null_comparison(
+                        &ast::Expr::Identifier(binding.to_string()),
+                        &ast::BinaryOp::Eq,
+                        &ast::Expr::Null,
+                    )?)
+                }
+                Pattern::MatchError => {
+                    if error_matched {
+                        return Err(TypeError::new(
+                            "Only an errorable value with errors left to match can match '!'",
+                        ));
+                    }
+                    Some(self.check_any_error(binding, matched))
+                }
+                Pattern::MatchType(ty) => {
+                    let name = self.check_error_pattern(ty, matched, error_matched)?;
+                    errors_left.retain(|e| *e != name);
+                    // The arm sees the binding as the error it holds.
+                    bindings.push((binding.to_string(), held_error(binding, matched, &name)));
+                    Some(holds_error(binding, matched, &name))
+                }
+                Pattern::Struct { name, .. } if self.errors.contains(name) && matched.errorable => {
+                    let ty = plain(TypeKind::Struct { name: name.clone() });
+                    self.check_error_pattern(&ty, matched, error_matched)?;
+                    let test = holds_error(binding, matched, name);
+                    let held = held_error(binding, matched, name);
+                    Some(match self.check_pattern(pattern, held, &mut bindings)? {
+                        Some(fields) => TypedExpr {
+                            expr: tast::Expr::Binary {
+                                left: Box::new(test),
+                                op: ast::BinaryOp::And,
+                                right: Box::new(fields),
+                            },
+                            ty: boolean(),
+                        },
+                        None => {
+                            errors_left.retain(|e| e != name);
+                            test
+                        }
+                    })
+                }
+                Pattern::MatchAll => None,
+                _ => {
+                    if !null_matched {
+                        return Err(TypeError::new(
+                            "Match a nullable value against '?' before other patterns",
+                        ));
+                    }
+                    if !error_matched {
+                        return Err(TypeError::new(
+                            "Match an errorable value against '!' before other patterns",
+                        ));
+                    }
+                    let value = self.read_variable(binding, matched.clone());
+                    self.check_pattern(pattern, value, &mut bindings)?
+                }
+            };
+
+            match (pattern, &condition) {
+                (Pattern::MatchNull, _) => null_matched = true,
+                (Pattern::Literal(ast::Expr::Boolean(value)), _) => {
+                    booleans_matched[*value as usize] = true;
+                    exhaustive = booleans_matched == [true, true];
+                }
+                (_, None) => exhaustive = true,
+                _ => {}
+            }
+
+            self.push_scope();
+            let mut typed_body = vec![];
+            for (name, value) in bindings {
+                self.define(name.clone(), value.ty.clone());
+                typed_body.push(TypedStatement::Let {
+                    name,
+                    ty: value.ty.clone(),
+                    value: Some(value),
+                });
+            }
+            typed_body.extend(self.check_block(body));
+            self.pop_scope();
+            typed_arms.push((condition, typed_body));
+
+            // Later arms only see what this one didn't match.
+            if *pattern == Pattern::MatchNull {
+                self.narrow(binding, Narrowing::NOT_NULL);
+            }
+            let errors_covered = !matched.errors.is_empty() && errors_left.is_empty();
+            if *pattern == Pattern::MatchError || errors_covered && !error_matched {
+                error_matched = true;
+                self.narrow(binding, Narrowing::NOT_ERROR);
+            }
+        }
+        if !exhaustive {
+            return Err(TypeError::new(
+                "Match does not cover every value, add a '_' arm",
+            ));
+        }
+        Ok(typed_arms)
+    }
+
+    /// `!`: the value holds an error, of any type. A nullable value that
+    /// holds no value may be null instead.
+    fn check_any_error(&self, binding: &str, matched: &Type) -> TypedExpr {
+        let held = read(binding, matched);
+        let mut failed = TypedExpr {
+            expr: tast::Expr::Unary {
+                op: ast::UnaryOp::Not,
+                expr: Box::new(TypedExpr {
+                    expr: tast::Expr::Builtin {
+                        builtin: Builtin::Holds,
+                        args: vec![held.clone()],
+                    },
+                    ty: boolean(),
+                }),
+            },
+            ty: boolean(),
+        };
+        if matched.nullable {
+            failed = TypedExpr {
+                expr: tast::Expr::Binary {
+                    left: Box::new(failed),
+                    op: ast::BinaryOp::And,
+                    right: Box::new(TypedExpr {
+                        expr: tast::Expr::Builtin {
+                            builtin: Builtin::Present,
+                            args: vec![held],
+                        },
+                        ty: boolean(),
+                    }),
+                },
+                ty: boolean(),
+            };
+        }
+        failed
+    }
+
+    /// The name of the error type `ty` an arm matches, which only an
+    /// errorable value with errors left to match can.
+    fn check_error_pattern(
+        &self,
+        ty: &Type,
+        matched: &Type,
+        error_matched: bool,
+    ) -> Result<String, TypeError> {
+        let name = match &ty.kind {
+            TypeKind::Struct { name } if !ty.nullable && !ty.errorable => name,
+            _ => return Err(TypeError::new(format!("'{}' is not an error type", ty))),
+        };
+        if !self.errors.contains(name) {
+            return Err(TypeError::new(format!("'{}' is not an error type", name)));
+        }
+        if !matched.errorable {
+            return Err(TypeError::new(format!(
+                "Only errorable values can match the error '{}'",
+                name
+            )));
+        }
+        if !matched.errors.is_empty() && !matched.errors.contains(name) {
+            return Err(TypeError::new(format!(
+                "'{}' is not among the errors of {}",
+                name, matched
+            )));
+        }
+        if error_matched {
+            return Err(TypeError::new(format!(
+                "Unreachable match arm: the arms before it match every error, including '{}'",
+                name
+            )));
+        }
+        Ok(name.clone())
+    }
+
+    /// The condition under which `value` matches `pattern`, or `None` if it
+    /// always does. The fields the pattern binds are added to `bindings`.
+    fn check_pattern(
+        &mut self,
+        pattern: &Pattern,
+        value: TypedExpr,
+        bindings: &mut Vec<(String, TypedExpr)>,
+    ) -> Result<Option<TypedExpr>, TypeError> {
+        if let Pattern::MatchAll = pattern {
+            return Ok(None);
+        }
+        if let Pattern::Binding(name) = pattern {
+            self.check_not_array(&value.ty)?;
+            bindings.push((name.clone(), value));
+            return Ok(None);
+        }
+        if value.ty.nullable || value.ty.errorable {
+            return Err(TypeError::new(
+                "Only '_', '?' and bindings can match nullable or errorable values",
+            ));
+        }
+        match pattern {
+            Pattern::Literal(literal) => {
+                let typed_literal = self.check_expr(literal)?;
+                if typed_literal.ty.kind != value.ty.kind {
+                    return Err(self.mismatch(
+                        "Pattern does not match the type of the value",
+                        &typed_literal.ty,
+                        &value.ty,
+                    ));
+                }
+                let expr = match literal {
+                    // A boolean is its own condition.
+                    ast::Expr::Boolean(true) => return Ok(Some(value)),
+                    ast::Expr::Boolean(false) => tast::Expr::Unary {
+                        op: ast::UnaryOp::Not,
+                        expr: Box::new(value),
+                    },
+                    _ => tast::Expr::Binary {
+                        left: Box::new(value),
+                        op: ast::BinaryOp::Eq,
+                        right: Box::new(typed_literal),
+                    },
+                };
+                Ok(Some(TypedExpr {
+                    expr,
+                    ty: boolean(),
+                }))
+            }
+            Pattern::Struct { name, fields } => {
+                if value.ty.kind != (TypeKind::Struct { name: name.clone() }) {
+                    return Err(TypeError::new(format!(
+                        "Pattern expects a '{}', which the value is not",
+                        name
+                    )));
+                }
+                let declared = self.structs[name].0.clone();
+                let mut condition: Option<TypedExpr> = None;
+                for (field, field_pattern) in fields {
+                    let Some((_, ty)) = declared.iter().find(|(declared, _)| declared == field)
+                    else {
+                        return Err(TypeError::new(format!(
+                            "Struct '{}' has no field '{}'",
+                            name, field
+                        )));
+                    };
+                    let field_value = TypedExpr {
+                        expr: tast::Expr::Field {
+                            object: Box::new(value.clone()),
+                            field: field.clone(),
+                        },
+                        ty: self.field_value_type(ty),
+                    };
+                    let Some(field_condition) =
+                        self.check_pattern(field_pattern, field_value, bindings)?
+                    else {
+                        continue;
+                    };
+                    condition = Some(match condition {
+                        Some(left) => TypedExpr {
+                            expr: tast::Expr::Binary {
+                                left: Box::new(left),
+                                op: ast::BinaryOp::And,
+                                right: Box::new(field_condition),
+                            },
+                            ty: boolean(),
+                        },
+                        None => field_condition,
+                    });
+                }
+                Ok(condition)
+            }
+            _ => Err(TypeError::new(
+                "Only literals, struct patterns, bindings and '_' can match fields",
+            )),
+        }
+    }
+}
+
+fn boolean() -> Type {
+    plain(TypeKind::Boolean)
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
+
+/// The match's binding, as it was matched.
+fn read(binding: &str, matched: &Type) -> TypedExpr {
+    TypedExpr {
+        expr: tast::Expr::Identifier(binding.to_string()),
+        ty: matched.clone(),
+    }
+}
+
+/// Whether the binding holds an error of type `name`.
+fn holds_error(binding: &str, matched: &Type, name: &str) -> TypedExpr {
+    TypedExpr {
+        expr: tast::Expr::Builtin {
+            builtin: Builtin::HoldsError,
+            args: vec![
+                read(binding, matched),
+                TypedExpr {
+                    expr: tast::Expr::String(name.to_string()),
+                    ty: plain(TypeKind::String),
+                },
+            ],
+        },
+        ty: boolean(),
+    }
+}
+
+/// The error the binding holds, once `holds_error` has found it is a
+/// `name`.
+fn held_error(binding: &str, matched: &Type, name: &str) -> TypedExpr {
+    TypedExpr {
+        expr: tast::Expr::Builtin {
+            builtin: Builtin::HeldError,
+            args: vec![read(binding, matched)],
+        },
+        ty: plain(TypeKind::Struct {
+            name: name.to_string(),
+        }),
+    }
+}
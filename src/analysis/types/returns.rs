@@ -0,0 +1,80 @@
+use crate::ast::tast::{Expr, TypedExpr, TypedStatement};
+use crate::ast::Builtin;
+
+/// Whether running `statements` always leaves the function they're in, by
+/// returning, raising or exiting, rather than going on past their end.
+/// Works on the checked body so `exit` only counts when the call resolved to
+/// the builtin, not to a function of the same name.
+pub(super) fn always_leaves(statements: &[TypedStatement]) -> bool {
+    statements.iter().any(leaves)
+}
+
+fn leaves(stmt: &TypedStatement) -> bool {
+    match stmt {
+        TypedStatement::Return(_) | TypedStatement::Raise(_) => true,
+        TypedStatement::Expr(TypedExpr {
+            expr: Expr::Builtin {
+                builtin: Builtin::Exit,
+                ..
+            },
+            ..
+        }) => true,
+        // The type checker makes every match cover every value.
+        TypedStatement::Expr(TypedExpr {
+            expr: Expr::Match { arms, .. },
+            ..
+        }) => arms.iter().all(|(_, body)| always_leaves(body)),
+        TypedStatement::If {
+            then_block,
+            else_block: Some(else_block),
+            ..
+        }
+        | TypedStatement::IfLet {
+            then_block,
+            else_block: Some(else_block),
+            ..
+        } => always_leaves(then_block) && always_leaves(else_block),
+        TypedStatement::While {
+            condition:
+                TypedExpr {
+                    expr: Expr::Boolean(true),
+                    ..
+                },
+            body,
+        } => !breaks_out(body, 0),
+        TypedStatement::Unchecked { body } => always_leaves(body),
+        _ => false,
+    }
+}
+
+/// Whether a `break` in `statements`, which `depth` loops inside the loop
+/// in question enclose, leaves that loop.
+fn breaks_out(statements: &[TypedStatement], depth: u32) -> bool {
+    statements.iter().any(|stmt| match stmt {
+        TypedStatement::Break(loops) => *loops >= depth,
+        TypedStatement::If {
+            then_block,
+            else_block,
+            ..
+        }
+        | TypedStatement::IfLet {
+            then_block,
+            else_block,
+            ..
+        } => {
+            breaks_out(then_block, depth)
+                || else_block
+                    .as_ref()
+                    .is_some_and(|block| breaks_out(block, depth))
+        }
+        TypedStatement::For { body, .. }
+        | TypedStatement::While { body, .. }
+        | TypedStatement::WhileLet { body, .. } => breaks_out(body, depth + 1),
+        TypedStatement::Unchecked { body } => breaks_out(body, depth),
+        TypedStatement::Expr(TypedExpr {
+            expr: Expr::Match { arms, .. },
+            ..
+        }) => arms.iter().any(|(_, body)| breaks_out(body, depth)),
+        _ => false,
+    })
+}
@@ -0,0 +1,199 @@
+use super::json::position_or_push;
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr, TypedStatement};
+use crate::ast::{self, Builtin, Type, TypeKind};
+use crate::frontend::Parser;
+use crate::stdlib::{json, show};
+
+impl TypeChecker {
+    /// `$value` on a list or struct becomes a call to the helper generated
+    /// for its type, filling a fresh builder. Other values are left to the
+    /// runtime's conversions.
+    pub(super) fn check_stringify(&mut self, value: TypedExpr) -> Result<TypedExpr, TypeError> {
+        let ty = self.check_unary_types(&ast::UnaryOp::Stringify, &value.ty)?;
+        if !show::needs_helper(&value.ty) {
+            return Ok(TypedExpr {
+                expr: tast::Expr::Unary {
+                    op: ast::UnaryOp::Stringify,
+                    expr: Box::new(value),
+                },
+                ty,
+            });
+        }
+        if value.ty.errorable {
+            return Err(TypeError::new(
+                "Cannot stringify an errorable list or struct",
+            ));
+        }
+        let index = position_or_push(&mut self.show_writes, &value.ty);
+        let builder = self.new_builder();
+        let shower = TypedExpr {
+            expr: tast::Expr::Identifier(show::shower_name(index)),
+            ty: plain(TypeKind::Function {
+                params: vec![value.ty.clone(), builder.ty.clone()],
+                returns: Box::new(builder.ty.clone()),
+            }),
+        };
+        let filled = TypedExpr {
+            expr: tast::Expr::Call {
+                callee: Box::new(shower),
+                args: vec![value, builder.clone()],
+            },
+            ty: builder.ty,
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin: Builtin::BuilderToString,
+                args: vec![filled],
+            },
+            ty: plain(TypeKind::String),
+        })
+    }
+
+    /// The string `print` writes for `value`. Anything but a plain string
+    /// goes through `$`, so missing values show as `null` or
+    /// `error(Name)`.
+    pub(super) fn printable(&mut self, value: TypedExpr) -> Result<TypedExpr, TypeError> {
+        if value.ty == plain(TypeKind::String) {
+            return Ok(value);
+        }
+        self.check_stringify(value)
+    }
+
+    /// `println a, b, c;` prints its values joined by spaces.
+    pub(super) fn check_println(&mut self, exprs: &[ast::Expr]) -> Result<TypedExpr, TypeError> {
+        let mut line: Option<TypedExpr> = None;
+        for expr in exprs {
+            let typed = self.check_expr(expr)?;
+            let text = self.printable(typed)?;
+            line = Some(match line {
+                None => text,
+                Some(left) => concat(concat(left, space()), text),
+            });
+        }
+        Ok(line.expect("println takes at least one value"))
+    }
+
+    /// `format("{} of {}", a, b)` puts each value, shown the way `print`
+    /// shows it, in place of the next `{}`. `{{` and `}}` stand for single
+    /// braces.
+    pub(super) fn check_format(&mut self, args: &[ast::Expr]) -> Result<TypedExpr, TypeError> {
+        let Some((ast::Expr::String(template), values)) = args.split_first() else {
+            return Err(TypeError::new(
+                "format() takes a string literal, then a value for each {}",
+            ));
+        };
+        let pieces = format_pieces(template)?;
+        if pieces.len() - 1 != values.len() {
+            return Err(TypeError::new(format!(
+                "format() needs a value for each of its {} placeholders, but got {}",
+                pieces.len() - 1,
+                values.len()
+            )));
+        }
+
+        let mut text: Option<TypedExpr> = None;
+        let mut add = |part: TypedExpr| {
+            text = Some(match text.take() {
+                None => part,
+                Some(left) => concat(left, part),
+            });
+        };
+        for (piece, value) in pieces.iter().zip(values) {
+            if !piece.is_empty() {
+                add(literal(piece));
+            }
+            let typed = self.check_expr(value)?;
+            add(self.printable(typed)?);
+        }
+        let last = &pieces[pieces.len() - 1];
+        if !last.is_empty() {
+            add(literal(last));
+        }
+        Ok(text.unwrap_or_else(|| literal("")))
+    }
+
+    /// Generates and checks the helpers that `$` calls refer to, so they
+    /// can go at the top of `main`.
+    pub(super) fn show_helpers(&mut self) -> Result<Vec<TypedStatement>, TypeError> {
+        let mut source = String::new();
+        let mut shown = Vec::new();
+        for ty in &self.show_writes {
+            json::structs_in(ty, &mut shown);
+        }
+        for name in self.dependency_order(shown, "`$`")? {
+            source.push_str(&show::struct_shower(&name, &self.structs));
+        }
+        for (index, ty) in self.show_writes.iter().enumerate() {
+            source.push_str(&show::shower(index, ty, &self.structs));
+        }
+
+        let program = Parser::new(&source)
+            .parse_program()
+            .expect("generated stringify helpers should parse");
+        self.generating = true;
+        self.push_scope();
+        let helpers = self.check_block(&program.statements);
+        self.pop_scope();
+        self.generating = false;
+        Ok(helpers)
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
+
+fn space() -> TypedExpr {
+    literal(" ")
+}
+
+fn literal(text: &str) -> TypedExpr {
+    TypedExpr {
+        expr: tast::Expr::String(text.to_string()),
+        ty: plain(TypeKind::String),
+    }
+}
+
+/// The text around the `{}`s of a `format` template, one more piece than
+/// there are placeholders.
+fn format_pieces(template: &str) -> Result<Vec<String>, TypeError> {
+    let mut pieces = vec![String::new()];
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                pieces.last_mut().unwrap().push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                pieces.push(String::new());
+            }
+            ('{' | '}', _) => {
+                return Err(TypeError::new(format!(
+                    "Unmatched '{}' in format string, write '{}{}' for a brace",
+                    c, c, c
+                )))
+            }
+            _ => pieces.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(pieces)
+}
+
+fn concat(left: TypedExpr, right: TypedExpr) -> TypedExpr {
+    TypedExpr {
+        expr: tast::Expr::Binary {
+            left: Box::new(left),
+            op: ast::BinaryOp::Plus,
+            right: Box::new(right),
+        },
+        ty: plain(TypeKind::String),
+    }
+}
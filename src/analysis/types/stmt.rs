@@ -1,6 +1,14 @@
+use super::narrowing::{always_exits, assigned_in};
+use super::returns::always_leaves;
 use super::{TypeChecker, TypeError};
-use crate::ast::{self, Type, TypeKind};
-use crate::ast::tast::{self, TypedProgram, TypedStatement};
+use crate::ast::{self, Builtin, Type, TypeKind};
+use crate::ast::tast::{self, TypedExpr, TypedProgram, TypedStatement};
+use std::collections::HashMap;
+
+/// Hidden locals of a lowered for-in loop.
+const FOR_ITEMS: &str = "for.items";
+const FOR_INDEX: &str = "for.index";
+const FOR_END: &str = "for.end";
 
 impl TypeChecker {
     pub fn check_stmt(&mut self, stmt: &ast::Statement) -> Result<TypedStatement, TypeError> {
@@ -11,8 +19,10 @@ impl TypeChecker {
             }
 
             ast::Statement::Let { name, value, ty } => {
+                self.check_not_array(ty)?;
+                self.check_error_set(ty)?;
                 let typed_value = if let Some(init_expr) = value {
-                    let mut typed_init = self.check_expr(init_expr)?;
+                    let mut typed_init = self.check_expr_as(init_expr, ty)?;
 
                     if let TypeKind::List { element } = &typed_init.ty.kind {
                         if element.kind == TypeKind::Unknown {
@@ -20,11 +30,13 @@ impl TypeChecker {
                         }
                     }
 
+                    let typed_init = self.widen(typed_init, ty);
                     if !self.is_assignable(&typed_init.ty, ty) {
-                        return Err(TypeError::new(format!(
-                            "Incompatible type in let binding for '{}'",
-                            name
-                        )));
+                        return Err(self.mismatch(
+                            format!("Incompatible type in let binding for '{}'", name),
+                            &typed_init.ty,
+                            ty,
+                        ));
                     }
                     Some(typed_init)
                 } else {
@@ -45,7 +57,9 @@ impl TypeChecker {
             }
 
             ast::Statement::Const { name, value, ty } => {
-                let mut typed_value = self.check_expr(value)?;
+                self.check_not_array(ty)?;
+                self.check_error_set(ty)?;
+                let mut typed_value = self.check_expr_as(value, ty)?;
 
                 if let TypeKind::List { element } = &typed_value.ty.kind {
                     if element.kind == TypeKind::Unknown {
@@ -53,13 +67,15 @@ impl TypeChecker {
                     }
                 }
 
+                let typed_value = self.widen(typed_value, ty);
                 if !self.is_assignable(&typed_value.ty, ty) {
-                    return Err(TypeError::new(format!(
-                        "Incompatible type in const binding for '{}'",
-                        name
-                    )));
+                    return Err(self.mismatch(
+                        format!("Incompatible type in const binding for '{}'", name),
+                        &typed_value.ty,
+                        ty,
+                    ));
                 }
-                self.define(name.clone(), ty.clone());
+                self.define_constant(name.clone(), ty.clone());
                 Ok(TypedStatement::Const {
                     name: name.clone(),
                     ty: ty.clone(),
@@ -69,13 +85,17 @@ impl TypeChecker {
 
             ast::Statement::Return(expr) => {
                 let typed_expr = if let Some(ret_expr) = expr {
-                    let typed_ret = self.check_expr(ret_expr)?;
-                    if let Some(expected_type) = &self.current_return_type {
-                        if !self.is_assignable(&typed_ret.ty, expected_type) {
-                            return Err(TypeError::new("Incompatible return type"));
-                        }
-                    } else {
+                    let Some(expected_type) = self.current_return_type.clone() else {
                         return Err(TypeError::new("Return statement outside of function"));
+                    };
+                    let typed_ret = self.check_expr_as(ret_expr, &expected_type)?;
+                    let typed_ret = self.widen(typed_ret, &expected_type);
+                    if !self.is_assignable(&typed_ret.ty, &expected_type) {
+                        return Err(self.mismatch(
+                            "Incompatible return type",
+                            &typed_ret.ty,
+                            &expected_type,
+                        ));
                     }
                     Some(typed_ret)
                 } else {
@@ -93,17 +113,24 @@ impl TypeChecker {
                 Ok(TypedStatement::Return(typed_expr))
             }
 
-            ast::Statement::Break => Ok(TypedStatement::Break),
+            ast::Statement::Break(label) => {
+                Ok(TypedStatement::Break(self.loops_out("break", label.as_ref())?))
+            }
 
-            ast::Statement::Continue => Ok(TypedStatement::Continue),
+            ast::Statement::Continue(label) => Ok(TypedStatement::Continue(
+                self.loops_out("continue", label.as_ref())?,
+            )),
 
             ast::Statement::If {
                 condition,
                 then_block,
                 else_block,
             } => {
-                let typed_condition = self.check_expr(condition)?;
-                if !self.is_boolean(&typed_condition.ty)
+                let facts = self.condition_facts(condition);
+                let mut typed_condition = self.check_expr(condition)?;
+                if typed_condition.ty.nullable && !typed_condition.ty.errorable {
+                    typed_condition = self.check_presence(typed_condition)?;
+                } else if !self.is_boolean(&typed_condition.ty)
                     || typed_condition.ty.nullable
                     || typed_condition.ty.errorable
                 {
@@ -113,24 +140,29 @@ impl TypeChecker {
                 }
 
                 self.push_scope();
-                let typed_then: Vec<TypedStatement> = then_block
-                    .iter()
-                    .map(|s| self.check_stmt(s))
-                    .collect::<Result<_, _>>()?;
+                self.narrow_all(&facts.when_true);
+                let typed_then = self.check_block(then_block);
                 self.pop_scope();
 
                 let typed_else = if let Some(alt_stmts) = else_block {
                     self.push_scope();
-                    let typed: Vec<TypedStatement> = alt_stmts
-                        .iter()
-                        .map(|s| self.check_stmt(s))
-                        .collect::<Result<_, _>>()?;
+                    self.narrow_all(&facts.when_false);
+                    let typed = self.check_block(alt_stmts);
                     self.pop_scope();
                     Some(typed)
                 } else {
                     None
                 };
 
+                // A branch that always leaves proves the other branch's facts
+                // for the rest of the block.
+                if always_exits(then_block) {
+                    self.narrow_all(&facts.when_false);
+                }
+                if else_block.as_deref().is_some_and(always_exits) {
+                    self.narrow_all(&facts.when_true);
+                }
+
                 Ok(TypedStatement::If {
                     condition: typed_condition,
                     then_block: typed_then,
@@ -139,71 +171,158 @@ impl TypeChecker {
             }
 
             ast::Statement::For {
+                label,
                 init,
                 condition,
                 update,
                 body,
             } => {
+                self.end_narrowing_in(stmt);
                 self.push_scope();
-                let typed_init = self.check_stmt(init)?;
+                let typed_for = self.check_for_loop(label, init, condition, update, body);
+                self.pop_scope();
+                typed_for
+            }
 
+            ast::Statement::While {
+                label,
+                condition,
+                body,
+            } => {
+                self.end_narrowing_in(stmt);
                 let typed_condition = self.check_expr(condition)?;
                 if !self.is_boolean(&typed_condition.ty)
                     || typed_condition.ty.nullable
                     || typed_condition.ty.errorable
                 {
                     return Err(TypeError::new(
-                        "For loop condition must be a non-nullable, non-errorable boolean",
+                        "While loop condition must be a non-nullable, non-errorable boolean",
                     ));
                 }
 
-                let typed_body: Vec<TypedStatement> = body
-                    .iter()
-                    .map(|s| self.check_stmt(s))
-                    .collect::<Result<_, _>>()?;
-
-                let typed_update = self.check_stmt(update)?;
-
+                self.push_scope();
+                let typed_body = self.check_loop_body(label, body);
                 self.pop_scope();
 
-                Ok(TypedStatement::For {
-                    init: Box::new(typed_init),
+                Ok(TypedStatement::While {
                     condition: typed_condition,
-                    update: Box::new(typed_update),
                     body: typed_body,
                 })
             }
 
-            ast::Statement::While { condition, body } => {
-                let typed_condition = self.check_expr(condition)?;
-                if !self.is_boolean(&typed_condition.ty)
-                    || typed_condition.ty.nullable
-                    || typed_condition.ty.errorable
-                {
-                    return Err(TypeError::new(
-                        "While loop condition must be a non-nullable, non-errorable boolean",
-                    ));
-                }
+            ast::Statement::IfLet {
+                name,
+                value,
+                then_block,
+                else_block,
+            } => {
+                let typed_value = self.check_expr(value)?;
+                let ty = self.let_binding_type(&typed_value.ty)?;
 
                 self.push_scope();
-                let typed_body: Vec<TypedStatement> = body
-                    .iter()
-                    .map(|s| self.check_stmt(s))
-                    .collect::<Result<_, _>>()?;
+                self.define(name.clone(), ty);
+                let typed_then = self.check_block(then_block);
                 self.pop_scope();
 
-                Ok(TypedStatement::While {
-                    condition: typed_condition,
+                let typed_else = if let Some(alt_stmts) = else_block {
+                    self.push_scope();
+                    let typed = self.check_block(alt_stmts);
+                    self.pop_scope();
+                    Some(typed)
+                } else {
+                    None
+                };
+
+                Ok(TypedStatement::IfLet {
+                    name: name.clone(),
+                    value: typed_value,
+                    then_block: typed_then,
+                    else_block: typed_else,
+                })
+            }
+
+            ast::Statement::WhileLet {
+                label,
+                name,
+                value,
+                body,
+            } => {
+                self.end_narrowing_in(stmt);
+                let typed_value = self.check_expr(value)?;
+                let ty = self.let_binding_type(&typed_value.ty)?;
+
+                self.push_scope();
+                self.define(name.clone(), ty);
+                let typed_body = self.check_loop_body(label, body);
+                self.pop_scope();
+
+                Ok(TypedStatement::WhileLet {
+                    name: name.clone(),
+                    value: typed_value,
                     body: typed_body,
                 })
             }
 
+            ast::Statement::ForIn {
+                label,
+                name,
+                iterable,
+                body,
+            } => {
+                self.end_narrowing_in(stmt);
+                self.push_scope();
+                let typed_for = self.check_for_in(label, name, iterable, body);
+                self.pop_scope();
+                typed_for
+            }
+
+            ast::Statement::Unchecked { body } => {
+                self.push_scope();
+                let typed_body = self.check_block(body);
+                self.pop_scope();
+
+                Ok(TypedStatement::Unchecked { body: typed_body })
+            }
+
             ast::Statement::Function {
                 name,
                 params,
                 returns,
                 body,
+                exported,
+                noalloc,
             } => {
+                if *exported && name == "main" {
+                    return Err(TypeError::new(
+                        "main is always exported, so it cannot be marked export",
+                    ));
+                }
+                if *noalloc && name == "main" {
+                    return Err(TypeError::new(
+                        "main sets up the runtime, so it cannot be marked @noalloc",
+                    ));
+                }
+                for (_, param_type) in params {
+                    self.check_not_array(param_type)?;
+                    self.check_error_set(param_type)?;
+                }
+                self.check_not_array(returns)?;
+                self.check_error_set(returns)?;
+                if name == "main" && self.current_return_type.is_none() {
+                    check_main_params(params)?;
+                }
+                let dropping = name.strip_suffix(".drop");
+                if let Some(owner) = dropping {
+                    let takes_owner = matches!(params.as_slice(), [(_, ty)]
+                        if !ty.nullable && !ty.errorable
+                            && ty.kind == TypeKind::Struct { name: owner.to_string() });
+                    if !takes_owner {
+                        return Err(TypeError::new(format!(
+                            "The drop method of '{}' must take just the {} being dropped",
+                            owner, owner
+                        )));
+                    }
+                }
                 let func_type = Type {
                     kind: TypeKind::Function {
                         params: params.iter().map(|(_, ty)| ty.clone()).collect(),
@@ -211,34 +330,84 @@ impl TypeChecker {
                     },
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 };
-                self.define(name.clone(), func_type);
+                // A top-level function is the same one wherever it's named,
+                // so its name can't be pointed at another.
+                match self.current_return_type {
+                    None => self.define_function(name.clone(), func_type),
+                    Some(_) => self.define(name.clone(), func_type),
+                }
 
+                // The body may run long after this point, so it starts
+                // without the narrowings that hold here.
+                let outer_narrowed = std::mem::take(&mut self.narrowed);
+                self.narrowed = vec![HashMap::new(); outer_narrowed.len()];
                 self.push_scope();
                 for (param_name, param_type) in params {
-                    self.define(param_name.clone(), param_type.clone());
+                    self.define_parameter(param_name.clone(), param_type.clone());
                 }
 
                 let prev_return_type = self.current_return_type.clone();
                 self.current_return_type = Some(returns.clone());
+                let outer_producing = std::mem::take(&mut self.producing);
+                let outer_blocks = std::mem::take(&mut self.blocks);
+                let outer_loops = std::mem::take(&mut self.loops);
 
-                let typed_body: Vec<TypedStatement> = body
-                    .iter()
-                    .map(|s| self.check_stmt(s))
-                    .collect::<Result<_, _>>()?;
+                // A statement that failed to check is missing from the typed
+                // body, so only a clean body can be trusted to run off its end.
+                let reported = self.diagnostics.len();
+                let typed_body = self.check_block(body);
+                if self.diagnostics.len() == reported && !always_leaves(&typed_body) {
+                    let function = match name.starts_with("lambda.") {
+                        true => "A lambda".to_string(),
+                        false => format!("Function '{}'", name),
+                    };
+                    self.diagnostics.push(TypeError::new(format!(
+                        "{} can reach the end of its body without returning",
+                        function
+                    )));
+                }
 
                 self.current_return_type = prev_return_type;
+                self.producing = outer_producing;
+                self.blocks = outer_blocks;
+                self.loops = outer_loops;
                 self.pop_scope();
+                self.narrowed = outer_narrowed;
 
                 Ok(TypedStatement::Function {
                     name: name.clone(),
                     params: params.clone(),
                     returns: returns.clone(),
                     body: typed_body,
+                    exported: *exported,
+                    noalloc: *noalloc,
                 })
             }
 
-            ast::Statement::Struct { name, fields } => {
+            ast::Statement::Struct {
+                name,
+                fields,
+                packed,
+            } => {
+                for (field_name, field_type) in fields {
+                    self.check_field_type(field_type)?;
+                    if let TypeKind::BitField { width } = field_type.kind {
+                        if !packed {
+                            return Err(TypeError::new(format!(
+                                "Bit-field '{}' is only allowed in a packed struct",
+                                field_name
+                            )));
+                        }
+                        if width == 0 || width > 64 {
+                            return Err(TypeError::new(format!(
+                                "Bit-field '{}' must be between 1 and 64 bits wide",
+                                field_name
+                            )));
+                        }
+                    }
+                }
                 self.structs
                     .insert(name.clone(), (fields.clone(), self.next_struct_index));
                 self.next_struct_index += 1;
@@ -248,21 +417,59 @@ impl TypeChecker {
                 })
             }
 
-            ast::Statement::Error { name } => {
+            ast::Statement::TypeAlias { name, .. } => Err(TypeError::new(format!(
+                "Type alias '{}' must be declared at top level",
+                name
+            ))),
+
+            ast::Statement::Import { path } => Err(TypeError::new(format!(
+                "Cannot import \"{}\" from a program without a file, compile it with compile_file",
+                path
+            ))),
+
+            ast::Statement::Extern { function, .. } => Err(TypeError::new(format!(
+                "Extern function '{}' must be declared at top level",
+                function.name
+            ))),
+
+            ast::Statement::Module { path, .. } => Err(TypeError::new(format!(
+                "Module {} must be at top level",
+                path
+            ))),
+
+            ast::Statement::Error { name, fields: declared } => {
+                // Treat as a struct whose `message: string` comes first
+                let message = Type {
+                    kind: TypeKind::String,
+                    nullable: false,
+                    errorable: false,
+                    errors: vec![],
+                };
+                let mut fields = vec![("message".to_string(), message.clone())];
+                for (field_name, field_type) in declared {
+                    self.check_field_type(field_type)?;
+                    if field_name == "message" {
+                        if *field_type != message {
+                            return Err(TypeError::new(format!(
+                                "The message of error '{}' must be a string",
+                                name
+                            )));
+                        }
+                        continue;
+                    }
+                    if let TypeKind::BitField { .. } = field_type.kind {
+                        return Err(TypeError::new(format!(
+                            "Bit-field '{}' is only allowed in a packed struct",
+                            field_name
+                        )));
+                    }
+                    fields.push((field_name.clone(), field_type.clone()));
+                }
                 self.errors.insert(name.clone());
-                // Treat as a struct with a single `message: String` field
-                let fields = vec![(
-                    "message".to_string(),
-                    Type {
-                        kind: TypeKind::String,
-                        nullable: false,
-                        errorable: false,
-                    },
-                )];
                 self.structs
                     .insert(name.clone(), (fields.clone(), self.next_struct_index));
                 self.next_struct_index += 1;
-                Ok(TypedStatement::Struct {
+                Ok(TypedStatement::Error {
                     name: name.clone(),
                     fields,
                 })
@@ -270,30 +477,60 @@ impl TypeChecker {
 
             ast::Statement::Produce(expr) => {
                 let typed_expr = self.check_expr(expr)?;
+                let Some(produced) = self.producing.last().cloned() else {
+                    return Err(TypeError::new("Produce outside of a match arm"));
+                };
+                let ty = match produced {
+                    Some(ty) if self.is_assignable(&typed_expr.ty, &ty) => ty,
+                    Some(ty) if !self.is_assignable(&ty, &typed_expr.ty) => {
+                        return Err(self.mismatch(
+                            "Match arms produce different types",
+                            &typed_expr.ty,
+                            &ty,
+                        ));
+                    }
+                    _ => typed_expr.ty.clone(),
+                };
+                *self.producing.last_mut().unwrap() = Some(ty);
                 Ok(TypedStatement::Produce(typed_expr))
             }
 
             ast::Statement::Print(expr) => {
                 let typed_expr = self.check_expr(expr)?;
-                if typed_expr.ty.nullable || typed_expr.ty.errorable {
+                Ok(TypedStatement::Print(self.printable(typed_expr)?))
+            }
+
+            ast::Statement::Println(exprs) => {
+                Ok(TypedStatement::Print(self.check_println(exprs)?))
+            }
+
+            // Exits are only known to come after a `defer` in the body
+            // itself, rather than in a branch or loop that may not run it.
+            ast::Statement::Defer(expr) => {
+                if self.current_return_type.is_none() || self.blocks != 1 {
                     return Err(TypeError::new(
-                        "Cannot print nullable or errorable expression",
+                        "Defer must be at the top level of a function's body",
                     ));
                 }
-                Ok(TypedStatement::Print(typed_expr))
+                Ok(TypedStatement::Defer(self.check_expr(expr)?))
             }
 
+            ast::Statement::Assert {
+                condition,
+                message,
+                line,
+            } => self.check_assert(condition, message.as_ref(), *line),
+
             ast::Statement::Raise(expr) => {
                 let typed_expr = self.check_expr(expr)?;
-                if let TypeKind::Struct { name } = &typed_expr.ty.kind {
-                    if !self.errors.contains(name) {
-                        return Err(TypeError::new(format!(
-                            "'{}' is not an error type",
-                            name
-                        )));
-                    }
-                } else {
+                let TypeKind::Struct { name } = &typed_expr.ty.kind else {
                     return Err(TypeError::new("Can only raise error types"));
+                };
+                if !self.errors.contains(name) {
+                    return Err(TypeError::new(format!(
+                        "'{}' is not an error type",
+                        name
+                    )));
                 }
                 if let Some(expected_type) = &self.current_return_type {
                     if !expected_type.errorable {
@@ -301,6 +538,12 @@ impl TypeChecker {
                             "Cannot raise in a function that does not return an errorable type",
                         ));
                     }
+                    if !expected_type.errors.is_empty() && !expected_type.errors.contains(name) {
+                        return Err(TypeError::new(format!(
+                            "Cannot raise '{}' in a function that returns {}",
+                            name, expected_type
+                        )));
+                    }
                 } else {
                     return Err(TypeError::new("Raise statement outside of function"));
                 }
@@ -309,15 +552,602 @@ impl TypeChecker {
         }
     }
 
-    pub fn check_program(&mut self, program: &ast::Program) -> Result<TypedProgram, TypeError> {
-        let typed_statements: Vec<TypedStatement> = program
-            .statements
+    /// Checks `assert condition, message;` as the `if` it stands for. A
+    /// failed assert raises an `AssertError` where the function may raise
+    /// one, and otherwise prints its message and exits the program.
+    fn check_assert(
+        &mut self,
+        condition: &ast::Expr,
+        message: Option<&ast::Expr>,
+        line: usize,
+    ) -> Result<TypedStatement, TypeError> {
+        if self.options.release {
+            self.check_expr(condition)?;
+            if let Some(message) = message {
+                self.check_expr(message)?;
+            }
+            // An empty block, which compiles to nothing.
+            return Ok(TypedStatement::Unchecked { body: vec![] });
+        }
+
+        let location = match &self.module {
+            Some(path) => format!("{}:{}", path, line),
+            None => format!("line {}", line),
+        };
+        let text = match message {
+            Some(message) => ast::Expr::Binary {
+                left: Box::new(ast::Expr::String(format!("{}: ", location))),
+                op: ast::BinaryOp::Plus,
+                right: Box::new(message.clone()),
+            },
+            None => ast::Expr::String(format!("{}: assertion failed", location)),
+        };
+        let raises = self.current_return_type.as_ref().is_some_and(|ty| {
+            ty.errorable && (ty.errors.is_empty() || ty.errors.iter().any(|e| e == "AssertError"))
+        });
+        let then_block = if raises {
+            vec![ast::Statement::Raise(ast::Expr::New {
+                name: "AssertError".to_string(),
+                fields: vec![("message".to_string(), text)],
+            })]
+        } else {
+            vec![
+                ast::Statement::Print(text),
+                ast::Statement::Expr(ast::Expr::Call {
+                    callee: Box::new(ast::Expr::Identifier("exit".to_string())),
+                    args: vec![ast::Expr::Integer(1)],
+                }),
+            ]
+        };
+        self.check_stmt(&ast::Statement::If {
+            condition: ast::Expr::Unary {
+                op: ast::UnaryOp::Not,
+                expr: Box::new(condition.clone()),
+            },
+            then_block,
+            else_block: None,
+        })
+    }
+
+    /// Checks a loop's body, inside which the loop is the innermost one a
+    /// `break` or `continue` can leave.
+    fn check_loop_body(
+        &mut self,
+        label: &Option<String>,
+        body: &[ast::Statement],
+    ) -> Vec<TypedStatement> {
+        self.loops.push(label.clone());
+        let typed_body = self.check_block(body);
+        self.loops.pop();
+        typed_body
+    }
+
+    /// How many loops a `break` or `continue` passes over to reach the one
+    /// it names, the innermost when it names none.
+    fn loops_out(&self, keyword: &str, label: Option<&String>) -> Result<u32, TypeError> {
+        let Some(label) = label else {
+            if self.loops.is_empty() {
+                return Err(TypeError::new(format!("'{}' outside of a loop", keyword)));
+            }
+            return Ok(0);
+        };
+        self.loops
             .iter()
-            .map(|s| self.check_stmt(s))
-            .collect::<Result<_, _>>()?;
+            .rev()
+            .position(|l| l.as_ref() == Some(label))
+            .map(|out| out as u32)
+            .ok_or_else(|| TypeError::new(format!("No enclosing loop is labeled '{}'", label)))
+    }
 
-        Ok(TypedProgram {
-            statements: typed_statements,
+    /// Checks a for loop inside the loop's scope. Kept separate so the caller
+    /// can pop the scope before propagating an error.
+    fn check_for_loop(
+        &mut self,
+        label: &Option<String>,
+        init: &ast::Statement,
+        condition: &ast::Expr,
+        update: &ast::Statement,
+        body: &[ast::Statement],
+    ) -> Result<TypedStatement, TypeError> {
+        let typed_init = self.check_stmt(init)?;
+
+        let typed_condition = self.check_expr(condition)?;
+        if !self.is_boolean(&typed_condition.ty)
+            || typed_condition.ty.nullable
+            || typed_condition.ty.errorable
+        {
+            return Err(TypeError::new(
+                "For loop condition must be a non-nullable, non-errorable boolean",
+            ));
+        }
+
+        let typed_body = self.check_loop_body(label, body);
+
+        let typed_update = self.check_stmt(update)?;
+
+        Ok(TypedStatement::For {
+            init: Box::new(typed_init),
+            condition: typed_condition,
+            update: Box::new(typed_update),
+            body: typed_body,
         })
     }
+
+    /// Checks `for name in iterable` inside the loop's scope and lowers it to
+    /// an index-based while loop:
+    ///
+    /// ```text
+    /// if true {
+    ///     let for.items = iterable;
+    ///     let for.index: integer = 0;
+    ///     while for.index < #for.items {
+    ///         let name = for.items[for.index];
+    ///         for.index = for.index + 1;
+    ///         body
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// The `if` only scopes the hidden locals, whose names cannot clash with
+    /// identifiers. Advancing the index before the body keeps `continue` from
+    /// looping forever. Strings yield one-character strings. A range `a..b`
+    /// starts the index at `a`, evaluates `b` once into `for.end`, and yields
+    /// the index itself.
+    fn check_for_in(
+        &mut self,
+        label: &Option<String>,
+        name: &str,
+        iterable: &ast::Expr,
+        body: &[ast::Statement],
+    ) -> Result<TypedStatement, TypeError> {
+        let integer = Type {
+            kind: TypeKind::Integer,
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+        let boolean = Type {
+            kind: TypeKind::Boolean,
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+        let local = |name: &str, ty: &Type| TypedExpr {
+            expr: tast::Expr::Identifier(name.to_string()),
+            ty: ty.clone(),
+        };
+        let add = |left: TypedExpr, right: i64| TypedExpr {
+            expr: tast::Expr::Binary {
+                left: Box::new(left),
+                op: ast::BinaryOp::Plus,
+                right: Box::new(TypedExpr {
+                    expr: tast::Expr::Integer(right),
+                    ty: integer.clone(),
+                }),
+            },
+            ty: integer.clone(),
+        };
+        let less_than = |left: TypedExpr, right: TypedExpr| TypedExpr {
+            expr: tast::Expr::Binary {
+                left: Box::new(left),
+                op: ast::BinaryOp::Lt,
+                right: Box::new(right),
+            },
+            ty: boolean.clone(),
+        };
+        let index = local(FOR_INDEX, &integer);
+
+        let (setup, condition, element, current) = if let ast::Expr::Range { start, end } = iterable
+        {
+            let start = self.check_expr(start)?;
+            let end = self.check_expr(end)?;
+            if !self.is_assignable(&start.ty, &integer) || !self.is_assignable(&end.ty, &integer) {
+                return Err(TypeError::new("Range bounds must be integers"));
+            }
+            let setup = vec![
+                TypedStatement::Let {
+                    name: FOR_INDEX.to_string(),
+                    ty: integer.clone(),
+                    value: Some(start),
+                },
+                TypedStatement::Let {
+                    name: FOR_END.to_string(),
+                    ty: integer.clone(),
+                    value: Some(end),
+                },
+            ];
+            let condition = less_than(index.clone(), local(FOR_END, &integer));
+            (setup, condition, integer.clone(), index.expr.clone())
+        } else {
+            let items = self.check_expr(iterable)?;
+            if items.ty.nullable || items.ty.errorable {
+                return Err(TypeError::new(
+                    "For-in needs a non-nullable, non-errorable list, string or range",
+                ));
+            }
+            let element = match &items.ty.kind {
+                TypeKind::List { element } => element.as_ref().clone(),
+                TypeKind::String => items.ty.clone(),
+                _ => return Err(TypeError::new("For-in needs a list, string or range")),
+            };
+
+            // Strings go by character, the way `s[i]` and `length()` count,
+            // not by byte.
+            let items_ty = items.ty.clone();
+            let (current, count) = match &items_ty.kind {
+                TypeKind::String => (
+                    self.check_character(local(FOR_ITEMS, &items_ty), index.clone())?
+                        .expr,
+                    tast::Expr::Builtin {
+                        builtin: Builtin::StringLength,
+                        args: vec![local(FOR_ITEMS, &items_ty)],
+                    },
+                ),
+                _ => (
+                    tast::Expr::Index {
+                        object: Box::new(local(FOR_ITEMS, &items_ty)),
+                        key: Box::new(index.clone()),
+                    },
+                    tast::Expr::Unary {
+                        op: ast::UnaryOp::Count,
+                        expr: Box::new(local(FOR_ITEMS, &items_ty)),
+                    },
+                ),
+            };
+            let count = TypedExpr {
+                expr: count,
+                ty: integer.clone(),
+            };
+            let setup = vec![
+                TypedStatement::Let {
+                    name: FOR_ITEMS.to_string(),
+                    ty: items_ty,
+                    value: Some(items),
+                },
+                TypedStatement::Let {
+                    name: FOR_INDEX.to_string(),
+                    ty: integer.clone(),
+                    value: Some(TypedExpr {
+                        expr: tast::Expr::Integer(0),
+                        ty: integer.clone(),
+                    }),
+                },
+            ];
+            (setup, less_than(index.clone(), count), element, current)
+        };
+
+        self.define(name.to_string(), element.clone());
+        self.push_scope();
+        let typed_body = self.check_loop_body(label, body);
+        self.pop_scope();
+
+        let mut loop_body = vec![
+            TypedStatement::Let {
+                name: name.to_string(),
+                ty: element.clone(),
+                value: Some(TypedExpr {
+                    expr: current,
+                    ty: element,
+                }),
+            },
+            TypedStatement::Expr(TypedExpr {
+                expr: tast::Expr::Binary {
+                    left: Box::new(index.clone()),
+                    op: ast::BinaryOp::Is,
+                    right: Box::new(add(index, 1)),
+                },
+                ty: integer,
+            }),
+        ];
+        loop_body.extend(typed_body);
+
+        let mut then_block = setup;
+        then_block.push(TypedStatement::While {
+            condition,
+            body: loop_body,
+        });
+        Ok(TypedStatement::If {
+            condition: TypedExpr {
+                expr: tast::Expr::Boolean(true),
+                ty: boolean,
+            },
+            then_block,
+            else_block: None,
+        })
+    }
+
+    /// Forgets the narrowings of variables that the loop `stmt` assigns,
+    /// since its condition and body may run after an assignment.
+    fn end_narrowing_in(&mut self, stmt: &ast::Statement) {
+        let mut names = Vec::new();
+        assigned_in(std::slice::from_ref(stmt), &mut names);
+        for name in names {
+            self.end_narrowing(&name);
+        }
+    }
+
+    /// Checks each statement in turn, recording failures in `diagnostics`
+    /// instead of stopping at the first one.
+    /// The type an `if let` or `while let` binds its value's contents as.
+    fn let_binding_type(&self, ty: &Type) -> Result<Type, TypeError> {
+        if !(ty.nullable || ty.errorable) || ty.kind == TypeKind::Null {
+            return Err(TypeError::new(format!(
+                "'let' in a condition needs a nullable or errorable value, not {}",
+                ty
+            )));
+        }
+        self.check_not_array(ty)?;
+        Ok(Type {
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+            ..ty.clone()
+        })
+    }
+
+    /// Turns a nullable `if` condition into a check that it holds a value.
+    pub(super) fn check_presence(&mut self, value: TypedExpr) -> Result<TypedExpr, TypeError> {
+        if self.is_boolean(&value.ty) {
+            return Err(TypeError::new(
+                "If condition of type boolean? is ambiguous; unwrap it with ??",
+            ));
+        }
+
+        Ok(TypedExpr {
+            expr: tast::Expr::Builtin {
+                builtin: ast::Builtin::Present,
+                args: vec![value],
+            },
+            ty: Type {
+                kind: TypeKind::Boolean,
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            },
+        })
+    }
+
+    pub fn check_block(&mut self, stmts: &[ast::Statement]) -> Vec<TypedStatement> {
+        self.blocks += 1;
+        let mut typed = Vec::new();
+        for stmt in stmts {
+            match self.check_stmt(stmt) {
+                Ok(typed_stmt) => typed.push(typed_stmt),
+                Err(e) => {
+                    // Keep the binding visible so later uses aren't reported as undefined.
+                    match stmt {
+                        ast::Statement::Let { name, ty, .. } => {
+                            self.define(name.clone(), ty.clone())
+                        }
+                        ast::Statement::Const { name, ty, .. } => {
+                            self.define_constant(name.clone(), ty.clone())
+                        }
+                        _ => {}
+                    }
+                    self.diagnostics.push(e);
+                }
+            }
+        }
+        self.blocks -= 1;
+        typed
+    }
+
+    /// Declares the top-level functions in `body` before any of them is
+    /// checked, so they can name each other whatever order they come in.
+    fn declare_functions(&mut self, body: &[ast::Statement]) {
+        for stmt in body {
+            if let ast::Statement::Function {
+                name,
+                params,
+                returns,
+                ..
+            } = stmt
+            {
+                let func_type = Type {
+                    kind: TypeKind::Function {
+                        params: params.iter().map(|(_, ty)| ty.clone()).collect(),
+                        returns: Box::new(returns.clone()),
+                    },
+                    nullable: false,
+                    errorable: false,
+                    errors: vec![],
+                };
+                self.define_function(name.clone(), func_type);
+            }
+        }
+    }
+
+    /// Checks a statement at the top of a file, where `extern fn`s are
+    /// declared. They leave nothing behind but the imports their calls make.
+    fn check_top_level(&mut self, stmt: &ast::Statement) -> Vec<TypedStatement> {
+        if let ast::Statement::Extern {
+            function,
+            params,
+            returns,
+        } = stmt
+        {
+            if let Err(e) = self.declare_extern(function, params, returns) {
+                self.diagnostics.push(e);
+            }
+            return vec![];
+        }
+        self.check_block(std::slice::from_ref(stmt))
+    }
+
+    /// Checks a module's statements, which see the top-level declarations
+    /// of the modules it imports besides its own. A name declared by two
+    /// modules is an error rather than one hiding the other, since both end
+    /// up in the same program.
+    fn check_module(
+        &mut self,
+        path: &str,
+        imports: &[String],
+        body: &[ast::Statement],
+    ) -> Vec<TypedStatement> {
+        self.module = Some(path.to_string());
+        self.module_imports
+            .insert(path.to_string(), imports.to_vec());
+        self.declare_functions(body);
+
+        let mut typed = Vec::new();
+        for stmt in body {
+            let name = match stmt {
+                ast::Statement::Function { name, .. }
+                | ast::Statement::Struct { name, .. }
+                | ast::Statement::Error { name, .. }
+                | ast::Statement::Let { name, .. }
+                | ast::Statement::Const { name, .. } => Some(name),
+                ast::Statement::Extern { function, .. } => Some(&function.name),
+                _ => None,
+            };
+            if let Some(name) = name {
+                match self.declared_in.get(name) {
+                    Some(owner) if owner != path => {
+                        self.diagnostics.push(TypeError::new(format!(
+                            "'{}' is defined in both {} and {}",
+                            name, owner, path
+                        )));
+                        continue;
+                    }
+                    _ => {
+                        self.declared_in.insert(name.clone(), path.to_string());
+                    }
+                }
+            }
+            typed.extend(self.check_top_level(stmt));
+        }
+
+        self.module = None;
+        typed
+    }
+
+    pub fn check_program(&mut self, program: &ast::Program) -> Result<TypedProgram, Vec<TypeError>> {
+        let program = &self.resolve_aliases(program);
+        self.declare_functions(&program.statements);
+        let mut typed_statements = Vec::new();
+        // The assignments giving top-level variables their initial values,
+        // which run as `main`'s first statements, in the order they were
+        // declared. The variables themselves are globals, which every
+        // function can reach, exported ones included.
+        let mut declarations = Vec::new();
+        let mut globals = Vec::new();
+        for stmt in &program.statements {
+            let typed = match stmt {
+                ast::Statement::Module {
+                    path,
+                    imports,
+                    body,
+                } => self.check_module(path, imports, body),
+                stmt => self.check_top_level(stmt),
+            };
+            for typed in typed {
+                match typed {
+                    TypedStatement::Let { name, ty, value } => {
+                        declarations.extend(value.map(|value| initialize(&name, &ty, value)));
+                        globals.push((name, ty));
+                    }
+                    TypedStatement::Const { name, ty, value } => {
+                        declarations.push(initialize(&name, &ty, value));
+                        globals.push((name, ty));
+                    }
+                    typed => typed_statements.push(typed),
+                }
+            }
+        }
+        if !declarations.is_empty() && !prepend_to_main(&mut typed_statements, declarations) {
+            self.diagnostics.push(TypeError::new(
+                "Top-level declarations need a main function to run in",
+            ));
+        }
+
+        if self.diagnostics.is_empty()
+            && !(self.json_writes.is_empty() && self.json_reads.is_empty())
+        {
+            match self.json_helpers() {
+                Ok(helpers) => typed_statements.extend(helpers),
+                Err(e) => self.diagnostics.push(e),
+            }
+        }
+        if self.diagnostics.is_empty() && !self.show_writes.is_empty() {
+            match self.show_helpers() {
+                Ok(helpers) => typed_statements.extend(helpers),
+                Err(e) => self.diagnostics.push(e),
+            }
+        }
+        if self.diagnostics.is_empty() && !self.parses.is_empty() {
+            typed_statements.extend(self.parse_helpers());
+        }
+        if self.diagnostics.is_empty() && !self.list_functions.is_empty() {
+            typed_statements.extend(self.list_helpers());
+        }
+
+        if !self.diagnostics.is_empty() {
+            return Err(std::mem::take(&mut self.diagnostics));
+        }
+
+        let program = TypedProgram {
+            statements: typed_statements,
+            globals,
+        };
+        let errors = crate::analysis::noalloc::check(&program);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(program)
+    }
+}
+
+/// The program's `main` takes nothing, or the command-line arguments as a
+/// `{string}`.
+fn check_main_params(params: &[(String, Type)]) -> Result<(), TypeError> {
+    let args = Type {
+        kind: TypeKind::List {
+            element: Box::new(Type {
+                kind: TypeKind::String,
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            }),
+        },
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    };
+    match params {
+        [] => Ok(()),
+        [(_, ty)] if *ty == args => Ok(()),
+        _ => Err(TypeError::new(
+            "main takes either no parameters or the arguments as {string}",
+        )),
+    }
+}
+
+/// The assignment of a top-level variable's initial value.
+fn initialize(name: &str, ty: &Type, value: TypedExpr) -> TypedStatement {
+    TypedStatement::Expr(TypedExpr {
+        expr: tast::Expr::Binary {
+            left: Box::new(TypedExpr {
+                expr: tast::Expr::Identifier(name.to_string()),
+                ty: ty.clone(),
+            }),
+            op: ast::BinaryOp::Is,
+            right: Box::new(value),
+        },
+        ty: ty.clone(),
+    })
+}
+
+/// Puts the initial assignments of top-level variables at the top of
+/// `main`, so they run before anything else. Returns whether there was a
+/// `main`.
+fn prepend_to_main(statements: &mut [TypedStatement], declarations: Vec<TypedStatement>) -> bool {
+    for statement in statements {
+        if let TypedStatement::Function { name, body, .. } = statement {
+            if name == "main" {
+                body.splice(0..0, declarations);
+                return true;
+            }
+        }
+    }
+    false
 }
@@ -0,0 +1,71 @@
+use super::{TypeChecker, TypeError};
+use crate::ast::tast::{self, TypedExpr};
+use crate::ast::{self, Builtin, Type, TypeKind};
+
+impl TypeChecker {
+    /// Checks `s[i]`, the character at `i` as a one-character string. It's
+    /// the substring from `i` to `i + 1`, which counts characters and traps
+    /// out of bounds; an index that isn't a literal or a variable is bound
+    /// first so it runs once.
+    pub(super) fn check_character(
+        &mut self,
+        string: TypedExpr,
+        index: TypedExpr,
+    ) -> Result<TypedExpr, TypeError> {
+        if string.ty.nullable || string.ty.errorable {
+            return Err(TypeError::new("Index access on nullable or errorable type"));
+        }
+        if index.ty.kind != TypeKind::Integer || index.ty.nullable || index.ty.errorable {
+            return Err(TypeError::new("String index must be of type integer"));
+        }
+
+        let character = |index: TypedExpr| {
+            let next = TypedExpr {
+                expr: tast::Expr::Binary {
+                    left: Box::new(index.clone()),
+                    op: ast::BinaryOp::Plus,
+                    right: Box::new(TypedExpr {
+                        expr: tast::Expr::Integer(1),
+                        ty: plain(TypeKind::Integer),
+                    }),
+                },
+                ty: plain(TypeKind::Integer),
+            };
+            TypedExpr {
+                expr: tast::Expr::Builtin {
+                    builtin: Builtin::StringSubstring,
+                    args: vec![string, index, next],
+                },
+                ty: plain(TypeKind::String),
+            }
+        };
+
+        if matches!(
+            index.expr,
+            tast::Expr::Integer(_) | tast::Expr::Identifier(_)
+        ) {
+            return Ok(character(index));
+        }
+        let bound = TypedExpr {
+            expr: tast::Expr::Identifier("char.index".to_string()),
+            ty: index.ty.clone(),
+        };
+        Ok(TypedExpr {
+            expr: tast::Expr::Match {
+                expr: Box::new(index),
+                binding: "char.index".to_string(),
+                arms: vec![(None, vec![tast::TypedStatement::Produce(character(bound))])],
+            },
+            ty: plain(TypeKind::String),
+        })
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
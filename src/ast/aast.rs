@@ -1,10 +1,11 @@
-use super::ast::{BinaryOp, Pattern, Type, UnaryOp};
+use super::ast::{BinaryOp, Builtin, Extern, Type, UnaryOp};
 use std::cell::RefCell;
 use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct AnalyzedProgram {
     pub statements: Vec<AnalyzedStatement>,
+    pub globals: Vec<(String, Type)>,
 }
 
 #[derive(Debug, Clone)]
@@ -24,7 +25,19 @@ pub enum Expr {
         name: String,
         index: Option<u32>,
     },
+    /// A top-level variable, by its place in the program's globals.
+    Global {
+        name: String,
+        index: u32,
+    },
+    /// A top-level function, which every function names directly rather
+    /// than capturing it.
+    Function {
+        name: String,
+        fn_index: u32,
+    },
     List(Vec<AnalyzedExpr>),
+    Array(Vec<AnalyzedExpr>),
     Field {
         object: Box<AnalyzedExpr>,
         field: String,
@@ -50,10 +63,23 @@ pub enum Expr {
         callee: Box<AnalyzedExpr>,
         args: Vec<AnalyzedExpr>,
     },
+    Builtin {
+        builtin: Builtin,
+        args: Vec<AnalyzedExpr>,
+    },
+    /// A call to a function the host provides.
+    ExternCall {
+        function: Extern,
+        args: Vec<AnalyzedExpr>,
+    },
     Match {
         expr: Box<AnalyzedExpr>,
         binding: String,
-        arms: Vec<(Pattern, Vec<AnalyzedStatement>)>,
+        captured: Rc<RefCell<Option<String>>>,
+        /// The locals holding the matched value and what the arms produce.
+        index: u32,
+        result: u32,
+        arms: Vec<(Option<AnalyzedExpr>, Vec<AnalyzedStatement>)>,
     },
     Slice {
         expr: Box<AnalyzedExpr>,
@@ -82,8 +108,8 @@ pub enum AnalyzedStatement {
         index: Option<u32>,
     },
     Return(Option<AnalyzedExpr>),
-    Break,
-    Continue,
+    Break(u32),
+    Continue(u32),
     If {
         condition: AnalyzedExpr,
         then_block: Vec<AnalyzedStatement>,
@@ -99,6 +125,34 @@ pub enum AnalyzedStatement {
         condition: AnalyzedExpr,
         body: Vec<AnalyzedStatement>,
     },
+    /// `defer`, with its expression as a block so matches in it can be
+    /// lowered. The Wrapper runs it before each exit after it.
+    Defer {
+        body: Vec<AnalyzedStatement>,
+    },
+    /// `if let`, lowered to `if`s by `lower_matches`.
+    IfLet {
+        name: String,
+        value: AnalyzedExpr,
+        captured: Rc<RefCell<Option<String>>>,
+        /// The locals holding `value` and `name`.
+        holder: u32,
+        index: u32,
+        then_block: Vec<AnalyzedStatement>,
+        else_block: Option<Vec<AnalyzedStatement>>,
+    },
+    /// `while let`, lowered to a `while` by `lower_matches`.
+    WhileLet {
+        name: String,
+        value: AnalyzedExpr,
+        captured: Rc<RefCell<Option<String>>>,
+        holder: u32,
+        index: u32,
+        body: Vec<AnalyzedStatement>,
+    },
+    Unchecked {
+        body: Vec<AnalyzedStatement>,
+    },
     Function {
         name: String,
         params: Vec<(String, Type, u32, Rc<RefCell<Option<String>>>)>,
@@ -108,6 +162,10 @@ pub enum AnalyzedStatement {
         index: Option<u32>,
         fn_index: Option<u32>,
         locals: Vec<Type>,
+        /// The name each of `locals` was declared with.
+        local_names: Vec<String>,
+        exported: bool,
+        noalloc: bool,
     },
     Struct {
         name: String,
@@ -115,6 +173,7 @@ pub enum AnalyzedStatement {
     },
     Error {
         name: String,
+        fields: Vec<(String, Type)>,
     },
     Print(AnalyzedExpr),
     Produce(AnalyzedExpr),
@@ -21,6 +21,9 @@ pub enum BinaryOp {
     BitwiseOr,
     Power,
     Sll,
+    /// `>>`, which copies the sign bit into the bits it frees.
+    Sra,
+    /// `>>>`, which fills the bits it frees with zeros.
     Srl,
     Xor,
     Is,
@@ -36,11 +39,155 @@ pub enum UnaryOp {
     Stringify,
 }
 
+/// Operations provided by the compiler rather than defined in Star source.
+/// Method builtins take their receiver as the first argument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    BuilderAppend,
+    BuilderToString,
+    ListPush,
+    /// The methods on strings, which count and index in characters.
+    StringLength,
+    StringFind,
+    StringSubstring,
+    StringSplit,
+    StringToUpper,
+    StringToLower,
+    StringTrim,
+    StringReplace,
+    /// `ord(c)`: the code point of `c`, a one-character string.
+    Ord,
+    /// `chr(code)`: the one-character string for a code point.
+    Chr,
+    Repeat,
+    /// `int(x)` on a float, truncating towards zero.
+    ToInteger,
+    /// `float(x)` on an integer, and implicit widening.
+    ToFloat,
+    /// The math functions. `abs`, `min` and `max` keep integers integers;
+    /// the rest take and return floats.
+    Abs,
+    Min,
+    Max,
+    Sqrt,
+    Floor,
+    Ceil,
+    Round,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    /// Whether a nullable value holds a value, for `if x` on a `T?`.
+    Present,
+    /// Whether a nullable or errorable value is neither null nor an error,
+    /// for `if let`.
+    Holds,
+    /// Whether an errorable value holds an error of one type. The type
+    /// checker names the error with a string literal, which becomes the
+    /// error's struct id in IR.
+    HoldsError,
+    /// The error struct an errorable value holds, once `HoldsError` has
+    /// found which one it is.
+    HeldError,
+    /// The runtime's memory counters, which `gcstats()` gathers up.
+    HeapUsed,
+    HeapFree,
+    GcCount,
+    AllocatedSinceGc,
+    LargestFreeBlock,
+    /// `gc()`: collects now, rather than when an allocation needs room.
+    Collect,
+    /// `set_gc_threshold(bytes)`: collects whenever a heap would grow past
+    /// `bytes`, or only when one is full again once `bytes` is 0.
+    SetGcThreshold,
+    /// The runtime's JSON scanner, called only by generated `to_json` and
+    /// `from_json` helpers.
+    JsonReset,
+    JsonFail,
+    JsonFinish,
+    JsonEat,
+    JsonExpect,
+    JsonNull,
+    JsonBoolean,
+    JsonInteger,
+    JsonFloat,
+    JsonString,
+    JsonSkip,
+    JsonQuote,
+    /// The runtime's number readers, called only by the generated helpers
+    /// behind `parse_int` and `parse_float`.
+    ParseInteger,
+    ParseFloat,
+    ParseFailed,
+    /// Orders two strings byte by byte, negative when the first comes
+    /// first, for the generated helpers behind `sort`.
+    CompareStrings,
+    /// Async host calls, which only `await` can make. Each one suspends the
+    /// program until the host has its result.
+    Sleep,
+    Fetch,
+    /// `env(name)`: the host's environment variable, or null when unset.
+    Env,
+    /// `exit(code)`: stops the program, handing `code` to the host.
+    Exit,
+}
+
+/// A function the host provides, declared with `extern fn` and imported as
+/// `name` from the WASM module `module`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Extern {
+    pub module: String,
+    pub name: String,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Type {
     pub kind: TypeKind,
     pub nullable: bool,
     pub errorable: bool,
+    /// The errors an errorable type may hold, as `integer ! ParseError |
+    /// IOError` names them. Empty when it may hold any error.
+    pub errors: Vec<String>,
+}
+
+/// Writes the type the way a program would annotate it.
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.kind {
+            TypeKind::Integer | TypeKind::BitField { .. } => write!(f, "integer")?,
+            TypeKind::Float => write!(f, "float")?,
+            TypeKind::Boolean => write!(f, "boolean")?,
+            TypeKind::String => write!(f, "string")?,
+            TypeKind::Struct { name } | TypeKind::Error { name } => write!(f, "{}", name)?,
+            TypeKind::List { element } => write!(f, "{{{}}}", element)?,
+            TypeKind::Array { element, length } => write!(f, "[{}; {}]", element, length)?,
+            TypeKind::Function { params, returns } => {
+                write!(f, "(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ": {})", returns)?;
+            }
+            TypeKind::Null => write!(f, "null")?,
+            TypeKind::Unknown => write!(f, "unknown")?,
+        }
+        if self.nullable {
+            write!(f, "?")?;
+        }
+        if self.errorable {
+            write!(f, "!")?;
+        }
+        for (i, error) in self.errors.iter().enumerate() {
+            write!(f, "{} {}", if i > 0 { " |" } else { "" }, error)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -58,10 +205,19 @@ pub enum TypeKind {
     List {
         element: Box<Type>,
     },
+    Array {
+        element: Box<Type>,
+        length: u32,
+    },
     Function {
         params: Vec<Type>,
         returns: Box<Type>,
     },
+    /// An unsigned integer field of `width` bits inside a packed struct.
+    /// Reading it yields an `integer`.
+    BitField {
+        width: u32,
+    },
     Null,
     Unknown,
 }
@@ -75,6 +231,7 @@ pub enum Expr {
     Boolean(bool),
     Identifier(String),
     List(Vec<Expr>),
+    Array(Vec<Expr>),
     Field {
         object: Box<Expr>,
         field: String,
@@ -112,14 +269,48 @@ pub enum Expr {
     },
     UnwrapError(Box<Expr>),
     UnwrapNull(Box<Expr>),
+    /// `start..end`, the integers from `start` up to but not including
+    /// `end`. Only a for-in loop can take one.
+    Range {
+        start: Box<Expr>,
+        end: Box<Expr>,
+    },
+    /// `await call`, which suspends the program while the host carries out
+    /// an async call such as `sleep(ms)`.
+    Await(Box<Expr>),
+    /// `if condition { then_value } else { else_value }`, which evaluates
+    /// to one of its values.
+    If {
+        condition: Box<Expr>,
+        then_value: Box<Expr>,
+        else_value: Box<Expr>,
+    },
+    /// `fn(params): returns { body }`, a function without a name. It
+    /// captures what it uses the way a nested `fn` does.
+    Lambda {
+        params: Vec<(String, Type)>,
+        returns: Type,
+        body: Vec<Statement>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Pattern {
     MatchNull,
     MatchError,
+    /// `_`, which matches anything.
     MatchAll,
     MatchType(Type),
+    /// A literal the value must equal.
+    Literal(Expr),
+    /// `Name { field: pattern, .. }`, a struct whose fields match their
+    /// patterns. A field named alone binds its value to its name.
+    Struct {
+        name: String,
+        fields: Vec<(String, Pattern)>,
+    },
+    /// A name a struct pattern binds the field's value to.
+    Binding(String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -136,37 +327,116 @@ pub enum Statement {
         value: Expr,
     },
     Return(Option<Expr>),
-    Break,
-    Continue,
+    /// `break;`, or `break label;` to leave the loop labeled `label`.
+    Break(Option<String>),
+    /// `continue;`, or `continue label;` to go round the loop labeled
+    /// `label` again.
+    Continue(Option<String>),
     If {
         condition: Expr,
         then_block: Vec<Statement>,
         else_block: Option<Vec<Statement>>,
     },
+    /// Loops carry the label written before them, as in `outer: while`.
     For {
+        label: Option<String>,
         init: Box<Statement>,
         condition: Expr,
         update: Box<Statement>,
         body: Vec<Statement>,
     },
     While {
+        label: Option<String>,
         condition: Expr,
         body: Vec<Statement>,
     },
+    /// `defer expr;`, which runs `expr` whenever the function it's in
+    /// returns or raises after it, or runs off its end.
+    Defer(Expr),
+    /// `if let name = value { ... }`, which runs `then_block` with `name`
+    /// bound to what `value` holds when it's neither null nor an error.
+    IfLet {
+        name: String,
+        value: Expr,
+        then_block: Vec<Statement>,
+        else_block: Option<Vec<Statement>>,
+    },
+    /// `while let name = value { ... }`, which runs `body` with `name`
+    /// bound to what `value` holds until it's null or an error.
+    WhileLet {
+        label: Option<String>,
+        name: String,
+        value: Expr,
+        body: Vec<Statement>,
+    },
+    /// `for name in iterable { ... }` over a list's elements or a string's
+    /// characters.
+    ForIn {
+        label: Option<String>,
+        name: String,
+        iterable: Expr,
+        body: Vec<Statement>,
+    },
+    /// A block whose indexing skips runtime bounds checks.
+    Unchecked {
+        body: Vec<Statement>,
+    },
     Function {
         name: String,
         params: Vec<(String, Type)>,
         returns: Type,
         body: Vec<Statement>,
+        /// Marked `export`, so hosts can call it by name.
+        exported: bool,
+        /// Marked `@noalloc`, so it runs without a shadow stack frame.
+        noalloc: bool,
     },
     Struct {
         name: String,
         fields: Vec<(String, Type)>,
+        packed: bool,
     },
+    /// `error Name;` or `error Name { field: type, ... }`. Every error has a
+    /// `message: string` first; `fields` are the ones declared after it.
     Error {
         name: String,
+        fields: Vec<(String, Type)>,
+    },
+    /// `type Name = type;`, another name for a type. The type checker
+    /// replaces every use of the name with the type it stands for.
+    TypeAlias {
+        name: String,
+        ty: Type,
     },
     Print(Expr),
+    /// `println a, b;`, printing its values on one line separated by
+    /// spaces.
+    Println(Vec<Expr>),
     Produce(Expr),
     Raise(Expr),
+    /// `assert condition;` or `assert condition, message;`, found on `line`
+    /// of its file.
+    Assert {
+        condition: Expr,
+        message: Option<Expr>,
+        line: usize,
+    },
+    /// `extern "module" fn name(params): returns;`, a function the host
+    /// provides. The module is `env` unless given.
+    Extern {
+        function: Extern,
+        params: Vec<(String, Type)>,
+        returns: Type,
+    },
+    /// `import "path";`, making another file's declarations visible.
+    Import {
+        path: String,
+    },
+    /// A file's statements, once the loader has replaced its imports with
+    /// the paths of the modules they name.
+    Module {
+        path: String,
+        imports: Vec<String>,
+        body: Vec<Statement>,
+    },
 }
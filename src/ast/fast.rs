@@ -1,7 +1,11 @@
 use super::aast::AnalyzedStatement;
+use super::{IRStructKind, Type};
 
 #[derive(Debug)]
 pub struct FlattenedProgram {
-    pub structs: Vec<(AnalyzedStatement, u32, u32)>,
+    /// Each struct with its counts of struct and list pointer slots, and
+    /// whether the program declared it or it holds a closure's captures.
+    pub structs: Vec<(AnalyzedStatement, u32, u32, IRStructKind)>,
     pub functions: Vec<AnalyzedStatement>,
+    pub globals: Vec<(String, Type)>,
 }
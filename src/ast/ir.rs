@@ -1,9 +1,12 @@
-use super::ast::{BinaryOp, Type, UnaryOp};
+use super::ast::{BinaryOp, Builtin, Extern, Type, UnaryOp};
+use std::collections::HashSet;
 
 #[derive(Debug)]
 pub struct IRProgram {
     pub structs: Vec<IRStruct>,
     pub functions: Vec<IRFunction>,
+    /// The program's top-level variables, which live as long as it does.
+    pub globals: Vec<(String, Type)>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +24,11 @@ pub enum IRExprKind {
     Null,           // ty
 
     Local(u32), // ty
+    /// A top-level variable, by its index in `IRProgram::globals`.
+    Global(u32), // ty
+    /// A top-level function as a value, by its index. It captures nothing,
+    /// so the closure has no environment.
+    Function(u32), // ty
 
     Binary {
         left: Box<IRExpr>,
@@ -36,8 +44,24 @@ pub enum IRExprKind {
         callee: Box<IRExpr>,
         args: Vec<IRExpr>,
     },
+    /// A call known to always call the function at `fn_index`, which never
+    /// reads its environment, made without going through a closure.
+    CallDirect {
+        fn_index: u32,
+        args: Vec<IRExpr>,
+    },
+    Builtin {
+        builtin: Builtin,
+        args: Vec<IRExpr>,
+    },
+    /// A call to a function the host provides.
+    ExternCall {
+        function: Extern,
+        args: Vec<IRExpr>,
+    },
 
     List(Vec<IRExpr>),
+    Array(Vec<IRExpr>),
 
     New {
         struct_index: u32,
@@ -47,6 +71,8 @@ pub enum IRExprKind {
         object: Box<IRExpr>,
         offset: u32,
     },
+    /// The address of a struct field, in alloc's memory, as the target of
+    /// an assignment.
     FieldReference {
         object: Box<IRExpr>,
         offset: u32,
@@ -55,15 +81,34 @@ pub enum IRExprKind {
         list: Box<IRExpr>,
         index: Box<IRExpr>,
     },
+    /// The address of a list element, in dalloc's memory, as the target
+    /// of an assignment.
     IndexReference {
         list: Box<IRExpr>,
         index: Box<IRExpr>,
     },
+    ArrayIndex {
+        array: Box<IRExpr>,
+        index: Box<IRExpr>,
+        length: u32,
+    },
+    /// The address of an element of a fixed array, which sits inside its
+    /// struct in alloc's memory, as the target of an assignment.
+    ArrayIndexReference {
+        array: Box<IRExpr>,
+        index: Box<IRExpr>,
+        length: u32,
+    },
     Slice {
         expr: Box<IRExpr>,
         start: Box<IRExpr>,
         end: Box<IRExpr>,
     },
+    SliceReference {
+        list: Box<IRExpr>,
+        start: Box<IRExpr>,
+        end: Box<IRExpr>,
+    },
 
     Match {
         expr: Box<IRExpr>,
@@ -73,6 +118,9 @@ pub enum IRExprKind {
 
     UnwrapError(Box<IRExpr>),
     UnwrapNull(Box<IRExpr>),
+
+    /// The program's `AsyncState`, as an integer.
+    AsyncState,
 }
 
 #[derive(Debug, Clone)]
@@ -91,8 +139,10 @@ pub enum IRStmt {
         value: IRExpr,
     },
     Return(Option<IRExpr>),
-    Break,
-    Continue,
+    /// Leaves the loop this many loops out from the innermost.
+    Break(u32),
+    /// Goes round the loop this many loops out from the innermost again.
+    Continue(u32),
     If {
         condition: IRExpr,
         then_block: Vec<IRStmt>,
@@ -108,6 +158,10 @@ pub enum IRStmt {
         condition: IRExpr,
         body: Vec<IRStmt>,
     },
+    /// Statements compiled without runtime bounds checks on indexing.
+    Unchecked {
+        body: Vec<IRStmt>,
+    },
     Print(IRExpr),
     Produce(IRExpr),
     Raise(IRExpr),
@@ -116,6 +170,12 @@ pub enum IRStmt {
         captures: Box<IRExpr>,
         index: u32,
     },
+    /// Saves the function's locals for a later rewind and returns, leaving
+    /// its shadow stack frame in place. Only resumable functions have one.
+    Suspend,
+    /// Returns while the program exits, popping the function's shadow stack
+    /// frame. Nothing reads the value it returns.
+    Exit,
 }
 
 #[derive(Debug, Clone)]
@@ -124,9 +184,24 @@ pub struct IRFunction {
     pub params: Vec<Type>,
     pub returns: Type,
     pub locals: Vec<Type>,
+    /// The names of the params, then of the locals the program declared,
+    /// which follow the scratch values and environment in wasm.
+    pub local_names: Vec<String>,
     pub captures_struct: Option<u32>,
     pub body: Vec<IRStmt>,
     pub func_index: u32,
+    /// Whether the function can suspend, so it saves its locals when
+    /// unwinding and restores them instead of starting over when rewinding.
+    pub resumable: bool,
+    /// Whether the function is also exported under its own name, with its
+    /// Star signature.
+    pub exported: bool,
+    /// Whether the function was checked never to allocate, so nothing can
+    /// collect while it runs and it needs no shadow stack frame.
+    pub noalloc: bool,
+    /// The params and locals no collection can happen while they hold a
+    /// value still to be read, which codegen leaves out of the shadow stack.
+    pub unrooted: HashSet<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -135,9 +210,14 @@ pub struct IRStruct {
     pub fields: Vec<(String, Type)>,
     pub size: u32,
     pub offsets: Vec<u32>,
+    /// `(shift, width)` of each field packed into a shared slot, if any.
+    pub bits: Vec<Option<(u32, u32)>>,
     pub kind: IRStructKind,
     pub struct_count: u32,
     pub list_count: u32,
+    /// The function the collector's caller runs on each struct of this
+    /// type it finds unreachable, before reclaiming it.
+    pub drop: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -146,3 +226,133 @@ pub enum IRStructKind {
     Captures,
     Error,
 }
+
+impl IRExpr {
+    /// Calls `f` on this expression and every expression nested in it,
+    /// including those in match arm bodies.
+    pub fn visit(&self, f: &mut dyn FnMut(&IRExpr)) {
+        f(self);
+        match &self.node {
+            IRExprKind::Integer(_)
+            | IRExprKind::Float(_)
+            | IRExprKind::Boolean(_)
+            | IRExprKind::String(_)
+            | IRExprKind::Null
+            | IRExprKind::Local(_)
+            | IRExprKind::Global(_)
+            | IRExprKind::Function(_)
+            | IRExprKind::AsyncState => {}
+            IRExprKind::Binary { left, right, .. } => {
+                left.visit(f);
+                right.visit(f);
+            }
+            IRExprKind::Unary { expr, .. }
+            | IRExprKind::Field { object: expr, .. }
+            | IRExprKind::FieldReference { object: expr, .. }
+            | IRExprKind::UnwrapError(expr)
+            | IRExprKind::UnwrapNull(expr) => expr.visit(f),
+            IRExprKind::Call { callee, args } => {
+                callee.visit(f);
+                for arg in args {
+                    arg.visit(f);
+                }
+            }
+            IRExprKind::CallDirect { args, .. }
+            | IRExprKind::Builtin { args, .. }
+            | IRExprKind::ExternCall { args, .. }
+            | IRExprKind::List(args)
+            | IRExprKind::Array(args)
+            | IRExprKind::New { fields: args, .. } => {
+                for arg in args {
+                    arg.visit(f);
+                }
+            }
+            IRExprKind::Index { list, index }
+            | IRExprKind::IndexReference { list, index }
+            | IRExprKind::ArrayIndex {
+                array: list, index, ..
+            }
+            | IRExprKind::ArrayIndexReference {
+                array: list, index, ..
+            } => {
+                list.visit(f);
+                index.visit(f);
+            }
+            IRExprKind::Slice { expr, start, end }
+            | IRExprKind::SliceReference {
+                list: expr,
+                start,
+                end,
+            } => {
+                expr.visit(f);
+                start.visit(f);
+                end.visit(f);
+            }
+            IRExprKind::Match { expr, arms, .. } => {
+                expr.visit(f);
+                for (_, body) in arms {
+                    for stmt in body {
+                        stmt.visit_exprs(f);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl IRStmt {
+    /// Calls `f` on every expression in this statement, recursively.
+    pub fn visit_exprs(&self, f: &mut dyn FnMut(&IRExpr)) {
+        match self {
+            IRStmt::Expr(expr)
+            | IRStmt::LocalSet { value: expr, .. }
+            | IRStmt::Print(expr)
+            | IRStmt::Produce(expr)
+            | IRStmt::Raise(expr) => expr.visit(f),
+            IRStmt::Return(expr) => {
+                if let Some(expr) = expr {
+                    expr.visit(f);
+                }
+            }
+            IRStmt::Break(_) | IRStmt::Continue(_) | IRStmt::Suspend | IRStmt::Exit => {}
+            IRStmt::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                condition.visit(f);
+                for stmt in then_block {
+                    stmt.visit_exprs(f);
+                }
+                for stmt in else_block.iter().flatten() {
+                    stmt.visit_exprs(f);
+                }
+            }
+            IRStmt::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                init.visit_exprs(f);
+                condition.visit(f);
+                update.visit_exprs(f);
+                for stmt in body {
+                    stmt.visit_exprs(f);
+                }
+            }
+            IRStmt::While { condition, body } => {
+                condition.visit(f);
+                for stmt in body {
+                    stmt.visit_exprs(f);
+                }
+            }
+            IRStmt::Unchecked { body } => {
+                for stmt in body {
+                    stmt.visit_exprs(f);
+                }
+            }
+            IRStmt::LocalClosure { captures, .. } => captures.visit(f),
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use super::ast::{BinaryOp, Pattern, Type, UnaryOp};
+use super::ast::{BinaryOp, Builtin, Extern, Type, UnaryOp};
 
 #[derive(Debug)]
 pub struct TypedProgram {
     pub statements: Vec<TypedStatement>,
+    /// The variables declared at the top level of a file, which `main`
+    /// assigns their initial values.
+    pub globals: Vec<(String, Type)>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -20,6 +23,7 @@ pub enum Expr {
     Boolean(bool),
     Identifier(String),
     List(Vec<TypedExpr>),
+    Array(Vec<TypedExpr>),
     Field {
         object: Box<TypedExpr>,
         field: String,
@@ -45,10 +49,22 @@ pub enum Expr {
         callee: Box<TypedExpr>,
         args: Vec<TypedExpr>,
     },
+    Builtin {
+        builtin: Builtin,
+        args: Vec<TypedExpr>,
+    },
+    /// A call to a function the host provides.
+    ExternCall {
+        function: Extern,
+        args: Vec<TypedExpr>,
+    },
     Match {
         expr: Box<TypedExpr>,
         binding: String,
-        arms: Vec<(Pattern, Vec<TypedStatement>)>,
+        /// Each arm's condition on `binding`, or `None` if it always
+        /// matches, with its body, which starts by binding the fields its
+        /// pattern names.
+        arms: Vec<(Option<TypedExpr>, Vec<TypedStatement>)>,
     },
     Slice {
         expr: Box<TypedExpr>,
@@ -73,8 +89,10 @@ pub enum TypedStatement {
         value: TypedExpr,
     },
     Return(Option<TypedExpr>),
-    Break,
-    Continue,
+    /// Leaves the loop this many loops out from the innermost.
+    Break(u32),
+    /// Goes round the loop this many loops out from the innermost again.
+    Continue(u32),
     If {
         condition: TypedExpr,
         then_block: Vec<TypedStatement>,
@@ -90,11 +108,30 @@ pub enum TypedStatement {
         condition: TypedExpr,
         body: Vec<TypedStatement>,
     },
+    Defer(TypedExpr),
+    /// `if let`, binding `name` to `value` unwrapped for `then_block`.
+    IfLet {
+        name: String,
+        value: TypedExpr,
+        then_block: Vec<TypedStatement>,
+        else_block: Option<Vec<TypedStatement>>,
+    },
+    /// `while let`, binding `name` to `value` unwrapped for `body`.
+    WhileLet {
+        name: String,
+        value: TypedExpr,
+        body: Vec<TypedStatement>,
+    },
+    Unchecked {
+        body: Vec<TypedStatement>,
+    },
     Function {
         name: String,
         params: Vec<(String, Type)>,
         returns: Type,
         body: Vec<TypedStatement>,
+        exported: bool,
+        noalloc: bool,
     },
     Struct {
         name: String,
@@ -102,6 +139,7 @@ pub enum TypedStatement {
     },
     Error {
         name: String,
+        fields: Vec<(String, Type)>,
     },
     Print(TypedExpr),
     Produce(TypedExpr),
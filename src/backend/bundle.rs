@@ -0,0 +1,348 @@
+use crate::error::CompilerError;
+use std::convert::Infallible;
+use wasm_encoder::reencode::{Error, Reencode};
+use wasm_encoder::{
+    CodeSection, DataCountSection, DataSection, ElementSection, ExportSection, FunctionSection,
+    GlobalSection, ImportSection, MemorySection, Module, TableSection, TypeSection,
+};
+use wasmparser::{
+    Data, Element, Export, ExternalKind, FunctionBody, Global, Import, MemoryType, Parser, Payload,
+    Table, TypeRef, TypeSectionReader,
+};
+
+/// The parts of a module a bundle copies, in its own index spaces.
+#[derive(Default)]
+struct Parsed<'a> {
+    types: Vec<TypeSectionReader<'a>>,
+    type_count: u32,
+    imports: Vec<Import<'a>>,
+    /// The type of each function the module defines.
+    functions: Vec<u32>,
+    tables: Vec<Table<'a>>,
+    memories: Vec<MemoryType>,
+    globals: Vec<Global<'a>>,
+    exports: Vec<Export<'a>>,
+    elements: Vec<Element<'a>>,
+    data: Vec<Data<'a>>,
+    bodies: Vec<FunctionBody<'a>>,
+}
+
+fn invalid(message: impl std::fmt::Display) -> CompilerError {
+    CompilerError::Codegen {
+        message: format!("Cannot bundle: {}", message),
+    }
+}
+
+fn parse<'a>(name: &str, bytes: &'a [u8]) -> Result<Parsed<'a>, CompilerError> {
+    wasmparser::validate(bytes).map_err(|e| invalid(format!("{} is invalid: {}", name, e)))?;
+
+    let mut parsed = Parsed::default();
+    for payload in Parser::new(0).parse_all(bytes) {
+        let read = |e: wasmparser::BinaryReaderError| invalid(format!("{}: {}", name, e));
+        match payload.map_err(read)? {
+            Payload::TypeSection(reader) => {
+                for group in reader.clone() {
+                    parsed.type_count += group.map_err(read)?.types().len() as u32;
+                }
+                parsed.types.push(reader);
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    parsed.imports.push(import.map_err(read)?);
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    parsed.functions.push(ty.map_err(read)?);
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    parsed.tables.push(table.map_err(read)?);
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    parsed.memories.push(memory.map_err(read)?);
+                }
+            }
+            Payload::GlobalSection(reader) => {
+                for global in reader {
+                    parsed.globals.push(global.map_err(read)?);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    parsed.exports.push(export.map_err(read)?);
+                }
+            }
+            Payload::ElementSection(reader) => {
+                for element in reader {
+                    parsed.elements.push(element.map_err(read)?);
+                }
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    parsed.data.push(data.map_err(read)?);
+                }
+            }
+            Payload::CodeSectionEntry(body) => parsed.bodies.push(body),
+            Payload::StartSection { .. } => {
+                return Err(invalid(format!("{} has a start function", name)));
+            }
+            Payload::Version { .. }
+            | Payload::DataCountSection { .. }
+            | Payload::CodeSectionStart { .. }
+            | Payload::CustomSection(_)
+            | Payload::End(_) => {}
+            _ => {
+                return Err(invalid(format!(
+                    "{} uses a section bundles can't hold",
+                    name
+                )))
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// Where the items of one module end up in the bundle, indexed by their
+/// positions in that module.
+#[derive(Default)]
+struct Indices {
+    types: u32,
+    functions: Vec<u32>,
+    tables: Vec<u32>,
+    memories: Vec<u32>,
+    globals: Vec<u32>,
+    elements: u32,
+    data: u32,
+}
+
+impl Indices {
+    fn space(&mut self, kind: ExternalKind) -> &mut Vec<u32> {
+        match kind {
+            ExternalKind::Func | ExternalKind::FuncExact => &mut self.functions,
+            ExternalKind::Table => &mut self.tables,
+            ExternalKind::Memory => &mut self.memories,
+            ExternalKind::Global | ExternalKind::Tag => &mut self.globals,
+        }
+    }
+}
+
+impl Reencode for Indices {
+    type Error = Infallible;
+
+    fn type_index(&mut self, ty: u32) -> Result<u32, Error> {
+        Ok(self.types + ty)
+    }
+
+    fn function_index(&mut self, func: u32) -> Result<u32, Error> {
+        Ok(self.functions[func as usize])
+    }
+
+    fn table_index(&mut self, table: u32) -> Result<u32, Error> {
+        Ok(self.tables[table as usize])
+    }
+
+    fn memory_index(&mut self, memory: u32) -> Result<u32, Error> {
+        Ok(self.memories[memory as usize])
+    }
+
+    fn global_index(&mut self, global: u32) -> Result<u32, Error> {
+        Ok(self.globals[global as usize])
+    }
+
+    fn element_index(&mut self, element: u32) -> Result<u32, Error> {
+        Ok(self.elements + element)
+    }
+
+    fn data_index(&mut self, data: u32) -> Result<u32, Error> {
+        Ok(self.data + data)
+    }
+}
+
+fn kind_of(ty: &TypeRef) -> ExternalKind {
+    match ty {
+        TypeRef::Func(_) | TypeRef::FuncExact(_) => ExternalKind::Func,
+        TypeRef::Table(_) => ExternalKind::Table,
+        TypeRef::Memory(_) => ExternalKind::Memory,
+        TypeRef::Global(_) => ExternalKind::Global,
+        TypeRef::Tag(_) => ExternalKind::Tag,
+    }
+}
+
+/// Links `program` and the `runtime` modules it imports into one module.
+/// Each runtime module is named as the modules after it import it, and
+/// may only import those before it. Whatever no module in the bundle
+/// provides, such as `print`, is still imported from the host. The bundle
+/// exports what the program does, and each runtime export under the name
+/// [`crate::host::bundled_export`] gives it.
+pub fn bundle(program: &[u8], runtime: &[(&str, &[u8])]) -> Result<Vec<u8>, CompilerError> {
+    let mut names = vec![];
+    let mut modules = vec![];
+    for (name, bytes) in runtime {
+        modules.push(parse(name, bytes)?);
+        names.push(Some(*name));
+    }
+    modules.push(parse("the program", program)?);
+    names.push(None);
+
+    // Imports from modules outside the bundle, shared by every module that
+    // imports the same thing, with the module that first did.
+    let provided = |module: usize, import: &Import| names[..module].contains(&Some(import.module));
+    let mut host_imports: Vec<(usize, Import)> = vec![];
+    for (i, module) in modules.iter().enumerate() {
+        for import in &module.imports {
+            if !provided(i, import) && !host_imports.iter().any(|(_, host)| host == import) {
+                host_imports.push((i, *import));
+            }
+        }
+    }
+
+    let mut next = Indices::default();
+    for (_, import) in &host_imports {
+        let space = next.space(kind_of(&import.ty));
+        space.push(space.len() as u32);
+    }
+    let mut next_function = next.functions.len() as u32;
+    let mut next_table = next.tables.len() as u32;
+    let mut next_memory = next.memories.len() as u32;
+    let mut next_global = next.globals.len() as u32;
+
+    let mut indices: Vec<Indices> = vec![];
+    let (mut types, mut elements, mut data) = (0, 0, 0);
+    for (i, module) in modules.iter().enumerate() {
+        let mut own = Indices {
+            types,
+            elements,
+            data,
+            ..Indices::default()
+        };
+        for import in &module.imports {
+            let kind = kind_of(&import.ty);
+            let index = if provided(i, import) {
+                let source = names.iter().position(|name| *name == Some(import.module));
+                let source = source.expect("provided modules are named");
+                let export = modules[source]
+                    .exports
+                    .iter()
+                    .find(|export| export.name == import.name && export.kind == kind)
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "{} has no export {} for the import of {}.{}",
+                            import.module, import.name, import.module, import.name
+                        ))
+                    })?;
+                indices[source].space(kind)[export.index as usize]
+            } else {
+                let host = host_imports
+                    .iter()
+                    .filter(|(_, host)| kind_of(&host.ty) == kind)
+                    .position(|(_, host)| host == import);
+                host.expect("every other import is a host import") as u32
+            };
+            own.space(kind).push(index);
+        }
+        for _ in &module.functions {
+            own.functions.push(next_function);
+            next_function += 1;
+        }
+        for _ in &module.tables {
+            own.tables.push(next_table);
+            next_table += 1;
+        }
+        for _ in &module.memories {
+            own.memories.push(next_memory);
+            next_memory += 1;
+        }
+        for _ in &module.globals {
+            own.globals.push(next_global);
+            next_global += 1;
+        }
+        types += module.type_count;
+        elements += module.elements.len() as u32;
+        data += module.data.len() as u32;
+        indices.push(own);
+    }
+
+    encode(&modules, &names, &mut indices, &host_imports, data).map_err(|e| invalid(e.to_string()))
+}
+
+fn encode(
+    modules: &[Parsed],
+    names: &[Option<&str>],
+    indices: &mut [Indices],
+    host_imports: &[(usize, Import)],
+    data_count: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut types = TypeSection::new();
+    let mut functions = FunctionSection::new();
+    let mut tables = TableSection::new();
+    let mut memories = MemorySection::new();
+    let mut globals = GlobalSection::new();
+    let mut exports = ExportSection::new();
+    let mut elements = ElementSection::new();
+    let mut code = CodeSection::new();
+    let mut data = DataSection::new();
+
+    let mut imports = ImportSection::new();
+    for (module, import) in host_imports {
+        let ty = indices[*module].entity_type(import.ty)?;
+        imports.import(import.module, import.name, ty);
+    }
+
+    for ((module, own), name) in modules.iter().zip(indices.iter_mut()).zip(names) {
+        for reader in &module.types {
+            own.parse_type_section(&mut types, reader.clone())?;
+        }
+        for ty in &module.functions {
+            functions.function(own.type_index(*ty)?);
+        }
+        for table in &module.tables {
+            own.parse_table(&mut tables, table.clone())?;
+        }
+        for memory in &module.memories {
+            memories.memory(own.memory_type(*memory)?);
+        }
+        for global in &module.globals {
+            own.parse_global(&mut globals, global.clone())?;
+        }
+        for export in &module.exports {
+            let kind = own.export_kind(export.kind)?;
+            let index = own.external_index(export.kind, export.index)?;
+            match name {
+                Some(name) => {
+                    let bundled = crate::host::bundled_export(name, export.name);
+                    exports.export(&bundled, kind, index)
+                }
+                None => exports.export(export.name, kind, index),
+            };
+        }
+        for element in &module.elements {
+            own.parse_element(&mut elements, element.clone())?;
+        }
+        for body in &module.bodies {
+            own.parse_function_body(&mut code, body.clone())?;
+        }
+        for datum in &module.data {
+            own.parse_data(&mut data, datum.clone())?;
+        }
+    }
+
+    let mut bundle = Module::new();
+    bundle.section(&types);
+    bundle.section(&imports);
+    bundle.section(&functions);
+    bundle.section(&tables);
+    bundle.section(&memories);
+    bundle.section(&globals);
+    bundle.section(&exports);
+    bundle.section(&elements);
+    if data_count > 0 {
+        bundle.section(&DataCountSection { count: data_count });
+    }
+    bundle.section(&code);
+    bundle.section(&data);
+    Ok(bundle.finish())
+}
@@ -0,0 +1,663 @@
+use crate::ast::{Builtin, IRExpr, IRExprKind, Type, TypeKind};
+use crate::error::CompilerError;
+use wasm_encoder::{BlockType, Instruction, MemArg, ValType};
+
+use super::constants::{dtype, import, mem, BLOCK_LENGTH};
+use super::helpers::{emit_gc_retry, emit_storage_cast};
+use super::expr::{is_leaf, needs_hold, pointer_memory};
+use super::sanitize::Function;
+use super::Codegen;
+
+/// The struct the wrapper gives nullable and errorable values, and the tags
+/// `env` stores in it.
+const TAGGED_UNION: i32 = abi::tag::TAGGED_UNION as i32;
+const TAG_NULL: i64 = abi::tag::NULL as i64;
+const TAG_ERROR: i64 = abi::tag::ERROR as i64;
+const TAG_PRIMITIVE: i64 = abi::tag::PRIMITIVE as i64;
+const TAG_LIST: i64 = abi::tag::LIST as i64;
+
+/// Field offsets of the prelude's `Builder` struct. `buffer` is its only
+/// list field, so field segregation keeps it first.
+const BUILDER_BUFFER_OFFSET: u64 = 0;
+const BUILDER_LENGTH_OFFSET: u64 = 8;
+
+impl Codegen {
+    pub(super) fn compile_builtin(
+        &mut self,
+        builtin: Builtin,
+        args: &[IRExpr],
+        f: &mut Function,
+    ) -> Result<(), CompilerError> {
+        // The error `HoldsError` looks for is a struct index, resolved to
+        // its type id here rather than pushed.
+        let operands = match builtin {
+            Builtin::HoldsError => &args[..1],
+            _ => args,
+        };
+        for (i, arg) in operands.iter().enumerate() {
+            self.compile_expr(arg, f, false)?;
+            if holds_operand(builtin, arg, &operands[i + 1..]) {
+                self.hold_temporary(&arg.ty, f);
+            }
+        }
+
+        match builtin {
+            Builtin::BuilderAppend => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        // stack: [builder, piece] -> store both
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 8);
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 4);
+                    },
+                    |f| {
+                        scratch_load(f, 4);
+                        builder_field(f, BUILDER_BUFFER_OFFSET);
+                        scratch_load(f, 4);
+                        builder_field(f, BUILDER_LENGTH_OFFSET);
+                        scratch_load(f, 8);
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DBUILD));
+                    },
+                );
+
+                // The buffer may have moved; store it back before bumping the length.
+                f.instruction(&Instruction::LocalSet(0));
+                scratch_load(f, 4);
+                f.instruction(&Instruction::LocalGet(0));
+                f.instruction(&Instruction::I64ExtendI32U);
+                f.instruction(&Instruction::I64Store(MemArg {
+                    offset: BUILDER_BUFFER_OFFSET,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+
+                scratch_load(f, 4);
+                scratch_load(f, 4);
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: BUILDER_LENGTH_OFFSET,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                scratch_load(f, 8);
+                f.instruction(&Instruction::I32Const(BLOCK_LENGTH));
+                f.instruction(&Instruction::I32Sub);
+                f.instruction(&Instruction::I32Load(MemArg {
+                    offset: 0,
+                    align: 2,
+                    memory_index: mem::DALLOC,
+                }));
+                f.instruction(&Instruction::I64ExtendI32U);
+                f.instruction(&Instruction::I64Add);
+                f.instruction(&Instruction::I64Store(MemArg {
+                    offset: BUILDER_LENGTH_OFFSET,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+
+                scratch_load(f, 4);
+            }
+            Builtin::ListPush => {
+                emit_storage_cast(f, &args[1].ty);
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        // stack: [list, value] -> store both
+                        f.instruction(&Instruction::LocalSet(1));
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::LocalGet(1));
+                        f.instruction(&Instruction::I64Store(MemArg {
+                            offset: 8,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 4);
+                    },
+                    |f| {
+                        scratch_load(f, 4);
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I64Load(MemArg {
+                            offset: 8,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DAPPEND));
+                    },
+                );
+            }
+            Builtin::StringLength => {
+                f.instruction(&Instruction::Call(import::DCHARS));
+                f.instruction(&Instruction::I64ExtendI32U);
+            }
+            Builtin::StringFind => {
+                f.instruction(&Instruction::Call(import::DFIND));
+            }
+            Builtin::Ord => {
+                f.instruction(&Instruction::Call(import::DORD));
+            }
+            Builtin::Chr => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::LocalSet(1));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::LocalGet(1));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DCHR));
+                    },
+                );
+            }
+            Builtin::StringSubstring => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        // stack: [string, start, end] -> end to the scratchpad,
+                        // start to local 1
+                        f.instruction(&Instruction::LocalSet(1));
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::LocalGet(1));
+                        f.instruction(&Instruction::I64Store(MemArg {
+                            offset: 8,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                        f.instruction(&Instruction::LocalSet(1));
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 4);
+                    },
+                    |f| {
+                        scratch_load(f, 4);
+                        f.instruction(&Instruction::LocalGet(1));
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I64Load(MemArg {
+                            offset: 8,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DSUBSTRING));
+                    },
+                );
+            }
+            Builtin::StringSplit
+            | Builtin::StringToUpper
+            | Builtin::StringToLower
+            | Builtin::StringTrim
+            | Builtin::StringReplace => {
+                let import = match builtin {
+                    Builtin::StringSplit => import::DSPLIT,
+                    Builtin::StringToUpper => import::DUPPER,
+                    Builtin::StringToLower => import::DLOWER,
+                    Builtin::StringTrim => import::DTRIM,
+                    _ => import::DREPLACE,
+                };
+                emit_string_call(f, import, args.len() as u64);
+            }
+            Builtin::Repeat => {
+                // stack: [value, count]
+                f.instruction(&Instruction::LocalTee(1));
+                f.instruction(&Instruction::I64Const(0));
+                f.instruction(&Instruction::I64LtS);
+                f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+                f.instruction(&Instruction::Unreachable);
+                f.instruction(&Instruction::End);
+                f.instruction(&Instruction::LocalGet(1));
+                f.instruction(&Instruction::I32WrapI64);
+                f.instruction(&Instruction::LocalSet(0));
+                scratch_store(f, 4);
+                emit_storage_cast(f, &args[0].ty);
+                f.instruction(&Instruction::LocalSet(1));
+                f.instruction(&Instruction::I32Const(0));
+                f.instruction(&Instruction::LocalGet(1));
+                f.instruction(&Instruction::I64Store(MemArg {
+                    offset: 8,
+                    align: 3,
+                    memory_index: mem::SHADOW,
+                }));
+
+                let ty = list_dtype(&args[0].ty);
+                emit_gc_retry(
+                    f,
+                    |_| {},
+                    |f| {
+                        f.instruction(&Instruction::I32Const(ty));
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I64Load(MemArg {
+                            offset: 8,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                        scratch_load(f, 4);
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DFILL));
+                    },
+                );
+            }
+            Builtin::ToInteger => {
+                f.instruction(&Instruction::I64TruncSatF64S);
+            }
+            Builtin::ToFloat => {
+                f.instruction(&Instruction::F64ConvertI64S);
+            }
+            Builtin::Abs | Builtin::Min | Builtin::Max
+                if args[0].ty.kind == TypeKind::Integer =>
+            {
+                emit_integer_math(f, builtin);
+            }
+            Builtin::Abs => {
+                f.instruction(&Instruction::F64Abs);
+            }
+            Builtin::Min => {
+                f.instruction(&Instruction::F64Min);
+            }
+            Builtin::Max => {
+                f.instruction(&Instruction::F64Max);
+            }
+            Builtin::Sqrt => {
+                f.instruction(&Instruction::F64Sqrt);
+            }
+            Builtin::Floor => {
+                f.instruction(&Instruction::F64Floor);
+            }
+            Builtin::Ceil => {
+                f.instruction(&Instruction::F64Ceil);
+            }
+            // Wasm only rounds halves to even, and has no trigonometry.
+            Builtin::Round
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Tan
+            | Builtin::Asin
+            | Builtin::Acos
+            | Builtin::Atan
+            | Builtin::Atan2 => {
+                let import = match builtin {
+                    Builtin::Round => import::DROUND,
+                    Builtin::Sin => import::DSIN,
+                    Builtin::Cos => import::DCOS,
+                    Builtin::Tan => import::DTAN,
+                    Builtin::Asin => import::DASIN,
+                    Builtin::Acos => import::DACOS,
+                    Builtin::Atan => import::DATAN,
+                    _ => import::DATAN2,
+                };
+                f.instruction(&Instruction::Call(import));
+            }
+            Builtin::Sleep | Builtin::Fetch => self.emit_await(builtin, f),
+            Builtin::Exit => self.emit_exit_call(f),
+            Builtin::Env => emit_env(f),
+            Builtin::Present => {
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 0,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                f.instruction(&Instruction::I64Const(0)); // the null tag
+                f.instruction(&Instruction::I64Ne);
+            }
+            Builtin::Holds => {
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 0,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                // Every tag past the null and error tags holds a value.
+                f.instruction(&Instruction::I64Const(TAG_PRIMITIVE));
+                f.instruction(&Instruction::I64GeU);
+            }
+            Builtin::HoldsError => {
+                let IRExprKind::Integer(index) = args[1].node else {
+                    unreachable!("errors are matched by struct index")
+                };
+                let Some(id) = self.type_ids[index as usize] else {
+                    // Nothing makes the error, so no value can hold it.
+                    f.instruction(&Instruction::Drop);
+                    f.instruction(&Instruction::I32Const(0));
+                    return Ok(());
+                };
+                // stack: [union]
+                f.instruction(&Instruction::LocalTee(0));
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 0,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                f.instruction(&Instruction::I64Const(TAG_ERROR));
+                f.instruction(&Instruction::I64Eq);
+                f.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+                // The error's type id is in its struct's header.
+                f.instruction(&Instruction::LocalGet(0));
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 8,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                f.instruction(&Instruction::I32WrapI64);
+                f.instruction(&Instruction::I32Const(8));
+                f.instruction(&Instruction::I32Sub);
+                f.instruction(&Instruction::I32Load(MemArg {
+                    offset: 0,
+                    align: 2,
+                    memory_index: mem::ALLOC,
+                }));
+                f.instruction(&Instruction::I32Const(id as i32));
+                f.instruction(&Instruction::I32Eq);
+                f.instruction(&Instruction::Else);
+                f.instruction(&Instruction::I32Const(0));
+                f.instruction(&Instruction::End);
+            }
+            Builtin::HeldError => {
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 8,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                f.instruction(&Instruction::I32WrapI64);
+            }
+            Builtin::HeapUsed
+            | Builtin::HeapFree
+            | Builtin::GcCount
+            | Builtin::AllocatedSinceGc
+            | Builtin::LargestFreeBlock => {
+                let import = match builtin {
+                    Builtin::HeapUsed => import::HEAP_USED,
+                    Builtin::HeapFree => import::HEAP_FREE,
+                    Builtin::GcCount => import::GC_COUNT,
+                    Builtin::AllocatedSinceGc => import::BYTES_ALLOCATED_SINCE_GC,
+                    _ => import::LARGEST_FREE_BLOCK,
+                };
+                f.instruction(&Instruction::Call(import));
+                f.instruction(&Instruction::I64ExtendI32U);
+            }
+            Builtin::Collect => {
+                f.collect();
+                f.instruction(&Instruction::I64Const(0));
+            }
+            Builtin::SetGcThreshold => {
+                f.instruction(&Instruction::I32WrapI64);
+                f.instruction(&Instruction::Call(import::SET_GC_THRESHOLD));
+                f.instruction(&Instruction::I64Const(0));
+            }
+            Builtin::JsonString | Builtin::JsonQuote => {
+                let import = match builtin {
+                    Builtin::JsonString => import::DJSON_STRING,
+                    _ => import::DJSON_QUOTE,
+                };
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 4);
+                    },
+                    |f| scratch_load(f, 4),
+                    |f| {
+                        f.instruction(&Instruction::Call(import));
+                    },
+                );
+            }
+            Builtin::JsonEat | Builtin::JsonExpect => {
+                // stack: [document, character]
+                f.instruction(&Instruction::I32WrapI64);
+                let import = match builtin {
+                    Builtin::JsonEat => import::DJSON_EAT,
+                    _ => import::DJSON_EXPECT,
+                };
+                f.instruction(&Instruction::Call(import));
+            }
+            Builtin::JsonReset
+            | Builtin::JsonFail
+            | Builtin::JsonFinish
+            | Builtin::JsonNull
+            | Builtin::JsonBoolean
+            | Builtin::JsonInteger
+            | Builtin::JsonFloat
+            | Builtin::JsonSkip => {
+                let import = match builtin {
+                    Builtin::JsonReset => import::DJSON_RESET,
+                    Builtin::JsonFail => import::DJSON_FAIL,
+                    Builtin::JsonFinish => import::DJSON_FINISH,
+                    Builtin::JsonNull => import::DJSON_NULL,
+                    Builtin::JsonBoolean => import::DJSON_BOOLEAN,
+                    Builtin::JsonInteger => import::DJSON_INTEGER,
+                    Builtin::JsonFloat => import::DJSON_FLOAT,
+                    _ => import::DJSON_SKIP,
+                };
+                f.instruction(&Instruction::Call(import));
+            }
+            Builtin::CompareStrings => {
+                f.instruction(&Instruction::Call(import::DCOMPARE));
+                f.instruction(&Instruction::I64ExtendI32S);
+            }
+            Builtin::ParseInteger | Builtin::ParseFloat | Builtin::ParseFailed => {
+                let import = match builtin {
+                    Builtin::ParseInteger => import::DATOI,
+                    Builtin::ParseFloat => import::DATOF,
+                    _ => import::DPARSE_FAILED,
+                };
+                f.instruction(&Instruction::Call(import));
+            }
+            Builtin::BuilderToString => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::LocalSet(0));
+                        scratch_store(f, 4);
+                    },
+                    |f| {
+                        scratch_load(f, 4);
+                        builder_field(f, BUILDER_BUFFER_OFFSET);
+                        f.instruction(&Instruction::I32Const(0));
+                        scratch_load(f, 4);
+                        builder_field(f, BUILDER_LENGTH_OFFSET);
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DSLICE));
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the name on the stack with a `string?` holding the host's
+/// variable of that name. The name, and then the copied value, wait in the
+/// scratchpad while allocating, which keeps them rooted.
+fn emit_env(f: &mut Function) {
+    f.instruction(&Instruction::LocalSet(0));
+    scratch_store(f, 4);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::Call(import::ENV_LENGTH));
+    f.instruction(&Instruction::LocalSet(0));
+    scratch_store(f, 8);
+
+    // Nothing to copy when the variable is unset.
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::LocalSet(0));
+    scratch_store(f, 12);
+    scratch_load(f, 8);
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32GeS);
+    f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+    emit_gc_retry(
+        f,
+        |_| {},
+        |f| {
+            f.instruction(&Instruction::I32Const(dtype::BYTES));
+            scratch_load(f, 8);
+        },
+        |f| {
+            f.instruction(&Instruction::Call(import::DALLOC));
+        },
+    );
+    f.instruction(&Instruction::Drop);
+    scratch_store(f, 12);
+    scratch_load(f, 4);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::Call(import::ENV_COPY));
+    f.instruction(&Instruction::End);
+
+    emit_gc_retry(
+        f,
+        |_| {},
+        |f| {
+            f.instruction(&Instruction::I32Const(TAGGED_UNION));
+        },
+        |f| {
+            f.instruction(&Instruction::Call(import::FALLOC));
+        },
+    );
+    // The tag: a string when one was copied, null otherwise.
+    scratch_load(f, 12);
+    f.instruction(&Instruction::I32Eqz);
+    f.instruction(&Instruction::If(wasm_encoder::BlockType::Result(
+        wasm_encoder::ValType::I64,
+    )));
+    f.instruction(&Instruction::I64Const(TAG_NULL));
+    f.instruction(&Instruction::Else);
+    f.instruction(&Instruction::I64Const(TAG_LIST));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::I64Store(MemArg {
+        offset: 0,
+        align: 3,
+        memory_index: mem::ALLOC,
+    }));
+    f.instruction(&Instruction::LocalGet(0));
+    scratch_load(f, 12);
+    f.instruction(&Instruction::I64ExtendI32U);
+    f.instruction(&Instruction::I64Store(MemArg {
+        offset: 8,
+        align: 3,
+        memory_index: mem::ALLOC,
+    }));
+    f.instruction(&Instruction::LocalGet(0));
+}
+
+
+/// Whether `builtin` holds its operand `arg` in a temp slot, as operands
+/// are held while the `later` ones are evaluated. The builtins that wait in
+/// the scratchpad while they allocate hold every fresh pointer operand too,
+/// since the scratchpad only roots dalloc blocks.
+pub(super) fn holds_operand(builtin: Builtin, arg: &IRExpr, later: &[IRExpr]) -> bool {
+    let allocates_after = matches!(
+        builtin,
+        Builtin::ListPush | Builtin::Repeat | Builtin::BuilderAppend | Builtin::BuilderToString
+    );
+    needs_hold(arg, &later.iter().collect::<Vec<_>>())
+        || (allocates_after && pointer_memory(&arg.ty).is_some() && !is_leaf(arg))
+}
+
+/// The dalloc block type of a list with `element`s, which tells the
+/// collector what the slots point to. A closure's environment is its low
+/// half, so closures are followed like struct pointers.
+pub(super) fn list_dtype(element: &Type) -> i32 {
+    if element.nullable || element.errorable {
+        return dtype::STRUCTS;
+    }
+    match element.kind {
+        TypeKind::Struct { .. } | TypeKind::Function { .. } => dtype::STRUCTS,
+        TypeKind::List { .. } | TypeKind::String => dtype::LISTS,
+        _ => dtype::PRIMITIVE,
+    }
+}
+
+/// Calls the runtime's `import` on the `count` strings on the stack, which
+/// wait in the scratchpad in case it has to collect and retry.
+fn emit_string_call(f: &mut Function, import: u32, count: u64) {
+    emit_gc_retry(
+        f,
+        |f| {
+            for slot in (0..count).rev() {
+                f.instruction(&Instruction::LocalSet(0));
+                scratch_store(f, 4 + slot * 4);
+            }
+        },
+        |f| {
+            for slot in 0..count {
+                scratch_load(f, 4 + slot * 4);
+            }
+        },
+        |f| {
+            f.instruction(&Instruction::Call(import));
+        },
+    );
+}
+
+/// Stores local 0 into the scratchpad at `offset`.
+/// Replaces the integer on the stack with its `abs`, or the two with their
+/// `min` or `max`. Each is read twice, so the last goes to local 1 and the
+/// other of two to the scratchpad.
+fn emit_integer_math(f: &mut Function, builtin: Builtin) {
+    f.instruction(&Instruction::LocalSet(1));
+    if builtin == Builtin::Abs {
+        // stack: [] -> [-x, x, x < 0]
+        f.instruction(&Instruction::I64Const(0));
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I64Sub);
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I64Const(0));
+        f.instruction(&Instruction::I64LtS);
+    } else {
+        // stack: [a] -> [a, b, a < b], or a > b for max
+        let b = MemArg {
+            offset: 8,
+            align: 3,
+            memory_index: mem::SHADOW,
+        };
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I64Store(b));
+        f.instruction(&Instruction::LocalTee(1));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I64Load(b));
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I64Load(b));
+        f.instruction(&if builtin == Builtin::Min {
+            Instruction::I64LtS
+        } else {
+            Instruction::I64GtS
+        });
+    }
+    f.instruction(&Instruction::Select);
+}
+
+fn scratch_store(f: &mut Function, offset: u64) {
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I32Store(MemArg {
+        offset,
+        align: 2,
+        memory_index: mem::SHADOW,
+    }));
+}
+
+fn scratch_load(f: &mut Function, offset: u64) {
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Load(MemArg {
+        offset,
+        align: 2,
+        memory_index: mem::SHADOW,
+    }));
+}
+
+/// Replaces a builder pointer on the stack with one of its fields as an i32.
+fn builder_field(f: &mut Function, offset: u64) {
+    f.instruction(&Instruction::I64Load(MemArg {
+        offset,
+        align: 3,
+        memory_index: mem::ALLOC,
+    }));
+    f.instruction(&Instruction::I32WrapI64);
+}
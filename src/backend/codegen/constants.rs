@@ -17,107 +17,449 @@ pub const FUNCTION_IMPORTS: &[ImportDef] = &[
         results: &[],
     },
     ImportDef {
-        module: "alloc",
+        module: abi::ALLOC,
         name: "init",
-        params: &[],
+        params: &[ValType::I32, ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "alloc",
+        module: abi::ALLOC,
         name: "register",
-        params: &[ValType::I32, ValType::I32, ValType::I32],
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "alloc",
+        module: abi::ALLOC,
         name: "falloc",
         params: &[ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dinit",
-        params: &[],
+        params: &[ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dalloc",
         params: &[ValType::I32, ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dconcat",
         params: &[ValType::I32, ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dslice",
         params: &[ValType::I32, ValType::I32, ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "din_u64",
         params: &[ValType::I64, ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "deq",
         params: &[ValType::I32, ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "ditoa",
         params: &[ValType::I64],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dbtoa",
         params: &[ValType::I32],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "dftoa",
         params: &[ValType::F64],
         results: &[ValType::I32],
     },
     ImportDef {
-        module: "shadow",
+        module: abi::DALLOC,
+        name: "dbuild",
+        params: &[ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dpin",
+        params: &[ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dsplice",
+        params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dappend",
+        params: &[ValType::I32, ValType::I64],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dfill",
+        params: &[ValType::I32, ValType::I64, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
         name: "init",
-        params: &[],
+        params: &[ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "shadow",
+        module: abi::SHADOW,
         name: "push",
         params: &[ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "shadow",
+        module: abi::SHADOW,
         name: "pop",
         params: &[],
         results: &[],
     },
     ImportDef {
-        module: "shadow",
+        module: abi::SHADOW,
         name: "set",
         params: &[ValType::I32, ValType::I32, ValType::I32],
         results: &[],
     },
     ImportDef {
-        module: "shadow",
+        module: abi::SHADOW,
         name: "gc",
         params: &[],
         results: &[],
     },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "heap_used",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "heap_free",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "gc_count",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "bytes_allocated_since_gc",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "largest_free_block",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_reset",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_fail",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_finish",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_eat",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_expect",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_null",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_boolean",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_integer",
+        params: &[ValType::I32],
+        results: &[ValType::I64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_float",
+        params: &[ValType::I32],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_string",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_skip",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "djson_quote",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: "env",
+        name: crate::host::ARG_COUNT_IMPORT,
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: "env",
+        name: crate::host::ARG_LENGTH_IMPORT,
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: "env",
+        name: crate::host::ARG_COPY_IMPORT,
+        params: &[ValType::I32, ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: "env",
+        name: crate::host::ENV_LENGTH_IMPORT,
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: "env",
+        name: crate::host::ENV_COPY_IMPORT,
+        params: &[ValType::I32, ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dchars",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dfind",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dsubstring",
+        params: &[ValType::I32, ValType::I64, ValType::I64],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dsplit",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dupper",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dlower",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dtrim",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dreplace",
+        params: &[ValType::I32, ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "datoi",
+        params: &[ValType::I32],
+        results: &[ValType::I64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "datof",
+        params: &[ValType::I32],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dparse_failed",
+        params: &[],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dround",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dsin",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dcos",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dtan",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dasin",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dacos",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "datan",
+        params: &[ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "datan2",
+        params: &[ValType::F64, ValType::F64],
+        results: &[ValType::F64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dcompare",
+        params: &[ValType::I32, ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dord",
+        params: &[ValType::I32],
+        results: &[ValType::I64],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dchr",
+        params: &[ValType::I64],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "drepeat",
+        params: &[ValType::I32, ValType::I64],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::DALLOC,
+        name: "dpolicy",
+        params: &[ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::ALLOC,
+        name: "finalizable",
+        params: &[ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::ALLOC,
+        name: "dequeue",
+        params: &[ValType::I32],
+        results: &[ValType::I32],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "park",
+        params: &[],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "unpark",
+        params: &[],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "set_gc_threshold",
+        params: &[ValType::I32],
+        results: &[],
+    },
+    ImportDef {
+        module: abi::SHADOW,
+        name: "set_global",
+        params: &[ValType::I32, ValType::I32, ValType::I32],
+        results: &[],
+    },
 ];
 
 /// Import function indices - derived from FUNCTION_IMPORTS array position
@@ -135,11 +477,68 @@ pub mod import {
     pub const DITOA: u32 = 10;
     pub const DBTOA: u32 = 11;
     pub const DFTOA: u32 = 12;
-    pub const SHADOW_INIT: u32 = 13;
-    pub const SHADOW_PUSH: u32 = 14;
-    pub const SHADOW_POP: u32 = 15;
-    pub const SHADOW_SET: u32 = 16;
-    pub const GC: u32 = 17;
+    pub const DBUILD: u32 = 13;
+    pub const DPIN: u32 = 14;
+    pub const DSPLICE: u32 = 15;
+    pub const DAPPEND: u32 = 16;
+    pub const DFILL: u32 = 17;
+    pub const SHADOW_INIT: u32 = 18;
+    pub const SHADOW_PUSH: u32 = 19;
+    pub const SHADOW_POP: u32 = 20;
+    pub const SHADOW_SET: u32 = 21;
+    pub const GC: u32 = 22;
+    pub const HEAP_USED: u32 = 23;
+    pub const HEAP_FREE: u32 = 24;
+    pub const GC_COUNT: u32 = 25;
+    pub const BYTES_ALLOCATED_SINCE_GC: u32 = 26;
+    pub const LARGEST_FREE_BLOCK: u32 = 27;
+    pub const DJSON_RESET: u32 = 28;
+    pub const DJSON_FAIL: u32 = 29;
+    pub const DJSON_FINISH: u32 = 30;
+    pub const DJSON_EAT: u32 = 31;
+    pub const DJSON_EXPECT: u32 = 32;
+    pub const DJSON_NULL: u32 = 33;
+    pub const DJSON_BOOLEAN: u32 = 34;
+    pub const DJSON_INTEGER: u32 = 35;
+    pub const DJSON_FLOAT: u32 = 36;
+    pub const DJSON_STRING: u32 = 37;
+    pub const DJSON_SKIP: u32 = 38;
+    pub const DJSON_QUOTE: u32 = 39;
+    pub const ARG_COUNT: u32 = 40;
+    pub const ARG_LENGTH: u32 = 41;
+    pub const ARG_COPY: u32 = 42;
+    pub const ENV_LENGTH: u32 = 43;
+    pub const ENV_COPY: u32 = 44;
+    pub const DCHARS: u32 = 45;
+    pub const DFIND: u32 = 46;
+    pub const DSUBSTRING: u32 = 47;
+    pub const DSPLIT: u32 = 48;
+    pub const DUPPER: u32 = 49;
+    pub const DLOWER: u32 = 50;
+    pub const DTRIM: u32 = 51;
+    pub const DREPLACE: u32 = 52;
+    pub const DATOI: u32 = 53;
+    pub const DATOF: u32 = 54;
+    pub const DPARSE_FAILED: u32 = 55;
+    pub const DROUND: u32 = 56;
+    pub const DSIN: u32 = 57;
+    pub const DCOS: u32 = 58;
+    pub const DTAN: u32 = 59;
+    pub const DASIN: u32 = 60;
+    pub const DACOS: u32 = 61;
+    pub const DATAN: u32 = 62;
+    pub const DATAN2: u32 = 63;
+    pub const DCOMPARE: u32 = 64;
+    pub const DORD: u32 = 65;
+    pub const DCHR: u32 = 66;
+    pub const DREPEAT: u32 = 67;
+    pub const DPOLICY: u32 = 68;
+    pub const FINALIZABLE: u32 = 69;
+    pub const DEQUEUE: u32 = 70;
+    pub const PARK: u32 = 71;
+    pub const UNPARK: u32 = 72;
+    pub const SET_GC_THRESHOLD: u32 = 73;
+    pub const SET_GLOBAL: u32 = 74;
 }
 
 /// Memory import definitions
@@ -151,27 +550,43 @@ pub struct MemoryImportDef {
 
 pub const MEMORY_IMPORTS: &[MemoryImportDef] = &[
     MemoryImportDef {
-        module: "alloc",
+        module: abi::ALLOC,
         name: "memory",
         min_pages: 1,
     },
     MemoryImportDef {
-        module: "dalloc",
+        module: abi::DALLOC,
         name: "memory",
         min_pages: 16,
     },
     MemoryImportDef {
-        module: "shadow",
+        module: abi::SHADOW,
         name: "memory",
         min_pages: 1,
     },
 ];
 
-/// Memory indices for the three memory spaces
+/// Memory indices for the three memory spaces, and the module's own memory
+/// for programs that await
 pub mod mem {
     pub const ALLOC: u32 = 0; // Fixed allocator memory (structs)
     pub const DALLOC: u32 = 1; // Dynamic allocator memory (lists, strings)
     pub const SHADOW: u32 = 2; // Shadow stack memory (GC roots + scratchpad)
+    pub const SAVE: u32 = 3; // Locals of suspended functions
+}
+
+/// Shadow memory word holding the current frame pointer
+pub const SHADOW_FRAME_POINTER: u64 = abi::shadow::FRAME_POINTER as u64;
+
+/// Bytes back from a dalloc pointer to the length in its block's header
+pub const BLOCK_LENGTH: i32 = abi::block::LENGTH as i32;
+
+/// Block types passed to `dalloc`, telling the collector what elements hold
+pub mod dtype {
+    pub const PRIMITIVE: i32 = abi::dtype::PRIMITIVE as i32;
+    pub const STRUCTS: i32 = abi::dtype::STRUCTS as i32;
+    pub const LISTS: i32 = abi::dtype::LISTS as i32;
+    pub const BYTES: i32 = abi::dtype::BYTES as i32;
 }
 
 pub const IMPORT_COUNT: u32 = FUNCTION_IMPORTS.len() as u32;
@@ -1,11 +1,21 @@
-use crate::ast::{BinaryOp, TypeKind, UnaryOp};
+use crate::ast::{BinaryOp, Type, TypeKind, UnaryOp};
 use crate::ast::{IRExpr, IRExprKind};
 use crate::error::CompilerError;
-use wasm_encoder::{Function, Instruction, MemArg};
+use wasm_encoder::{BlockType, Instruction, MemArg, ValType};
 
-use super::constants::{import, mem};
-use super::helpers::{emit_access_cast, emit_gc_retry, emit_storage_cast, emit_unwrap};
-use super::Codegen;
+use super::constants::{dtype, import, mem, BLOCK_LENGTH, SHADOW_FRAME_POINTER};
+use super::builtins::{holds_operand, list_dtype};
+use super::helpers::{
+    constant_list_bytes, emit_access_cast, emit_array_address, emit_gc_retry, emit_storage_cast,
+    emit_unwrap,
+};
+use super::sanitize::Function;
+use super::suspend::global;
+use super::{Codegen, DataSegment};
+
+/// How many elements of a list literal are stored for each push of the
+/// list's address.
+const LIST_CHUNK: usize = 64;
 
 impl Codegen {
     pub(super) fn compile_expr(
@@ -13,6 +23,20 @@ impl Codegen {
         expr: &IRExpr,
         f: &mut Function,
         preallocated: bool,
+    ) -> Result<(), CompilerError> {
+        // Temp slots taken while compiling an expression are free again once
+        // its value is on the stack.
+        let depth = self.temp_slot_depth;
+        let result = self.compile_node(expr, f, preallocated);
+        self.temp_slot_depth = depth;
+        result
+    }
+
+    fn compile_node(
+        &mut self,
+        expr: &IRExpr,
+        f: &mut Function,
+        preallocated: bool,
     ) -> Result<(), CompilerError> {
         match &expr.node {
             IRExprKind::Integer(n) => {
@@ -21,63 +45,52 @@ impl Codegen {
             IRExprKind::Float(n) => {
                 f.instruction(&Instruction::F64Const(wasm_encoder::Ieee64::from(*n)));
             }
+            IRExprKind::AsyncState => {
+                f.instruction(&Instruction::GlobalGet(self.async_global(global::STATE)));
+                f.instruction(&Instruction::I64ExtendI32U);
+            }
             IRExprKind::Boolean(b) => {
                 f.instruction(&Instruction::I32Const(if *b { 1 } else { 0 }));
             }
             IRExprKind::String(s) => {
+                let global = self.data_segment_indices[&DataSegment {
+                    dtype: dtype::BYTES,
+                    bytes: s.as_bytes().to_vec(),
+                }];
                 let len = s.len() as i32;
+
+                // The first evaluation copies the segment into a pinned template.
+                f.instruction(&Instruction::GlobalGet(global));
+                f.instruction(&Instruction::I32Eqz);
+                f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
                 emit_gc_retry(
                     f,
+                    |_| {},
                     |f| {
-                        // prepare: store params to scratchpad at memory 2, bytes 4-11
-                        f.instruction(&Instruction::I32Const(0));
-                        f.instruction(&Instruction::I32Const(1)); // type = 1
-                        f.instruction(&Instruction::I32Store(MemArg {
-                            offset: 4,
-                            align: 2,
-                            memory_index: mem::SHADOW,
-                        }));
-                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I32Const(dtype::BYTES));
                         f.instruction(&Instruction::I32Const(len));
-                        f.instruction(&Instruction::I32Store(MemArg {
-                            offset: 8,
-                            align: 2,
-                            memory_index: mem::SHADOW,
-                        }));
                     },
                     |f| {
-                        // retrieve: load params from scratchpad
-                        f.instruction(&Instruction::I32Const(0));
-                        f.instruction(&Instruction::I32Load(MemArg {
-                            offset: 4,
-                            align: 2,
-                            memory_index: mem::SHADOW,
-                        }));
+                        f.instruction(&Instruction::Call(import::DALLOC));
+                    },
+                );
+                f.instruction(&Instruction::GlobalSet(global));
+                self.emit_data_segment_copy(f, global);
+                f.instruction(&Instruction::End);
+
+                // Builders write into their buffer, so every evaluation gets its own copy.
+                emit_gc_retry(
+                    f,
+                    |_| {},
+                    |f| {
+                        f.instruction(&Instruction::GlobalGet(global));
                         f.instruction(&Instruction::I32Const(0));
-                        f.instruction(&Instruction::I32Load(MemArg {
-                            offset: 8,
-                            align: 2,
-                            memory_index: mem::SHADOW,
-                        }));
+                        f.instruction(&Instruction::I32Const(len));
                     },
                     |f| {
-                        // operation: call dalloc
-                        f.instruction(&Instruction::Call(import::DALLOC));
+                        f.instruction(&Instruction::Call(import::DSLICE));
                     },
                 );
-
-                for _ in 0..s.len() {
-                    f.instruction(&Instruction::LocalGet(0));
-                }
-
-                for (i, byte) in s.bytes().enumerate() {
-                    f.instruction(&Instruction::I32Const(byte as i32));
-                    f.instruction(&Instruction::I32Store(MemArg {
-                        offset: (i * 8) as u64,
-                        align: 2,
-                        memory_index: mem::DALLOC,
-                    }));
-                }
             }
             IRExprKind::Null => {
                 f.instruction(&Instruction::I64Const(0));
@@ -85,6 +98,12 @@ impl Codegen {
             IRExprKind::Local(index) => {
                 f.instruction(&Instruction::LocalGet(*index));
             }
+            IRExprKind::Global(index) => {
+                f.instruction(&Instruction::GlobalGet(self.program_global(*index)));
+            }
+            IRExprKind::Function(fn_index) => {
+                f.instruction(&Instruction::I64Const((*fn_index as i64) << 32));
+            }
             IRExprKind::Binary {
                 left,
                 op: BinaryOp::Is,
@@ -93,45 +112,127 @@ impl Codegen {
                 if let IRExprKind::Local(index) = &left.node {
                     self.compile_expr(right, f, false)?;
                     f.instruction(&Instruction::LocalTee(*index));
-                    match &right.ty.kind {
-                        TypeKind::Struct { .. } => {
-                            f.instruction(&Instruction::I32Const((*index - 2) as i32));
-                            f.instruction(&Instruction::I32Const(1));
-                            f.instruction(&Instruction::Call(import::SHADOW_SET));
-                            f.instruction(&Instruction::LocalGet(*index));
-                        }
-                        TypeKind::List { .. } | TypeKind::String => {
-                            f.instruction(&Instruction::I32Const((*index - 2) as i32));
-                            f.instruction(&Instruction::I32Const(2));
-                            f.instruction(&Instruction::Call(import::SHADOW_SET));
-                            f.instruction(&Instruction::LocalGet(*index));
+                    if !self.frameless
+                        && !self.unrooted.contains(index)
+                        && pointer_memory(&right.ty).is_some()
+                    {
+                        emit_root(f, &right.ty, (*index - 2) as i32);
+                        f.instruction(&Instruction::LocalGet(*index));
+                    }
+                } else if let IRExprKind::Global(index) = &left.node {
+                    let global = self.program_global(*index);
+                    self.compile_expr(right, f, false)?;
+                    f.instruction(&Instruction::GlobalSet(global));
+                    if let Some(memory) = pointer_memory(&left.ty) {
+                        f.instruction(&Instruction::GlobalGet(global));
+                        if is_closure(&left.ty) {
+                            // A closure's environment is its low half.
+                            f.instruction(&Instruction::I32WrapI64);
                         }
-                        _ => {}
+                        f.instruction(&Instruction::I32Const(*index as i32));
+                        f.instruction(&Instruction::I32Const(memory));
+                        f.instruction(&Instruction::Call(import::SET_GLOBAL));
+                    }
+                    f.instruction(&Instruction::GlobalGet(global));
+                } else if let (IRExprKind::FieldReference { .. }, IRExprKind::Array(elements)) =
+                    (&left.node, &right.node)
+                {
+                    // Copy the literal into the field's slots, leaving the address.
+                    self.compile_expr(left, f, false)?;
+                    f.instruction(&Instruction::LocalTee(0));
+                    for _ in elements {
+                        f.instruction(&Instruction::LocalGet(0));
+                    }
+                    self.compile_inline_array(elements, 0, f)?;
+                } else if let IRExprKind::SliceReference { list, start, end } = &left.node {
+                    self.compile_expr(list, f, false)?;
+                    if needs_hold(list, &[start, end, right]) {
+                        self.hold_temporary(&list.ty, f);
                     }
+                    self.compile_expr(start, f, false)?;
+                    f.instruction(&Instruction::I32WrapI64);
+                    self.compile_expr(end, f, false)?;
+                    f.instruction(&Instruction::I32WrapI64);
+                    self.compile_expr(right, f, false)?;
+                    f.instruction(&Instruction::Call(import::DSPLICE));
+                    // dsplice returns 0 when the range or the source length is wrong.
+                    f.instruction(&Instruction::LocalTee(0));
+                    f.instruction(&Instruction::I32Eqz);
+                    f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+                    f.instruction(&Instruction::Unreachable);
+                    f.instruction(&Instruction::End);
+                    f.instruction(&Instruction::LocalGet(0));
+                } else if let IRExprKind::ArrayIndexReference { .. } = &left.node {
+                    self.compile_expr(left, f, false)?;
+                    self.compile_expr(right, f, false)?;
+                    emit_storage_cast(f, &right.ty);
+                    f.instruction(&Instruction::LocalSet(1));
+                    f.instruction(&Instruction::LocalGet(1));
+                    f.instruction(&Instruction::I64Store(MemArg {
+                        offset: 0,
+                        align: 3,
+                        memory_index: mem::ALLOC,
+                    }));
+                    f.instruction(&Instruction::LocalGet(1));
+                    emit_access_cast(f, &right.ty);
                 } else if let IRExprKind::FieldReference { object, offset } = &left.node {
                     self.compile_expr(left, f, false)?;
                     f.instruction(&Instruction::LocalTee(0));
                     self.compile_expr(right, f, false)?;
+                    emit_storage_cast(f, &right.ty);
                     f.instruction(&Instruction::I64Store(MemArg {
                         offset: 0,
                         align: 3,
                         memory_index: mem::ALLOC,
                     }));
                     f.instruction(&Instruction::LocalGet(0));
-                } else {
-                    self.compile_expr(left, f, false)?;
+                } else if let IRExprKind::IndexReference { list, index } = &left.node {
+                    self.compile_element_address(list, index, &[right], f)?;
                     f.instruction(&Instruction::LocalTee(0));
                     self.compile_expr(right, f, false)?;
+                    emit_storage_cast(f, &right.ty);
                     f.instruction(&Instruction::I64Store(MemArg {
                         offset: 0,
                         align: 3,
                         memory_index: mem::DALLOC,
                     }));
                     f.instruction(&Instruction::LocalGet(0));
+                } else {
+                    return Err(CompilerError::Codegen {
+                        message: "Can only assign to locals, globals, fields and elements"
+                            .to_string(),
+                    });
+                }
+            }
+            IRExprKind::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => {
+                // The right side only runs when it decides the result, so a
+                // condition can guard it, as in `x != null and x > 0`.
+                self.compile_expr(left, f, false)?;
+                f.instruction(&Instruction::If(BlockType::Result(ValType::I32)));
+                if *op == BinaryOp::And {
+                    self.compile_expr(right, f, false)?;
+                    f.instruction(&Instruction::Else);
+                    f.instruction(&Instruction::I32Const(0));
+                } else {
+                    f.instruction(&Instruction::I32Const(1));
+                    f.instruction(&Instruction::Else);
+                    self.compile_expr(right, f, false)?;
                 }
+                f.instruction(&Instruction::End);
             }
             IRExprKind::Binary { left, op, right } => {
                 self.compile_expr(left, f, false)?;
+                if needs_hold(left, &[right]) {
+                    self.hold_temporary(&left.ty, f);
+                }
+                if *op == BinaryOp::In {
+                    // din_u64 takes the value as an element is stored.
+                    emit_storage_cast(f, &left.ty);
+                }
                 self.compile_expr(right, f, false)?;
                 match op {
                     BinaryOp::Plus => {
@@ -198,7 +299,36 @@ impl Codegen {
                         }
                     }
                     BinaryOp::Multiply => {
-                        if left.ty.kind == TypeKind::Float {
+                        if matches!(left.ty.kind, TypeKind::String | TypeKind::List { .. }) {
+                            emit_gc_retry(
+                                f,
+                                |f| {
+                                    // stack: [sequence, times]
+                                    f.instruction(&Instruction::LocalSet(1));
+                                    f.instruction(&Instruction::LocalSet(0));
+                                    f.instruction(&Instruction::I32Const(0));
+                                    f.instruction(&Instruction::LocalGet(0));
+                                    f.instruction(&Instruction::I32Store(MemArg {
+                                        offset: 4,
+                                        align: 2,
+                                        memory_index: mem::SHADOW,
+                                    }));
+                                },
+                                |f| {
+                                    f.instruction(&Instruction::I32Const(0));
+                                    f.instruction(&Instruction::I32Load(MemArg {
+                                        offset: 4,
+                                        align: 2,
+                                        memory_index: mem::SHADOW,
+                                    }));
+                                    f.instruction(&Instruction::LocalGet(1));
+                                },
+                                |f| {
+                                    f.instruction(&Instruction::Call(import::DREPEAT));
+                                },
+                            );
+                            return Ok(());
+                        } else if left.ty.kind == TypeKind::Float {
                             f.instruction(&Instruction::F64Mul);
                         } else {
                             f.instruction(&Instruction::I64Mul);
@@ -235,7 +365,6 @@ impl Codegen {
                             || matches!(left.ty.kind, TypeKind::List { .. })
                         {
                             f.instruction(&Instruction::Call(import::DEQ));
-                            f.instruction(&Instruction::I32Const(0));
                             f.instruction(&Instruction::I32Eqz);
                             return Ok(());
                         }
@@ -279,18 +408,15 @@ impl Codegen {
                     BinaryOp::Sll => {
                         f.instruction(&Instruction::I64Shl);
                     }
-                    BinaryOp::Srl => {
+                    BinaryOp::Sra => {
                         f.instruction(&Instruction::I64ShrS);
                     }
+                    BinaryOp::Srl => {
+                        f.instruction(&Instruction::I64ShrU);
+                    }
                     BinaryOp::Xor => {
                         f.instruction(&Instruction::I64Xor);
                     }
-                    BinaryOp::And => {
-                        f.instruction(&Instruction::I32And);
-                    }
-                    BinaryOp::Or => {
-                        f.instruction(&Instruction::I32Or);
-                    }
                     BinaryOp::In => {
                         f.instruction(&Instruction::Call(import::DIN_U64));
                     }
@@ -317,8 +443,12 @@ impl Codegen {
                     f.instruction(&Instruction::I32Eqz);
                 }
                 UnaryOp::Count => {
+                    if let TypeKind::Array { length, .. } = &expr.ty.kind {
+                        f.instruction(&Instruction::I64Const(*length as i64));
+                        return Ok(());
+                    }
                     self.compile_expr(expr, f, false)?;
-                    f.instruction(&Instruction::I32Const(4));
+                    f.instruction(&Instruction::I32Const(BLOCK_LENGTH));
                     f.instruction(&Instruction::I32Sub);
                     f.instruction(&Instruction::I32Load(MemArg {
                         offset: 0,
@@ -327,97 +457,10 @@ impl Codegen {
                     }));
                     f.instruction(&Instruction::I64ExtendI32U);
                 }
-                UnaryOp::Stringify => match expr.ty.kind {
-                    TypeKind::Integer => {
-                        self.compile_expr(expr, f, false)?;
-                        emit_gc_retry(
-                            f,
-                            |f| {
-                                f.instruction(&Instruction::LocalSet(1)); // i64 needs local1
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::LocalGet(1));
-                                f.instruction(&Instruction::I64Store(MemArg {
-                                    offset: 4,
-                                    align: 3,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::I64Load(MemArg {
-                                    offset: 4,
-                                    align: 3,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::Call(import::DITOA));
-                            },
-                        );
-                    }
-                    TypeKind::String => {
-                        self.compile_expr(expr, f, false)?;
-                    }
-                    TypeKind::Boolean => {
-                        self.compile_expr(expr, f, false)?;
-                        emit_gc_retry(
-                            f,
-                            |f| {
-                                f.instruction(&Instruction::LocalSet(0));
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::LocalGet(0));
-                                f.instruction(&Instruction::I32Store(MemArg {
-                                    offset: 4,
-                                    align: 2,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::I32Load(MemArg {
-                                    offset: 4,
-                                    align: 2,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::Call(import::DBTOA));
-                            },
-                        );
-                    }
-                    TypeKind::Float => {
-                        self.compile_expr(expr, f, false)?;
-                        emit_gc_retry(
-                            f,
-                            |f| {
-                                f.instruction(&Instruction::LocalSet(1)); // f64 needs local1
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::LocalGet(1));
-                                f.instruction(&Instruction::F64Store(MemArg {
-                                    offset: 4,
-                                    align: 3,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::F64Load(MemArg {
-                                    offset: 4,
-                                    align: 3,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::Call(import::DFTOA));
-                            },
-                        );
-                    }
-                    _ => {
-                        return Err(CompilerError::Codegen {
-                            message: format!("Cannot stringify type {:?}", expr.ty),
-                        })
-                    }
-                },
+                UnaryOp::Stringify => {
+                    self.compile_expr(expr, f, false)?;
+                    self.emit_stringify(&expr.ty, f)?;
+                }
             },
             IRExprKind::Call { callee, args } => {
                 let type_index = self.find_type_index(&callee.ty)?;
@@ -430,13 +473,16 @@ impl Codegen {
                 f.instruction(&Instruction::I64Const(32));
                 f.instruction(&Instruction::I64ShrU);
                 f.instruction(&Instruction::I32WrapI64);
-                for arg in args {
+                for (i, arg) in args.iter().enumerate() {
                     self.compile_expr(arg, f, false)?;
-                    emit_storage_cast(f, &arg.ty.kind);
+                    if needs_hold(arg, &args[i + 1..].iter().collect::<Vec<_>>()) {
+                        self.hold_temporary(&arg.ty, f);
+                    }
+                    emit_storage_cast(f, &arg.ty);
                     f.instruction(&Instruction::LocalSet(1));
                     f.instruction(&Instruction::LocalSet(0));
                     f.instruction(&Instruction::LocalGet(1));
-                    emit_access_cast(f, &arg.ty.kind);
+                    emit_access_cast(f, &arg.ty);
                     f.instruction(&Instruction::LocalGet(0));
                 }
 
@@ -445,46 +491,68 @@ impl Codegen {
                     table_index: 0,
                 });
             }
+            IRExprKind::CallDirect { fn_index, args } => {
+                f.instruction(&Instruction::I32Const(0));
+                f.instruction(&Instruction::I64Const(0));
+                f.instruction(&Instruction::I32Const(0));
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_expr(arg, f, false)?;
+                    if needs_hold(arg, &args[i + 1..].iter().collect::<Vec<_>>()) {
+                        self.hold_temporary(&arg.ty, f);
+                    }
+                }
+                f.instruction(&Instruction::Call(self.first_function() + fn_index));
+            }
+            IRExprKind::Builtin { builtin, args } => {
+                self.compile_builtin(*builtin, args, f)?;
+            }
+            IRExprKind::ExternCall { function, args } => {
+                for (i, arg) in args.iter().enumerate() {
+                    self.compile_expr(arg, f, false)?;
+                    if needs_hold(arg, &args[i + 1..].iter().collect::<Vec<_>>()) {
+                        self.hold_temporary(&arg.ty, f);
+                    }
+                }
+                f.instruction(&Instruction::Call(self.extern_index(function)));
+            }
             IRExprKind::New {
                 struct_index,
                 fields,
             } => {
                 if !preallocated {
-                    let idx = *struct_index as i32;
-                    emit_gc_retry(
-                        f,
-                        |f| {
-                            f.instruction(&Instruction::I32Const(0));
-                            f.instruction(&Instruction::I32Const(idx));
-                            f.instruction(&Instruction::I32Store(MemArg {
-                                offset: 4,
-                                align: 2,
-                                memory_index: mem::SHADOW,
-                            }));
-                        },
-                        |f| {
-                            f.instruction(&Instruction::I32Const(0));
-                            f.instruction(&Instruction::I32Load(MemArg {
-                                offset: 4,
-                                align: 2,
-                                memory_index: mem::SHADOW,
-                            }));
-                        },
-                        |f| {
-                            f.instruction(&Instruction::Call(import::FALLOC));
-                        },
-                    );
+                    self.emit_struct_alloc(*struct_index, f);
                 }
                 f.instruction(&Instruction::LocalTee(0));
 
-                for _ in fields {
+                // Evaluating a field may collect, so keep the half-built
+                // struct reachable until it is stored somewhere.
+                let rooted = fields.iter().any(|field| !is_leaf(field));
+                if rooted {
                     f.instruction(&Instruction::LocalGet(0));
+                    f.instruction(&Instruction::I32Const(self.next_temp_slot()));
+                    f.instruction(&Instruction::I32Const(1));
+                    f.instruction(&Instruction::Call(import::SHADOW_SET));
+                }
+
+                for field_expr in fields {
+                    let slots = match &field_expr.node {
+                        IRExprKind::Array(elements) => elements.len(),
+                        _ => 1,
+                    };
+                    for _ in 0..slots {
+                        f.instruction(&Instruction::LocalGet(0));
+                    }
                 }
 
                 let mut offset = 0u64;
                 for field_expr in fields {
+                    if let IRExprKind::Array(elements) = &field_expr.node {
+                        self.compile_inline_array(elements, offset, f)?;
+                        offset += 8 * elements.len() as u64;
+                        continue;
+                    }
                     self.compile_expr(field_expr, f, false)?;
-                    emit_storage_cast(f, &field_expr.ty.kind);
+                    emit_storage_cast(f, &field_expr.ty);
                     f.instruction(&Instruction::I64Store(MemArg {
                         offset,
                         align: 3,
@@ -500,7 +568,7 @@ impl Codegen {
                     align: 3,
                     memory_index: mem::ALLOC,
                 }));
-                emit_access_cast(f, &expr.ty.kind);
+                emit_access_cast(f, &expr.ty);
             }
             IRExprKind::FieldReference { object, offset } => {
                 self.compile_expr(object, f, false)?;
@@ -508,18 +576,14 @@ impl Codegen {
                 f.instruction(&Instruction::I32Add);
             }
             IRExprKind::IndexReference { list, index } => {
-                self.compile_expr(list, f, false)?;
-
-                self.compile_expr(index, f, false)?;
-                f.instruction(&Instruction::I64Const(8));
-                f.instruction(&Instruction::I64Mul);
-                f.instruction(&Instruction::I32WrapI64);
-
-                f.instruction(&Instruction::I32Add);
+                self.compile_element_address(list, index, &[], f)?;
             }
 
             IRExprKind::Slice { expr, start, end } => {
                 self.compile_expr(expr, f, false)?;
+                if needs_hold(expr, &[start, end]) {
+                    self.hold_temporary(&expr.ty, f);
+                }
                 self.compile_expr(start, f, false)?;
                 f.instruction(&Instruction::I32WrapI64);
                 self.compile_expr(end, f, false)?;
@@ -580,12 +644,38 @@ impl Codegen {
             }
 
             IRExprKind::List(elements) => {
+                if let Some(bytes) = constant_list_bytes(elements) {
+                    // Copy the pinned template in one go rather than storing each element.
+                    let global = self.data_segment_indices[&DataSegment {
+                        dtype: dtype::PRIMITIVE,
+                        bytes,
+                    }];
+                    let len = elements.len() as i32;
+                    emit_gc_retry(
+                        f,
+                        |_| {},
+                        |f| {
+                            f.instruction(&Instruction::GlobalGet(global));
+                            f.instruction(&Instruction::I32Const(0));
+                            f.instruction(&Instruction::I32Const(len));
+                        },
+                        |f| {
+                            f.instruction(&Instruction::Call(import::DSLICE));
+                        },
+                    );
+                    return Ok(());
+                }
                 let len = elements.len() as i32;
+                // The block type tells the collector whether the elements are pointers.
+                let ty = match &expr.ty.kind {
+                    TypeKind::List { element } => list_dtype(element),
+                    _ => dtype::PRIMITIVE,
+                };
                 emit_gc_retry(
                     f,
                     |f| {
                         f.instruction(&Instruction::I32Const(0));
-                        f.instruction(&Instruction::I32Const(1));
+                        f.instruction(&Instruction::I32Const(ty));
                         f.instruction(&Instruction::I32Store(MemArg {
                             offset: 4,
                             align: 2,
@@ -617,34 +707,78 @@ impl Codegen {
                         f.instruction(&Instruction::Call(import::DALLOC));
                     },
                 );
+                let held = if elements.iter().any(|element| !is_leaf(element)) {
+                    self.hold_temporary(&expr.ty, f)
+                } else {
+                    None
+                };
                 f.instruction(&Instruction::LocalTee(0));
-                for (_, _) in elements.iter().enumerate() {
-                    f.instruction(&Instruction::LocalGet(0));
-                }
-                for (i, element) in elements.iter().enumerate() {
-                    self.compile_expr(element, f, false)?;
-                    f.instruction(&Instruction::I64Store(MemArg {
-                        offset: (i * 8) as u64,
-                        align: 3,
-                        memory_index: mem::DALLOC,
-                    }));
+                // The list is pushed once per element it's stored into, a
+                // chunk at a time so long lists don't pile up on the stack.
+                // Elements may clobber local 0, so later chunks read it back
+                // from the slot holding it.
+                for (first, chunk) in (0..).step_by(LIST_CHUNK).zip(elements.chunks(LIST_CHUNK)) {
+                    for _ in chunk {
+                        match held {
+                            Some(slot) if first > 0 => emit_held(f, slot),
+                            _ => {
+                                f.instruction(&Instruction::LocalGet(0));
+                            }
+                        }
+                    }
+                    for (i, element) in chunk.iter().enumerate() {
+                        self.compile_expr(element, f, false)?;
+                        emit_storage_cast(f, &element.ty);
+                        f.instruction(&Instruction::I64Store(MemArg {
+                            offset: ((first + i) * 8) as u64,
+                            align: 3,
+                            memory_index: mem::DALLOC,
+                        }));
+                    }
                 }
             }
             IRExprKind::Index { list, index } => {
-                self.compile_expr(list, f, false)?;
-
-                self.compile_expr(index, f, false)?;
-                f.instruction(&Instruction::I64Const(8));
-                f.instruction(&Instruction::I64Mul);
-                f.instruction(&Instruction::I32WrapI64);
-
-                f.instruction(&Instruction::I32Add);
-
+                self.compile_element_address(list, index, &[], f)?;
                 f.instruction(&Instruction::I64Load(MemArg {
                     offset: 0,
                     align: 3,
                     memory_index: mem::DALLOC,
                 }));
+                emit_access_cast(f, &expr.ty);
+            }
+            IRExprKind::Array(_) => {
+                return Err(CompilerError::Codegen {
+                    message: "Array literals can only initialize struct fields".to_string(),
+                })
+            }
+            IRExprKind::ArrayIndex {
+                array,
+                index,
+                length,
+            } => {
+                self.compile_expr(array, f, false)?;
+                self.compile_expr(index, f, false)?;
+                emit_array_address(f, *length, !self.unchecked);
+                f.instruction(&Instruction::I64Load(MemArg {
+                    offset: 0,
+                    align: 3,
+                    memory_index: mem::ALLOC,
+                }));
+                emit_access_cast(f, &expr.ty);
+            }
+            IRExprKind::ArrayIndexReference {
+                array,
+                index,
+                length,
+            } => {
+                self.compile_expr(array, f, false)?;
+                self.compile_expr(index, f, false)?;
+                emit_array_address(f, *length, !self.unchecked);
+            }
+            IRExprKind::SliceReference { .. } => {
+                return Err(CompilerError::Codegen {
+                    message: "Slice references can only be assigned to".to_string(),
+                })
             }
             IRExprKind::Match { .. } => todo!(),
             IRExprKind::UnwrapError(inside) => {
@@ -658,4 +792,247 @@ impl Codegen {
         }
         Ok(())
     }
+
+    /// Leaves the address of element `index` of `list`, trapping when the
+    /// index is outside the list unless unchecked. The list stays rooted
+    /// while the index and then the `later` operands are evaluated, so the
+    /// address is still good once they are.
+    fn compile_element_address(
+        &mut self,
+        list: &IRExpr,
+        index: &IRExpr,
+        later: &[&IRExpr],
+        f: &mut Function,
+    ) -> Result<(), CompilerError> {
+        self.compile_expr(list, f, false)?;
+        if needs_hold(list, &[index]) || needs_hold(list, later) {
+            self.hold_temporary(&list.ty, f);
+        }
+
+        self.compile_expr(index, f, false)?;
+        if !self.unchecked {
+            // The length sits in the word before the elements. A negative
+            // index is a huge one unsigned, so one comparison covers both.
+            f.instruction(&Instruction::LocalSet(1));
+            f.instruction(&Instruction::LocalTee(0));
+            f.instruction(&Instruction::LocalGet(0));
+            f.instruction(&Instruction::I32Const(BLOCK_LENGTH));
+            f.instruction(&Instruction::I32Sub);
+            f.instruction(&Instruction::I32Load(MemArg {
+                offset: 0,
+                align: 2,
+                memory_index: mem::DALLOC,
+            }));
+            f.instruction(&Instruction::I64ExtendI32U);
+            f.instruction(&Instruction::LocalGet(1));
+            f.instruction(&Instruction::I64LeU);
+            f.instruction(&Instruction::If(BlockType::Empty));
+            f.instruction(&Instruction::Unreachable);
+            f.instruction(&Instruction::End);
+            f.instruction(&Instruction::LocalGet(1));
+        }
+        f.instruction(&Instruction::I64Const(8));
+        f.instruction(&Instruction::I64Mul);
+        f.instruction(&Instruction::I32WrapI64);
+
+        f.instruction(&Instruction::I32Add);
+        Ok(())
+    }
+
+    /// Roots the value of type `ty` on top of the stack in a temp slot.
+    /// Nothing else refers to a fresh pointer, so a collection while later
+    /// operands are evaluated would free it or, when compacting, move it.
+    /// Returns the slot, unless the function has no frame to hold it in.
+    pub(super) fn hold_temporary(&mut self, ty: &Type, f: &mut Function) -> Option<i32> {
+        if self.frameless {
+            return None;
+        }
+        let slot = self.next_temp_slot();
+        // Closures are i64s, with their environment in the low half.
+        let scratch = if is_closure(ty) { 1 } else { 0 };
+        f.instruction(&Instruction::LocalTee(scratch));
+        emit_root(f, ty, slot);
+        f.instruction(&Instruction::LocalGet(scratch));
+        Some(slot)
+    }
+
+    /// Allocates a struct of `struct_index`, collecting and retrying once
+    /// when alloc is out of blocks. The id is saved in the shadow memory
+    /// across the collection.
+    pub(super) fn emit_struct_alloc(&self, struct_index: u32, f: &mut Function) {
+        let Some(id) = self.type_ids[struct_index as usize] else {
+            // Only functions that nothing reachable calls make structs that
+            // aren't registered.
+            f.instruction(&Instruction::Unreachable);
+            return;
+        };
+        emit_gc_retry(
+            f,
+            |f| {
+                f.instruction(&Instruction::I32Const(0));
+                f.instruction(&Instruction::I32Const(id as i32));
+                f.instruction(&Instruction::I32Store(MemArg {
+                    offset: 4,
+                    align: 2,
+                    memory_index: mem::SHADOW,
+                }));
+            },
+            |f| {
+                f.instruction(&Instruction::I32Const(0));
+                f.instruction(&Instruction::I32Load(MemArg {
+                    offset: 4,
+                    align: 2,
+                    memory_index: mem::SHADOW,
+                }));
+            },
+            |f| {
+                f.instruction(&Instruction::Call(import::FALLOC));
+            },
+        );
+    }
+
+    /// The wasm global holding the program's global `index`.
+    pub(super) fn program_global(&self, index: u32) -> u32 {
+        self.data_segments.len() as u32 + index
+    }
+
+    /// Takes the next temp slot, until the enclosing expression is compiled.
+    fn next_temp_slot(&mut self) -> i32 {
+        let slot = self.temp_slot_base + self.temp_slot_depth;
+        self.temp_slot_depth += 1;
+        slot as i32
+    }
+
+    /// Stores array literal elements into consecutive struct slots starting
+    /// at `offset`. Expects one copy of the struct address per element on the
+    /// stack.
+    fn compile_inline_array(
+        &mut self,
+        elements: &[IRExpr],
+        offset: u64,
+        f: &mut Function,
+    ) -> Result<(), CompilerError> {
+        for (i, element) in elements.iter().enumerate() {
+            self.compile_expr(element, f, false)?;
+            emit_storage_cast(f, &element.ty);
+            f.instruction(&Instruction::I64Store(MemArg {
+                offset: offset + (i * 8) as u64,
+                align: 3,
+                memory_index: mem::ALLOC,
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// The memory a value of type `ty` points into, as the shadow stack's
+/// `set` and `set_global` take it, or `None` when it holds no pointer.
+/// Closures are rooted by their environment.
+pub(super) fn pointer_memory(ty: &Type) -> Option<i32> {
+    if ty.kind == TypeKind::Null {
+        return None;
+    }
+    if ty.nullable || ty.errorable {
+        return Some(1);
+    }
+    match ty.kind {
+        TypeKind::Struct { .. } | TypeKind::Function { .. } => Some(1),
+        TypeKind::List { .. } | TypeKind::String => Some(2),
+        _ => None,
+    }
+}
+
+/// Whether a value of type `ty` is a closure, an i64 rather than a pointer.
+fn is_closure(ty: &Type) -> bool {
+    matches!(ty.kind, TypeKind::Function { .. }) && !ty.nullable && !ty.errorable
+}
+
+/// Roots the value of type `ty` on top of the stack in `slot` of the
+/// current frame, taking it off the stack. Values that hold no pointer are
+/// just dropped.
+pub(super) fn emit_root(f: &mut Function, ty: &Type, slot: i32) {
+    let Some(memory) = pointer_memory(ty) else {
+        f.instruction(&Instruction::Drop);
+        return;
+    };
+    if is_closure(ty) {
+        f.instruction(&Instruction::I32WrapI64);
+    }
+    f.instruction(&Instruction::I32Const(slot));
+    f.instruction(&Instruction::I32Const(memory));
+    f.instruction(&Instruction::Call(import::SHADOW_SET));
+}
+
+/// Pushes the pointer `hold_temporary` put in `slot` of the current frame.
+fn emit_held(f: &mut Function, slot: i32) {
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Load(MemArg {
+        offset: SHADOW_FRAME_POINTER,
+        align: 2,
+        memory_index: mem::SHADOW,
+    }));
+    f.instruction(&Instruction::I32Load(MemArg {
+        offset: slot as u64 * 8 + 4,
+        align: 2,
+        memory_index: mem::SHADOW,
+    }));
+}
+
+/// Whether `operand` is a fresh pointer that must be held while the `later`
+/// operands are evaluated, because one of them may collect. Locals and
+/// globals are rooted in their own slots already.
+pub(super) fn needs_hold(operand: &IRExpr, later: &[&IRExpr]) -> bool {
+    pointer_memory(&operand.ty).is_some()
+        && !is_leaf(operand)
+        && later.iter().any(|expr| !is_leaf(expr))
+}
+
+/// How many temp slots compiling `expr` itself may take, not counting its
+/// operands.
+pub(super) fn temp_slots(expr: &IRExpr) -> usize {
+    match &expr.node {
+        IRExprKind::New { fields, .. } => fields.iter().any(|field| !is_leaf(field)) as usize,
+        IRExprKind::List(elements) => elements.iter().any(|element| !is_leaf(element)) as usize,
+        IRExprKind::Binary {
+            left,
+            op: BinaryOp::Is,
+            right,
+        } => match &left.node {
+            IRExprKind::IndexReference { list, index } => {
+                (needs_hold(list, &[index]) || needs_hold(list, &[right])) as usize
+            }
+            IRExprKind::SliceReference { list, start, end } => {
+                needs_hold(list, &[start, end, right]) as usize
+            }
+            _ => 0,
+        },
+        IRExprKind::Binary { left, right, .. } => needs_hold(left, &[right]) as usize,
+        IRExprKind::Index { list, index } | IRExprKind::IndexReference { list, index } => {
+            needs_hold(list, &[index]) as usize
+        }
+        IRExprKind::Slice { expr, start, end } => needs_hold(expr, &[start, end]) as usize,
+        IRExprKind::Builtin { builtin, args } => (0..args.len())
+            .filter(|&i| holds_operand(*builtin, &args[i], &args[i + 1..]))
+            .count(),
+        IRExprKind::Call { args, .. }
+        | IRExprKind::CallDirect { args, .. }
+        | IRExprKind::ExternCall { args, .. } => (0..args.len())
+            .filter(|&i| needs_hold(&args[i], &args[i + 1..].iter().collect::<Vec<_>>()))
+            .count(),
+        _ => 0,
+    }
+}
+
+/// Whether an expression is evaluated without allocating.
+pub(super) fn is_leaf(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::Integer(_)
+            | IRExprKind::Float(_)
+            | IRExprKind::Boolean(_)
+            | IRExprKind::Null
+            | IRExprKind::Local(_)
+            | IRExprKind::Global(_)
+            | IRExprKind::Function(_)
+    )
 }
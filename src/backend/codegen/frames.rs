@@ -0,0 +1,108 @@
+use crate::ast::{IRFunction, IRProgram};
+use crate::error::CompilerError;
+
+use super::expr::temp_slots;
+use super::CodegenOptions;
+
+/// The most locals, params included, engines let a function declare.
+pub const ENGINE_MAX_LOCALS: u32 = 50000;
+
+/// What a function takes of wasm locals and of the shadow stack when
+/// compiled for linear memory.
+#[derive(Debug, Clone)]
+pub struct FrameUsage {
+    pub name: String,
+    /// Its wasm locals: the scratch values, environment, params and locals,
+    /// and the sanitizer's when sanitizing.
+    pub locals: u32,
+    /// The slots of its shadow stack frame: the environment, params and
+    /// locals, and the temp slots expressions hold values in. Functions
+    /// without a frame take none.
+    pub frame_slots: u32,
+}
+
+impl FrameUsage {
+    pub fn new(func: &IRFunction, sanitized: bool) -> Self {
+        let mut temps = 0;
+        for stmt in &func.body {
+            stmt.visit_exprs(&mut |expr| temps += temp_slots(expr));
+        }
+        let declared = (func.params.len() + func.locals.len()) as u32;
+        FrameUsage {
+            name: func.name.clone(),
+            locals: 3 + declared + if sanitized { 3 } else { 0 },
+            frame_slots: if func.noalloc {
+                0
+            } else {
+                1 + declared + temps as u32
+            },
+        }
+    }
+
+    /// Errors naming the function when it needs more locals than `options`
+    /// or the engine allow, or a bigger frame than `options` do.
+    pub fn check(&self, options: &CodegenOptions) -> Result<(), CompilerError> {
+        check_locals(&self.name, self.locals, options)?;
+        match options.max_frame_slots {
+            Some(max) if self.frame_slots > max => Err(CompilerError::Codegen {
+                message: format!(
+                    "Function '{}' needs a shadow stack frame of {} slots, more than the limit of {}",
+                    self.name, self.frame_slots, max
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Errors naming the function `name` when its `locals` are more than
+/// `options` or the engine allow.
+pub fn check_locals(
+    name: &str,
+    locals: u32,
+    options: &CodegenOptions,
+) -> Result<(), CompilerError> {
+    let max = options
+        .max_locals
+        .map_or(ENGINE_MAX_LOCALS, |max| max.min(ENGINE_MAX_LOCALS));
+    if locals > max {
+        return Err(CompilerError::Codegen {
+            message: format!(
+                "Function '{}' needs {} locals, more than the limit of {}",
+                name, locals, max
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// The locals and frame of every function of a program.
+pub struct Frames {
+    pub functions: Vec<FrameUsage>,
+}
+
+impl Frames {
+    pub fn new(program: &IRProgram) -> Self {
+        Frames {
+            functions: program
+                .functions
+                .iter()
+                .map(|func| FrameUsage::new(func, false))
+                .collect(),
+        }
+    }
+
+    /// The usage as text, one function per line, biggest frame first.
+    pub fn report(&self) -> String {
+        let mut functions: Vec<&FrameUsage> = self.functions.iter().collect();
+        functions.sort_by_key(|usage| std::cmp::Reverse(usage.frame_slots));
+        let mut out = String::new();
+        for usage in functions {
+            out.push_str(&format!(
+                "fn {}: {} locals, {} frame slots\n",
+                usage.name, usage.locals, usage.frame_slots
+            ));
+        }
+        out
+    }
+}
@@ -1,7 +1,8 @@
-use crate::ast::{Type, TypeKind};
-use wasm_encoder::{Function, Instruction, MemArg, ValType};
+use crate::ast::{IRExpr, IRExprKind, Type, TypeKind, UnaryOp};
+use wasm_encoder::{Instruction, MemArg, ValType};
 
-use super::constants::{import, mem};
+use super::constants::mem;
+use super::sanitize::Function;
 
 pub fn type_to_valtype(ty: &Type) -> ValType {
     if ty.nullable || ty.errorable {
@@ -11,6 +12,7 @@ pub fn type_to_valtype(ty: &Type) -> ValType {
         TypeKind::String => ValType::I32,
         TypeKind::Function { .. } => ValType::I64,
         TypeKind::List { .. } => ValType::I32,
+        TypeKind::Array { .. } => ValType::I32,
         TypeKind::Struct { .. } => ValType::I32,
         TypeKind::Boolean => ValType::I32,
         TypeKind::Float => ValType::F64,
@@ -18,6 +20,34 @@ pub fn type_to_valtype(ty: &Type) -> ValType {
     }
 }
 
+/// The element bytes of a non-empty list literal made only of primitive
+/// constants, negated numbers included, in dalloc's one-u64-per-element
+/// layout.
+pub fn constant_list_bytes(elements: &[IRExpr]) -> Option<Vec<u8>> {
+    if elements.is_empty() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(elements.len() * 8);
+    for element in elements {
+        let value = match &element.node {
+            IRExprKind::Integer(n) => *n as u64,
+            IRExprKind::Float(n) => n.to_bits(),
+            IRExprKind::Boolean(b) => *b as u64,
+            IRExprKind::Unary {
+                op: UnaryOp::Minus,
+                expr,
+            } => match expr.node {
+                IRExprKind::Integer(n) => n.wrapping_neg() as u64,
+                IRExprKind::Float(n) => (-n).to_bits(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    Some(bytes)
+}
+
 pub fn emit_gc_retry<P, R, O>(f: &mut Function, prepare: P, retrieve: R, operation: O)
 where
     P: Fn(&mut Function),
@@ -25,6 +55,9 @@ where
     O: Fn(&mut Function),
 {
     prepare(f);
+    if f.stressed() {
+        f.collect();
+    }
 
     retrieve(f);
     operation(f);
@@ -33,7 +66,7 @@ where
     f.instruction(&Instruction::I32Eqz);
     f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
 
-    f.instruction(&Instruction::Call(import::GC));
+    f.collect();
     retrieve(f);
     operation(f);
     f.instruction(&Instruction::LocalSet(0));
@@ -43,10 +76,37 @@ where
     f.instruction(&Instruction::LocalGet(0));
 }
 
+/// Turn `[base, index]` on the stack into the address of a fixed array slot.
+/// When `checked`, traps if the index is outside `0..length`. Uses local 1.
+pub fn emit_array_address(f: &mut Function, length: u32, checked: bool) {
+    if checked {
+        f.instruction(&Instruction::LocalTee(1));
+        f.instruction(&Instruction::I64Const(length as i64));
+        f.instruction(&Instruction::I64GeU);
+        f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+    } else {
+        f.instruction(&Instruction::LocalSet(1));
+    }
+
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Const(8));
+    f.instruction(&Instruction::I64Mul);
+    f.instruction(&Instruction::I32WrapI64);
+    f.instruction(&Instruction::I32Add);
+}
+
 /// Emit instructions to convert a value from i64 storage format to its actual runtime type.
 /// Values are stored as i64 in memory, but need conversion for pointer types and floats.
-pub fn emit_access_cast(f: &mut Function, ty: &TypeKind) {
-    match ty {
+/// Nullable and errorable values are pointers to their tagged union, while a
+/// bare `null` is already an i64 zero.
+pub fn emit_access_cast(f: &mut Function, ty: &Type) {
+    if (ty.nullable || ty.errorable) && ty.kind != TypeKind::Null {
+        f.instruction(&Instruction::I32WrapI64);
+        return;
+    }
+    match &ty.kind {
         TypeKind::Struct { .. }
         | TypeKind::List { .. }
         | TypeKind::String
@@ -64,8 +124,12 @@ pub fn emit_access_cast(f: &mut Function, ty: &TypeKind) {
 
 /// Emit instructions to convert a value from its runtime type to i64 storage format.
 /// Inverse of emit_access_cast.
-pub fn emit_storage_cast(f: &mut Function, ty: &TypeKind) {
-    match ty {
+pub fn emit_storage_cast(f: &mut Function, ty: &Type) {
+    if (ty.nullable || ty.errorable) && ty.kind != TypeKind::Null {
+        f.instruction(&Instruction::I64ExtendI32U);
+        return;
+    }
+    match &ty.kind {
         TypeKind::Struct { .. }
         | TypeKind::List { .. }
         | TypeKind::String
@@ -110,7 +174,7 @@ pub fn emit_unwrap(f: &mut Function, tag: i64, result_ty: &Type) {
             align: 3,
             memory_index: mem::ALLOC,
         }));
-        emit_access_cast(f, &result_ty.kind);
+        emit_access_cast(f, result_ty);
     }
 
     f.instruction(&Instruction::End);
@@ -1,33 +1,445 @@
+mod builtins;
 mod constants;
 mod expr;
+mod frames;
 mod helpers;
+mod sanitize;
 mod stmt;
+mod stringify;
+mod suspend;
 
-use crate::ast::{IRFunction, IRProgram, Type, TypeKind};
+use crate::ast::{
+    Extern, IRExprKind, IRFunction, IRProgram, IRStructKind, Type, TypeKind, UnaryOp,
+};
 use crate::error::CompilerError;
+use super::names::Names;
+use std::collections::{HashMap, HashSet};
 use wasm_encoder::{
-    CodeSection, ConstExpr, ElementSection, Elements, EntityType, ExportSection, FunctionSection,
-    ImportSection, Module, RefType, TableSection, TableType, TypeSection, ValType,
+    CodeSection, ConstExpr, DataCountSection, DataSection, ElementSection, Elements, EntityType,
+    ExportKind, ExportSection, FunctionSection, GlobalSection, GlobalType, ImportSection,
+    MemorySection, MemoryType, Module, RefType, TableSection, TableType, TypeSection, ValType,
 };
 
-use constants::{FUNCTION_IMPORTS, IMPORT_COUNT, MEMORY_IMPORTS};
-use helpers::type_to_valtype;
+use constants::{dtype, FUNCTION_IMPORTS, IMPORT_COUNT, MEMORY_IMPORTS};
+pub use frames::{check_locals, FrameUsage, Frames};
+use helpers::{constant_list_bytes, type_to_valtype};
+use sanitize::Sanitizer;
+use stmt::{compile_collector, compile_export};
+use suspend::{global, SavedFrame};
+
+/// The bytes of a constant literal, together with the dalloc block type they
+/// are copied into.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct DataSegment {
+    dtype: i32,
+    bytes: Vec<u8>,
+}
+
+impl DataSegment {
+    /// Number of elements in the block, as passed to `dalloc`.
+    fn length(&self) -> i32 {
+        if self.dtype == dtype::BYTES {
+            self.bytes.len() as i32
+        } else {
+            (self.bytes.len() / 8) as i32
+        }
+    }
+}
+
+/// An `extern fn` the program calls, imported after the runtime's functions.
+struct ExternImport {
+    function: Extern,
+    params: Vec<ValType>,
+    result: ValType,
+}
+
+/// The module's function types, each distinct signature listed once.
+#[derive(Default)]
+struct FunctionTypes {
+    signatures: Vec<(Vec<ValType>, Vec<ValType>)>,
+    indices: HashMap<(Vec<ValType>, Vec<ValType>), u32>,
+}
+
+impl FunctionTypes {
+    /// The index of the type taking `params` and returning `results`, added
+    /// if no earlier function has it.
+    fn add(&mut self, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+        let next = self.signatures.len() as u32;
+        *self
+            .indices
+            .entry((params.clone(), results.clone()))
+            .or_insert_with(|| {
+                self.signatures.push((params, results));
+                next
+            })
+    }
+
+    fn get(&self, params: Vec<ValType>, results: Vec<ValType>) -> Option<u32> {
+        self.indices.get(&(params, results)).copied()
+    }
+
+    fn section(&self) -> TypeSection {
+        let mut types = TypeSection::new();
+        for (params, results) in &self.signatures {
+            types.ty().function(params.clone(), results.clone());
+        }
+        types
+    }
+}
+
+/// The type of each function the module imports or defines.
+struct TypeIndices {
+    imports: Vec<u32>,
+    externs: Vec<u32>,
+    /// The type of the sanitizer's checks, when sanitizing.
+    check: Option<u32>,
+    /// The program's functions, then the wrappers of exported ones and the
+    /// collector, if any.
+    functions: Vec<u32>,
+}
+
+/// The params every program function takes before its own: the scratch
+/// locals and the closure environment.
+const CALLING_CONVENTION: [ValType; 3] = [ValType::I32, ValType::I64, ValType::I32];
+
+/// The params and results of the functions a value of `ty` holds, as
+/// `call_indirect` calls them.
+fn indirect_signature(ty: &Type) -> Option<(Vec<ValType>, Vec<ValType>)> {
+    let TypeKind::Function { params, returns } = &ty.kind else {
+        return None;
+    };
+    let mut valtypes = CALLING_CONVENTION.to_vec();
+    valtypes.extend(params.iter().map(type_to_valtype));
+    Some((valtypes, vec![type_to_valtype(returns)]))
+}
+
+/// Build options that change the code generated, but not what it does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CodegenOptions {
+    /// Checks every load and store in the alloc and dalloc heaps against
+    /// their live blocks, trapping on any that falls outside one.
+    pub sanitize_memory: bool,
+    /// Targets the WebAssembly GC proposal: structs, lists and strings are
+    /// GC references, and the module runs without the runtime modules.
+    pub wasm_gc: bool,
+    /// The most locals a function may need, below the engines' own limit,
+    /// failing the build when one needs more.
+    pub max_locals: Option<u32>,
+    /// The most slots a function's shadow stack frame may take, failing
+    /// the build when one takes more.
+    pub max_frame_slots: Option<u32>,
+    /// How dalloc picks among the free blocks that fit a list or string.
+    pub alloc_policy: AllocPolicy,
+    /// Collects before every allocation rather than only when the heap is
+    /// full, so a value the collector can't see is freed or moved at once
+    /// instead of whenever a collection happens to come.
+    pub gc_stress: bool,
+}
+
+/// How dalloc picks among the free blocks that fit a request, trading how
+/// fast it allocates against how much it fragments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// The first block on the free list for the request's size.
+    #[default]
+    FirstFit,
+    /// The smallest block that fits.
+    BestFit,
+    /// The first block that fits after the last one handed out, walking
+    /// the heap.
+    NextFit,
+}
+
+impl AllocPolicy {
+    /// The number dalloc's `dpolicy` takes for this policy.
+    pub fn code(self) -> u32 {
+        match self {
+            AllocPolicy::FirstFit => abi::policy::FIRST_FIT,
+            AllocPolicy::BestFit => abi::policy::BEST_FIT,
+            AllocPolicy::NextFit => abi::policy::NEXT_FIT,
+        }
+    }
+}
+
+/// The runtime's checks a sanitized program imports after its externs.
+const SANITIZER_IMPORTS: [(&str, &str); 2] = [(abi::ALLOC, "fcheck"), (abi::DALLOC, "dcheck")];
 
 pub struct Codegen {
+    options: CodegenOptions,
     functions: Vec<IRFunction>,
+    externs: Vec<ExternImport>,
+    /// Contents of constant list and string literals. Each becomes a passive
+    /// data segment copied into a pinned dalloc block, whose address is kept
+    /// in the global with the same index. Lists are copied at startup and
+    /// strings at their first use.
+    data_segments: Vec<DataSegment>,
+    data_segment_indices: HashMap<DataSegment, u32>,
+    /// The types of the program's top-level variables. Each is a wasm
+    /// global after the data segments' ones, and main roots what they point
+    /// at in a shadow stack frame of their own.
+    globals: Vec<Type>,
+    /// First shadow slot after the current function's locals. Structs whose
+    /// fields are still being evaluated are rooted from here upwards, one
+    /// slot per level of nesting.
+    temp_slot_base: u32,
+    temp_slot_depth: u32,
+    /// Set while compiling the body of an `unchecked` block.
+    unchecked: bool,
+    /// The id alloc knows each struct by, for the structs that reachable
+    /// code instantiates. Only those are registered, so the type table and
+    /// the heap after it don't grow with unused declarations.
+    type_ids: Vec<Option<u32>>,
+    /// Type id and name of every error struct, for stringifying errors.
+    error_types: Vec<(u32, String)>,
+    /// Blocks, loops and ifs entered in the current function, and the depth
+    /// inside each enclosing loop, so `break` and `continue` can count out
+    /// to it.
+    block_depth: u32,
+    loop_depths: Vec<u32>,
+    /// Set while compiling a resumable function.
+    saved_frame: Option<SavedFrame>,
+    /// Set while compiling a `@noalloc` function. Nothing can collect while
+    /// it runs, so it pushes no shadow stack frame and roots nothing.
+    frameless: bool,
+    /// The params and locals of the function being compiled that are never
+    /// rooted in its frame.
+    unrooted: HashSet<u32>,
+    /// The checks imported when sanitizing memory.
+    sanitizer: Option<Sanitizer>,
+    /// The type id of each registered struct with a drop method, and the
+    /// position of the method.
+    drops: Vec<(u32, u32)>,
+    /// The function collections go through when there are drop methods to
+    /// run after them.
+    collector: Option<u32>,
+    types: FunctionTypes,
+}
+
+/// Whether `func` is a `main` that takes the command-line arguments. It is
+/// still exported with the plain `main` signature, and builds the list of
+/// arguments in its prologue instead, so hosts call every `main` alike.
+fn takes_args(func: &IRFunction) -> bool {
+    func.name == "main" && !func.params.is_empty()
+}
+
+/// The functions marked `export` and their positions, each of which gets a
+/// wrapper exported under its name that takes just its Star parameters.
+fn exported_functions(program: &IRProgram) -> Result<Vec<(u32, &IRFunction)>, CompilerError> {
+    let reserved = [
+        crate::host::TABLE_EXPORT,
+        crate::host::ASYNC_STATE_EXPORT,
+        crate::host::ASYNC_REQUEST_EXPORT,
+        crate::host::ASYNC_ARGUMENT_EXPORT,
+        crate::host::ASYNC_RESULT_EXPORT,
+    ];
+    let mut exported = vec![];
+    for (index, func) in program.functions.iter().enumerate() {
+        if !func.exported {
+            continue;
+        }
+        let error = |problem: &str| CompilerError::Codegen {
+            message: format!("Exported function '{}' {}", func.name, problem),
+        };
+        if reserved.contains(&func.name.as_str()) {
+            return Err(error("has the name of an export every program may have"));
+        }
+        if func.resumable {
+            return Err(error("cannot await or exit, since hosts call it directly"));
+        }
+        exported.push((index as u32, func));
+    }
+    Ok(exported)
+}
+
+/// The type id of each registered struct with a drop method, and the
+/// position of the method. The collector calls these directly, with no
+/// environment, right after `gc` returns.
+fn drop_methods(
+    program: &IRProgram,
+    type_ids: &[Option<u32>],
+) -> Result<Vec<(u32, u32)>, CompilerError> {
+    let mut drops = vec![];
+    for (ir_struct, id) in program.structs.iter().zip(type_ids) {
+        let (Some(id), Some(drop)) = (id, ir_struct.drop) else {
+            continue;
+        };
+        if program.functions[drop as usize].resumable {
+            return Err(CompilerError::Codegen {
+                message: format!(
+                    "The drop method of '{}' cannot await or exit, since it runs after a collection",
+                    ir_struct.name
+                ),
+            });
+        }
+        drops.push((*id, drop));
+    }
+    Ok(drops)
+}
+
+/// Numbers the structs the program instantiates, in declaration order.
+/// Dead code elimination has already dropped the functions nothing calls,
+/// so the structs only they make go unregistered. The tagged union is
+/// always type 0, which the collector recognizes boxes by.
+fn assign_type_ids(program: &IRProgram) -> Vec<Option<u32>> {
+    let mut instantiated = HashSet::from([0]);
+    for stmt in program.functions.iter().flat_map(|func| &func.body) {
+        stmt.visit_exprs(&mut |expr| {
+            if let IRExprKind::New { struct_index, .. } = &expr.node {
+                instantiated.insert(*struct_index);
+            }
+        });
+    }
+
+    let mut next = 0;
+    (0..program.structs.len() as u32)
+        .map(|index| {
+            instantiated.contains(&index).then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect()
 }
 
 impl Codegen {
     pub fn new() -> Self {
-        Codegen { functions: vec![] }
+        Codegen::with_options(CodegenOptions::default())
     }
 
+    pub fn with_options(options: CodegenOptions) -> Self {
+        Codegen {
+            options,
+            functions: vec![],
+            externs: vec![],
+            data_segments: vec![],
+            data_segment_indices: HashMap::new(),
+            globals: vec![],
+            temp_slot_base: 0,
+            temp_slot_depth: 0,
+            unchecked: false,
+            type_ids: vec![],
+            error_types: vec![],
+            block_depth: 0,
+            loop_depths: vec![],
+            saved_frame: None,
+            frameless: false,
+            unrooted: HashSet::new(),
+            sanitizer: None,
+            drops: vec![],
+            collector: None,
+            types: FunctionTypes::default(),
+        }
+    }
+
+    /// Assigns a data segment to every distinct constant list and string
+    /// literal, so `main` can initialize the lists before any code runs.
+    fn collect_data_segments(&mut self, program: &IRProgram) {
+        let mut found = vec![];
+        let mut wrapped_stringify = false;
+        for func in &program.functions {
+            for stmt in &func.body {
+                stmt.visit_exprs(&mut |expr| {
+                    let segment = match &expr.node {
+                        IRExprKind::List(elements) => {
+                            constant_list_bytes(elements).map(|bytes| DataSegment {
+                                dtype: dtype::PRIMITIVE,
+                                bytes,
+                            })
+                        }
+                        IRExprKind::String(s) => Some(DataSegment {
+                            dtype: dtype::BYTES,
+                            bytes: s.as_bytes().to_vec(),
+                        }),
+                        IRExprKind::Unary {
+                            op: UnaryOp::Stringify,
+                            expr,
+                        } if expr.ty.nullable || expr.ty.errorable => {
+                            wrapped_stringify = true;
+                            None
+                        }
+                        _ => None,
+                    };
+                    found.extend(segment);
+                });
+            }
+        }
+
+        if wrapped_stringify {
+            // The strings that stringify produces for missing values.
+            let names = std::iter::once("null".to_string()).chain(
+                self.error_types
+                    .iter()
+                    .map(|(_, name)| stringify::error_string(name)),
+            );
+            found.extend(names.map(|name| DataSegment {
+                dtype: dtype::BYTES,
+                bytes: name.into_bytes(),
+            }));
+        }
+
+        for segment in found {
+            if !self.data_segment_indices.contains_key(&segment) {
+                let index = self.data_segments.len() as u32;
+                self.data_segment_indices.insert(segment.clone(), index);
+                self.data_segments.push(segment);
+            }
+        }
+    }
+
+    /// Imports every `extern fn` the program calls, in the order of their
+    /// first calls.
+    fn collect_externs(&mut self, program: &IRProgram) -> Result<(), CompilerError> {
+        for func in &program.functions {
+            for stmt in &func.body {
+                stmt.visit_exprs(&mut |expr| {
+                    if let IRExprKind::ExternCall { function, args } = &expr.node {
+                        if !self.externs.iter().any(|e| e.function == *function) {
+                            self.externs.push(ExternImport {
+                                function: function.clone(),
+                                params: args.iter().map(|arg| type_to_valtype(&arg.ty)).collect(),
+                                result: type_to_valtype(&expr.ty),
+                            });
+                        }
+                    }
+                });
+            }
+        }
+        for import in &self.externs {
+            let Extern { module, name } = &import.function;
+            if FUNCTION_IMPORTS
+                .iter()
+                .any(|def| def.module == module && def.name == name)
+            {
+                return Err(CompilerError::Codegen {
+                    message: format!(
+                        "Extern function '{}' has the name of the runtime's own {}.{} import",
+                        name, module, name
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The function index of the `extern fn` a call names.
+    fn extern_index(&self, function: &Extern) -> u32 {
+        let position = self.externs.iter().position(|e| e.function == *function);
+        IMPORT_COUNT + position.expect("externs are collected before codegen") as u32
+    }
+
+    /// The function index of the program's first function, `main`, after
+    /// the runtime's imports, the program's externs and any sanitizer checks.
+    fn first_function(&self) -> u32 {
+        let checks = self.sanitizer.map_or(0, |_| SANITIZER_IMPORTS.len());
+        IMPORT_COUNT + (self.externs.len() + checks) as u32
+    }
+
+    /// The type `call_indirect` checks a call to a function value against,
+    /// shared with every function of that signature.
     fn find_type_index(&self, callee_ty: &Type) -> Result<u32, CompilerError> {
-        if let TypeKind::Function { params, returns } = &callee_ty.kind {
-            for (i, func) in self.functions.iter().enumerate() {
-                if func.params == *params && func.returns == **returns {
-                    return Ok(IMPORT_COUNT + i as u32);
-                }
+        if let Some((params, results)) = indirect_signature(callee_ty) {
+            if let Some(index) = self.types.get(params, results) {
+                return Ok(index);
             }
         }
         Err(CompilerError::Codegen {
@@ -35,35 +447,84 @@ impl Codegen {
         })
     }
 
-    /// Build the type section from declarative imports + program functions
-    fn build_type_section(&self, program: &IRProgram) -> TypeSection {
-        let mut types = TypeSection::new();
+    /// Collects the type of every function from declarative imports, the
+    /// program's externs, the sanitizer's checks, the program's functions
+    /// and the wrappers of exported functions, listing each signature once.
+    /// The signatures of function values called through `call_indirect` are
+    /// listed too, whether or not a function has them.
+    fn collect_types(
+        &mut self,
+        program: &IRProgram,
+        exported: &[(u32, &IRFunction)],
+    ) -> TypeIndices {
+        let types = &mut self.types;
+        let imports = FUNCTION_IMPORTS
+            .iter()
+            .map(|def| types.add(def.params.to_vec(), def.results.to_vec()))
+            .collect();
+        let externs = self
+            .externs
+            .iter()
+            .map(|import| types.add(import.params.clone(), vec![import.result]))
+            .collect();
+        // Every check takes a pointer, an offset and a size, and returns
+        // the pointer.
+        let check = self
+            .sanitizer
+            .map(|_| types.add(vec![ValType::I32; 3], vec![ValType::I32]));
 
-        // Add types for imported functions
-        for def in FUNCTION_IMPORTS {
-            types
-                .ty()
-                .function(def.params.to_vec(), def.results.to_vec());
+        let mut functions = vec![];
+        for func in &program.functions {
+            let mut params = CALLING_CONVENTION.to_vec();
+            if !takes_args(func) {
+                params.extend(func.params.iter().map(type_to_valtype));
+            }
+            functions.push(types.add(params, vec![type_to_valtype(&func.returns)]));
+        }
+        for (_, func) in exported {
+            let params = func.params.iter().map(type_to_valtype).collect();
+            functions.push(types.add(params, vec![type_to_valtype(&func.returns)]));
+        }
+        if self.collector.is_some() {
+            functions.push(types.add(vec![], vec![]));
         }
-
-        // Add types for program functions
         for func in &program.functions {
-            let mut params: Vec<ValType> = vec![ValType::I32, ValType::I64, ValType::I32];
-            params.extend(func.params.iter().map(type_to_valtype));
-            let results: Vec<ValType> = vec![type_to_valtype(&func.returns)];
-            types.ty().function(params, results);
+            for stmt in &func.body {
+                stmt.visit_exprs(&mut |expr| {
+                    if let IRExprKind::Call { callee, .. } = &expr.node {
+                        if let Some((params, results)) = indirect_signature(&callee.ty) {
+                            types.add(params, results);
+                        }
+                    }
+                });
+            }
         }
 
-        types
+        TypeIndices {
+            imports,
+            externs,
+            check,
+            functions,
+        }
     }
 
-    /// Build the import section from declarative imports
-    fn build_import_section(&self) -> ImportSection {
+    /// Build the import section from declarative imports + the program's
+    /// externs and the sanitizer's checks
+    fn build_import_section(&self, types: &TypeIndices) -> ImportSection {
         let mut imports = ImportSection::new();
 
         // Add function imports
-        for (i, def) in FUNCTION_IMPORTS.iter().enumerate() {
-            imports.import(def.module, def.name, EntityType::Function(i as u32));
+        for (def, ty) in FUNCTION_IMPORTS.iter().zip(&types.imports) {
+            imports.import(def.module, def.name, EntityType::Function(*ty));
+        }
+        for (import, ty) in self.externs.iter().zip(&types.externs) {
+            let Extern { module, name } = &import.function;
+            imports.import(module, name, EntityType::Function(*ty));
+        }
+        if let Some(check_type) = types.check {
+            for (module, name) in SANITIZER_IMPORTS {
+                imports.import(module, name, EntityType::Function(check_type));
+            }
         }
 
         // Add memory imports
@@ -86,14 +547,37 @@ impl Codegen {
 
     pub fn compile(&mut self, program: &IRProgram) -> Result<Vec<u8>, CompilerError> {
         self.functions = program.functions.clone();
+        self.type_ids = assign_type_ids(program);
+        self.error_types = program
+            .structs
+            .iter()
+            .zip(&self.type_ids)
+            .filter(|(s, _)| matches!(s.kind, IRStructKind::Error))
+            .filter_map(|(s, id)| id.map(|id| (id, s.name.clone())))
+            .collect();
+        self.collect_data_segments(program);
+        self.globals = program.globals.iter().map(|(_, ty)| ty.clone()).collect();
+        self.collect_externs(program)?;
+        let checks = IMPORT_COUNT + self.externs.len() as u32;
+        self.sanitizer = self.options.sanitize_memory.then_some(Sanitizer {
+            alloc_check: checks,
+            dalloc_check: checks + 1,
+        });
+        let exported = exported_functions(program)?;
+        // Wrappers come after the program's functions, and the collector
+        // after them.
+        let first_wrapper = self.first_function() + program.functions.len() as u32;
+        self.drops = drop_methods(program, &self.type_ids)?;
+        self.collector = (!self.drops.is_empty()).then_some(first_wrapper + exported.len() as u32);
+        let types = self.collect_types(program, &exported);
         let mut module = Module::new();
 
-        module.section(&self.build_type_section(program));
-        module.section(&self.build_import_section());
+        module.section(&self.types.section());
+        module.section(&self.build_import_section(&types));
 
         let mut functions = FunctionSection::new();
-        for (i, _) in program.functions.iter().enumerate() {
-            functions.function((i as u32 + IMPORT_COUNT) as u32);
+        for ty in &types.functions {
+            functions.function(*ty);
         }
         module.section(&functions);
 
@@ -109,13 +593,77 @@ impl Codegen {
             module.section(&tables);
         }
 
+        let resumable = program.functions.iter().any(|func| func.resumable);
+        if resumable {
+            let mut memories = MemorySection::new();
+            memories.memory(MemoryType {
+                minimum: 1,
+                maximum: None,
+                memory64: false,
+                shared: false,
+                page_size_log2: None,
+            });
+            module.section(&memories);
+        }
+
+        if !self.data_segments.is_empty() || !self.globals.is_empty() || resumable {
+            let mut globals = GlobalSection::new();
+            for _ in &self.data_segments {
+                globals.global(
+                    GlobalType {
+                        val_type: ValType::I32,
+                        mutable: true,
+                        shared: false,
+                    },
+                    &ConstExpr::i32_const(0),
+                );
+            }
+            for ty in &self.globals {
+                let val_type = type_to_valtype(ty);
+                let init = match val_type {
+                    ValType::I64 => ConstExpr::i64_const(0),
+                    ValType::F64 => ConstExpr::f64_const(0.0.into()),
+                    _ => ConstExpr::i32_const(0),
+                };
+                globals.global(
+                    GlobalType {
+                        val_type,
+                        mutable: true,
+                        shared: false,
+                    },
+                    &init,
+                );
+            }
+            if resumable {
+                self.add_async_globals(&mut globals);
+            }
+            module.section(&globals);
+        }
+
         let mut exports = ExportSection::new();
-        exports.export("main", wasm_encoder::ExportKind::Func, IMPORT_COUNT);
+        exports.export("main", wasm_encoder::ExportKind::Func, self.first_function());
+        for (i, (_, func)) in exported.iter().enumerate() {
+            exports.export(&func.name, ExportKind::Func, first_wrapper + i as u32);
+        }
+        if !program.functions.is_empty() {
+            exports.export(crate::host::TABLE_EXPORT, wasm_encoder::ExportKind::Table, 0);
+        }
+        if resumable {
+            let async_exports = [
+                (crate::host::ASYNC_STATE_EXPORT, global::STATE),
+                (crate::host::ASYNC_REQUEST_EXPORT, global::REQUEST),
+                (crate::host::ASYNC_ARGUMENT_EXPORT, global::ARGUMENT),
+                (crate::host::ASYNC_RESULT_EXPORT, global::RESULT),
+            ];
+            for (name, global) in async_exports {
+                exports.export(name, ExportKind::Global, self.async_global(global));
+            }
+        }
         module.section(&exports);
 
         if !program.functions.is_empty() {
             let func_indices: Vec<u32> =
-                (IMPORT_COUNT..(IMPORT_COUNT + program.functions.len() as u32)).collect();
+                (self.first_function()..first_wrapper).collect();
             let mut elements = ElementSection::new();
             elements.active(
                 Some(0),
@@ -125,14 +673,70 @@ impl Codegen {
             module.section(&elements);
         }
 
+        if !self.data_segments.is_empty() {
+            module.section(&DataCountSection {
+                count: self.data_segments.len() as u32,
+            });
+        }
+
         let mut codes = CodeSection::new();
 
         for func in &program.functions {
             self.compile_function(func, &mut codes, program)?;
         }
+        for (position, func) in &exported {
+            compile_export(self.first_function() + position, func, &mut codes);
+        }
+        if self.collector.is_some() {
+            compile_collector(self.first_function(), &self.drops, &mut codes);
+        }
 
         module.section(&codes);
 
+        if !self.data_segments.is_empty() {
+            let mut data = DataSection::new();
+            for segment in &self.data_segments {
+                data.passive(segment.bytes.iter().copied());
+            }
+            module.section(&data);
+        }
+
+        module.section(&self.names(program, &exported).section());
+
         Ok(module.finish())
     }
+
+    /// Names the imports after what they import, the program's functions
+    /// and their locals after the source, and the function types after
+    /// their signatures.
+    fn names(&self, program: &IRProgram, exported: &[(u32, &IRFunction)]) -> Names {
+        let mut names = Names::default();
+        let imports = FUNCTION_IMPORTS
+            .iter()
+            .map(|def| (def.module, def.name))
+            .chain(
+                self.externs
+                    .iter()
+                    .map(|import| (import.function.module.as_str(), import.function.name.as_str())),
+            )
+            .chain(self.sanitizer.iter().flat_map(|_| SANITIZER_IMPORTS));
+        for (index, (module, name)) in imports.enumerate() {
+            names.function(index as u32, &format!("{}.{}", module, name));
+        }
+        let first = self.first_function();
+        for (index, func) in program.functions.iter().enumerate() {
+            names.program_function(first + index as u32, func);
+        }
+        let first_wrapper = first + program.functions.len() as u32;
+        for (i, (_, func)) in exported.iter().enumerate() {
+            names.function(first_wrapper + i as u32, &format!("export {}", func.name));
+        }
+        if let Some(collector) = self.collector {
+            names.function(collector, "collect");
+        }
+        for (index, (params, results)) in self.types.signatures.iter().enumerate() {
+            names.signature(index as u32, params, results);
+        }
+        names
+    }
 }
@@ -0,0 +1,157 @@
+use wasm_encoder::{Instruction, MemArg, ValType};
+
+use super::constants::{import, mem};
+
+/// The runtime functions that check accesses to each heap, as imported by a
+/// program built with `--sanitize=memory`.
+#[derive(Clone, Copy)]
+pub struct Sanitizer {
+    pub alloc_check: u32,
+    pub dalloc_check: u32,
+}
+
+/// Where a sanitized function keeps a value being stored while its address
+/// is checked.
+#[derive(Clone, Copy)]
+struct Checks {
+    sanitizer: Sanitizer,
+    i32_local: u32,
+    i64_local: u32,
+    f64_local: u32,
+}
+
+/// A function body under construction. When sanitizing, every load and store
+/// in the alloc and dalloc heaps first passes its address through the
+/// runtime's check for that heap, which traps unless the access lies inside
+/// a live block.
+pub struct Function {
+    body: wasm_encoder::Function,
+    checks: Option<Checks>,
+    /// What a failed allocation calls before retrying: the runtime's `gc`,
+    /// or the program's collector when it has drop methods to run.
+    collector: u32,
+    /// Whether allocations collect before their first try too, as they do
+    /// under `--gc-stress`.
+    stress: bool,
+}
+
+impl Function {
+    pub fn new(locals: Vec<(u32, ValType)>) -> Self {
+        Function::sanitized(locals, 0, None)
+    }
+
+    /// A body whose heap accesses `sanitizer` checks, if given. The first of
+    /// `locals` has index `first_local`, after the parameters.
+    pub fn sanitized(
+        mut locals: Vec<(u32, ValType)>,
+        first_local: u32,
+        sanitizer: Option<Sanitizer>,
+    ) -> Self {
+        let checks = sanitizer.map(|sanitizer| {
+            let next = first_local + locals.iter().map(|(count, _)| count).sum::<u32>();
+            locals.extend([(1, ValType::I32), (1, ValType::I64), (1, ValType::F64)]);
+            Checks {
+                sanitizer,
+                i32_local: next,
+                i64_local: next + 1,
+                f64_local: next + 2,
+            }
+        });
+        Function {
+            body: wasm_encoder::Function::new(locals),
+            checks,
+            collector: import::GC,
+            stress: false,
+        }
+    }
+
+    /// Has collections call `collector` instead of the runtime's `gc`.
+    pub fn collect_through(&mut self, collector: u32) {
+        self.collector = collector;
+    }
+
+    /// Has every allocation collect before trying, not only once it fails.
+    pub fn stress_collections(&mut self) {
+        self.stress = true;
+    }
+
+    pub fn stressed(&self) -> bool {
+        self.stress
+    }
+
+    /// Collects, then runs whatever it found to drop.
+    pub fn collect(&mut self) -> &mut Self {
+        self.instruction(&Instruction::Call(self.collector))
+    }
+
+    pub fn instruction(&mut self, instruction: &Instruction) -> &mut Self {
+        if let Some(checks) = self.checks {
+            if let Some((arg, size, stored)) = access(instruction) {
+                emit_check(&mut self.body, checks, arg, size, stored);
+            }
+        }
+        self.body.instruction(instruction);
+        self
+    }
+
+    pub fn body(&self) -> &wasm_encoder::Function {
+        &self.body
+    }
+}
+
+/// Checks the address under the value being stored, or on top of the stack
+/// for a load, leaving the stack as it was.
+fn emit_check(
+    f: &mut wasm_encoder::Function,
+    checks: Checks,
+    arg: &MemArg,
+    size: i32,
+    stored: Option<ValType>,
+) {
+    let check = match arg.memory_index {
+        mem::ALLOC => checks.sanitizer.alloc_check,
+        mem::DALLOC => checks.sanitizer.dalloc_check,
+        // The shadow stack and saved locals are the runtime's and the
+        // module's own, never a program's values.
+        _ => return,
+    };
+    let local = stored.map(|ty| match ty {
+        ValType::I64 => checks.i64_local,
+        ValType::F64 => checks.f64_local,
+        _ => checks.i32_local,
+    });
+    if let Some(local) = local {
+        f.instruction(&Instruction::LocalSet(local));
+    }
+    f.instruction(&Instruction::I32Const(arg.offset as i32));
+    f.instruction(&Instruction::I32Const(size));
+    f.instruction(&Instruction::Call(check));
+    if let Some(local) = local {
+        f.instruction(&Instruction::LocalGet(local));
+    }
+}
+
+/// The memory an instruction reads or writes, how many bytes, and the type
+/// of the value it stores, if it is a store.
+fn access<'a>(instruction: &'a Instruction) -> Option<(&'a MemArg, i32, Option<ValType>)> {
+    let access = match instruction {
+        Instruction::I32Load8S(arg) | Instruction::I32Load8U(arg) => (arg, 1, None),
+        Instruction::I64Load8S(arg) | Instruction::I64Load8U(arg) => (arg, 1, None),
+        Instruction::I32Load16S(arg) | Instruction::I32Load16U(arg) => (arg, 2, None),
+        Instruction::I64Load16S(arg) | Instruction::I64Load16U(arg) => (arg, 2, None),
+        Instruction::I32Load(arg) | Instruction::I64Load32S(arg) | Instruction::I64Load32U(arg) => {
+            (arg, 4, None)
+        }
+        Instruction::I64Load(arg) | Instruction::F64Load(arg) => (arg, 8, None),
+        Instruction::I32Store8(arg) => (arg, 1, Some(ValType::I32)),
+        Instruction::I32Store16(arg) => (arg, 2, Some(ValType::I32)),
+        Instruction::I32Store(arg) => (arg, 4, Some(ValType::I32)),
+        Instruction::I64Store8(arg) => (arg, 1, Some(ValType::I64)),
+        Instruction::I64Store16(arg) => (arg, 2, Some(ValType::I64)),
+        Instruction::I64Store32(arg) => (arg, 4, Some(ValType::I64)),
+        Instruction::I64Store(arg) => (arg, 8, Some(ValType::I64)),
+        Instruction::F64Store(arg) => (arg, 8, Some(ValType::F64)),
+        _ => return None,
+    };
+    Some(access)
+}
@@ -1,10 +1,67 @@
-use crate::ast::{IRExprKind, IRFunction, IRProgram, IRStmt, TypeKind};
+use crate::ast::{IRExprKind, IRFunction, IRProgram, IRStmt};
 use crate::error::CompilerError;
-use wasm_encoder::{CodeSection, Function, Instruction, MemArg};
+use crate::host::AsyncState;
+use wasm_encoder::{BlockType, CodeSection, Instruction, MemArg, ValType};
 
-use super::constants::{import, mem};
+use super::constants::{dtype, import, mem, BLOCK_LENGTH};
+use super::frames::FrameUsage;
+use super::expr::{emit_root, pointer_memory};
 use super::helpers::{emit_gc_retry, type_to_valtype};
-use super::Codegen;
+use super::sanitize::Function;
+use super::suspend::SavedFrame;
+use super::{takes_args, AllocPolicy, Codegen};
+
+/// The wrapper hosts call the exported function at `index` through: it
+/// passes the zeros and empty environment every function takes before its
+/// own parameters.
+pub(super) fn compile_export(index: u32, func: &IRFunction, codes: &mut CodeSection) {
+    let mut f = Function::new(vec![]);
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I64Const(0));
+    f.instruction(&Instruction::I32Const(0));
+    for i in 0..func.params.len() {
+        f.instruction(&Instruction::LocalGet(i as u32));
+    }
+    f.instruction(&Instruction::Call(index));
+    f.instruction(&Instruction::End);
+    codes.function(f.body());
+}
+
+/// The function collections go through when the program has drop methods:
+/// it calls the runtime's `gc`, then each struct's drop method on every
+/// struct of its type that the collection found unreachable. Running them
+/// may collect again, so the scratchpad the interrupted allocation parked
+/// its operands in is parked on the shadow stack meanwhile.
+pub(super) fn compile_collector(
+    first_function: u32,
+    drops: &[(u32, u32)],
+    codes: &mut CodeSection,
+) {
+    let mut f = Function::new(vec![(1, ValType::I32)]);
+    f.instruction(&Instruction::Call(import::GC));
+    f.instruction(&Instruction::Call(import::PARK));
+    for (id, drop) in drops {
+        f.instruction(&Instruction::Block(BlockType::Empty));
+        f.instruction(&Instruction::Loop(BlockType::Empty));
+        f.instruction(&Instruction::I32Const(*id as i32));
+        f.instruction(&Instruction::Call(import::DEQUEUE));
+        f.instruction(&Instruction::LocalTee(0));
+        f.instruction(&Instruction::I32Eqz);
+        f.instruction(&Instruction::BrIf(1));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I64Const(0));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::Call(first_function + drop));
+        f.instruction(&Instruction::Drop);
+        f.instruction(&Instruction::Br(0));
+        f.instruction(&Instruction::End);
+        f.instruction(&Instruction::End);
+    }
+    f.instruction(&Instruction::Call(import::UNPARK));
+    f.instruction(&Instruction::End);
+    codes.function(f.body());
+}
 
 impl Codegen {
     pub(super) fn compile_function(
@@ -14,22 +71,121 @@ impl Codegen {
         program: &IRProgram,
     ) -> Result<(), CompilerError> {
         let mut locals: Vec<(u32, wasm_encoder::ValType)> = vec![];
+        if takes_args(func) {
+            // The arguments list, in the slot its parameter would have had.
+            locals.push((1, ValType::I32));
+        }
         locals.extend(func.locals.iter().map(|t| (1, type_to_valtype(t))));
-        let mut f = Function::new(locals);
+        // Locals follow the scratch values, the environment and whichever
+        // parameters the function takes in wasm.
+        let params = if takes_args(func) {
+            0
+        } else {
+            func.params.len()
+        };
+        let mut f = Function::sanitized(locals, 3 + params as u32, self.sanitizer);
+        if let Some(collector) = self.collector {
+            f.collect_through(collector);
+        }
+        // A drop method collecting would run the next drop inside it, and
+        // so on down the whole queue.
+        let is_drop = self
+            .drops
+            .iter()
+            .any(|&(_, drop)| std::ptr::eq(&program.functions[drop as usize], func));
+        if self.options.gc_stress && !is_drop {
+            f.stress_collections();
+        }
+
+        let usage = FrameUsage::new(func, self.sanitizer.is_some());
+        usage.check(&self.options)?;
+        self.temp_slot_base = 1 + (func.params.len() + func.locals.len()) as u32;
+        self.temp_slot_depth = 0;
+        let frame_size = usage.frame_slots as usize;
+
+        self.saved_frame = func.resumable.then(|| {
+            let mut saved = vec![ValType::I32];
+            saved.extend(func.params.iter().chain(&func.locals).map(type_to_valtype));
+            SavedFrame {
+                slots: frame_size as u32,
+                locals: saved,
+                returns: type_to_valtype(&func.returns),
+            }
+        });
+        if func.resumable {
+            // A rewind finds the frame where it was left, so only restores
+            // the locals.
+            self.emit_in_state(&mut f, AsyncState::Rewinding);
+            f.instruction(&Instruction::If(BlockType::Empty));
+            self.emit_restore(&mut f);
+            f.instruction(&Instruction::Else);
+        }
 
         if func.name == "main" {
+            let registered = program.structs.iter().zip(&self.type_ids);
+            let registered: Vec<_> = registered
+                .filter_map(|(ir_struct, id)| Some((ir_struct, (*id)?)))
+                .collect();
+            // Each runtime checks it was built against the ABI this was
+            // compiled against.
+            let version = abi::VERSION as i32;
+            f.instruction(&Instruction::I32Const(registered.len() as i32));
+            f.instruction(&Instruction::I32Const(version));
             f.instruction(&Instruction::Call(import::ALLOC_INIT));
+            f.instruction(&Instruction::I32Const(version));
             f.instruction(&Instruction::Call(import::DINIT));
+            if self.options.alloc_policy != AllocPolicy::default() {
+                f.instruction(&Instruction::I32Const(
+                    self.options.alloc_policy.code() as i32
+                ));
+                f.instruction(&Instruction::Call(import::DPOLICY));
+            }
+            f.instruction(&Instruction::I32Const(version));
             f.instruction(&Instruction::Call(import::SHADOW_INIT));
-            for ir_struct in &program.structs {
+            if !self.globals.is_empty() {
+                // The globals' frame, under main's, is never popped.
+                f.instruction(&Instruction::I32Const(self.globals.len() as i32));
+                f.instruction(&Instruction::Call(import::SHADOW_PUSH));
+            }
+            for (ir_struct, id) in registered {
+                f.instruction(&Instruction::I32Const(id as i32));
                 f.instruction(&Instruction::I32Const(ir_struct.size as i32));
                 f.instruction(&Instruction::I32Const(ir_struct.struct_count as i32));
                 f.instruction(&Instruction::I32Const(ir_struct.list_count as i32));
                 f.instruction(&Instruction::Call(import::ALLOC_REGISTER));
             }
+            for (id, _) in &self.drops {
+                f.instruction(&Instruction::I32Const(*id as i32));
+                f.instruction(&Instruction::Call(import::FINALIZABLE));
+            }
+            self.emit_data_segment_init(&mut f);
         }
 
-        let frame_size = 1 + func.params.len() + func.locals.len();
+        self.frameless = func.noalloc;
+        self.unrooted = func.unrooted.clone();
+        if !self.frameless {
+            self.emit_frame(&mut f, func, frame_size);
+        }
+
+        if func.resumable {
+            f.instruction(&Instruction::End);
+        }
+
+        for stmt in &func.body {
+            self.compile_stmt(stmt, &mut f)?;
+        }
+
+        // Every path ends in a return, so control never reaches here. Saying
+        // so lets bodies end in an if/else or a loop and still validate.
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+        codes.function(f.body());
+        Ok(())
+    }
+
+    /// Pushes the function's shadow stack frame and roots its environment
+    /// and pointer parameters in it.
+    fn emit_frame(&mut self, f: &mut Function, func: &IRFunction, frame_size: usize) {
         f.instruction(&Instruction::I32Const(frame_size as i32));
         f.instruction(&Instruction::Call(import::SHADOW_PUSH));
 
@@ -38,34 +194,21 @@ impl Codegen {
         f.instruction(&Instruction::I32Const(1));
         f.instruction(&Instruction::Call(import::SHADOW_SET));
 
+        if takes_args(func) {
+            emit_args_list(f);
+        }
+
         for (i, param_ty) in func.params.iter().enumerate() {
             let local_index = 3 + i as u32;
             let shadow_slot = 1 + i as i32;
-            match &param_ty.kind {
-                TypeKind::Struct { .. } => {
-                    f.instruction(&Instruction::LocalGet(local_index));
-                    f.instruction(&Instruction::I32Const(shadow_slot));
-                    f.instruction(&Instruction::I32Const(1));
-                    f.instruction(&Instruction::Call(import::SHADOW_SET));
-                }
-                TypeKind::List { .. } | TypeKind::String => {
-                    f.instruction(&Instruction::LocalGet(local_index));
-                    f.instruction(&Instruction::I32Const(shadow_slot));
-                    f.instruction(&Instruction::I32Const(2));
-                    f.instruction(&Instruction::Call(import::SHADOW_SET));
-                }
-                _ => {}
+            if self.unrooted.contains(&local_index) {
+                continue;
+            }
+            if pointer_memory(param_ty).is_some() {
+                f.instruction(&Instruction::LocalGet(local_index));
+                emit_root(f, param_ty, shadow_slot);
             }
         }
-
-        for stmt in &func.body {
-            self.compile_stmt(stmt, &mut f)?;
-        }
-
-        f.instruction(&Instruction::Call(import::SHADOW_POP));
-        f.instruction(&Instruction::End);
-        codes.function(&f);
-        Ok(())
     }
 
     pub(super) fn compile_stmt(
@@ -80,22 +223,12 @@ impl Codegen {
             }
             IRStmt::LocalSet { index, value } => {
                 self.compile_expr(value, f, false)?;
-                f.instruction(&Instruction::LocalTee(*index));
-                match value.ty.kind {
-                    TypeKind::Struct { .. } => {
-                        f.instruction(&Instruction::I32Const((*index - 2) as i32));
-                        f.instruction(&Instruction::I32Const(1));
-                        f.instruction(&Instruction::Call(import::SHADOW_SET));
-                    }
-                    TypeKind::List { .. } | TypeKind::String => {
-                        f.instruction(&Instruction::I32Const((*index - 2) as i32));
-                        f.instruction(&Instruction::I32Const(2));
-                        f.instruction(&Instruction::Call(import::SHADOW_SET));
-                    }
-                    _ => {
-                        f.instruction(&Instruction::Drop);
-                    }
+                if self.frameless || self.unrooted.contains(index) {
+                    f.instruction(&Instruction::LocalSet(*index));
+                    return Ok(());
                 }
+                f.instruction(&Instruction::LocalTee(*index));
+                emit_root(f, &value.ty, (*index - 2) as i32);
             }
             IRStmt::Return(expr) => {
                 if let Some(expr) = expr {
@@ -103,15 +236,19 @@ impl Codegen {
                 } else {
                     f.instruction(&Instruction::I64Const(0));
                 }
-                f.instruction(&Instruction::Call(import::SHADOW_POP));
+                if !self.frameless {
+                    f.instruction(&Instruction::Call(import::SHADOW_POP));
+                }
                 f.instruction(&Instruction::Return);
             }
-            IRStmt::Break => {
-                f.instruction(&Instruction::Br(1));
+            IRStmt::Break(out) => {
+                f.instruction(&Instruction::Br(self.loop_label(*out) + 1));
             }
-            IRStmt::Continue => {
-                f.instruction(&Instruction::Br(0));
+            IRStmt::Continue(out) => {
+                f.instruction(&Instruction::Br(self.loop_label(*out)));
             }
+            IRStmt::Suspend => self.emit_suspend(f),
+            IRStmt::Exit => self.emit_exit(f),
             IRStmt::If {
                 condition,
                 then_block,
@@ -119,6 +256,7 @@ impl Codegen {
             } => {
                 self.compile_expr(condition, f, false)?;
                 f.instruction(&Instruction::If(wasm_encoder::BlockType::Empty));
+                self.block_depth += 1;
                 for stmt in then_block {
                     self.compile_stmt(stmt, f)?;
                 }
@@ -128,6 +266,7 @@ impl Codegen {
                         self.compile_stmt(stmt, f)?;
                     }
                 }
+                self.block_depth -= 1;
                 f.instruction(&Instruction::End);
             }
             IRStmt::While { condition, body } => {
@@ -136,13 +275,23 @@ impl Codegen {
                 self.compile_expr(condition, f, false)?;
                 f.instruction(&Instruction::I32Eqz);
                 f.instruction(&Instruction::BrIf(1));
+                self.enter_loop();
                 for stmt in body {
                     self.compile_stmt(stmt, f)?;
                 }
+                self.exit_loop();
                 f.instruction(&Instruction::Br(0));
                 f.instruction(&Instruction::End);
                 f.instruction(&Instruction::End);
             }
+            IRStmt::Unchecked { body } => {
+                let outer = self.unchecked;
+                self.unchecked = true;
+                for stmt in body {
+                    self.compile_stmt(stmt, f)?;
+                }
+                self.unchecked = outer;
+            }
             IRStmt::For {
                 init,
                 condition,
@@ -155,9 +304,11 @@ impl Codegen {
                 self.compile_expr(condition, f, false)?;
                 f.instruction(&Instruction::I32Eqz);
                 f.instruction(&Instruction::BrIf(1));
+                self.enter_loop();
                 for stmt in body {
                     self.compile_stmt(stmt, f)?;
                 }
+                self.exit_loop();
                 self.compile_stmt(update, f)?;
                 f.instruction(&Instruction::Br(0));
                 f.instruction(&Instruction::End);
@@ -183,30 +334,7 @@ impl Codegen {
                         struct_index,
                         fields: _,
                     } => {
-                        let idx = *struct_index as i32;
-                        emit_gc_retry(
-                            f,
-                            |f| {
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::I32Const(idx));
-                                f.instruction(&Instruction::I32Store(MemArg {
-                                    offset: 4,
-                                    align: 2,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::I32Const(0));
-                                f.instruction(&Instruction::I32Load(MemArg {
-                                    offset: 4,
-                                    align: 2,
-                                    memory_index: mem::SHADOW,
-                                }));
-                            },
-                            |f| {
-                                f.instruction(&Instruction::Call(import::FALLOC));
-                            },
-                        );
+                        self.emit_struct_alloc(*struct_index, f);
                         f.instruction(&Instruction::LocalTee(0));
                     }
                     _ => {
@@ -229,8 +357,144 @@ impl Codegen {
                 f.instruction(&Instruction::Call(import::SHADOW_SET));
                 f.instruction(&Instruction::LocalGet(0));
                 self.compile_expr(captures, f, true)?;
+                f.instruction(&Instruction::Drop);
             }
         }
         Ok(())
     }
+
+    /// Enters the body of a loop, inside its block and loop.
+    fn enter_loop(&mut self) {
+        self.block_depth += 2;
+        self.loop_depths.push(self.block_depth);
+    }
+
+    fn exit_loop(&mut self) {
+        self.loop_depths.pop();
+        self.block_depth -= 2;
+    }
+
+    /// Label of the loop `out` loops out from the innermost; its block is
+    /// the label after.
+    fn loop_label(&self, out: u32) -> u32 {
+        let loops = self.loop_depths.len();
+        assert!((out as usize) < loops, "break outside a loop");
+        self.block_depth - self.loop_depths[loops - 1 - out as usize]
+    }
+
+    /// Copies each constant list into its own pinned dalloc block. Runs
+    /// once, at the start of `main`.
+    fn emit_data_segment_init(&self, f: &mut Function) {
+        for (index, segment) in self.data_segments.iter().enumerate() {
+            if segment.dtype != dtype::PRIMITIVE {
+                continue;
+            }
+            f.instruction(&Instruction::I32Const(segment.dtype));
+            f.instruction(&Instruction::I32Const(segment.length()));
+            f.instruction(&Instruction::Call(import::DALLOC));
+            f.instruction(&Instruction::GlobalSet(index as u32));
+            self.emit_data_segment_copy(f, index as u32);
+        }
+    }
+
+    /// Fills the block whose address is in global `index` from the data
+    /// segment of the same index, drops the segment and pins the block.
+    pub(super) fn emit_data_segment_copy(&self, f: &mut Function, index: u32) {
+        let segment = &self.data_segments[index as usize];
+
+        f.instruction(&Instruction::GlobalGet(index));
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::I32Const(segment.bytes.len() as i32));
+        f.instruction(&Instruction::MemoryInit {
+            mem: mem::DALLOC,
+            data_index: index,
+        });
+        f.instruction(&Instruction::DataDrop(index));
+
+        f.instruction(&Instruction::GlobalGet(index));
+        f.instruction(&Instruction::Call(import::DPIN));
+    }
+}
+
+/// Builds `main`'s arguments list in local 3 from the host's `arg_count`,
+/// `arg_length` and `arg_copy`. The list is rooted before any string is
+/// allocated, and starts out zeroed so a collection midway skips the slots
+/// not yet filled. Uses locals 0 and 1.
+fn emit_args_list(f: &mut Function) {
+    emit_gc_retry(
+        f,
+        |_| {},
+        |f| {
+            f.instruction(&Instruction::I32Const(dtype::LISTS));
+            f.instruction(&Instruction::I64Const(0));
+            f.instruction(&Instruction::Call(import::ARG_COUNT));
+        },
+        |f| {
+            f.instruction(&Instruction::Call(import::DFILL));
+        },
+    );
+    f.instruction(&Instruction::LocalTee(3));
+    f.instruction(&Instruction::I32Const(1));
+    f.instruction(&Instruction::I32Const(2));
+    f.instruction(&Instruction::Call(import::SHADOW_SET));
+
+    f.instruction(&Instruction::I64Const(0));
+    f.instruction(&Instruction::LocalSet(1));
+    f.instruction(&Instruction::Block(BlockType::Empty));
+    f.instruction(&Instruction::Loop(BlockType::Empty));
+
+    // Stop once every slot of the list is filled.
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::I32Const(BLOCK_LENGTH));
+    f.instruction(&Instruction::I32Sub);
+    f.instruction(&Instruction::I32Load(MemArg {
+        offset: 0,
+        align: 2,
+        memory_index: mem::DALLOC,
+    }));
+    f.instruction(&Instruction::I64ExtendI32U);
+    f.instruction(&Instruction::I64GeU);
+    f.instruction(&Instruction::BrIf(1));
+
+    emit_gc_retry(
+        f,
+        |_| {},
+        |f| {
+            f.instruction(&Instruction::I32Const(dtype::BYTES));
+            f.instruction(&Instruction::LocalGet(1));
+            f.instruction(&Instruction::I32WrapI64);
+            f.instruction(&Instruction::Call(import::ARG_LENGTH));
+        },
+        |f| {
+            f.instruction(&Instruction::Call(import::DALLOC));
+        },
+    );
+    f.instruction(&Instruction::Drop);
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32WrapI64);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::Call(import::ARG_COPY));
+
+    f.instruction(&Instruction::LocalGet(3));
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I32WrapI64);
+    f.instruction(&Instruction::I32Const(8));
+    f.instruction(&Instruction::I32Mul);
+    f.instruction(&Instruction::I32Add);
+    f.instruction(&Instruction::LocalGet(0));
+    f.instruction(&Instruction::I64ExtendI32U);
+    f.instruction(&Instruction::I64Store(MemArg {
+        offset: 0,
+        align: 3,
+        memory_index: mem::DALLOC,
+    }));
+
+    f.instruction(&Instruction::LocalGet(1));
+    f.instruction(&Instruction::I64Const(1));
+    f.instruction(&Instruction::I64Add);
+    f.instruction(&Instruction::LocalSet(1));
+    f.instruction(&Instruction::Br(0));
+    f.instruction(&Instruction::End);
+    f.instruction(&Instruction::End);
 }
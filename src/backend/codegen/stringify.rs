@@ -0,0 +1,232 @@
+use crate::ast::{IRExpr, IRExprKind, Type, TypeKind};
+use crate::error::CompilerError;
+use wasm_encoder::{BlockType, Instruction, MemArg};
+
+use super::constants::{import, mem};
+use super::helpers::{emit_access_cast, emit_gc_retry};
+use super::sanitize::Function;
+use super::Codegen;
+
+/// Tags of the tagged union that stringify tells apart. Every other tag
+/// means a value is present.
+const TAG_NULL: i64 = 0;
+const TAG_ERROR: i64 = 1;
+
+impl Codegen {
+    /// Replaces the value of type `ty` on the stack with its string form.
+    /// Nullable and errorable values print as `null` or `error(Name)` when
+    /// they don't hold a value.
+    pub(super) fn emit_stringify(
+        &mut self,
+        ty: &Type,
+        f: &mut Function,
+    ) -> Result<(), CompilerError> {
+        if ty.nullable || ty.errorable {
+            return self.emit_stringify_wrapped(ty, f);
+        }
+
+        match ty.kind {
+            TypeKind::Integer => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::LocalSet(1)); // i64 needs local1
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::LocalGet(1));
+                        f.instruction(&Instruction::I64Store(MemArg {
+                            offset: 4,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I64Load(MemArg {
+                            offset: 4,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DITOA));
+                    },
+                );
+            }
+            TypeKind::String => {}
+            TypeKind::Boolean => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::LocalSet(0));
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::LocalGet(0));
+                        f.instruction(&Instruction::I32Store(MemArg {
+                            offset: 4,
+                            align: 2,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::I32Load(MemArg {
+                            offset: 4,
+                            align: 2,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DBTOA));
+                    },
+                );
+            }
+            TypeKind::Float => {
+                emit_gc_retry(
+                    f,
+                    |f| {
+                        f.instruction(&Instruction::I64ReinterpretF64);
+                        f.instruction(&Instruction::LocalSet(1)); // f64 bits go in local1
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::LocalGet(1));
+                        f.instruction(&Instruction::I64Store(MemArg {
+                            offset: 4,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::I32Const(0));
+                        f.instruction(&Instruction::F64Load(MemArg {
+                            offset: 4,
+                            align: 3,
+                            memory_index: mem::SHADOW,
+                        }));
+                    },
+                    |f| {
+                        f.instruction(&Instruction::Call(import::DFTOA));
+                    },
+                );
+            }
+            _ => {
+                return Err(CompilerError::Codegen {
+                    message: format!("Cannot stringify type {:?}", ty),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_stringify_wrapped(&mut self, ty: &Type, f: &mut Function) -> Result<(), CompilerError> {
+        let value_ty = Type {
+            kind: ty.kind.clone(),
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+
+        f.instruction(&Instruction::LocalTee(0));
+        f.instruction(&Instruction::I64Load(MemArg {
+            offset: 0,
+            align: 3,
+            memory_index: mem::ALLOC,
+        }));
+        f.instruction(&Instruction::LocalTee(1));
+        f.instruction(&Instruction::I64Const(TAG_NULL));
+        f.instruction(&Instruction::I64Eq);
+        f.instruction(&Instruction::If(BlockType::Result(
+            wasm_encoder::ValType::I32,
+        )));
+        if ty.nullable {
+            self.emit_string("null", f)?;
+        } else {
+            f.instruction(&Instruction::Unreachable);
+        }
+        f.instruction(&Instruction::Else);
+
+        f.instruction(&Instruction::LocalGet(1));
+        f.instruction(&Instruction::I64Const(TAG_ERROR));
+        f.instruction(&Instruction::I64Eq);
+        f.instruction(&Instruction::If(BlockType::Result(
+            wasm_encoder::ValType::I32,
+        )));
+        // Name the error after the type id in its struct's header.
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::I64Load(MemArg {
+            offset: 8,
+            align: 3,
+            memory_index: mem::ALLOC,
+        }));
+        f.instruction(&Instruction::I32WrapI64);
+        f.instruction(&Instruction::I32Const(8));
+        f.instruction(&Instruction::I32Sub);
+        f.instruction(&Instruction::I32Load(MemArg {
+            offset: 0,
+            align: 2,
+            memory_index: mem::ALLOC,
+        }));
+        f.instruction(&Instruction::LocalSet(0));
+        let errors = self.error_types.clone();
+        self.emit_error_name(&errors, f)?;
+        f.instruction(&Instruction::Else);
+
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::I64Load(MemArg {
+            offset: 8,
+            align: 3,
+            memory_index: mem::ALLOC,
+        }));
+        emit_access_cast(f, &value_ty);
+        self.emit_stringify(&value_ty, f)?;
+        f.instruction(&Instruction::End);
+
+        f.instruction(&Instruction::End);
+        Ok(())
+    }
+
+    /// Picks the `error(Name)` string for the type id in local 0.
+    fn emit_error_name(
+        &mut self,
+        errors: &[(u32, String)],
+        f: &mut Function,
+    ) -> Result<(), CompilerError> {
+        match errors {
+            [] => {
+                f.instruction(&Instruction::Unreachable);
+            }
+            [(_, name)] => self.emit_string(&error_string(name), f)?,
+            [(id, name), rest @ ..] => {
+                f.instruction(&Instruction::LocalGet(0));
+                f.instruction(&Instruction::I32Const(*id as i32));
+                f.instruction(&Instruction::I32Eq);
+                f.instruction(&Instruction::If(BlockType::Result(
+                    wasm_encoder::ValType::I32,
+                )));
+                self.emit_string(&error_string(name), f)?;
+                f.instruction(&Instruction::Else);
+                self.emit_error_name(rest, f)?;
+                f.instruction(&Instruction::End);
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_string(&mut self, s: &str, f: &mut Function) -> Result<(), CompilerError> {
+        self.compile_expr(
+            &IRExpr {
+                node: IRExprKind::String(s.to_string()),
+                ty: Type {
+                    kind: TypeKind::String,
+                    nullable: false,
+                    errorable: false,
+                    errors: vec![],
+                },
+            },
+            f,
+            false,
+        )
+    }
+}
+
+/// How a value holding the error `name` is stringified.
+pub(super) fn error_string(name: &str) -> String {
+    format!("error({})", name)
+}
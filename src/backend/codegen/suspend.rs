@@ -0,0 +1,247 @@
+use crate::ast::Builtin;
+use crate::host::{AsyncRequest, AsyncState};
+use wasm_encoder::{BlockType, ConstExpr, GlobalSection, GlobalType, Instruction, MemArg, ValType};
+
+use super::constants::{import, mem, SHADOW_FRAME_POINTER};
+use super::sanitize::Function;
+use super::Codegen;
+
+/// Globals of a program that awaits, following the data segment globals.
+/// The first four are exported under the names in `crate::host`.
+pub(super) mod global {
+    pub const STATE: u32 = 0;
+    pub const REQUEST: u32 = 1;
+    pub const ARGUMENT: u32 = 2;
+    pub const RESULT: u32 = 3;
+    /// Top of the saved locals in save memory.
+    pub const SAVED: u32 = 4;
+}
+
+/// What a resumable function saves when it suspends: its frame pointer,
+/// then its locals from the environment on, eight bytes each.
+pub(super) struct SavedFrame {
+    /// Shadow stack slots in the function's frame.
+    pub slots: u32,
+    pub locals: Vec<ValType>,
+    pub returns: ValType,
+}
+
+impl SavedFrame {
+    fn bytes(&self) -> i32 {
+        8 * (1 + self.locals.len() as i32)
+    }
+}
+
+impl Codegen {
+    pub(super) fn async_global(&self, global: u32) -> u32 {
+        (self.data_segments.len() + self.globals.len()) as u32 + global
+    }
+
+    pub(super) fn add_async_globals(&self, globals: &mut GlobalSection) {
+        let types = [
+            ValType::I32,
+            ValType::I32,
+            ValType::I64,
+            ValType::I64,
+            ValType::I32,
+        ];
+        for val_type in types {
+            let init = match val_type {
+                ValType::I64 => ConstExpr::i64_const(0),
+                _ => ConstExpr::i32_const(0),
+            };
+            globals.global(
+                GlobalType {
+                    val_type,
+                    mutable: true,
+                    shared: false,
+                },
+                &init,
+            );
+        }
+    }
+
+    /// Pushes whether the program is in `state`.
+    pub(super) fn emit_in_state(&self, f: &mut Function, state: AsyncState) {
+        f.instruction(&Instruction::GlobalGet(self.async_global(global::STATE)));
+        f.instruction(&Instruction::I32Const(state as i32));
+        f.instruction(&Instruction::I32Eq);
+    }
+
+    /// Saves the current function's frame and returns without popping it.
+    /// Its frame pointer is set to the caller's frame, which the caller saves
+    /// next.
+    pub(super) fn emit_suspend(&self, f: &mut Function) {
+        let frame = self
+            .saved_frame
+            .as_ref()
+            .expect("only resumable functions suspend");
+        let saved = self.async_global(global::SAVED);
+        let bytes = frame.bytes();
+
+        // Grow save memory by a page if the frame does not fit.
+        f.instruction(&Instruction::GlobalGet(saved));
+        f.instruction(&Instruction::I32Const(bytes));
+        f.instruction(&Instruction::I32Add);
+        f.instruction(&Instruction::MemorySize(mem::SAVE));
+        f.instruction(&Instruction::I32Const(16));
+        f.instruction(&Instruction::I32Shl);
+        f.instruction(&Instruction::I32GtU);
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::I32Const(1));
+        f.instruction(&Instruction::MemoryGrow(mem::SAVE));
+        f.instruction(&Instruction::I32Const(-1));
+        f.instruction(&Instruction::I32Eq);
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+        f.instruction(&Instruction::End);
+
+        f.instruction(&Instruction::GlobalGet(saved));
+        f.instruction(&Instruction::LocalTee(0));
+        emit_frame_pointer(f);
+        f.instruction(&Instruction::I32Store(save_slot(0, 2)));
+        for (i, val_type) in frame.locals.iter().enumerate() {
+            f.instruction(&Instruction::LocalGet(0));
+            f.instruction(&Instruction::LocalGet(2 + i as u32));
+            f.instruction(&match val_type {
+                ValType::I64 => Instruction::I64Store(save_slot(i + 1, 3)),
+                ValType::F64 => Instruction::F64Store(save_slot(i + 1, 3)),
+                _ => Instruction::I32Store(save_slot(i + 1, 2)),
+            });
+        }
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::I32Const(bytes));
+        f.instruction(&Instruction::I32Add);
+        f.instruction(&Instruction::GlobalSet(saved));
+
+        // The caller's frame pointer sits in the last word of this frame.
+        f.instruction(&Instruction::I32Const(0));
+        emit_frame_pointer(f);
+        f.instruction(&Instruction::I32Load(MemArg {
+            offset: frame.slots as u64 * 8 + 4,
+            align: 2,
+            memory_index: mem::SHADOW,
+        }));
+        f.instruction(&Instruction::I32Store(frame_pointer()));
+
+        emit_zero(f, frame.returns);
+        f.instruction(&Instruction::Return);
+    }
+
+    /// Returns from the current function while the program exits, popping
+    /// its frame like any other return.
+    pub(super) fn emit_exit(&self, f: &mut Function) {
+        let frame = self
+            .saved_frame
+            .as_ref()
+            .expect("only resumable functions exit");
+        emit_zero(f, frame.returns);
+        f.instruction(&Instruction::Call(import::SHADOW_POP));
+        f.instruction(&Instruction::Return);
+    }
+
+    /// Restores what `emit_suspend` saved, making the frame current again.
+    pub(super) fn emit_restore(&self, f: &mut Function) {
+        let frame = self
+            .saved_frame
+            .as_ref()
+            .expect("only resumable functions restore");
+        let saved = self.async_global(global::SAVED);
+
+        f.instruction(&Instruction::GlobalGet(saved));
+        f.instruction(&Instruction::I32Const(frame.bytes()));
+        f.instruction(&Instruction::I32Sub);
+        f.instruction(&Instruction::LocalTee(0));
+        f.instruction(&Instruction::GlobalSet(saved));
+
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::LocalGet(0));
+        f.instruction(&Instruction::I32Load(save_slot(0, 2)));
+        f.instruction(&Instruction::I32Store(frame_pointer()));
+        for (i, val_type) in frame.locals.iter().enumerate() {
+            f.instruction(&Instruction::LocalGet(0));
+            f.instruction(&match val_type {
+                ValType::I64 => Instruction::I64Load(save_slot(i + 1, 3)),
+                ValType::F64 => Instruction::F64Load(save_slot(i + 1, 3)),
+                _ => Instruction::I32Load(save_slot(i + 1, 2)),
+            });
+            f.instruction(&Instruction::LocalSet(2 + i as u32));
+        }
+    }
+
+    /// Compiles `sleep` or `fetch`, with its argument on the stack. While
+    /// running, it makes the request and starts unwinding; when rewound, it
+    /// evaluates to the host's result.
+    pub(super) fn emit_await(&self, builtin: Builtin, f: &mut Function) {
+        let (request, result) = match builtin {
+            Builtin::Sleep => (AsyncRequest::Sleep, ValType::I64),
+            Builtin::Fetch => {
+                f.instruction(&Instruction::I64ExtendI32U);
+                (AsyncRequest::Fetch, ValType::I32)
+            }
+            _ => unreachable!("{:?} is not awaited", builtin),
+        };
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::ARGUMENT)));
+
+        self.emit_in_state(f, AsyncState::Rewinding);
+        f.instruction(&Instruction::If(BlockType::Result(result)));
+        f.instruction(&Instruction::I32Const(AsyncState::Running as i32));
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::STATE)));
+        f.instruction(&Instruction::GlobalGet(self.async_global(global::RESULT)));
+        if result == ValType::I32 {
+            f.instruction(&Instruction::I32WrapI64);
+        }
+        f.instruction(&Instruction::Else);
+        f.instruction(&Instruction::I32Const(request as i32));
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::REQUEST)));
+        f.instruction(&Instruction::I32Const(AsyncState::Unwinding as i32));
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::STATE)));
+        // Never used: the call site suspends straight away.
+        f.instruction(&match result {
+            ValType::I64 => Instruction::I64Const(0),
+            _ => Instruction::I32Const(0),
+        });
+        f.instruction(&Instruction::End);
+    }
+
+    /// Compiles `exit`, with its code on the stack: hands the code to the
+    /// host and starts unwinding for good.
+    pub(super) fn emit_exit_call(&self, f: &mut Function) {
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::ARGUMENT)));
+        f.instruction(&Instruction::I32Const(AsyncState::Exiting as i32));
+        f.instruction(&Instruction::GlobalSet(self.async_global(global::STATE)));
+        // Never used: the call site returns straight away.
+        f.instruction(&Instruction::I64Const(0));
+    }
+}
+
+/// Pushes a placeholder for a value nobody reads.
+fn emit_zero(f: &mut Function, val_type: ValType) {
+    f.instruction(&match val_type {
+        ValType::I64 => Instruction::I64Const(0),
+        ValType::F64 => Instruction::F64Const(0.0.into()),
+        _ => Instruction::I32Const(0),
+    });
+}
+
+fn emit_frame_pointer(f: &mut Function) {
+    f.instruction(&Instruction::I32Const(0));
+    f.instruction(&Instruction::I32Load(frame_pointer()));
+}
+
+fn frame_pointer() -> MemArg {
+    MemArg {
+        offset: SHADOW_FRAME_POINTER,
+        align: 2,
+        memory_index: mem::SHADOW,
+    }
+}
+
+fn save_slot(index: usize, align: u32) -> MemArg {
+    MemArg {
+        offset: index as u64 * 8,
+        align,
+        memory_index: mem::SAVE,
+    }
+}
@@ -0,0 +1,62 @@
+use wasm_encoder::{Function, Instruction, ValType};
+
+/// A function body being generated. Instructions are kept until the end,
+/// since the scratch locals that expressions take along the way are only
+/// declared then.
+pub(super) struct Body {
+    params: u32,
+    locals: Vec<ValType>,
+    /// Scratch locals released for reuse.
+    free: Vec<u32>,
+    instructions: Vec<Instruction<'static>>,
+}
+
+impl Body {
+    /// A body with `params` parameters, followed by `locals`.
+    pub(super) fn new(params: u32, locals: Vec<ValType>) -> Self {
+        Body {
+            params,
+            locals,
+            free: vec![],
+            instructions: vec![],
+        }
+    }
+
+    pub(super) fn push(&mut self, instruction: Instruction<'static>) {
+        self.instructions.push(instruction);
+    }
+
+    /// A local of type `ty` to hold a value for the duration of an
+    /// expression, until it is released.
+    pub(super) fn scratch(&mut self, ty: ValType) -> u32 {
+        let found = self
+            .free
+            .iter()
+            .position(|&local| self.locals[(local - self.params) as usize] == ty);
+        match found {
+            Some(position) => self.free.swap_remove(position),
+            None => {
+                self.locals.push(ty);
+                self.params + self.locals.len() as u32 - 1
+            }
+        }
+    }
+
+    /// How many locals the body declares so far, params included.
+    pub(super) fn local_count(&self) -> u32 {
+        self.params + self.locals.len() as u32
+    }
+
+    pub(super) fn release(&mut self, local: u32) {
+        self.free.push(local);
+    }
+
+    pub(super) fn finish(self) -> Function {
+        let mut f = Function::new_with_locals_types(self.locals);
+        for instruction in &self.instructions {
+            f.instruction(instruction);
+        }
+        f.instruction(&Instruction::End);
+        f
+    }
+}
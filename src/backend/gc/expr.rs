@@ -0,0 +1,905 @@
+use crate::ast::{BinaryOp, Builtin, IRExpr, IRExprKind, Type, TypeKind, UnaryOp};
+use crate::error::CompilerError;
+use wasm_encoder::{AbstractHeapType, BlockType, HeapType, Instruction, RefType, ValType};
+
+use super::body::Body;
+use super::runtime::Helper;
+use super::{
+    field, reference, struct_type, ty, unsupported, GcCodegen, Kind, TAG_ERROR, TAG_NULL,
+    TAG_PRIMITIVE,
+};
+
+/// The abstract `eq` heap type, of `null` and of values in boxes.
+const EQ: HeapType = HeapType::Abstract {
+    shared: false,
+    ty: AbstractHeapType::Eq,
+};
+
+impl GcCodegen {
+    pub(super) fn compile_expr(
+        &mut self,
+        expr: &IRExpr,
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        match &expr.node {
+            IRExprKind::Integer(n) => f.push(Instruction::I64Const(*n)),
+            IRExprKind::Float(n) => f.push(Instruction::F64Const(wasm_encoder::Ieee64::from(*n))),
+            IRExprKind::Boolean(b) => f.push(Instruction::I32Const(*b as i32)),
+            IRExprKind::String(s) => self.emit_string(s, f),
+            IRExprKind::Null => f.push(Instruction::RefNull(EQ)),
+            IRExprKind::Local(index) => {
+                f.push(Instruction::LocalGet(*index));
+                if let (2, Some(env)) = (*index, self.env) {
+                    // Every function takes its environment as a `structref`.
+                    let captures = HeapType::Concrete(struct_type(env));
+                    f.push(Instruction::RefCastNullable(captures));
+                }
+            }
+            IRExprKind::Global(index) => f.push(Instruction::GlobalGet(*index)),
+            IRExprKind::Function(fn_index) => {
+                f.push(Instruction::I32Const(*fn_index as i32));
+                f.push(Instruction::RefNull(HeapType::Abstract {
+                    shared: false,
+                    ty: AbstractHeapType::Struct,
+                }));
+                f.push(Instruction::StructNew(ty::CLOSURE));
+            }
+            IRExprKind::Binary {
+                left,
+                op: BinaryOp::Is,
+                right,
+            } => self.compile_assignment(left, right, f)?,
+            IRExprKind::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => {
+                // The right side only runs when it decides the result.
+                self.compile_expr(left, f)?;
+                f.push(Instruction::If(BlockType::Result(ValType::I32)));
+                if *op == BinaryOp::And {
+                    self.compile_expr(right, f)?;
+                    f.push(Instruction::Else);
+                    f.push(Instruction::I32Const(0));
+                } else {
+                    f.push(Instruction::I32Const(1));
+                    f.push(Instruction::Else);
+                    self.compile_expr(right, f)?;
+                }
+                f.push(Instruction::End);
+            }
+            IRExprKind::Binary {
+                left,
+                op: BinaryOp::In,
+                right,
+            } => {
+                self.compile_expr(left, f)?;
+                to_storage(f, &left.ty);
+                self.compile_expr(right, f)?;
+                let contains = self.helper(Helper::ListContains(element_kind(&right.ty)));
+                f.push(Instruction::Call(contains));
+            }
+            IRExprKind::Binary { left, op, right } => {
+                self.compile_expr(left, f)?;
+                self.compile_expr(right, f)?;
+                self.compile_binary(op, &left.ty, f)?;
+            }
+            IRExprKind::Unary { op, expr } => match op {
+                UnaryOp::Minus => {
+                    if expr.ty.kind == TypeKind::Float {
+                        self.compile_expr(expr, f)?;
+                        f.push(Instruction::F64Neg);
+                    } else {
+                        f.push(Instruction::I64Const(0));
+                        self.compile_expr(expr, f)?;
+                        f.push(Instruction::I64Sub);
+                    }
+                }
+                UnaryOp::Not => {
+                    self.compile_expr(expr, f)?;
+                    f.push(Instruction::I32Eqz);
+                }
+                UnaryOp::Count => match &expr.ty.kind {
+                    TypeKind::Array { length, .. } => f.push(Instruction::I64Const(*length as i64)),
+                    TypeKind::String => {
+                        self.compile_expr(expr, f)?;
+                        f.push(Instruction::ArrayLen);
+                        f.push(Instruction::I64ExtendI32U);
+                    }
+                    _ => {
+                        self.compile_expr(expr, f)?;
+                        f.push(Instruction::StructGet {
+                            struct_type_index: element_kind(&expr.ty).list(),
+                            field_index: field::LENGTH,
+                        });
+                        f.push(Instruction::I64ExtendI32U);
+                    }
+                },
+                UnaryOp::Stringify => {
+                    self.compile_expr(expr, f)?;
+                    self.emit_stringify(&expr.ty, f)?;
+                }
+            },
+            IRExprKind::Call { callee, args } => {
+                let type_index = self.function_type(&callee.ty);
+                let closure = f.scratch(reference(ty::CLOSURE));
+                f.push(Instruction::I32Const(0));
+                f.push(Instruction::I64Const(0));
+                self.compile_expr(callee, f)?;
+                f.push(Instruction::LocalTee(closure));
+                f.push(Instruction::StructGet {
+                    struct_type_index: ty::CLOSURE,
+                    field_index: field::ENV,
+                });
+                for arg in args {
+                    self.compile_expr(arg, f)?;
+                }
+                f.push(Instruction::LocalGet(closure));
+                f.push(Instruction::StructGet {
+                    struct_type_index: ty::CLOSURE,
+                    field_index: field::FUNCTION,
+                });
+                f.push(Instruction::CallIndirect {
+                    type_index,
+                    table_index: 0,
+                });
+                f.release(closure);
+            }
+            IRExprKind::CallDirect { fn_index, args } => {
+                f.push(Instruction::I32Const(0));
+                f.push(Instruction::I64Const(0));
+                f.push(Instruction::RefNull(HeapType::Abstract {
+                    shared: false,
+                    ty: AbstractHeapType::Struct,
+                }));
+                for arg in args {
+                    self.compile_expr(arg, f)?;
+                }
+                f.push(Instruction::Call(self.first_function() + fn_index));
+            }
+            IRExprKind::Builtin {
+                builtin: Builtin::HeldError,
+                args,
+            } => {
+                self.compile_expr(&args[0], f)?;
+                f.push(Instruction::StructGet {
+                    struct_type_index: ty::BOX,
+                    field_index: field::VALUE,
+                });
+                self.emit_cast(&expr.ty, f);
+            }
+            IRExprKind::Builtin { builtin, args } => self.compile_builtin(*builtin, args, f)?,
+            IRExprKind::ExternCall { function, args } => {
+                for arg in args {
+                    self.compile_expr(arg, f)?;
+                }
+                f.push(Instruction::Call(self.extern_index(function)));
+            }
+            IRExprKind::New {
+                struct_index: 0,
+                fields,
+            } => {
+                let [tag, value] = &fields[..] else {
+                    panic!("the tagged union has a tag and a value");
+                };
+                self.compile_expr(tag, f)?;
+                if Kind::of(&value.ty) == Kind::Primitive {
+                    self.compile_expr(value, f)?;
+                    to_bits(f, &value.ty);
+                    f.push(Instruction::RefNull(EQ));
+                } else {
+                    f.push(Instruction::I64Const(0));
+                    self.compile_expr(value, f)?;
+                }
+                f.push(Instruction::StructNew(ty::BOX));
+            }
+            IRExprKind::New {
+                struct_index,
+                fields,
+            } => {
+                for field in fields {
+                    self.compile_field_value(field, f)?;
+                }
+                f.push(Instruction::StructNew(struct_type(*struct_index)));
+            }
+            IRExprKind::Field { object, offset }
+            | IRExprKind::FieldReference { object, offset } => {
+                // A reference to an array field is the array itself.
+                let index = self.struct_of(object);
+                self.compile_expr(object, f)?;
+                f.push(Instruction::StructGet {
+                    struct_type_index: struct_type(index),
+                    field_index: self.slot(index, *offset),
+                });
+            }
+            IRExprKind::Index { list, index } => {
+                self.compile_element(list, index, f)?;
+                f.push(Instruction::ArrayGet(element_kind(&list.ty).array()));
+                self.emit_from_storage(&expr.ty, f);
+            }
+            IRExprKind::ArrayIndex {
+                array,
+                index,
+                length,
+            } => {
+                self.compile_expr(array, f)?;
+                self.compile_expr(index, f)?;
+                self.emit_array_index(*length, f);
+                f.push(Instruction::ArrayGet(element_kind(&array.ty).array()));
+                self.emit_from_storage(&expr.ty, f);
+            }
+            IRExprKind::Slice { expr, start, end } => {
+                self.compile_expr(expr, f)?;
+                self.compile_expr(start, f)?;
+                self.compile_expr(end, f)?;
+                let slice = match &expr.ty.kind {
+                    TypeKind::String => Helper::BytesSlice,
+                    _ => Helper::ListSlice(element_kind(&expr.ty)),
+                };
+                let slice = self.helper(slice);
+                f.push(Instruction::Call(slice));
+            }
+            IRExprKind::List(elements) => {
+                let kind = element_kind(&expr.ty);
+                f.push(Instruction::I32Const(elements.len() as i32));
+                for element in elements {
+                    self.compile_expr(element, f)?;
+                    to_storage(f, &element.ty);
+                }
+                f.push(Instruction::ArrayNewFixed {
+                    array_type_index: kind.array(),
+                    array_size: elements.len() as u32,
+                });
+                f.push(Instruction::StructNew(kind.list()));
+            }
+            IRExprKind::Array(_) => {
+                return Err(CompilerError::Codegen {
+                    message: "Array literals can only initialize struct fields".to_string(),
+                })
+            }
+            IRExprKind::IndexReference { .. }
+            | IRExprKind::ArrayIndexReference { .. }
+            | IRExprKind::SliceReference { .. } => {
+                return Err(CompilerError::Codegen {
+                    message: "Element and slice references can only be assigned to".to_string(),
+                })
+            }
+            IRExprKind::UnwrapError(inside) => {
+                self.compile_unwrap(inside, TAG_ERROR, &expr.ty, f)?
+            }
+            IRExprKind::UnwrapNull(inside) => self.compile_unwrap(inside, TAG_NULL, &expr.ty, f)?,
+            IRExprKind::Match { .. } => return Err(unsupported("Match expressions")),
+            IRExprKind::AsyncState => return Err(unsupported("Await")),
+        }
+        Ok(())
+    }
+
+    /// Assigns `right` to the place `left` names, leaving the value.
+    fn compile_assignment(
+        &mut self,
+        left: &IRExpr,
+        right: &IRExpr,
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        if let IRExprKind::Local(index) = &left.node {
+            self.compile_expr(right, f)?;
+            f.push(Instruction::LocalTee(*index));
+            return Ok(());
+        }
+        if let IRExprKind::Global(index) = &left.node {
+            self.compile_expr(right, f)?;
+            f.push(Instruction::GlobalSet(*index));
+            f.push(Instruction::GlobalGet(*index));
+            return Ok(());
+        }
+        let value = f.scratch(self.valtype(&right.ty));
+        match &left.node {
+            IRExprKind::FieldReference { object, offset } => {
+                let index = self.struct_of(object);
+                self.compile_expr(object, f)?;
+                self.compile_field_value(right, f)?;
+                f.push(Instruction::LocalTee(value));
+                f.push(Instruction::StructSet {
+                    struct_type_index: struct_type(index),
+                    field_index: self.slot(index, *offset),
+                });
+            }
+            IRExprKind::IndexReference { list, index } => {
+                self.compile_element(list, index, f)?;
+                self.compile_expr(right, f)?;
+                f.push(Instruction::LocalTee(value));
+                to_storage(f, &right.ty);
+                f.push(Instruction::ArraySet(element_kind(&list.ty).array()));
+            }
+            IRExprKind::ArrayIndexReference {
+                array,
+                index,
+                length,
+            } => {
+                self.compile_expr(array, f)?;
+                self.compile_expr(index, f)?;
+                self.emit_array_index(*length, f);
+                self.compile_expr(right, f)?;
+                f.push(Instruction::LocalTee(value));
+                to_storage(f, &right.ty);
+                f.push(Instruction::ArraySet(element_kind(&array.ty).array()));
+            }
+            IRExprKind::SliceReference { .. } => return Err(unsupported("Assigning to a slice")),
+            _ => {
+                return Err(CompilerError::Codegen {
+                    message: "Can only assign to locals, fields and elements".to_string(),
+                })
+            }
+        }
+        f.push(Instruction::LocalGet(value));
+        f.release(value);
+        Ok(())
+    }
+
+    fn compile_binary(
+        &mut self,
+        op: &BinaryOp,
+        operands: &Type,
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        let float = operands.kind == TypeKind::Float;
+        let instruction = match op {
+            BinaryOp::Plus => match &operands.kind {
+                TypeKind::Float => Instruction::F64Add,
+                TypeKind::String => Instruction::Call(self.helper(Helper::Concat)),
+                TypeKind::List { element } => {
+                    Instruction::Call(self.helper(Helper::ListConcat(Kind::of(element))))
+                }
+                _ => Instruction::I64Add,
+            },
+            BinaryOp::Minus if float => Instruction::F64Sub,
+            BinaryOp::Minus => Instruction::I64Sub,
+            BinaryOp::Multiply if float => Instruction::F64Mul,
+            BinaryOp::Multiply => match &operands.kind {
+                TypeKind::String => Instruction::Call(self.helper(Helper::BytesRepeat)),
+                TypeKind::List { element } => {
+                    Instruction::Call(self.helper(Helper::ListRepeat(Kind::of(element))))
+                }
+                _ => Instruction::I64Mul,
+            },
+            BinaryOp::Divide if float => Instruction::F64Div,
+            BinaryOp::Divide => Instruction::I64DivS,
+            BinaryOp::Modulo => Instruction::I64RemS,
+            BinaryOp::BitwiseAnd => Instruction::I64And,
+            BinaryOp::BitwiseOr => Instruction::I64Or,
+            BinaryOp::Xor => Instruction::I64Xor,
+            BinaryOp::Sll => Instruction::I64Shl,
+            BinaryOp::Sra => Instruction::I64ShrS,
+            BinaryOp::Srl => Instruction::I64ShrU,
+            BinaryOp::Lt if float => Instruction::F64Lt,
+            BinaryOp::Lt => Instruction::I64LtS,
+            BinaryOp::Gt if float => Instruction::F64Gt,
+            BinaryOp::Gt => Instruction::I64GtS,
+            BinaryOp::Lte if float => Instruction::F64Le,
+            BinaryOp::Lte => Instruction::I64LeS,
+            BinaryOp::Gte if float => Instruction::F64Ge,
+            BinaryOp::Gte => Instruction::I64GeS,
+            BinaryOp::Eq | BinaryOp::Neq => {
+                let equal = match &operands.kind {
+                    _ if operands.nullable || operands.errorable => Instruction::RefEq,
+                    TypeKind::String => Instruction::Call(self.helper(Helper::BytesEqual)),
+                    TypeKind::List { element } => {
+                        Instruction::Call(self.helper(Helper::ListEqual(Kind::of(element))))
+                    }
+                    _ => match self.valtype(operands) {
+                        ValType::I32 => Instruction::I32Eq,
+                        ValType::I64 => Instruction::I64Eq,
+                        ValType::F64 => Instruction::F64Eq,
+                        _ => Instruction::RefEq,
+                    },
+                };
+                f.push(equal);
+                if *op == BinaryOp::Neq {
+                    f.push(Instruction::I32Eqz);
+                }
+                return Ok(());
+            }
+            _ => {
+                return Err(CompilerError::Codegen {
+                    message: format!("Unsupported binary operation: {:?}", op),
+                })
+            }
+        };
+        f.push(instruction);
+        Ok(())
+    }
+
+    fn compile_builtin(
+        &mut self,
+        builtin: Builtin,
+        args: &[IRExpr],
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        match builtin {
+            Builtin::ListPush => {
+                self.compile_expr(&args[0], f)?;
+                self.compile_expr(&args[1], f)?;
+                to_storage(f, &args[1].ty);
+                let push = self.helper(Helper::ListPush(element_kind(&args[0].ty)));
+                f.push(Instruction::Call(push));
+            }
+            Builtin::Repeat => {
+                // A list of `count` copies of `value`.
+                let kind = Kind::of(&args[0].ty);
+                let value = f.scratch(kind.storage());
+                let count = f.scratch(ValType::I32);
+                self.compile_expr(&args[0], f)?;
+                to_storage(f, &args[0].ty);
+                f.push(Instruction::LocalSet(value));
+                self.compile_expr(&args[1], f)?;
+                emit_length(f, count);
+                f.push(Instruction::LocalGet(count));
+                f.push(Instruction::LocalGet(value));
+                f.push(Instruction::LocalGet(count));
+                f.push(Instruction::ArrayNew(kind.array()));
+                f.push(Instruction::StructNew(kind.list()));
+                f.release(value);
+                f.release(count);
+            }
+            Builtin::BuilderAppend | Builtin::BuilderToString => {
+                for arg in args {
+                    self.compile_expr(arg, f)?;
+                }
+                let helper = match builtin {
+                    Builtin::BuilderAppend => Helper::BuilderAppend,
+                    _ => Helper::BuilderToString,
+                };
+                let helper = self.helper(helper);
+                f.push(Instruction::Call(helper));
+            }
+            Builtin::ToInteger | Builtin::ToFloat | Builtin::Present | Builtin::Holds => {
+                self.compile_expr(&args[0], f)?;
+                match builtin {
+                    Builtin::ToInteger => f.push(Instruction::I64TruncSatF64S),
+                    Builtin::ToFloat => f.push(Instruction::F64ConvertI64S),
+                    _ => {
+                        f.push(Instruction::StructGet {
+                            struct_type_index: ty::BOX,
+                            field_index: field::TAG,
+                        });
+                        if builtin == Builtin::Present {
+                            f.push(Instruction::I64Const(TAG_NULL));
+                            f.push(Instruction::I64Ne);
+                        } else {
+                            f.push(Instruction::I64Const(TAG_PRIMITIVE));
+                            f.push(Instruction::I64GeU);
+                        }
+                    }
+                }
+            }
+            Builtin::HoldsError => {
+                let IRExprKind::Integer(index) = args[1].node else {
+                    unreachable!("errors are matched by struct index")
+                };
+                let boxed = f.scratch(reference(ty::BOX));
+                self.compile_expr(&args[0], f)?;
+                f.push(Instruction::LocalTee(boxed));
+                f.push(Instruction::StructGet {
+                    struct_type_index: ty::BOX,
+                    field_index: field::TAG,
+                });
+                f.push(Instruction::I64Const(TAG_ERROR));
+                f.push(Instruction::I64Eq);
+                f.push(Instruction::If(BlockType::Result(ValType::I32)));
+                f.push(Instruction::LocalGet(boxed));
+                f.push(Instruction::StructGet {
+                    struct_type_index: ty::BOX,
+                    field_index: field::VALUE,
+                });
+                f.push(Instruction::RefTestNonNull(HeapType::Concrete(
+                    struct_type(index as u32),
+                )));
+                f.push(Instruction::Else);
+                f.push(Instruction::I32Const(0));
+                f.push(Instruction::End);
+                f.release(boxed);
+            }
+            Builtin::HeldError => unreachable!("compiled with its result type"),
+            Builtin::Abs | Builtin::Min | Builtin::Max if args[0].ty.kind == TypeKind::Integer => {
+                // Each number is read twice: [a, b, a < b] for min, say.
+                let a = f.scratch(ValType::I64);
+                let b = f.scratch(ValType::I64);
+                self.compile_expr(&args[0], f)?;
+                f.push(Instruction::LocalSet(a));
+                match args.get(1) {
+                    Some(arg) => self.compile_expr(arg, f)?,
+                    None => {
+                        f.push(Instruction::I64Const(0));
+                        f.push(Instruction::LocalGet(a));
+                        f.push(Instruction::I64Sub);
+                    }
+                }
+                f.push(Instruction::LocalSet(b));
+                f.push(Instruction::LocalGet(a));
+                f.push(Instruction::LocalGet(b));
+                f.push(Instruction::LocalGet(a));
+                f.push(Instruction::LocalGet(b));
+                // abs picks x over -x when x > -x.
+                f.push(if builtin == Builtin::Min {
+                    Instruction::I64LtS
+                } else {
+                    Instruction::I64GtS
+                });
+                f.push(Instruction::Select);
+                f.release(a);
+                f.release(b);
+            }
+            Builtin::Abs
+            | Builtin::Min
+            | Builtin::Max
+            | Builtin::Sqrt
+            | Builtin::Floor
+            | Builtin::Ceil => {
+                for arg in args {
+                    self.compile_expr(arg, f)?;
+                }
+                f.push(match builtin {
+                    Builtin::Abs => Instruction::F64Abs,
+                    Builtin::Min => Instruction::F64Min,
+                    Builtin::Max => Instruction::F64Max,
+                    Builtin::Sqrt => Instruction::F64Sqrt,
+                    Builtin::Floor => Instruction::F64Floor,
+                    _ => Instruction::F64Ceil,
+                });
+            }
+            Builtin::Round
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Tan
+            | Builtin::Asin
+            | Builtin::Acos
+            | Builtin::Atan
+            | Builtin::Atan2 => return Err(unsupported("Rounding and trigonometry")),
+            Builtin::Sleep | Builtin::Fetch => return Err(unsupported("Await")),
+            Builtin::Exit => return Err(unsupported("Exit")),
+            Builtin::Env => return Err(unsupported("Env")),
+            Builtin::StringLength
+            | Builtin::StringFind
+            | Builtin::StringSubstring
+            | Builtin::StringSplit
+            | Builtin::StringToUpper
+            | Builtin::StringToLower
+            | Builtin::StringTrim
+            | Builtin::StringReplace
+            | Builtin::Ord
+            | Builtin::Chr => return Err(unsupported("The string library")),
+            Builtin::HeapUsed
+            | Builtin::HeapFree
+            | Builtin::GcCount
+            | Builtin::AllocatedSinceGc
+            | Builtin::LargestFreeBlock => return Err(unsupported("Gcstats")),
+            Builtin::Collect => return Err(unsupported("gc()")),
+            Builtin::SetGcThreshold => return Err(unsupported("set_gc_threshold()")),
+            Builtin::JsonReset
+            | Builtin::JsonFail
+            | Builtin::JsonFinish
+            | Builtin::JsonEat
+            | Builtin::JsonExpect
+            | Builtin::JsonNull
+            | Builtin::JsonBoolean
+            | Builtin::JsonInteger
+            | Builtin::JsonFloat
+            | Builtin::JsonString
+            | Builtin::JsonSkip
+            | Builtin::JsonQuote => return Err(unsupported("JSON")),
+            Builtin::ParseInteger | Builtin::ParseFloat | Builtin::ParseFailed => {
+                return Err(unsupported("Parsing numbers"))
+            }
+            Builtin::CompareStrings => return Err(unsupported("Sorting strings")),
+        }
+        Ok(())
+    }
+
+    /// A field's value, building a new array for an array literal.
+    fn compile_field_value(&mut self, value: &IRExpr, f: &mut Body) -> Result<(), CompilerError> {
+        let IRExprKind::Array(elements) = &value.node else {
+            return self.compile_expr(value, f);
+        };
+        for element in elements {
+            self.compile_expr(element, f)?;
+            to_storage(f, &element.ty);
+        }
+        f.push(Instruction::ArrayNewFixed {
+            array_type_index: element_kind(&value.ty).array(),
+            array_size: elements.len() as u32,
+        });
+        Ok(())
+    }
+
+    /// Leaves the elements of `list` and the position of element `index`
+    /// in them, trapping outside the list unless unchecked.
+    fn compile_element(
+        &mut self,
+        list: &IRExpr,
+        index: &IRExpr,
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        let list_type = element_kind(&list.ty).list();
+        self.compile_expr(list, f)?;
+        if self.unchecked {
+            f.push(Instruction::StructGet {
+                struct_type_index: list_type,
+                field_index: field::ITEMS,
+            });
+            self.compile_expr(index, f)?;
+            f.push(Instruction::I32WrapI64);
+            return Ok(());
+        }
+
+        let held = f.scratch(reference(list_type));
+        let position = f.scratch(ValType::I64);
+        f.push(Instruction::LocalTee(held));
+        f.push(Instruction::StructGet {
+            struct_type_index: list_type,
+            field_index: field::ITEMS,
+        });
+        self.compile_expr(index, f)?;
+        f.push(Instruction::LocalTee(position));
+        f.push(Instruction::LocalGet(held));
+        f.push(Instruction::StructGet {
+            struct_type_index: list_type,
+            field_index: field::LENGTH,
+        });
+        f.push(Instruction::I64ExtendI32U);
+        f.push(Instruction::I64GeU);
+        f.push(Instruction::If(BlockType::Empty));
+        f.push(Instruction::Unreachable);
+        f.push(Instruction::End);
+        f.push(Instruction::LocalGet(position));
+        f.push(Instruction::I32WrapI64);
+        f.release(held);
+        f.release(position);
+        Ok(())
+    }
+
+    /// Turns the `i64` index on the stack into a position in a fixed array
+    /// of `length` elements, trapping outside it unless unchecked.
+    fn emit_array_index(&mut self, length: u32, f: &mut Body) {
+        if !self.unchecked {
+            let position = f.scratch(ValType::I64);
+            f.push(Instruction::LocalTee(position));
+            f.push(Instruction::I64Const(length as i64));
+            f.push(Instruction::I64GeU);
+            f.push(Instruction::If(BlockType::Empty));
+            f.push(Instruction::Unreachable);
+            f.push(Instruction::End);
+            f.push(Instruction::LocalGet(position));
+            f.release(position);
+        }
+        f.push(Instruction::I32WrapI64);
+    }
+
+    /// Traps when the box `inside` evaluates to has `tag`, and otherwise
+    /// leaves its value, still boxed if `result` is still nullable or
+    /// errorable.
+    fn compile_unwrap(
+        &mut self,
+        inside: &IRExpr,
+        tag: i64,
+        result: &Type,
+        f: &mut Body,
+    ) -> Result<(), CompilerError> {
+        let boxed = f.scratch(reference(ty::BOX));
+        self.compile_expr(inside, f)?;
+        f.push(Instruction::LocalTee(boxed));
+        f.push(Instruction::StructGet {
+            struct_type_index: ty::BOX,
+            field_index: field::TAG,
+        });
+        f.push(Instruction::I64Const(tag));
+        f.push(Instruction::I64Eq);
+        f.push(Instruction::If(BlockType::Empty));
+        f.push(Instruction::Unreachable);
+        f.push(Instruction::End);
+        f.push(Instruction::LocalGet(boxed));
+        if !result.nullable && !result.errorable {
+            self.emit_unbox(result, f);
+        }
+        f.release(boxed);
+        Ok(())
+    }
+
+    /// Replaces the box on the stack with the `ty` value it holds.
+    fn emit_unbox(&self, ty: &Type, f: &mut Body) {
+        if Kind::of(ty) == Kind::Primitive {
+            f.push(Instruction::StructGet {
+                struct_type_index: ty::BOX,
+                field_index: field::BITS,
+            });
+            from_bits(f, ty);
+        } else {
+            f.push(Instruction::StructGet {
+                struct_type_index: ty::BOX,
+                field_index: field::VALUE,
+            });
+            self.emit_cast(ty, f);
+        }
+    }
+
+    /// Casts an `eqref` on the stack down to the type of `ty` values.
+    fn emit_cast(&self, ty: &Type, f: &mut Body) {
+        if let ValType::Ref(RefType {
+            heap_type: heap_type @ HeapType::Concrete(_),
+            ..
+        }) = self.valtype(ty)
+        {
+            f.push(Instruction::RefCastNullable(heap_type));
+        }
+    }
+
+    /// Converts a list or array element of type `ty` from its storage.
+    fn emit_from_storage(&self, ty: &Type, f: &mut Body) {
+        match Kind::of(ty) {
+            Kind::Primitive => from_bits(f, ty),
+            Kind::Reference => self.emit_cast(ty, f),
+        }
+    }
+
+    pub(super) fn emit_string(&mut self, s: &str, f: &mut Body) {
+        let segment = self.string_segment(s);
+        f.push(Instruction::I32Const(0));
+        f.push(Instruction::I32Const(s.len() as i32));
+        f.push(Instruction::ArrayNewData {
+            array_type_index: ty::BYTES,
+            array_data_index: segment,
+        });
+    }
+
+    /// Replaces the value of type `ty` on the stack with its string form.
+    /// Nullable and errorable values print as `null` or `error(Name)` when
+    /// they don't hold a value.
+    fn emit_stringify(&mut self, ty: &Type, f: &mut Body) -> Result<(), CompilerError> {
+        if ty.nullable || ty.errorable {
+            return self.emit_stringify_wrapped(ty, f);
+        }
+        match ty.kind {
+            TypeKind::Integer | TypeKind::BitField { .. } => {
+                let itoa = self.helper(Helper::Itoa);
+                f.push(Instruction::Call(itoa));
+            }
+            TypeKind::Float => {
+                let ftoa = self.helper(Helper::Ftoa);
+                f.push(Instruction::Call(ftoa));
+            }
+            TypeKind::Boolean => {
+                f.push(Instruction::If(BlockType::Result(reference(ty::BYTES))));
+                self.emit_string("true", f);
+                f.push(Instruction::Else);
+                self.emit_string("false", f);
+                f.push(Instruction::End);
+            }
+            TypeKind::String => {}
+            _ => {
+                return Err(CompilerError::Codegen {
+                    message: format!("Cannot stringify type {:?}", ty),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn emit_stringify_wrapped(&mut self, ty: &Type, f: &mut Body) -> Result<(), CompilerError> {
+        let value_ty = Type {
+            kind: ty.kind.clone(),
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        };
+        let string = BlockType::Result(reference(ty::BYTES));
+        let boxed = f.scratch(reference(ty::BOX));
+        f.push(Instruction::LocalTee(boxed));
+        f.push(Instruction::StructGet {
+            struct_type_index: ty::BOX,
+            field_index: field::TAG,
+        });
+        f.push(Instruction::I64Const(TAG_NULL));
+        f.push(Instruction::I64Eq);
+        f.push(Instruction::If(string));
+        if ty.nullable {
+            self.emit_string("null", f);
+        } else {
+            f.push(Instruction::Unreachable);
+        }
+        f.push(Instruction::Else);
+
+        f.push(Instruction::LocalGet(boxed));
+        f.push(Instruction::StructGet {
+            struct_type_index: ty::BOX,
+            field_index: field::TAG,
+        });
+        f.push(Instruction::I64Const(TAG_ERROR));
+        f.push(Instruction::I64Eq);
+        f.push(Instruction::If(string));
+        // Name the error after the struct type it has.
+        let errors = self.error_types.clone();
+        for (error_type, name) in &errors {
+            f.push(Instruction::LocalGet(boxed));
+            f.push(Instruction::StructGet {
+                struct_type_index: ty::BOX,
+                field_index: field::VALUE,
+            });
+            f.push(Instruction::RefTestNonNull(HeapType::Concrete(*error_type)));
+            f.push(Instruction::If(string));
+            self.emit_string(&format!("error({})", name), f);
+            f.push(Instruction::Else);
+        }
+        f.push(Instruction::Unreachable);
+        for _ in &errors {
+            f.push(Instruction::End);
+        }
+        f.push(Instruction::Else);
+
+        f.push(Instruction::LocalGet(boxed));
+        self.emit_unbox(&value_ty, f);
+        self.emit_stringify(&value_ty, f)?;
+        f.push(Instruction::End);
+
+        f.push(Instruction::End);
+        f.release(boxed);
+        Ok(())
+    }
+}
+
+/// The kind of the elements of a list or array of type `ty`.
+pub(super) fn element_kind(ty: &Type) -> Kind {
+    match &ty.kind {
+        TypeKind::List { element } | TypeKind::Array { element, .. } => Kind::of(element),
+        _ => panic!("expected a list or array, found {:?}", ty),
+    }
+}
+
+/// Converts a value of type `ty` to its list element storage.
+pub(super) fn to_storage(f: &mut Body, ty: &Type) {
+    if Kind::of(ty) == Kind::Primitive {
+        to_bits(f, ty);
+    }
+}
+
+/// Converts a primitive value of type `ty` to the `i64` holding its bits.
+fn to_bits(f: &mut Body, ty: &Type) {
+    match ty.kind {
+        TypeKind::Float => f.push(Instruction::I64ReinterpretF64),
+        TypeKind::Boolean => f.push(Instruction::I64ExtendI32U),
+        _ => {}
+    }
+}
+
+fn from_bits(f: &mut Body, ty: &Type) {
+    match ty.kind {
+        TypeKind::Float => f.push(Instruction::F64ReinterpretI64),
+        TypeKind::Boolean => f.push(Instruction::I32WrapI64),
+        _ => {}
+    }
+}
+
+/// Turns the `i64` count on the stack into a length in `local`, trapping
+/// when it is negative or too long for an array.
+fn emit_length(f: &mut Body, local: u32) {
+    let count = f.scratch(ValType::I64);
+    f.push(Instruction::LocalTee(count));
+    f.push(Instruction::I64Const(i32::MAX as i64));
+    f.push(Instruction::I64GtU);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::Unreachable);
+    f.push(Instruction::End);
+    f.push(Instruction::LocalGet(count));
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::LocalSet(local));
+    f.release(count);
+}
+
+/// Pushes the zero value of `valtype`, as a function without a value
+/// returns.
+pub(super) fn default_value(f: &mut Body, valtype: ValType) {
+    match valtype {
+        ValType::I32 => f.push(Instruction::I32Const(0)),
+        ValType::F64 => f.push(Instruction::F64Const(wasm_encoder::Ieee64::from(0.0))),
+        ValType::Ref(RefType { heap_type, .. }) => f.push(Instruction::RefNull(heap_type)),
+        _ => f.push(Instruction::I64Const(0)),
+    }
+}
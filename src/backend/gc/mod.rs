@@ -0,0 +1,751 @@
+mod body;
+mod expr;
+mod runtime;
+mod stmt;
+
+use crate::ast::{
+    Extern, IRExpr, IRExprKind, IRFunction, IRProgram, IRStmt, IRStruct, IRStructKind, Type,
+    TypeKind,
+};
+use crate::error::CompilerError;
+use super::names::{struct_name, Names};
+use super::{check_locals, CodegenOptions};
+use std::collections::HashMap;
+use wasm_encoder::{
+    AbstractHeapType, CodeSection, CompositeInnerType, CompositeType, ConstExpr, DataCountSection,
+    DataSection, ElementSection, Elements, EntityType, ExportKind, ExportSection, FieldType,
+    FunctionSection, GlobalSection, GlobalType, HeapType, ImportSection, Instruction, MemorySection, MemoryType, Module,
+    RefType, StorageType, StructType, SubType, TableSection, TableType, TypeSection, ValType,
+};
+
+use body::Body;
+use runtime::Helper;
+
+/// Indices of the types every module declares. They share one recursive
+/// group with the program's structs, which keeps each distinct from the
+/// others even where two have the same fields.
+mod ty {
+    /// The bytes of a string.
+    pub const BYTES: u32 = 0;
+    /// The elements of a list or fixed array of primitive values.
+    pub const PRIMITIVES: u32 = 1;
+    /// The elements of a list or fixed array of references.
+    pub const REFERENCES: u32 = 2;
+    /// A list: its length and its elements, which may have room for more.
+    pub const PRIMITIVE_LIST: u32 = 3;
+    pub const REFERENCE_LIST: u32 = 4;
+    /// A function value: its index in the table and its environment.
+    pub const CLOSURE: u32 = 5;
+    /// A nullable or errorable value: its tag, and the value as bits or as
+    /// a reference. This is the IR's tagged union, struct 0.
+    pub const BOX: u32 = 6;
+    /// The program's other structs, in order.
+    pub const STRUCTS: u32 = 7;
+}
+
+/// Field indices of the types above.
+mod field {
+    pub const LENGTH: u32 = 0;
+    pub const ITEMS: u32 = 1;
+    pub const FUNCTION: u32 = 0;
+    pub const ENV: u32 = 1;
+    pub const TAG: u32 = 0;
+    pub const BITS: u32 = 1;
+    pub const VALUE: u32 = 2;
+}
+
+/// Tags of the tagged union that unwrapping and stringifying tell apart.
+/// Every tag from `TAG_PRIMITIVE` on holds a value.
+const TAG_NULL: i64 = 0;
+const TAG_ERROR: i64 = 1;
+const TAG_PRIMITIVE: i64 = 2;
+
+/// The host's `print`, the module's only import besides its externs.
+const PRINT_IMPORT: u32 = 0;
+
+/// How list and fixed array elements are stored: primitives as the bits of
+/// an `i64`, the way the runtime stores them, and everything else as an
+/// `eqref` cast back to its type on the way out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Primitive,
+    Reference,
+}
+
+impl Kind {
+    fn of(ty: &Type) -> Kind {
+        match ty.kind {
+            _ if ty.nullable || ty.errorable => Kind::Reference,
+            TypeKind::Integer | TypeKind::Float | TypeKind::Boolean | TypeKind::BitField { .. } => {
+                Kind::Primitive
+            }
+            _ => Kind::Reference,
+        }
+    }
+
+    fn array(self) -> u32 {
+        match self {
+            Kind::Primitive => ty::PRIMITIVES,
+            Kind::Reference => ty::REFERENCES,
+        }
+    }
+
+    fn list(self) -> u32 {
+        match self {
+            Kind::Primitive => ty::PRIMITIVE_LIST,
+            Kind::Reference => ty::REFERENCE_LIST,
+        }
+    }
+
+    fn storage(self) -> ValType {
+        match self {
+            Kind::Primitive => ValType::I64,
+            Kind::Reference => ValType::Ref(RefType::EQREF),
+        }
+    }
+}
+
+/// A nullable reference to the type at `index`.
+fn reference(index: u32) -> ValType {
+    ValType::Ref(RefType {
+        nullable: true,
+        heap_type: HeapType::Concrete(index),
+    })
+}
+
+/// `structref`, the type of every function's environment.
+const STRUCTREF: ValType = ValType::Ref(RefType {
+    nullable: true,
+    heap_type: HeapType::Abstract {
+        shared: false,
+        ty: AbstractHeapType::Struct,
+    },
+});
+
+/// The params every program function takes before its own: the scratch
+/// values and the closure environment, as in the linear memory backend.
+const CALLING_CONVENTION: [ValType; 3] = [ValType::I32, ValType::I64, STRUCTREF];
+
+fn unsupported(what: &str) -> CompilerError {
+    CompilerError::Codegen {
+        message: format!("{} isn't supported by the wasm-gc backend yet", what),
+    }
+}
+
+/// The module's function types, each distinct signature listed once after
+/// the recursive group.
+struct FunctionTypes {
+    first: u32,
+    signatures: Vec<(Vec<ValType>, Vec<ValType>)>,
+    indices: HashMap<(Vec<ValType>, Vec<ValType>), u32>,
+}
+
+impl FunctionTypes {
+    fn add(&mut self, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+        let next = self.first + self.signatures.len() as u32;
+        *self
+            .indices
+            .entry((params.clone(), results.clone()))
+            .or_insert_with(|| {
+                self.signatures.push((params, results));
+                next
+            })
+    }
+}
+
+/// An `extern fn` the program calls, imported after `print`.
+struct ExternImport {
+    function: Extern,
+    params: Vec<ValType>,
+    result: ValType,
+}
+
+/// Generates a module for the WebAssembly GC proposal instead of the
+/// runtime's heaps: structs, lists, strings and closures become GC structs
+/// and arrays that the engine collects, so the module imports nothing but
+/// `print` and its externs, and needs no shadow stack. Its one memory only
+/// passes strings to `print`, and is exported where the runtime's string
+/// heap would be.
+pub struct GcCodegen {
+    structs: Vec<IRStruct>,
+    /// The struct each struct and error name refers to. Captures structs
+    /// are named after their functions, so user structs take precedence.
+    struct_names: HashMap<String, u32>,
+    /// Type index and name of every error struct, for stringifying errors.
+    error_types: Vec<(u32, String)>,
+    /// The prelude's `Builder`.
+    builder: u32,
+    externs: Vec<ExternImport>,
+    types: FunctionTypes,
+    /// Contents of string literals, each a passive data segment that
+    /// `array.new_data` copies from.
+    strings: Vec<Vec<u8>>,
+    string_indices: HashMap<Vec<u8>, u32>,
+    /// Helper functions in the order of their first use, after every other
+    /// function.
+    helpers: Vec<Helper>,
+    first_helper: u32,
+    /// Set while compiling the body of an `unchecked` block.
+    unchecked: bool,
+    /// Blocks, loops and ifs entered in the current function, and the depth
+    /// inside each enclosing loop, so `break` and `continue` can count out
+    /// to it.
+    block_depth: u32,
+    loop_depths: Vec<u32>,
+    /// What the current function returns.
+    returns: ValType,
+    /// The captures struct each closure's function gets as its
+    /// environment, by function index, from the `LocalClosure` that makes
+    /// it. The IR's types for local 2 don't always name it.
+    environments: HashMap<u32, u32>,
+    /// The environment of the current function.
+    env: Option<u32>,
+}
+
+impl GcCodegen {
+    pub fn new(program: &IRProgram) -> Self {
+        let mut struct_names = HashMap::new();
+        for (index, ir_struct) in program.structs.iter().enumerate() {
+            let captures = matches!(ir_struct.kind, IRStructKind::Captures);
+            if !captures || !struct_names.contains_key(&ir_struct.name) {
+                struct_names.insert(ir_struct.name.clone(), index as u32);
+            }
+        }
+        let error_types = program
+            .structs
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| matches!(s.kind, IRStructKind::Error))
+            .map(|(index, s)| (struct_type(index as u32), s.name.clone()))
+            .collect();
+        let mut environments = HashMap::new();
+        for func in &program.functions {
+            collect_environments(&func.body, &mut environments);
+        }
+        GcCodegen {
+            builder: struct_names["Builder"],
+            structs: program.structs.clone(),
+            struct_names,
+            error_types,
+            externs: vec![],
+            types: FunctionTypes {
+                first: ty::STRUCTS + program.structs.len().saturating_sub(1) as u32,
+                signatures: vec![],
+                indices: HashMap::new(),
+            },
+            strings: vec![],
+            string_indices: HashMap::new(),
+            helpers: vec![],
+            first_helper: 0,
+            unchecked: false,
+            block_depth: 0,
+            loop_depths: vec![],
+            returns: ValType::I64,
+            environments,
+            env: None,
+        }
+    }
+
+    /// The type a value of `ty` has in the module.
+    fn valtype(&self, ty: &Type) -> ValType {
+        if ty.nullable || ty.errorable {
+            return reference(ty::BOX);
+        }
+        match &ty.kind {
+            TypeKind::Integer | TypeKind::BitField { .. } => ValType::I64,
+            TypeKind::Float => ValType::F64,
+            TypeKind::Boolean => ValType::I32,
+            TypeKind::String => reference(ty::BYTES),
+            TypeKind::List { element } => reference(Kind::of(element).list()),
+            TypeKind::Array { element, .. } => reference(Kind::of(element).array()),
+            TypeKind::Struct { name } | TypeKind::Error { name } => {
+                reference(self.struct_type(name))
+            }
+            TypeKind::Function { .. } => reference(ty::CLOSURE),
+            TypeKind::Null | TypeKind::Unknown => ValType::Ref(RefType::EQREF),
+        }
+    }
+
+    fn struct_type(&self, name: &str) -> u32 {
+        struct_type(self.struct_names[name])
+    }
+
+    /// The field of struct `index` at `offset`: one per slot, so fields
+    /// packed into the same slot share it.
+    fn slot(&self, index: u32, offset: u32) -> u32 {
+        let offsets = slot_offsets(&self.structs[index as usize]);
+        offsets
+            .iter()
+            .position(|&o| o == offset)
+            .expect("fields are at slot offsets") as u32
+    }
+
+    /// The struct type of values of `ty`, which is a struct.
+    fn struct_of(&self, object: &IRExpr) -> u32 {
+        if let (IRExprKind::Local(2), Some(env)) = (&object.node, self.env) {
+            return env;
+        }
+        match &object.ty.kind {
+            TypeKind::Struct { name } | TypeKind::Error { name } => self.struct_names[name],
+            _ => panic!("expected a struct, found {:?}", object.ty),
+        }
+    }
+
+    /// The function index of the program's first function, `main`, after
+    /// the print import and the program's externs.
+    fn first_function(&self) -> u32 {
+        1 + self.externs.len() as u32
+    }
+
+    /// The type `call_indirect` checks a call to a value of `ty` against.
+    fn function_type(&mut self, ty: &Type) -> u32 {
+        let TypeKind::Function { params, returns } = &ty.kind else {
+            panic!("expected a function, found {:?}", ty);
+        };
+        let mut valtypes = CALLING_CONVENTION.to_vec();
+        valtypes.extend(params.iter().map(|param| self.valtype(param)));
+        let result = self.valtype(returns);
+        self.types.add(valtypes, vec![result])
+    }
+
+    /// The data segment holding `s`, added if new.
+    fn string_segment(&mut self, s: &str) -> u32 {
+        let next = self.strings.len() as u32;
+        *self
+            .string_indices
+            .entry(s.as_bytes().to_vec())
+            .or_insert_with(|| {
+                self.strings.push(s.as_bytes().to_vec());
+                next
+            })
+    }
+
+    /// The function index of `helper`, which is generated after the
+    /// program's functions.
+    fn helper(&mut self, helper: Helper) -> u32 {
+        let position = match self.helpers.iter().position(|&h| h == helper) {
+            Some(position) => position,
+            None => {
+                self.helpers.push(helper);
+                self.helpers.len() - 1
+            }
+        };
+        self.first_helper + position as u32
+    }
+
+    /// Imports every `extern fn` the program calls. Only numbers and
+    /// booleans cross into the host, having no reference in the host's
+    /// terms.
+    fn collect_externs(&mut self, program: &IRProgram) -> Result<(), CompilerError> {
+        let mut calls = vec![];
+        for func in &program.functions {
+            for stmt in &func.body {
+                stmt.visit_exprs(&mut |expr| {
+                    if let IRExprKind::ExternCall { function, args } = &expr.node {
+                        calls.push((function.clone(), args.clone(), expr.ty.clone()));
+                    }
+                });
+            }
+        }
+        for (function, args, returns) in calls {
+            if self.externs.iter().any(|e| e.function == function) {
+                continue;
+            }
+            let primitive = |ty: &Type| Kind::of(ty) == Kind::Primitive;
+            if !args.iter().all(|arg| primitive(&arg.ty)) || !primitive(&returns) {
+                return Err(unsupported(&format!(
+                    "Extern function '{}' with strings, lists or structs",
+                    function.name
+                )));
+            }
+            self.externs.push(ExternImport {
+                params: args.iter().map(|arg| self.valtype(&arg.ty)).collect(),
+                result: self.valtype(&returns),
+                function,
+            });
+        }
+        Ok(())
+    }
+
+    fn extern_index(&self, function: &Extern) -> u32 {
+        let position = self.externs.iter().position(|e| e.function == *function);
+        1 + position.expect("externs are collected before codegen") as u32
+    }
+
+    /// The recursive group of the types every module declares and of the
+    /// program's structs.
+    fn struct_types(&self) -> Vec<SubType> {
+        let field = |valtype: ValType| FieldType {
+            element_type: StorageType::Val(valtype),
+            mutable: true,
+        };
+        let array =
+            |element: FieldType| CompositeInnerType::Array(wasm_encoder::ArrayType(element));
+        let structure = |fields: Vec<FieldType>| {
+            CompositeInnerType::Struct(StructType {
+                fields: fields.into_boxed_slice(),
+            })
+        };
+
+        let mut types = vec![
+            array(FieldType {
+                element_type: StorageType::I8,
+                mutable: true,
+            }),
+            array(field(Kind::Primitive.storage())),
+            array(field(Kind::Reference.storage())),
+            structure(vec![field(ValType::I32), field(reference(ty::PRIMITIVES))]),
+            structure(vec![field(ValType::I32), field(reference(ty::REFERENCES))]),
+            structure(vec![field(ValType::I32), field(STRUCTREF)]),
+            structure(vec![
+                field(ValType::I64),
+                field(ValType::I64),
+                field(ValType::Ref(RefType::EQREF)),
+            ]),
+        ];
+        for ir_struct in self.structs.iter().skip(1) {
+            let fields = slot_offsets(ir_struct)
+                .into_iter()
+                .map(|offset| field(self.slot_type(ir_struct, offset)))
+                .collect();
+            types.push(structure(fields));
+        }
+
+        types
+            .into_iter()
+            .map(|inner| SubType {
+                is_final: true,
+                supertype_idx: None,
+                composite_type: CompositeType {
+                    inner,
+                    shared: false,
+                    descriptor: None,
+                    describes: None,
+                },
+            })
+            .collect()
+    }
+
+    /// The type of the slot at `offset`: an `i64` when fields are packed
+    /// into it, and the type of its field otherwise.
+    fn slot_type(&self, ir_struct: &IRStruct, offset: u32) -> ValType {
+        let fields: Vec<&Type> = ir_struct
+            .offsets
+            .iter()
+            .zip(&ir_struct.fields)
+            .filter(|(&o, _)| o == offset)
+            .map(|(_, (_, ty))| ty)
+            .collect();
+        match fields[..] {
+            [ty] => self.valtype(ty),
+            _ => ValType::I64,
+        }
+    }
+
+    pub fn compile(
+        &mut self,
+        program: &IRProgram,
+        options: CodegenOptions,
+    ) -> Result<Vec<u8>, CompilerError> {
+        let Some(main) = program.functions.first() else {
+            return Err(CompilerError::Codegen {
+                message: "Program has no main function".to_string(),
+            });
+        };
+        if !main.params.is_empty() {
+            return Err(unsupported("A main that takes arguments"));
+        }
+        if self.valtype(&main.returns) != ValType::I64 {
+            return Err(unsupported("A main that doesn't return an integer"));
+        }
+        // The engine reclaims structs without telling the program, so there
+        // is nowhere to run drop methods from.
+        if program.structs.iter().any(|s| s.drop.is_some()) {
+            return Err(unsupported("Drop methods"));
+        }
+        let exported: Vec<&IRFunction> = program.functions.iter().filter(|f| f.exported).collect();
+        for func in &exported {
+            let primitive = |ty: &Type| Kind::of(ty) == Kind::Primitive;
+            if !func.params.iter().all(primitive) || !primitive(&func.returns) {
+                return Err(unsupported(&format!(
+                    "Exporting '{}', which takes or returns strings, lists or structs,",
+                    func.name
+                )));
+            }
+        }
+        self.collect_externs(program)?;
+        // Imports come first in the function index space, so their types
+        // do too.
+        self.types.add(vec![ValType::I32], vec![]);
+        for i in 0..self.externs.len() {
+            let import = &self.externs[i];
+            self.types.add(import.params.clone(), vec![import.result]);
+        }
+
+        let first_function = self.first_function();
+        let main_wrapper = first_function + program.functions.len() as u32;
+        self.first_helper = main_wrapper + 1 + exported.len() as u32;
+
+        let mut bodies = vec![];
+        let mut function_types = vec![];
+        for func in &program.functions {
+            let mut params = CALLING_CONVENTION.to_vec();
+            params.extend(func.params.iter().map(|param| self.valtype(param)));
+            let result = self.valtype(&func.returns);
+            function_types.push(self.types.add(params.clone(), vec![result]));
+            let body = self.compile_function(func, params)?;
+            check_locals(&func.name, body.local_count(), &options)?;
+            bodies.push(body);
+        }
+
+        // `main` keeps the signature hosts call it with, and the wrappers of
+        // exported functions take just their Star parameters.
+        let main_type = self.types.add(
+            vec![ValType::I32, ValType::I64, ValType::I32],
+            vec![ValType::I64],
+        );
+        function_types.push(main_type);
+        bodies.push(wrapper(first_function, 3, 0));
+        for (position, func) in program.functions.iter().enumerate() {
+            if !func.exported {
+                continue;
+            }
+            let params: Vec<ValType> = func.params.iter().map(|p| self.valtype(p)).collect();
+            let result = self.valtype(&func.returns);
+            function_types.push(self.types.add(params.clone(), vec![result]));
+            let index = first_function + position as u32;
+            bodies.push(wrapper(index, params.len() as u32, params.len() as u32));
+        }
+
+        // Generating a helper may need more of them.
+        let mut next = 0;
+        while next < self.helpers.len() {
+            let helper = self.helpers[next];
+            let (params, results) = self.helper_signature(helper);
+            function_types.push(self.types.add(params.clone(), results));
+            bodies.push(self.compile_helper(helper, params));
+            next += 1;
+        }
+
+        let mut module = Module::new();
+
+        let mut types = TypeSection::new();
+        types.ty().rec(self.struct_types());
+        for (params, results) in &self.types.signatures {
+            types.ty().function(params.clone(), results.clone());
+        }
+        module.section(&types);
+
+        let mut imports = ImportSection::new();
+        let print_type = self.types.add(vec![ValType::I32], vec![]);
+        imports.import("env", "print", EntityType::Function(print_type));
+        for i in 0..self.externs.len() {
+            let import = &self.externs[i];
+            let ty = self.types.add(import.params.clone(), vec![import.result]);
+            let Extern { module, name } = &self.externs[i].function;
+            imports.import(module, name, EntityType::Function(ty));
+        }
+        module.section(&imports);
+
+        let mut functions = FunctionSection::new();
+        for ty in &function_types {
+            functions.function(*ty);
+        }
+        module.section(&functions);
+
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            minimum: program.functions.len() as u64,
+            maximum: Some(program.functions.len() as u64),
+            table64: false,
+            shared: false,
+        });
+        module.section(&tables);
+
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        module.section(&memories);
+
+        // The program's top-level variables, with the engine tracing what
+        // they hold like any other reference.
+        if !program.globals.is_empty() {
+            let mut globals = GlobalSection::new();
+            for (_, ty) in &program.globals {
+                let val_type = self.valtype(ty);
+                let init = match val_type {
+                    ValType::I32 => ConstExpr::i32_const(0),
+                    ValType::I64 => ConstExpr::i64_const(0),
+                    ValType::F64 => ConstExpr::f64_const(0.0.into()),
+                    ValType::Ref(reference) => ConstExpr::ref_null(reference.heap_type),
+                    _ => unreachable!("values are never {:?}", val_type),
+                };
+                globals.global(
+                    GlobalType {
+                        val_type,
+                        mutable: true,
+                        shared: false,
+                    },
+                    &init,
+                );
+            }
+            module.section(&globals);
+        }
+
+        let mut exports = ExportSection::new();
+        exports.export("main", ExportKind::Func, main_wrapper);
+        for (i, func) in exported.iter().enumerate() {
+            exports.export(&func.name, ExportKind::Func, main_wrapper + 1 + i as u32);
+        }
+        exports.export(crate::host::DALLOC_MEMORY_EXPORT, ExportKind::Memory, 0);
+        module.section(&exports);
+
+        let table: Vec<u32> = (first_function..main_wrapper).collect();
+        let mut elements = ElementSection::new();
+        elements.active(
+            Some(0),
+            &ConstExpr::i32_const(0),
+            Elements::Functions(std::borrow::Cow::Borrowed(&table)),
+        );
+        module.section(&elements);
+
+        module.section(&DataCountSection {
+            count: self.strings.len() as u32,
+        });
+
+        let mut codes = CodeSection::new();
+        for body in bodies {
+            codes.function(&body.finish());
+        }
+        module.section(&codes);
+
+        let mut data = DataSection::new();
+        for bytes in &self.strings {
+            data.passive(bytes.iter().copied());
+        }
+        module.section(&data);
+
+        module.section(&self.names(program, first_function).section());
+
+        Ok(module.finish())
+    }
+
+    /// Names the imports after what they import, the program's functions
+    /// and their locals after the source, and types after what they hold.
+    fn names(&self, program: &IRProgram, first_function: u32) -> Names {
+        let mut names = Names::default();
+        names.function(PRINT_IMPORT, "env.print");
+        for (i, import) in self.externs.iter().enumerate() {
+            let Extern { module, name } = &import.function;
+            names.function(1 + i as u32, &format!("{}.{}", module, name));
+        }
+        for (index, func) in program.functions.iter().enumerate() {
+            names.program_function(first_function + index as u32, func);
+        }
+        let wrappers = std::iter::once(&program.functions[0].name)
+            .chain(program.functions.iter().filter(|f| f.exported).map(|f| &f.name));
+        let main_wrapper = first_function + program.functions.len() as u32;
+        for (i, name) in wrappers.enumerate() {
+            names.function(main_wrapper + i as u32, &format!("export {}", name));
+        }
+        for (i, helper) in self.helpers.iter().enumerate() {
+            names.function(self.first_helper + i as u32, &format!("{:?}", helper));
+        }
+
+        let builtins = [
+            (ty::BYTES, "bytes"),
+            (ty::PRIMITIVES, "primitives"),
+            (ty::REFERENCES, "references"),
+            (ty::PRIMITIVE_LIST, "primitive list"),
+            (ty::REFERENCE_LIST, "reference list"),
+            (ty::CLOSURE, "closure"),
+            (ty::BOX, "box"),
+        ];
+        for (index, name) in builtins {
+            names.ty(index, name);
+        }
+        for (index, ir_struct) in self.structs.iter().enumerate().skip(1) {
+            names.ty(struct_type(index as u32), &struct_name(ir_struct));
+        }
+        for (i, (params, results)) in self.types.signatures.iter().enumerate() {
+            names.signature(self.types.first + i as u32, params, results);
+        }
+        names
+    }
+}
+
+/// The type index of IR struct `index`. Struct 0 is the tagged union.
+fn struct_type(index: u32) -> u32 {
+    match index {
+        0 => ty::BOX,
+        _ => ty::STRUCTS + index - 1,
+    }
+}
+
+/// The distinct slot offsets of a struct, in order.
+fn slot_offsets(ir_struct: &IRStruct) -> Vec<u32> {
+    let mut offsets = ir_struct.offsets.clone();
+    offsets.sort_unstable();
+    offsets.dedup();
+    offsets
+}
+
+/// Calls the function at `index` with the scratch values, no environment
+/// and the first `forwarded` of the wrapper's own `params`.
+fn wrapper(index: u32, params: u32, forwarded: u32) -> Body {
+    let mut f = Body::new(params, vec![]);
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::RefNull(HeapType::Abstract {
+        shared: false,
+        ty: AbstractHeapType::Struct,
+    }));
+    for i in 0..forwarded {
+        f.push(Instruction::LocalGet(i));
+    }
+    f.push(Instruction::Call(index));
+    f
+}
+
+/// Records the captures struct of every closure `body` makes, by the
+/// function it calls.
+fn collect_environments(body: &[IRStmt], environments: &mut HashMap<u32, u32>) {
+    for stmt in body {
+        match stmt {
+            IRStmt::LocalClosure {
+                fn_index, captures, ..
+            } => {
+                if let IRExprKind::New { struct_index, .. } = &captures.node {
+                    environments.insert(*fn_index, *struct_index);
+                }
+            }
+            IRStmt::If {
+                then_block,
+                else_block,
+                ..
+            } => {
+                collect_environments(then_block, environments);
+                if let Some(else_block) = else_block {
+                    collect_environments(else_block, environments);
+                }
+            }
+            IRStmt::While { body, .. } | IRStmt::Unchecked { body } => {
+                collect_environments(body, environments);
+            }
+            IRStmt::For {
+                init, update, body, ..
+            } => {
+                collect_environments(std::slice::from_ref(init), environments);
+                collect_environments(std::slice::from_ref(update), environments);
+                collect_environments(body, environments);
+            }
+            _ => {}
+        }
+    }
+}
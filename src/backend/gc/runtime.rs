@@ -0,0 +1,887 @@
+//! Functions the module defines for what the runtime's modules would do
+//! otherwise: printing, formatting numbers, and the string, list and
+//! builder operations that loop.
+
+use wasm_encoder::{BlockType, HeapType, Instruction, MemArg, ValType};
+
+use super::body::Body;
+use super::{field, reference, struct_type, ty, GcCodegen, Kind, PRINT_IMPORT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Helper {
+    /// Copies a string into memory and hands it to the host's `print`.
+    Print,
+    Itoa,
+    /// Formats a float like the runtime's `dftoa`: the integer part, a dot
+    /// and six digits of fraction.
+    Ftoa,
+    Concat,
+    BytesEqual,
+    BytesSlice,
+    /// A string over and over, a number of times.
+    BytesRepeat,
+    /// Appends to a list in place, growing its elements when full, and
+    /// returns the list.
+    ListPush(Kind),
+    ListConcat(Kind),
+    ListEqual(Kind),
+    ListSlice(Kind),
+    ListRepeat(Kind),
+    /// Whether a value, in element storage, is in a list.
+    ListContains(Kind),
+    /// Whether two elements of a list of references are equal: strings
+    /// and lists by their contents, anything else only to itself.
+    ReferenceEqual,
+    BuilderAppend,
+    BuilderToString,
+    /// `array.copy` for arrays of references, which wasmtime doesn't
+    /// implement yet.
+    CopyReferences,
+}
+
+/// Where `print` finds the length of the string it prints, and then its
+/// bytes.
+fn print_buffer(offset: u64) -> MemArg {
+    MemArg {
+        offset,
+        align: 0,
+        memory_index: 0,
+    }
+}
+
+impl GcCodegen {
+    pub(super) fn helper_signature(&self, helper: Helper) -> (Vec<ValType>, Vec<ValType>) {
+        let bytes = reference(ty::BYTES);
+        let builder = reference(struct_type(self.builder));
+        let list = |kind: Kind| reference(kind.list());
+        match helper {
+            Helper::Print => (vec![bytes], vec![]),
+            Helper::Itoa => (vec![ValType::I64], vec![bytes]),
+            Helper::Ftoa => (vec![ValType::F64], vec![bytes]),
+            Helper::Concat => (vec![bytes, bytes], vec![bytes]),
+            Helper::BytesEqual => (vec![bytes, bytes], vec![ValType::I32]),
+            Helper::BytesSlice => (vec![bytes, ValType::I64, ValType::I64], vec![bytes]),
+            Helper::BytesRepeat => (vec![bytes, ValType::I64], vec![bytes]),
+            Helper::ListPush(kind) => (vec![list(kind), kind.storage()], vec![list(kind)]),
+            Helper::ListConcat(kind) => (vec![list(kind), list(kind)], vec![list(kind)]),
+            Helper::ListEqual(kind) => (vec![list(kind), list(kind)], vec![ValType::I32]),
+            Helper::ListSlice(kind) => (
+                vec![list(kind), ValType::I64, ValType::I64],
+                vec![list(kind)],
+            ),
+            Helper::ListRepeat(kind) => (vec![list(kind), ValType::I64], vec![list(kind)]),
+            Helper::ListContains(kind) => (vec![kind.storage(), list(kind)], vec![ValType::I32]),
+            Helper::ReferenceEqual => {
+                let element = Kind::Reference.storage();
+                (vec![element, element], vec![ValType::I32])
+            }
+            Helper::BuilderAppend => (vec![builder, bytes], vec![builder]),
+            Helper::BuilderToString => (vec![builder], vec![bytes]),
+            Helper::CopyReferences => {
+                let references = reference(ty::REFERENCES);
+                let i32 = ValType::I32;
+                (vec![references, i32, references, i32, i32], vec![])
+            }
+        }
+    }
+
+    pub(super) fn compile_helper(&mut self, helper: Helper, params: Vec<ValType>) -> Body {
+        let mut f = Body::new(params.len() as u32, vec![]);
+        match helper {
+            Helper::Print => emit_print(&mut f),
+            Helper::Itoa => emit_itoa(&mut f),
+            Helper::Ftoa => {
+                let itoa = self.helper(Helper::Itoa);
+                emit_ftoa(&mut f, itoa);
+            }
+            Helper::Concat => emit_concat(&mut f),
+            Helper::BytesEqual => emit_equal(&mut f, ty::BYTES, None, Instruction::I32Eq),
+            Helper::BytesSlice => emit_slice(&mut f, ty::BYTES, None, copy(ty::BYTES)),
+            Helper::BytesRepeat => emit_repeat(&mut f, ty::BYTES, None, copy(ty::BYTES)),
+            Helper::ListPush(kind) => {
+                let copy = self.copy_elements(kind);
+                emit_list_push(&mut f, kind, copy);
+            }
+            Helper::ListConcat(kind) => {
+                let copy = self.copy_elements(kind);
+                emit_list_concat(&mut f, kind, copy);
+            }
+            Helper::ListEqual(kind) => {
+                let equal = self.element_equal(kind);
+                emit_equal(&mut f, kind.array(), Some(kind), equal);
+            }
+            Helper::ListSlice(kind) => {
+                let copy = self.copy_elements(kind);
+                emit_slice(&mut f, kind.array(), Some(kind), copy);
+            }
+            Helper::ListRepeat(kind) => {
+                let copy = self.copy_elements(kind);
+                emit_repeat(&mut f, kind.array(), Some(kind), copy);
+            }
+            Helper::CopyReferences => emit_copy_references(&mut f),
+            Helper::ListContains(kind) => {
+                let equal = self.element_equal(kind);
+                emit_list_contains(&mut f, kind, equal);
+            }
+            Helper::ReferenceEqual => {
+                let equal = [
+                    (ty::BYTES, Helper::BytesEqual),
+                    (ty::PRIMITIVE_LIST, Helper::ListEqual(Kind::Primitive)),
+                    (ty::REFERENCE_LIST, Helper::ListEqual(Kind::Reference)),
+                ]
+                .map(|(ty, helper)| (ty, self.helper(helper)));
+                emit_reference_equal(&mut f, equal);
+            }
+            Helper::BuilderAppend => self.emit_builder_append(&mut f),
+            Helper::BuilderToString => {
+                let slice = self.helper(Helper::BytesSlice);
+                let (buffer, length) = self.builder_fields();
+                f.push(Instruction::LocalGet(0));
+                f.push(buffer);
+                f.push(Instruction::I64Const(0));
+                f.push(Instruction::LocalGet(0));
+                f.push(length);
+                f.push(Instruction::Call(slice));
+            }
+        }
+        f
+    }
+
+    /// What compares two elements of a list of `kind`, leaving whether
+    /// they're equal.
+    fn element_equal(&mut self, kind: Kind) -> Instruction<'static> {
+        match kind {
+            Kind::Primitive => Instruction::I64Eq,
+            Kind::Reference => Instruction::Call(self.helper(Helper::ReferenceEqual)),
+        }
+    }
+
+    /// What copies elements between arrays of a list of `kind`, taking
+    /// the operands of `array.copy`.
+    fn copy_elements(&mut self, kind: Kind) -> Instruction<'static> {
+        match kind {
+            Kind::Primitive => copy(kind.array()),
+            Kind::Reference => Instruction::Call(self.helper(Helper::CopyReferences)),
+        }
+    }
+
+    /// The instructions that get `Builder`'s `buffer` and `length` fields.
+    fn builder_fields(&self) -> (Instruction<'static>, Instruction<'static>) {
+        let builder = &self.structs[self.builder as usize];
+        let get = |name: &str| {
+            let position = builder.fields.iter().position(|(field, _)| field == name);
+            let offset = builder.offsets[position.expect("Builder has the field")];
+            Instruction::StructGet {
+                struct_type_index: struct_type(self.builder),
+                field_index: self.slot(self.builder, offset),
+            }
+        };
+        (get("buffer"), get("length"))
+    }
+
+    /// Appends the string in local 1 to the builder in local 0, doubling
+    /// its buffer when the string doesn't fit.
+    fn emit_builder_append(&self, f: &mut Body) {
+        let (buffer, length) = self.builder_fields();
+        let set = |get: &Instruction| match get {
+            Instruction::StructGet {
+                struct_type_index,
+                field_index,
+            } => Instruction::StructSet {
+                struct_type_index: *struct_type_index,
+                field_index: *field_index,
+            },
+            _ => unreachable!(),
+        };
+        let (set_buffer, set_length) = (set(&buffer), set(&length));
+        let used = f.scratch(ValType::I32);
+        let needed = f.scratch(ValType::I32);
+        let grown = f.scratch(reference(ty::BYTES));
+
+        f.push(Instruction::LocalGet(0));
+        f.push(length);
+        f.push(Instruction::I32WrapI64);
+        f.push(Instruction::LocalTee(used));
+        f.push(Instruction::LocalGet(1));
+        f.push(Instruction::ArrayLen);
+        f.push(Instruction::I32Add);
+        f.push(Instruction::LocalTee(needed));
+        f.push(Instruction::LocalGet(0));
+        f.push(buffer.clone());
+        f.push(Instruction::ArrayLen);
+        f.push(Instruction::I32GtU);
+        f.push(Instruction::If(BlockType::Empty));
+        f.push(Instruction::LocalGet(needed));
+        f.push(Instruction::I32Const(1));
+        f.push(Instruction::I32Shl);
+        f.push(Instruction::ArrayNewDefault(ty::BYTES));
+        f.push(Instruction::LocalTee(grown));
+        f.push(Instruction::I32Const(0));
+        f.push(Instruction::LocalGet(0));
+        f.push(buffer.clone());
+        f.push(Instruction::I32Const(0));
+        f.push(Instruction::LocalGet(used));
+        f.push(copy(ty::BYTES));
+        f.push(Instruction::LocalGet(0));
+        f.push(Instruction::LocalGet(grown));
+        f.push(set_buffer);
+        f.push(Instruction::End);
+
+        f.push(Instruction::LocalGet(0));
+        f.push(buffer);
+        f.push(Instruction::LocalGet(used));
+        f.push(Instruction::LocalGet(1));
+        f.push(Instruction::I32Const(0));
+        f.push(Instruction::LocalGet(1));
+        f.push(Instruction::ArrayLen);
+        f.push(copy(ty::BYTES));
+        f.push(Instruction::LocalGet(0));
+        f.push(Instruction::LocalGet(needed));
+        f.push(Instruction::I64ExtendI32U);
+        f.push(set_length);
+        f.push(Instruction::LocalGet(0));
+    }
+}
+
+fn copy(array: u32) -> Instruction<'static> {
+    Instruction::ArrayCopy {
+        array_type_index_dst: array,
+        array_type_index_src: array,
+    }
+}
+
+fn items(kind: Kind) -> Instruction<'static> {
+    Instruction::StructGet {
+        struct_type_index: kind.list(),
+        field_index: field::ITEMS,
+    }
+}
+
+fn length(kind: Kind) -> Instruction<'static> {
+    Instruction::StructGet {
+        struct_type_index: kind.list(),
+        field_index: field::LENGTH,
+    }
+}
+
+/// Increments the `i32` in `local`.
+fn increment(f: &mut Body, local: u32) {
+    f.push(Instruction::LocalGet(local));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalSet(local));
+}
+
+/// Writes the length of the string in local 0 and then its bytes to
+/// memory, growing it to fit, and prints them.
+fn emit_print(f: &mut Body) {
+    let len = f.scratch(ValType::I32);
+    let i = f.scratch(ValType::I32);
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::LocalTee(len));
+    f.push(Instruction::I32Const(4 + 0xffff));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::I32Const(16));
+    f.push(Instruction::I32ShrU);
+    f.push(Instruction::MemorySize(0));
+    f.push(Instruction::I32Sub);
+    f.push(Instruction::LocalTee(i));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::I32GtS);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::MemoryGrow(0));
+    f.push(Instruction::Drop);
+    f.push(Instruction::End);
+
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(len));
+    f.push(Instruction::I32Store(MemArg {
+        offset: 0,
+        align: 2,
+        memory_index: 0,
+    }));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalSet(i));
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(len));
+    f.push(Instruction::I32GeU);
+    f.push(Instruction::BrIf(1));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::ArrayGetU(ty::BYTES));
+    f.push(Instruction::I32Store8(print_buffer(4)));
+    increment(f, i);
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+
+    f.push(Instruction::I32Const(4));
+    f.push(Instruction::Call(PRINT_IMPORT));
+}
+
+/// Formats the integer in local 0. Its magnitude is taken as unsigned, so
+/// the most negative integer formats too.
+fn emit_itoa(f: &mut Body) {
+    let negative = f.scratch(ValType::I32);
+    let magnitude = f.scratch(ValType::I64);
+    let rest = f.scratch(ValType::I64);
+    let digits = f.scratch(ValType::I32);
+    let string = f.scratch(reference(ty::BYTES));
+
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::I64Sub);
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::I64LtS);
+    f.push(Instruction::LocalTee(negative));
+    f.push(Instruction::Select);
+    f.push(Instruction::LocalTee(magnitude));
+    f.push(Instruction::LocalSet(rest));
+
+    // One digit, a sign if negative, and a digit for each power of ten.
+    f.push(Instruction::LocalGet(negative));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalSet(digits));
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(rest));
+    f.push(Instruction::I64Const(10));
+    f.push(Instruction::I64LtU);
+    f.push(Instruction::BrIf(1));
+    f.push(Instruction::LocalGet(rest));
+    f.push(Instruction::I64Const(10));
+    f.push(Instruction::I64DivU);
+    f.push(Instruction::LocalSet(rest));
+    increment(f, digits);
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(digits));
+    f.push(Instruction::ArrayNewDefault(ty::BYTES));
+    f.push(Instruction::LocalSet(string));
+    f.push(Instruction::LocalGet(negative));
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::I32Const(b'-' as i32));
+    f.push(Instruction::ArraySet(ty::BYTES));
+    f.push(Instruction::End);
+
+    // Digits from the last, until nothing is left.
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(digits));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Sub);
+    f.push(Instruction::LocalSet(digits));
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::LocalGet(digits));
+    f.push(Instruction::LocalGet(magnitude));
+    f.push(Instruction::I64Const(10));
+    f.push(Instruction::I64RemU);
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::I32Const(b'0' as i32));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArraySet(ty::BYTES));
+    f.push(Instruction::LocalGet(magnitude));
+    f.push(Instruction::I64Const(10));
+    f.push(Instruction::I64DivU);
+    f.push(Instruction::LocalTee(magnitude));
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::I64Ne);
+    f.push(Instruction::BrIf(0));
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(string));
+}
+
+/// Formats the float in local 0 with `itoa`, the function at that index.
+fn emit_ftoa(f: &mut Body, itoa: u32) {
+    let whole = f.scratch(ValType::I64);
+    let int_string = f.scratch(reference(ty::BYTES));
+    let fraction = f.scratch(reference(ty::BYTES));
+    let zeros = f.scratch(ValType::I32);
+    let string = f.scratch(reference(ty::BYTES));
+
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::I64TruncSatF64S);
+    f.push(Instruction::LocalTee(whole));
+    f.push(Instruction::Call(itoa));
+    f.push(Instruction::LocalSet(int_string));
+
+    // Six digits of what is left, rounded.
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(whole));
+    f.push(Instruction::F64ConvertI64S);
+    f.push(Instruction::F64Sub);
+    f.push(Instruction::F64Abs);
+    f.push(Instruction::F64Const(wasm_encoder::Ieee64::from(1000000.0)));
+    f.push(Instruction::F64Mul);
+    f.push(Instruction::F64Const(wasm_encoder::Ieee64::from(0.5)));
+    f.push(Instruction::F64Add);
+    f.push(Instruction::I64TruncSatF64U);
+    f.push(Instruction::Call(itoa));
+    f.push(Instruction::LocalSet(fraction));
+
+    // Zeros to pad the fraction to six digits.
+    f.push(Instruction::I32Const(6));
+    f.push(Instruction::LocalGet(fraction));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Sub);
+    f.push(Instruction::LocalTee(zeros));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(zeros));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::I32GtS);
+    f.push(Instruction::Select);
+    f.push(Instruction::LocalSet(zeros));
+
+    // Filled with the padding, which is left between the copies.
+    f.push(Instruction::I32Const(b'0' as i32));
+
+    f.push(Instruction::LocalGet(int_string));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalGet(zeros));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalGet(fraction));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArrayNew(ty::BYTES));
+    f.push(Instruction::LocalSet(string));
+
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(int_string));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(int_string));
+    f.push(Instruction::ArrayLen);
+    f.push(copy(ty::BYTES));
+
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::LocalGet(int_string));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Const(b'.' as i32));
+    f.push(Instruction::ArraySet(ty::BYTES));
+
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::LocalGet(int_string));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalGet(zeros));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalGet(fraction));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(fraction));
+    f.push(Instruction::ArrayLen);
+    f.push(copy(ty::BYTES));
+
+    f.push(Instruction::LocalGet(string));
+}
+
+/// Joins the strings in locals 0 and 1 into a new one.
+fn emit_concat(f: &mut Body) {
+    let string = f.scratch(reference(ty::BYTES));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArrayNewDefault(ty::BYTES));
+    f.push(Instruction::LocalTee(string));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::ArrayLen);
+    f.push(copy(ty::BYTES));
+    f.push(Instruction::LocalGet(string));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::ArrayLen);
+    f.push(copy(ty::BYTES));
+    f.push(Instruction::LocalGet(string));
+}
+
+/// Pushes the elements of the string or list in `local`, and how many
+/// there are, for a list of `kind`, or a string without one.
+fn emit_contents(f: &mut Body, local: u32, list: Option<Kind>) {
+    f.push(Instruction::LocalGet(local));
+    if let Some(kind) = list {
+        f.push(items(kind));
+    }
+}
+
+fn emit_count(f: &mut Body, local: u32, list: Option<Kind>) {
+    f.push(Instruction::LocalGet(local));
+    match list {
+        Some(kind) => f.push(length(kind)),
+        None => f.push(Instruction::ArrayLen),
+    }
+}
+
+/// Compares the strings or lists in locals 0 and 1 element by element,
+/// with `equal` taking two elements.
+fn emit_equal(f: &mut Body, array: u32, list: Option<Kind>, equal: Instruction<'static>) {
+    let i = f.scratch(ValType::I32);
+    emit_count(f, 0, list);
+    emit_count(f, 1, list);
+    f.push(Instruction::I32Ne);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::Return);
+    f.push(Instruction::End);
+
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(i));
+    emit_count(f, 0, list);
+    f.push(Instruction::I32GeU);
+    f.push(Instruction::BrIf(1));
+    let get = match list {
+        None => Instruction::ArrayGetU(array),
+        Some(_) => Instruction::ArrayGet(array),
+    };
+    for local in [0, 1] {
+        emit_contents(f, local, list);
+        f.push(Instruction::LocalGet(i));
+        f.push(get.clone());
+    }
+    f.push(equal);
+    f.push(Instruction::I32Eqz);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::Return);
+    f.push(Instruction::End);
+    increment(f, i);
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+    f.push(Instruction::I32Const(1));
+}
+
+/// Copies elements 1 to 2, given as `i64`s in those locals, of the string
+/// or list in local 0, a negative index counting back from the end. Traps
+/// unless they resolve to `0 <= start <= end <= length`.
+fn emit_slice(f: &mut Body, array: u32, list: Option<Kind>, copy: Instruction<'static>) {
+    let count = f.scratch(ValType::I32);
+    let elements = f.scratch(reference(array));
+    for index in [1, 2] {
+        f.push(Instruction::LocalGet(index));
+        f.push(Instruction::I64Const(0));
+        f.push(Instruction::I64LtS);
+        f.push(Instruction::If(BlockType::Empty));
+        f.push(Instruction::LocalGet(index));
+        emit_count(f, 0, list);
+        f.push(Instruction::I64ExtendI32U);
+        f.push(Instruction::I64Add);
+        f.push(Instruction::LocalSet(index));
+        f.push(Instruction::End);
+    }
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::LocalGet(2));
+    f.push(Instruction::I64GtU);
+    f.push(Instruction::LocalGet(2));
+    emit_count(f, 0, list);
+    f.push(Instruction::I64ExtendI32U);
+    f.push(Instruction::I64GtU);
+    f.push(Instruction::I32Or);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::Unreachable);
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(2));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I64Sub);
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::LocalTee(count));
+    f.push(Instruction::ArrayNewDefault(array));
+    f.push(Instruction::LocalTee(elements));
+    f.push(Instruction::I32Const(0));
+    emit_contents(f, 0, list);
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::LocalGet(count));
+    f.push(copy);
+
+    if let Some(kind) = list {
+        f.push(Instruction::LocalGet(count));
+        f.push(Instruction::LocalGet(elements));
+        f.push(Instruction::StructNew(kind.list()));
+    } else {
+        f.push(Instruction::LocalGet(elements));
+    }
+}
+
+/// Copies the string or list in local 0 over and over, as many times as
+/// the `i64` in local 1 says. Traps when that's negative or comes to more
+/// than `i32::MAX` elements, as the runtime's `drepeat` does.
+fn emit_repeat(f: &mut Body, array: u32, list: Option<Kind>, copy: Instruction<'static>) {
+    let count = f.scratch(ValType::I32);
+    let total = f.scratch(ValType::I64);
+    let i = f.scratch(ValType::I32);
+    let elements = f.scratch(reference(array));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::I64LtS);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::Unreachable);
+    f.push(Instruction::End);
+    emit_count(f, 0, list);
+    f.push(Instruction::LocalTee(count));
+    f.push(Instruction::I32Eqz);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I64Const(0));
+    f.push(Instruction::LocalSet(1));
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I64Const(i32::MAX as i64));
+    f.push(Instruction::I64GtU);
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::LocalGet(count));
+    f.push(Instruction::I64ExtendI32U);
+    f.push(Instruction::I64Mul);
+    f.push(Instruction::LocalTee(total));
+    f.push(Instruction::I64Const(i32::MAX as i64));
+    f.push(Instruction::I64GtU);
+    f.push(Instruction::I32Or);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::Unreachable);
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(total));
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::ArrayNewDefault(array));
+    f.push(Instruction::LocalSet(elements));
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::I32WrapI64);
+    f.push(Instruction::I32GeU);
+    f.push(Instruction::BrIf(1));
+    f.push(Instruction::LocalGet(elements));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(count));
+    f.push(Instruction::I32Mul);
+    emit_contents(f, 0, list);
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(count));
+    f.push(copy);
+    increment(f, i);
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+
+    if let Some(kind) = list {
+        f.push(Instruction::LocalGet(total));
+        f.push(Instruction::I32WrapI64);
+        f.push(Instruction::LocalGet(elements));
+        f.push(Instruction::StructNew(kind.list()));
+    } else {
+        f.push(Instruction::LocalGet(elements));
+    }
+}
+
+fn emit_list_push(f: &mut Body, kind: Kind, copy: Instruction<'static>) {
+    let len = f.scratch(ValType::I32);
+    let grown = f.scratch(reference(kind.array()));
+    f.push(Instruction::LocalGet(0));
+    f.push(length(kind));
+    f.push(Instruction::LocalTee(len));
+    f.push(Instruction::LocalGet(0));
+    f.push(items(kind));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::I32Eq);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::LocalGet(len));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Shl);
+    f.push(Instruction::I32Const(4));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArrayNewDefault(kind.array()));
+    f.push(Instruction::LocalTee(grown));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(items(kind));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(len));
+    f.push(copy);
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(grown));
+    f.push(Instruction::StructSet {
+        struct_type_index: kind.list(),
+        field_index: field::ITEMS,
+    });
+    f.push(Instruction::End);
+
+    f.push(Instruction::LocalGet(0));
+    f.push(items(kind));
+    f.push(Instruction::LocalGet(len));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::ArraySet(kind.array()));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(len));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::StructSet {
+        struct_type_index: kind.list(),
+        field_index: field::LENGTH,
+    });
+    f.push(Instruction::LocalGet(0));
+}
+
+fn emit_list_concat(f: &mut Body, kind: Kind, copy: Instruction<'static>) {
+    let elements = f.scratch(reference(kind.array()));
+    f.push(Instruction::LocalGet(0));
+    f.push(length(kind));
+    f.push(Instruction::LocalGet(1));
+    f.push(length(kind));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArrayNewDefault(kind.array()));
+    f.push(Instruction::LocalTee(elements));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(items(kind));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(0));
+    f.push(length(kind));
+    f.push(copy.clone());
+    f.push(Instruction::LocalGet(elements));
+    f.push(Instruction::LocalGet(0));
+    f.push(length(kind));
+    f.push(Instruction::LocalGet(1));
+    f.push(items(kind));
+    f.push(Instruction::I32Const(0));
+    f.push(Instruction::LocalGet(1));
+    f.push(length(kind));
+    f.push(copy);
+
+    f.push(Instruction::LocalGet(elements));
+    f.push(Instruction::ArrayLen);
+    f.push(Instruction::LocalGet(elements));
+    f.push(Instruction::StructNew(kind.list()));
+}
+
+/// Whether the references in locals 0 and 1 are equal. Two strings or two
+/// lists of the same kind are compared by `equal`'s helper for their type,
+/// and anything else only to itself.
+fn emit_reference_equal(f: &mut Body, equal: [(u32, u32); 3]) {
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::RefEq);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::Return);
+    f.push(Instruction::End);
+    for (ty, helper) in equal {
+        let heap_type = HeapType::Concrete(ty);
+        f.push(Instruction::LocalGet(0));
+        f.push(Instruction::RefTestNonNull(heap_type));
+        f.push(Instruction::LocalGet(1));
+        f.push(Instruction::RefTestNonNull(heap_type));
+        f.push(Instruction::I32And);
+        f.push(Instruction::If(BlockType::Empty));
+        for local in [0, 1] {
+            f.push(Instruction::LocalGet(local));
+            f.push(Instruction::RefCastNonNull(heap_type));
+        }
+        f.push(Instruction::Call(helper));
+        f.push(Instruction::Return);
+        f.push(Instruction::End);
+    }
+    f.push(Instruction::I32Const(0));
+}
+
+/// Whether the value in local 0 is in the list in local 1, compared by
+/// `equal`.
+fn emit_list_contains(f: &mut Body, kind: Kind, equal: Instruction<'static>) {
+    let i = f.scratch(ValType::I32);
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(1));
+    f.push(length(kind));
+    f.push(Instruction::I32GeU);
+    f.push(Instruction::BrIf(1));
+    f.push(Instruction::LocalGet(1));
+    f.push(items(kind));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::ArrayGet(kind.array()));
+    f.push(Instruction::LocalGet(0));
+    f.push(equal);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::Return);
+    f.push(Instruction::End);
+    increment(f, i);
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+    f.push(Instruction::I32Const(0));
+}
+
+/// Copies local 4 references from local 2, starting at local 3, to local
+/// 0, starting at local 1. Copies backwards when the destination is ahead
+/// in the same array, like `array.copy`.
+fn emit_copy_references(f: &mut Body) {
+    let (step, i) = (f.scratch(ValType::I32), f.scratch(ValType::I32));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::LocalSet(step));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(2));
+    f.push(Instruction::RefEq);
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::LocalGet(3));
+    f.push(Instruction::I32GtU);
+    f.push(Instruction::I32And);
+    f.push(Instruction::If(BlockType::Empty));
+    f.push(Instruction::I32Const(-1));
+    f.push(Instruction::LocalSet(step));
+    f.push(Instruction::LocalGet(4));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Sub);
+    f.push(Instruction::LocalSet(i));
+    f.push(Instruction::End);
+
+    f.push(Instruction::Block(BlockType::Empty));
+    f.push(Instruction::Loop(BlockType::Empty));
+    f.push(Instruction::LocalGet(4));
+    f.push(Instruction::I32Eqz);
+    f.push(Instruction::BrIf(1));
+    f.push(Instruction::LocalGet(0));
+    f.push(Instruction::LocalGet(1));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalGet(2));
+    f.push(Instruction::LocalGet(3));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::ArrayGet(ty::REFERENCES));
+    f.push(Instruction::ArraySet(ty::REFERENCES));
+    f.push(Instruction::LocalGet(i));
+    f.push(Instruction::LocalGet(step));
+    f.push(Instruction::I32Add);
+    f.push(Instruction::LocalSet(i));
+    f.push(Instruction::LocalGet(4));
+    f.push(Instruction::I32Const(1));
+    f.push(Instruction::I32Sub);
+    f.push(Instruction::LocalSet(4));
+    f.push(Instruction::Br(0));
+    f.push(Instruction::End);
+    f.push(Instruction::End);
+}
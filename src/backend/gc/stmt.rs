@@ -0,0 +1,197 @@
+use crate::ast::{IRExprKind, IRFunction, IRStmt};
+use crate::error::CompilerError;
+use wasm_encoder::{BlockType, Instruction, ValType};
+
+use super::body::Body;
+use super::expr::default_value;
+use super::runtime::Helper;
+use super::{reference, struct_type, ty, unsupported, GcCodegen};
+
+impl GcCodegen {
+    /// Compiles a program function, which takes the calling convention's
+    /// values and then its own as `params`.
+    pub(super) fn compile_function(
+        &mut self,
+        func: &IRFunction,
+        params: Vec<ValType>,
+    ) -> Result<Body, CompilerError> {
+        if func.resumable {
+            return Err(unsupported(&format!(
+                "Function '{}', which awaits or exits,",
+                func.name
+            )));
+        }
+        let locals = func
+            .locals
+            .iter()
+            .map(|local| self.valtype(local))
+            .collect();
+        let mut f = Body::new(params.len() as u32, locals);
+        self.returns = self.valtype(&func.returns);
+        self.env = self.environments.get(&func.func_index).copied();
+        self.unchecked = false;
+        self.block_depth = 0;
+        self.loop_depths.clear();
+
+        for stmt in &func.body {
+            self.compile_stmt(stmt, &mut f)?;
+        }
+
+        // Every path ends in a return, so control never reaches here.
+        f.push(Instruction::Unreachable);
+        Ok(f)
+    }
+
+    fn compile_stmt(&mut self, stmt: &IRStmt, f: &mut Body) -> Result<(), CompilerError> {
+        match stmt {
+            IRStmt::Expr(expr) => {
+                self.compile_expr(expr, f)?;
+                f.push(Instruction::Drop);
+            }
+            IRStmt::LocalSet { index, value } => {
+                self.compile_expr(value, f)?;
+                f.push(Instruction::LocalSet(*index));
+            }
+            IRStmt::Return(expr) => {
+                match expr {
+                    Some(expr) => self.compile_expr(expr, f)?,
+                    None => default_value(f, self.returns),
+                }
+                f.push(Instruction::Return);
+            }
+            IRStmt::Raise(expr) => {
+                self.compile_expr(expr, f)?;
+                f.push(Instruction::Return);
+            }
+            IRStmt::Break(out) => {
+                f.push(Instruction::Br(self.loop_label(*out) + 1));
+            }
+            IRStmt::Continue(out) => {
+                f.push(Instruction::Br(self.loop_label(*out)));
+            }
+            IRStmt::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.compile_expr(condition, f)?;
+                f.push(Instruction::If(BlockType::Empty));
+                self.block_depth += 1;
+                self.compile_block(then_block, f)?;
+                if let Some(else_block) = else_block {
+                    f.push(Instruction::Else);
+                    self.compile_block(else_block, f)?;
+                }
+                self.block_depth -= 1;
+                f.push(Instruction::End);
+            }
+            IRStmt::While { condition, body } => {
+                f.push(Instruction::Block(BlockType::Empty));
+                f.push(Instruction::Loop(BlockType::Empty));
+                self.compile_expr(condition, f)?;
+                f.push(Instruction::I32Eqz);
+                f.push(Instruction::BrIf(1));
+                self.enter_loop();
+                self.compile_block(body, f)?;
+                self.exit_loop();
+                f.push(Instruction::Br(0));
+                f.push(Instruction::End);
+                f.push(Instruction::End);
+            }
+            IRStmt::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                f.push(Instruction::Block(BlockType::Empty));
+                self.compile_stmt(init, f)?;
+                f.push(Instruction::Loop(BlockType::Empty));
+                self.compile_expr(condition, f)?;
+                f.push(Instruction::I32Eqz);
+                f.push(Instruction::BrIf(1));
+                self.enter_loop();
+                self.compile_block(body, f)?;
+                self.exit_loop();
+                self.compile_stmt(update, f)?;
+                f.push(Instruction::Br(0));
+                f.push(Instruction::End);
+                f.push(Instruction::End);
+            }
+            IRStmt::Unchecked { body } => {
+                let outer = self.unchecked;
+                self.unchecked = true;
+                self.compile_block(body, f)?;
+                self.unchecked = outer;
+            }
+            IRStmt::Print(expr) => {
+                self.compile_expr(expr, f)?;
+                let print = self.helper(Helper::Print);
+                f.push(Instruction::Call(print));
+            }
+            IRStmt::LocalClosure {
+                fn_index,
+                captures,
+                index,
+            } => {
+                let IRExprKind::New {
+                    struct_index,
+                    fields,
+                } = &captures.node
+                else {
+                    return Err(CompilerError::Codegen {
+                        message: "Captures must be a local struct allocation".to_string(),
+                    });
+                };
+                // The closure is set before its captures are filled in, so
+                // a function can capture itself.
+                let captures_type = struct_type(*struct_index);
+                let env = f.scratch(reference(captures_type));
+                f.push(Instruction::StructNewDefault(captures_type));
+                f.push(Instruction::LocalSet(env));
+                f.push(Instruction::I32Const(*fn_index as i32));
+                f.push(Instruction::LocalGet(env));
+                f.push(Instruction::StructNew(ty::CLOSURE));
+                f.push(Instruction::LocalSet(*index));
+                for (slot, value) in fields.iter().enumerate() {
+                    f.push(Instruction::LocalGet(env));
+                    self.compile_expr(value, f)?;
+                    f.push(Instruction::StructSet {
+                        struct_type_index: captures_type,
+                        field_index: slot as u32,
+                    });
+                }
+                f.release(env);
+            }
+            IRStmt::Produce(_) => return Err(unsupported("Match arms")),
+            IRStmt::Suspend | IRStmt::Exit => return Err(unsupported("Suspending")),
+        }
+        Ok(())
+    }
+
+    fn compile_block(&mut self, block: &[IRStmt], f: &mut Body) -> Result<(), CompilerError> {
+        for stmt in block {
+            self.compile_stmt(stmt, f)?;
+        }
+        Ok(())
+    }
+
+    /// Enters the body of a loop, inside its block and loop.
+    fn enter_loop(&mut self) {
+        self.block_depth += 2;
+        self.loop_depths.push(self.block_depth);
+    }
+
+    fn exit_loop(&mut self) {
+        self.loop_depths.pop();
+        self.block_depth -= 2;
+    }
+
+    /// Label of the loop `out` loops out from the innermost; its block is
+    /// the label after.
+    fn loop_label(&self, out: u32) -> u32 {
+        let loops = self.loop_depths.len();
+        assert!((out as usize) < loops, "break outside a loop");
+        self.block_depth - self.loop_depths[loops - 1 - out as usize]
+    }
+}
@@ -0,0 +1,399 @@
+use crate::ast::tast::{TypedProgram, TypedStatement};
+use crate::ast::{Extern, IRExprKind, IRProgram, Type, TypeKind};
+use crate::host::{
+    AsyncRequest, AsyncState, ARG_COPY_IMPORT, ARG_COUNT_IMPORT, ARG_LENGTH_IMPORT,
+    ASYNC_ARGUMENT_EXPORT, ASYNC_REQUEST_EXPORT, ASYNC_RESULT_EXPORT, ASYNC_STATE_EXPORT,
+    ENV_COPY_IMPORT, ENV_LENGTH_IMPORT, RUNTIME_MODULES,
+};
+
+/// A JavaScript module that loads a compiled program, and its TypeScript
+/// declarations.
+#[derive(Debug)]
+pub struct Glue {
+    pub js: String,
+    pub dts: String,
+}
+
+/// A function crossing between JavaScript and the program, with the Star
+/// types of its parameters and result.
+struct Signature {
+    params: Vec<(String, Type)>,
+    returns: Type,
+}
+
+impl Glue {
+    /// Glue for the program compiled into `wasm`, a file next to the glue.
+    /// Unless `bundled`, it also loads the runtime modules from `alloc.wasm`,
+    /// `dalloc.wasm` and `shadow.wasm` there.
+    pub fn new(program: &TypedProgram, ir: &IRProgram, wasm: &str, bundled: bool) -> Self {
+        let exports: Vec<(&str, Signature)> = program
+            .statements
+            .iter()
+            .filter_map(|statement| match statement {
+                TypedStatement::Function {
+                    name,
+                    params,
+                    returns,
+                    exported: true,
+                    ..
+                } => Some((
+                    name.as_str(),
+                    Signature {
+                        params: params.clone(),
+                        returns: returns.clone(),
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+        let externs = externs(ir);
+
+        let mut js = String::new();
+        js.push_str(&format!(
+            "// Generated by star. Loads {} and the runtime it imports.\n\n",
+            wasm
+        ));
+        js.push_str(&format!("const WASM = \"{}\";\n", wasm));
+        js.push_str(&format!("const BUNDLED = {};\n", bundled));
+        js.push_str(&format!(
+            "const RUNTIME = [{}];\n",
+            RUNTIME_MODULES
+                .map(|name| format!("\"{}\"", name))
+                .join(", ")
+        ));
+        js.push_str(&LOADER.replace("$CONSTANTS", &constants()));
+
+        js.push_str("\n  const externs = {\n");
+        for (function, signature) in &externs {
+            let args = arg_names(signature.params.len());
+            let call = format!(
+                "need(\"{}\", \"{}\")({})",
+                function.module,
+                function.name,
+                to_js_args(&signature.params, &args)
+            );
+            js.push_str(&format!(
+                "    \"{}.{}\": ({}) => {},\n",
+                function.module,
+                function.name,
+                args.join(", "),
+                to_wasm(&signature.returns, &call)
+            ));
+        }
+        js.push_str("  };\n");
+        js.push_str(LINK);
+
+        js.push_str("\n  return {\n    instance,\n    main,\n    exports: {\n");
+        for (name, signature) in &exports {
+            let args: Vec<String> = signature.params.iter().map(|(p, _)| p.clone()).collect();
+            let call = format!(
+                "instance.exports.{}({})",
+                name,
+                signature
+                    .params
+                    .iter()
+                    .zip(&args)
+                    .map(|((_, ty), arg)| to_wasm(ty, arg))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            js.push_str(&format!(
+                "      {}: ({}) => {},\n",
+                name,
+                args.join(", "),
+                to_js(&signature.returns, &call)
+            ));
+        }
+        js.push_str("    },\n  };\n}\n");
+
+        let mut dts = String::new();
+        dts.push_str(&format!(
+            "// Generated by star. Types for the loader of {}.\n\n",
+            wasm
+        ));
+        dts.push_str(DECLARATIONS);
+        dts.push_str("\n  /** The functions the program's `extern fn`s call, by module. */\n");
+        dts.push_str("  imports?: {\n");
+        let mut modules: Vec<&str> = externs.iter().map(|(f, _)| f.module.as_str()).collect();
+        modules.dedup();
+        for module in modules {
+            dts.push_str(&format!("    \"{}\": {{\n", module));
+            for (function, signature) in externs.iter().filter(|(f, _)| f.module == module) {
+                dts.push_str(&format!(
+                    "      {}({}): {};\n",
+                    function.name,
+                    declare_params(&signature.params),
+                    ts_type(&signature.returns)
+                ));
+            }
+            dts.push_str("    };\n");
+        }
+        dts.push_str("  };\n}\n\nexport interface Program {\n");
+        dts.push_str("  instance: WebAssembly.Instance;\n");
+        dts.push_str(
+            "  /** Runs `main`, carrying out its awaits. Resolves to what it\n   * returned, or to the code it passed to `exit`. */\n",
+        );
+        dts.push_str("  main(): Promise<bigint>;\n");
+        dts.push_str("  /** The functions marked `export`, callable once `main` has run. */\n");
+        dts.push_str("  exports: {\n");
+        for (name, signature) in &exports {
+            dts.push_str(&format!(
+                "    {}({}): {};\n",
+                name,
+                declare_params(&signature.params),
+                ts_type(&signature.returns)
+            ));
+        }
+        dts.push_str("  };\n}\n\n");
+        dts.push_str("export function load(options?: LoadOptions): Promise<Program>;\n");
+
+        Glue { js, dts }
+    }
+}
+
+/// The extern functions the program calls, in the order codegen imports
+/// them.
+fn externs(ir: &IRProgram) -> Vec<(Extern, Signature)> {
+    let mut externs: Vec<(Extern, Signature)> = vec![];
+    for func in &ir.functions {
+        for stmt in &func.body {
+            stmt.visit_exprs(&mut |expr| {
+                if let IRExprKind::ExternCall { function, args } = &expr.node {
+                    if !externs.iter().any(|(f, _)| f == function) {
+                        let params = args.iter().map(|arg| arg.ty.clone());
+                        externs.push((
+                            function.clone(),
+                            Signature {
+                                params: arg_names(args.len()).into_iter().zip(params).collect(),
+                                returns: expr.ty.clone(),
+                            },
+                        ));
+                    }
+                }
+            });
+        }
+    }
+    externs
+}
+
+fn arg_names(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("arg{}", i)).collect()
+}
+
+fn to_js_args(params: &[(String, Type)], args: &[String]) -> String {
+    params
+        .iter()
+        .zip(args)
+        .map(|((_, ty), arg)| to_js(ty, arg))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn is_plain(ty: &Type) -> bool {
+    !ty.nullable && !ty.errorable
+}
+
+/// Converts the WASM value `value` to what JavaScript sees.
+fn to_js(ty: &Type, value: &str) -> String {
+    match ty.kind {
+        TypeKind::Boolean if is_plain(ty) => format!("{} !== 0", value),
+        TypeKind::String if is_plain(ty) => format!("text({})", value),
+        _ => value.to_string(),
+    }
+}
+
+/// Converts the JavaScript value `value` to what the program takes.
+fn to_wasm(ty: &Type, value: &str) -> String {
+    match ty.kind {
+        TypeKind::Boolean if is_plain(ty) => format!("({} ? 1 : 0)", value),
+        TypeKind::String if is_plain(ty) => format!("string({})", value),
+        _ => value.to_string(),
+    }
+}
+
+/// The TypeScript type a value has once converted. Structs, lists and boxed
+/// values stay pointers, and functions the `i64` of `host::FunctionValue`.
+fn ts_type(ty: &Type) -> &'static str {
+    if !is_plain(ty) {
+        return "number";
+    }
+    match ty.kind {
+        TypeKind::Integer | TypeKind::BitField { .. } | TypeKind::Function { .. } => "bigint",
+        TypeKind::Float => "number",
+        TypeKind::Boolean => "boolean",
+        TypeKind::String => "string",
+        _ => "number",
+    }
+}
+
+fn declare_params(params: &[(String, Type)]) -> String {
+    params
+        .iter()
+        .map(|(name, ty)| format!("{}: {}", name, ts_type(ty)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The host's names and values the loader uses, as JavaScript constants.
+fn constants() -> String {
+    let names = [
+        ("ARG_COUNT", ARG_COUNT_IMPORT),
+        ("ARG_LENGTH", ARG_LENGTH_IMPORT),
+        ("ARG_COPY", ARG_COPY_IMPORT),
+        ("ENV_LENGTH", ENV_LENGTH_IMPORT),
+        ("ENV_COPY", ENV_COPY_IMPORT),
+        ("ASYNC_STATE", ASYNC_STATE_EXPORT),
+        ("ASYNC_REQUEST", ASYNC_REQUEST_EXPORT),
+        ("ASYNC_ARGUMENT", ASYNC_ARGUMENT_EXPORT),
+        ("ASYNC_RESULT", ASYNC_RESULT_EXPORT),
+    ];
+    let values = [
+        ("UNWINDING", AsyncState::Unwinding as i32),
+        ("REWINDING", AsyncState::Rewinding as i32),
+        ("EXITING", AsyncState::Exiting as i32),
+        ("SLEEP", AsyncRequest::Sleep as i32),
+        ("FETCH", AsyncRequest::Fetch as i32),
+    ];
+    let mut out = String::new();
+    for (constant, name) in names {
+        out.push_str(&format!("const {} = \"{}\";\n", constant, name));
+    }
+    for (constant, value) in values {
+        out.push_str(&format!("const {} = {};\n", constant, value));
+    }
+    out
+}
+
+/// The start of the loader, up to the extern wrappers.
+const LOADER: &str = r#"$CONSTANTS
+// Reads a file next to this module, from disk under Node and over the
+// network in browsers.
+async function read(url) {
+  if (typeof process !== "undefined" && process.versions && process.versions.node) {
+    const { readFile } = await import("node:fs/promises");
+    return readFile(url);
+  }
+  const response = await fetch(url);
+  return new Uint8Array(await response.arrayBuffer());
+}
+
+export async function load(options = {}) {
+  const base = options.base ?? new URL(".", import.meta.url);
+  const print = options.print ?? ((line) => console.log(line));
+  const args = options.args ?? [];
+  const env = options.env ?? {};
+  const imports = options.imports ?? {};
+
+  const encoder = new TextEncoder();
+  const decoder = new TextDecoder();
+  const runtime = {};
+  let instance;
+
+  // The runtime's export `name` from `module`, which a bundle exports
+  // itself as `module.name`.
+  const from = (module, name) =>
+    BUNDLED ? instance.exports[`${module}.${name}`] : runtime[module][name];
+  // Strings live in dalloc's memory: their UTF-8 bytes, after four bytes
+  // holding their length.
+  const memory = () => new Uint8Array(from("dalloc", "memory").buffer);
+  const text = (ptr) => {
+    const bytes = memory();
+    const length = new DataView(bytes.buffer).getUint32(ptr - 4, true);
+    return decoder.decode(bytes.subarray(ptr, ptr + length));
+  };
+  // Copies `value` into a new string, which stays pinned for good.
+  const string = (value) => {
+    const bytes = encoder.encode(value);
+    const ptr = from("shadow", "host_alloc_string")(bytes.length);
+    if (ptr === 0) {
+      throw new Error("out of memory for a string");
+    }
+    memory().set(bytes, ptr);
+    return ptr;
+  };
+  const need = (module, name) => {
+    const f = imports[module]?.[name];
+    if (typeof f !== "function") {
+      throw new Error(`missing import ${module}.${name}`);
+    }
+    return f;
+  };
+"#;
+
+/// The rest of the loader: instantiating the modules and running `main`.
+const LINK: &str = r#"
+  const modules = {
+    env: {
+      print: (ptr) => print(text(ptr)),
+      [ARG_COUNT]: () => args.length,
+      [ARG_LENGTH]: (i) => encoder.encode(args[i]).length,
+      [ARG_COPY]: (i, ptr) => memory().set(encoder.encode(args[i]), ptr),
+      [ENV_LENGTH]: (ptr) => {
+        const value = env[text(ptr)];
+        return value === undefined ? -1 : encoder.encode(value).length;
+      },
+      [ENV_COPY]: (ptr, dest) => memory().set(encoder.encode(env[text(ptr)] ?? ""), dest),
+    },
+  };
+  for (const [name, f] of Object.entries(externs)) {
+    const dot = name.lastIndexOf(".");
+    const module = name.slice(0, dot);
+    modules[module] = { ...modules[module], [name.slice(dot + 1)]: f };
+  }
+
+  if (!BUNDLED) {
+    for (const name of RUNTIME) {
+      const bytes = options.runtime?.[name] ?? (await read(new URL(`${name}.wasm`, base)));
+      runtime[name] = (await WebAssembly.instantiate(bytes, runtime)).instance.exports;
+      modules[name] = runtime[name];
+    }
+  }
+  const bytes = options.wasm ?? (await read(new URL(WASM, base)));
+  instance = (await WebAssembly.instantiate(bytes, modules)).instance;
+
+  const main = async () => {
+    const run = () => instance.exports.main(0, 0n, 0);
+    let result = run();
+    const state = instance.exports[ASYNC_STATE];
+    if (state === undefined) {
+      return result;
+    }
+    const argument = instance.exports[ASYNC_ARGUMENT];
+    const answer = instance.exports[ASYNC_RESULT];
+    while (state.value === UNWINDING) {
+      switch (instance.exports[ASYNC_REQUEST].value) {
+        case SLEEP: {
+          const started = Date.now();
+          await new Promise((resolve) => setTimeout(resolve, Number(argument.value)));
+          answer.value = BigInt(Date.now() - started);
+          break;
+        }
+        case FETCH: {
+          const response = await fetch(text(Number(argument.value)));
+          answer.value = BigInt(string(await response.text()));
+          break;
+        }
+        default:
+          throw new Error("unknown async request");
+      }
+      state.value = REWINDING;
+      result = run();
+    }
+    return state.value === EXITING ? argument.value : result;
+  };
+"#;
+
+/// The declarations that don't depend on the program.
+const DECLARATIONS: &str = r#"export interface LoadOptions {
+  /** Where the .wasm files are. Defaults to the directory of this module. */
+  base?: string | URL;
+  /** The program's module, instead of reading it from `base`. */
+  wasm?: BufferSource;
+  /** The runtime's modules, instead of reading them from `base`. */
+  runtime?: { alloc?: BufferSource; dalloc?: BufferSource; shadow?: BufferSource };
+  /** Prints a line. Defaults to `console.log`. */
+  print?: (line: string) => void;
+  /** The arguments a `main(args: {string})` receives. */
+  args?: string[];
+  /** The variables `env(name)` reads. */
+  env?: Record<string, string>;"#;
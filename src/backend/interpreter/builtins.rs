@@ -0,0 +1,230 @@
+use crate::ast::{Builtin, Extern, IRExpr, Type, TypeKind};
+use crate::error::CompilerError;
+use crate::host::HostValue;
+
+use super::memory::{dtype, Space};
+use super::{Interpreter, Result};
+
+/// Field offsets of the prelude's `Builder` struct.
+const BUILDER_BUFFER_OFFSET: u32 = 0;
+const BUILDER_LENGTH_OFFSET: u32 = 8;
+
+/// The struct the wrapper gives nullable values, and the tag of one that
+/// holds a string.
+const TAGGED_UNION: u32 = 0;
+const TAG_ERROR: u64 = 1;
+const TAG_PRIMITIVE: u64 = 2;
+const TAG_LIST: u64 = 4;
+
+impl Interpreter<'_> {
+    /// Runs `builtin` on the evaluated `values` of `args`.
+    pub(super) fn builtin(
+        &mut self,
+        builtin: Builtin,
+        args: &[IRExpr],
+        values: &[u64],
+    ) -> Result<u64> {
+        let heap = &mut self.heap;
+        let value = match builtin {
+            Builtin::BuilderAppend => {
+                let (builder, piece) = (values[0] as u32, values[1] as u32);
+                let buffer = heap.load(Space::Alloc, builder + BUILDER_BUFFER_OFFSET)? as u32;
+                let length = heap.load(Space::Alloc, builder + BUILDER_LENGTH_OFFSET)?;
+                let buffer = heap.build(buffer, length as u32, piece)?;
+                let length = length + heap.length(piece)? as u64;
+                heap.store(Space::Alloc, builder + BUILDER_BUFFER_OFFSET, buffer as u64)?;
+                heap.store(Space::Alloc, builder + BUILDER_LENGTH_OFFSET, length)?;
+                builder as u64
+            }
+            Builtin::BuilderToString => {
+                let builder = values[0] as u32;
+                let buffer = heap.load(Space::Alloc, builder + BUILDER_BUFFER_OFFSET)? as u32;
+                let length = heap.load(Space::Alloc, builder + BUILDER_LENGTH_OFFSET)?;
+                heap.slice(buffer, 0, length as i32)? as u64
+            }
+            Builtin::ListPush => heap.append(values[0] as u32, values[1])? as u64,
+            Builtin::StringLength => heap.chars(values[0] as u32)? as u64,
+            Builtin::StringFind => heap.find(values[0] as u32, values[1] as u32)? as u64,
+            Builtin::StringSubstring => {
+                heap.substring(values[0] as u32, values[1] as i64, values[2] as i64)? as u64
+            }
+            Builtin::StringSplit => heap.split(values[0] as u32, values[1] as u32)? as u64,
+            Builtin::StringToUpper => heap.upper(values[0] as u32)? as u64,
+            Builtin::StringToLower => heap.lower(values[0] as u32)? as u64,
+            Builtin::StringTrim => heap.trim(values[0] as u32)? as u64,
+            Builtin::Ord => heap.ord(values[0] as u32)? as u64,
+            Builtin::Chr => heap.chr(values[0] as i64)? as u64,
+            Builtin::StringReplace => {
+                heap.replace(values[0] as u32, values[1] as u32, values[2] as u32)? as u64
+            }
+            Builtin::Repeat => {
+                let count = values[1] as i64;
+                if count < 0 {
+                    return Err(super::trap("negative repeat count"));
+                }
+                heap.fill(list_dtype(&args[0].ty), values[0], count as u32)? as u64
+            }
+            Builtin::ToInteger => f64::from_bits(values[0]) as i64 as u64,
+            Builtin::ToFloat => (values[0] as i64 as f64).to_bits(),
+            Builtin::Abs | Builtin::Min | Builtin::Max if args[0].ty.kind == TypeKind::Integer => {
+                let (a, b) = (values[0] as i64, values.get(1).copied().unwrap_or(0) as i64);
+                match builtin {
+                    Builtin::Abs => a.wrapping_abs() as u64,
+                    Builtin::Min => a.min(b) as u64,
+                    _ => a.max(b) as u64,
+                }
+            }
+            Builtin::Abs
+            | Builtin::Min
+            | Builtin::Max
+            | Builtin::Sqrt
+            | Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Round
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Tan
+            | Builtin::Asin
+            | Builtin::Acos
+            | Builtin::Atan
+            | Builtin::Atan2 => {
+                let x = f64::from_bits(values[0]);
+                let y = values.get(1).map_or(0.0, |&y| f64::from_bits(y));
+                match builtin {
+                    Builtin::Abs => x.abs(),
+                    Builtin::Min => x.min(y),
+                    Builtin::Max => x.max(y),
+                    Builtin::Sqrt => x.sqrt(),
+                    Builtin::Floor => x.floor(),
+                    Builtin::Ceil => x.ceil(),
+                    Builtin::Round => x.round(),
+                    Builtin::Sin => x.sin(),
+                    Builtin::Cos => x.cos(),
+                    Builtin::Tan => x.tan(),
+                    Builtin::Asin => x.asin(),
+                    Builtin::Acos => x.acos(),
+                    Builtin::Atan => x.atan(),
+                    _ => x.atan2(y),
+                }
+                .to_bits()
+            }
+            Builtin::Present => (heap.load(Space::Alloc, values[0] as u32)? != 0) as u64,
+            Builtin::Holds => (heap.load(Space::Alloc, values[0] as u32)? >= TAG_PRIMITIVE) as u64,
+            Builtin::HoldsError => {
+                let union = values[0] as u32;
+                let holds = heap.load(Space::Alloc, union)? == TAG_ERROR
+                    && heap.struct_id(heap.load(Space::Alloc, union + 8)? as u32)? as u64
+                        == values[1];
+                holds as u64
+            }
+            Builtin::HeldError => heap.load(Space::Alloc, values[0] as u32 + 8)?,
+            // Nothing is ever collected, so nothing is ever free.
+            Builtin::HeapUsed => heap.used(),
+            Builtin::HeapFree | Builtin::GcCount | Builtin::LargestFreeBlock => 0,
+            Builtin::AllocatedSinceGc => heap.allocated,
+            Builtin::Collect | Builtin::SetGcThreshold => 0,
+            Builtin::JsonReset => {
+                heap.json_reset();
+                1
+            }
+            Builtin::JsonFail => heap.json_fail() as u64,
+            Builtin::JsonFinish => heap.json_finish(values[0] as u32)? as u64,
+            Builtin::JsonEat => heap.json_eat(values[0] as u32, values[1] as u32)? as u64,
+            Builtin::JsonExpect => heap.json_expect(values[0] as u32, values[1] as u32)? as u64,
+            Builtin::JsonNull => heap.json_null(values[0] as u32)? as u64,
+            Builtin::JsonBoolean => heap.json_boolean(values[0] as u32)? as u64,
+            Builtin::JsonInteger => heap.json_integer(values[0] as u32)? as u64,
+            Builtin::JsonFloat => heap.json_float(values[0] as u32)?.to_bits(),
+            Builtin::JsonString => heap.json_string(values[0] as u32)? as u64,
+            Builtin::JsonSkip => heap.json_skip(values[0] as u32)? as u64,
+            Builtin::JsonQuote => heap.json_quote(values[0] as u32)? as u64,
+            Builtin::ParseInteger => heap.atoi(values[0] as u32)? as u64,
+            Builtin::ParseFloat => heap.atof(values[0] as u32)?.to_bits(),
+            Builtin::ParseFailed => heap.parse_failed as u64,
+            Builtin::CompareStrings => {
+                let (a, b) = (
+                    heap.string(values[0] as u32)?,
+                    heap.string(values[1] as u32)?,
+                );
+                a.cmp(b) as i64 as u64
+            }
+            Builtin::Sleep => self.io.sleep(values[0] as i64) as u64,
+            Builtin::Fetch => {
+                let url = String::from_utf8_lossy(heap.string(values[0] as u32)?).into_owned();
+                let body = self
+                    .io
+                    .fetch(&url)
+                    .map_err(|message| CompilerError::Runtime { message })?;
+                self.heap.alloc_string(body.as_bytes())? as u64
+            }
+            Builtin::Exit => {
+                self.exit_code = Some(values[0] as i64);
+                0
+            }
+            Builtin::Env => {
+                let name = String::from_utf8_lossy(heap.string(values[0] as u32)?).into_owned();
+                let value = self.io.env(&name);
+                let wrapper = self.heap.falloc(TAGGED_UNION)?;
+                if let Some(value) = value {
+                    let string = self.heap.alloc_string(value.as_bytes())?;
+                    self.heap.store(Space::Alloc, wrapper, TAG_LIST)?;
+                    self.heap.store(Space::Alloc, wrapper + 8, string as u64)?;
+                }
+                wrapper as u64
+            }
+        };
+        Ok(value)
+    }
+
+    /// Hands the evaluated `values` of `args` to the host's `function`, and
+    /// its answer back as a value of type `returns`.
+    pub(super) fn extern_call(
+        &mut self,
+        function: &Extern,
+        returns: &Type,
+        args: &[IRExpr],
+        values: &[u64],
+    ) -> Result<u64> {
+        let mut host_args = Vec::new();
+        for (arg, &value) in args.iter().zip(values) {
+            host_args.push(match arg.ty.kind {
+                TypeKind::Float => HostValue::Float(f64::from_bits(value)),
+                TypeKind::Boolean => HostValue::Boolean(value != 0),
+                TypeKind::String => HostValue::String(
+                    String::from_utf8_lossy(self.heap.string(value as u32)?).into_owned(),
+                ),
+                _ => HostValue::Integer(value as i64),
+            });
+        }
+        let result = self
+            .io
+            .call(&function.module, &function.name, &host_args)
+            .map_err(|message| CompilerError::Runtime { message })?;
+        match (&returns.kind, result) {
+            (TypeKind::Integer, HostValue::Integer(n)) => Ok(n as u64),
+            (TypeKind::Float, HostValue::Float(n)) => Ok(n.to_bits()),
+            (TypeKind::Boolean, HostValue::Boolean(b)) => Ok(b as u64),
+            (TypeKind::String, HostValue::String(s)) => {
+                Ok(self.heap.alloc_string(s.as_bytes())? as u64)
+            }
+            (_, result) => Err(CompilerError::Runtime {
+                message: format!(
+                    "{}.{} returned {:?} where {} was expected",
+                    function.module, function.name, result, returns
+                ),
+            }),
+        }
+    }
+}
+
+/// The dalloc block type of a list with `element`s.
+pub(super) fn list_dtype(element: &Type) -> u32 {
+    if element.nullable || element.errorable {
+        return dtype::STRUCTS;
+    }
+    match element.kind {
+        TypeKind::Struct { .. } => dtype::STRUCTS,
+        TypeKind::List { .. } | TypeKind::String => dtype::LISTS,
+        _ => dtype::PRIMITIVE,
+    }
+}
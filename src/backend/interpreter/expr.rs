@@ -0,0 +1,488 @@
+use crate::ast::{BinaryOp, Builtin, Extern, IRExpr, IRExprKind, IRStructKind, Type, TypeKind, UnaryOp};
+use crate::error::CompilerError;
+use crate::host::AsyncState;
+
+use super::builtins::list_dtype;
+use super::memory::{dtype, Space};
+use super::{trap, Interpreter, Result};
+
+/// Tags of the tagged union behind nullable and errorable values.
+const TAG_NULL: u64 = 0;
+const TAG_ERROR: u64 = 1;
+
+impl Interpreter<'_> {
+    /// Evaluates `expr` to the bits of its value: integers and floats as
+    /// themselves, booleans and pointers zero-extended, the way they are
+    /// stored in struct fields and list elements.
+    pub(super) fn eval(&mut self, expr: &IRExpr, locals: &mut [u64]) -> Result<u64> {
+        // Everything past a leaf lives in its own method: debug builds give
+        // every arm's temporaries their own stack slots, and this frame is
+        // on the stack once per nested expression.
+        match &expr.node {
+            IRExprKind::Integer(n) => Ok(*n as u64),
+            IRExprKind::Float(n) => Ok(n.to_bits()),
+            IRExprKind::Boolean(b) => Ok(*b as u64),
+            IRExprKind::String(s) => self.eval_string(s),
+            IRExprKind::Null => Ok(0),
+            // Awaits are answered as they are made, so nothing ever
+            // suspends, but an `exit` unwinds as it does compiled.
+            IRExprKind::AsyncState => Ok(match self.exit_code {
+                Some(_) => AsyncState::Exiting as u64,
+                None => AsyncState::Running as u64,
+            }),
+            IRExprKind::Local(index) => Ok(locals[*index as usize]),
+            IRExprKind::Global(index) => Ok(self.globals[*index as usize]),
+            IRExprKind::Function(fn_index) => Ok((*fn_index as u64) << 32),
+            IRExprKind::Binary { left, op, right } => self.eval_binary(left, op, right, locals),
+            IRExprKind::Unary { op, expr } => self.eval_unary(op, expr, locals),
+            IRExprKind::Call { callee, args } => self.eval_call(callee, args, locals),
+            IRExprKind::CallDirect { fn_index, args } => {
+                let values = self.eval_all(args, locals)?;
+                self.call(*fn_index, 0, values)
+            }
+            IRExprKind::Builtin { builtin, args } => self.eval_builtin(*builtin, args, locals),
+            IRExprKind::ExternCall { function, args } => {
+                self.eval_extern_call(function, &expr.ty, args, locals)
+            }
+            IRExprKind::New {
+                struct_index,
+                fields,
+            } => self.eval_new(*struct_index, fields, locals),
+            IRExprKind::Field { object, offset } => self.eval_load(Space::Alloc, |this| {
+                this.field_address(object, *offset, locals)
+            }),
+            IRExprKind::FieldReference { object, offset } => {
+                self.eval_address(|this| this.field_address(object, *offset, locals))
+            }
+            IRExprKind::Index { list, index } => self.eval_load(Space::Dalloc, |this| {
+                this.element_address(list, index, locals)
+            }),
+            IRExprKind::IndexReference { list, index } => {
+                self.eval_address(|this| this.element_address(list, index, locals))
+            }
+            IRExprKind::Slice { expr, start, end } => self.eval_slice(expr, start, end, locals),
+            IRExprKind::List(elements) => self.eval_list(&expr.ty, elements, locals),
+            IRExprKind::Array(_) => Err(trap("array literals can only initialize struct fields")),
+            IRExprKind::ArrayIndex {
+                array,
+                index,
+                length,
+            } => self.eval_load(Space::Alloc, |this| {
+                this.array_address(array, index, *length, locals)
+            }),
+            IRExprKind::ArrayIndexReference {
+                array,
+                index,
+                length,
+            } => self.eval_address(|this| this.array_address(array, index, *length, locals)),
+            IRExprKind::SliceReference { .. } => {
+                Err(trap("slice references can only be assigned to"))
+            }
+            IRExprKind::Match { .. } => Err(trap("match expressions are not supported yet")),
+            IRExprKind::UnwrapError(inside) => {
+                self.eval_unwrap(inside, TAG_ERROR, &expr.ty, locals)
+            }
+            IRExprKind::UnwrapNull(inside) => self.eval_unwrap(inside, TAG_NULL, &expr.ty, locals),
+        }
+    }
+
+    fn eval_string(&mut self, s: &str) -> Result<u64> {
+        Ok(self.heap.alloc_string(s.as_bytes())? as u64)
+    }
+
+    fn eval_builtin(
+        &mut self,
+        builtin: Builtin,
+        args: &[IRExpr],
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        let values = self.eval_all(args, locals)?;
+        self.builtin(builtin, args, &values)
+    }
+
+    fn eval_extern_call(
+        &mut self,
+        function: &Extern,
+        returns: &Type,
+        args: &[IRExpr],
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        let values = self.eval_all(args, locals)?;
+        self.extern_call(function, returns, args, &values)
+    }
+
+    fn eval_new(
+        &mut self,
+        struct_index: u32,
+        fields: &[IRExpr],
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        let ptr = self.heap.falloc(struct_index)?;
+        self.fill_struct(ptr, fields, locals)?;
+        Ok(ptr as u64)
+    }
+
+    /// Loads the word at the address `place` computes.
+    fn eval_load(
+        &mut self,
+        space: Space,
+        place: impl FnOnce(&mut Self) -> Result<u32>,
+    ) -> Result<u64> {
+        let address = place(self)?;
+        self.heap.load(space, address)
+    }
+
+    fn eval_address(&mut self, place: impl FnOnce(&mut Self) -> Result<u32>) -> Result<u64> {
+        Ok(place(self)? as u64)
+    }
+
+    fn eval_all(&mut self, exprs: &[IRExpr], locals: &mut [u64]) -> Result<Vec<u64>> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for expr in exprs {
+            values.push(self.eval(expr, locals)?);
+        }
+        Ok(values)
+    }
+
+    fn eval_binary(
+        &mut self,
+        left: &IRExpr,
+        op: &BinaryOp,
+        right: &IRExpr,
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        match op {
+            BinaryOp::Is => self.eval_assignment(left, right, locals),
+            BinaryOp::And => {
+                if self.eval(left, locals)? != 0 {
+                    self.eval(right, locals)
+                } else {
+                    Ok(0)
+                }
+            }
+            BinaryOp::Or => {
+                if self.eval(left, locals)? != 0 {
+                    Ok(1)
+                } else {
+                    self.eval(right, locals)
+                }
+            }
+            _ => {
+                let l = self.eval(left, locals)?;
+                let r = self.eval(right, locals)?;
+                self.binary(op, &left.ty.kind, l, r)
+            }
+        }
+    }
+
+    fn eval_unary(&mut self, op: &UnaryOp, expr: &IRExpr, locals: &mut [u64]) -> Result<u64> {
+        if let (UnaryOp::Count, TypeKind::Array { length, .. }) = (op, &expr.ty.kind) {
+            return Ok(*length as u64);
+        }
+        let value = self.eval(expr, locals)?;
+        match op {
+            UnaryOp::Minus if expr.ty.kind == TypeKind::Float => {
+                Ok((-f64::from_bits(value)).to_bits())
+            }
+            UnaryOp::Minus => Ok(0u64.wrapping_sub(value)),
+            UnaryOp::Not => Ok((value == 0) as u64),
+            UnaryOp::Count => Ok(self.heap.length(value as u32)? as u64),
+            UnaryOp::Stringify => Ok(self.stringify(&expr.ty, value)? as u64),
+        }
+    }
+
+    fn eval_call(&mut self, callee: &IRExpr, args: &[IRExpr], locals: &mut [u64]) -> Result<u64> {
+        let callee = self.eval(callee, locals)?;
+        let values = self.eval_all(args, locals)?;
+        self.call((callee >> 32) as u32, callee as u32, values)
+    }
+
+    fn eval_slice(
+        &mut self,
+        list: &IRExpr,
+        start: &IRExpr,
+        end: &IRExpr,
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        let list = self.eval(list, locals)? as u32;
+        let start = self.eval(start, locals)? as i32;
+        let end = self.eval(end, locals)? as i32;
+        Ok(self.heap.slice(list, start, end)? as u64)
+    }
+
+    fn eval_list(&mut self, ty: &Type, elements: &[IRExpr], locals: &mut [u64]) -> Result<u64> {
+        let ty = match &ty.kind {
+            TypeKind::List { element } => list_dtype(element),
+            _ => dtype::PRIMITIVE,
+        };
+        let list = self.heap.dalloc(ty, elements.len() as u32)?;
+        for (i, element) in elements.iter().enumerate() {
+            let value = self.eval(element, locals)?;
+            self.heap.store(Space::Dalloc, list + 8 * i as u32, value)?;
+        }
+        Ok(list as u64)
+    }
+
+    fn eval_unwrap(
+        &mut self,
+        inside: &IRExpr,
+        tag: u64,
+        ty: &Type,
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        let value = self.eval(inside, locals)?;
+        self.unwrap(value, tag, ty)
+    }
+
+    /// `left is right`, which evaluates to what the compiled assignment
+    /// leaves behind: the value for locals and array slots, the place for
+    /// everything else.
+    fn eval_assignment(
+        &mut self,
+        left: &IRExpr,
+        right: &IRExpr,
+        locals: &mut [u64],
+    ) -> Result<u64> {
+        match &left.node {
+            IRExprKind::Local(index) => {
+                let value = self.eval(right, locals)?;
+                locals[*index as usize] = value;
+                Ok(value)
+            }
+            IRExprKind::Global(index) => {
+                let value = self.eval(right, locals)?;
+                self.globals[*index as usize] = value;
+                Ok(value)
+            }
+            IRExprKind::FieldReference { .. } => {
+                let address = self.eval(left, locals)? as u32;
+                if let IRExprKind::Array(_) = &right.node {
+                    // Copy the literal into the field's slots.
+                    self.fill_struct(address, std::slice::from_ref(right), locals)?;
+                } else {
+                    let value = self.eval(right, locals)?;
+                    self.heap.store(Space::Alloc, address, value)?;
+                }
+                Ok(address as u64)
+            }
+            IRExprKind::SliceReference { list, start, end } => {
+                let list = self.eval(list, locals)? as u32;
+                let start = self.eval(start, locals)? as i32;
+                let end = self.eval(end, locals)? as i32;
+                let source = self.eval(right, locals)? as u32;
+                match self.heap.splice(list, start, end, source)? {
+                    Some(source) => Ok(source as u64),
+                    None => Err(trap("slice assignment out of bounds")),
+                }
+            }
+            IRExprKind::ArrayIndexReference { .. } => {
+                let address = self.eval(left, locals)? as u32;
+                let value = self.eval(right, locals)?;
+                self.heap.store(Space::Alloc, address, value)?;
+                Ok(value)
+            }
+            IRExprKind::IndexReference { .. } => {
+                let address = self.eval(left, locals)? as u32;
+                let value = self.eval(right, locals)?;
+                self.heap.store(Space::Dalloc, address, value)?;
+                Ok(address as u64)
+            }
+            _ => Err(trap("can only assign to locals, fields and elements")),
+        }
+    }
+
+    fn binary(&mut self, op: &BinaryOp, kind: &TypeKind, l: u64, r: u64) -> Result<u64> {
+        let float = *kind == TypeKind::Float;
+        let pointers = *kind == TypeKind::String || matches!(kind, TypeKind::List { .. });
+        let (fl, fr) = (f64::from_bits(l), f64::from_bits(r));
+        let (il, ir) = (l as i64, r as i64);
+        let value = match op {
+            BinaryOp::Plus if float => (fl + fr).to_bits(),
+            BinaryOp::Plus if *kind == TypeKind::Integer => il.wrapping_add(ir) as u64,
+            BinaryOp::Plus => self.heap.concat(l as u32, r as u32)? as u64,
+            BinaryOp::Minus if float => (fl - fr).to_bits(),
+            BinaryOp::Minus if *kind == TypeKind::Integer => il.wrapping_sub(ir) as u64,
+            BinaryOp::Multiply if float => (fl * fr).to_bits(),
+            BinaryOp::Multiply if pointers => self.heap.repeat(l as u32, ir)? as u64,
+            BinaryOp::Multiply => il.wrapping_mul(ir) as u64,
+            BinaryOp::Divide if float => (fl / fr).to_bits(),
+            BinaryOp::Divide => match ir {
+                0 => return Err(trap("integer divide by zero")),
+                -1 if il == i64::MIN => return Err(trap("integer overflow")),
+                _ => (il / ir) as u64,
+            },
+            BinaryOp::Modulo if ir == 0 => return Err(trap("integer divide by zero")),
+            BinaryOp::Modulo => il.wrapping_rem(ir) as u64,
+            BinaryOp::BitwiseAnd => l & r,
+            BinaryOp::BitwiseOr => l | r,
+            BinaryOp::Xor => l ^ r,
+            BinaryOp::Sll => l.wrapping_shl(r as u32),
+            BinaryOp::Sra => il.wrapping_shr(r as u32) as u64,
+            BinaryOp::Srl => l.wrapping_shr(r as u32),
+            BinaryOp::Eq if pointers => self.heap.equal(l as u32, r as u32)? as u64,
+            BinaryOp::Eq if float => (fl == fr) as u64,
+            BinaryOp::Eq => (l == r) as u64,
+            BinaryOp::Neq if pointers => !self.heap.equal(l as u32, r as u32)? as u64,
+            BinaryOp::Neq if float => (fl != fr) as u64,
+            BinaryOp::Neq => (l != r) as u64,
+            BinaryOp::Lt if float => (fl < fr) as u64,
+            BinaryOp::Lt => (il < ir) as u64,
+            BinaryOp::Gt if float => (fl > fr) as u64,
+            BinaryOp::Gt => (il > ir) as u64,
+            BinaryOp::Lte if float => (fl <= fr) as u64,
+            BinaryOp::Lte => (il <= ir) as u64,
+            BinaryOp::Gte if float => (fl >= fr) as u64,
+            BinaryOp::Gte => (il >= ir) as u64,
+            BinaryOp::In => self.heap.contains(l, r as u32)? as u64,
+            BinaryOp::Minus => return Err(trap("cannot subtract non-numeric types")),
+            _ => {
+                return Err(CompilerError::Runtime {
+                    message: format!("unsupported binary operation: {:?}", op),
+                })
+            }
+        };
+        Ok(value)
+    }
+
+    /// Stores `fields` into consecutive slots from `ptr`, array literals
+    /// taking one slot per element.
+    fn fill_struct(&mut self, ptr: u32, fields: &[IRExpr], locals: &mut [u64]) -> Result<()> {
+        let mut offset = 0;
+        for field in fields {
+            let elements = match &field.node {
+                IRExprKind::Array(elements) => elements.as_slice(),
+                _ => std::slice::from_ref(field),
+            };
+            for element in elements {
+                let value = self.eval(element, locals)?;
+                self.heap
+                    .store(Space::Alloc, ptr.wrapping_add(offset), value)?;
+                offset += 8;
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn fill_captures(
+        &mut self,
+        ptr: u32,
+        captures: &IRExpr,
+        locals: &mut [u64],
+    ) -> Result<()> {
+        match &captures.node {
+            IRExprKind::New { fields, .. } => self.fill_struct(ptr, fields, locals),
+            _ => Err(trap("captures must be a local struct allocation")),
+        }
+    }
+
+    fn field_address(&mut self, object: &IRExpr, offset: u32, locals: &mut [u64]) -> Result<u32> {
+        let object = self.eval(object, locals)? as u32;
+        Ok(object.wrapping_add(offset))
+    }
+
+    /// The address of element `index` of a list, checking the index outside
+    /// `unchecked` blocks.
+    fn element_address(
+        &mut self,
+        list: &IRExpr,
+        index: &IRExpr,
+        locals: &mut [u64],
+    ) -> Result<u32> {
+        let list = self.eval(list, locals)? as u32;
+        let index = self.eval(index, locals)?;
+        if !self.unchecked && index >= self.heap.length(list)? as u64 {
+            return Err(trap("list index out of bounds"));
+        }
+        Ok(list.wrapping_add(index.wrapping_mul(8) as u32))
+    }
+
+    /// The address of a fixed array slot, checking the index outside
+    /// `unchecked` blocks.
+    fn array_address(
+        &mut self,
+        array: &IRExpr,
+        index: &IRExpr,
+        length: u32,
+        locals: &mut [u64],
+    ) -> Result<u32> {
+        let array = self.eval(array, locals)? as u32;
+        let index = self.eval(index, locals)?;
+        if !self.unchecked && index >= length as u64 {
+            return Err(trap("array index out of bounds"));
+        }
+        Ok(array.wrapping_add(index.wrapping_mul(8) as u32))
+    }
+
+    /// Unwraps the tagged union at `value`, failing when its tag is `tag`.
+    /// A value that is still nullable or errorable afterwards stays wrapped.
+    fn unwrap(&self, value: u64, tag: u64, ty: &Type) -> Result<u64> {
+        if self.heap.load(Space::Alloc, value as u32)? == tag {
+            return Err(trap(if tag == TAG_NULL {
+                "unwrapped a null value"
+            } else {
+                "unwrapped an error"
+            }));
+        }
+        if ty.nullable || ty.errorable {
+            return Ok(value);
+        }
+        self.heap.load(Space::Alloc, value as u32 + 8)
+    }
+
+    /// The string form of `value`, a `ty`. Nullable and errorable values
+    /// read `null` or `error(Name)` when they don't hold a value.
+    pub(super) fn stringify(&mut self, ty: &Type, value: u64) -> Result<u32> {
+        if ty.nullable || ty.errorable {
+            let tag = self.heap.load(Space::Alloc, value as u32)?;
+            let inner = self.heap.load(Space::Alloc, value as u32 + 8)?;
+            return match tag {
+                TAG_NULL if ty.nullable => self.heap.alloc_string(b"null"),
+                TAG_NULL => Err(trap("unreachable")),
+                TAG_ERROR => {
+                    let id = self.heap.struct_id(inner as u32)?;
+                    let name = self.error_name(id)?;
+                    self.heap
+                        .alloc_string(format!("error({})", name).as_bytes())
+                }
+                _ => {
+                    let value_ty = Type {
+                        kind: ty.kind.clone(),
+                        nullable: false,
+                        errorable: false,
+                        errors: vec![],
+                    };
+                    self.stringify(&value_ty, inner)
+                }
+            };
+        }
+
+        match ty.kind {
+            TypeKind::Integer => self.heap.itoa(value as i64),
+            TypeKind::Boolean => self.heap.btoa(value != 0),
+            TypeKind::Float => self.heap.ftoa(f64::from_bits(value)),
+            TypeKind::String => Ok(value as u32),
+            _ => Err(CompilerError::Runtime {
+                message: format!("cannot stringify type {:?}", ty),
+            }),
+        }
+    }
+
+    /// The name of the error type `id`. Like compiled code, an id that is
+    /// no error type's names the last one.
+    fn error_name(&self, id: u32) -> Result<String> {
+        let errors = self
+            .program
+            .structs
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| matches!(s.kind, IRStructKind::Error));
+        let mut last = None;
+        for (index, error) in errors {
+            if index as u32 == id {
+                return Ok(error.name.clone());
+            }
+            last = Some(error.name.clone());
+        }
+        last.ok_or_else(|| trap("unreachable"))
+    }
+}
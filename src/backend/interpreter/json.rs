@@ -0,0 +1,469 @@
+//! The runtime's JSON scanner, which the generated `to_json` and
+//! `from_json` helpers call. It reads the document straight out of dalloc
+//! memory, keeping its position and failure flag on the heap just as
+//! dalloc keeps them in the words below its blocks.
+
+use crate::error::CompilerError;
+
+use super::memory::{dtype, Heap};
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+/// Returned by `peek` past the end of the document.
+const END: u32 = 0x100;
+
+fn is_digit(c: u32) -> bool {
+    (b'0' as u32..=b'9' as u32).contains(&c)
+}
+
+fn hex_value(c: u32) -> Option<u32> {
+    match c {
+        0x30..=0x39 => Some(c - 0x30),
+        0x41..=0x46 => Some(c - 0x41 + 10),
+        0x61..=0x66 => Some(c - 0x61 + 10),
+        _ => None,
+    }
+}
+
+fn hex_digit(value: u32) -> u8 {
+    if value < 10 {
+        b'0' + value as u8
+    } else {
+        b'a' + (value - 10) as u8
+    }
+}
+
+fn byte_at(s: &[u8], at: u32) -> u32 {
+    s.get(at as usize).map_or(END, |&c| c as u32)
+}
+
+fn read_hex4(s: &[u8], at: u32) -> Option<u32> {
+    let mut value = 0;
+    for i in 0..4 {
+        value = value * 16 + hex_value(byte_at(s, at + i))?;
+    }
+    Some(value)
+}
+
+/// Decodes the escape whose backslash is at `at`, returning the code point
+/// (or byte) it stands for and the position after it.
+fn read_escape(s: &[u8], at: u32) -> Option<(u32, u32)> {
+    let c = byte_at(s, at + 1);
+    let simple = match c {
+        0x22 | 0x5c | 0x2f => c,
+        0x62 => 0x08,
+        0x66 => 0x0c,
+        0x6e => 0x0a,
+        0x72 => 0x0d,
+        0x74 => 0x09,
+        0x75 => {
+            let high = read_hex4(s, at + 2)?;
+            if !(0xd800..0xe000).contains(&high) {
+                return Some((high, at + 6));
+            }
+            // A surrogate pair, spelled as two escapes.
+            if high >= 0xdc00 || byte_at(s, at + 6) != 0x5c || byte_at(s, at + 7) != 0x75 {
+                return None;
+            }
+            let low = read_hex4(s, at + 8)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return None;
+            }
+            return Some((0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00), at + 12));
+        }
+        _ => return None,
+    };
+    Some((simple, at + 2))
+}
+
+fn push_utf8(out: &mut Vec<u8>, code_point: u32) {
+    // Lone surrogates aren't chars, but the runtime encodes them all the same.
+    let len = match code_point {
+        0..=0x7f => 1,
+        0x80..=0x7ff => 2,
+        0x800..=0xffff => 3,
+        _ => 4,
+    };
+    if len == 1 {
+        out.push(code_point as u8);
+        return;
+    }
+    let lead = match len {
+        2 => 0xc0,
+        3 => 0xe0,
+        _ => 0xf0,
+    };
+    out.push((lead | (code_point >> (6 * (len - 1)))) as u8);
+    for i in 1..len {
+        out.push((0x80 | ((code_point >> (6 * (len - 1 - i))) & 0x3f)) as u8);
+    }
+}
+
+/// The decoded bytes of the string whose opening quote is at `start`, and
+/// the position of its closing quote, or `None` when it is malformed.
+fn decode_string(s: &[u8], start: u32) -> Option<(Vec<u8>, u32)> {
+    let mut at = start + 1;
+    let mut out = vec![];
+    loop {
+        match byte_at(s, at) {
+            0x22 => return Some((out, at)),
+            0x5c => {
+                let (code_point, next) = read_escape(s, at)?;
+                push_utf8(&mut out, code_point);
+                at = next;
+            }
+            c if c < 0x20 || c == END => return None,
+            c => {
+                out.push(c as u8);
+                at += 1;
+            }
+        }
+    }
+}
+
+/// The escape that stands for `c` inside a JSON string, if it needs one
+/// shorter than `\u00XX`.
+fn short_escape(c: u8) -> Option<u8> {
+    match c {
+        0x22 => Some(b'"'),
+        0x5c => Some(b'\\'),
+        0x08 => Some(b'b'),
+        0x0c => Some(b'f'),
+        0x0a => Some(b'n'),
+        0x0d => Some(b'r'),
+        0x09 => Some(b't'),
+        _ => None,
+    }
+}
+
+/// Reads a number without a fraction or exponent that fits in an i64,
+/// starting at `at`. Returns it with the position after it.
+pub(super) fn read_integer(s: &[u8], at: u32) -> Option<(i64, u32)> {
+    let mut at = at;
+    let negative = byte_at(s, at) == b'-' as u32;
+    if negative {
+        at += 1;
+    }
+    if !is_digit(byte_at(s, at)) {
+        return None;
+    }
+    // Accumulate below zero so that i64::MIN fits.
+    let mut value: i64 = 0;
+    while is_digit(byte_at(s, at)) {
+        let digit = (byte_at(s, at) - b'0' as u32) as i64;
+        value = value.checked_mul(10)?.checked_sub(digit)?;
+        at += 1;
+    }
+    if matches!(byte_at(s, at), 0x2e | 0x45 | 0x65) {
+        // A fraction or exponent: not an integer.
+        return None;
+    }
+    let value = if negative {
+        value
+    } else {
+        value.checked_neg()?
+    };
+    Some((value, at))
+}
+
+/// Reads any number starting at `at`, returning it with the position after
+/// it. Digits past the nineteenth only scale the result, so very long
+/// mantissas lose precision.
+pub(super) fn read_float(s: &[u8], at: u32) -> Option<(f64, u32)> {
+    let mut at = at;
+    let negative = byte_at(s, at) == b'-' as u32;
+    if negative {
+        at += 1;
+    }
+    if !is_digit(byte_at(s, at)) {
+        return None;
+    }
+
+    let mut mantissa: u64 = 0;
+    let mut digits = 0;
+    let mut exponent: i32 = 0;
+    while is_digit(byte_at(s, at)) {
+        if digits < 19 {
+            mantissa = mantissa * 10 + (byte_at(s, at) - b'0' as u32) as u64;
+            digits += 1;
+        } else {
+            exponent += 1;
+        }
+        at += 1;
+    }
+    if byte_at(s, at) == b'.' as u32 {
+        at += 1;
+        if !is_digit(byte_at(s, at)) {
+            return None;
+        }
+        while is_digit(byte_at(s, at)) {
+            if digits < 19 {
+                mantissa = mantissa * 10 + (byte_at(s, at) - b'0' as u32) as u64;
+                digits += 1;
+                exponent -= 1;
+            }
+            at += 1;
+        }
+    }
+    if matches!(byte_at(s, at), 0x45 | 0x65) {
+        at += 1;
+        let negative_exponent = byte_at(s, at) == b'-' as u32;
+        if negative_exponent || byte_at(s, at) == b'+' as u32 {
+            at += 1;
+        }
+        if !is_digit(byte_at(s, at)) {
+            return None;
+        }
+        let mut written: i32 = 0;
+        while is_digit(byte_at(s, at)) {
+            if written < 10000 {
+                written = written * 10 + (byte_at(s, at) - b'0' as u32) as i32;
+            }
+            at += 1;
+        }
+        exponent += if negative_exponent { -written } else { written };
+    }
+
+    let mut value = mantissa as f64;
+    let mut scale = 1.0;
+    for _ in 0..exponent.unsigned_abs().min(400) {
+        scale *= 10.0;
+    }
+    if exponent < 0 {
+        value /= scale;
+    } else {
+        value *= scale;
+    }
+    Some((if negative { -value } else { value }, at))
+}
+
+impl Heap {
+    fn fail(&mut self) {
+        self.json_failed = true;
+    }
+
+    fn peek(&self, s: u32) -> Result<u32> {
+        Ok(byte_at(self.string(s)?, self.json_cursor))
+    }
+
+    fn skip_whitespace(&mut self, s: u32) -> Result<()> {
+        let document = self.string(s)?;
+        let mut at = self.json_cursor;
+        while matches!(byte_at(document, at), 0x20 | 0x09 | 0x0a | 0x0d) {
+            at += 1;
+        }
+        self.json_cursor = at;
+        Ok(())
+    }
+
+    /// Consumes `word` if the document continues with it.
+    fn eat_word(&mut self, s: u32, word: &[u8]) -> Result<bool> {
+        let document = self.string(s)?;
+        let at = self.json_cursor as usize;
+        if !document[at.min(document.len())..].starts_with(word) {
+            return Ok(false);
+        }
+        self.json_cursor += word.len() as u32;
+        Ok(true)
+    }
+
+    /// Starts reading a new document.
+    pub(super) fn json_reset(&mut self) {
+        self.json_cursor = 0;
+        self.json_failed = false;
+    }
+
+    pub(super) fn json_fail(&mut self) -> bool {
+        self.fail();
+        false
+    }
+
+    /// Whether every read succeeded and only whitespace is left.
+    pub(super) fn json_finish(&mut self, s: u32) -> Result<bool> {
+        self.skip_whitespace(s)?;
+        Ok(!self.json_failed && self.peek(s)? == END)
+    }
+
+    /// Consumes `c` if it is the next character after whitespace.
+    pub(super) fn json_eat(&mut self, s: u32, c: u32) -> Result<bool> {
+        if self.json_failed {
+            return Ok(false);
+        }
+        self.skip_whitespace(s)?;
+        if self.peek(s)? != c {
+            return Ok(false);
+        }
+        self.json_cursor += 1;
+        Ok(true)
+    }
+
+    /// Like `json_eat`, but fails the document when `c` is missing.
+    pub(super) fn json_expect(&mut self, s: u32, c: u32) -> Result<bool> {
+        if self.json_eat(s, c)? {
+            return Ok(true);
+        }
+        Ok(self.json_fail())
+    }
+
+    /// Consumes a `null`, reporting whether there was one.
+    pub(super) fn json_null(&mut self, s: u32) -> Result<bool> {
+        if self.json_failed {
+            return Ok(false);
+        }
+        self.skip_whitespace(s)?;
+        self.eat_word(s, b"null")
+    }
+
+    pub(super) fn json_boolean(&mut self, s: u32) -> Result<bool> {
+        if self.json_failed {
+            return Ok(false);
+        }
+        self.skip_whitespace(s)?;
+        if self.eat_word(s, b"true")? {
+            return Ok(true);
+        }
+        if !self.eat_word(s, b"false")? {
+            self.fail();
+        }
+        Ok(false)
+    }
+
+    /// Reads a number without a fraction or exponent that fits in an i64.
+    pub(super) fn json_integer(&mut self, s: u32) -> Result<i64> {
+        if self.json_failed {
+            return Ok(0);
+        }
+        self.skip_whitespace(s)?;
+        match read_integer(self.string(s)?, self.json_cursor) {
+            Some((value, at)) => {
+                self.json_cursor = at;
+                Ok(value)
+            }
+            None => {
+                self.fail();
+                Ok(0)
+            }
+        }
+    }
+
+    /// Reads any number.
+    pub(super) fn json_float(&mut self, s: u32) -> Result<f64> {
+        if self.json_failed {
+            return Ok(0.0);
+        }
+        self.skip_whitespace(s)?;
+        match read_float(self.string(s)?, self.json_cursor) {
+            Some((value, at)) => {
+                self.json_cursor = at;
+                Ok(value)
+            }
+            None => {
+                self.fail();
+                Ok(0.0)
+            }
+        }
+    }
+
+    /// Reads a string. A malformed one fails the document and reads as
+    /// empty.
+    pub(super) fn json_string(&mut self, s: u32) -> Result<u32> {
+        if !self.json_failed {
+            self.skip_whitespace(s)?;
+            if self.peek(s)? != b'"' as u32 {
+                self.fail();
+            }
+        }
+        let decoded = if self.json_failed {
+            None
+        } else {
+            decode_string(self.string(s)?, self.json_cursor)
+        };
+        let Some((bytes, end)) = decoded else {
+            self.fail();
+            return self.dalloc(dtype::BYTES, 0);
+        };
+        self.json_cursor = end + 1;
+        self.alloc_string(&bytes)
+    }
+
+    /// Skips the string whose opening quote is at the cursor.
+    fn skip_string(&mut self, s: u32) -> Result<()> {
+        match decode_string(self.string(s)?, self.json_cursor) {
+            Some((_, end)) => self.json_cursor = end + 1,
+            None => self.fail(),
+        }
+        Ok(())
+    }
+
+    /// Skips the comma separated values or members up to `close`.
+    fn skip_items(&mut self, s: u32, close: u8, members: bool) -> Result<()> {
+        if self.json_eat(s, close as u32)? {
+            return Ok(());
+        }
+        loop {
+            if members {
+                self.skip_whitespace(s)?;
+                if self.peek(s)? != b'"' as u32 {
+                    self.fail();
+                    return Ok(());
+                }
+                self.skip_string(s)?;
+                self.json_expect(s, b':' as u32)?;
+            }
+            self.json_skip(s)?;
+            if self.json_failed || !self.json_eat(s, b',' as u32)? {
+                break;
+            }
+        }
+        self.json_expect(s, close as u32)?;
+        Ok(())
+    }
+
+    /// Skips over one value of any kind, such as a member no field asks for.
+    pub(super) fn json_skip(&mut self, s: u32) -> Result<bool> {
+        if self.json_failed {
+            return Ok(false);
+        }
+        self.skip_whitespace(s)?;
+        match self.peek(s)? {
+            0x22 => self.skip_string(s)?,
+            0x5b => {
+                self.json_cursor += 1;
+                self.skip_items(s, b']', false)?;
+            }
+            0x7b => {
+                self.json_cursor += 1;
+                self.skip_items(s, b'}', true)?;
+            }
+            0x74 | 0x66 => {
+                self.json_boolean(s)?;
+            }
+            0x6e => {
+                if !self.json_null(s)? {
+                    self.fail();
+                }
+            }
+            _ => {
+                self.json_float(s)?;
+            }
+        }
+        Ok(!self.json_failed)
+    }
+
+    /// Writes `s` as a quoted JSON string.
+    pub(super) fn json_quote(&mut self, s: u32) -> Result<u32> {
+        let mut quoted = vec![b'"'];
+        for &c in self.string(s)? {
+            if let Some(escape) = short_escape(c) {
+                quoted.extend([b'\\', escape]);
+            } else if c < 0x20 {
+                let hex = [hex_digit(c as u32 >> 4), hex_digit(c as u32 & 0xf)];
+                quoted.extend([b'\\', b'u', b'0', b'0', hex[0], hex[1]]);
+            } else {
+                quoted.push(c);
+            }
+        }
+        quoted.push(b'"');
+        self.alloc_string(&quoted)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::ast::IRStruct;
+use crate::error::CompilerError;
+use abi::block;
+
+use super::trap;
+
+/// dalloc block types, as the runtime numbers them.
+pub(super) mod dtype {
+    pub const PRIMITIVE: u32 = 1;
+    pub const STRUCTS: u32 = 2;
+    pub const LISTS: u32 = 3;
+    pub const BYTES: u32 = 4;
+}
+
+/// Bytes before the first block of each memory, so no pointer is 0.
+const ALLOC_START: usize = 8;
+const DALLOC_START: usize = 24;
+
+/// Which memory a load or store goes to.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Space {
+    Alloc,
+    Dalloc,
+}
+
+/// Alloc and dalloc memory, laid out the way the runtime lays them out so
+/// that the offsets in the IR mean the same thing here. A struct has its
+/// type id in the word before its 8-byte header ends, and a dalloc block
+/// has its mark, its size and type, and its length in the 12 bytes before
+/// its data. Blocks are handed out one after the other and never freed.
+pub(super) struct Heap {
+    alloc: Vec<u8>,
+    dalloc: Vec<u8>,
+    struct_sizes: Vec<u32>,
+    /// Bytes dalloc has handed out, headers included.
+    pub(super) allocated: u64,
+    pub(super) json_cursor: u32,
+    pub(super) json_failed: bool,
+    /// Whether the last `atoi` or `atof` failed.
+    pub(super) parse_failed: bool,
+}
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+fn element_size(ty: u32) -> u32 {
+    if ty == dtype::BYTES {
+        1
+    } else {
+        8
+    }
+}
+
+/// Bytes a dalloc block needs for `length` elements of type `ty`, rounded
+/// up to a whole word as dalloc rounds them. Traps past the largest block
+/// its header can describe.
+fn block_bytes(ty: u32, length: u32) -> Result<u32> {
+    let bytes = length as u64 * element_size(ty) as u64;
+    if bytes > (block::SIZE_MASK - block::HEADER) as u64 {
+        return Err(trap("out of memory"));
+    }
+    Ok((bytes as u32 + 3) & !3)
+}
+
+fn out_of_bounds() -> CompilerError {
+    trap("out of bounds memory access")
+}
+
+/// How many copies of a block of `len` elements `times` asks for, as
+/// dalloc counts them. `None` when it's negative or they'd hold more than
+/// `i32::MAX` elements.
+fn repeat_count(len: u32, times: i64) -> Option<u32> {
+    if times < 0 {
+        return None;
+    }
+    if len == 0 {
+        return Some(0);
+    }
+    let total = (len as i64).checked_mul(times)?;
+    (total <= i32::MAX as i64).then_some(times as u32)
+}
+
+/// Elements `start..end` of a block of `len` elements, where a negative
+/// index counts back from the end, as dalloc resolves them. `None` unless
+/// they land within the block.
+fn slice_range(len: u32, start: i32, end: i32) -> Option<(u32, u32)> {
+    let resolve = |index: i32| {
+        if index < 0 {
+            len as i64 + index as i64
+        } else {
+            index as i64
+        }
+    };
+    let (start, end) = (resolve(start), resolve(end));
+    if start < 0 || start > end || end > len as i64 {
+        return None;
+    }
+    Some((start as u32, end as u32))
+}
+
+impl Heap {
+    pub(super) fn new(structs: &[IRStruct]) -> Self {
+        Heap {
+            alloc: vec![0; ALLOC_START],
+            dalloc: vec![0; DALLOC_START],
+            struct_sizes: structs.iter().map(|s| s.size).collect(),
+            allocated: 0,
+            json_cursor: 0,
+            json_failed: false,
+            parse_failed: false,
+        }
+    }
+
+    /// Bytes in use in both memories.
+    pub(super) fn used(&self) -> u64 {
+        (self.alloc.len() - ALLOC_START + self.dalloc.len() - DALLOC_START) as u64
+    }
+
+    fn memory(&self, space: Space) -> &Vec<u8> {
+        match space {
+            Space::Alloc => &self.alloc,
+            Space::Dalloc => &self.dalloc,
+        }
+    }
+
+    fn memory_mut(&mut self, space: Space) -> &mut Vec<u8> {
+        match space {
+            Space::Alloc => &mut self.alloc,
+            Space::Dalloc => &mut self.dalloc,
+        }
+    }
+
+    fn range(&self, space: Space, addr: u32, len: u32) -> Result<std::ops::Range<usize>> {
+        let start = addr as usize;
+        let end = start + len as usize;
+        if end > self.memory(space).len() {
+            return Err(out_of_bounds());
+        }
+        Ok(start..end)
+    }
+
+    pub(super) fn load(&self, space: Space, addr: u32) -> Result<u64> {
+        let range = self.range(space, addr, 8)?;
+        Ok(u64::from_le_bytes(
+            self.memory(space)[range].try_into().unwrap(),
+        ))
+    }
+
+    pub(super) fn store(&mut self, space: Space, addr: u32, value: u64) -> Result<()> {
+        let range = self.range(space, addr, 8)?;
+        self.memory_mut(space)[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    pub(super) fn load_u32(&self, space: Space, addr: u32) -> Result<u32> {
+        let range = self.range(space, addr, 4)?;
+        Ok(u32::from_le_bytes(
+            self.memory(space)[range].try_into().unwrap(),
+        ))
+    }
+
+    fn store_u32(&mut self, space: Space, addr: u32, value: u32) -> Result<()> {
+        let range = self.range(space, addr, 4)?;
+        self.memory_mut(space)[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// The `len` bytes of dalloc memory at `addr`.
+    pub(super) fn bytes(&self, addr: u32, len: u32) -> Result<&[u8]> {
+        let range = self.range(Space::Dalloc, addr, len)?;
+        Ok(&self.dalloc[range])
+    }
+
+    fn copy(&mut self, dst: u32, src: u32, len: u32) -> Result<()> {
+        let from = self.range(Space::Dalloc, src, len)?;
+        self.range(Space::Dalloc, dst, len)?;
+        self.dalloc.copy_within(from, dst as usize);
+        Ok(())
+    }
+
+    /// Appends `len` zeroed bytes, 8-byte aligned, and returns where they start.
+    fn grow(space: &mut Vec<u8>, len: usize) -> Result<u32> {
+        let start = (space.len() + 7) & !7;
+        let end = start + len;
+        if end > u32::MAX as usize {
+            return Err(trap("out of memory"));
+        }
+        space.resize(end, 0);
+        Ok(start as u32)
+    }
+
+    /// Allocates a zeroed struct of type `struct_index`.
+    pub(super) fn falloc(&mut self, struct_index: u32) -> Result<u32> {
+        let size = self.struct_sizes[struct_index as usize];
+        let block = Self::grow(&mut self.alloc, 8 + size as usize)?;
+        self.store_u32(Space::Alloc, block, struct_index)?;
+        Ok(block + 8)
+    }
+
+    /// The type id in a struct's header.
+    pub(super) fn struct_id(&self, ptr: u32) -> Result<u32> {
+        self.load_u32(Space::Alloc, ptr.wrapping_sub(8))
+    }
+
+    pub(super) fn dalloc(&mut self, ty: u32, length: u32) -> Result<u32> {
+        let size = block_bytes(ty, length)?;
+        let block = Self::grow(&mut self.dalloc, (block::HEADER + size) as usize)?;
+        self.store_u32(Space::Dalloc, block + 4, ty << block::TYPE_SHIFT | size)?;
+        self.store_u32(Space::Dalloc, block + 8, length)?;
+        self.allocated += (size + block::HEADER) as u64;
+        Ok(block + block::HEADER)
+    }
+
+    fn info(&self, ptr: u32) -> Result<u32> {
+        self.load_u32(Space::Dalloc, ptr.wrapping_sub(block::INFO))
+    }
+
+    fn block_type(&self, ptr: u32) -> Result<u32> {
+        Ok((self.info(ptr)? & block::TYPE_MASK) >> block::TYPE_SHIFT)
+    }
+
+    fn capacity(&self, ptr: u32) -> Result<u32> {
+        Ok(self.info(ptr)? & block::SIZE_MASK)
+    }
+
+    pub(super) fn length(&self, ptr: u32) -> Result<u32> {
+        self.load_u32(Space::Dalloc, ptr.wrapping_sub(block::LENGTH))
+    }
+
+    fn set_length(&mut self, ptr: u32, length: u32) -> Result<()> {
+        self.store_u32(Space::Dalloc, ptr.wrapping_sub(block::LENGTH), length)
+    }
+
+    /// The bytes of the string at `ptr`.
+    pub(super) fn string(&self, ptr: u32) -> Result<&[u8]> {
+        self.bytes(ptr, self.length(ptr)?)
+    }
+
+    /// Copies `bytes` into a new string.
+    pub(super) fn alloc_string(&mut self, bytes: &[u8]) -> Result<u32> {
+        let ptr = self.dalloc(dtype::BYTES, bytes.len() as u32)?;
+        let range = self.range(Space::Dalloc, ptr, bytes.len() as u32)?;
+        self.dalloc[range].copy_from_slice(bytes);
+        Ok(ptr)
+    }
+
+    /// `drealloc`: `ptr` resized to `new_len` elements, keeping the existing
+    /// ones. It grows in place when its block has room or is the last one,
+    /// as dalloc grows into a free block after it, and is copied otherwise.
+    fn realloc(&mut self, ptr: u32, new_len: u32) -> Result<u32> {
+        let ty = self.block_type(ptr)?;
+        let old_len = self.length(ptr)?;
+        let capacity = self.capacity(ptr)?;
+        let size = block_bytes(ty, new_len)?;
+        let last = ptr as usize + capacity as usize == self.dalloc.len();
+        if new_len >= old_len && (size <= capacity || last) {
+            if size > capacity {
+                self.dalloc.resize(ptr as usize + size as usize, 0);
+                let info = self.info(ptr)? & !block::SIZE_MASK | size;
+                self.store_u32(Space::Dalloc, ptr - block::INFO, info)?;
+                self.allocated += (size - capacity) as u64;
+            }
+            self.set_length(ptr, new_len)?;
+            return Ok(ptr);
+        }
+
+        let kept = old_len.min(new_len);
+        let target = self.dalloc(ty, new_len)?;
+        self.copy(target, ptr, kept * element_size(ty))?;
+        Ok(target)
+    }
+
+    /// `dappend`: grows the list in place while its block has room.
+    pub(super) fn append(&mut self, ptr: u32, value: u64) -> Result<u32> {
+        let length = self.length(ptr)?;
+        let capacity = self.capacity(ptr)? / 8;
+        let mut target = ptr;
+        if length == capacity {
+            let new_capacity = if capacity == 0 { 4 } else { capacity * 2 };
+            target = self.realloc(ptr, new_capacity)?;
+        }
+        self.store(Space::Dalloc, target + length * 8, value)?;
+        self.set_length(target, length + 1)?;
+        Ok(target)
+    }
+
+    /// `dfill`: `length` elements that all hold `value`.
+    pub(super) fn fill(&mut self, ty: u32, value: u64, length: u32) -> Result<u32> {
+        let ptr = self.dalloc(ty, length)?;
+        for i in 0..length {
+            self.store(Space::Dalloc, ptr + i * 8, value)?;
+        }
+        Ok(ptr)
+    }
+
+    /// `dbuild`: appends `piece` to a builder buffer whose first `used`
+    /// elements are in use, doubling the buffer when it runs out.
+    pub(super) fn build(&mut self, buffer: u32, used: u32, piece: u32) -> Result<u32> {
+        let capacity = self.length(buffer)?;
+        let piece_len = self.length(piece)?;
+        let needed = used + piece_len;
+        let mut target = buffer;
+        if needed > capacity {
+            let mut new_capacity = if capacity == 0 { 8 } else { capacity * 2 };
+            while new_capacity < needed {
+                new_capacity *= 2;
+            }
+            target = self.realloc(buffer, new_capacity)?;
+        }
+        let element = element_size(self.block_type(buffer)?);
+        self.copy(target + used * element, piece, piece_len * element)?;
+        Ok(target)
+    }
+
+    pub(super) fn concat(&mut self, first: u32, second: u32) -> Result<u32> {
+        let ty = self.block_type(first)?;
+        let first_len = self.length(first)?;
+        let second_len = self.length(second)?;
+        let target = self.dalloc(ty, first_len + second_len)?;
+        let element = element_size(ty);
+        self.copy(target, first, first_len * element)?;
+        self.copy(target + first_len * element, second, second_len * element)?;
+        Ok(target)
+    }
+
+    pub(super) fn repeat(&mut self, ptr: u32, times: i64) -> Result<u32> {
+        let ty = self.block_type(ptr)?;
+        let len = self.length(ptr)?;
+        let times = repeat_count(len, times).ok_or_else(|| trap("repeat count out of range"))?;
+        let target = self.dalloc(ty, len * times)?;
+        let element = element_size(ty);
+        for i in 0..times {
+            self.copy(target + i * len * element, ptr, len * element)?;
+        }
+        Ok(target)
+    }
+
+    pub(super) fn slice(&mut self, ptr: u32, start: i32, end: i32) -> Result<u32> {
+        let ty = self.block_type(ptr)?;
+        let (start, end) = slice_range(self.length(ptr)?, start, end)
+            .ok_or_else(|| trap("slice out of bounds"))?;
+        let new_len = end - start;
+        let target = self.dalloc(ty, new_len)?;
+        let element = element_size(ty);
+        self.copy(target, ptr + start * element, new_len * element)?;
+        Ok(target)
+    }
+
+    /// `dsplice`: overwrites elements `start..end` of `ptr` with those of
+    /// `source`. `None` when the range or the source length is wrong.
+    pub(super) fn splice(
+        &mut self,
+        ptr: u32,
+        start: i32,
+        end: i32,
+        source: u32,
+    ) -> Result<Option<u32>> {
+        let Some((start, end)) = slice_range(self.length(ptr)?, start, end) else {
+            return Ok(None);
+        };
+        if self.length(source)? != end - start {
+            return Ok(None);
+        }
+        let element = element_size(self.block_type(ptr)?);
+        self.copy(ptr + start * element, source, (end - start) * element)?;
+        Ok(Some(source))
+    }
+
+    /// `din_u64`: whether `list` has `element`, comparing strings and lists
+    /// in it by their contents.
+    pub(super) fn contains(&self, element: u64, list: u32) -> Result<bool> {
+        let deep = self.block_type(list)? == dtype::LISTS;
+        for i in 0..self.length(list)? {
+            let value = self.load(Space::Dalloc, list + i * 8)?;
+            if value == element || (deep && self.equal(value as u32, element as u32)?) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// `deq`: whether two strings or lists have the same elements, those
+    /// that are strings or lists compared the same way.
+    pub(super) fn equal(&self, first: u32, second: u32) -> Result<bool> {
+        if first == second {
+            return Ok(true);
+        }
+        let len = self.length(first)?;
+        if len != self.length(second)? {
+            return Ok(false);
+        }
+        let ty = self.block_type(first)?;
+        if ty == dtype::LISTS {
+            for i in 0..len {
+                let a = self.load(Space::Dalloc, first + i * 8)? as u32;
+                let b = self.load(Space::Dalloc, second + i * 8)? as u32;
+                if !self.equal(a, b)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+        let len = len * element_size(ty);
+        Ok(self.bytes(first, len)? == self.bytes(second, len)?)
+    }
+
+    pub(super) fn itoa(&mut self, value: i64) -> Result<u32> {
+        self.alloc_string(value.to_string().as_bytes())
+    }
+
+    pub(super) fn btoa(&mut self, value: bool) -> Result<u32> {
+        self.alloc_string(if value { b"true" } else { b"false" })
+    }
+
+    /// `dftoa`: the integer part, a dot and six digits of fraction, rounded
+    /// half up.
+    pub(super) fn ftoa(&mut self, value: f64) -> Result<u32> {
+        let int_part = value as i64;
+        let fraction = (value - int_part as f64).abs();
+        let fraction = (fraction * 1000000.0 + 0.5) as u64;
+        let text = format!("{}.{:06}", int_part, fraction);
+        self.alloc_string(text.as_bytes())
+    }
+}
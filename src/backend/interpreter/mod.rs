@@ -0,0 +1,80 @@
+//! Runs lowered IR directly, for hosts that can't instantiate the WASM
+//! modules codegen produces. Memory is emulated byte for byte in the
+//! runtime's layout, so field offsets, list headers and tagged unions work
+//! exactly as they do compiled, and a trap becomes a runtime error. Nothing
+//! is ever collected.
+
+mod builtins;
+mod expr;
+mod json;
+mod memory;
+mod stmt;
+mod strings;
+
+use crate::ast::IRProgram;
+use crate::error::CompilerError;
+use crate::host::Io;
+use memory::Heap;
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+pub struct Interpreter<'a> {
+    program: &'a IRProgram,
+    io: &'a mut dyn Io,
+    heap: Heap,
+    /// The values of the program's top-level variables.
+    globals: Vec<u64>,
+    unchecked: bool,
+    depth: usize,
+    /// Set by `exit`, while the program unwinds.
+    exit_code: Option<i64>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(program: &'a IRProgram, io: &'a mut dyn Io) -> Self {
+        Interpreter {
+            program,
+            io,
+            heap: Heap::new(&program.structs),
+            globals: vec![0; program.globals.len()],
+            unchecked: false,
+            depth: 0,
+            exit_code: None,
+        }
+    }
+
+    /// Runs `main`, the first function, and returns what it returned, or the
+    /// code it exited with.
+    pub fn run(&mut self) -> Result<i64> {
+        let takes_args = self
+            .program
+            .functions
+            .first()
+            .is_some_and(|main| !main.params.is_empty());
+        let args = if takes_args {
+            vec![self.args_list()? as u64]
+        } else {
+            vec![]
+        };
+        let value = self.call(0, 0, args)? as i64;
+        Ok(self.exit_code.unwrap_or(value))
+    }
+
+    /// The list of strings a `main(args: {string})` receives.
+    fn args_list(&mut self) -> Result<u32> {
+        let args = self.io.args();
+        let list = self.heap.dalloc(memory::dtype::LISTS, args.len() as u32)?;
+        for (i, arg) in args.iter().enumerate() {
+            let string = self.heap.alloc_string(arg.as_bytes())?;
+            self.heap
+                .store(memory::Space::Dalloc, list + 8 * i as u32, string as u64)?;
+        }
+        Ok(list)
+    }
+}
+
+fn trap(message: &str) -> CompilerError {
+    CompilerError::Runtime {
+        message: message.to_string(),
+    }
+}
@@ -0,0 +1,169 @@
+use crate::ast::{IRExpr, IRExprKind, IRStmt};
+
+use super::{trap, Interpreter, Result};
+
+/// How control leaves a statement.
+pub(super) enum Flow {
+    Next,
+    /// Leaving the loop this many loops out from the innermost.
+    Break(u32),
+    /// Going round the loop this many loops out from the innermost again.
+    Continue(u32),
+    Return(u64),
+}
+
+/// Calls nested deeper than this fail instead of overflowing the host's
+/// stack, given the 8 MiB a main thread usually has.
+const MAX_CALL_DEPTH: usize = 1000;
+
+impl Interpreter<'_> {
+    /// Calls the function at `index` of the function table with the
+    /// environment `env`, the way a function value is called.
+    pub(super) fn call(&mut self, index: u32, env: u32, args: Vec<u64>) -> Result<u64> {
+        let program = self.program;
+        let func = program
+            .functions
+            .get(index as usize)
+            .ok_or_else(|| trap("call to a function outside the table"))?;
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(trap("call stack exhausted"));
+        }
+
+        // Locals are numbered as in compiled code: two scratch locals and
+        // the environment, then the parameters, then the function's own.
+        let mut locals = vec![0; 3 + func.params.len() + func.locals.len()];
+        locals[2] = env as u64;
+        locals[3..3 + args.len()].copy_from_slice(&args);
+
+        self.depth += 1;
+        let flow = self.exec_block(&func.body, &mut locals);
+        self.depth -= 1;
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            // Every path ends in a return.
+            _ => Err(trap("unreachable")),
+        }
+    }
+
+    fn exec_block(&mut self, body: &[IRStmt], locals: &mut [u64]) -> Result<Flow> {
+        for stmt in body {
+            match self.exec(stmt, locals)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn exec(&mut self, stmt: &IRStmt, locals: &mut [u64]) -> Result<Flow> {
+        // Like `eval`, this keeps every statement with a body in its own
+        // method to keep its frame small.
+        match stmt {
+            IRStmt::Expr(expr) => {
+                self.eval(expr, locals)?;
+            }
+            IRStmt::LocalSet { index, value } => {
+                locals[*index as usize] = self.eval(value, locals)?;
+            }
+            IRStmt::Return(Some(expr)) | IRStmt::Raise(expr) => {
+                return Ok(Flow::Return(self.eval(expr, locals)?))
+            }
+            IRStmt::Return(None) => return Ok(Flow::Return(0)),
+            IRStmt::Break(out) => return Ok(Flow::Break(*out)),
+            IRStmt::Continue(out) => return Ok(Flow::Continue(*out)),
+            IRStmt::If {
+                condition,
+                then_block,
+                else_block,
+            } => return self.exec_if(condition, then_block, else_block.as_deref(), locals),
+            IRStmt::While { condition, body } => {
+                return self.exec_loop(None, condition, None, body, locals)
+            }
+            IRStmt::For {
+                init,
+                condition,
+                update,
+                body,
+            } => return self.exec_loop(Some(init), condition, Some(update), body, locals),
+            IRStmt::Unchecked { body } => {
+                let outer = self.unchecked;
+                self.unchecked = true;
+                let flow = self.exec_block(body, locals);
+                self.unchecked = outer;
+                return flow;
+            }
+            IRStmt::Print(expr) => self.exec_print(expr, locals)?,
+            IRStmt::Produce(_) => return Err(trap("produce is not supported yet")),
+            IRStmt::LocalClosure {
+                fn_index,
+                captures,
+                index,
+            } => {
+                let IRExprKind::New { struct_index, .. } = &captures.node else {
+                    return Err(trap("captures must be a local struct allocation"));
+                };
+                // The closure exists before its captures are filled in, so
+                // a function can capture itself.
+                let env = self.heap.falloc(*struct_index)?;
+                locals[*index as usize] = (*fn_index as u64) << 32 | env as u64;
+                self.fill_captures(env, captures, locals)?;
+            }
+            // Only reached while unwinding, which never happens here.
+            IRStmt::Suspend => return Err(trap("unreachable")),
+            IRStmt::Exit => return Ok(Flow::Return(0)),
+        }
+        Ok(Flow::Next)
+    }
+
+    fn exec_if(
+        &mut self,
+        condition: &IRExpr,
+        then_block: &[IRStmt],
+        else_block: Option<&[IRStmt]>,
+        locals: &mut [u64],
+    ) -> Result<Flow> {
+        if self.eval(condition, locals)? != 0 {
+            return self.exec_block(then_block, locals);
+        }
+        match else_block {
+            Some(else_block) => self.exec_block(else_block, locals),
+            None => Ok(Flow::Next),
+        }
+    }
+
+    /// A `while` loop, or a `for` loop with its `init` and `update`.
+    fn exec_loop(
+        &mut self,
+        init: Option<&IRStmt>,
+        condition: &IRExpr,
+        update: Option<&IRStmt>,
+        body: &[IRStmt],
+        locals: &mut [u64],
+    ) -> Result<Flow> {
+        if let Some(init) = init {
+            self.exec(init, locals)?;
+        }
+        while self.eval(condition, locals)? != 0 {
+            match self.exec_block(body, locals)? {
+                Flow::Break(0) => break,
+                // Straight back to the condition, as in compiled code.
+                Flow::Continue(0) => continue,
+                Flow::Break(out) => return Ok(Flow::Break(out - 1)),
+                Flow::Continue(out) => return Ok(Flow::Continue(out - 1)),
+                Flow::Return(value) => return Ok(Flow::Return(value)),
+                Flow::Next => {}
+            }
+            if let Some(update) = update {
+                self.exec(update, locals)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn exec_print(&mut self, expr: &IRExpr, locals: &mut [u64]) -> Result<()> {
+        let string = self.eval(expr, locals)? as u32;
+        let line = String::from_utf8_lossy(self.heap.string(string)?).into_owned();
+        self.io.print(&line);
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+//! The string methods, counting and indexing in characters of UTF-8 the way
+//! dalloc's do, and the number parsers behind `parse_int` and `parse_float`.
+
+use crate::error::CompilerError;
+
+use super::json::{read_float, read_integer};
+use super::memory::{dtype, Heap, Space};
+use super::trap;
+
+type Result<T> = std::result::Result<T, CompilerError>;
+
+/// Reads a number starting at a position, returning it with the position
+/// after it.
+type NumberReader<T> = fn(&[u8], u32) -> Option<(T, u32)>;
+
+/// Whether `byte` continues a character rather than starting one.
+fn continues(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+fn chars(s: &[u8]) -> usize {
+    s.iter().filter(|&&c| !continues(c)).count()
+}
+
+/// The byte at which `needle` next occurs in `s` from byte `from`.
+fn next_occurrence(s: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    let end = s.len().checked_sub(needle.len())?;
+    (from..=end).find(|&at| s[at..].starts_with(needle))
+}
+
+/// The byte at which character `index` of `s` starts, or the length of `s`
+/// for the index just past its last character.
+fn char_offset(s: &[u8], index: i64) -> Result<usize> {
+    let starts = (0..s.len()).filter(|&at| !continues(s[at]));
+    let mut offsets = starts.chain(std::iter::once(s.len()));
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| offsets.nth(index))
+        .ok_or_else(|| trap("substring out of bounds"))
+}
+
+/// The pieces of `s` between the occurrences of `sep`, or its characters
+/// when `sep` is empty.
+fn pieces<'a>(s: &'a [u8], sep: &[u8]) -> Vec<&'a [u8]> {
+    if sep.is_empty() {
+        let bounds: Vec<usize> = (0..s.len())
+            .filter(|&at| !continues(s[at]))
+            .chain(std::iter::once(s.len()))
+            .collect();
+        return bounds.windows(2).map(|pair| &s[pair[0]..pair[1]]).collect();
+    }
+    let mut pieces = vec![];
+    let mut at = 0;
+    while let Some(found) = next_occurrence(s, at, sep) {
+        pieces.push(&s[at..found]);
+        at = found + sep.len();
+    }
+    pieces.push(&s[at..]);
+    pieces
+}
+
+impl Heap {
+    /// The characters in `s`.
+    pub(super) fn chars(&self, s: u32) -> Result<u32> {
+        Ok(chars(self.string(s)?) as u32)
+    }
+
+    /// The index of the character where `needle` first occurs in `s`, or -1.
+    pub(super) fn find(&self, s: u32, needle: u32) -> Result<i64> {
+        let s = self.string(s)?;
+        Ok(match next_occurrence(s, 0, self.string(needle)?) {
+            Some(at) => chars(&s[..at]) as i64,
+            None => -1,
+        })
+    }
+
+    pub(super) fn substring(&mut self, s: u32, start: i64, end: i64) -> Result<u32> {
+        let string = self.string(s)?;
+        if start > end {
+            return Err(trap("substring out of bounds"));
+        }
+        let (first, last) = (char_offset(string, start)?, char_offset(string, end)?);
+        let piece = string[first..last].to_vec();
+        self.alloc_string(&piece)
+    }
+
+    /// The code point of `s`, which must hold exactly one character.
+    pub(super) fn ord(&self, s: u32) -> Result<i64> {
+        let mut chars = std::str::from_utf8(self.string(s)?)
+            .unwrap_or_default()
+            .chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c as i64),
+            _ => Err(trap("ord needs a string of one character")),
+        }
+    }
+
+    /// The one-character string for the code point `code`.
+    pub(super) fn chr(&mut self, code: i64) -> Result<u32> {
+        let c = u32::try_from(code)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| trap("chr needs a valid code point"))?;
+        self.alloc_string(c.to_string().as_bytes())
+    }
+
+    pub(super) fn split(&mut self, s: u32, sep: u32) -> Result<u32> {
+        let pieces: Vec<Vec<u8>> = pieces(self.string(s)?, self.string(sep)?)
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect();
+        let list = self.dalloc(dtype::LISTS, pieces.len() as u32)?;
+        for (i, piece) in pieces.iter().enumerate() {
+            let piece = self.alloc_string(piece)?;
+            self.store(Space::Dalloc, list + i as u32 * 8, piece as u64)?;
+        }
+        Ok(list)
+    }
+
+    pub(super) fn upper(&mut self, s: u32) -> Result<u32> {
+        let upper = self.string(s)?.to_ascii_uppercase();
+        self.alloc_string(&upper)
+    }
+
+    pub(super) fn lower(&mut self, s: u32) -> Result<u32> {
+        let lower = self.string(s)?.to_ascii_lowercase();
+        self.alloc_string(&lower)
+    }
+
+    /// `s` without the ASCII whitespace at either end.
+    pub(super) fn trim(&mut self, s: u32) -> Result<u32> {
+        let trimmed = self.string(s)?.trim_ascii().to_vec();
+        self.alloc_string(&trimmed)
+    }
+
+    /// `s` with every occurrence of `from` replaced by `to`. An empty `from`
+    /// replaces nothing.
+    pub(super) fn replace(&mut self, s: u32, from: u32, to: u32) -> Result<u32> {
+        let (string, from, to) = (self.string(s)?, self.string(from)?, self.string(to)?);
+        let replaced = if from.is_empty() {
+            string.to_vec()
+        } else {
+            pieces(string, from).join(to)
+        };
+        self.alloc_string(&replaced)
+    }
+
+    /// The number `s` spells with `read`, with an optional leading `+` and
+    /// nothing else around it. 0 when it isn't one, which `parse_failed`
+    /// records.
+    fn parse<T: Default>(&mut self, s: u32, read: NumberReader<T>) -> Result<T> {
+        let string = self.string(s)?;
+        let at = string.starts_with(b"+") as u32;
+        let parsed = match read(string, at) {
+            Some((value, end))
+                if end as usize == string.len() && (at == 0 || string.get(1) != Some(&b'-')) =>
+            {
+                Some(value)
+            }
+            _ => None,
+        };
+        self.parse_failed = parsed.is_none();
+        Ok(parsed.unwrap_or_default())
+    }
+
+    pub(super) fn atoi(&mut self, s: u32) -> Result<i64> {
+        self.parse(s, read_integer)
+    }
+
+    pub(super) fn atof(&mut self, s: u32) -> Result<f64> {
+        self.parse(s, read_float)
+    }
+}
@@ -1,7 +1,9 @@
 use crate::ast::aast::{AnalyzedExpr, AnalyzedStatement, Expr};
-use crate::ast::{BinaryOp, Pattern, Type, FlattenedProgram};
-use crate::ast::{IRExpr, IRFunction, IRPattern, IRProgram, IRStmt, IRStruct, IRExprKind, IRStructKind};
+use crate::ast::{BinaryOp, Builtin, Type, TypeKind, FlattenedProgram};
+use crate::ast::{IRExpr, IRFunction, IRProgram, IRStmt, IRStruct, IRExprKind, IRStructKind};
 use crate::error::CompilerError;
+use std::collections::HashSet;
+use crate::transforms::field_slots;
 
 pub struct IRGenerator {
     structs: Vec<IRStruct>,
@@ -14,7 +16,10 @@ impl IRGenerator {
 
     pub fn generate(&mut self, program: &FlattenedProgram) -> Result<IRProgram, CompilerError> {
         for stmt in &program.structs {
-            let ir_struct = self.lower_struct(stmt)?;
+            let mut ir_struct = self.lower_struct(stmt)?;
+            if let IRStructKind::User = ir_struct.kind {
+                ir_struct.drop = drop_method(program, &ir_struct.name);
+            }
             self.structs.push(ir_struct);
         }
 
@@ -27,27 +32,55 @@ impl IRGenerator {
         Ok(IRProgram {
             structs: self.structs.clone(),
             functions,
+            globals: program.globals.clone(),
         })
     }
 
-    fn lower_struct(&mut self, entry: &(AnalyzedStatement, u32, u32)) -> Result<IRStruct, CompilerError> {
-        let (stmt, struct_count, list_count) = entry;
+    fn lower_struct(
+        &mut self,
+        entry: &(AnalyzedStatement, u32, u32, IRStructKind),
+    ) -> Result<IRStruct, CompilerError> {
+        let (stmt, struct_count, list_count, kind) = entry;
         match stmt {
-            AnalyzedStatement::Struct { name, fields } => {
+            AnalyzedStatement::Struct { name, fields }
+            | AnalyzedStatement::Error { name, fields } => {
                 let mut offsets = vec![];
+                let mut bits = vec![];
                 let mut offset = 0u32;
-                for _ in fields {
+                // Bits already used in the slot before `offset`, while packing bit-fields.
+                let mut packed_bits: Option<u32> = None;
+                for (_, ty) in fields {
+                    if let TypeKind::BitField { width } = ty.kind {
+                        match packed_bits {
+                            Some(used) if used + width <= 64 => {
+                                offsets.push(offset - 8);
+                                bits.push(Some((used, width)));
+                                packed_bits = Some(used + width);
+                            }
+                            _ => {
+                                offsets.push(offset);
+                                bits.push(Some((0, width)));
+                                offset += 8;
+                                packed_bits = Some(width);
+                            }
+                        }
+                        continue;
+                    }
+                    packed_bits = None;
                     offsets.push(offset);
-                    offset += 8;
+                    bits.push(None);
+                    offset += 8 * field_slots(ty);
                 }
                 Ok(IRStruct {
                     name: name.clone(),
                     fields: fields.clone(),
                     size: offset,
                     offsets,
+                    bits,
                     struct_count: *struct_count,
                     list_count: *list_count,
-                    kind: IRStructKind::Captures,
+                    kind: kind.clone(),
+                    drop: None,
                 })
             }
             _ => Err(CompilerError::IRGen {
@@ -67,6 +100,9 @@ impl IRGenerator {
                 index,
                 fn_index,
                 locals,
+                local_names,
+                exported,
+                noalloc,
             } => {
                 let mut ir_body = Vec::new();
                 for s in body {
@@ -77,9 +113,18 @@ impl IRGenerator {
                     params: params.iter().map(|(_, ty, _, _)| ty.clone()).collect(),
                     returns: returns.clone(),
                     locals: locals.clone(),
+                    local_names: params
+                        .iter()
+                        .map(|(name, ..)| name.clone())
+                        .chain(local_names.iter().cloned())
+                        .collect(),
                     captures_struct: Some(self.lookup_struct(name)?),
                     body: ir_body,
                     func_index: fn_index.unwrap(),
+                    resumable: false,
+                    exported: *exported,
+                    noalloc: *noalloc,
+                    unrooted: HashSet::new(),
                 })
             }
             _ => Err(CompilerError::IRGen {
@@ -133,8 +178,8 @@ impl IRGenerator {
                 };
                 Ok(IRStmt::Return(ir_expr))
             }
-            AnalyzedStatement::Break => Ok(IRStmt::Break),
-            AnalyzedStatement::Continue => Ok(IRStmt::Continue),
+            AnalyzedStatement::Break(out) => Ok(IRStmt::Break(*out)),
+            AnalyzedStatement::Continue(out) => Ok(IRStmt::Continue(*out)),
             AnalyzedStatement::If {
                 condition,
                 then_block,
@@ -192,6 +237,19 @@ impl IRGenerator {
                     body: ir_body,
                 })
             }
+            AnalyzedStatement::Unchecked { body } => {
+                let mut ir_body = Vec::new();
+                for s in body {
+                    ir_body.push(self.lower_stmt(s)?);
+                }
+                Ok(IRStmt::Unchecked { body: ir_body })
+            }
+            AnalyzedStatement::IfLet { .. } | AnalyzedStatement::WhileLet { .. } => {
+                unreachable!("if let and while let are lowered when flattening")
+            }
+            AnalyzedStatement::Defer { .. } => {
+                unreachable!("deferred blocks are run before each exit when wrapping")
+            }
             AnalyzedStatement::Print(expr) => {
                 let ir_expr = self.lower_expr(expr)?;
                 Ok(IRStmt::Print(ir_expr))
@@ -256,6 +314,14 @@ impl IRGenerator {
                 node: IRExprKind::Local(index.unwrap()),
                 ty: expr.ty.clone(),
             }),
+            Expr::Global { index, .. } => Ok(IRExpr {
+                node: IRExprKind::Global(*index),
+                ty: expr.ty.clone(),
+            }),
+            Expr::Function { fn_index, .. } => Ok(IRExpr {
+                node: IRExprKind::Function(*fn_index),
+                ty: expr.ty.clone(),
+            }),
             Expr::List(elements) => {
                 let mut ir_elements = Vec::new();
                 for e in elements {
@@ -274,23 +340,61 @@ impl IRGenerator {
                         message: "expected struct type for field access".to_string(),
                     }),
                 };
-                let offset = self.get_field_offset(struct_name, field)?;
-                Ok(IRExpr {
-                    node: IRExprKind::Field {
+                let (offset, bits) = self.get_field_layout(struct_name, field)?;
+                if let Some((shift, width)) = bits {
+                    let slot = IRExpr {
+                        node: IRExprKind::Field {
+                            object: Box::new(ir_object),
+                            offset,
+                        },
+                        ty: expr.ty.clone(),
+                    };
+                    return Ok(extract_bits(slot, shift, width));
+                }
+                // An array field evaluates to the address of its first slot.
+                let node = if let TypeKind::Array { .. } = &expr.ty.kind {
+                    IRExprKind::FieldReference {
                         object: Box::new(ir_object),
                         offset,
-                    },
+                    }
+                } else {
+                    IRExprKind::Field {
+                        object: Box::new(ir_object),
+                        offset,
+                    }
+                };
+                Ok(IRExpr {
+                    node,
                     ty: expr.ty.clone(),
                 })
             }
             Expr::Index { object, key } => {
                 let ir_object = self.lower_expr(object)?;
                 let ir_key = self.lower_expr(key)?;
-                Ok(IRExpr {
-                    node: IRExprKind::Index {
+                let node = if let TypeKind::Array { length, .. } = &object.ty.kind {
+                    IRExprKind::ArrayIndex {
+                        array: Box::new(ir_object),
+                        index: Box::new(ir_key),
+                        length: *length,
+                    }
+                } else {
+                    IRExprKind::Index {
                         list: Box::new(ir_object),
                         index: Box::new(ir_key),
-                    },
+                    }
+                };
+                Ok(IRExpr {
+                    node,
+                    ty: expr.ty.clone(),
+                })
+            }
+            Expr::Array(elements) => {
+                let mut ir_elements = Vec::new();
+                for e in elements {
+                    ir_elements.push(self.lower_expr(e)?);
+                }
+                Ok(IRExpr {
+                    node: IRExprKind::Array(ir_elements),
                     ty: expr.ty.clone(),
                 })
             }
@@ -315,9 +419,11 @@ impl IRGenerator {
                     .map(|(n, _)| n.clone())
                     .collect();
 
+                let field_bits = self.structs[struct_index as usize].bits.clone();
+
                 // Reorder fields to match struct definition order (segregated order)
-                let mut ir_fields = Vec::new();
-                for field_name in &field_order {
+                let mut ir_fields: Vec<IRExpr> = Vec::new();
+                for (field_name, bits) in field_order.iter().zip(field_bits) {
                     let field_expr = fields
                         .iter()
                         .find(|(n, _)| n == field_name)
@@ -325,7 +431,20 @@ impl IRGenerator {
                         .ok_or_else(|| CompilerError::IRGen {
                             message: format!("field '{}' not found in struct instantiation", field_name),
                         })?;
-                    ir_fields.push(self.lower_expr(field_expr)?);
+                    let ir_field = self.lower_expr(field_expr)?;
+                    match bits {
+                        // Bit-fields sharing a slot are OR-ed into one value.
+                        Some((0, width)) => ir_fields.push(place_bits(ir_field, 0, width)),
+                        Some((shift, width)) => {
+                            let slot = ir_fields.pop().unwrap();
+                            ir_fields.push(integer_binary(
+                                slot,
+                                BinaryOp::BitwiseOr,
+                                place_bits(ir_field, shift, width),
+                            ));
+                        }
+                        None => ir_fields.push(ir_field),
+                    }
                 }
 
                 Ok(IRExpr {
@@ -341,7 +460,7 @@ impl IRGenerator {
                 op: BinaryOp::Is,
                 right,
             } => match &left.expr {
-                Expr::Identifier { name: _, index } => {
+                Expr::Identifier { .. } | Expr::Global { .. } => {
                     let ir_left = self.lower_expr(left)?;
                     let ir_right = self.lower_expr(right)?;
                     Ok(IRExpr {
@@ -361,7 +480,28 @@ impl IRGenerator {
                             message: "expected struct type for field access".to_string(),
                         }),
                     };
-                    let offset = self.get_field_offset(struct_name, &field)?;
+                    let (offset, bits) = self.get_field_layout(struct_name, field)?;
+                    let mut ir_right = self.lower_expr(right)?;
+                    if let Some((shift, width)) = bits {
+                        // Read-modify-write the shared slot, keeping the other fields' bits.
+                        let slot = IRExpr {
+                            node: IRExprKind::Field {
+                                object: Box::new(ir_object.clone()),
+                                offset,
+                            },
+                            ty: left.ty.clone(),
+                        };
+                        let kept = integer_binary(
+                            slot,
+                            BinaryOp::BitwiseAnd,
+                            integer(!(bit_mask(width) << shift)),
+                        );
+                        ir_right = integer_binary(
+                            kept,
+                            BinaryOp::BitwiseOr,
+                            place_bits(ir_right, shift, width),
+                        );
+                    }
                     let ir_left = IRExpr {
                         node: IRExprKind::FieldReference {
                             object: Box::new(ir_object),
@@ -369,7 +509,6 @@ impl IRGenerator {
                         },
                         ty: left.ty.clone(),
                     };
-                    let ir_right = self.lower_expr(right)?;
                     Ok(IRExpr {
                         node: IRExprKind::Binary {
                             left: Box::new(ir_left),
@@ -382,10 +521,42 @@ impl IRGenerator {
                 Expr::Index { object, key } => {
                     let ir_object = self.lower_expr(object)?;
                     let ir_key = self.lower_expr(key)?;
-                    let ir_left = IRExpr {
-                        node: IRExprKind::IndexReference {
+                    let node = if let TypeKind::Array { length, .. } = &object.ty.kind {
+                        IRExprKind::ArrayIndexReference {
+                            array: Box::new(ir_object),
+                            index: Box::new(ir_key),
+                            length: *length,
+                        }
+                    } else {
+                        IRExprKind::IndexReference {
                             list: Box::new(ir_object),
                             index: Box::new(ir_key),
+                        }
+                    };
+                    let ir_left = IRExpr {
+                        node,
+                        ty: left.ty.clone(),
+                    };
+                    let ir_right = self.lower_expr(right)?;
+                    Ok(IRExpr {
+                        node: IRExprKind::Binary {
+                            left: Box::new(ir_left),
+                            op: BinaryOp::Is,
+                            right: Box::new(ir_right),
+                        },
+                        ty: expr.ty.clone(),
+                    })
+                }
+                Expr::Slice {
+                    expr: list,
+                    start,
+                    end,
+                } => {
+                    let ir_left = IRExpr {
+                        node: IRExprKind::SliceReference {
+                            list: Box::new(self.lower_expr(list)?),
+                            start: Box::new(self.lower_expr(start)?),
+                            end: Box::new(self.lower_expr(end)?),
                         },
                         ty: left.ty.clone(),
                     };
@@ -439,6 +610,38 @@ impl IRGenerator {
                     ty: expr.ty.clone(),
                 })
             }
+            Expr::Builtin { builtin, args } => {
+                let mut ir_args = Vec::new();
+                for a in args {
+                    ir_args.push(match (builtin, &a.expr) {
+                        // The error is named in source and checked by struct id.
+                        (Builtin::HoldsError, Expr::String(name)) => {
+                            integer(self.lookup_struct(name)? as i64)
+                        }
+                        _ => self.lower_expr(a)?,
+                    });
+                }
+                Ok(IRExpr {
+                    node: IRExprKind::Builtin {
+                        builtin: *builtin,
+                        args: ir_args,
+                    },
+                    ty: expr.ty.clone(),
+                })
+            }
+            Expr::ExternCall { function, args } => {
+                let mut ir_args = Vec::new();
+                for a in args {
+                    ir_args.push(self.lower_expr(a)?);
+                }
+                Ok(IRExpr {
+                    node: IRExprKind::ExternCall {
+                        function: function.clone(),
+                        args: ir_args,
+                    },
+                    ty: expr.ty.clone(),
+                })
+            }
             Expr::Match { .. } => todo!(),
             Expr::UnwrapError(inner) => {
                 let ir_inner = self.lower_expr(inner)?;
@@ -457,15 +660,6 @@ impl IRGenerator {
         }
     }
 
-    fn lower_pattern(&mut self, pattern: &Pattern) -> IRPattern {
-        match pattern {
-            Pattern::MatchNull => todo!(),
-            Pattern::MatchError => todo!(),
-            Pattern::MatchType(ty) => todo!(),
-            Pattern::MatchAll => todo!(),
-        }
-    }
-
     fn lookup_struct(&self, name: &str) -> Result<u32, CompilerError> {
         self.structs
             .iter()
@@ -476,7 +670,12 @@ impl IRGenerator {
             })
     }
 
-    fn get_field_offset(&self, struct_name: &str, field_name: &str) -> Result<u32, CompilerError> {
+    /// Byte offset of a field's slot, plus its `(shift, width)` if it is a bit-field.
+    fn get_field_layout(
+        &self,
+        struct_name: &str,
+        field_name: &str,
+    ) -> Result<(u32, Option<(u32, u32)>), CompilerError> {
         let structure = self
             .structs
             .iter()
@@ -484,15 +683,68 @@ impl IRGenerator {
             .ok_or_else(|| CompilerError::IRGen {
                 message: format!("struct '{}' not found", struct_name),
             })?;
-        let mut offset: u32 = 0;
-        for (name, _ty) in &structure.fields {
+        for (i, (name, _ty)) in structure.fields.iter().enumerate() {
             if name == field_name {
-                return Ok(offset);
+                return Ok((structure.offsets[i], structure.bits[i]));
             }
-            offset += 8; // assuming each field is 8 bytes for simplicity
         }
         Err(CompilerError::IRGen {
             message: format!("field '{}' not found in struct '{}'", field_name, struct_name),
         })
     }
 }
+
+fn integer(n: i64) -> IRExpr {
+    IRExpr {
+        node: IRExprKind::Integer(n),
+        ty: Type {
+            kind: TypeKind::Integer,
+            nullable: false,
+            errorable: false,
+            errors: vec![],
+        },
+    }
+}
+
+fn integer_binary(left: IRExpr, op: BinaryOp, right: IRExpr) -> IRExpr {
+    IRExpr {
+        ty: left.ty.clone(),
+        node: IRExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+    }
+}
+
+fn bit_mask(width: u32) -> i64 {
+    if width == 64 {
+        -1
+    } else {
+        (1i64 << width) - 1
+    }
+}
+
+/// `(slot >> shift) & mask`. The shift is arithmetic, but the mask clears
+/// any copied sign bits.
+fn extract_bits(slot: IRExpr, shift: u32, width: u32) -> IRExpr {
+    let shifted = integer_binary(slot, BinaryOp::Sra, integer(shift as i64));
+    integer_binary(shifted, BinaryOp::BitwiseAnd, integer(bit_mask(width)))
+}
+
+/// `(value & mask) << shift`
+fn place_bits(value: IRExpr, shift: u32, width: u32) -> IRExpr {
+    let masked = integer_binary(value, BinaryOp::BitwiseAnd, integer(bit_mask(width)));
+    integer_binary(masked, BinaryOp::Sll, integer(shift as i64))
+}
+
+/// The index of the `drop` method the struct `name` declares, if any.
+fn drop_method(program: &FlattenedProgram, name: &str) -> Option<u32> {
+    let method = format!("{}.drop", name);
+    program.functions.iter().find_map(|stmt| match stmt {
+        AnalyzedStatement::Function {
+            name, fn_index, ..
+        } if *name == method => *fn_index,
+        _ => None,
+    })
+}
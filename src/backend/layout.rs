@@ -0,0 +1,121 @@
+use crate::ast::{IRProgram, IRStructKind, Type, TypeKind};
+
+/// Bytes alloc puts before every struct, for its type id and mark.
+const HEADER_SIZE: u32 = 8;
+/// Bytes alloc puts before every slab, for its type id and block count.
+const SLAB_HEADER_SIZE: u32 = 8;
+/// Most structs alloc carves out of each slab it takes from memory.
+const SLAB_BLOCKS: u32 = 32;
+/// Most bytes of blocks in a slab, unless a single block is larger.
+const SLAB_BYTES: u32 = 4096;
+/// The struct nullable and errorable values are boxed in.
+const TAGGED_UNION: usize = 0;
+
+/// Where each field of the program's structs and errors lives, and what the
+/// structs cost to allocate.
+#[derive(Debug)]
+pub struct Layout {
+    pub structs: Vec<StructLayout>,
+    /// Bytes of each box around a nullable or errorable value, header
+    /// included.
+    pub box_bytes: u32,
+}
+
+#[derive(Debug)]
+pub struct StructLayout {
+    pub name: String,
+    pub error: bool,
+    /// Bytes of the fields, which `falloc` clears for each new struct.
+    pub size: u32,
+    /// Bytes each struct takes in its slab, header included.
+    pub block_bytes: u32,
+    /// Structs in each slab, fewer for large ones.
+    pub slab_count: u32,
+    pub fields: Vec<FieldLayout>,
+}
+
+#[derive(Debug)]
+pub struct FieldLayout {
+    pub name: String,
+    pub ty: Type,
+    pub offset: u32,
+    /// `(shift, width)` within the slot it shares, for bit-fields.
+    pub bits: Option<(u32, u32)>,
+    /// Whether the slot points to a separate box holding the value, as it
+    /// does for nullable and errorable fields.
+    pub boxed: bool,
+}
+
+impl Layout {
+    /// The layouts of the structs and errors the program declares, leaving
+    /// out those the compiler makes for closures and boxes.
+    pub fn new(program: &IRProgram) -> Self {
+        let structs = program
+            .structs
+            .iter()
+            .enumerate()
+            .filter(|(i, s)| *i != TAGGED_UNION && !matches!(s.kind, IRStructKind::Captures))
+            .map(|(_, s)| StructLayout {
+                name: s.name.clone(),
+                error: matches!(s.kind, IRStructKind::Error),
+                size: s.size,
+                block_bytes: HEADER_SIZE + s.size,
+                slab_count: (SLAB_BYTES / (HEADER_SIZE + s.size)).clamp(1, SLAB_BLOCKS),
+                fields: s
+                    .fields
+                    .iter()
+                    .zip(&s.offsets)
+                    .zip(&s.bits)
+                    .map(|(((name, ty), offset), bits)| FieldLayout {
+                        name: name.clone(),
+                        ty: ty.clone(),
+                        offset: *offset,
+                        bits: *bits,
+                        boxed: ty.nullable || ty.errorable,
+                    })
+                    .collect(),
+            })
+            .collect();
+        let box_bytes = program
+            .structs
+            .get(TAGGED_UNION)
+            .map_or(0, |s| HEADER_SIZE + s.size);
+        Layout { structs, box_bytes }
+    }
+
+    /// The layouts as text, one struct after another.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for (i, s) in self.structs.iter().enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            out.push_str(&format!(
+                "{} {}: {} bytes, in {}-byte blocks, {} per {}-byte slab\n",
+                if s.error { "error" } else { "struct" },
+                s.name,
+                s.size,
+                s.block_bytes,
+                s.slab_count,
+                SLAB_HEADER_SIZE + s.block_bytes * s.slab_count
+            ));
+            for field in &s.fields {
+                out.push_str(&format!(
+                    "  {:>4}  {}: {}",
+                    field.offset, field.name, field.ty
+                ));
+                if let Some((shift, width)) = field.bits {
+                    out.push_str(&format!(", bits {}..{}", shift, shift + width));
+                }
+                if let TypeKind::Array { length, .. } = field.ty.kind {
+                    out.push_str(&format!(", {} slots inline", length));
+                }
+                if field.boxed {
+                    out.push_str(&format!(", boxed in {} bytes", self.box_bytes));
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
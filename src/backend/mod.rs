@@ -1,5 +1,16 @@
 mod irgen;
 mod codegen;
+mod interpreter;
+mod layout;
+mod bundle;
+mod glue;
+mod gc;
+mod names;
 
 pub use irgen::IRGenerator;
-pub use codegen::Codegen;
+pub use codegen::{check_locals, AllocPolicy, Codegen, CodegenOptions, FrameUsage, Frames};
+pub use interpreter::Interpreter;
+pub use layout::{FieldLayout, Layout, StructLayout};
+pub use bundle::bundle;
+pub use glue::Glue;
+pub use gc::GcCodegen;
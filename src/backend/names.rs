@@ -0,0 +1,71 @@
+use crate::ast::{IRFunction, IRStruct, IRStructKind};
+use wasm_encoder::{IndirectNameMap, NameMap, NameSection, ValType};
+
+/// The contents of a module's `name` section, which debuggers and stack
+/// traces show functions, locals and types by. Each is added in index
+/// order.
+#[derive(Default)]
+pub(super) struct Names {
+    functions: NameMap,
+    locals: IndirectNameMap,
+    types: NameMap,
+}
+
+impl Names {
+    pub(super) fn function(&mut self, index: u32, name: &str) {
+        self.functions.append(index, name);
+    }
+
+    /// Names the program's function at `index`, its environment, and the
+    /// params and locals the program declared.
+    pub(super) fn program_function(&mut self, index: u32, func: &IRFunction) {
+        self.function(index, &func.name);
+        let mut locals = NameMap::new();
+        locals.append(2, "env");
+        for (i, name) in func.local_names.iter().enumerate() {
+            locals.append(3 + i as u32, name);
+        }
+        self.locals.append(index, &locals);
+    }
+
+    pub(super) fn ty(&mut self, index: u32, name: &str) {
+        self.types.append(index, name);
+    }
+
+    /// Names the function type at `index` after its signature, since
+    /// functions with the same one share it.
+    pub(super) fn signature(&mut self, index: u32, params: &[ValType], results: &[ValType]) {
+        let list = |types: &[ValType]| {
+            types
+                .iter()
+                .map(|ty| match ty {
+                    ValType::I32 => "i32",
+                    ValType::I64 => "i64",
+                    ValType::F32 => "f32",
+                    ValType::F64 => "f64",
+                    ValType::V128 => "v128",
+                    ValType::Ref(_) => "ref",
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        self.ty(index, &format!("({}) -> ({})", list(params), list(results)));
+    }
+
+    pub(super) fn section(&self) -> NameSection {
+        let mut section = NameSection::new();
+        section.functions(&self.functions);
+        section.locals(&self.locals);
+        section.types(&self.types);
+        section
+    }
+}
+
+/// The name of a struct's type: its own, or for a closure's captures the
+/// function it belongs to.
+pub(super) fn struct_name(ir_struct: &IRStruct) -> String {
+    match ir_struct.kind {
+        IRStructKind::Captures => format!("{} captures", ir_struct.name),
+        _ => ir_struct.name.clone(),
+    }
+}
@@ -1,65 +1,168 @@
+use star::host::{
+    AsyncRequest, AsyncState, ARG_COPY_IMPORT, ARG_COUNT_IMPORT, ARG_LENGTH_IMPORT,
+    ASYNC_ARGUMENT_EXPORT, ASYNC_REQUEST_EXPORT, ASYNC_RESULT_EXPORT, ASYNC_STATE_EXPORT,
+    DALLOC_MEMORY_EXPORT, ENV_COPY_IMPORT, ENV_LENGTH_IMPORT, RUNTIME_MODULES,
+};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use wasmtime::*;
 
 fn main() -> Result<()> {
-    let engine = Engine::default();
+    // Programs built with --wasm-gc use GC structs and arrays. wasmtime's
+    // reference-counting collector misses roots in them, so they run on
+    // the null collector until its heap is full.
+    let mut config = Config::new();
+    config
+        .wasm_gc(true)
+        .wasm_function_references(true)
+        .collector(Collector::Null);
+    let engine = Engine::new(&config)?;
     let mut store = Store::new(&engine, ());
     let mut linker = Linker::new(&engine);
 
-    // Load and instantiate fixed-size allocator (alloc)
-    let alloc_bytes = std::fs::read("alloc/target/wasm32-unknown-unknown/release/alloc.wasm")
-        .expect(
-            "Build alloc first: cd alloc && cargo build --target wasm32-unknown-unknown --release",
-        );
-    let alloc_module = Module::new(&engine, &alloc_bytes)?;
-    let alloc_instance = linker.instantiate(&mut store, &alloc_module)?;
-    linker.instance(&mut store, "alloc", alloc_instance)?;
-
-    // Load and instantiate dynamic allocator (dalloc) for lists
-    let dalloc_bytes = std::fs::read("dalloc/target/wasm32-unknown-unknown/release/dalloc.wasm")
-        .expect("Build dalloc first: cd dalloc && cargo build --target wasm32-unknown-unknown --release");
-    let dalloc_module = Module::new(&engine, &dalloc_bytes)?;
-    let dalloc_instance = linker.instantiate(&mut store, &dalloc_module)?;
-    linker.instance(&mut store, "dalloc", dalloc_instance)?;
-
-    // Load and instantiate shadow stack for GC roots
-    let shadow_bytes = std::fs::read("shadow/target/wasm32-unknown-unknown/release/shadow.wasm")
-        .expect("Build shadow first: cd shadow && cargo build --target wasm32-unknown-unknown --release");
-    let shadow_module = Module::new(&engine, &shadow_bytes)?;
-    let shadow_instance = linker.instantiate(&mut store, &shadow_module)?;
-    linker.instance(&mut store, "shadow", shadow_instance)?;
+    // Load Star program
+    let wasm_bytes = std::fs::read("output.wasm").expect("Failed to read output.wasm");
+    let module = Module::new(&engine, &wasm_bytes)?;
 
-    let lists = dalloc_instance
-        .get_memory(&mut store, "memory")
-        .expect("Expected a memory export in dalloc");
+    // A bundled program carries the runtime inside it, and exports dalloc's
+    // memory itself once instantiated.
+    let bundled = !module.imports().any(|import| import.module() == abi::ALLOC);
+    let lists = if bundled {
+        None
+    } else {
+        Some(instantiate_runtime(&engine, &mut store, &mut linker)?)
+    };
 
     // Host function: print
-    linker.func_wrap("env", "print", move |caller: Caller<'_, ()>, ptr: i32| {
-        let data = lists.data(&caller);
+    linker.func_wrap("env", "print", move |mut caller: Caller<'_, ()>, ptr: i32| {
+        let data = strings(&mut caller, lists).data(&caller);
 
         let ptr = ptr as usize;
         let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap());
 
-        let mut string: Vec<u8> = Vec::with_capacity(length as usize);
-
-        for i in 0..length {
-            let start = ptr + (i as usize) * 8;
-            string.push(data[start]);
-        }
+        let string = data[ptr..ptr + length as usize].to_vec();
 
         let decoded = String::from_utf8(string).unwrap();
         print!("{}\n", decoded);
         Ok(())
     })?;
 
-    // Load Star program
-    let wasm_bytes = std::fs::read("output.wasm").expect("Failed to read output.wasm");
-    let module = Module::new(&engine, &wasm_bytes)?;
+    // Host functions: the arguments after our own name, for main(args)
+    let args: Arc<Vec<String>> = Arc::new(std::env::args().skip(1).collect());
+    let count = args.clone();
+    linker.func_wrap("env", ARG_COUNT_IMPORT, move || count.len() as i32)?;
+    let lengths = args.clone();
+    linker.func_wrap("env", ARG_LENGTH_IMPORT, move |index: i32| {
+        lengths[index as usize].len() as i32
+    })?;
+    linker.func_wrap(
+        "env",
+        ARG_COPY_IMPORT,
+        move |mut caller: Caller<'_, ()>, index: i32, ptr: i32| {
+            let bytes = args[index as usize].as_bytes();
+            let ptr = ptr as usize;
+            strings(&mut caller, lists).data_mut(&mut caller)[ptr..ptr + bytes.len()]
+                .copy_from_slice(bytes);
+        },
+    )?;
+
+    // Host functions: our own environment variables, for env(name)
+    fn var(data: &[u8], ptr: i32) -> Option<String> {
+        let ptr = ptr as usize;
+        let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap()) as usize;
+        std::env::var(String::from_utf8_lossy(&data[ptr..ptr + length]).as_ref()).ok()
+    }
+    linker.func_wrap(
+        "env",
+        ENV_LENGTH_IMPORT,
+        move |mut caller: Caller<'_, ()>, ptr: i32| {
+            var(strings(&mut caller, lists).data(&caller), ptr).map_or(-1, |value| value.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        ENV_COPY_IMPORT,
+        move |mut caller: Caller<'_, ()>, ptr: i32, dest: i32| {
+            let memory = strings(&mut caller, lists);
+            let value = var(memory.data(&caller), ptr).unwrap_or_default();
+            let dest = dest as usize;
+            memory.data_mut(&mut caller)[dest..dest + value.len()]
+                .copy_from_slice(value.as_bytes());
+        },
+    )?;
+
     let instance = linker.instantiate(&mut store, &module)?;
 
     // Get and call the main function
     let main = instance.get_typed_func::<(i32, i64, i32), i64>(&mut store, "main")?;
-    let result = main.call(&mut store, (0, 0, 0))?;
+    let mut result = main.call(&mut store, (0, 0, 0))?;
+
+    // A program that awaits returns here with a request, then resumes.
+    if let Some(state) = instance.get_global(&mut store, ASYNC_STATE_EXPORT) {
+        let mut global = |name| instance.get_global(&mut store, name).unwrap();
+        let request = global(ASYNC_REQUEST_EXPORT);
+        let argument = global(ASYNC_ARGUMENT_EXPORT);
+        let answer = global(ASYNC_RESULT_EXPORT);
+        while state.get(&mut store).unwrap_i32() == AsyncState::Unwinding as i32 {
+            let argument = argument.get(&mut store).unwrap_i64();
+            match AsyncRequest::from_i32(request.get(&mut store).unwrap_i32()) {
+                Some(AsyncRequest::Sleep) => {
+                    let started = Instant::now();
+                    std::thread::sleep(Duration::from_millis(argument.max(0) as u64));
+                    answer.set(&mut store, Val::I64(started.elapsed().as_millis() as i64))?;
+                }
+                Some(AsyncRequest::Fetch) => {
+                    return Err(Error::msg("fetch is only available in the playground"));
+                }
+                None => return Err(Error::msg("unknown async request")),
+            }
+            state.set(&mut store, Val::I32(AsyncState::Rewinding as i32))?;
+            result = main.call(&mut store, (0, 0, 0))?;
+        }
+        // exit(code) unwinds all the way out, leaving its code behind.
+        if state.get(&mut store).unwrap_i32() == AsyncState::Exiting as i32 {
+            std::io::stdout().flush()?;
+            std::process::exit(argument.get(&mut store).unwrap_i64() as i32);
+        }
+    }
     println!("main returned: {}", result);
 
     Ok(())
 }
+
+/// Instantiates the runtime modules a program imports, returning dalloc's
+/// memory, where strings live.
+fn instantiate_runtime(
+    engine: &Engine,
+    store: &mut Store<()>,
+    linker: &mut Linker<()>,
+) -> Result<Memory> {
+    let mut dalloc = None;
+    for name in RUNTIME_MODULES {
+        let path = format!("{0}/target/wasm32-unknown-unknown/release/{0}.wasm", name);
+        let bytes = std::fs::read(&path).unwrap_or_else(|_| {
+            panic!(
+                "Build {0} first: cd {0} && cargo build --target wasm32-unknown-unknown --release",
+                name
+            )
+        });
+        let module = Module::new(engine, &bytes)?;
+        let instance = linker.instantiate(&mut *store, &module)?;
+        linker.instance(&mut *store, name, instance)?;
+        if name == abi::DALLOC {
+            dalloc = instance.get_memory(&mut *store, "memory");
+        }
+    }
+    Ok(dalloc.expect("Expected a memory export in dalloc"))
+}
+
+/// The memory strings live in: dalloc's, or the bundle's export of it.
+fn strings(caller: &mut Caller<'_, ()>, lists: Option<Memory>) -> Memory {
+    lists.unwrap_or_else(|| {
+        caller
+            .get_export(DALLOC_MEMORY_EXPORT)
+            .and_then(Extern::into_memory)
+            .expect("Expected a dalloc.memory export in the bundle")
+    })
+}
@@ -3,22 +3,42 @@ use std::fmt;
 #[derive(Debug, Clone)]
 pub enum CompilerError {
     Parse { message: String },
+    /// A file that couldn't be read, or an import cycle.
+    Module { message: String },
     Type { message: String },
     Locals { message: String },
     IRGen { message: String },
     Codegen { message: String },
+    /// A trap while `execute` runs a program: the interpreter's counterpart
+    /// of a WASM trap.
+    Runtime { message: String },
 }
 
+/// A single problem reported by `compile`. Parsing and type checking recover
+/// at statement boundaries, so one compilation can report several of these.
+pub type Diagnostic = CompilerError;
+
 impl fmt::Display for CompilerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             CompilerError::Parse { message } => write!(f, "Parse error: {}", message),
+            CompilerError::Module { message } => write!(f, "Module error: {}", message),
             CompilerError::Type { message } => write!(f, "Type error: {}", message),
             CompilerError::Locals { message } => write!(f, "Locals error: {}", message),
             CompilerError::IRGen { message } => write!(f, "IR generation error: {}", message),
             CompilerError::Codegen { message } => write!(f, "Codegen error: {}", message),
+            CompilerError::Runtime { message } => write!(f, "Runtime error: {}", message),
         }
     }
 }
 
 impl std::error::Error for CompilerError {}
+
+/// Renders diagnostics one per line, in the order they were reported.
+pub fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
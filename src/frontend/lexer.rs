@@ -1,6 +1,7 @@
+use crate::error::CompilerError;
 use logos::Logos;
 
-#[derive(Logos, Debug, PartialEq)]
+#[derive(Logos, Debug, Clone, PartialEq)]
 #[logos(skip r"[ \t\n\r]+")]
 #[logos(skip r"//[^\n]*")]
 pub enum Token {
@@ -22,6 +23,12 @@ pub enum Token {
     #[token("while")]
     While,
 
+    #[token("unchecked")]
+    Unchecked,
+
+    #[token("await")]
+    Await,
+
     #[token("match")]
     Match,
 
@@ -31,6 +38,15 @@ pub enum Token {
     #[token("import")]
     Import,
 
+    #[token("export")]
+    Export,
+
+    #[token("extern")]
+    Extern,
+
+    #[token("@noalloc")]
+    NoAlloc,
+
     #[token("from")]
     From,
 
@@ -119,6 +135,9 @@ pub enum Token {
     Sll,
 
     #[token(">>")]
+    Sra,
+
+    #[token(">>>")]
     Srl,
 
     #[token("{")]
@@ -151,6 +170,9 @@ pub enum Token {
     #[token(".")]
     Access,
 
+    #[token("..")]
+    Range,
+
     #[token("?")]
     Nullable,
 
@@ -169,6 +191,9 @@ pub enum Token {
     #[token("struct")]
     Struct,
 
+    #[token("packed")]
+    Packed,
+
     #[token("error")]
     Error,
 
@@ -178,6 +203,9 @@ pub enum Token {
     #[token("print")]
     Print,
 
+    #[token("println")]
+    Println,
+
     #[token("new")]
     New,
 
@@ -187,6 +215,15 @@ pub enum Token {
     #[token("produce")]
     Produce,
 
+    #[token("defer")]
+    Defer,
+
+    #[token("type")]
+    Type,
+
+    #[token("assert")]
+    Assert,
+
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier,
 
@@ -201,4 +238,39 @@ pub enum Token {
 
     #[regex(r#""([^"\\]|\\.)*""#)]
     String,
+
+    /// `r"..."`: taken verbatim, so backslashes need no doubling in regexes
+    /// and paths. A raw string cannot contain a `"`.
+    #[regex(r#"r"[^"]*""#)]
+    RawString,
+}
+
+/// The text a string literal token stands for. Ordinary strings have their
+/// escapes decoded; raw strings are returned as written.
+pub fn string_value(token: &Token, slice: &str) -> Result<String, CompilerError> {
+    if let Token::RawString = token {
+        return Ok(slice[2..slice.len() - 1].to_string());
+    }
+    let mut value = String::new();
+    let mut chars = slice[1..slice.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        value.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(other) => {
+                return Err(CompilerError::Parse {
+                    message: format!("Invalid escape sequence '\\{}' in string", other),
+                })
+            }
+            None => unreachable!("the lexer never ends a string on a backslash"),
+        });
+    }
+    Ok(value)
 }
@@ -1,5 +1,7 @@
 mod lexer;
+mod modules;
 pub mod parser;
 
 pub use lexer::Token;
+pub use modules::{load_program, ReadModule};
 pub use parser::Parser;
@@ -0,0 +1,142 @@
+use crate::ast::{Program, Statement};
+use crate::error::CompilerError;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use super::Parser;
+
+/// Reads the source of the module at a path.
+pub type ReadModule<'a> = dyn FnMut(&Path) -> std::io::Result<String> + 'a;
+
+/// Parses `entry` and every file it imports into one program of
+/// `Statement::Module`s. Each file is parsed once however many files import
+/// it, and comes after every module it imports, so its declarations are
+/// checked before anything that uses them.
+pub fn load_program(entry: &Path, read: &mut ReadModule) -> Result<Program, Vec<CompilerError>> {
+    let root = entry.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut loader = Loader {
+        read,
+        root,
+        loaded: HashMap::new(),
+        loading: Vec::new(),
+        modules: Vec::new(),
+        errors: Vec::new(),
+    };
+    loader.load(&normalize(entry));
+
+    if loader.errors.is_empty() {
+        Ok(Program {
+            statements: loader.modules,
+        })
+    } else {
+        Err(loader.errors)
+    }
+}
+
+struct Loader<'a, 'b> {
+    read: &'a mut ReadModule<'b>,
+    /// The entry's directory, which module names are relative to.
+    root: PathBuf,
+    /// The name of every module parsed so far.
+    loaded: HashMap<PathBuf, String>,
+    /// The chain of imports being followed, to report cycles.
+    loading: Vec<PathBuf>,
+    modules: Vec<Statement>,
+    errors: Vec<CompilerError>,
+}
+
+impl Loader<'_, '_> {
+    /// Loads the module at `path` after its imports, returning its name.
+    fn load(&mut self, path: &Path) -> Option<String> {
+        if let Some(name) = self.loaded.get(path) {
+            return Some(name.clone());
+        }
+        let name = self.name(path);
+        if let Some(start) = self.loading.iter().position(|p| p == path) {
+            let cycle: Vec<String> = self.loading[start..]
+                .iter()
+                .map(|p| self.name(p))
+                .chain(std::iter::once(name))
+                .collect();
+            self.errors.push(CompilerError::Module {
+                message: format!("Import cycle: {}", cycle.join(" -> ")),
+            });
+            return None;
+        }
+
+        let source = match (self.read)(path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.errors.push(CompilerError::Module {
+                    message: format!("Failed to read {}: {}", name, e),
+                });
+                return None;
+            }
+        };
+        let statements = match Parser::new(&source).parse_program() {
+            Ok(program) => program.statements,
+            Err(errors) => {
+                self.errors.extend(errors.into_iter().map(|e| in_module(e, &name)));
+                return None;
+            }
+        };
+
+        self.loading.push(path.to_path_buf());
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut imports = Vec::new();
+        let mut body = Vec::new();
+        for statement in statements {
+            match statement {
+                Statement::Import { path } => {
+                    if let Some(import) = self.load(&normalize(&dir.join(path))) {
+                        imports.push(import);
+                    }
+                }
+                statement => body.push(statement),
+            }
+        }
+        self.loading.pop();
+
+        self.loaded.insert(path.to_path_buf(), name.clone());
+        self.modules.push(Statement::Module {
+            path: name.clone(),
+            imports,
+            body,
+        });
+        Some(name)
+    }
+
+    /// How diagnostics refer to the module at `path`.
+    fn name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+}
+
+/// Names the module a parse error was found in.
+fn in_module(error: CompilerError, name: &str) -> CompilerError {
+    match error {
+        CompilerError::Parse { message } => CompilerError::Parse {
+            message: format!("{}: {}", name, message),
+        },
+        other => other,
+    }
+}
+
+/// Resolves `.` and `..` in `path` without touching the filesystem, so one
+/// file reached by two different routes is still loaded once.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
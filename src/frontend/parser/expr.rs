@@ -20,11 +20,7 @@ impl<'a> Parser<'a> {
                 self.advance();
                 Expr::Float(slice.parse().unwrap())
             }
-            Some(Token::String) => {
-                let slice = self.slice().to_string();
-                self.advance();
-                Expr::String(slice[1..slice.len() - 1].to_string())
-            }
+            Some(Token::String | Token::RawString) => Expr::String(self.string_literal()?),
             Some(Token::True) => {
                 self.advance();
                 Expr::Boolean(true)
@@ -44,6 +40,22 @@ impl<'a> Parser<'a> {
                 self.expect(&Token::RParenthesis)?;
                 expr
             }
+            Some(Token::If) => self.parse_if_expression()?,
+            Some(Token::Fn) => {
+                self.advance();
+                let (params, returns) = self.parse_parameters()?;
+                let body = self.parse_block()?;
+                Expr::Lambda {
+                    params,
+                    returns,
+                    body,
+                }
+            }
+            Some(Token::Await) => {
+                self.advance();
+                let rbp = Parser::prefix_binding_power(&Token::Await).unwrap();
+                Expr::Await(Box::new(self.parse_expression(rbp)?))
+            }
             Some(Token::Not)
             | Some(Token::Minus)
             | Some(Token::Count)
@@ -90,6 +102,16 @@ impl<'a> Parser<'a> {
                     Expr::List(elements)
                 }
             }
+            Some(Token::LBracket) => {
+                self.advance();
+                let mut elements = vec![self.parse_expression(0)?];
+                while self.check(&Token::Separator) {
+                    self.advance();
+                    elements.push(self.parse_expression(0)?);
+                }
+                self.expect(&Token::RBracket)?;
+                Expr::Array(elements)
+            }
             Some(Token::New) => {
                 self.advance();
                 let name = if let Some(Token::Identifier) = self.peek() {
@@ -145,21 +167,17 @@ impl<'a> Parser<'a> {
                     } else if self.check(&Token::Errorable) {
                         self.advance();
                         Pattern::MatchError
-                    } else if self.check(&Token::Identifier) {
+                    } else if self.check(&Token::Identifier)
+                        && self.slice() != "_"
+                        && self.peek_next() != Some(Token::LBrace)
+                    {
                         let ty = self.parse_type()?;
                         Pattern::MatchType(ty)
                     } else {
-                        return Err(CompilerError::Parse {
-                            message: format!("Expected pattern in match arm, found {:?}", self.peek()),
-                        });
+                        self.parse_pattern()?
                     };
                     self.expect(&Token::Colon)?;
-                    self.expect(&Token::LBrace)?;
-                    let mut body = Vec::new();
-                    while !self.check(&Token::RBrace) {
-                        body.push(self.parse_statement(false)?);
-                    }
-                    self.expect(&Token::RBrace)?;
+                    let body = self.parse_block()?;
                     arms.push((pattern, body));
                 }
                 self.expect(&Token::RBrace)?;
@@ -187,6 +205,16 @@ impl<'a> Parser<'a> {
                     break;
                 }
 
+                if *op == Token::Range {
+                    self.advance();
+                    let end = self.parse_expression(r_bp)?;
+                    left = Expr::Range {
+                        start: Box::new(left),
+                        end: Box::new(end),
+                    };
+                    continue;
+                }
+
                 let infix = Parser::token_to_binary_op(op)?;
                 self.advance();
                 let right = self.parse_expression(r_bp)?;
@@ -262,4 +290,105 @@ impl<'a> Parser<'a> {
 
         Ok(left)
     }
+
+    /// Parses `_`, a literal, or a struct pattern. Inside a struct pattern,
+    /// a bare name binds the field's value.
+    fn parse_pattern(&mut self) -> Result<Pattern, CompilerError> {
+        match self.peek() {
+            Some(Token::Identifier) if self.slice() == "_" => {
+                self.advance();
+                Ok(Pattern::MatchAll)
+            }
+            Some(Token::Identifier) if self.peek_next() == Some(Token::LBrace) => {
+                let name = self.slice().to_string();
+                self.advance();
+                self.expect(&Token::LBrace)?;
+                let mut fields = Vec::new();
+                while !self.check(&Token::RBrace) {
+                    if !self.check(&Token::Identifier) {
+                        return Err(CompilerError::Parse {
+                            message: format!("Expected field name in struct pattern, found {:?}", self.peek()),
+                        });
+                    }
+                    let field = self.slice().to_string();
+                    self.advance();
+                    let pattern = if self.match_token(&Token::Colon) {
+                        self.parse_pattern()?
+                    } else {
+                        Pattern::Binding(field.clone())
+                    };
+                    fields.push((field, pattern));
+                    if self.check(&Token::Separator) {
+                        self.advance();
+                    }
+                }
+                self.expect(&Token::RBrace)?;
+                Ok(Pattern::Struct { name, fields })
+            }
+            Some(Token::Identifier) => {
+                let name = self.slice().to_string();
+                self.advance();
+                Ok(Pattern::Binding(name))
+            }
+            Some(Token::Minus) => {
+                self.advance();
+                match self.parse_pattern()? {
+                    Pattern::Literal(Expr::Integer(n)) => Ok(Pattern::Literal(Expr::Integer(-n))),
+                    Pattern::Literal(Expr::Float(n)) => Ok(Pattern::Literal(Expr::Float(-n))),
+                    _ => Err(CompilerError::Parse {
+                        message: "Expected a number after '-' in pattern".to_string(),
+                    }),
+                }
+            }
+            Some(Token::Integer) => {
+                let n = self.slice().parse().unwrap();
+                self.advance();
+                Ok(Pattern::Literal(Expr::Integer(n)))
+            }
+            Some(Token::Float) => {
+                let n = self.slice().parse().unwrap();
+                self.advance();
+                Ok(Pattern::Literal(Expr::Float(n)))
+            }
+            Some(Token::String | Token::RawString) => {
+                Ok(Pattern::Literal(Expr::String(self.string_literal()?)))
+            }
+            Some(Token::True | Token::False) => {
+                let value = self.check(&Token::True);
+                self.advance();
+                Ok(Pattern::Literal(Expr::Boolean(value)))
+            }
+            _ => Err(CompilerError::Parse {
+                message: format!("Expected pattern in match arm, found {:?}", self.peek()),
+            }),
+        }
+    }
+
+    /// `if condition { value } else { value }`, where the `else` may be
+    /// another `if` expression.
+    fn parse_if_expression(&mut self) -> Result<Expr, CompilerError> {
+        self.expect(&Token::If)?;
+        let condition = self.parse_expression(0)?;
+        self.expect(&Token::LBrace)?;
+        let then_value = self.parse_expression(0)?;
+        self.expect(&Token::RBrace)?;
+        if !self.match_token(&Token::Else) {
+            return Err(CompilerError::Parse {
+                message: "An if expression needs an else branch".to_string(),
+            });
+        }
+        let else_value = if self.check(&Token::If) {
+            self.parse_if_expression()?
+        } else {
+            self.expect(&Token::LBrace)?;
+            let value = self.parse_expression(0)?;
+            self.expect(&Token::RBrace)?;
+            value
+        };
+        Ok(Expr::If {
+            condition: Box::new(condition),
+            then_value: Box::new(then_value),
+            else_value: Box::new(else_value),
+        })
+    }
 }
@@ -2,8 +2,8 @@ mod expr;
 mod stmt;
 mod types;
 
-use super::lexer::Token;
-use crate::ast::{BinaryOp, Expr, Program, Statement, Type, TypeKind, UnaryOp};
+use super::lexer::{string_value, Token};
+use crate::ast::{BinaryOp, Program, Statement};
 use crate::error::CompilerError;
 use logos::Logos;
 
@@ -11,6 +11,11 @@ pub struct Parser<'a> {
     lexer: logos::Lexer<'a, Token>,
     current: Option<Token>,
     current_slice: String,
+    consumed: usize,
+    errors: Vec<CompilerError>,
+    /// Functions declared inside a top-level statement, such as a struct's
+    /// `drop` method, which follow it in the program.
+    hoisted: Vec<Statement>,
 }
 
 impl<'a> Parser<'a> {
@@ -23,6 +28,9 @@ impl<'a> Parser<'a> {
             lexer,
             current,
             current_slice,
+            consumed: 0,
+            errors: Vec::new(),
+            hoisted: Vec::new(),
         }
     }
 
@@ -34,8 +42,14 @@ impl<'a> Parser<'a> {
         &self.current_slice
     }
 
+    /// The token after the current one, without consuming anything.
+    pub fn peek_next(&self) -> Option<Token> {
+        self.lexer.clone().next().and_then(|r| r.ok())
+    }
+
     pub fn advance(&mut self) -> Option<Token> {
         let token = self.current.take();
+        self.consumed += 1;
         self.current = self.lexer.next().and_then(|r| r.ok());
         self.current_slice = self.lexer.slice().to_string();
         token
@@ -66,16 +80,123 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Consumes the current string or raw string token, returning its value.
+    pub fn string_literal(&mut self) -> Result<String, CompilerError> {
+        let slice = self.slice().to_string();
+        let token = self.advance().unwrap();
+        string_value(&token, &slice)
+    }
+
+    /// The line the current token starts on, counting from 1.
+    pub fn line(&self) -> usize {
+        let start = self.lexer.span().start;
+        self.lexer.source()[..start].matches('\n').count() + 1
+    }
+
     pub fn at_end(&self) -> bool {
         self.current.is_none()
     }
 
-    pub fn parse_program(&mut self) -> Result<Program, CompilerError> {
+    /// Parses the whole source, recovering at statement boundaries so that
+    /// every syntax error in the file is reported rather than just the first.
+    pub fn parse_program(&mut self) -> Result<Program, Vec<CompilerError>> {
         let mut stmts = Vec::new();
         while !self.at_end() {
-            stmts.push(self.parse_statement(true)?);
+            if let Some(stmt) = self.parse_statement_recovering(true) {
+                stmts.push(stmt);
+            }
+            stmts.append(&mut self.hoisted);
+        }
+
+        if self.errors.is_empty() {
+            Ok(Program { statements: stmts })
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Parses `{ stmt* }`. Statements that fail to parse are recorded and
+    /// skipped so the rest of the block is still checked.
+    pub fn parse_block(&mut self) -> Result<Vec<Statement>, CompilerError> {
+        self.expect(&Token::LBrace)?;
+        let mut stmts = Vec::new();
+        while !self.check(&Token::RBrace) && !self.at_end() {
+            if let Some(stmt) = self.parse_statement_recovering(false) {
+                stmts.push(stmt);
+            }
+        }
+        self.expect(&Token::RBrace)?;
+        Ok(stmts)
+    }
+
+    fn parse_statement_recovering(&mut self, top_level: bool) -> Option<Statement> {
+        let start = self.consumed;
+        match self.parse_statement(top_level) {
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.errors.push(e);
+                self.synchronize();
+                if self.consumed == start {
+                    self.advance();
+                }
+                None
+            }
+        }
+    }
+
+    /// Skips tokens until the end of the current statement (a consumed `;`),
+    /// the end of the enclosing block, or the start of the next statement.
+    /// Braces opened while skipping are skipped as a whole, so a broken `if`
+    /// or `fn` header doesn't leave its body to be parsed as outer statements.
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth > 0 => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                Token::Semicolon if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                Token::RBrace
+                | Token::Let
+                | Token::Const
+                | Token::Fn
+                | Token::Struct
+                | Token::Packed
+                | Token::Error
+                | Token::Type
+                | Token::Import
+                | Token::Export
+                | Token::NoAlloc
+                | Token::Extern
+                | Token::If
+                | Token::For
+                | Token::While
+                | Token::Unchecked
+                | Token::Return
+                | Token::Break
+                | Token::Continue
+                | Token::Print
+                | Token::Println
+                | Token::Produce
+                | Token::Raise
+                | Token::Assert
+                | Token::Defer
+                    if depth == 0 =>
+                {
+                    return
+                }
+                _ => {}
+            }
+            self.advance();
         }
-        Ok(Program { statements: stmts })
     }
 
     pub fn infix_binding_power(op: &Token) -> Option<(u8, u8)> {
@@ -87,11 +208,12 @@ impl<'a> Parser<'a> {
             Token::Eq | Token::Neq => Some((5, 6)),
 
             Token::Lt | Token::Gt | Token::Lte | Token::Gte => Some((7, 8)),
+            Token::Range => Some((8, 9)),
 
             Token::BitwiseOr => Some((9, 10)),
             Token::Xor => Some((11, 12)),
             Token::BitwiseAnd => Some((13, 14)),
-            Token::Sll | Token::Srl => Some((15, 16)),
+            Token::Sll | Token::Sra | Token::Srl => Some((15, 16)),
 
             Token::Plus | Token::Minus => Some((17, 18)),
             Token::Multiply | Token::Divide | Token::Modulo => Some((19, 20)),
@@ -106,7 +228,7 @@ impl<'a> Parser<'a> {
 
     pub fn prefix_binding_power(op: &Token) -> Option<u8> {
         match op {
-            Token::Minus | Token::Not | Token::Count | Token::Stringify => Some(23),
+            Token::Minus | Token::Not | Token::Count | Token::Stringify | Token::Await => Some(23),
             _ => None,
         }
     }
@@ -130,6 +252,7 @@ impl<'a> Parser<'a> {
             Token::BitwiseOr => Ok(BinaryOp::BitwiseOr),
             Token::Xor => Ok(BinaryOp::Xor),
             Token::Sll => Ok(BinaryOp::Sll),
+            Token::Sra => Ok(BinaryOp::Sra),
             Token::Srl => Ok(BinaryOp::Srl),
             Token::Is => Ok(BinaryOp::Is),
             Token::In => Ok(BinaryOp::In),
@@ -1,8 +1,12 @@
-use crate::ast::Statement;
+use crate::ast::{Expr, Extern, Statement, Type, TypeKind};
 use crate::error::CompilerError;
 use crate::frontend::lexer::Token;
 use super::Parser;
 
+/// A function's name, parameters and return type.
+type Signature = (String, Vec<(String, Type)>, Type);
+type Parameters = (Vec<(String, Type)>, Type);
+
 impl<'a> Parser<'a> {
     fn parse_let_statement(&mut self) -> Result<Statement, CompilerError> {
         self.expect(&Token::Let)?;
@@ -79,14 +83,41 @@ impl<'a> Parser<'a> {
 
     fn parse_break_statement(&mut self) -> Result<Statement, CompilerError> {
         self.expect(&Token::Break)?;
-        self.expect(&Token::Semicolon)?;
-        Ok(Statement::Break)
+        let label = self.parse_loop_label()?;
+        Ok(Statement::Break(label))
     }
 
     fn parse_continue_statement(&mut self) -> Result<Statement, CompilerError> {
         self.expect(&Token::Continue)?;
+        let label = self.parse_loop_label()?;
+        Ok(Statement::Continue(label))
+    }
+
+    /// The label a `break` or `continue` names, if any, and its `;`.
+    fn parse_loop_label(&mut self) -> Result<Option<String>, CompilerError> {
+        let label = if self.check(&Token::Identifier) {
+            let label = self.current_slice.clone();
+            self.advance();
+            Some(label)
+        } else {
+            None
+        };
         self.expect(&Token::Semicolon)?;
-        Ok(Statement::Continue)
+        Ok(label)
+    }
+
+    /// `label: while ...` or `label: for ...`.
+    fn parse_labeled_loop(&mut self) -> Result<Statement, CompilerError> {
+        let label = Some(self.current_slice.clone());
+        self.advance();
+        self.expect(&Token::Colon)?;
+        match self.peek() {
+            Some(Token::While) => self.parse_while_statement(label),
+            Some(Token::For) => self.parse_for_statement(label),
+            _ => Err(CompilerError::Parse {
+                message: format!("Expected a loop after its label, found {:?}", self.peek()),
+            }),
+        }
     }
 
     fn parse_print_statement(&mut self) -> Result<Statement, CompilerError> {
@@ -96,6 +127,17 @@ impl<'a> Parser<'a> {
         Ok(Statement::Print(expr))
     }
 
+    fn parse_println_statement(&mut self) -> Result<Statement, CompilerError> {
+        self.expect(&Token::Println)?;
+        let mut exprs = vec![self.parse_expression(0)?];
+        while self.check(&Token::Separator) {
+            self.advance();
+            exprs.push(self.parse_expression(0)?);
+        }
+        self.expect(&Token::Semicolon)?;
+        Ok(Statement::Println(exprs))
+    }
+
     fn parse_produce_statement(&mut self) -> Result<Statement, CompilerError> {
         self.expect(&Token::Produce)?;
         let expr = self.parse_expression(0)?;
@@ -110,60 +152,124 @@ impl<'a> Parser<'a> {
         Ok(Statement::Raise(expr))
     }
 
-    fn parse_if_statement(&mut self) -> Result<Statement, CompilerError> {
-        self.expect(&Token::If)?;
+    fn parse_assert_statement(&mut self) -> Result<Statement, CompilerError> {
+        let line = self.line();
+        self.expect(&Token::Assert)?;
         let condition = self.parse_expression(0)?;
-        self.expect(&Token::LBrace)?;
-        let mut then_block = Vec::new();
-        while !self.check(&Token::RBrace) {
-            then_block.push(self.parse_statement(false)?);
-        }
-        self.expect(&Token::RBrace)?;
-
-        let else_block = if self.match_token(&Token::Else) {
-            if self.check(&Token::If) {
-                Some(vec![self.parse_if_statement()?])
-            } else {
-                self.expect(&Token::LBrace)?;
-                let mut alternate_block = Vec::new();
-                while !self.check(&Token::RBrace) {
-                    alternate_block.push(self.parse_statement(false)?);
-                }
-                self.expect(&Token::RBrace)?;
-                Some(alternate_block)
-            }
+        let message = if self.match_token(&Token::Separator) {
+            Some(self.parse_expression(0)?)
         } else {
             None
         };
+        self.expect(&Token::Semicolon)?;
+        Ok(Statement::Assert {
+            condition,
+            message,
+            line,
+        })
+    }
+
+    fn parse_defer_statement(&mut self) -> Result<Statement, CompilerError> {
+        self.expect(&Token::Defer)?;
+        let expr = self.parse_expression(0)?;
+        self.expect(&Token::Semicolon)?;
+        Ok(Statement::Defer(expr))
+    }
 
+    fn parse_if_statement(&mut self) -> Result<Statement, CompilerError> {
+        self.expect(&Token::If)?;
+        if self.check(&Token::Let) {
+            let (name, value) = self.parse_let_binding()?;
+            let then_block = self.parse_block()?;
+            let else_block = self.parse_else_block()?;
+            return Ok(Statement::IfLet { name, value, then_block, else_block });
+        }
+        let condition = self.parse_expression(0)?;
+        let then_block = self.parse_block()?;
+        let else_block = self.parse_else_block()?;
         Ok(Statement::If { condition, then_block, else_block })
     }
 
-    fn parse_for_statement(&mut self) -> Result<Statement, CompilerError> {
+    fn parse_else_block(&mut self) -> Result<Option<Vec<Statement>>, CompilerError> {
+        if !self.match_token(&Token::Else) {
+            return Ok(None);
+        }
+        if self.check(&Token::If) {
+            Ok(Some(vec![self.parse_if_statement()?]))
+        } else {
+            Ok(Some(self.parse_block()?))
+        }
+    }
+
+    /// `let name = value`, the binding of an `if let` or `while let`.
+    fn parse_let_binding(&mut self) -> Result<(String, Expr), CompilerError> {
+        self.expect(&Token::Let)?;
+        if !self.check(&Token::Identifier) {
+            return Err(CompilerError::Parse {
+                message: format!("Expected identifier after 'let', found {:?}", self.peek()),
+            });
+        }
+        let name = self.current_slice.clone();
+        self.advance();
+        self.expect(&Token::Is)?;
+        let value = self.parse_expression(0)?;
+        Ok((name, value))
+    }
+
+    fn parse_for_statement(&mut self, label: Option<String>) -> Result<Statement, CompilerError> {
         self.expect(&Token::For)?;
+        if self.check(&Token::Identifier) && self.peek_next() == Some(Token::In) {
+            let name = self.current_slice.clone();
+            self.advance();
+            self.expect(&Token::In)?;
+            let iterable = self.parse_expression(0)?;
+            let body = self.parse_block()?;
+            return Ok(Statement::ForIn {
+                label,
+                name,
+                iterable,
+                body,
+            });
+        }
         let init = Box::new(self.parse_statement(false)?);
         let condition = self.parse_expression(0)?;
         self.expect(&Token::Semicolon)?;
         let update = Box::new(self.parse_statement(false)?);
-        self.expect(&Token::LBrace)?;
-        let mut body = Vec::new();
-        while !self.check(&Token::RBrace) {
-            body.push(self.parse_statement(false)?);
-        }
-        self.expect(&Token::RBrace)?;
-        Ok(Statement::For { init, condition, update, body })
+        let body = self.parse_block()?;
+        Ok(Statement::For {
+            label,
+            init,
+            condition,
+            update,
+            body,
+        })
     }
 
-    fn parse_while_statement(&mut self) -> Result<Statement, CompilerError> {
+    fn parse_while_statement(&mut self, label: Option<String>) -> Result<Statement, CompilerError> {
         self.expect(&Token::While)?;
-        let condition = self.parse_expression(0)?;
-        self.expect(&Token::LBrace)?;
-        let mut body = Vec::new();
-        while !self.check(&Token::RBrace) {
-            body.push(self.parse_statement(false)?);
+        if self.check(&Token::Let) {
+            let (name, value) = self.parse_let_binding()?;
+            let body = self.parse_block()?;
+            return Ok(Statement::WhileLet {
+                label,
+                name,
+                value,
+                body,
+            });
         }
-        self.expect(&Token::RBrace)?;
-        Ok(Statement::While { condition, body })
+        let condition = self.parse_expression(0)?;
+        let body = self.parse_block()?;
+        Ok(Statement::While {
+            label,
+            condition,
+            body,
+        })
+    }
+
+    fn parse_unchecked_block(&mut self) -> Result<Statement, CompilerError> {
+        self.expect(&Token::Unchecked)?;
+        let body = self.parse_block()?;
+        Ok(Statement::Unchecked { body })
     }
 
     fn parse_struct_definition(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
@@ -172,6 +278,7 @@ impl<'a> Parser<'a> {
                 message: "Struct definitions must be at top level".to_string(),
             });
         }
+        let packed = self.match_token(&Token::Packed);
         self.expect(&Token::Struct)?;
         let name = if let Some(Token::Identifier) = self.peek() {
             let name = self.current_slice.clone();
@@ -183,9 +290,54 @@ impl<'a> Parser<'a> {
             });
         };
 
+        let mut drop = None;
+        let fields = self.parse_fields(Some((&name, &mut drop)))?;
+        self.hoisted.extend(drop);
+
+        Ok(Statement::Struct {
+            name,
+            fields,
+            packed,
+        })
+    }
+
+    /// `{ name: type, ... }`, the fields of a struct or error. A struct's
+    /// may be joined by a `drop` method, which is put in `drop` as a
+    /// function named `Name.drop`.
+    fn parse_fields(
+        &mut self,
+        mut drop: Option<(&str, &mut Option<Statement>)>,
+    ) -> Result<Vec<(String, Type)>, CompilerError> {
         self.expect(&Token::LBrace)?;
         let mut fields = Vec::new();
         while !self.check(&Token::RBrace) {
+            if let (Some(Token::Fn), Some((owner, method))) = (self.peek(), drop.as_mut()) {
+                self.advance();
+                let (name, params, returns) = self.parse_signature()?;
+                if name != "drop" {
+                    return Err(CompilerError::Parse {
+                        message: format!("Struct '{}' can only declare a 'drop' method, not '{}'", owner, name),
+                    });
+                }
+                if method.is_some() {
+                    return Err(CompilerError::Parse {
+                        message: format!("Struct '{}' declares 'drop' more than once", owner),
+                    });
+                }
+                let body = self.parse_block()?;
+                **method = Some(Statement::Function {
+                    name: format!("{}.drop", owner),
+                    params,
+                    returns,
+                    body,
+                    exported: false,
+                    noalloc: false,
+                });
+                if self.check(&Token::Separator) {
+                    self.advance();
+                }
+                continue;
+            }
             let field_name = if let Some(Token::Identifier) = self.peek() {
                 let field_name = self.current_slice.clone();
                 self.advance();
@@ -198,7 +350,28 @@ impl<'a> Parser<'a> {
 
             self.expect(&Token::Colon)?;
 
-            let field_type = self.parse_type()?;
+            let mut field_type = self.parse_type()?;
+
+            // `name: integer : 4` declares a 4-bit field.
+            if self.match_token(&Token::Colon) {
+                if field_type.kind != TypeKind::Integer || field_type.nullable || field_type.errorable {
+                    return Err(CompilerError::Parse {
+                        message: format!("Bit-field '{}' must have type integer", field_name),
+                    });
+                }
+                let width = if self.check(&Token::Integer) {
+                    let slice = self.current_slice.clone();
+                    self.advance();
+                    slice.parse().map_err(|_| CompilerError::Parse {
+                        message: format!("Invalid bit-field width '{}'", slice),
+                    })?
+                } else {
+                    return Err(CompilerError::Parse {
+                        message: format!("Expected bit-field width, found {:?}", self.peek()),
+                    });
+                };
+                field_type.kind = TypeKind::BitField { width };
+            }
 
             fields.push((field_name, field_type));
 
@@ -207,8 +380,7 @@ impl<'a> Parser<'a> {
             }
         }
         self.expect(&Token::RBrace)?;
-
-        Ok(Statement::Struct { name, fields })
+        Ok(fields)
     }
 
     fn parse_error_definition(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
@@ -228,12 +400,105 @@ impl<'a> Parser<'a> {
             });
         };
 
+        // `error Name;` carries only its message.
+        let fields = if self.check(&Token::LBrace) {
+            self.parse_fields(None)?
+        } else {
+            self.expect(&Token::Semicolon)?;
+            Vec::new()
+        };
+        Ok(Statement::Error { name, fields })
+    }
+
+    fn parse_type_alias(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
+        if !top_level {
+            return Err(CompilerError::Parse {
+                message: "Type aliases must be at top level".to_string(),
+            });
+        }
+        self.expect(&Token::Type)?;
+        let name = if let Some(Token::Identifier) = self.peek() {
+            let name = self.current_slice.clone();
+            self.advance();
+            name
+        } else {
+            return Err(CompilerError::Parse {
+                message: format!("Expected identifier after 'type', found {:?}", self.peek()),
+            });
+        };
+        self.expect(&Token::Is)?;
+        let ty = self.parse_type()?;
         self.expect(&Token::Semicolon)?;
-        Ok(Statement::Error { name })
+        Ok(Statement::TypeAlias { name, ty })
     }
 
-    fn parse_function_definition(&mut self) -> Result<Statement, CompilerError> {
+    fn parse_import(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
+        if !top_level {
+            return Err(CompilerError::Parse {
+                message: "Imports must be at top level".to_string(),
+            });
+        }
+        self.expect(&Token::Import)?;
+        let path = if let Some(Token::String | Token::RawString) = self.peek() {
+            self.string_literal()?
+        } else {
+            return Err(CompilerError::Parse {
+                message: format!("Expected a path after 'import', found {:?}", self.peek()),
+            });
+        };
+
+        self.expect(&Token::Semicolon)?;
+        Ok(Statement::Import { path })
+    }
+
+    fn parse_function_definition(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
+        let noalloc = self.match_token(&Token::NoAlloc);
+        let exported = self.match_token(&Token::Export);
+        if exported && !top_level {
+            return Err(CompilerError::Parse {
+                message: "Exported functions must be at top level".to_string(),
+            });
+        }
         self.expect(&Token::Fn)?;
+        let (name, params, returns) = self.parse_signature()?;
+        let body = self.parse_block()?;
+
+        Ok(Statement::Function {
+            name,
+            params,
+            returns,
+            body,
+            exported,
+            noalloc,
+        })
+    }
+
+    /// Parses `extern "module" fn name(params): returns;`.
+    fn parse_extern(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
+        if !top_level {
+            return Err(CompilerError::Parse {
+                message: "Extern functions must be declared at top level".to_string(),
+            });
+        }
+        self.expect(&Token::Extern)?;
+        let module = if let Some(Token::String | Token::RawString) = self.peek() {
+            self.string_literal()?
+        } else {
+            "env".to_string()
+        };
+        self.expect(&Token::Fn)?;
+        let (name, params, returns) = self.parse_signature()?;
+        self.expect(&Token::Semicolon)?;
+
+        Ok(Statement::Extern {
+            function: Extern { module, name },
+            params,
+            returns,
+        })
+    }
+
+    /// Parses a function's name, parameters and return type, after `fn`.
+    fn parse_signature(&mut self) -> Result<Signature, CompilerError> {
         let name = if let Some(Token::Identifier) = self.peek() {
             let name = self.current_slice.clone();
             self.advance();
@@ -243,7 +508,12 @@ impl<'a> Parser<'a> {
                 message: format!("Expected identifier after 'fn', found {:?}", self.peek()),
             });
         };
+        let (params, returns) = self.parse_parameters()?;
+        Ok((name, params, returns))
+    }
 
+    /// Parses `(params): returns`, which named functions and lambdas share.
+    pub(super) fn parse_parameters(&mut self) -> Result<Parameters, CompilerError> {
         self.expect(&Token::LParenthesis)?;
         let mut params = Vec::new();
         while !self.check(&Token::RParenthesis) {
@@ -273,14 +543,7 @@ impl<'a> Parser<'a> {
 
         let returns = self.parse_type()?;
 
-        self.expect(&Token::LBrace)?;
-        let mut body = Vec::new();
-        while !self.check(&Token::RBrace) {
-            body.push(self.parse_statement(false)?);
-        }
-        self.expect(&Token::RBrace)?;
-
-        Ok(Statement::Function { name, params, returns, body })
+        Ok((params, returns))
     }
 
     pub fn parse_statement(&mut self, top_level: bool) -> Result<Statement, CompilerError> {
@@ -291,14 +554,26 @@ impl<'a> Parser<'a> {
             Some(Token::Break) => self.parse_break_statement(),
             Some(Token::Continue) => self.parse_continue_statement(),
             Some(Token::If) => self.parse_if_statement(),
-            Some(Token::For) => self.parse_for_statement(),
-            Some(Token::While) => self.parse_while_statement(),
-            Some(Token::Struct) => self.parse_struct_definition(top_level),
+            Some(Token::For) => self.parse_for_statement(None),
+            Some(Token::While) => self.parse_while_statement(None),
+            Some(Token::Identifier) if self.peek_next() == Some(Token::Colon) => {
+                self.parse_labeled_loop()
+            }
+            Some(Token::Unchecked) => self.parse_unchecked_block(),
+            Some(Token::Struct) | Some(Token::Packed) => self.parse_struct_definition(top_level),
             Some(Token::Error) => self.parse_error_definition(top_level),
-            Some(Token::Fn) => self.parse_function_definition(),
+            Some(Token::Type) => self.parse_type_alias(top_level),
+            Some(Token::Fn) | Some(Token::Export) | Some(Token::NoAlloc) => {
+                self.parse_function_definition(top_level)
+            }
+            Some(Token::Extern) => self.parse_extern(top_level),
+            Some(Token::Import) => self.parse_import(top_level),
             Some(Token::Print) => self.parse_print_statement(),
+            Some(Token::Println) => self.parse_println_statement(),
             Some(Token::Produce) => self.parse_produce_statement(),
             Some(Token::Raise) => self.parse_raise_statement(),
+            Some(Token::Assert) => self.parse_assert_statement(),
+            Some(Token::Defer) => self.parse_defer_statement(),
             _ if !self.at_end() => self.parse_expression_statement(),
             _ => Err(CompilerError::Parse {
                 message: format!("Unexpected token in statement: {:?}", self.peek()),
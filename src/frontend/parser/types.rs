@@ -19,6 +19,22 @@ impl<'a> Parser<'a> {
             let element = Box::new(self.parse_type()?);
             self.expect(&Token::RBrace)?;
             TypeKind::List { element }
+        } else if self.match_token(&Token::LBracket) {
+            let element = Box::new(self.parse_type()?);
+            self.expect(&Token::Semicolon)?;
+            let length = if self.check(&Token::Integer) {
+                let slice = self.current_slice.clone();
+                self.advance();
+                slice.parse().map_err(|_| CompilerError::Parse {
+                    message: format!("Invalid array length '{}'", slice),
+                })?
+            } else {
+                return Err(CompilerError::Parse {
+                    message: format!("Expected array length, found {:?}", self.peek()),
+                });
+            };
+            self.expect(&Token::RBracket)?;
+            TypeKind::Array { element, length }
         } else if self.match_token(&Token::LParenthesis) {
             let mut params = Vec::new();
             while !self.check(&Token::Colon) {
@@ -37,10 +53,32 @@ impl<'a> Parser<'a> {
             });
         };
 
+        let nullable = self.match_token(&Token::Nullable);
+        let errorable = self.match_token(&Token::Errorable);
+        let mut errors = Vec::new();
+        if errorable && self.check(&Token::Identifier) {
+            errors.push(self.parse_error_name()?);
+            while self.match_token(&Token::BitwiseOr) {
+                errors.push(self.parse_error_name()?);
+            }
+        }
         Ok(Type {
             kind,
-            nullable: self.match_token(&Token::Nullable),
-            errorable: self.match_token(&Token::Errorable),
+            nullable,
+            errorable,
+            errors,
         })
     }
+
+    /// One of the errors in `integer ! ParseError | IOError`.
+    fn parse_error_name(&mut self) -> Result<String, CompilerError> {
+        if !self.check(&Token::Identifier) {
+            return Err(CompilerError::Parse {
+                message: format!("Expected an error name, found {:?}", self.peek()),
+            });
+        }
+        let name = self.current_slice.clone();
+        self.advance();
+        Ok(name)
+    }
 }
@@ -0,0 +1,294 @@
+//! The calling convention embedders use to call Star function values.
+//!
+//! Every compiled function takes three leading parameters before its own:
+//! two scratch values the caller passes as `0` (an `i32` and an `i64`) and
+//! the closure's environment pointer. Parameters and results then map to
+//! WASM types as follows: `integer` is `i64`, `float` is `f64`, `boolean` is
+//! `i32`, strings, lists and structs are `i32` pointers, and functions are
+//! `i64` [`FunctionValue`] bits. All functions sit in the module's exported
+//! [`TABLE_EXPORT`] table, so a host calls a function value by looking its
+//! index up there:
+//!
+//! ```text
+//! table[value.index](0, 0, value.env, args...)
+//! ```
+//!
+//! The callee pushes its own shadow stack frame and roots its environment
+//! and pointer arguments in it, so the host needs no setup beyond keeping
+//! the environment alive between calls with the shadow module's `pin`.
+//!
+//! # Async calls
+//!
+//! A program that uses `await` also exports the globals named below. An
+//! `await` stores what it wants in [`ASYNC_REQUEST_EXPORT`] and
+//! [`ASYNC_ARGUMENT_EXPORT`], then sets [`ASYNC_STATE_EXPORT`] to
+//! [`AsyncState::Unwinding`] and returns from every function up to `main`,
+//! saving their locals as it goes. When `main` returns in that state, the
+//! host carries out the request, stores its answer in
+//! [`ASYNC_RESULT_EXPORT`], sets the state to [`AsyncState::Rewinding`] and
+//! calls `main` again. The program then restores its locals on the way back
+//! down to the `await`, which resets the state to [`AsyncState::Running`]
+//! and evaluates to the result:
+//!
+//! ```text
+//! main(0, 0, 0)
+//! while async_state == Unwinding {
+//!     async_result = perform(async_request, async_argument)
+//!     async_state = Rewinding
+//!     main(0, 0, 0)
+//! }
+//! ```
+//!
+//! Shadow stack frames stay in place while the program is suspended, so
+//! everything its functions refer to stays alive.
+//!
+//! `exit(code)` returns to the host the same way, except that it stores the
+//! code in [`ASYNC_ARGUMENT_EXPORT`] and sets the state to
+//! [`AsyncState::Exiting`]. Every function returns without saving anything
+//! and pops its frame, so `main` returns to a program that has finished, and
+//! the host stops instead of calling it again.
+//!
+//! # Arguments
+//!
+//! Every program imports three functions from `env` alongside `print`, which
+//! a `main(args: {string})` calls at startup to copy in its arguments:
+//! [`ARG_COUNT_IMPORT`] returns how many there are, [`ARG_LENGTH_IMPORT`]
+//! the length in bytes of argument `i`, and [`ARG_COPY_IMPORT`] writes the
+//! bytes of argument `i` to the dalloc string at `ptr`, which already has
+//! that length. `main` keeps its usual signature either way, and a host
+//! with no arguments to give can return 0 from `arg_count`.
+//!
+//! # Environment variables
+//!
+//! `env(name)` works the same way through two more `env` imports:
+//! [`ENV_LENGTH_IMPORT`] returns the length in bytes of the variable named
+//! by the string at `name`, or -1 when it is unset, and [`ENV_COPY_IMPORT`]
+//! writes its bytes to the string at `ptr`. A host that keeps its
+//! environment to itself can return -1 for every name.
+//!
+//! # Extern functions
+//!
+//! Every `extern fn` a program calls becomes a function import, named after
+//! it, from the module its declaration gives, `env` by default. Its
+//! parameters and result use the types above, without the three leading
+//! parameters, so a string arrives as a pointer to its bytes in dalloc's
+//! memory, with its length in the four bytes before them. Hosts that run
+//! programs on the interpreter answer these calls in [`Io::call`].
+//!
+//! # Exported functions
+//!
+//! A function marked `export` is also exported under its own name, taking
+//! just its own parameters with the types above. `main` sets up the runtime,
+//! so a host calls exported functions once `main` has returned.
+//!
+//! # Bundled modules
+//!
+//! `star build --bundle` links the [`RUNTIME_MODULES`] into the program, so
+//! the host instantiates one module that imports only from `env`. Each
+//! memory, function and global the runtime exported is exported by the
+//! bundle under the name [`bundled_export`] gives it, such as
+//! [`DALLOC_MEMORY_EXPORT`] for the memory strings live in. The runtime
+//! keeps its own memories inside the bundle, so the bundle defines several.
+//!
+//! # Runtime version
+//!
+//! The runtime modules and the compiler share the `abi` crate, which lays
+//! out the memory both sides touch and gives it a version. `main` passes
+//! the version it was compiled against to each runtime's init, which traps
+//! unless it was built against the same major version and one at least as
+//! new, so a runtime left over from another build fails on the first call
+//! instead of corrupting memory. Rebuild the runtime crates after updating
+//! the compiler.
+//!
+//! # GC modules
+//!
+//! `star build --wasm-gc` needs no runtime modules either: its values are
+//! structs and arrays the engine collects, so the host must enable the GC
+//! proposal. Its one memory exists only to pass the string `print` prints,
+//! and is exported as [`DALLOC_MEMORY_EXPORT`] too, so hosts read strings
+//! from it as from a bundle.
+
+/// Name of the exported table holding every compiled function.
+pub const TABLE_EXPORT: &str = "table";
+
+/// The runtime modules a program imports, each of which may import those
+/// before it.
+pub const RUNTIME_MODULES: [&str; 3] = abi::RUNTIME_MODULES;
+
+/// Name of dalloc's memory in a bundled program.
+pub const DALLOC_MEMORY_EXPORT: &str = "dalloc.memory";
+
+/// The name a bundled program exports the runtime `module`'s export `name`
+/// under.
+pub fn bundled_export(module: &str, name: &str) -> String {
+    format!("{}.{}", module, name)
+}
+
+/// Names of the `env` imports that hand `main` its arguments, as
+/// `arg_count() -> i32`, `arg_length(i: i32) -> i32` and
+/// `arg_copy(i: i32, ptr: i32)`.
+pub const ARG_COUNT_IMPORT: &str = "arg_count";
+pub const ARG_LENGTH_IMPORT: &str = "arg_length";
+pub const ARG_COPY_IMPORT: &str = "arg_copy";
+
+/// Names of the `env` imports behind `env(name)`, as
+/// `env_length(name: i32) -> i32` and `env_copy(name: i32, ptr: i32)`.
+pub const ENV_LENGTH_IMPORT: &str = "env_length";
+pub const ENV_COPY_IMPORT: &str = "env_copy";
+
+/// A Star function value: where the function sits in [`TABLE_EXPORT`], and
+/// the struct holding its captured variables in alloc memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionValue {
+    pub index: u32,
+    pub env: u32,
+}
+
+impl FunctionValue {
+    /// Splits the `i64` a function value travels as: the environment
+    /// pointer in the low half and the table index in the high half.
+    pub fn from_bits(bits: i64) -> Self {
+        FunctionValue {
+            index: (bits >> 32) as u32,
+            env: bits as u32,
+        }
+    }
+
+    pub fn to_bits(self) -> i64 {
+        ((self.index as i64) << 32) | self.env as i64
+    }
+}
+
+/// Names of the exported globals of a program that awaits. The state and
+/// request are `i32`s, the argument and result `i64`s.
+pub const ASYNC_STATE_EXPORT: &str = "async_state";
+pub const ASYNC_REQUEST_EXPORT: &str = "async_request";
+pub const ASYNC_ARGUMENT_EXPORT: &str = "async_argument";
+pub const ASYNC_RESULT_EXPORT: &str = "async_result";
+
+/// Values of [`ASYNC_STATE_EXPORT`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncState {
+    Running = 0,
+    Unwinding = 1,
+    Rewinding = 2,
+    /// Set by `exit`, which leaves its code in [`ASYNC_ARGUMENT_EXPORT`].
+    Exiting = 3,
+}
+
+impl AsyncState {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(AsyncState::Running),
+            1 => Some(AsyncState::Unwinding),
+            2 => Some(AsyncState::Rewinding),
+            3 => Some(AsyncState::Exiting),
+            _ => None,
+        }
+    }
+}
+
+/// Values of [`ASYNC_REQUEST_EXPORT`]: the async calls a program can await.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncRequest {
+    /// `sleep(ms)`. The argument is the number of milliseconds to wait, and
+    /// the result the number that actually passed.
+    Sleep = 1,
+    /// `fetch(url)`. The argument is a dalloc pointer to the URL string, and
+    /// the result a pointer to the response body, such as one made with the
+    /// shadow module's `host_alloc_string`.
+    Fetch = 2,
+}
+
+impl AsyncRequest {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            1 => Some(AsyncRequest::Sleep),
+            2 => Some(AsyncRequest::Fetch),
+            _ => None,
+        }
+    }
+}
+
+/// An object in the heap snapshot the shadow module's `heap_snapshot` writes
+/// to shadow memory, for hosts profiling a program's memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapObject {
+    /// A struct's type id, or a list or string's dalloc block type.
+    pub ty: u32,
+    /// Bytes the object takes, its header included.
+    pub bytes: u32,
+    /// Whether the object is a list or string rather than a struct.
+    pub dalloc: bool,
+    /// Whether the roots still reach the object, or it's pinned. The rest
+    /// are garbage the next collection frees.
+    pub reachable: bool,
+}
+
+impl HeapObject {
+    /// The objects in the snapshot `heap_snapshot` returned `address` of,
+    /// read out of shadow `memory`.
+    pub fn read_snapshot(memory: &[u8], address: u32) -> Vec<HeapObject> {
+        let word = |at: usize| u32::from_le_bytes(memory[at..at + 4].try_into().unwrap());
+        let address = address as usize;
+        (0..word(address) as usize)
+            .map(|i| {
+                let record = address + 4 + i * abi::snapshot::RECORD_SIZE as usize;
+                let flags = word(record + 8);
+                HeapObject {
+                    ty: word(record),
+                    bytes: word(record + 4),
+                    dalloc: flags & abi::snapshot::DALLOC != 0,
+                    reachable: flags & abi::snapshot::MARKED != 0,
+                }
+            })
+            .collect()
+    }
+}
+
+/// What a program run by [`crate::execute`] asks of its host. Awaits are
+/// answered on the spot, so `sleep` and `fetch` block until they have a
+/// result.
+pub trait Io {
+    /// Prints one line, without its newline.
+    fn print(&mut self, line: &str);
+
+    /// The arguments a `main(args: {string})` receives.
+    fn args(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// The value `env(name)` returns. Programs see no variables unless the
+    /// host hands them out.
+    fn env(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Waits `ms` milliseconds and returns how many actually passed.
+    fn sleep(&mut self, ms: i64) -> i64 {
+        let started = std::time::Instant::now();
+        std::thread::sleep(std::time::Duration::from_millis(ms.max(0) as u64));
+        started.elapsed().as_millis() as i64
+    }
+
+    /// Fetches `url` and returns the response body. An error stops the
+    /// program with a runtime error carrying the message.
+    fn fetch(&mut self, url: &str) -> Result<String, String> {
+        Err(format!("cannot fetch {}: this host has no network access", url))
+    }
+
+    /// Runs the `extern fn` imported as `name` from `module`. An error stops
+    /// the program with a runtime error carrying the message.
+    fn call(&mut self, module: &str, name: &str, _args: &[HostValue]) -> Result<HostValue, String> {
+        Err(format!("this host does not provide {}.{}", module, name))
+    }
+}
+
+/// A value passed to or returned from an `extern fn` on the interpreter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+}
@@ -1,29 +1,108 @@
 pub mod ast;
 pub mod error;
+pub mod host;
 mod frontend;
 mod analysis;
 mod transforms;
 mod backend;
+mod stdlib;
 
-use backend::Codegen;
-use error::CompilerError;
-use transforms::{Flattener, Wrapper};
+use backend::{Codegen, Interpreter};
+use error::{CompilerError, Diagnostic};
+use transforms::{
+    call_directly, eliminate_dead_code, find_unrooted, inline_calls, make_resumable, Flattener,
+    Wrapper,
+};
 use backend::IRGenerator;
-use analysis::LocalsIndexer;
+use analysis::{box_mutable_captures, LocalsIndexer};
 use frontend::Parser;
+use std::path::Path;
 use analysis::TypeChecker;
 
+pub use analysis::{CallGraph, CallNode, LanguageOptions};
+pub use backend::{AllocPolicy, CodegenOptions, FieldLayout, FrameUsage, Frames, Glue, Layout, StructLayout};
+pub use frontend::ReadModule;
+
 /// Compiles Star source code to WASM bytes.
-/// Returns Ok(wasm_bytes) on success, Err(CompilerError) on failure.
-pub fn compile(source: &str) -> Result<Vec<u8>, CompilerError> {
+/// Returns Ok(wasm_bytes) on success, Err(diagnostics) on failure. Parse and
+/// type errors are collected across the whole file; later passes stop at the
+/// first error.
+pub fn compile(source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    compile_with(source, LanguageOptions::default())
+}
+
+/// Like `compile`, with language options that change which programs are
+/// accepted.
+pub fn compile_with(source: &str, options: LanguageOptions) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let program = parse(source)?;
+    build(&program, options)
+}
+
+/// Compiles the Star file at `entry`, together with every file it imports,
+/// into one WASM module.
+pub fn compile_file(entry: &Path) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let program = parse_file(entry)?;
+    build(&program, LanguageOptions::default())
+}
+
+fn build(program: &ast::Program, options: LanguageOptions) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let typed_program = check_with(program, options)?;
+    let ir_program = lower(&typed_program).map_err(|e| vec![e])?;
+    codegen(&ir_program).map_err(|e| vec![e])
+}
+
+/// Parses source code into an AST, without the prelude. The source can't
+/// import other files, having no path to resolve them from.
+pub fn parse(source: &str) -> Result<ast::Program, Vec<Diagnostic>> {
     let mut parser = Parser::new(source);
-    let program = parser.parse_program()?;
+    parser.parse_program()
+}
 
-    let mut type_checker = TypeChecker::new();
-    let typed_program = type_checker
-        .check_program(&program)
-        .map_err(|e| CompilerError::Type { message: e.message })?;
+/// Parses the file at `entry` and the files it imports, with paths in
+/// imports relative to the importing file.
+pub fn parse_file(entry: &Path) -> Result<ast::Program, Vec<Diagnostic>> {
+    parse_modules(entry, &mut |path| std::fs::read_to_string(path))
+}
 
+/// Like `parse_file`, reading each module with `read` instead of from the
+/// filesystem.
+pub fn parse_modules(entry: &Path, read: &mut ReadModule) -> Result<ast::Program, Vec<Diagnostic>> {
+    frontend::load_program(entry, read)
+}
+
+/// Type checks a parsed program against the prelude.
+pub fn check(program: &ast::Program) -> Result<ast::TypedProgram, Vec<Diagnostic>> {
+    check_with(program, LanguageOptions::default())
+}
+
+/// Type checks a parsed program against the prelude under `options`.
+pub fn check_with(
+    program: &ast::Program,
+    options: LanguageOptions,
+) -> Result<ast::TypedProgram, Vec<Diagnostic>> {
+    let program = stdlib::with_prelude(program);
+
+    let mut type_checker = TypeChecker::with_options(options);
+    type_checker.check_program(&program).map_err(|errors| {
+        errors
+            .into_iter()
+            .map(|e| CompilerError::Type { message: e.message })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Which functions of a type checked program call which, with estimates of
+/// the shadow stack their calls take.
+pub fn call_graph(typed_program: &ast::TypedProgram) -> CallGraph {
+    CallGraph::new(typed_program)
+}
+
+/// Moves the captured variables closures assign into cells, runs local
+/// analysis, flattening and wrapping, then lowers to IR, inlines
+/// small functions, calls known functions directly, makes the functions an
+/// `await` can suspend resumable and finds the locals that need no rooting.
+pub fn lower(typed_program: &ast::TypedProgram) -> Result<ast::IRProgram, CompilerError> {
+    let typed_program = box_mutable_captures(typed_program);
     let mut indexer = LocalsIndexer::new();
     let analyzed_program = indexer.analyze_program(&typed_program)?;
 
@@ -34,16 +113,109 @@ pub fn compile(source: &str) -> Result<Vec<u8>, CompilerError> {
     let wrapped_program = wrapper.wrap_program(flattened_program)?;
 
     let mut ir_generator = IRGenerator::new();
-    let ir_program = ir_generator.generate(&wrapped_program)?;
+    let mut ir_program = ir_generator.generate(&wrapped_program)?;
+    inline_calls(&mut ir_program);
+    call_directly(&mut ir_program);
+    eliminate_dead_code(&mut ir_program);
+    make_resumable(&mut ir_program);
+    find_unrooted(&mut ir_program);
+    Ok(ir_program)
+}
 
+/// Where the fields of a lowered program's structs live, and what the
+/// structs cost to allocate.
+pub fn layout(ir_program: &ast::IRProgram) -> Layout {
+    Layout::new(ir_program)
+}
+
+/// The wasm locals and shadow stack frame each function of a lowered
+/// program takes.
+pub fn frames(ir_program: &ast::IRProgram) -> Frames {
+    Frames::new(ir_program)
+}
+
+/// Encodes an IR program as a WASM module.
+pub fn codegen(ir_program: &ast::IRProgram) -> Result<Vec<u8>, CompilerError> {
     let mut codegen = Codegen::new();
-    codegen.compile(&ir_program)
+    codegen.compile(ir_program)
+}
+
+/// Like `codegen`, with options such as memory sanitizing.
+pub fn codegen_with(
+    ir_program: &ast::IRProgram,
+    options: CodegenOptions,
+) -> Result<Vec<u8>, CompilerError> {
+    if options.wasm_gc {
+        if options.sanitize_memory {
+            return Err(CompilerError::Codegen {
+                message: "--sanitize memory can't be used with --wasm-gc, which has no heaps to check"
+                    .to_string(),
+            });
+        }
+        if options.alloc_policy != AllocPolicy::default() {
+            return Err(CompilerError::Codegen {
+                message: "--alloc-policy can't be used with --wasm-gc, which has no heaps to allocate from"
+                    .to_string(),
+            });
+        }
+        if options.gc_stress {
+            return Err(CompilerError::Codegen {
+                message: "--gc-stress can't be used with --wasm-gc, whose collections the engine runs"
+                    .to_string(),
+            });
+        }
+        return backend::GcCodegen::new(ir_program).compile(ir_program, options);
+    }
+    let mut codegen = Codegen::with_options(options);
+    codegen.compile(ir_program)
+}
+
+/// Links a compiled program and the runtime modules it imports, by name in
+/// the order of [`host::RUNTIME_MODULES`], into one module that runs
+/// without them.
+pub fn bundle(program: &[u8], runtime: &[(&str, &[u8])]) -> Result<Vec<u8>, CompilerError> {
+    backend::bundle(program, runtime)
+}
+
+/// A JavaScript loader for a program compiled to the file `wasm`, with
+/// TypeScript declarations for its exported functions and the externs it
+/// imports. A `bundled` program loads without the runtime modules.
+pub fn js_glue(
+    typed_program: &ast::TypedProgram,
+    ir_program: &ast::IRProgram,
+    wasm: &str,
+    bundled: bool,
+) -> Glue {
+    Glue::new(typed_program, ir_program, wasm, bundled)
+}
+
+/// Runs Star source code on the IR interpreter instead of compiling it to
+/// WASM, for hosts that can't instantiate WASM modules. `io` prints and
+/// answers awaits. Returns what `main` returned, or the diagnostics that
+/// stopped the program, a runtime error for anything compiled code would
+/// trap on.
+pub fn execute(source: &str, io: &mut dyn host::Io) -> Result<i64, Vec<Diagnostic>> {
+    let program = parse(source)?;
+    run(&program, io)
+}
+
+/// Like `execute`, for the file at `entry` and the files it imports.
+pub fn execute_file(entry: &Path, io: &mut dyn host::Io) -> Result<i64, Vec<Diagnostic>> {
+    let program = parse_file(entry)?;
+    run(&program, io)
+}
+
+fn run(program: &ast::Program, io: &mut dyn host::Io) -> Result<i64, Vec<Diagnostic>> {
+    let typed_program = check(program)?;
+    let ir_program = lower(&typed_program).map_err(|e| vec![e])?;
+    Interpreter::new(&ir_program, io).run().map_err(|e| vec![e])
 }
 
 // WASM exports for browser
 #[cfg(target_arch = "wasm32")]
 mod wasm_exports {
     use super::compile;
+    use super::error::format_diagnostics;
 
     static mut RESULT_BUFFER: Vec<u8> = Vec::new();
     static mut ERROR_BUFFER: String = String::new();
@@ -76,8 +248,8 @@ mod wasm_exports {
                 RESULT_BUFFER = bytes;
                 1
             },
-            Err(e) => unsafe {
-                ERROR_BUFFER = e.to_string();
+            Err(diagnostics) => unsafe {
+                ERROR_BUFFER = format_diagnostics(&diagnostics);
                 0
             },
         }
@@ -1,42 +1,349 @@
-use star::compile;
+use star::error::Diagnostic;
+use star::ast::{IRProgram, TypedProgram};
+use std::path::{Path, PathBuf};
 use std::process;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-fn main() {
-    let source = r#"
-fn main(): integer {
-    fn add(x: integer, y: integer): integer {
-        return x + y;
+const USAGE: &str = "Usage: star <command> [options] <input.star>
+
+Commands:
+  build    Compile a program to WebAssembly
+  check    Parse and type check a program without generating code
+
+Options:
+  -o, --output <file>    Write output to <file> (default: <input>.wasm)
+  --emit <kind>          Choose what to write: ast, ir, callgraph, layout,
+                         frames or wasm (default: wasm)
+  --implicit-widening    Convert integers to floats where floats are expected
+  --release              Leave assert statements out of the program
+  --sanitize <memory>    Trap on loads and stores outside live heap blocks
+  --alloc-policy <kind>  How lists and strings pick a free block: first-fit,
+                         best-fit or next-fit (default: first-fit)
+  --gc-stress            Collect before every allocation, to catch values
+                         the collector can't see
+  --max-locals <n>       Fail when a function needs more than <n> locals
+  --max-frame <n>        Fail when a function's shadow stack frame takes more
+                         than <n> slots
+  --bundle               Link the runtime into the module, so it runs alone
+  --js                   Also write a JavaScript loader and its .d.ts next to
+                         the wasm
+  --wasm-gc              Target the WebAssembly GC proposal, so the program
+                         runs without the runtime modules
+  --verbose              Print how long each pass took
+  -h, --help             Print this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Build,
+    Check,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Ast,
+    Ir,
+    CallGraph,
+    Layout,
+    Frames,
+    Wasm,
+}
+
+struct Options {
+    command: Command,
+    input: PathBuf,
+    output: Option<PathBuf>,
+    emit: Emit,
+    language: star::LanguageOptions,
+    codegen: star::CodegenOptions,
+    bundle: bool,
+    js: bool,
+    verbose: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    // `--flag=value` is the same as `--flag value`.
+    let args: Vec<&str> = args
+        .iter()
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => vec![flag, value],
+            _ => vec![arg.as_str()],
+        })
+        .collect();
+    let mut args = args.into_iter();
+
+    let command = match args.next() {
+        Some("build") => Command::Build,
+        Some("check") => Command::Check,
+        Some(other) => return Err(format!("Unknown command '{}'", other)),
+        None => return Err("Missing command".to_string()),
+    };
+
+    let mut input = None;
+    let mut output = None;
+    let mut emit = Emit::Wasm;
+    let mut language = star::LanguageOptions::default();
+    let mut codegen = star::CodegenOptions::default();
+    let mut bundle = false;
+    let mut js = false;
+    let mut verbose = false;
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "-o" | "--output" => {
+                let path = args.next().ok_or("Expected a path after -o")?;
+                output = Some(PathBuf::from(path));
+            }
+            "--emit" => {
+                emit = match args.next() {
+                    Some("ast") => Emit::Ast,
+                    Some("ir") => Emit::Ir,
+                    Some("callgraph") => Emit::CallGraph,
+                    Some("layout") => Emit::Layout,
+                    Some("frames") => Emit::Frames,
+                    Some("wasm") => Emit::Wasm,
+                    Some(other) => return Err(format!("Unknown emit kind '{}'", other)),
+                    None => {
+                        return Err(
+                            "Expected ast, ir, callgraph, layout, frames or wasm after --emit"
+                                .to_string(),
+                        )
+                    }
+                };
+            }
+            "--implicit-widening" => language.implicit_widening = true,
+            "--release" => language.release = true,
+            "--sanitize" => match args.next() {
+                Some("memory") => codegen.sanitize_memory = true,
+                Some(other) => return Err(format!("Unknown sanitizer '{}'", other)),
+                None => return Err("Expected memory after --sanitize".to_string()),
+            },
+            "--alloc-policy" => {
+                codegen.alloc_policy = match args.next() {
+                    Some("first-fit") => star::AllocPolicy::FirstFit,
+                    Some("best-fit") => star::AllocPolicy::BestFit,
+                    Some("next-fit") => star::AllocPolicy::NextFit,
+                    Some(other) => return Err(format!("Unknown allocation policy '{}'", other)),
+                    None => {
+                        return Err(
+                            "Expected first-fit, best-fit or next-fit after --alloc-policy"
+                                .to_string(),
+                        )
+                    }
+                };
+            }
+            "--gc-stress" => codegen.gc_stress = true,
+            "--max-locals" => codegen.max_locals = Some(limit(args.next(), "--max-locals")?),
+            "--max-frame" => codegen.max_frame_slots = Some(limit(args.next(), "--max-frame")?),
+            "--bundle" => bundle = true,
+            "--js" => js = true,
+            "--wasm-gc" => codegen.wasm_gc = true,
+            "--verbose" => verbose = true,
+            flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+            path => {
+                if input.is_some() {
+                    return Err(format!("Unexpected argument '{}'", path));
+                }
+                input = Some(PathBuf::from(path));
+            }
+        }
     }
 
-    fn mult(x: integer, y: integer): integer {
-        return x * y;
+    if codegen.wasm_gc && (bundle || js) {
+        return Err("--wasm-gc can't be used with --bundle or --js".to_string());
     }
-    
-    print $(10 + add(add(1, mult(3, 4)), 2));
-    return 0;
+
+    Ok(Options {
+        command,
+        input: input.ok_or("Missing input file")?,
+        output,
+        emit,
+        language,
+        codegen,
+        bundle,
+        js,
+        verbose,
+    })
 }
 
+/// The number a limit flag is followed by.
+fn limit(value: Option<&str>, flag: &str) -> Result<u32, String> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("Expected a number after {}", flag))
+}
 
-    "#;
+/// Runs a pass and records how long it took.
+fn timed<T>(
+    timings: &mut Vec<(&'static str, Duration)>,
+    name: &'static str,
+    pass: impl FnOnce() -> T,
+) -> T {
+    let start = Instant::now();
+    let result = pass();
+    timings.push((name, start.elapsed()));
+    result
+}
 
-    println!("Compiling...\n");
+fn run(options: &Options) -> Result<(), Vec<Diagnostic>> {
+    let mut timings = Vec::new();
+    let result = compile_with_timings(options, &mut timings);
 
-    let start = Instant::now();
-    match compile(source) {
-        Ok(wasm_bytes) => {
-            let duration = start.elapsed();
-            println!("WASM bytes: {} bytes", wasm_bytes.len());
-            println!("Compilation took: {:?}\n", duration);
-
-            std::fs::write("output.wasm", &wasm_bytes).expect("Failed to write output.wasm");
-            println!("Written to output.wasm");
+    if options.verbose {
+        for (name, duration) in &timings {
+            eprintln!("{:>10}: {:?}", name, duration);
+        }
+    }
+
+    result
+}
+
+fn compile_with_timings(
+    options: &Options,
+    timings: &mut Vec<(&'static str, Duration)>,
+) -> Result<(), Vec<Diagnostic>> {
+    // Reads the files the input imports too.
+    let program = timed(timings, "parse", || star::parse_file(&options.input))?;
+    if options.emit == Emit::Ast {
+        write_output(options, format!("{:#?}\n", program).as_bytes(), false);
+        return Ok(());
+    }
+
+    let typed_program = timed(timings, "typecheck", || {
+        star::check_with(&program, options.language)
+    })?;
+    if options.command == Command::Check {
+        return Ok(());
+    }
+    if options.emit == Emit::CallGraph {
+        // DOT, unless written to a .json file.
+        let graph = star::call_graph(&typed_program);
+        let json = options
+            .output
+            .as_ref()
+            .is_some_and(|path| path.extension().is_some_and(|ext| ext == "json"));
+        let text = match json {
+            true => graph.to_json(),
+            false => graph.to_dot(),
+        };
+        write_output(options, text.as_bytes(), false);
+        return Ok(());
+    }
+
+    let ir_program =
+        timed(timings, "lower", || star::lower(&typed_program)).map_err(|e| vec![e])?;
+    if options.emit == Emit::Ir {
+        write_output(options, format!("{:#?}\n", ir_program).as_bytes(), false);
+        return Ok(());
+    }
+    if options.emit == Emit::Layout {
+        let layout = star::layout(&ir_program);
+        write_output(options, layout.report().as_bytes(), false);
+        return Ok(());
+    }
+    if options.emit == Emit::Frames {
+        let frames = star::frames(&ir_program);
+        write_output(options, frames.report().as_bytes(), false);
+        return Ok(());
+    }
+
+    let wasm_bytes = timed(timings, "codegen", || {
+        star::codegen_with(&ir_program, options.codegen)
+    })
+    .map_err(|e| vec![e])?;
+    if options.bundle {
+        let bundled =
+            timed(timings, "bundle", || bundle_runtime(&wasm_bytes)).map_err(|e| vec![e])?;
+        write_output(options, &bundled, true);
+    } else {
+        write_output(options, &wasm_bytes, true);
+    }
+    if options.js {
+        write_glue(options, &typed_program, &ir_program);
+    }
+    Ok(())
+}
+
+/// Links the runtime, as built in its crates' directories, into the program.
+fn bundle_runtime(program: &[u8]) -> Result<Vec<u8>, star::error::CompilerError> {
+    let mut runtime = vec![];
+    for name in star::host::RUNTIME_MODULES {
+        let path = format!("{0}/target/wasm32-unknown-unknown/release/{0}.wasm", name);
+        let bytes = std::fs::read(&path).map_err(|e| star::error::CompilerError::Module {
+            message: format!(
+                "Cannot read {}: {}. Build it first: cd {} && cargo build --target wasm32-unknown-unknown --release",
+                path, e, name
+            ),
+        })?;
+        runtime.push((name, bytes));
+    }
+    let runtime: Vec<(&str, &[u8])> = runtime
+        .iter()
+        .map(|(name, bytes)| (*name, &bytes[..]))
+        .collect();
+    star::bundle(program, &runtime)
+}
+
+/// Where wasm output goes: `-o` if given, next to the input file otherwise.
+fn wasm_path(options: &Options) -> PathBuf {
+    options
+        .output
+        .clone()
+        .unwrap_or_else(|| options.input.with_extension("wasm"))
+}
+
+/// Writes the loader for the wasm output next to it, as `.js` and `.d.ts`.
+fn write_glue(options: &Options, typed_program: &TypedProgram, ir_program: &IRProgram) {
+    let wasm = wasm_path(options);
+    let name = wasm.file_name().unwrap_or_default().to_string_lossy();
+    let glue = star::js_glue(typed_program, ir_program, &name, options.bundle);
+    write_file(options, &wasm.with_extension("js"), glue.js.as_bytes());
+    write_file(options, &wasm.with_extension("d.ts"), glue.dts.as_bytes());
+}
+
+/// Writes to `-o` if given. Text goes to stdout otherwise, and wasm next to
+/// the input file.
+fn write_output(options: &Options, bytes: &[u8], is_wasm: bool) {
+    let path = match (&options.output, is_wasm) {
+        (Some(path), _) => path.clone(),
+        (None, true) => wasm_path(options),
+        (None, false) => {
+            print!("{}", String::from_utf8_lossy(bytes));
+            return;
+        }
+    };
+    write_file(options, &path, bytes);
+}
+
+fn write_file(options: &Options, path: &Path, bytes: &[u8]) {
+    if let Err(e) = std::fs::write(path, bytes) {
+        eprintln!("Error: Failed to write {}: {}", path.display(), e);
+        process::exit(1);
+    }
+    if options.verbose {
+        eprintln!("Written to {}", path.display());
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.is_empty() || args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("Error: {}\n\n{}", message, USAGE);
+            process::exit(2);
         }
-        Err(e) => {
-            let duration = start.elapsed();
-            eprintln!("Error: {}", e);
-            eprintln!("Compilation took: {:?}", duration);
-            process::exit(1);
+    };
+
+    if let Err(diagnostics) = run(&options) {
+        for diagnostic in &diagnostics {
+            eprintln!("Error: {}", diagnostic);
         }
+        process::exit(1);
     }
 }
@@ -0,0 +1,466 @@
+//! Star source for the helpers behind `to_json` and `from_json`.
+//!
+//! Every struct that is written or read gets a helper of its own, and so
+//! does every type passed to `to_json` or read by `from_json`. Lists and
+//! nullables are handled inline by whichever helper meets them. Readers
+//! call into the runtime's JSON cursor, which remembers the first failure,
+//! so nothing is checked until the whole document has been read.
+
+use crate::ast::{Type, TypeKind};
+use std::collections::{HashMap, HashSet};
+
+/// Struct fields and ids, as the type checker keeps them.
+pub type Structs = HashMap<String, (Vec<(String, Type)>, i32)>;
+
+pub fn writer_name(index: usize) -> String {
+    format!("__json_to_{}", index)
+}
+
+pub fn reader_name(index: usize) -> String {
+    format!("__json_from_{}", index)
+}
+
+fn encoder_name(name: &str) -> String {
+    format!("__json_encode_{}", name)
+}
+
+fn decoder_name(name: &str) -> String {
+    format!("__json_decode_{}", name)
+}
+
+/// Why values of `ty` have no JSON form, if they don't.
+pub fn unsupported(ty: &Type, structs: &Structs) -> Option<String> {
+    unsupported_in(ty, structs, &mut HashSet::new())
+}
+
+fn unsupported_in(ty: &Type, structs: &Structs, seen: &mut HashSet<String>) -> Option<String> {
+    if ty.errorable {
+        return Some("errorable values have no JSON form".to_string());
+    }
+    match &ty.kind {
+        TypeKind::Integer
+        | TypeKind::Float
+        | TypeKind::Boolean
+        | TypeKind::String
+        | TypeKind::BitField { .. } => None,
+        TypeKind::List { element } | TypeKind::Array { element, .. } => {
+            unsupported_in(element, structs, seen)
+        }
+        TypeKind::Struct { name } => {
+            if !seen.insert(name.clone()) {
+                return None;
+            }
+            let fields = &structs.get(name)?.0;
+            if endless(name, structs, &mut Vec::new()) {
+                return Some(format!("'{}' contains itself", name));
+            }
+            fields
+                .iter()
+                .find_map(|(_, field)| unsupported_in(field, structs, seen))
+        }
+        TypeKind::Function { .. } => Some("functions have no JSON form".to_string()),
+        TypeKind::Error { .. } | TypeKind::Null | TypeKind::Unknown => {
+            Some("the type is not known".to_string())
+        }
+    }
+}
+
+/// Whether a `name` always holds another `name`, so no value of it can be
+/// built.
+fn endless(name: &str, structs: &Structs, path: &mut Vec<String>) -> bool {
+    if path.iter().any(|seen| seen == name) {
+        return true;
+    }
+    let Some((fields, _)) = structs.get(name) else {
+        return false;
+    };
+    path.push(name.to_string());
+    let endless = fields.iter().any(|(_, field)| match &field.kind {
+        TypeKind::Struct { name } if !field.nullable => endless(name, structs, path),
+        TypeKind::Array { element, .. } => match &element.kind {
+            TypeKind::Struct { name } if !element.nullable => endless(name, structs, path),
+            _ => false,
+        },
+        _ => false,
+    });
+    path.pop();
+    endless
+}
+
+/// The structs whose helpers a helper for `ty` calls.
+pub fn structs_in(ty: &Type, found: &mut Vec<String>) {
+    match &ty.kind {
+        TypeKind::Struct { name } if !found.contains(name) => found.push(name.clone()),
+        TypeKind::List { element } | TypeKind::Array { element, .. } => structs_in(element, found),
+        _ => {}
+    }
+}
+
+/// Builds the source of one helper.
+pub(super) struct Writer<'a> {
+    structs: &'a Structs,
+    pub(super) source: String,
+    indent: usize,
+    next_local: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(super) fn new(structs: &'a Structs) -> Self {
+        Writer {
+            structs,
+            source: String::new(),
+            indent: 0,
+            next_local: 0,
+        }
+    }
+
+    pub(super) fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.source.push_str("    ");
+        }
+        self.source.push_str(text);
+        self.source.push('\n');
+    }
+
+    pub(super) fn open(&mut self, text: &str) {
+        self.line(&format!("{} {{", text));
+        self.indent += 1;
+    }
+
+    pub(super) fn close(&mut self) {
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// Closes the `if` branch being written and opens its `else`.
+    pub(super) fn otherwise(&mut self, text: &str) {
+        self.indent -= 1;
+        self.line(&format!("}} {} {{", text));
+        self.indent += 1;
+    }
+
+    pub(super) fn local(&mut self, prefix: &str) -> String {
+        self.next_local += 1;
+        format!("{}{}", prefix, self.next_local)
+    }
+
+    pub(super) fn fields(&self, name: &str) -> Vec<(String, Type)> {
+        self.structs
+            .get(name)
+            .map(|(fields, _)| fields.clone())
+            .unwrap_or_default()
+    }
+
+    /// Appends `value`, an expression that is free to read more than once,
+    /// to the builder `b`.
+    fn encode(&mut self, ty: &Type, value: &str) {
+        match &ty.kind {
+            // `$` already writes null, numbers and booleans as JSON does.
+            TypeKind::Integer | TypeKind::Float | TypeKind::Boolean | TypeKind::BitField { .. } => {
+                self.line(&format!("b.append($({}));", value));
+            }
+            _ if ty.nullable => {
+                let present = self.local("present");
+                self.line(&format!("let {}: {} = {};", present, ty, value));
+                self.open(&format!("if {}", present));
+                self.encode(&non_null(ty), &present);
+                self.otherwise("else");
+                self.line("b.append(\"null\");");
+                self.close();
+            }
+            TypeKind::String => self.line(&format!("b.append(json_quote({}));", value)),
+            TypeKind::Struct { name } => {
+                self.line(&format!("{}({}, b);", encoder_name(name), value));
+            }
+            TypeKind::List { element } => {
+                let list = self.local("list");
+                self.line(&format!("let {}: {} = {};", list, ty, value));
+                self.encode_items(element, &format!("#{}", list), &list);
+            }
+            TypeKind::Array { element, length } => {
+                self.encode_items(element, &length.to_string(), value);
+            }
+            _ => unreachable!("checked by `unsupported`"),
+        }
+    }
+
+    fn encode_items(&mut self, element: &Type, count: &str, items: &str) {
+        let index = self.local("index");
+        self.line("b.append(\"[\");");
+        self.line(&format!("let {}: integer = 0;", index));
+        self.open(&format!("while {} < {}", index, count));
+        self.open(&format!("if {} > 0", index));
+        self.line("b.append(\",\");");
+        self.close();
+        self.encode(element, &format!("{}[{}]", items, index));
+        self.line(&format!("{} = {} + 1;", index, index));
+        self.close();
+        self.line("b.append(\"]\");");
+    }
+
+    /// Reads a `ty` from the document `s` into the local `target`.
+    fn decode(&mut self, ty: &Type, target: &str) {
+        if let Some(read) = read_expr(ty) {
+            self.line(&format!("{} = {};", target, read));
+            return;
+        }
+        match &ty.kind {
+            _ if ty.nullable => {
+                let value_ty = non_null(ty);
+                let value = self.local("value");
+                self.open("if not json_null(s)");
+                self.declare(&value_ty, &value);
+                self.line(&format!("{} = {};", target, value));
+                self.close();
+            }
+            TypeKind::List { element } => {
+                let more = self.local("more");
+                let item = self.local("item");
+                self.open("if json_expect(s, 91)");
+                self.open("if not json_eat(s, 93)");
+                self.line(&format!("let {}: boolean = true;", more));
+                self.open(&format!("while {}", more));
+                self.declare(element, &item);
+                self.line(&format!("{}.push({});", target, item));
+                self.line(&format!("{} = json_eat(s, 44);", more));
+                self.close();
+                self.line("json_expect(s, 93);");
+                self.close();
+                self.close();
+            }
+            _ => unreachable!("checked by `unsupported`"),
+        }
+    }
+
+    /// Declares `name` and reads a `ty` into it.
+    fn declare(&mut self, ty: &Type, name: &str) {
+        match read_expr(ty) {
+            Some(read) => self.line(&format!("let {}: {} = {};", name, ty, read)),
+            None => {
+                let empty = if ty.nullable { "null" } else { "{}" };
+                self.line(&format!("let {}: {} = {};", name, ty, empty));
+                self.decode(ty, name);
+            }
+        }
+    }
+
+    /// An expression of type `ty`, for a struct field that never showed up
+    /// in a document that already failed.
+    fn zero(&self, ty: &Type) -> String {
+        if ty.nullable {
+            return "null".to_string();
+        }
+        match &ty.kind {
+            TypeKind::Integer | TypeKind::BitField { .. } => "0".to_string(),
+            TypeKind::Float => "0.0".to_string(),
+            TypeKind::Boolean => "false".to_string(),
+            TypeKind::String => "\"\"".to_string(),
+            TypeKind::List { element } => format!("repeat({}, 0)", self.zero(element)),
+            TypeKind::Array { element, length } => {
+                let zero = self.zero(element);
+                format!("[{}]", vec![zero; *length as usize].join(", "))
+            }
+            TypeKind::Struct { name } => {
+                let fields: Vec<String> = self
+                    .fields(name)
+                    .iter()
+                    .map(|(field, ty)| format!("{}: {}", field, self.zero(ty)))
+                    .collect();
+                format!("new {} {{ {} }}", name, fields.join(", "))
+            }
+            _ => unreachable!("checked by `unsupported`"),
+        }
+    }
+}
+
+/// An expression reading a whole `ty` from `s`, for types that need no
+/// statements to read.
+fn read_expr(ty: &Type) -> Option<String> {
+    if ty.nullable {
+        return None;
+    }
+    match &ty.kind {
+        TypeKind::Integer | TypeKind::BitField { .. } => Some("json_integer(s)".to_string()),
+        TypeKind::Float => Some("json_float(s)".to_string()),
+        TypeKind::Boolean => Some("json_boolean(s)".to_string()),
+        TypeKind::String => Some("json_string(s)".to_string()),
+        TypeKind::Struct { name } => Some(format!("{}(s)", decoder_name(name))),
+        _ => None,
+    }
+}
+
+pub(super) fn non_null(ty: &Type) -> Type {
+    Type {
+        nullable: false,
+        ..ty.clone()
+    }
+}
+
+/// `fn __json_encode_Name(value: Name, b: Builder): Builder`, writing an
+/// object with the fields in declaration order.
+pub fn struct_encoder(name: &str, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    w.open(&format!(
+        "fn {}(value: {}, b: Builder): Builder",
+        encoder_name(name),
+        name
+    ));
+    w.line("b.append(\"{\");");
+    for (i, (field, ty)) in w.fields(name).iter().enumerate() {
+        let separator = if i > 0 { "," } else { "" };
+        w.line(&format!(
+            "b.append(\"{}\" + json_quote(\"{}\") + \":\");",
+            separator, field
+        ));
+        w.encode(ty, &format!("value.{}", field));
+    }
+    w.line("b.append(\"}\");");
+    w.line("return b;");
+    w.close();
+    w.source
+}
+
+/// `fn __json_decode_Name(s: string): Name`, reading an object whose
+/// members may come in any order. Unknown members are skipped; missing ones
+/// fail the document unless the field is nullable.
+pub fn struct_decoder(name: &str, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    let fields = w.fields(name);
+    w.open(&format!("fn {}(s: string): {}", decoder_name(name), name));
+
+    // Struct fields start out null and arrays as lists, since neither has
+    // a cheap placeholder.
+    let slots: Vec<Type> = fields
+        .iter()
+        .map(|(_, ty)| match &ty.kind {
+            TypeKind::Struct { .. } => Type {
+                nullable: true,
+                ..ty.clone()
+            },
+            TypeKind::Array { element, .. } => Type {
+                kind: TypeKind::List {
+                    element: element.clone(),
+                },
+                nullable: false,
+                errorable: false,
+                errors: vec![],
+            },
+            _ => ty.clone(),
+        })
+        .collect();
+    for (i, slot) in slots.iter().enumerate() {
+        let zero = match &slot.kind {
+            TypeKind::List { .. } if !slot.nullable => "{}".to_string(),
+            _ => w.zero(slot),
+        };
+        w.line(&format!("let field{}: {} = {};", i, slot, zero));
+        w.line(&format!("let seen{}: boolean = false;", i));
+    }
+
+    let more = w.local("more");
+    let key = w.local("key");
+    w.open("if json_expect(s, 123)");
+    w.open("if not json_eat(s, 125)");
+    w.line(&format!("let {}: boolean = true;", more));
+    w.open(&format!("while {}", more));
+    w.line(&format!("let {}: string = json_string(s);", key));
+    w.line("json_expect(s, 58);");
+    for (i, ((field, ty), slot)) in fields.iter().zip(&slots).enumerate() {
+        let test = format!("if {} == \"{}\"", key, field);
+        if i == 0 {
+            w.open(&test);
+        } else {
+            w.otherwise(&format!("else {}", test));
+        }
+        match &ty.kind {
+            TypeKind::Struct { name } if !ty.nullable => {
+                w.line(&format!("field{} = {}(s);", i, decoder_name(name)));
+            }
+            _ => w.decode(slot, &format!("field{}", i)),
+        }
+        w.line(&format!("seen{} = true;", i));
+    }
+    if fields.is_empty() {
+        w.line("json_skip(s);");
+    } else {
+        w.otherwise("else");
+        w.line("json_skip(s);");
+        w.close();
+    }
+    w.line(&format!("{} = json_eat(s, 44);", more));
+    w.close();
+    w.line("json_expect(s, 125);");
+    w.close();
+    w.close();
+
+    let mut values = Vec::new();
+    for (i, (field, ty)) in fields.iter().enumerate() {
+        match &ty.kind {
+            TypeKind::Struct { .. } if !ty.nullable => {
+                w.open(&format!("if not seen{}", i));
+                w.line("json_fail();");
+                w.line(&format!("field{} = {};", i, w.zero(ty)));
+                w.close();
+                values.push(format!("{}: field{}??", field, i));
+            }
+            TypeKind::Array { element, length } => {
+                w.open(&format!("if #field{} != {}", i, length));
+                w.line("json_fail();");
+                w.line(&format!(
+                    "field{} = repeat({}, {});",
+                    i,
+                    w.zero(element),
+                    length
+                ));
+                w.close();
+                let items: Vec<String> = (0..*length)
+                    .map(|item| format!("field{}[{}]", i, item))
+                    .collect();
+                values.push(format!("{}: [{}]", field, items.join(", ")));
+            }
+            _ => {
+                if !ty.nullable {
+                    w.open(&format!("if not seen{}", i));
+                    w.line("json_fail();");
+                    w.close();
+                }
+                values.push(format!("{}: field{}", field, i));
+            }
+        }
+    }
+    w.line(&format!("return new {} {{ {} }};", name, values.join(", ")));
+    w.close();
+    w.source
+}
+
+/// The helper behind `to_json` for values of `ty`.
+pub fn writer(index: usize, ty: &Type, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    w.open(&format!(
+        "fn {}(value: {}, b: Builder): Builder",
+        writer_name(index),
+        ty
+    ));
+    w.encode(ty, "value");
+    w.line("return b;");
+    w.close();
+    w.source
+}
+
+/// The helper behind `from_json` for `ty`, raising a `JsonError` when the
+/// document is malformed or doesn't match.
+pub fn reader(index: usize, ty: &Type, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    w.open(&format!("fn {}(s: string): {}!", reader_name(index), ty));
+    w.line("json_reset();");
+    w.declare(ty, "value");
+    w.open("if json_finish(s)");
+    w.line("return value;");
+    w.close();
+    w.line(&format!(
+        "raise new JsonError {{ message: \"invalid JSON for {}\" }};",
+        ty
+    ));
+    w.close();
+    w.source
+}
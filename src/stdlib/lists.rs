@@ -0,0 +1,162 @@
+//! Star source for the helpers behind the list methods `map`, `filter`,
+//! `reduce` and `get`, and behind `sort` and `sort_by`. There are no generic
+//! functions, so each combination of types the program calls them with gets
+//! a helper of its own. The helpers are ordinary Star, so the values they
+//! build stay rooted like any other.
+
+use crate::ast::{Type, TypeKind};
+
+/// A list method and the types a call to it was checked with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListFunction {
+    /// `xs.map(f)` on a `{element}`, with `f` returning `to`.
+    Map { element: Type, to: Type },
+    /// `xs.filter(f)` on a `{element}`.
+    Filter { element: Type },
+    /// `xs.reduce(initial, f)` on a `{element}`, folding into an
+    /// `accumulator`.
+    Reduce { element: Type, accumulator: Type },
+    /// `xs.get(i)` on a `{element}`.
+    Get { element: Type },
+    /// `sort(xs)` on a `{element}` of numbers or strings.
+    Sort { element: Type },
+    /// `sort_by(xs, cmp)` on a `{element}`.
+    SortBy { element: Type },
+}
+
+impl ListFunction {
+    /// The helper's name, given its index among those the program uses.
+    pub fn helper_name(&self, index: usize) -> String {
+        let method = match self {
+            ListFunction::Map { .. } => "map",
+            ListFunction::Filter { .. } => "filter",
+            ListFunction::Reduce { .. } => "reduce",
+            ListFunction::Get { .. } => "get",
+            ListFunction::Sort { .. } => "sort",
+            ListFunction::SortBy { .. } => "sort_by",
+        };
+        format!("__list_{}_{}", method, index)
+    }
+
+    /// The helper, taking the list and then the arguments of the call.
+    pub fn helper(&self, index: usize) -> String {
+        let name = self.helper_name(index);
+        match self {
+            ListFunction::Map { element, to } => format!(
+                "fn {name}(xs: {{{element}}}, f: ({element}: {to})): {{{to}}} {{
+    let out: {{{to}}} = {{}};
+    for x in xs {{
+        out.push(f(x));
+    }}
+    return out;
+}}
+"
+            ),
+            ListFunction::Filter { element } => format!(
+                "fn {name}(xs: {{{element}}}, f: ({element}: boolean)): {{{element}}} {{
+    let out: {{{element}}} = {{}};
+    for x in xs {{
+        if f(x) {{
+            out.push(x);
+        }}
+    }}
+    return out;
+}}
+"
+            ),
+            ListFunction::Reduce {
+                element,
+                accumulator,
+            } => format!(
+                "fn {name}(xs: {{{element}}}, initial: {accumulator}, f: ({accumulator}, {element}: {accumulator})): {accumulator} {{
+    let acc: {accumulator} = initial;
+    for x in xs {{
+        acc = f(acc, x);
+    }}
+    return acc;
+}}
+"
+            ),
+            ListFunction::Get { element } => {
+                let result = Type {
+                    errorable: true,
+                    errors: vec![],
+                    ..element.clone()
+                };
+                format!(
+                    "fn {name}(xs: {{{element}}}, i: integer): {result} {{
+    if i < 0 or i >= #xs {{
+        raise new IndexError {{ message: \"index \" + $i + \" is out of bounds for length \" + $(#xs) }};
+    }}
+    return xs[i];
+}}
+"
+                )
+            }
+            ListFunction::Sort { element } => {
+                let before = match element.kind {
+                    TypeKind::String => "compare(a[j], a[i]) < 0",
+                    _ => "a[j] < a[i]",
+                };
+                merge_sort(&name, element, "", before)
+            }
+            ListFunction::SortBy { element } => merge_sort(
+                &name,
+                element,
+                &format!(", cmp: ({element}, {element}: integer)"),
+                "cmp(a[j], a[i]) < 0",
+            ),
+        }
+    }
+}
+
+/// A stable merge sort of a copy of `xs`, taking `params` after it. It
+/// merges runs from `a` into `b`, twice as long each pass, and `before`
+/// says whether `a[j]`, from the right run, goes before `a[i]`.
+fn merge_sort(name: &str, element: &Type, params: &str, before: &str) -> String {
+    format!(
+        "fn {name}(xs: {{{element}}}{params}): {{{element}}} {{
+    let n: integer = #xs;
+    let a: {{{element}}} = {{}};
+    a = a + xs;
+    let b: {{{element}}} = {{}};
+    b = b + xs;
+    let width: integer = 1;
+    while width < n {{
+        let start: integer = 0;
+        while start < n {{
+            let middle: integer = start + width;
+            if middle > n {{
+                middle = n;
+            }}
+            let end: integer = middle + width;
+            if end > n {{
+                end = n;
+            }}
+            let i: integer = start;
+            let j: integer = middle;
+            for k in start..end {{
+                let left: boolean = i < middle;
+                if left and j < end {{
+                    left = not ({before});
+                }}
+                if left {{
+                    b[k] = a[i];
+                    i = i + 1;
+                }} else {{
+                    b[k] = a[j];
+                    j = j + 1;
+                }}
+            }}
+            start = end;
+        }}
+        let merged: {{{element}}} = b;
+        b = a;
+        a = merged;
+        width = width * 2;
+    }}
+    return a;
+}}
+"
+    )
+}
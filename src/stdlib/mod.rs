@@ -0,0 +1,22 @@
+pub mod json;
+pub mod lists;
+pub mod parse;
+pub mod show;
+
+use crate::ast::Program;
+use crate::frontend::Parser;
+
+/// Star declarations available to every program, compiled ahead of the
+/// user's own statements.
+const PRELUDE: &str = include_str!("prelude.star");
+
+/// Returns a copy of a parsed program with the prelude's declarations first.
+pub fn with_prelude(program: &Program) -> Program {
+    let mut parser = Parser::new(PRELUDE);
+    let mut statements = parser
+        .parse_program()
+        .expect("prelude should always parse")
+        .statements;
+    statements.extend(program.statements.iter().cloned());
+    Program { statements }
+}
@@ -0,0 +1,41 @@
+//! Star source for the helpers behind `parse_int` and `parse_float`, which
+//! call into the runtime's number readers and raise a `ParseError` when the
+//! string isn't a number.
+
+use crate::ast::TypeKind;
+
+/// `integer` or `float`, the kinds there are helpers for.
+fn kind_name(kind: &TypeKind) -> &'static str {
+    match kind {
+        TypeKind::Integer => "integer",
+        _ => "float",
+    }
+}
+
+pub fn helper_name(kind: &TypeKind) -> String {
+    format!("__parse_{}", kind_name(kind))
+}
+
+/// The helper reading a `kind` out of a string.
+pub fn helper(kind: &TypeKind) -> String {
+    let name = kind_name(kind);
+    let read = match kind {
+        TypeKind::Integer => "atoi",
+        _ => "atof",
+    };
+    format!(
+        "fn {}(s: string): {}! {{
+    let value: {} = {}(s);
+    if parse_failed() {{
+        raise new ParseError {{ message: \"invalid {}: \" + s }};
+    }}
+    return value;
+}}
+",
+        helper_name(kind),
+        name,
+        name,
+        read,
+        name
+    )
+}
@@ -0,0 +1,126 @@
+//! Star source for the helpers behind `$` on lists and structs.
+//!
+//! Values are written the way they are spelled in source, so `{1, 2, 3}`
+//! and `Point { x: 1, y: 2 }`. Like the JSON writers, every struct gets a
+//! helper of its own and every type passed to `$` gets one that fills a
+//! builder, while lists and nullables are written inline.
+
+use super::json::{non_null, Structs, Writer};
+use crate::ast::{Type, TypeKind};
+
+pub fn shower_name(index: usize) -> String {
+    format!("__show_{}", index)
+}
+
+fn struct_shower_name(name: &str) -> String {
+    format!("__show_struct_{}", name)
+}
+
+/// Whether `$` on a `ty` needs a generated helper rather than the
+/// runtime's own conversions.
+pub fn needs_helper(ty: &Type) -> bool {
+    matches!(ty.kind, TypeKind::List { .. } | TypeKind::Struct { .. })
+}
+
+/// Appends `value`, an expression that is free to read more than once, to
+/// the builder `b`.
+fn show(w: &mut Writer, ty: &Type, value: &str) {
+    match &ty.kind {
+        // `$` already writes null, numbers and booleans.
+        TypeKind::Integer | TypeKind::Float | TypeKind::Boolean | TypeKind::BitField { .. } => {
+            w.line(&format!("b.append($({}));", value));
+        }
+        _ if ty.nullable => {
+            let present = w.local("present");
+            w.line(&format!("let {}: {} = {};", present, ty, value));
+            w.open(&format!("if {}", present));
+            show(w, &non_null(ty), &present);
+            w.otherwise("else");
+            w.line("b.append(\"null\");");
+            w.close();
+        }
+        // Quoted the way JSON quotes them, so quotes inside come out escaped.
+        TypeKind::String => w.line(&format!("b.append(json_quote({}));", value)),
+        TypeKind::Struct { name } => {
+            w.line(&format!("{}({}, b);", struct_shower_name(name), value));
+        }
+        TypeKind::Error { name } => {
+            w.line(&format!("b.append(\"{} {{ message: \");", name));
+            show(w, &plain(TypeKind::String), &format!("{}.message", value));
+            w.line("b.append(\" }\");");
+        }
+        TypeKind::List { element } => {
+            let list = w.local("list");
+            w.line(&format!("let {}: {} = {};", list, ty, value));
+            show_items(w, element, &format!("#{}", list), &list, ("{", "}"));
+        }
+        TypeKind::Array { element, length } => {
+            show_items(w, element, &length.to_string(), value, ("[", "]"));
+        }
+        TypeKind::Function { .. } => w.line("b.append(\"fn\");"),
+        TypeKind::Null | TypeKind::Unknown => unreachable!("values have a known type"),
+    }
+}
+
+fn show_items(w: &mut Writer, element: &Type, count: &str, items: &str, brackets: (&str, &str)) {
+    let index = w.local("index");
+    w.line(&format!("b.append(\"{}\");", brackets.0));
+    w.line(&format!("let {}: integer = 0;", index));
+    w.open(&format!("while {} < {}", index, count));
+    w.open(&format!("if {} > 0", index));
+    w.line("b.append(\", \");");
+    w.close();
+    show(w, element, &format!("{}[{}]", items, index));
+    w.line(&format!("{} = {} + 1;", index, index));
+    w.close();
+    w.line(&format!("b.append(\"{}\");", brackets.1));
+}
+
+/// `fn __show_struct_Name(value: Name, b: Builder): Builder`, writing the
+/// fields in declaration order.
+pub fn struct_shower(name: &str, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    w.open(&format!(
+        "fn {}(value: {}, b: Builder): Builder",
+        struct_shower_name(name),
+        name
+    ));
+    let fields = w.fields(name);
+    if fields.is_empty() {
+        w.line(&format!("b.append(\"{} {{}}\");", name));
+    } else {
+        w.line(&format!("b.append(\"{} {{ \");", name));
+        for (i, (field, ty)) in fields.iter().enumerate() {
+            let separator = if i > 0 { ", " } else { "" };
+            w.line(&format!("b.append(\"{}{}: \");", separator, field));
+            show(&mut w, ty, &format!("value.{}", field));
+        }
+        w.line("b.append(\" }\");");
+    }
+    w.line("return b;");
+    w.close();
+    w.source
+}
+
+/// The helper behind `$` for values of `ty`.
+pub fn shower(index: usize, ty: &Type, structs: &Structs) -> String {
+    let mut w = Writer::new(structs);
+    w.open(&format!(
+        "fn {}(value: {}, b: Builder): Builder",
+        shower_name(index),
+        ty
+    ));
+    show(&mut w, ty, "value");
+    w.line("return b;");
+    w.close();
+    w.source
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
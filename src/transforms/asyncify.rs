@@ -0,0 +1,593 @@
+use crate::ast::{
+    BinaryOp, Builtin, IRExpr, IRExprKind, IRFunction, IRProgram, IRStmt, Type, TypeKind, UnaryOp,
+};
+use crate::host::AsyncState;
+
+/// Makes every function that may be on the stack when an `await` suspends
+/// resumable, if the program awaits at all. Calls go through the function
+/// table, so any function that calls may be one of them.
+///
+/// A resumable function has each call and `await` hoisted into a statement
+/// of its own, its call site. The rest of its statements only run while the
+/// program is running, not while it rewinds:
+///
+/// ```text
+/// if state == Running or resume == 2 {
+///     t = f(x);
+///     if state == Unwinding {
+///         resume = 2;
+///         suspend;
+///     }
+/// }
+/// ```
+///
+/// Rewinding restores the locals, including `resume` and the conditions of
+/// the `if`s and loops entered, so it takes the same path back to the call
+/// that suspended, skipping everything else.
+///
+/// A program that calls `exit` unwinds the same way, but each call site
+/// then returns for good, popping its frame:
+///
+/// ```text
+///     if state == Exiting {
+///         exit;
+///     }
+/// ```
+pub fn make_resumable(program: &mut IRProgram) {
+    let has = |test| {
+        program
+            .functions
+            .iter()
+            .any(|func| func.body.iter().any(|stmt| stmt_has(stmt, test)))
+    };
+    let exits = has(is_exit);
+    if !exits && !has(is_await) {
+        return;
+    }
+
+    // A `@noalloc` function only calls functions that never await or exit.
+    for func in program.functions.iter_mut().filter(|func| !func.noalloc) {
+        if func.body.iter().any(|stmt| stmt_has(stmt, is_site)) {
+            let body = std::mem::take(&mut func.body);
+            let mut resumer = Resumer {
+                func,
+                resume: 0,
+                sites: 0,
+                exits,
+            };
+            resumer.resume = resumer.temp(plain(TypeKind::Integer));
+            let flat = resumer.flatten_block(body);
+            resumer.func.body = resumer.guard_block(flat);
+            resumer.func.resumable = true;
+        }
+    }
+}
+
+struct Resumer<'a> {
+    func: &'a mut IRFunction,
+    /// Local holding the call site to rewind to.
+    resume: u32,
+    sites: i64,
+    /// Whether call sites also check for an `exit`.
+    exits: bool,
+}
+
+impl Resumer<'_> {
+    /// A new local of type `ty`.
+    fn temp(&mut self, ty: Type) -> u32 {
+        let index = 3 + self.func.params.len() + self.func.locals.len();
+        self.func.locals.push(ty);
+        index as u32
+    }
+
+    // Hoisting calls into call sites.
+
+    fn flatten_block(&mut self, stmts: Vec<IRStmt>) -> Vec<IRStmt> {
+        let mut out = Vec::new();
+        for stmt in stmts {
+            self.flatten_stmt(stmt, &mut out);
+        }
+        out
+    }
+
+    fn flatten_stmt(&mut self, stmt: IRStmt, out: &mut Vec<IRStmt>) {
+        let stmt = match stmt {
+            IRStmt::Expr(expr) => IRStmt::Expr(self.flatten_site(expr, out)),
+            IRStmt::LocalSet { index, value } => IRStmt::LocalSet {
+                index,
+                value: self.flatten_site(value, out),
+            },
+            IRStmt::Return(expr) => IRStmt::Return(expr.map(|e| self.flatten_expr(e, out))),
+            IRStmt::Print(expr) => IRStmt::Print(self.flatten_expr(expr, out)),
+            IRStmt::Produce(expr) => IRStmt::Produce(self.flatten_expr(expr, out)),
+            IRStmt::Raise(expr) => IRStmt::Raise(self.flatten_expr(expr, out)),
+            IRStmt::If {
+                condition,
+                then_block,
+                else_block,
+            } => IRStmt::If {
+                condition: self.flatten_expr(condition, out),
+                then_block: self.flatten_block(then_block),
+                else_block: else_block.map(|block| self.flatten_block(block)),
+            },
+            IRStmt::While { condition, body } => {
+                if !expr_has(&condition, is_site) {
+                    IRStmt::While {
+                        condition,
+                        body: self.flatten_block(body),
+                    }
+                } else {
+                    // Evaluate the condition inside the loop, where its calls
+                    // can have sites of their own.
+                    let mut loop_body = Vec::new();
+                    let condition = self.flatten_expr(condition, &mut loop_body);
+                    loop_body.push(IRStmt::If {
+                        condition: not(condition),
+                        then_block: vec![IRStmt::Break(0)],
+                        else_block: None,
+                    });
+                    loop_body.extend(self.flatten_block(body));
+                    IRStmt::While {
+                        condition: boolean(true),
+                        body: loop_body,
+                    }
+                }
+            }
+            IRStmt::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                // The same loop as a while, which `continue` treats alike:
+                // both jump back to the condition.
+                self.flatten_stmt(*init, out);
+                let mut body = body;
+                body.push(*update);
+                self.flatten_stmt(IRStmt::While { condition, body }, out);
+                return;
+            }
+            IRStmt::Unchecked { body } => IRStmt::Unchecked {
+                body: self.flatten_block(body),
+            },
+            IRStmt::LocalClosure {
+                fn_index,
+                captures,
+                index,
+            } => IRStmt::LocalClosure {
+                fn_index,
+                captures: Box::new(self.flatten_expr(*captures, out)),
+                index,
+            },
+            stmt @ (IRStmt::Break(_) | IRStmt::Continue(_) | IRStmt::Suspend | IRStmt::Exit) => {
+                stmt
+            }
+        };
+        out.push(stmt);
+    }
+
+    /// Returns `expr` without calls, hoisting them into sites in `out`.
+    fn flatten_expr(&mut self, expr: IRExpr, out: &mut Vec<IRStmt>) -> IRExpr {
+        let expr = self.flatten_site(expr, out);
+        if is_site(&expr) {
+            self.store(expr, out)
+        } else {
+            expr
+        }
+    }
+
+    /// Like `flatten_expr`, but leaves a call at the top in place, for a
+    /// statement that is itself a call site.
+    fn flatten_site(&mut self, expr: IRExpr, out: &mut Vec<IRStmt>) -> IRExpr {
+        if !expr_has(&expr, is_site) {
+            return expr;
+        }
+        let site = is_site(&expr);
+        let IRExpr { mut node, ty } = expr;
+        match &mut node {
+            _ if site => {
+                // A rewind evaluates these again, so they may only read
+                // locals and constants. String constants allocate, which
+                // could collect the result of the `await` being rewound to
+                // before it is rooted.
+                for operand in operands(&mut node) {
+                    let flat = self.flatten_expr(take(operand), out);
+                    *operand = match flat.node {
+                        IRExprKind::String(_) => self.store(flat, out),
+                        _ => self.stable(flat, out),
+                    };
+                }
+            }
+            IRExprKind::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } if expr_has(right, is_site) => {
+                // The right side's calls must only run when it does.
+                let left = self.flatten_expr(take(left), out);
+                let result = self.store(left, out);
+                let mut guarded = Vec::new();
+                let right = self.flatten_expr(take(right), &mut guarded);
+                let IRExprKind::Local(index) = result.node else {
+                    unreachable!()
+                };
+                guarded.push(IRStmt::LocalSet {
+                    index,
+                    value: right,
+                });
+                let condition = if *op == BinaryOp::And {
+                    result.clone()
+                } else {
+                    not(result.clone())
+                };
+                out.push(IRStmt::If {
+                    condition,
+                    then_block: guarded,
+                    else_block: None,
+                });
+                return result;
+            }
+            IRExprKind::Binary {
+                left,
+                op: BinaryOp::Is,
+                right,
+            } if matches!(left.node, IRExprKind::Global(_)) => {
+                // The global is assigned, not read, so it stays in place.
+                self.flatten_operands(vec![right], out)
+            }
+            _ => self.flatten_operands(operands(&mut node), out),
+        }
+        IRExpr { node, ty }
+    }
+
+    /// Flattens operands evaluated in order. Those before the last one
+    /// with a call are evaluated into locals first, so the call cannot
+    /// change what they evaluate to.
+    fn flatten_operands(&mut self, operands: Vec<&mut IRExpr>, out: &mut Vec<IRStmt>) {
+        let Some(last) = operands.iter().rposition(|e| expr_has(e, is_site)) else {
+            return;
+        };
+        for (i, operand) in operands.into_iter().enumerate() {
+            if i > last {
+                break;
+            }
+            if expr_has(operand, is_site) {
+                *operand = self.flatten_expr(take(operand), out);
+            } else if is_place(operand) {
+                let inner = operands_of(operand);
+                self.settle(inner, out);
+            } else {
+                *operand = self.stable(take(operand), out);
+            }
+        }
+    }
+
+    /// Evaluates the operands of a place into locals, since the place
+    /// itself cannot be stored.
+    fn settle(&mut self, operands: Vec<&mut IRExpr>, out: &mut Vec<IRStmt>) {
+        for operand in operands {
+            if is_place(operand) {
+                let inner = operands_of(operand);
+                self.settle(inner, out);
+            } else {
+                *operand = self.stable(take(operand), out);
+            }
+        }
+    }
+
+    /// `expr`, or a local holding its value if reading it again could give
+    /// something else.
+    fn stable(&mut self, expr: IRExpr, out: &mut Vec<IRStmt>) -> IRExpr {
+        if is_constant(&expr) {
+            expr
+        } else {
+            self.store(expr, out)
+        }
+    }
+
+    fn store(&mut self, expr: IRExpr, out: &mut Vec<IRStmt>) -> IRExpr {
+        let ty = expr.ty.clone();
+        let index = self.temp(ty.clone());
+        out.push(IRStmt::LocalSet { index, value: expr });
+        IRExpr {
+            node: IRExprKind::Local(index),
+            ty,
+        }
+    }
+
+    // Guarding statements for rewinds.
+
+    fn guard_block(&mut self, stmts: Vec<IRStmt>) -> Vec<IRStmt> {
+        let mut out = Vec::new();
+        let mut running = Vec::new();
+        for stmt in stmts {
+            if !stmt_has(&stmt, is_site) {
+                running.push(stmt);
+                continue;
+            }
+            flush(&mut running, &mut out);
+            match stmt {
+                IRStmt::If {
+                    condition,
+                    then_block,
+                    else_block,
+                } => {
+                    let condition = self.remembered(condition, &mut out);
+                    out.push(IRStmt::If {
+                        condition,
+                        then_block: self.guard_block(then_block),
+                        else_block: else_block.map(|block| self.guard_block(block)),
+                    });
+                }
+                IRStmt::While { condition, body } => {
+                    let mut loop_body = Vec::new();
+                    let condition = self.remembered(condition, &mut loop_body);
+                    loop_body.push(IRStmt::If {
+                        condition: not(condition),
+                        then_block: vec![IRStmt::Break(0)],
+                        else_block: None,
+                    });
+                    loop_body.extend(self.guard_block(body));
+                    out.push(IRStmt::While {
+                        condition: boolean(true),
+                        body: loop_body,
+                    });
+                }
+                IRStmt::Unchecked { body } => out.push(IRStmt::Unchecked {
+                    body: self.guard_block(body),
+                }),
+                site => {
+                    self.sites += 1;
+                    let resume = IRExpr {
+                        node: IRExprKind::Local(self.resume),
+                        ty: plain(TypeKind::Integer),
+                    };
+                    let mut then_block = vec![
+                        site,
+                        IRStmt::If {
+                            condition: in_state(AsyncState::Unwinding),
+                            then_block: vec![
+                                IRStmt::LocalSet {
+                                    index: self.resume,
+                                    value: integer(self.sites),
+                                },
+                                IRStmt::Suspend,
+                            ],
+                            else_block: None,
+                        },
+                    ];
+                    if self.exits {
+                        then_block.push(IRStmt::If {
+                            condition: in_state(AsyncState::Exiting),
+                            then_block: vec![IRStmt::Exit],
+                            else_block: None,
+                        });
+                    }
+                    out.push(IRStmt::If {
+                        condition: or(
+                            in_state(AsyncState::Running),
+                            equals(resume, integer(self.sites)),
+                        ),
+                        then_block,
+                        else_block: None,
+                    });
+                }
+            }
+        }
+        flush(&mut running, &mut out);
+        out
+    }
+
+    /// Keeps a condition in a local, set only while running, so a rewind
+    /// sees the value that chose the path it is retracing.
+    fn remembered(&mut self, condition: IRExpr, out: &mut Vec<IRStmt>) -> IRExpr {
+        if is_constant(&condition) {
+            return condition;
+        }
+        let mut set = Vec::new();
+        let local = self.store(condition, &mut set);
+        flush(&mut set, out);
+        local
+    }
+}
+
+/// Moves the statements in `running` into a block that only runs while
+/// the program is running.
+fn flush(running: &mut Vec<IRStmt>, out: &mut Vec<IRStmt>) {
+    if running.is_empty() {
+        return;
+    }
+    out.push(IRStmt::If {
+        condition: in_state(AsyncState::Running),
+        then_block: std::mem::take(running),
+        else_block: None,
+    });
+}
+
+fn stmt_has(stmt: &IRStmt, test: fn(&IRExpr) -> bool) -> bool {
+    let mut found = false;
+    stmt.visit_exprs(&mut |expr| found |= test(expr));
+    found
+}
+
+fn expr_has(expr: &IRExpr, test: fn(&IRExpr) -> bool) -> bool {
+    let mut found = false;
+    expr.visit(&mut |inner| found |= test(inner));
+    found
+}
+
+fn is_await(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::Builtin {
+            builtin: Builtin::Sleep | Builtin::Fetch,
+            ..
+        }
+    )
+}
+
+fn is_exit(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::Builtin {
+            builtin: Builtin::Exit,
+            ..
+        }
+    )
+}
+
+/// Whether the program may suspend or exit inside `expr` itself.
+fn is_site(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::Call { .. }
+            | IRExprKind::CallDirect { .. }
+            | IRExprKind::Builtin {
+                builtin: Builtin::Sleep | Builtin::Fetch | Builtin::Exit,
+                ..
+            }
+    )
+}
+
+/// Whether `expr` evaluates to the same thing wherever it is read.
+fn is_constant(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::Integer(_)
+            | IRExprKind::Float(_)
+            | IRExprKind::Boolean(_)
+            | IRExprKind::String(_)
+            | IRExprKind::Null
+            | IRExprKind::Local(_)
+            | IRExprKind::Function(_)
+    )
+}
+
+/// Whether `expr` names storage, or is an array copied into it, rather than
+/// being a value a local could hold.
+fn is_place(expr: &IRExpr) -> bool {
+    matches!(
+        expr.node,
+        IRExprKind::FieldReference { .. }
+            | IRExprKind::IndexReference { .. }
+            | IRExprKind::ArrayIndexReference { .. }
+            | IRExprKind::SliceReference { .. }
+            | IRExprKind::Array(_)
+    )
+}
+
+fn operands_of(expr: &mut IRExpr) -> Vec<&mut IRExpr> {
+    operands(&mut expr.node)
+}
+
+/// The operands of an expression, in the order they are evaluated.
+fn operands(node: &mut IRExprKind) -> Vec<&mut IRExpr> {
+    match node {
+        IRExprKind::Integer(_)
+        | IRExprKind::Float(_)
+        | IRExprKind::Boolean(_)
+        | IRExprKind::String(_)
+        | IRExprKind::Null
+        | IRExprKind::Local(_)
+        | IRExprKind::Global(_)
+        | IRExprKind::Function(_)
+        | IRExprKind::AsyncState
+        | IRExprKind::Match { .. } => vec![],
+        IRExprKind::Binary { left, right, .. } => vec![left, right],
+        IRExprKind::Unary { expr, .. }
+        | IRExprKind::Field { object: expr, .. }
+        | IRExprKind::FieldReference { object: expr, .. }
+        | IRExprKind::UnwrapError(expr)
+        | IRExprKind::UnwrapNull(expr) => vec![expr],
+        IRExprKind::Call { callee, args } => {
+            let mut all = vec![callee.as_mut()];
+            all.extend(args.iter_mut());
+            all
+        }
+        IRExprKind::CallDirect { args, .. }
+        | IRExprKind::Builtin { args, .. }
+        | IRExprKind::ExternCall { args, .. }
+        | IRExprKind::List(args)
+        | IRExprKind::Array(args)
+        | IRExprKind::New { fields: args, .. } => args.iter_mut().collect(),
+        IRExprKind::Index { list, index }
+        | IRExprKind::IndexReference { list, index }
+        | IRExprKind::ArrayIndex {
+            array: list, index, ..
+        }
+        | IRExprKind::ArrayIndexReference {
+            array: list, index, ..
+        } => vec![list, index],
+        IRExprKind::Slice { expr, start, end }
+        | IRExprKind::SliceReference {
+            list: expr,
+            start,
+            end,
+        } => vec![expr, start, end],
+    }
+}
+
+/// Moves an expression out, leaving a placeholder to be overwritten.
+fn take(expr: &mut IRExpr) -> IRExpr {
+    std::mem::replace(expr, boolean(false))
+}
+
+fn in_state(state: AsyncState) -> IRExpr {
+    let current = IRExpr {
+        node: IRExprKind::AsyncState,
+        ty: plain(TypeKind::Integer),
+    };
+    equals(current, integer(state as i64))
+}
+
+fn equals(left: IRExpr, right: IRExpr) -> IRExpr {
+    binary(left, BinaryOp::Eq, right)
+}
+
+fn or(left: IRExpr, right: IRExpr) -> IRExpr {
+    binary(left, BinaryOp::Or, right)
+}
+
+fn binary(left: IRExpr, op: BinaryOp, right: IRExpr) -> IRExpr {
+    IRExpr {
+        node: IRExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+        ty: plain(TypeKind::Boolean),
+    }
+}
+
+fn not(expr: IRExpr) -> IRExpr {
+    IRExpr {
+        node: IRExprKind::Unary {
+            op: UnaryOp::Not,
+            expr: Box::new(expr),
+        },
+        ty: plain(TypeKind::Boolean),
+    }
+}
+
+fn integer(value: i64) -> IRExpr {
+    IRExpr {
+        node: IRExprKind::Integer(value),
+        ty: plain(TypeKind::Integer),
+    }
+}
+
+fn boolean(value: bool) -> IRExpr {
+    IRExpr {
+        node: IRExprKind::Boolean(value),
+        ty: plain(TypeKind::Boolean),
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
@@ -0,0 +1,364 @@
+use crate::ast::{IRExpr, IRExprKind, IRFunction, IRProgram, IRStmt, IRStruct};
+use std::collections::{HashMap, HashSet};
+
+/// Drops the statements nothing can reach, after a `return`, `raise`,
+/// `break` or `continue`, and then the functions nothing can call.
+///
+/// `main`, the exported functions and drop methods are always reachable. The
+/// other top-level functions are reachable when a reachable function names
+/// them. Every other function is made by a `LocalClosure`, and is reachable
+/// when the function making it is and reads the local it's put in. Reads by the captures of
+/// a closure that isn't reachable itself don't count, so functions that
+/// only call each other are dropped together, along with the closures
+/// making them. A closure's captures only count for the fields its function
+/// reads out of its environment. Calls made directly reach their function
+/// too, and a closure nothing reads is dropped even when its function is
+/// reachable that way, unless the function reads its environment. The
+/// functions left are renumbered in order.
+pub fn eliminate_dead_code(program: &mut IRProgram) {
+    for func in &mut program.functions {
+        for_each_block(&mut func.body, &mut |block| {
+            if let Some(end) = block.iter().position(leaves) {
+                block.truncate(end + 1);
+            }
+        });
+    }
+
+    let (live, mut unread) = live_functions(program);
+    program
+        .functions
+        .retain(|func| live.contains(&func.func_index));
+    let mut renumbered = HashMap::new();
+    let mut unread_by_function = vec![];
+    for (index, func) in program.functions.iter_mut().enumerate() {
+        renumbered.insert(func.func_index, index as u32);
+        unread_by_function.push(unread.remove(&func.func_index).unwrap_or_default());
+        func.func_index = index as u32;
+    }
+    for ir_struct in &mut program.structs {
+        ir_struct.drop = ir_struct.drop.map(|drop| renumbered[&drop]);
+    }
+    for (func, unread) in program.functions.iter_mut().zip(&unread_by_function) {
+        for_each_block(&mut func.body, &mut |block| {
+            block.retain_mut(|stmt| match stmt {
+                IRStmt::LocalClosure { index, .. } if unread.contains(index) => false,
+                IRStmt::LocalClosure { fn_index, .. } => match renumbered.get(fn_index) {
+                    Some(index) => {
+                        *fn_index = *index;
+                        true
+                    }
+                    None => false,
+                },
+                _ => true,
+            });
+            for stmt in block.iter_mut() {
+                for expr in own_exprs(stmt) {
+                    renumber_calls(expr, &renumbered);
+                }
+            }
+        });
+    }
+}
+
+fn renumber_calls(expr: &mut IRExpr, renumbered: &HashMap<u32, u32>) {
+    if let IRExprKind::CallDirect { fn_index, .. } | IRExprKind::Function(fn_index) =
+        &mut expr.node
+    {
+        *fn_index = renumbered[fn_index];
+    }
+    for child in children(expr) {
+        renumber_calls(child, renumbered);
+    }
+}
+
+/// The indices of the functions reachable from `main`, the exported
+/// functions and drop methods, and the locals of the closures each of them
+/// makes that nothing reads, of functions that don't read their environment.
+fn live_functions(program: &mut IRProgram) -> (HashSet<u32>, HashMap<u32, HashSet<u32>>) {
+    let positions: HashMap<u32, usize> = program
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(position, func)| (func.func_index, position))
+        .collect();
+    let env_fields: HashMap<u32, Option<HashSet<u32>>> = program
+        .functions
+        .iter_mut()
+        .map(|func| (func.func_index, env_fields(func)))
+        .collect();
+    let mut worklist: Vec<u32> = program
+        .functions
+        .iter()
+        .filter(|func| func.func_index == 0 || func.exported)
+        .map(|func| func.func_index)
+        .chain(program.structs.iter().filter_map(|s| s.drop))
+        .collect();
+    let mut live = HashSet::new();
+    let mut unread = HashMap::new();
+    while let Some(index) = worklist.pop() {
+        if !live.insert(index) {
+            continue;
+        }
+        // The local and function of each closure with the reads of each of
+        // its captures, and every other local read. Functions called
+        // directly or named are reachable straight away.
+        let mut closures = vec![];
+        let mut reads = HashSet::new();
+        let structs = &program.structs;
+        let func = &mut program.functions[positions[&index]];
+        for_each_block(&mut func.body, &mut |block| {
+            for stmt in block.iter_mut() {
+                if let IRStmt::LocalClosure {
+                    fn_index,
+                    captures,
+                    index,
+                } = stmt
+                {
+                    closures.push((*index, *fn_index, captured(structs, captures)));
+                } else {
+                    for expr in own_exprs(stmt) {
+                        expr.visit(&mut |expr| {
+                            read(expr, &mut reads);
+                            if let IRExprKind::CallDirect { fn_index, .. }
+                            | IRExprKind::Function(fn_index) = expr.node
+                            {
+                                worklist.push(fn_index);
+                            }
+                        });
+                    }
+                }
+            }
+        });
+
+        loop {
+            let (called, rest): (Vec<_>, Vec<_>) = closures
+                .into_iter()
+                .partition(|(local, ..)| reads.contains(local));
+            if called.is_empty() {
+                let unused = rest.into_iter().filter(|(_, fn_index, _)| {
+                    env_fields[fn_index].as_ref().is_some_and(HashSet::is_empty)
+                });
+                unread.insert(index, unused.map(|(local, ..)| local).collect());
+                break;
+            }
+            for (_, fn_index, captured) in called {
+                worklist.push(fn_index);
+                let fields = &env_fields[&fn_index];
+                for (offset, captured) in captured {
+                    let needed = match (offset, fields) {
+                        (Some(offset), Some(fields)) => fields.contains(&offset),
+                        _ => true,
+                    };
+                    if needed {
+                        reads.extend(captured);
+                    }
+                }
+            }
+            closures = rest;
+        }
+    }
+    (live, unread)
+}
+
+/// The offset of each field of a closure's captures, with the locals it
+/// reads. Captures that aren't a new struct come as one, without an offset.
+fn captured(structs: &[IRStruct], captures: &mut IRExpr) -> Vec<(Option<u32>, HashSet<u32>)> {
+    let reads = |expr: &mut IRExpr| {
+        let mut reads = HashSet::new();
+        expr.visit(&mut |expr| read(expr, &mut reads));
+        reads
+    };
+    match &mut captures.node {
+        IRExprKind::New {
+            struct_index,
+            fields,
+        } => {
+            let offsets = &structs[*struct_index as usize].offsets;
+            offsets
+                .iter()
+                .zip(fields.iter_mut())
+                .map(|(offset, field)| (Some(*offset), reads(field)))
+                .collect()
+        }
+        _ => vec![(None, reads(captures))],
+    }
+}
+
+/// The offsets of the fields `func` reads out of its environment, or
+/// `None` if the environment is used as a whole.
+fn env_fields(func: &mut IRFunction) -> Option<HashSet<u32>> {
+    let mut fields = HashSet::new();
+    let mut uses = 0;
+    for_each_block(&mut func.body, &mut |block| {
+        for stmt in block.iter_mut() {
+            for expr in own_exprs(stmt) {
+                expr.visit(&mut |expr| match &expr.node {
+                    IRExprKind::Local(2) => uses += 1,
+                    IRExprKind::Field { object, offset }
+                    | IRExprKind::FieldReference { object, offset }
+                        if matches!(object.node, IRExprKind::Local(2)) =>
+                    {
+                        fields.insert(*offset);
+                        uses -= 1;
+                    }
+                    _ => {}
+                });
+            }
+        }
+    });
+    (uses == 0).then_some(fields)
+}
+
+fn read(expr: &IRExpr, reads: &mut HashSet<u32>) {
+    if let IRExprKind::Local(local) = &expr.node {
+        reads.insert(*local);
+    }
+}
+
+/// Whether control never gets past `stmt` to the next statement.
+fn leaves(stmt: &IRStmt) -> bool {
+    match stmt {
+        IRStmt::Return(_) | IRStmt::Raise(_) | IRStmt::Break(_) | IRStmt::Continue(_) => true,
+        IRStmt::If {
+            then_block,
+            else_block: Some(else_block),
+            ..
+        } => then_block.iter().any(leaves) && else_block.iter().any(leaves),
+        IRStmt::Unchecked { body } => body.iter().any(leaves),
+        _ => false,
+    }
+}
+
+/// The expressions `stmt` evaluates itself, leaving out those of the
+/// statements nested in it.
+pub(super) fn own_exprs(stmt: &mut IRStmt) -> Vec<&mut IRExpr> {
+    match stmt {
+        IRStmt::Expr(expr)
+        | IRStmt::LocalSet { value: expr, .. }
+        | IRStmt::Print(expr)
+        | IRStmt::Produce(expr)
+        | IRStmt::Raise(expr)
+        | IRStmt::Return(Some(expr))
+        | IRStmt::If {
+            condition: expr, ..
+        }
+        | IRStmt::While {
+            condition: expr, ..
+        } => vec![expr],
+        IRStmt::For {
+            init,
+            condition,
+            update,
+            ..
+        } => {
+            let mut exprs = own_exprs(init);
+            exprs.push(condition);
+            exprs.extend(own_exprs(update));
+            exprs
+        }
+        IRStmt::LocalClosure { captures, .. } => vec![captures],
+        IRStmt::Return(None)
+        | IRStmt::Break(_)
+        | IRStmt::Continue(_)
+        | IRStmt::Unchecked { .. }
+        | IRStmt::Suspend
+        | IRStmt::Exit => vec![],
+    }
+}
+
+/// The expressions directly inside `expr`, leaving out those in match arm
+/// bodies.
+pub(super) fn children(expr: &mut IRExpr) -> Vec<&mut IRExpr> {
+    match &mut expr.node {
+        IRExprKind::Integer(_)
+        | IRExprKind::Float(_)
+        | IRExprKind::Boolean(_)
+        | IRExprKind::String(_)
+        | IRExprKind::Null
+        | IRExprKind::Local(_)
+        | IRExprKind::Global(_)
+        | IRExprKind::Function(_)
+        | IRExprKind::AsyncState => vec![],
+        IRExprKind::Unary { expr, .. }
+        | IRExprKind::Field { object: expr, .. }
+        | IRExprKind::FieldReference { object: expr, .. }
+        | IRExprKind::UnwrapError(expr)
+        | IRExprKind::UnwrapNull(expr)
+        | IRExprKind::Match { expr, .. } => vec![expr],
+        IRExprKind::Binary { left, right, .. }
+        | IRExprKind::Index {
+            list: left,
+            index: right,
+        }
+        | IRExprKind::IndexReference {
+            list: left,
+            index: right,
+        }
+        | IRExprKind::ArrayIndex {
+            array: left,
+            index: right,
+            ..
+        }
+        | IRExprKind::ArrayIndexReference {
+            array: left,
+            index: right,
+            ..
+        } => vec![left, right],
+        IRExprKind::Call { callee, args } => {
+            let mut exprs: Vec<&mut IRExpr> = vec![callee];
+            exprs.extend(args.iter_mut());
+            exprs
+        }
+        IRExprKind::CallDirect { args, .. }
+        | IRExprKind::Builtin { args, .. }
+        | IRExprKind::ExternCall { args, .. }
+        | IRExprKind::List(args)
+        | IRExprKind::Array(args)
+        | IRExprKind::New { fields: args, .. } => args.iter_mut().collect(),
+        IRExprKind::Slice { expr, start, end }
+        | IRExprKind::SliceReference {
+            list: expr,
+            start,
+            end,
+        } => vec![expr, start, end],
+    }
+}
+
+/// Calls `f` on `body` and every block nested in it, in `if`s, loops,
+/// `unchecked` blocks and match arms, each once.
+pub(super) fn for_each_block(body: &mut Vec<IRStmt>, f: &mut dyn FnMut(&mut Vec<IRStmt>)) {
+    f(body);
+    for stmt in body.iter_mut() {
+        for expr in own_exprs(stmt) {
+            for_each_arm(expr, f);
+        }
+        match stmt {
+            IRStmt::If {
+                then_block,
+                else_block,
+                ..
+            } => {
+                for_each_block(then_block, f);
+                if let Some(else_block) = else_block {
+                    for_each_block(else_block, f);
+                }
+            }
+            IRStmt::For { body, .. } | IRStmt::While { body, .. } | IRStmt::Unchecked { body } => {
+                for_each_block(body, f)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Calls `for_each_block` on the body of every match arm in `expr`.
+fn for_each_arm(expr: &mut IRExpr, f: &mut dyn FnMut(&mut Vec<IRStmt>)) {
+    if let IRExprKind::Match { arms, .. } = &mut expr.node {
+        for (_, body) in arms {
+            for_each_block(body, f);
+        }
+    }
+    for child in children(expr) {
+        for_each_arm(child, f);
+    }
+}
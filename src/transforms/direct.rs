@@ -0,0 +1,92 @@
+use super::dce::{children, for_each_block, own_exprs};
+use super::targets::Targets;
+use crate::ast::{IRExpr, IRExprKind, IRProgram};
+use std::collections::{HashMap, HashSet};
+
+/// Turns calls known to always call the same function, which never reads
+/// its environment, into direct calls. They skip making a closure to call
+/// through and `call_indirect`, passing no environment instead.
+///
+/// A function's environment goes unread when it only reads it for calls
+/// that are made direct, so a function calling itself through its
+/// environment is called directly too.
+pub fn call_directly(program: &mut IRProgram) {
+    let IRProgram {
+        structs, functions, ..
+    } = program;
+    let targets = Targets::new(structs, functions);
+
+    // Each function's calls through its environment to known functions, and
+    // whether it reads its environment for anything else.
+    let mut through_env: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut needs_env = HashSet::new();
+    for func in functions.iter() {
+        let mut uses = 0;
+        let mut called = vec![];
+        for stmt in &func.body {
+            stmt.visit_exprs(&mut |expr| match &expr.node {
+                IRExprKind::Local(2) => uses += 1,
+                IRExprKind::Call { callee, .. } => {
+                    if let IRExprKind::Field { object, .. } = &callee.node {
+                        if let (IRExprKind::Local(2), Some(target)) =
+                            (&object.node, targets.target(func.func_index, callee))
+                        {
+                            called.push(target);
+                            uses -= 1;
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+        if uses > 0 {
+            needs_env.insert(func.func_index);
+        }
+        through_env.insert(func.func_index, called);
+    }
+    // A call to a function that needs its environment stays indirect, so
+    // the caller keeps reading its own.
+    loop {
+        let more: Vec<u32> = through_env
+            .iter()
+            .filter(|(func, called)| {
+                !needs_env.contains(*func) && called.iter().any(|target| needs_env.contains(target))
+            })
+            .map(|(func, _)| *func)
+            .collect();
+        if more.is_empty() {
+            break;
+        }
+        needs_env.extend(more);
+    }
+
+    for func in functions.iter_mut() {
+        let caller = func.func_index;
+        let target = |callee: &IRExpr| {
+            targets
+                .target(caller, callee)
+                .filter(|target| !needs_env.contains(target))
+        };
+        for_each_block(&mut func.body, &mut |block| {
+            for stmt in block.iter_mut() {
+                for expr in own_exprs(stmt) {
+                    make_direct(expr, &target);
+                }
+            }
+        });
+    }
+}
+
+fn make_direct(expr: &mut IRExpr, target: &dyn Fn(&IRExpr) -> Option<u32>) {
+    for child in children(expr) {
+        make_direct(child, target);
+    }
+    if let IRExprKind::Call { callee, args } = &mut expr.node {
+        if let Some(fn_index) = target(callee) {
+            expr.node = IRExprKind::CallDirect {
+                fn_index,
+                args: std::mem::take(args),
+            };
+        }
+    }
+}
@@ -1,14 +1,32 @@
 use crate::ast::aast::{self, AnalyzedExpr, AnalyzedProgram, AnalyzedStatement};
-use crate::ast::FlattenedProgram;
+use crate::ast::{FlattenedProgram, IRStructKind};
 use crate::ast::{Type, TypeKind};
 
+use super::matches::lower_matches;
+use super::tailcall::loop_tail_calls;
+
+/// Number of 8-byte slots a field occupies. Fixed arrays are stored inline.
+pub fn field_slots(ty: &Type) -> u32 {
+    match &ty.kind {
+        TypeKind::Array { length, .. } => *length,
+        _ => 1,
+    }
+}
+
 pub fn segregate_fields(fields: Vec<(String, Type)>) -> (Vec<(String, Type)>, u32, u32) {
     let mut struct_ptrs = vec![];
     let mut list_ptrs = vec![];
     let mut primitives = vec![];
 
     for (name, ty) in fields {
-        match &ty.kind {
+        let slot = match &ty.kind {
+            TypeKind::Array { element, .. } => element.as_ref(),
+            _ => &ty,
+        };
+        match slot.kind {
+            // Missing values are tagged unions, which are structs whatever
+            // they hold.
+            _ if slot.nullable || slot.errorable => struct_ptrs.push((name, ty)),
             // TODO: function change order
             TypeKind::Struct { .. } | TypeKind::Function { .. } => struct_ptrs.push((name, ty)),
             TypeKind::List { .. } | TypeKind::String => list_ptrs.push((name, ty)),
@@ -16,8 +34,8 @@ pub fn segregate_fields(fields: Vec<(String, Type)>) -> (Vec<(String, Type)>, u3
         }
     }
 
-    let struct_count = struct_ptrs.len() as u32;
-    let list_count = list_ptrs.len() as u32;
+    let struct_count = struct_ptrs.iter().map(|(_, ty)| field_slots(ty)).sum();
+    let list_count = list_ptrs.iter().map(|(_, ty)| field_slots(ty)).sum();
 
     struct_ptrs.append(&mut list_ptrs);
     struct_ptrs.append(&mut primitives);
@@ -26,7 +44,7 @@ pub fn segregate_fields(fields: Vec<(String, Type)>) -> (Vec<(String, Type)>, u3
 }
 
 pub struct Flattener {
-    structs: Vec<(AnalyzedStatement, u32, u32)>,
+    structs: Vec<(AnalyzedStatement, u32, u32, IRStructKind)>,
     functions: Vec<AnalyzedStatement>,
     captures: Vec<(String, Type, CaptureKind)>,
 }
@@ -68,6 +86,9 @@ impl Flattener {
                 AnalyzedStatement::While { condition, body } => {
                     captures.extend(self.gather_captures(body));
                 }
+                AnalyzedStatement::Unchecked { body } => {
+                    captures.extend(self.gather_captures(body));
+                }
                 AnalyzedStatement::For {
                     init,
                     condition,
@@ -96,10 +117,6 @@ impl Flattener {
                             ty.clone(),
                             CaptureKind::Index(index.unwrap()),
                         ));
-                        println!(
-                            "Captured variable '{}' of type {:?} at index {:?}",
-                            field_name, ty, index
-                        );
                     }
                 }
                 AnalyzedStatement::Function {
@@ -122,6 +139,7 @@ impl Flattener {
                                 },
                                 nullable: false,
                                 errorable: false,
+                                errors: vec![],
                             },
                             CaptureKind::Index(index.unwrap()),
                         ));
@@ -168,7 +186,11 @@ impl Flattener {
                 index,
                 fn_index,
                 locals,
+                local_names,
+                exported,
+                noalloc,
             } => {
+                let body = &lower_matches(body);
                 let fn_captures = self.gather_captures(body);
                 let param_captures = self.scan_params(params);
 
@@ -185,8 +207,6 @@ impl Flattener {
                     captures_to_pass_down.push((n.clone(), t.clone(), k.clone()));
                 }
 
-                println!("Function '{}' captures {:?}", name, fn_captures);
-                println!("Function '{}' captures: {:?}", name, captures_to_pass_down);
 
                 let (segregated, struct_count, list_count) = segregate_fields(
                     captures_to_pass_down
@@ -201,6 +221,7 @@ impl Flattener {
                     },
                     struct_count,
                     list_count,
+                    IRStructKind::Captures,
                 ));
 
                 let outer_fields: Vec<(String, AnalyzedExpr)> = captures
@@ -222,6 +243,7 @@ impl Flattener {
                                                 },
                                                 nullable: false,
                                                 errorable: false,
+                                                errors: vec![],
                                             },
                                         }),
                                         field: n.clone(),
@@ -251,14 +273,26 @@ impl Flattener {
                         },
                         nullable: false,
                         errorable: false,
+                        errors: vec![],
                     },
                 };
 
-                let analyzed_body: Vec<_> = body
+                let mut analyzed_body: Vec<_> = body
                     .iter()
                     .map(|s| self.flatten_stmt(s, captures_to_pass_down.clone(), name.clone()))
                     .collect();
 
+                let mut locals = locals.clone();
+                if let Some(looped) = loop_tail_calls(
+                    &analyzed_body,
+                    captured.borrow().as_deref(),
+                    fn_index.unwrap(),
+                    params,
+                    &mut locals,
+                ) {
+                    analyzed_body = looped;
+                }
+
                 // Push the flattened function to the functions list
                 self.functions.push(AnalyzedStatement::Function {
                     name: name.clone(),
@@ -268,7 +302,10 @@ impl Flattener {
                     captured: captured.clone(),
                     index: *index,
                     fn_index: *fn_index,
-                    locals: locals.clone(),
+                    locals,
+                    local_names: local_names.clone(),
+                    exported: *exported,
+                    noalloc: *noalloc,
                 });
 
                 let fn_type = Type {
@@ -278,6 +315,7 @@ impl Flattener {
                     },
                     nullable: false,
                     errorable: false,
+                    errors: vec![],
                 };
 
                 AnalyzedStatement::LocalClosure {
@@ -318,6 +356,15 @@ impl Flattener {
                     body: analyzed_body,
                 }
             }
+            AnalyzedStatement::Unchecked { body } => {
+                let analyzed_body: Vec<_> = body
+                    .iter()
+                    .map(|s| self.flatten_stmt(s, captures.clone(), prev.clone()))
+                    .collect();
+                AnalyzedStatement::Unchecked {
+                    body: analyzed_body,
+                }
+            }
             AnalyzedStatement::For {
                 init,
                 condition,
@@ -346,9 +393,21 @@ impl Flattener {
                     fields: segregated,
                 };
 
-                self.structs.push((str.clone(), struct_count, list_count));
+                self.structs
+                    .push((str.clone(), struct_count, list_count, IRStructKind::User));
                 str
             }
+            AnalyzedStatement::Error { name, fields } => {
+                let (segregated, struct_count, list_count) = segregate_fields(fields.clone());
+                let error = AnalyzedStatement::Error {
+                    name: name.clone(),
+                    fields: segregated,
+                };
+
+                self.structs
+                    .push((error.clone(), struct_count, list_count, IRStructKind::Error));
+                error
+            }
             nonfunc => nonfunc.clone(),
         }
     }
@@ -369,6 +428,10 @@ impl Flattener {
             functions.insert(0, main_fn);
         }
 
-        FlattenedProgram { structs, functions }
+        FlattenedProgram {
+            structs,
+            functions,
+            globals: program.globals.clone(),
+        }
     }
 }
@@ -0,0 +1,369 @@
+use super::dce::{children, for_each_block, own_exprs};
+use super::targets::{walk, Targets};
+use crate::ast::{BinaryOp, IRExpr, IRExprKind, IRFunction, IRProgram, IRStmt, TypeKind, UnaryOp};
+use std::collections::HashMap;
+
+/// The most statements and expressions a function's body can have for
+/// calls to it to be inlined.
+const INLINE_SIZE: usize = 16;
+
+/// Inlines calls to small functions, which otherwise each pay for a shadow
+/// stack frame and a `call_indirect`.
+///
+/// A function can be inlined if its body is small, ends in its only
+/// `return`, and doesn't read its environment, make closures or `raise`. A
+/// call is inlined when it's known which function it calls: a top-level
+/// one it names, the one a `LocalClosure` put in a local nothing else
+/// assigns, or in the field of an environment such a local was captured
+/// into. Nothing its statement
+/// evaluates before the call may be told apart from running after it, so
+/// the inlined body can run before the statement:
+///
+/// ```text
+/// total = total + twice(n);
+/// ```
+///
+/// becomes
+///
+/// ```text
+/// twice.n = n;
+/// twice.return = twice.n * 2;
+/// total = total + twice.return;
+/// ```
+///
+/// The params and locals of the function inlined are renumbered into new
+/// locals of the caller. Functions no longer called are left for dead code
+/// elimination to drop.
+pub fn inline_calls(program: &mut IRProgram) {
+    let inlinable: HashMap<u32, IRFunction> = program
+        .functions
+        .iter()
+        .filter(|func| inlinable(func))
+        .map(|func| (func.func_index, func.clone()))
+        .collect();
+    if inlinable.is_empty() {
+        return;
+    }
+
+    let IRProgram {
+        structs, functions, ..
+    } = program;
+    let targets = Targets::new(structs, functions);
+    for func in functions.iter_mut() {
+        let caller = func.func_index;
+        let target = |call: &IRExpr| -> Option<&IRFunction> {
+            let IRExprKind::Call { callee, args } = &call.node else {
+                return None;
+            };
+            let target = inlinable.get(&targets.target(caller, callee)?)?;
+            let matching = target.params.len() == args.len()
+                && target
+                    .params
+                    .iter()
+                    .zip(args)
+                    .all(|(ty, arg)| *ty == arg.ty)
+                && target.returns == call.ty;
+            matching.then_some(target)
+        };
+
+        let mut body = std::mem::take(&mut func.body);
+        for_each_block(&mut body, &mut |block| {
+            for mut stmt in std::mem::take(block) {
+                if let Some(expr) = evaluated_first(&mut stmt) {
+                    while let Lead::Call(path) = leading(expr, &|call| target(call).is_some()) {
+                        let call = at(expr, &path);
+                        let target = target(call).unwrap();
+                        block.extend(inline(func, call, target));
+                    }
+                }
+                // A call on its own leaves nothing to run once inlined.
+                if !matches!(
+                    &stmt,
+                    IRStmt::Expr(IRExpr {
+                        node: IRExprKind::Local(_),
+                        ..
+                    })
+                ) {
+                    block.push(stmt);
+                }
+            }
+        });
+        func.body = body;
+    }
+}
+
+/// Whether `func` is small enough to inline and can run inside its caller.
+fn inlinable(func: &IRFunction) -> bool {
+    let Some((IRStmt::Return(Some(value)), rest)) = func.body.split_last() else {
+        return false;
+    };
+    if value.ty != func.returns || func.resumable {
+        return false;
+    }
+    let mut size = 0;
+    let mut inside = true;
+    walk(rest, &mut |stmt| {
+        size += 1;
+        if matches!(
+            stmt,
+            IRStmt::Return(_)
+                | IRStmt::Raise(_)
+                | IRStmt::Produce(_)
+                | IRStmt::LocalClosure { .. }
+                | IRStmt::Suspend
+                | IRStmt::Exit
+        ) {
+            inside = false;
+        }
+    });
+    for stmt in &func.body {
+        stmt.visit_exprs(&mut |expr| {
+            size += 1;
+            if matches!(
+                expr.node,
+                IRExprKind::Local(2) | IRExprKind::Match { .. } | IRExprKind::AsyncState
+            ) {
+                inside = false;
+            }
+        });
+    }
+    inside && size <= INLINE_SIZE
+}
+
+/// Adds `target`'s params, locals and result to `func`'s locals, and
+/// replaces `call` with a read of the result. Returns the statements to run
+/// before the call's statement: the arguments assigned to the params, then
+/// `target`'s body, assigning the result instead of returning it.
+fn inline(func: &mut IRFunction, call: &mut IRExpr, target: &IRFunction) -> Vec<IRStmt> {
+    let first = 3 + func.params.len() + func.locals.len();
+    let count = target.params.len() + target.locals.len();
+    let result = (first + count) as u32;
+    func.local_names.resize(first - 3, String::new());
+    func.locals
+        .extend(target.params.iter().chain(&target.locals).cloned());
+    func.locals.push(target.returns.clone());
+    func.local_names.extend((0..count).map(|local| {
+        let name = target.local_names.get(local).map_or("", String::as_str);
+        format!("{}.{}", target.name, name)
+    }));
+    func.local_names.push(format!("{}.return", target.name));
+
+    let IRExprKind::Call { args, .. } =
+        std::mem::replace(&mut call.node, IRExprKind::Local(result))
+    else {
+        unreachable!("only calls are inlined");
+    };
+    let mut stmts: Vec<IRStmt> = args
+        .into_iter()
+        .enumerate()
+        .map(|(param, value)| IRStmt::LocalSet {
+            index: (first + param) as u32,
+            value,
+        })
+        .collect();
+    let mut body = target.body.clone();
+    let Some(IRStmt::Return(Some(mut value))) = body.pop() else {
+        unreachable!("inlined functions end in a return");
+    };
+    for stmt in &mut body {
+        renumber(stmt, first as u32);
+    }
+    renumber_expr(&mut value, first as u32);
+    stmts.extend(body);
+    stmts.push(IRStmt::LocalSet {
+        index: result,
+        value,
+    });
+    stmts
+}
+
+/// What a statement evaluates up to the first call, found by `leading`.
+enum Lead {
+    /// Nothing a call could tell apart from running after it.
+    Pure,
+    /// The first call, which can be inlined, as the path to it through the
+    /// `children` of each expression, innermost first.
+    Call(Vec<usize>),
+    /// Something else first.
+    Blocked,
+}
+
+/// The expression `stmt` evaluates first, before anything else it does.
+fn evaluated_first(stmt: &mut IRStmt) -> Option<&mut IRExpr> {
+    match stmt {
+        IRStmt::Expr(expr)
+        | IRStmt::LocalSet { value: expr, .. }
+        | IRStmt::Return(Some(expr))
+        | IRStmt::Print(expr)
+        | IRStmt::Raise(expr)
+        | IRStmt::If {
+            condition: expr, ..
+        } => Some(expr),
+        _ => None,
+    }
+}
+
+/// Walks `expr` in the order it's evaluated to the first call, and finds
+/// whether `inlined` accepts it and nothing before it matters.
+fn leading(expr: &IRExpr, inlined: &dyn Fn(&IRExpr) -> bool) -> Lead {
+    match &expr.node {
+        IRExprKind::Integer(_)
+        | IRExprKind::Float(_)
+        | IRExprKind::Boolean(_)
+        | IRExprKind::Null
+        | IRExprKind::Local(_)
+        | IRExprKind::Function(_) => Lead::Pure,
+        IRExprKind::Binary {
+            left,
+            op: BinaryOp::Is,
+            right,
+        } if matches!(left.node, IRExprKind::Local(_)) => in_order([(1, &**right)], inlined),
+        IRExprKind::Binary {
+            left,
+            op: BinaryOp::And | BinaryOp::Or,
+            right,
+        } => match in_order([(0, &**left)], inlined) {
+            // The right only runs depending on the left.
+            Lead::Pure => match leading(right, inlined) {
+                Lead::Pure => Lead::Pure,
+                _ => Lead::Blocked,
+            },
+            lead => lead,
+        },
+        // Operands run before their operator, which only matters to a call
+        // after it.
+        IRExprKind::Binary { left, op, right } => {
+            match in_order([(0, &**left), (1, &**right)], inlined) {
+                Lead::Pure if !(plain_op(op) && plain(left) && plain(right)) => Lead::Blocked,
+                lead => lead,
+            }
+        }
+        IRExprKind::Unary { op, expr: operand } => match in_order([(0, &**operand)], inlined) {
+            Lead::Pure if !(matches!(op, UnaryOp::Not | UnaryOp::Minus) && plain(operand)) => {
+                Lead::Blocked
+            }
+            lead => lead,
+        },
+        IRExprKind::Field { object, .. } => match in_order([(0, &**object)], inlined) {
+            Lead::Pure => Lead::Blocked,
+            lead => lead,
+        },
+        IRExprKind::Call { callee, args } => {
+            let known = match &callee.node {
+                IRExprKind::Local(_) | IRExprKind::Function(_) => true,
+                IRExprKind::Field { object, .. } => matches!(object.node, IRExprKind::Local(2)),
+                _ => false,
+            };
+            if !known {
+                return Lead::Blocked;
+            }
+            match in_order(
+                args.iter().enumerate().map(|(i, arg)| (i + 1, arg)),
+                inlined,
+            ) {
+                Lead::Pure if inlined(expr) => Lead::Call(vec![]),
+                Lead::Pure => Lead::Blocked,
+                lead => lead,
+            }
+        }
+        _ => Lead::Blocked,
+    }
+}
+
+/// `leading` over `exprs`, the children of an expression at their indices,
+/// evaluated in order.
+fn in_order<'e>(
+    exprs: impl IntoIterator<Item = (usize, &'e IRExpr)>,
+    inlined: &dyn Fn(&IRExpr) -> bool,
+) -> Lead {
+    for (index, expr) in exprs {
+        match leading(expr, inlined) {
+            Lead::Pure => {}
+            Lead::Call(mut path) => {
+                path.push(index);
+                return Lead::Call(path);
+            }
+            Lead::Blocked => return Lead::Blocked,
+        }
+    }
+    Lead::Pure
+}
+
+/// Whether `op` never traps or allocates on numbers and booleans.
+fn plain_op(op: &BinaryOp) -> bool {
+    !matches!(
+        op,
+        BinaryOp::Divide | BinaryOp::Modulo | BinaryOp::Power | BinaryOp::In | BinaryOp::Is
+    )
+}
+
+fn plain(expr: &IRExpr) -> bool {
+    matches!(
+        expr.ty.kind,
+        TypeKind::Integer | TypeKind::Float | TypeKind::Boolean
+    ) && !expr.ty.nullable
+        && !expr.ty.errorable
+}
+
+/// The expression at `path` in `expr`.
+fn at<'e>(expr: &'e mut IRExpr, path: &[usize]) -> &'e mut IRExpr {
+    path.iter().rev().fold(expr, |expr, index| {
+        children(expr).into_iter().nth(*index).unwrap()
+    })
+}
+
+/// Moves the locals `stmt` uses past the environment to start at `first`.
+fn renumber(stmt: &mut IRStmt, first: u32) {
+    let block = |block: &mut Vec<IRStmt>| {
+        for stmt in block {
+            renumber(stmt, first);
+        }
+    };
+    match stmt {
+        IRStmt::LocalSet { index, value } => {
+            *index = *index - 3 + first;
+            renumber_expr(value, first);
+        }
+        IRStmt::If {
+            condition,
+            then_block,
+            else_block,
+        } => {
+            renumber_expr(condition, first);
+            block(then_block);
+            if let Some(else_block) = else_block {
+                block(else_block);
+            }
+        }
+        IRStmt::For {
+            init,
+            condition,
+            update,
+            body,
+        } => {
+            renumber(init, first);
+            renumber_expr(condition, first);
+            renumber(update, first);
+            block(body);
+        }
+        IRStmt::While { condition, body } => {
+            renumber_expr(condition, first);
+            block(body);
+        }
+        IRStmt::Unchecked { body } => block(body),
+        _ => {
+            for expr in own_exprs(stmt) {
+                renumber_expr(expr, first);
+            }
+        }
+    }
+}
+
+fn renumber_expr(expr: &mut IRExpr, first: u32) {
+    if let IRExprKind::Local(local) = &mut expr.node {
+        *local = *local - 3 + first;
+    }
+    for child in children(expr) {
+        renumber_expr(child, first);
+    }
+}
@@ -0,0 +1,422 @@
+use crate::ast::aast::{AnalyzedExpr, AnalyzedStatement, Expr};
+use crate::ast::{BinaryOp, Builtin, Type, TypeKind, UnaryOp};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Rewrites every `match` in `body` into nested `if`s. The matched value is
+/// stored in the match's binding and the arms are tried in order, each
+/// `produce` assigning the match's result local, which the expression the
+/// match was in then reads. Since statements can't go inside expressions,
+/// they run before the statement the match was in, and a loop condition
+/// with a match in it moves into the loop's body.
+///
+/// `if let` and `while let` become an `if` and a `while` too, storing the
+/// value in a hidden local, testing that it's neither null nor an error,
+/// and binding the name to it unwrapped at the start of the block.
+pub fn lower_matches(body: &[AnalyzedStatement]) -> Vec<AnalyzedStatement> {
+    body.iter().flat_map(lower_stmt).collect()
+}
+
+fn lower_stmt(stmt: &AnalyzedStatement) -> Vec<AnalyzedStatement> {
+    let mut out = vec![];
+    let lowered = match stmt {
+        AnalyzedStatement::Expr(expr) => {
+            let value = hoist(expr, &mut out);
+            // A match on its own is run for its arms, not its value.
+            if let Expr::Match { .. } = expr.expr {
+                return out;
+            }
+            AnalyzedStatement::Expr(value)
+        }
+        AnalyzedStatement::Let {
+            name,
+            ty,
+            value,
+            captured,
+            index,
+        } => AnalyzedStatement::Let {
+            name: name.clone(),
+            ty: ty.clone(),
+            value: value.as_ref().map(|value| hoist(value, &mut out)),
+            captured: captured.clone(),
+            index: *index,
+        },
+        AnalyzedStatement::Const {
+            name,
+            ty,
+            value,
+            captured,
+            index,
+        } => AnalyzedStatement::Const {
+            name: name.clone(),
+            ty: ty.clone(),
+            value: hoist(value, &mut out),
+            captured: captured.clone(),
+            index: *index,
+        },
+        AnalyzedStatement::Return(value) => {
+            AnalyzedStatement::Return(value.as_ref().map(|value| hoist(value, &mut out)))
+        }
+        AnalyzedStatement::Print(expr) => AnalyzedStatement::Print(hoist(expr, &mut out)),
+        AnalyzedStatement::Produce(expr) => AnalyzedStatement::Produce(hoist(expr, &mut out)),
+        AnalyzedStatement::Raise(expr) => AnalyzedStatement::Raise(hoist(expr, &mut out)),
+        AnalyzedStatement::If {
+            condition,
+            then_block,
+            else_block,
+        } => AnalyzedStatement::If {
+            condition: hoist(condition, &mut out),
+            then_block: lower_matches(then_block),
+            else_block: else_block.as_deref().map(lower_matches),
+        },
+        AnalyzedStatement::While { condition, body } => {
+            let (condition, body) = loop_condition(condition, lower_matches(body));
+            AnalyzedStatement::While { condition, body }
+        }
+        AnalyzedStatement::For {
+            init,
+            condition,
+            update,
+            body,
+        } => {
+            out.extend(lower_stmt(init));
+            let init = out.pop().unwrap();
+            let mut update = lower_stmt(update);
+            let update = match update.len() {
+                1 => update.pop().unwrap(),
+                _ => AnalyzedStatement::If {
+                    condition: boolean_literal(true),
+                    then_block: update,
+                    else_block: None,
+                },
+            };
+            let (condition, body) = loop_condition(condition, lower_matches(body));
+            AnalyzedStatement::For {
+                init: Box::new(init),
+                condition,
+                update: Box::new(update),
+                body,
+            }
+        }
+        AnalyzedStatement::Unchecked { body } => AnalyzedStatement::Unchecked {
+            body: lower_matches(body),
+        },
+        AnalyzedStatement::Defer { body } => AnalyzedStatement::Defer {
+            body: lower_matches(body),
+        },
+        AnalyzedStatement::IfLet {
+            name,
+            value,
+            captured,
+            holder,
+            index,
+            then_block,
+            else_block,
+        } => {
+            let value = hoist(value, &mut out);
+            let (hold, holds, bind) = let_binding(name, value, captured, *holder, *index);
+            out.push(hold);
+            let mut then_lowered = vec![bind];
+            then_lowered.extend(lower_matches(then_block));
+            AnalyzedStatement::If {
+                condition: holds,
+                then_block: then_lowered,
+                else_block: else_block.as_deref().map(lower_matches),
+            }
+        }
+        // The value is taken again each time round the loop, which leaves
+        // once it doesn't hold one.
+        AnalyzedStatement::WhileLet {
+            name,
+            value,
+            captured,
+            holder,
+            index,
+            body,
+        } => {
+            let mut checked = vec![];
+            let value = hoist(value, &mut checked);
+            let (hold, holds, bind) = let_binding(name, value, captured, *holder, *index);
+            checked.push(hold);
+            checked.push(AnalyzedStatement::If {
+                condition: AnalyzedExpr {
+                    expr: Expr::Unary {
+                        op: UnaryOp::Not,
+                        expr: Box::new(holds),
+                    },
+                    ty: plain(TypeKind::Boolean),
+                },
+                then_block: vec![AnalyzedStatement::Break(0)],
+                else_block: None,
+            });
+            checked.push(bind);
+            checked.extend(lower_matches(body));
+            AnalyzedStatement::While {
+                condition: boolean_literal(true),
+                body: checked,
+            }
+        }
+        // Functions are lowered when they are flattened.
+        other => other.clone(),
+    };
+    out.push(lowered);
+    out
+}
+
+/// The condition and body of a loop. A condition with a match in it
+/// becomes `true`, with the body starting by leaving the loop once the
+/// condition is false.
+fn loop_condition(
+    condition: &AnalyzedExpr,
+    body: Vec<AnalyzedStatement>,
+) -> (AnalyzedExpr, Vec<AnalyzedStatement>) {
+    let mut checked = vec![];
+    let condition = hoist(condition, &mut checked);
+    if checked.is_empty() {
+        return (condition, body);
+    }
+    checked.push(AnalyzedStatement::If {
+        condition: AnalyzedExpr {
+            expr: Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(condition),
+            },
+            ty: plain(TypeKind::Boolean),
+        },
+        then_block: vec![AnalyzedStatement::Break(0)],
+        else_block: None,
+    });
+    checked.extend(body);
+    (boolean_literal(true), checked)
+}
+
+/// The statement storing an `if let` or `while let`'s value in `holder`,
+/// the condition that it holds one, and the statement binding `name` to it.
+fn let_binding(
+    name: &str,
+    value: AnalyzedExpr,
+    captured: &Rc<RefCell<Option<String>>>,
+    holder: u32,
+    index: u32,
+) -> (AnalyzedStatement, AnalyzedExpr, AnalyzedStatement) {
+    let held = AnalyzedExpr {
+        expr: Expr::Identifier {
+            name: "let".to_string(),
+            index: Some(holder),
+        },
+        ty: value.ty.clone(),
+    };
+    let hold = AnalyzedStatement::Let {
+        name: "let".to_string(),
+        ty: value.ty.clone(),
+        value: Some(value),
+        captured: Rc::new(RefCell::new(None)),
+        index: Some(holder),
+    };
+    let holds = AnalyzedExpr {
+        expr: Expr::Builtin {
+            builtin: Builtin::Holds,
+            args: vec![held.clone()],
+        },
+        ty: plain(TypeKind::Boolean),
+    };
+    let mut unwrapped = held;
+    if unwrapped.ty.errorable {
+        let ty = Type {
+            errorable: false,
+            errors: vec![],
+            ..unwrapped.ty.clone()
+        };
+        unwrapped = AnalyzedExpr {
+            expr: Expr::UnwrapError(Box::new(unwrapped)),
+            ty,
+        };
+    }
+    if unwrapped.ty.nullable {
+        let ty = Type {
+            nullable: false,
+            ..unwrapped.ty.clone()
+        };
+        unwrapped = AnalyzedExpr {
+            expr: Expr::UnwrapNull(Box::new(unwrapped)),
+            ty,
+        };
+    }
+    let bind = AnalyzedStatement::Let {
+        name: name.to_string(),
+        ty: unwrapped.ty.clone(),
+        value: Some(unwrapped),
+        captured: Rc::clone(captured),
+        index: Some(index),
+    };
+    (hold, holds, bind)
+}
+
+/// `expr` with each match in it replaced by a read of its result, after
+/// adding the statements computing the result to `out`.
+fn hoist(expr: &AnalyzedExpr, out: &mut Vec<AnalyzedStatement>) -> AnalyzedExpr {
+    let lowered = match &expr.expr {
+        Expr::Match {
+            expr: matched,
+            binding,
+            captured,
+            index,
+            result,
+            arms,
+        } => {
+            let value = hoist(matched, out);
+            out.push(AnalyzedStatement::Let {
+                name: binding.clone(),
+                ty: matched.ty.clone(),
+                value: Some(value),
+                captured: captured.clone(),
+                index: Some(*index),
+            });
+            let result = AnalyzedExpr {
+                expr: Expr::Identifier {
+                    name: "match".to_string(),
+                    index: Some(*result),
+                },
+                ty: expr.ty.clone(),
+            };
+            // Built from the last arm back, each arm the `else` of the one
+            // before it.
+            let mut chain: Vec<AnalyzedStatement> = vec![];
+            for (condition, body) in arms.iter().rev() {
+                let body = produce_into(lower_matches(body), &result);
+                chain = match condition {
+                    Some(condition) => vec![AnalyzedStatement::If {
+                        condition: condition.clone(),
+                        then_block: body,
+                        else_block: (!chain.is_empty()).then_some(chain),
+                    }],
+                    None => body,
+                };
+            }
+            out.extend(chain);
+            return result;
+        }
+        Expr::List(items) => Expr::List(hoist_all(items, out)),
+        Expr::Array(items) => Expr::Array(hoist_all(items, out)),
+        Expr::Field { object, field } => Expr::Field {
+            object: Box::new(hoist(object, out)),
+            field: field.clone(),
+        },
+        Expr::Index { object, key } => Expr::Index {
+            object: Box::new(hoist(object, out)),
+            key: Box::new(hoist(key, out)),
+        },
+        Expr::New { name, fields } => Expr::New {
+            name: name.clone(),
+            fields: fields
+                .iter()
+                .map(|(field, value)| (field.clone(), hoist(value, out)))
+                .collect(),
+        },
+        Expr::Binary { left, op, right } => Expr::Binary {
+            left: Box::new(hoist(left, out)),
+            op: op.clone(),
+            right: Box::new(hoist(right, out)),
+        },
+        Expr::Unary { op, expr } => Expr::Unary {
+            op: op.clone(),
+            expr: Box::new(hoist(expr, out)),
+        },
+        Expr::Call { callee, args } => Expr::Call {
+            callee: Box::new(hoist(callee, out)),
+            args: hoist_all(args, out),
+        },
+        Expr::Builtin { builtin, args } => Expr::Builtin {
+            builtin: *builtin,
+            args: hoist_all(args, out),
+        },
+        Expr::ExternCall { function, args } => Expr::ExternCall {
+            function: function.clone(),
+            args: hoist_all(args, out),
+        },
+        Expr::Slice { expr, start, end } => Expr::Slice {
+            expr: Box::new(hoist(expr, out)),
+            start: Box::new(hoist(start, out)),
+            end: Box::new(hoist(end, out)),
+        },
+        Expr::UnwrapError(expr) => Expr::UnwrapError(Box::new(hoist(expr, out))),
+        Expr::UnwrapNull(expr) => Expr::UnwrapNull(Box::new(hoist(expr, out))),
+        Expr::Null
+        | Expr::Integer(_)
+        | Expr::Float(_)
+        | Expr::String(_)
+        | Expr::Boolean(_)
+        | Expr::Identifier { .. }
+        | Expr::Global { .. }
+        | Expr::Function { .. } => expr.expr.clone(),
+    };
+    AnalyzedExpr {
+        expr: lowered,
+        ty: expr.ty.clone(),
+    }
+}
+
+fn hoist_all(exprs: &[AnalyzedExpr], out: &mut Vec<AnalyzedStatement>) -> Vec<AnalyzedExpr> {
+    exprs.iter().map(|expr| hoist(expr, out)).collect()
+}
+
+/// `body` with each `produce` of its match assigning `result` instead.
+fn produce_into(body: Vec<AnalyzedStatement>, result: &AnalyzedExpr) -> Vec<AnalyzedStatement> {
+    let block = |body: Vec<AnalyzedStatement>| produce_into(body, result);
+    body.into_iter()
+        .map(|stmt| match stmt {
+            AnalyzedStatement::Produce(value) => AnalyzedStatement::Expr(AnalyzedExpr {
+                expr: Expr::Binary {
+                    left: Box::new(result.clone()),
+                    op: BinaryOp::Is,
+                    right: Box::new(value),
+                },
+                ty: result.ty.clone(),
+            }),
+            AnalyzedStatement::If {
+                condition,
+                then_block,
+                else_block,
+            } => AnalyzedStatement::If {
+                condition,
+                then_block: block(then_block),
+                else_block: else_block.map(block),
+            },
+            AnalyzedStatement::While { condition, body } => AnalyzedStatement::While {
+                condition,
+                body: block(body),
+            },
+            AnalyzedStatement::For {
+                init,
+                condition,
+                update,
+                body,
+            } => AnalyzedStatement::For {
+                init,
+                condition,
+                update,
+                body: block(body),
+            },
+            AnalyzedStatement::Unchecked { body } => {
+                AnalyzedStatement::Unchecked { body: block(body) }
+            }
+            other => other,
+        })
+        .collect()
+}
+
+fn boolean_literal(value: bool) -> AnalyzedExpr {
+    AnalyzedExpr {
+        expr: Expr::Boolean(value),
+        ty: plain(TypeKind::Boolean),
+    }
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
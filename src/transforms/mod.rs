@@ -1,5 +1,18 @@
+mod asyncify;
+mod dce;
+mod direct;
 mod flatten;
+mod inline;
+mod matches;
+mod roots;
+mod tailcall;
+mod targets;
 mod wrap;
 
-pub use flatten::{Flattener, segregate_fields};
+pub use asyncify::make_resumable;
+pub use dce::eliminate_dead_code;
+pub use direct::call_directly;
+pub use inline::inline_calls;
+pub use flatten::{Flattener, field_slots, segregate_fields};
+pub use roots::find_unrooted;
 pub use wrap::Wrapper;
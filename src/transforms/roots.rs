@@ -0,0 +1,257 @@
+use crate::ast::{
+    BinaryOp, Builtin, IRExpr, IRExprKind, IRFunction, IRProgram, IRStmt, TypeKind, UnaryOp,
+};
+use std::collections::HashSet;
+
+/// Finds the params and locals of each function that never hold a value
+/// still to be read while a collection can happen, so codegen can leave
+/// them out of the function's shadow stack frame.
+///
+/// Only allocating can collect, so a function collects when it allocates
+/// itself, calls through a closure, or calls a function that collects
+/// directly. A statement that can collect roots the locals it reads and
+/// those live after it; the locals no such statement roots go unrooted.
+/// Resumable functions, functions with a match left in them and those
+/// without a frame are skipped.
+pub fn find_unrooted(program: &mut IRProgram) {
+    let mut collecting = HashSet::new();
+    loop {
+        let more: Vec<u32> = program
+            .functions
+            .iter()
+            .filter(|func| !collecting.contains(&func.func_index))
+            .filter(|func| func.body.iter().any(|stmt| collects(stmt, &collecting)))
+            .map(|func| func.func_index)
+            .collect();
+        if more.is_empty() {
+            break;
+        }
+        collecting.extend(more);
+    }
+
+    for func in &mut program.functions {
+        if func.resumable || func.noalloc || has_match(func) {
+            continue;
+        }
+        let mut liveness = Liveness {
+            collecting: &collecting,
+            rooted: HashSet::new(),
+            loops: vec![],
+        };
+        liveness.block(&func.body, HashSet::new());
+        let count = (func.params.len() + func.locals.len()) as u32;
+        func.unrooted = (3..3 + count)
+            .filter(|local| !liveness.rooted.contains(local))
+            .collect();
+    }
+}
+
+/// Whether `stmt`, or a statement nested in it, can collect, counting
+/// direct calls to the functions in `collecting`.
+fn collects(stmt: &IRStmt, collecting: &HashSet<u32>) -> bool {
+    let mut collects = matches!(stmt, IRStmt::LocalClosure { .. } | IRStmt::Suspend);
+    stmt.visit_exprs(&mut |expr| collects |= allocates(expr, collecting));
+    collects
+}
+
+/// Whether evaluating `expr` itself, not counting its operands, can
+/// allocate.
+fn allocates(expr: &IRExpr, collecting: &HashSet<u32>) -> bool {
+    match &expr.node {
+        IRExprKind::String(_)
+        | IRExprKind::List(_)
+        | IRExprKind::Array(_)
+        | IRExprKind::New { .. }
+        | IRExprKind::Call { .. }
+        | IRExprKind::Match { .. }
+        | IRExprKind::Slice { .. }
+        | IRExprKind::SliceReference { .. } => true,
+        // The host can call back into the program.
+        IRExprKind::ExternCall { .. } => true,
+        IRExprKind::CallDirect { fn_index, .. } => collecting.contains(fn_index),
+        IRExprKind::Index { list, .. } => list.ty.kind == TypeKind::String,
+        IRExprKind::Binary {
+            op: BinaryOp::Plus | BinaryOp::Multiply,
+            ..
+        } => matches!(expr.ty.kind, TypeKind::String | TypeKind::List { .. }),
+        IRExprKind::Unary {
+            op: UnaryOp::Stringify,
+            ..
+        } => true,
+        IRExprKind::Builtin { builtin, .. } => !matches!(
+            builtin,
+            Builtin::ToInteger
+                | Builtin::ToFloat
+                | Builtin::Abs
+                | Builtin::Min
+                | Builtin::Max
+                | Builtin::Sqrt
+                | Builtin::Floor
+                | Builtin::Ceil
+                | Builtin::Round
+                | Builtin::Sin
+                | Builtin::Cos
+                | Builtin::Tan
+                | Builtin::Asin
+                | Builtin::Acos
+                | Builtin::Atan
+                | Builtin::Atan2
+                | Builtin::StringLength
+                | Builtin::StringFind
+                | Builtin::Ord
+                | Builtin::ParseInteger
+                | Builtin::ParseFloat
+                | Builtin::ParseFailed
+                | Builtin::CompareStrings
+                | Builtin::Present
+                | Builtin::Holds
+                | Builtin::HoldsError
+                | Builtin::HeldError
+                | Builtin::HeapUsed
+                | Builtin::HeapFree
+                | Builtin::GcCount
+                | Builtin::AllocatedSinceGc
+                | Builtin::LargestFreeBlock
+                | Builtin::SetGcThreshold
+        ),
+        _ => false,
+    }
+}
+
+fn has_match(func: &IRFunction) -> bool {
+    let mut found = false;
+    for stmt in &func.body {
+        stmt.visit_exprs(&mut |expr| found |= matches!(expr.node, IRExprKind::Match { .. }));
+    }
+    found
+}
+
+/// Walks a function's body backwards, tracking the locals live at each
+/// point.
+struct Liveness<'a> {
+    collecting: &'a HashSet<u32>,
+    /// The locals live, or read, where a collection can happen.
+    rooted: HashSet<u32>,
+    /// The locals live at the condition and after each loop being walked,
+    /// innermost last.
+    loops: Vec<(HashSet<u32>, HashSet<u32>)>,
+}
+
+impl Liveness<'_> {
+    /// The locals live before `stmts`, given those live after them.
+    fn block(&mut self, stmts: &[IRStmt], mut live: HashSet<u32>) -> HashSet<u32> {
+        for stmt in stmts.iter().rev() {
+            live = self.stmt(stmt, live);
+        }
+        live
+    }
+
+    fn stmt(&mut self, stmt: &IRStmt, live: HashSet<u32>) -> HashSet<u32> {
+        match stmt {
+            IRStmt::Expr(expr) | IRStmt::Print(expr) => self.expr(expr, live),
+            IRStmt::LocalSet { index, value } => {
+                let mut live = live;
+                live.remove(index);
+                self.expr(value, live)
+            }
+            IRStmt::Return(Some(expr)) | IRStmt::Raise(expr) | IRStmt::Produce(expr) => {
+                self.expr(expr, HashSet::new())
+            }
+            IRStmt::Return(None) | IRStmt::Exit => HashSet::new(),
+            IRStmt::Break(out) => self.enclosing_loop(*out).1.clone(),
+            IRStmt::Continue(out) => self.enclosing_loop(*out).0.clone(),
+            IRStmt::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let mut branches = self.block(then_block, live.clone());
+                match else_block {
+                    Some(else_block) => branches.extend(self.block(else_block, live)),
+                    None => branches.extend(live),
+                }
+                self.expr(condition, branches)
+            }
+            IRStmt::While { condition, body } => self.run_loop(live, |this, head| {
+                let mut after = this.block(body, head.clone());
+                after.extend(this.loops.last().unwrap().1.iter().copied());
+                this.expr(condition, after)
+            }),
+            IRStmt::For {
+                init,
+                condition,
+                update,
+                body,
+            } => {
+                let head = self.run_loop(live, |this, head| {
+                    let updated = this.stmt(update, head.clone());
+                    let mut after = this.block(body, updated);
+                    after.extend(this.loops.last().unwrap().1.iter().copied());
+                    this.expr(condition, after)
+                });
+                self.stmt(init, head)
+            }
+            IRStmt::Unchecked { body } => self.block(body, live),
+            IRStmt::LocalClosure {
+                captures, index, ..
+            } => {
+                let mut live = live;
+                live.remove(index);
+                live.extend(reads(captures));
+                self.rooted.extend(live.iter().copied());
+                live
+            }
+            IRStmt::Suspend => {
+                self.rooted.extend(live.iter().copied());
+                live
+            }
+        }
+    }
+
+    /// The locals live at a loop's condition, walking it with `walk` from
+    /// what's live at its condition until that stops changing.
+    fn run_loop(
+        &mut self,
+        exit: HashSet<u32>,
+        walk: impl Fn(&mut Self, &HashSet<u32>) -> HashSet<u32>,
+    ) -> HashSet<u32> {
+        let mut head = exit.clone();
+        loop {
+            self.loops.push((head.clone(), exit.clone()));
+            let walked = walk(self, &head);
+            self.loops.pop();
+            if walked == head {
+                return head;
+            }
+            head.extend(walked);
+        }
+    }
+
+    /// What's live at the head and exit of the loop `out` loops out from
+    /// the innermost.
+    fn enclosing_loop(&self, out: u32) -> &(HashSet<u32>, HashSet<u32>) {
+        &self.loops[self.loops.len() - 1 - out as usize]
+    }
+
+    /// The locals live before `expr`, rooting those live around it if it
+    /// can collect.
+    fn expr(&mut self, expr: &IRExpr, mut live: HashSet<u32>) -> HashSet<u32> {
+        let mut collects = false;
+        expr.visit(&mut |expr| collects |= allocates(expr, self.collecting));
+        live.extend(reads(expr));
+        if collects {
+            self.rooted.extend(live.iter().copied());
+        }
+        live
+    }
+}
+
+fn reads(expr: &IRExpr) -> HashSet<u32> {
+    let mut reads = HashSet::new();
+    expr.visit(&mut |expr| {
+        if let IRExprKind::Local(local) = expr.node {
+            reads.insert(local);
+        }
+    });
+    reads
+}
@@ -0,0 +1,178 @@
+use crate::ast::aast::{AnalyzedExpr, AnalyzedStatement, Expr};
+use crate::ast::{BinaryOp, Type, TypeKind};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+type Param = (String, Type, u32, Rc<RefCell<Option<String>>>);
+
+/// Rewrites `return f(...)` inside `f` into reassigning the parameters and
+/// looping, so self tail recursion runs in constant stack space. `self_field`
+/// is the captures field through which `f` refers to itself, if it does,
+/// and `fn_index` its index, by which a top-level `f` names itself. Returns
+/// `None` when the body has no self tail calls, or defers something, which
+/// each call runs as it returns.
+pub fn loop_tail_calls(
+    body: &[AnalyzedStatement],
+    self_field: Option<&str>,
+    fn_index: u32,
+    params: &[Param],
+    locals: &mut Vec<Type>,
+) -> Option<Vec<AnalyzedStatement>> {
+    if body
+        .iter()
+        .any(|stmt| matches!(stmt, AnalyzedStatement::Defer { .. }))
+    {
+        return None;
+    }
+    let mut rewriter = TailCallRewriter {
+        self_field,
+        fn_index,
+        params,
+        locals,
+        found: false,
+    };
+    let rewritten = rewriter.rewrite_block(body);
+    if !rewriter.found {
+        return None;
+    }
+
+    Some(vec![AnalyzedStatement::While {
+        condition: AnalyzedExpr {
+            expr: Expr::Boolean(true),
+            ty: plain(TypeKind::Boolean),
+        },
+        body: rewritten,
+    }])
+}
+
+struct TailCallRewriter<'a> {
+    self_field: Option<&'a str>,
+    fn_index: u32,
+    params: &'a [Param],
+    locals: &'a mut Vec<Type>,
+    found: bool,
+}
+
+impl TailCallRewriter<'_> {
+    /// Rewrites a block whose end is the end of the function. Only the last
+    /// statement is in tail position, except that an `if` whose branch always
+    /// returns makes everything after it the implicit else branch.
+    fn rewrite_block(&mut self, stmts: &[AnalyzedStatement]) -> Vec<AnalyzedStatement> {
+        let mut result = Vec::new();
+
+        for (i, stmt) in stmts.iter().enumerate() {
+            let is_last = i + 1 == stmts.len();
+            match stmt {
+                AnalyzedStatement::Return(Some(value)) => {
+                    if let Some(args) = self.self_call_args(value) {
+                        self.found = true;
+                        result.extend(self.reassign_params(args));
+                    } else {
+                        result.push(stmt.clone());
+                    }
+                    // Anything after a return never runs.
+                    return result;
+                }
+                AnalyzedStatement::If {
+                    condition,
+                    then_block,
+                    else_block,
+                } if is_last || (else_block.is_none() && ends_in_return(then_block)) => {
+                    let rest = &stmts[i + 1..];
+                    let else_block = match else_block {
+                        Some(else_block) => Some(self.rewrite_block(else_block)),
+                        None if rest.is_empty() => None,
+                        None => Some(self.rewrite_block(rest)),
+                    };
+                    result.push(AnalyzedStatement::If {
+                        condition: condition.clone(),
+                        then_block: self.rewrite_block(then_block),
+                        else_block,
+                    });
+                    return result;
+                }
+                _ => result.push(stmt.clone()),
+            }
+        }
+
+        result
+    }
+
+    fn self_call_args<'e>(&self, expr: &'e AnalyzedExpr) -> Option<&'e [AnalyzedExpr]> {
+        let Expr::Call { callee, args } = &expr.expr else {
+            return None;
+        };
+        match &callee.expr {
+            Expr::Function { fn_index, .. } if *fn_index == self.fn_index => Some(args),
+            Expr::Field { object, field } => match object.expr {
+                Expr::Identifier { index: Some(2), .. }
+                    if self.self_field == Some(field.as_str()) =>
+                {
+                    Some(args)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Evaluates every argument into a fresh local before assigning any
+    /// parameter, since later arguments may read earlier parameters.
+    fn reassign_params(&mut self, args: &[AnalyzedExpr]) -> Vec<AnalyzedStatement> {
+        let mut temps = Vec::new();
+        let mut stmts = Vec::new();
+
+        for ((name, ty, index, _), arg) in self.params.iter().zip(args) {
+            if matches!(arg.expr, Expr::Identifier { index: Some(i), .. } if i == *index) {
+                continue;
+            }
+            let temp = 3 + self.params.len() as u32 + self.locals.len() as u32;
+            self.locals.push(ty.clone());
+            stmts.push(assign(format!("{}'", name), temp, ty, arg.clone()));
+            temps.push((name, ty, *index, temp));
+        }
+
+        for (name, ty, index, temp) in temps {
+            let value = AnalyzedExpr {
+                expr: Expr::Identifier {
+                    name: format!("{}'", name),
+                    index: Some(temp),
+                },
+                ty: ty.clone(),
+            };
+            stmts.push(assign(name.clone(), index, ty, value));
+        }
+
+        stmts
+    }
+}
+
+fn ends_in_return(stmts: &[AnalyzedStatement]) -> bool {
+    matches!(stmts.last(), Some(AnalyzedStatement::Return(_)))
+}
+
+fn assign(name: String, index: u32, ty: &Type, value: AnalyzedExpr) -> AnalyzedStatement {
+    AnalyzedStatement::Expr(AnalyzedExpr {
+        expr: Expr::Binary {
+            left: Box::new(AnalyzedExpr {
+                expr: Expr::Identifier {
+                    name,
+                    index: Some(index),
+                },
+                ty: ty.clone(),
+            }),
+            op: BinaryOp::Is,
+            right: Box::new(value),
+        },
+        ty: ty.clone(),
+    })
+}
+
+fn plain(kind: TypeKind) -> Type {
+    Type {
+        kind,
+        nullable: false,
+        errorable: false,
+        errors: vec![],
+    }
+}
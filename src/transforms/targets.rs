@@ -0,0 +1,113 @@
+use crate::ast::{BinaryOp, IRExpr, IRExprKind, IRFunction, IRStmt, IRStruct};
+use std::collections::{HashMap, HashSet};
+
+/// Which function each call through a local or the environment calls, when
+/// it's always the same one, besides calls naming a top-level function.
+pub(super) struct Targets<'a> {
+    structs: &'a [IRStruct],
+    /// The function in each local of each function that only ever holds
+    /// the closure a `LocalClosure` made.
+    closures: HashMap<u32, HashMap<u32, u32>>,
+    /// The function making each closure, and the captures it makes it with.
+    made_by: HashMap<u32, (u32, IRExpr)>,
+}
+
+impl<'a> Targets<'a> {
+    pub(super) fn new(structs: &'a [IRStruct], functions: &[IRFunction]) -> Self {
+        let mut closures = HashMap::new();
+        let mut made_by = HashMap::new();
+        for func in functions {
+            let mut made: HashMap<u32, Vec<u32>> = HashMap::new();
+            let mut assigned = HashSet::new();
+            walk(&func.body, &mut |stmt| match stmt {
+                IRStmt::LocalClosure {
+                    fn_index,
+                    captures,
+                    index,
+                } => {
+                    made.entry(*index).or_default().push(*fn_index);
+                    made_by.insert(*fn_index, (func.func_index, (**captures).clone()));
+                }
+                IRStmt::LocalSet { index, .. } => {
+                    assigned.insert(*index);
+                }
+                _ => {}
+            });
+            for stmt in &func.body {
+                stmt.visit_exprs(&mut |expr| {
+                    if let IRExprKind::Binary {
+                        left,
+                        op: BinaryOp::Is,
+                        ..
+                    } = &expr.node
+                    {
+                        if let IRExprKind::Local(local) = left.node {
+                            assigned.insert(local);
+                        }
+                    }
+                });
+            }
+            let locals = made
+                .into_iter()
+                .filter(|(local, made)| made.len() == 1 && !assigned.contains(local))
+                .map(|(local, made)| (local, made[0]))
+                .collect();
+            closures.insert(func.func_index, locals);
+        }
+        Targets {
+            structs,
+            closures,
+            made_by,
+        }
+    }
+
+    /// The function `callee`, read in `func`, always is.
+    pub(super) fn target(&self, func: u32, callee: &IRExpr) -> Option<u32> {
+        match &callee.node {
+            IRExprKind::Function(fn_index) => Some(*fn_index),
+            IRExprKind::Local(local) => self.closures.get(&func)?.get(local).copied(),
+            IRExprKind::Field { object, offset } if matches!(object.node, IRExprKind::Local(2)) => {
+                let (maker, captures) = self.made_by.get(&func)?;
+                let IRExprKind::New {
+                    struct_index,
+                    fields,
+                } = &captures.node
+                else {
+                    return None;
+                };
+                let position = self.structs[*struct_index as usize]
+                    .offsets
+                    .iter()
+                    .position(|field| field == offset)?;
+                self.target(*maker, &fields[position])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Calls `f` on every statement in `body`, and those nested in them.
+pub(super) fn walk(body: &[IRStmt], f: &mut dyn FnMut(&IRStmt)) {
+    for stmt in body {
+        f(stmt);
+        match stmt {
+            IRStmt::If {
+                then_block,
+                else_block,
+                ..
+            } => {
+                walk(then_block, f);
+                walk(else_block.as_deref().unwrap_or_default(), f);
+            }
+            IRStmt::For {
+                init, update, body, ..
+            } => {
+                walk(std::slice::from_ref(&**init), f);
+                walk(std::slice::from_ref(&**update), f);
+                walk(body, f);
+            }
+            IRStmt::While { body, .. } | IRStmt::Unchecked { body } => walk(body, f),
+            _ => {}
+        }
+    }
+}
@@ -1,13 +1,39 @@
 use crate::ast::aast::{AnalyzedExpr, AnalyzedStatement, Expr};
-use crate::ast::{BinaryOp, Type, TypeKind, UnaryOp};
+use crate::ast::{BinaryOp, Builtin, Type, TypeKind, UnaryOp};
 use crate::error::CompilerError;
-use crate::ast::FlattenedProgram;
+use crate::ast::{FlattenedProgram, IRStructKind};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Tags of the tagged union behind nullable and errorable values. A present
+/// value's tag also says what the value slot holds, so the collector knows
+/// whether to follow it.
+const TAG_NULL: i64 = 0;
+const TAG_ERROR: i64 = 1;
+const TAG_PRIMITIVE: i64 = 2;
+const TAG_STRUCT: i64 = 3;
+const TAG_LIST: i64 = 4;
+
+fn value_tag(ty: &Type) -> i64 {
+    if ty.nullable || ty.errorable {
+        return TAG_STRUCT;
+    }
+    match ty.kind {
+        TypeKind::Struct { .. } => TAG_STRUCT,
+        TypeKind::List { .. } | TypeKind::String => TAG_LIST,
+        _ => TAG_PRIMITIVE,
+    }
+}
 
 pub struct Wrapper {
     functions: HashMap<String, (Vec<Type>, Type)>, // name -> (param_types, return_type)
     structs: HashMap<String, Vec<(String, Type)>>, // name -> fields
     current_return_type: Option<Type>,
+    /// The blocks the function being wrapped has deferred so far, and the
+    /// local its exits hold their value in while they run.
+    deferred: Vec<Vec<AnalyzedStatement>>,
+    exit_local: Option<u32>,
 }
 
 impl Wrapper {
@@ -16,12 +42,16 @@ impl Wrapper {
             functions: HashMap::new(),
             structs: HashMap::new(),
             current_return_type: None,
+            deferred: Vec::new(),
+            exit_local: None,
         }
     }
 
     fn build_lookups(&mut self, program: &FlattenedProgram) {
-        for (stmt, _, _) in &program.structs {
-            if let AnalyzedStatement::Struct { name, fields } = stmt {
+        for (stmt, _, _, _) in &program.structs {
+            if let AnalyzedStatement::Struct { name, fields }
+            | AnalyzedStatement::Error { name, fields } = stmt
+            {
                 self.structs.insert(name.clone(), fields.clone());
             }
         }
@@ -73,13 +103,14 @@ impl Wrapper {
                                     kind: TypeKind::Integer,
                                     nullable: false,
                                     errorable: false,
+                                    errors: vec![],
                                 },
                                 expr: Expr::Integer(if is_raised {
-                                    1
+                                    TAG_ERROR
                                 } else if expr.ty.kind == TypeKind::Null {
-                                    0
+                                    TAG_NULL
                                 } else {
-                                    2
+                                    value_tag(&expr.ty)
                                 }),
                             },
                         ),
@@ -167,6 +198,44 @@ impl Wrapper {
                     message: "Callee is not a function type".to_string(),
                 }),
             },
+            Expr::Builtin { builtin, args } => {
+                let mut wrapped_args = Vec::new();
+                for arg_expr in args {
+                    wrapped_args.push(self.wrap_expr(arg_expr)?);
+                }
+
+                // Values stored as list elements take the element's representation.
+                let stored = match builtin {
+                    Builtin::ListPush => Some((1, wrapped_args[0].ty.kind.clone())),
+                    Builtin::Repeat => Some((0, expr.ty.kind.clone())),
+                    _ => None,
+                };
+                if let Some((i, TypeKind::List { element })) = stored {
+                    let value = wrapped_args[i].clone();
+                    wrapped_args[i] = self.wrap_to_type(value, &element, false);
+                }
+
+                Ok(AnalyzedExpr {
+                    ty: expr.ty.clone(),
+                    expr: Expr::Builtin {
+                        builtin,
+                        args: wrapped_args,
+                    },
+                })
+            }
+            Expr::ExternCall { function, args } => {
+                let mut wrapped_args = Vec::new();
+                for arg_expr in args {
+                    wrapped_args.push(self.wrap_expr(arg_expr)?);
+                }
+                Ok(AnalyzedExpr {
+                    ty: expr.ty.clone(),
+                    expr: Expr::ExternCall {
+                        function,
+                        args: wrapped_args,
+                    },
+                })
+            }
             Expr::Field { object, field } => Ok(AnalyzedExpr {
                 ty: expr.ty.clone(),
                 expr: Expr::Field {
@@ -203,24 +272,44 @@ impl Wrapper {
                     expr: Expr::List(wrapped),
                 })
             }
+            Expr::Array(elements) => {
+                let mut wrapped = Vec::new();
+                for e in elements {
+                    wrapped.push(self.wrap_expr(e)?);
+                }
+                Ok(AnalyzedExpr {
+                    ty: expr.ty.clone(),
+                    expr: Expr::Array(wrapped),
+                })
+            }
             Expr::Match {
                 expr: match_expr,
                 binding,
+                captured,
+                index,
+                result,
                 arms,
             } => {
                 let mut wrapped_arms = Vec::new();
-                for (pattern, stmts) in arms {
+                for (condition, stmts) in arms {
+                    let condition = match condition {
+                        Some(c) => Some(self.wrap_expr(c)?),
+                        None => None,
+                    };
                     let mut wrapped_stmts = Vec::new();
                     for s in stmts {
                         wrapped_stmts.push(self.wrap_stmt(s)?);
                     }
-                    wrapped_arms.push((pattern, wrapped_stmts));
+                    wrapped_arms.push((condition, wrapped_stmts));
                 }
                 Ok(AnalyzedExpr {
                     ty: expr.ty.clone(),
                     expr: Expr::Match {
                         expr: Box::new(self.wrap_expr(*match_expr)?),
                         binding,
+                        captured,
+                        index,
+                        result,
                         arms: wrapped_arms,
                     },
                 })
@@ -238,7 +327,9 @@ impl Wrapper {
             | Expr::Float(_)
             | Expr::String(_)
             | Expr::Boolean(_)
-            | Expr::Identifier { .. } => Ok(expr),
+            | Expr::Identifier { .. }
+            | Expr::Global { .. }
+            | Expr::Function { .. } => Ok(expr),
         }
     }
 
@@ -294,6 +385,7 @@ impl Wrapper {
                             kind: TypeKind::Null,
                             nullable: false,
                             errorable: false,
+                            errors: vec![],
                         },
                     };
                     let wrapped = self.wrap_expr(null_expr)?;
@@ -308,18 +400,9 @@ impl Wrapper {
                 then_block,
                 else_block,
             } => {
-                let mut wrapped_then = Vec::new();
-                for s in then_block {
-                    wrapped_then.push(self.wrap_stmt(s)?);
-                }
+                let wrapped_then = self.wrap_block(then_block)?;
                 let wrapped_else = match else_block {
-                    Some(stmts) => {
-                        let mut result = Vec::new();
-                        for s in stmts {
-                            result.push(self.wrap_stmt(s)?);
-                        }
-                        Some(result)
-                    }
+                    Some(stmts) => Some(self.wrap_block(stmts)?),
                     None => None,
                 };
                 Ok(AnalyzedStatement::If {
@@ -329,25 +412,29 @@ impl Wrapper {
                 })
             }
             AnalyzedStatement::While { condition, body } => {
-                let mut wrapped_body = Vec::new();
-                for s in body {
-                    wrapped_body.push(self.wrap_stmt(s)?);
-                }
+                let wrapped_body = self.wrap_block(body)?;
                 Ok(AnalyzedStatement::While {
                     condition: self.wrap_expr(condition)?,
                     body: wrapped_body,
                 })
             }
+            AnalyzedStatement::Unchecked { body } => {
+                let wrapped_body = self.wrap_block(body)?;
+                Ok(AnalyzedStatement::Unchecked { body: wrapped_body })
+            }
+            AnalyzedStatement::IfLet { .. } | AnalyzedStatement::WhileLet { .. } => {
+                unreachable!("if let and while let are lowered when flattening")
+            }
+            AnalyzedStatement::Defer { .. } => {
+                unreachable!("defer is only at the top of a function's body")
+            }
             AnalyzedStatement::For {
                 init,
                 condition,
                 update,
                 body,
             } => {
-                let mut wrapped_body = Vec::new();
-                for s in body {
-                    wrapped_body.push(self.wrap_stmt(s)?);
-                }
+                let wrapped_body = self.wrap_block(body)?;
                 Ok(AnalyzedStatement::For {
                     init: Box::new(self.wrap_stmt(*init)?),
                     condition: self.wrap_expr(condition)?,
@@ -362,8 +449,8 @@ impl Wrapper {
                 let ret_type = self.current_return_type.as_ref().unwrap().clone();
                 Ok(AnalyzedStatement::Raise(self.wrap_to_type(wrapped_expr, &ret_type, true)))
             }
-            AnalyzedStatement::Break
-            | AnalyzedStatement::Continue
+            AnalyzedStatement::Break(_)
+            | AnalyzedStatement::Continue(_)
             | AnalyzedStatement::Struct { .. }
             | AnalyzedStatement::Error { .. }
             | AnalyzedStatement::Function { .. }
@@ -371,6 +458,62 @@ impl Wrapper {
         }
     }
 
+    /// Wraps each statement of `stmts`. A `defer`'s block is kept back, and
+    /// run before each exit after it, last deferred first.
+    fn wrap_block(
+        &mut self,
+        stmts: Vec<AnalyzedStatement>,
+    ) -> Result<Vec<AnalyzedStatement>, CompilerError> {
+        let mut wrapped = Vec::new();
+        for stmt in stmts {
+            match stmt {
+                AnalyzedStatement::Defer { body } => {
+                    let body = self.wrap_block(body)?;
+                    self.deferred.push(body);
+                }
+                AnalyzedStatement::Return(_) | AnalyzedStatement::Raise(_)
+                    if !self.deferred.is_empty() =>
+                {
+                    let exit = self.wrap_stmt(stmt)?;
+                    wrapped.extend(self.run_deferred(exit));
+                }
+                stmt => wrapped.push(self.wrap_stmt(stmt)?),
+            }
+        }
+        Ok(wrapped)
+    }
+
+    /// The wrapped `return` or `raise` `exit` with the deferred blocks run
+    /// between evaluating its value and leaving, so they can't change it.
+    fn run_deferred(&self, exit: AnalyzedStatement) -> Vec<AnalyzedStatement> {
+        let (value, raised) = match exit {
+            AnalyzedStatement::Return(Some(value)) => (value, false),
+            AnalyzedStatement::Raise(value) => (value, true),
+            _ => unreachable!("returns are wrapped with a value"),
+        };
+        let local = self.exit_local.unwrap();
+        let held = AnalyzedExpr {
+            expr: Expr::Identifier {
+                name: "defer".to_string(),
+                index: Some(local),
+            },
+            ty: value.ty.clone(),
+        };
+        let mut stmts = vec![AnalyzedStatement::Let {
+            name: "defer".to_string(),
+            ty: self.current_return_type.clone().unwrap(),
+            value: Some(value),
+            captured: Rc::new(RefCell::new(None)),
+            index: Some(local),
+        }];
+        stmts.extend(self.deferred.iter().rev().flatten().cloned());
+        stmts.push(match raised {
+            true => AnalyzedStatement::Raise(held),
+            false => AnalyzedStatement::Return(Some(held)),
+        });
+        stmts
+    }
+
     fn wrap_function(&mut self, stmt: AnalyzedStatement) -> Result<AnalyzedStatement, CompilerError> {
         match stmt {
             AnalyzedStatement::Function {
@@ -382,12 +525,29 @@ impl Wrapper {
                 index,
                 fn_index,
                 locals,
+                local_names,
+                exported,
+                noalloc,
             } => {
                 self.current_return_type = Some(returns.clone());
-                let mut wrapped_body = Vec::new();
-                for s in body {
-                    wrapped_body.push(self.wrap_stmt(s)?);
+                let mut locals = locals;
+                if body
+                    .iter()
+                    .any(|stmt| matches!(stmt, AnalyzedStatement::Defer { .. }))
+                {
+                    self.exit_local = Some(3 + params.len() as u32 + locals.len() as u32);
+                    locals.push(returns.clone());
+                }
+                let mut wrapped_body = self.wrap_block(body)?;
+                // Running off the end is an exit too.
+                if !matches!(
+                    wrapped_body.last(),
+                    Some(AnalyzedStatement::Return(_) | AnalyzedStatement::Raise(_))
+                ) {
+                    wrapped_body.extend(self.deferred.iter().rev().flatten().cloned());
                 }
+                self.deferred.clear();
+                self.exit_local = None;
                 self.current_return_type = None;
                 Ok(AnalyzedStatement::Function {
                     name,
@@ -398,6 +558,9 @@ impl Wrapper {
                     index,
                     fn_index,
                     locals,
+                    local_names,
+                    exported,
+                    noalloc,
                 })
             }
             _ => Ok(stmt),
@@ -422,6 +585,7 @@ impl Wrapper {
                             kind: TypeKind::Integer,
                             nullable: false,
                             errorable: false,
+                            errors: vec![],
                         },
                     ),
                     (
@@ -430,12 +594,14 @@ impl Wrapper {
                             kind: TypeKind::Integer,
                             nullable: false,
                             errorable: false,
+                            errors: vec![],
                         },
                     ),
                 ],
             },
             0u32,
             0u32,
+            IRStructKind::User,
         );
 
         let mut structs = vec![tagged_union_struct];
@@ -444,6 +610,7 @@ impl Wrapper {
         Ok(FlattenedProgram {
             structs,
             functions: wrapped_functions,
+            globals: program.globals,
         })
     }
 }
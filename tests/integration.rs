@@ -1,11 +1,206 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use star::host::HostValue;
 use std::sync::{Arc, Mutex};
 use wasmtime::*;
 
-fn run_program(source: &str) -> Result<Vec<String>, String> {
-    let wasm_bytes = star::compile(source).map_err(|e| e.to_string())?;
+fn run_program(path: &Path, host: &Host) -> Result<Vec<String>, String> {
+    let wasm_bytes =
+        star::compile_file(path).map_err(|e| star::error::format_diagnostics(&e))?;
+    let mut runtime = load(&wasm_bytes, host)?;
+    run_main(&mut runtime)?;
+    let result = runtime.output.lock().unwrap().clone();
+    Ok(result)
+}
+
+/// Like `run_program`, built with `--gc-stress` so every allocation
+/// collects first and a value left unrooted is freed under the program.
+fn run_stressed_program(path: &Path, host: &Host) -> Result<Vec<String>, String> {
+    let program = star::parse_file(path).map_err(|e| star::error::format_diagnostics(&e))?;
+    let typed = star::check(&program).map_err(|e| star::error::format_diagnostics(&e))?;
+    let ir = star::lower(&typed).map_err(|e| e.to_string())?;
+    let options = star::CodegenOptions {
+        gc_stress: true,
+        ..Default::default()
+    };
+    let wasm_bytes = star::codegen_with(&ir, options).map_err(|e| e.to_string())?;
+    let mut runtime = load(&wasm_bytes, host)?;
+    run_main(&mut runtime)?;
+    let result = runtime.output.lock().unwrap().clone();
+    Ok(result)
+}
+
+/// What the host hands a program: the arguments `main` receives, from
+/// `// args:` separated by spaces, and the environment variables `env`
+/// reads, from `// env: NAME=value`.
+#[derive(Debug, Default, Clone)]
+struct Host {
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+}
+
+impl Host {
+    fn var(&self, name: &str) -> Option<&str> {
+        self.env
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Collects what a program prints when it runs on the interpreter, and
+/// answers awaits the way `run_main` does.
+#[derive(Default)]
+struct TestIo {
+    output: Vec<String>,
+    host: Host,
+}
+
+impl star::host::Io for TestIo {
+    fn print(&mut self, line: &str) {
+        self.output.push(line.to_string());
+    }
+
+    fn args(&self) -> Vec<String> {
+        self.host.args.clone()
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        self.host.var(name).map(String::from)
+    }
+
+    fn sleep(&mut self, ms: i64) -> i64 {
+        ms
+    }
+
+    fn fetch(&mut self, url: &str) -> Result<String, String> {
+        Ok(format!("response from {}", url))
+    }
+
+    fn call(&mut self, module: &str, name: &str, args: &[HostValue]) -> Result<HostValue, String> {
+        match (module, name, args) {
+            ("test", "add", [HostValue::Integer(a), HostValue::Integer(b)]) => {
+                Ok(HostValue::Integer(a + b))
+            }
+            ("test", "length", [HostValue::String(text)]) => {
+                Ok(HostValue::Integer(text.len() as i64))
+            }
+            _ => Err(format!("no {}.{}", module, name)),
+        }
+    }
+}
+
+fn interpret_program(path: &Path, host: &Host) -> Result<Vec<String>, String> {
+    let mut io = TestIo {
+        host: host.clone(),
+        ..TestIo::default()
+    };
+    star::execute_file(path, &mut io).map_err(|e| star::error::format_diagnostics(&e))?;
+    Ok(io.output)
+}
+
+/// A compiled program linked against the runtime modules.
+struct Runtime {
+    store: Store<()>,
+    instance: Instance,
+    dalloc: Instance,
+    shadow: Instance,
+    output: Arc<Mutex<Vec<String>>>,
+}
+
+fn run_wasm(wasm_bytes: &[u8]) -> Result<Vec<String>, String> {
+    let mut runtime = load(wasm_bytes, &Host::default())?;
+    run_main(&mut runtime)?;
+    let result = runtime.output.lock().unwrap().clone();
+    Ok(result)
+}
+
+fn run_main(runtime: &mut Runtime) -> Result<(), String> {
+    use star::host::{AsyncRequest, AsyncState};
 
+    let main = runtime
+        .instance
+        .get_typed_func::<(i32, i64, i32), i64>(&mut runtime.store, "main")
+        .map_err(|e| e.to_string())?;
+
+    main.call(&mut runtime.store, (0, 0, 0))
+        .map_err(|e| e.to_string())?;
+
+    // Programs that await return to the host with a request to carry out.
+    let Some(state) = runtime
+        .instance
+        .get_global(&mut runtime.store, star::host::ASYNC_STATE_EXPORT)
+    else {
+        return Ok(());
+    };
+    let mut global = |name| runtime.instance.get_global(&mut runtime.store, name);
+    let (request, argument, result) = (
+        global(star::host::ASYNC_REQUEST_EXPORT).unwrap(),
+        global(star::host::ASYNC_ARGUMENT_EXPORT).unwrap(),
+        global(star::host::ASYNC_RESULT_EXPORT).unwrap(),
+    );
+    while state.get(&mut runtime.store).unwrap_i32() == AsyncState::Unwinding as i32 {
+        let request = request.get(&mut runtime.store).unwrap_i32();
+        let argument = argument.get(&mut runtime.store).unwrap_i64();
+        let answer = match AsyncRequest::from_i32(request).ok_or("Unknown async request")? {
+            AsyncRequest::Sleep => argument,
+            AsyncRequest::Fetch => {
+                let url = read_string(runtime, argument as usize);
+                alloc_string(runtime, format!("response from {}", url).as_bytes())? as i64
+            }
+        };
+        result
+            .set(&mut runtime.store, Val::I64(answer))
+            .map_err(|e| e.to_string())?;
+        state
+            .set(&mut runtime.store, Val::I32(AsyncState::Rewinding as i32))
+            .map_err(|e| e.to_string())?;
+        main.call(&mut runtime.store, (0, 0, 0))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn read_string(runtime: &mut Runtime, ptr: usize) -> String {
+    let memory = runtime
+        .dalloc
+        .get_memory(&mut runtime.store, "memory")
+        .unwrap();
+    let data = memory.data(&runtime.store);
+    let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap()) as usize;
+    String::from_utf8_lossy(&data[ptr..ptr + length]).into_owned()
+}
+
+/// Copies `text` into a new string the program owns.
+fn alloc_string(runtime: &mut Runtime, text: &[u8]) -> Result<i32, String> {
+    let store = &mut runtime.store;
+    let alloc = runtime
+        .shadow
+        .get_typed_func::<i32, i32>(&mut *store, "host_alloc_string")
+        .map_err(|e| e.to_string())?;
+    let write_bytes = runtime
+        .dalloc
+        .get_typed_func::<(i32, i32, i64, i32), i32>(&mut *store, "host_write_bytes")
+        .map_err(|e| e.to_string())?;
+    let string = alloc
+        .call(&mut *store, text.len() as i32)
+        .map_err(|e| e.to_string())?;
+    for (i, chunk) in text.chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let (offset, length) = ((i * 8) as i32, chunk.len() as i32);
+        let args = (string, offset, i64::from_le_bytes(bytes), length);
+        write_bytes
+            .call(&mut *store, args)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(string)
+}
+
+/// Links a compiled program against the runtime modules, with `host`'s
+/// arguments and environment.
+fn load(wasm_bytes: &[u8], host: &Host) -> Result<Runtime, String> {
     let engine = Engine::default();
     let mut store = Store::new(&engine, ());
     let mut linker = Linker::new(&engine);
@@ -64,11 +259,7 @@ fn run_program(source: &str) -> Result<Vec<String>, String> {
             let ptr = ptr as usize;
             let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap());
 
-            let mut string: Vec<u8> = Vec::with_capacity(length as usize);
-            for i in 0..length {
-                let start = ptr + (i as usize) * 8;
-                string.push(data[start]);
-            }
+            let string = data[ptr..ptr + length as usize].to_vec();
 
             let decoded = String::from_utf8(string).unwrap_or_else(|_| "<invalid utf8>".into());
             output_clone.lock().unwrap().push(decoded);
@@ -76,57 +267,150 @@ fn run_program(source: &str) -> Result<Vec<String>, String> {
         })
         .map_err(|e| e.to_string())?;
 
-    let module = Module::new(&engine, &wasm_bytes).map_err(|e| e.to_string())?;
-    let instance = linker
-        .instantiate(&mut store, &module)
+    let args = Arc::new(host.args.clone());
+    let count = args.clone();
+    linker
+        .func_wrap("env", star::host::ARG_COUNT_IMPORT, move || count.len() as i32)
+        .map_err(|e| e.to_string())?;
+    let lengths = args.clone();
+    linker
+        .func_wrap("env", star::host::ARG_LENGTH_IMPORT, move |index: i32| {
+            lengths[index as usize].len() as i32
+        })
+        .map_err(|e| e.to_string())?;
+    linker
+        .func_wrap(
+            "env",
+            star::host::ARG_COPY_IMPORT,
+            move |mut caller: Caller<'_, ()>, index: i32, ptr: i32| {
+                let bytes = args[index as usize].as_bytes();
+                let ptr = ptr as usize;
+                lists.data_mut(&mut caller)[ptr..ptr + bytes.len()].copy_from_slice(bytes);
+            },
+        )
         .map_err(|e| e.to_string())?;
 
-    let main = instance
-        .get_typed_func::<(i32, i64, i32), i64>(&mut store, "main")
+    // Both look the name up again rather than keep it between the calls.
+    fn name(data: &[u8], ptr: i32) -> String {
+        let ptr = ptr as usize;
+        let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap()) as usize;
+        String::from_utf8_lossy(&data[ptr..ptr + length]).into_owned()
+    }
+    let vars = Arc::new(host.clone());
+    let lookup = vars.clone();
+    linker
+        .func_wrap(
+            "env",
+            star::host::ENV_LENGTH_IMPORT,
+            move |caller: Caller<'_, ()>, ptr: i32| {
+                let name = name(lists.data(&caller), ptr);
+                lookup.var(&name).map_or(-1, |value| value.len() as i32)
+            },
+        )
+        .map_err(|e| e.to_string())?;
+    linker
+        .func_wrap(
+            "env",
+            star::host::ENV_COPY_IMPORT,
+            move |mut caller: Caller<'_, ()>, ptr: i32, dest: i32| {
+                let name = name(lists.data(&caller), ptr);
+                let bytes = vars.var(&name).unwrap_or_default().as_bytes();
+                let dest = dest as usize;
+                lists.data_mut(&mut caller)[dest..dest + bytes.len()].copy_from_slice(bytes);
+            },
+        )
         .map_err(|e| e.to_string())?;
 
-    main.call(&mut store, (0, 0, 0))
+    // What `extern "test" fn`s call, as `TestIo::call` answers them.
+    linker
+        .func_wrap("test", "add", |a: i64, b: i64| a + b)
+        .map_err(|e| e.to_string())?;
+    linker
+        .func_wrap("test", "length", move |caller: Caller<'_, ()>, ptr: i32| {
+            name(lists.data(&caller), ptr).len() as i64
+        })
         .map_err(|e| e.to_string())?;
 
-    let result = output.lock().unwrap().clone();
-    Ok(result)
+    let module = Module::new(&engine, wasm_bytes).map_err(|e| e.to_string())?;
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .map_err(|e| e.to_string())?;
+
+    Ok(Runtime {
+        store,
+        instance,
+        dalloc: dalloc_instance,
+        shadow: shadow_instance,
+        output,
+    })
 }
 
 #[derive(Debug)]
 struct TestExpectation {
     output: Vec<String>,
     expect_panic: bool,
+    /// Skips the interpreter, for programs that depend on the collector.
+    compiled_only: bool,
+    /// Why the program is left out of the `--gc-stress` run, for programs
+    /// whose output depends on when collections happen or that keep so
+    /// much alive that collecting on every allocation takes too long.
+    no_gc_stress: Option<String>,
+    host: Host,
 }
 
-fn parse_test_file(content: &str) -> (String, TestExpectation) {
+/// Reads the expectations from a test program's comments. The program is
+/// compiled from its file as it stands, so diagnostics and asserts report
+/// the lines it really has.
+fn parse_test_file(content: &str) -> TestExpectation {
     let mut expected = Vec::new();
-    let mut source_lines = Vec::new();
     let mut expect_panic = false;
+    let mut compiled_only = false;
+    let mut no_gc_stress = None;
+    let mut host = Host::default();
 
     for line in content.lines() {
         if line.starts_with("// expect: ") {
             expected.push(line.trim_start_matches("// expect: ").to_string());
         } else if line.starts_with("// expect_panic") {
             expect_panic = true;
-        } else {
-            source_lines.push(line);
+        } else if line.starts_with("// compiled_only") {
+            compiled_only = true;
+        } else if let Some(reason) = line.strip_prefix("// no_gc_stress: ") {
+            no_gc_stress = Some(reason.to_string());
+        } else if let Some(list) = line.strip_prefix("// args: ") {
+            host.args.extend(list.split_whitespace().map(String::from));
+        } else if let Some(var) = line.strip_prefix("// env: ") {
+            let (name, value) = var.split_once('=').unwrap_or((var, ""));
+            host.env.push((name.to_string(), value.to_string()));
         }
     }
 
-    (
-        source_lines.join("\n"),
-        TestExpectation {
-            output: expected,
-            expect_panic,
-        },
-    )
+    TestExpectation {
+        output: expected,
+        expect_panic,
+        compiled_only,
+        no_gc_stress,
+        host,
+    }
 }
 
 fn run_test_file(path: &Path) -> Result<(), String> {
     let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
-    let (source, expectation) = parse_test_file(&content);
+    let expectation = parse_test_file(&content);
+
+    check_outcome(run_program(path, &expectation.host), &expectation)?;
+    if !expectation.compiled_only {
+        check_outcome(interpret_program(path, &expectation.host), &expectation)
+            .map_err(|e| format!("Interpreter: {}", e))?;
+    }
+    Ok(())
+}
 
-    match run_program(&source) {
+fn check_outcome(
+    outcome: Result<Vec<String>, String>,
+    expectation: &TestExpectation,
+) -> Result<(), String> {
+    match outcome {
         Ok(actual) => {
             if expectation.expect_panic {
                 return Err("Expected panic but program succeeded".to_string());
@@ -149,8 +433,30 @@ fn run_test_file(path: &Path) -> Result<(), String> {
     }
 }
 
+/// Runs the program at `path` built with `--gc-stress`, for the programs
+/// whose output doesn't depend on when collections happen.
+fn run_stressed_test_file(path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let expectation = parse_test_file(&content);
+    if expectation.no_gc_stress.is_some() {
+        return Ok(());
+    }
+    check_outcome(run_stressed_program(path, &expectation.host), &expectation)
+}
+
 #[test]
 fn run_all_program_tests() {
+    run_program_tests(run_test_file);
+}
+
+#[test]
+fn run_all_program_tests_under_gc_stress() {
+    run_program_tests(run_stressed_test_file);
+}
+
+/// Runs every program in tests/programs with `run`, reporting each that
+/// fails.
+fn run_program_tests(run: fn(&Path) -> Result<(), String>) {
     let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
 
     if !test_dir.exists() {
@@ -169,7 +475,7 @@ fn run_all_program_tests() {
             let name = path.file_name().unwrap().to_string_lossy();
             print!("Testing {}... ", name);
 
-            match run_test_file(&path) {
+            match run(&path) {
                 Ok(()) => {
                     println!("OK");
                     passed += 1;
@@ -192,3 +498,969 @@ fn run_all_program_tests() {
         panic!("{} test(s) failed", failures.len());
     }
 }
+
+#[test]
+fn list_functions_survive_gc_stress() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/list_functions.star");
+    let content = fs::read_to_string(&path).unwrap();
+    let expectation = parse_test_file(&content);
+    check_outcome(run_stressed_program(&path, &expectation.host), &expectation).unwrap();
+}
+
+#[test]
+fn reports_every_error_in_file() {
+    let parse_errors = star::compile(
+        "fn main(): integer {\n    let x: integer = ;\n    print $x;\n    let y: = 2;\n    return 0;\n}\n",
+    )
+    .unwrap_err();
+    assert_eq!(parse_errors.len(), 2, "{:?}", parse_errors);
+
+    let type_errors = star::compile(
+        "fn main(): integer {\n    let x: integer = true;\n    print $x;\n    let s: string = 1;\n    return \"no\";\n}\n",
+    )
+    .unwrap_err();
+    assert_eq!(type_errors.len(), 3, "{:?}", type_errors);
+}
+
+#[test]
+fn suggests_numeric_conversions() {
+    let errors = star::compile(
+        "fn main(): integer {\n    fn half(x: float): float {\n        return x / 2.0;\n    }\n\n    let x: float = 1;\n    print $half(3);\n    let n: integer = 2.5;\n    print $(n + 0.5);\n    return 0;\n}\n",
+    )
+    .unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(messages.len(), 4, "{:?}", messages);
+    assert!(messages[0].contains("float(...)"), "{}", messages[0]);
+    assert!(messages[1].contains("float(...)"), "{}", messages[1]);
+    assert!(messages[2].contains("int(...)"), "{}", messages[2]);
+    assert!(messages[3].contains("float(...)"), "{}", messages[3]);
+}
+
+#[test]
+fn rejects_assignments_to_parameters() {
+    let errors = star::compile(
+        "fn main(): integer {\n    fn countdown(n: integer): integer {\n        while n > 0 {\n            n = n - 1;\n        }\n        return n;\n    }\n    return countdown(3);\n}\n",
+    )
+    .unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec!["Type error: Cannot assign to 'n', which is a parameter"]
+    );
+}
+
+#[test]
+fn widens_integers_when_asked() {
+    let source = "fn main(): integer {\n    fn half(x: float): float {\n        return x / 2;\n    }\n\n    let x: float = 1;\n    x = x + 2;\n    print $x;\n    print $half(3);\n    print $(x > 2);\n    return 0;\n}\n";
+    assert!(star::compile(source).is_err());
+
+    let options = star::LanguageOptions {
+        implicit_widening: true,
+        ..Default::default()
+    };
+    let wasm_bytes = star::compile_with(source, options)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    assert_eq!(
+        run_wasm(&wasm_bytes).unwrap(),
+        vec!["3.000000", "1.500000", "true"]
+    );
+}
+
+#[test]
+fn leaves_asserts_out_of_release_builds() {
+    let source = "fn main(): integer {\n    assert 1 > 2, \"never\";\n    print \"done\";\n    return 0;\n}\n";
+    let debug = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    assert_eq!(run_wasm(&debug).unwrap(), vec!["line 2: never"]);
+
+    let options = star::LanguageOptions {
+        release: true,
+        ..Default::default()
+    };
+    let release = star::compile_with(source, options)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    assert_eq!(run_wasm(&release).unwrap(), vec!["done"]);
+}
+
+#[test]
+fn host_builds_strings_and_lists() {
+    let source = "fn main(): integer {\n    let words: {string} = {};\n    let i: integer = 0;\n    while i < 2000 {\n        words.push(\"garbage \" + $i);\n        i = i + 1;\n    }\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+
+    let store = &mut runtime.store;
+    let alloc_string = runtime
+        .shadow
+        .get_typed_func::<i32, i32>(&mut *store, "host_alloc_string")
+        .unwrap();
+    let alloc_list = runtime
+        .shadow
+        .get_typed_func::<i32, i32>(&mut *store, "host_alloc_list")
+        .unwrap();
+    let gc = runtime
+        .shadow
+        .get_typed_func::<(), ()>(&mut *store, "gc")
+        .unwrap();
+    let write_bytes = runtime
+        .dalloc
+        .get_typed_func::<(i32, i32, i64, i32), i32>(&mut *store, "host_write_bytes")
+        .unwrap();
+    let memory = runtime.dalloc.get_memory(&mut *store, "memory").unwrap();
+
+    let text = b"hello, host";
+    let string = alloc_string.call(&mut *store, text.len() as i32).unwrap();
+    assert_ne!(string, 0);
+    for (i, chunk) in text.chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let args = (string, (i * 8) as i32, i64::from_le_bytes(bytes), chunk.len() as i32);
+        assert_eq!(write_bytes.call(&mut *store, args).unwrap(), 1);
+    }
+    // Past the end of the string.
+    let args = (string, text.len() as i32 - 1, 0, 2);
+    assert_eq!(write_bytes.call(&mut *store, args).unwrap(), 0);
+
+    let list = alloc_list.call(&mut *store, 3).unwrap();
+    for i in 0..3 {
+        let args = (list, i * 8, 100 + i as i64, 8);
+        assert_eq!(write_bytes.call(&mut *store, args).unwrap(), 1);
+    }
+
+    gc.call(&mut *store, ()).unwrap();
+    gc.call(&mut *store, ()).unwrap();
+
+    let data = memory.data(&*store);
+    let string = string as usize;
+    assert_eq!(&data[string - 4..string], &(text.len() as u32).to_le_bytes());
+    assert_eq!(&data[string..string + text.len()], text);
+    let list = list as usize;
+    for i in 0..3 {
+        let slot = &data[list + i * 8..list + i * 8 + 8];
+        assert_eq!(i64::from_le_bytes(slot.try_into().unwrap()), 100 + i as i64);
+    }
+}
+
+#[test]
+fn pinned_objects_survive_collections() {
+    let source = "fn main(): integer {\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+
+    let store = &mut runtime.store;
+    let dalloc = runtime
+        .dalloc
+        .get_typed_func::<(i32, i32), i32>(&mut *store, "dalloc")
+        .unwrap();
+    let dblock = runtime
+        .dalloc
+        .get_typed_func::<i32, i32>(&mut *store, "dblock")
+        .unwrap();
+    let pin = runtime
+        .shadow
+        .get_typed_func::<(i32, i32), i32>(&mut *store, "pin")
+        .unwrap();
+    let unpin = runtime
+        .shadow
+        .get_typed_func::<i32, ()>(&mut *store, "unpin")
+        .unwrap();
+    let gc = runtime
+        .shadow
+        .get_typed_func::<(), ()>(&mut *store, "gc")
+        .unwrap();
+
+    // An ordinary string, as a Star function would return it.
+    let kept = dalloc.call(&mut *store, (4, 5)).unwrap();
+    let dropped = dalloc.call(&mut *store, (4, 5)).unwrap();
+    let handle = pin.call(&mut *store, (kept, 2)).unwrap();
+    assert_ne!(handle, 0);
+
+    gc.call(&mut *store, ()).unwrap();
+    assert_eq!(dblock.call(&mut *store, kept).unwrap(), 1);
+    assert_eq!(dblock.call(&mut *store, dropped).unwrap(), 0);
+
+    unpin.call(&mut *store, handle).unwrap();
+    gc.call(&mut *store, ()).unwrap();
+    assert_eq!(dblock.call(&mut *store, kept).unwrap(), 0);
+}
+
+#[test]
+fn heap_snapshots_tell_garbage_from_live_objects() {
+    let source = "struct Point {\n    x: integer,\n    y: integer,\n}\n\nfn main(): integer {\n    let p: Point = new Point { x: 1, y: 2 };\n    print \"x \" + $p.x;\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+
+    let store = &mut runtime.store;
+    let heap_snapshot = runtime
+        .shadow
+        .get_typed_func::<(), i32>(&mut *store, "heap_snapshot")
+        .unwrap();
+    let gc = runtime
+        .shadow
+        .get_typed_func::<(), ()>(&mut *store, "gc")
+        .unwrap();
+    let memory = runtime.shadow.get_memory(&mut *store, "memory").unwrap();
+    let snapshot = |store: &mut Store<()>| {
+        let address = heap_snapshot.call(&mut *store, ()).unwrap();
+        star::host::HeapObject::read_snapshot(memory.data(&*store), address as u32)
+    };
+
+    // Once main has returned, the point and the string are garbage.
+    let before = snapshot(&mut *store);
+    assert!(before.iter().any(|o| !o.dalloc && !o.reachable && o.bytes == 8 + 16));
+    assert!(before.iter().any(|o| o.dalloc && !o.reachable));
+    // Taking a snapshot leaves the marks as it found them.
+    assert_eq!(snapshot(&mut *store), before);
+
+    gc.call(&mut *store, ()).unwrap();
+    assert!(snapshot(&mut *store).iter().all(|o| o.reachable));
+}
+
+#[test]
+fn host_calls_star_closures() {
+    let source = "fn main(): (integer: integer) {\n    let base: integer = 40;\n    fn add(n: integer): integer {\n        let words: {string} = {};\n        let i: integer = 0;\n        while i < 500 {\n            words.push(\"garbage \" + $i);\n            i = i + 1;\n        }\n        return base + n;\n    }\n    return add;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    let store = &mut runtime.store;
+
+    let main = runtime
+        .instance
+        .get_typed_func::<(i32, i64, i32), i64>(&mut *store, "main")
+        .unwrap();
+    let add = star::host::FunctionValue::from_bits(main.call(&mut *store, (0, 0, 0)).unwrap());
+    assert_eq!(star::host::FunctionValue::from_bits(add.to_bits()), add);
+
+    let pin = runtime
+        .shadow
+        .get_typed_func::<(i32, i32), i32>(&mut *store, "pin")
+        .unwrap();
+    let gc = runtime
+        .shadow
+        .get_typed_func::<(), ()>(&mut *store, "gc")
+        .unwrap();
+    assert_ne!(pin.call(&mut *store, (add.env as i32, 1)).unwrap(), 0);
+
+    let table = runtime
+        .instance
+        .get_table(&mut *store, star::host::TABLE_EXPORT)
+        .unwrap();
+    let function = table
+        .get(&mut *store, add.index as u64)
+        .unwrap()
+        .as_func()
+        .unwrap()
+        .unwrap()
+        .typed::<(i32, i64, i32, i64), i64>(&*store)
+        .unwrap();
+    for n in 0..3 {
+        let result = function.call(&mut *store, (0, 0, add.env as i32, n)).unwrap();
+        assert_eq!(result, 40 + n);
+        gc.call(&mut *store, ()).unwrap();
+    }
+}
+
+#[test]
+fn interpreter_runs_without_wasm() {
+    struct Quiet;
+    impl star::host::Io for Quiet {
+        fn print(&mut self, _: &str) {}
+    }
+
+    let source = "fn main(): integer {\n    fn depth(n: integer): integer {\n        if n == 0 {\n            return 0;\n        }\n        return 1 + depth(n - 1);\n    }\n\n    return depth(200);\n}\n";
+    assert_eq!(star::execute(source, &mut Quiet).unwrap(), 200);
+}
+
+#[test]
+fn compiles_imported_modules() {
+    let entry = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/modules/main.star");
+    let expected = vec!["area: 12", "perimeter: 14"];
+
+    let mut io = TestIo::default();
+    star::execute_file(&entry, &mut io)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    assert_eq!(io.output, expected);
+
+    let wasm_bytes = star::compile_file(&entry)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    assert_eq!(run_wasm(&wasm_bytes).unwrap(), expected);
+}
+
+#[test]
+fn reports_module_errors() {
+    fn errors(files: &[(&str, &str)]) -> String {
+        let files: std::collections::HashMap<_, _> = files
+            .iter()
+            .map(|(path, source)| (Path::new(path).to_path_buf(), source.to_string()))
+            .collect();
+        let mut read = |path: &Path| {
+            files
+                .get(path)
+                .cloned()
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+        };
+        let program = match star::parse_modules(Path::new("main.star"), &mut read) {
+            Ok(program) => program,
+            Err(errors) => return star::error::format_diagnostics(&errors),
+        };
+        star::check(&program)
+            .map(|_| String::new())
+            .unwrap_or_else(|errors| star::error::format_diagnostics(&errors))
+    }
+
+    let main = "import \"a.star\";\nfn main(): integer {\n    return 0;\n}\n";
+    assert!(errors(&[("main.star", main), ("a.star", "import \"./main.star\";\n")])
+        .contains("Import cycle: main.star -> a.star -> main.star"));
+    assert!(errors(&[("main.star", main)]).contains("Failed to read a.star"));
+    assert!(errors(&[
+        ("main.star", main),
+        ("a.star", "fn main(): integer {\n    return 1;\n}\n"),
+    ])
+    .contains("'main' is defined in both a.star and main.star"));
+
+    // b.star is only imported by a.star, so main.star can't use it.
+    let uses_b = "import \"a.star\";\nfn main(): integer {\n    return twice(1);\n}\n";
+    let a = "import \"b.star\";\nfn once(x: integer): integer {\n    return twice(x) / 2;\n}\n";
+    let b = "fn twice(x: integer): integer {\n    return 2 * x;\n}\n";
+    assert!(errors(&[("main.star", uses_b), ("a.star", a), ("b.star", b)])
+        .contains("Identifier 'twice' is defined in b.star, which this module does not import"));
+
+    assert!(star::compile("import \"a.star\";\nfn main(): integer {\n    return 0;\n}\n")
+        .is_err());
+}
+
+#[test]
+fn exit_hands_its_code_to_the_host() {
+    let source = "fn main(): integer {\n    fn stop(code: integer): integer {\n        let waited: integer = await sleep(code);\n        exit(waited);\n        return 0;\n    }\n\n    print \"stopping\";\n    stop(7);\n    print \"unreachable\";\n    return 0;\n}\n";
+
+    let mut io = TestIo::default();
+    assert_eq!(star::execute(source, &mut io).unwrap(), 7);
+    assert_eq!(io.output, vec!["stopping"]);
+
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+    assert_eq!(*runtime.output.lock().unwrap(), vec!["stopping"]);
+    let mut global = |name| {
+        runtime
+            .instance
+            .get_global(&mut runtime.store, name)
+            .unwrap()
+            .get(&mut runtime.store)
+    };
+    assert_eq!(
+        global(star::host::ASYNC_STATE_EXPORT).unwrap_i32(),
+        star::host::AsyncState::Exiting as i32
+    );
+    assert_eq!(global(star::host::ASYNC_ARGUMENT_EXPORT).unwrap_i64(), 7);
+}
+
+#[test]
+fn hosts_call_exported_functions() {
+    let source = "export fn add(a: integer, b: integer): integer {\n    return a + b;\n}\n\nexport fn greet(name: string): string {\n    return greeting() + name;\n}\n\nfn greeting(): string {\n    return \"hello \";\n}\n\nfn main(): integer {\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+
+    let module = wasmtime::Module::new(&wasmtime::Engine::default(), &wasm_bytes).unwrap();
+    let add = module.get_export("add").unwrap().func().unwrap().clone();
+    assert_eq!(add.params().map(|p| p.to_string()).collect::<Vec<_>>(), ["i64", "i64"]);
+    assert_eq!(add.results().map(|r| r.to_string()).collect::<Vec<_>>(), ["i64"]);
+
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+    let store = &mut runtime.store;
+    let add = runtime
+        .instance
+        .get_typed_func::<(i64, i64), i64>(&mut *store, "add")
+        .unwrap();
+    assert_eq!(add.call(&mut *store, (40, 2)).unwrap(), 42);
+    let greet = runtime
+        .instance
+        .get_typed_func::<i32, i32>(&mut *store, "greet")
+        .unwrap();
+    let name = alloc_string(&mut runtime, b"host").unwrap();
+    let greeting = greet.call(&mut runtime.store, name).unwrap();
+    assert_eq!(read_string(&mut runtime, greeting as usize), "hello host");
+
+    let errors = |source: &str| {
+        star::compile(source)
+            .map(|_| String::new())
+            .unwrap_or_else(|errors| star::error::format_diagnostics(&errors))
+    };
+    let main = "fn main(): integer {\n    return 0;\n}\n";
+    assert!(errors(&format!("export {}", main)).contains("main is always exported"));
+}
+
+#[test]
+fn exported_functions_share_globals_across_calls() {
+    let source = "let names: {string} = {\"main\"};\nlet calls: integer = 10;\n\nexport fn remember(name: string): integer {\n    names.push(name);\n    calls = calls + 1;\n    return calls;\n}\n\nexport fn last(): string {\n    return names[#names - 1] + \" of \" + $#names;\n}\n\nfn main(): integer {\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source)
+        .map_err(|e| star::error::format_diagnostics(&e))
+        .unwrap();
+    let mut runtime = load(&wasm_bytes, &Host::default()).unwrap();
+    run_main(&mut runtime).unwrap();
+
+    let store = &mut runtime.store;
+    let remember = runtime
+        .instance
+        .get_typed_func::<i32, i64>(&mut *store, "remember")
+        .unwrap();
+    let last = runtime
+        .instance
+        .get_typed_func::<(), i32>(&mut *store, "last")
+        .unwrap();
+    let gc = runtime
+        .shadow
+        .get_typed_func::<(), ()>(&mut *store, "gc")
+        .unwrap();
+
+    for (i, name) in ["ann", "bob"].into_iter().enumerate() {
+        let name = alloc_string(&mut runtime, name.as_bytes()).unwrap();
+        let calls = remember.call(&mut runtime.store, name).unwrap();
+        assert_eq!(calls, 11 + i as i64);
+        // Only the global holds the list between calls.
+        gc.call(&mut runtime.store, ()).unwrap();
+    }
+    let text = last.call(&mut runtime.store, ()).unwrap();
+    assert_eq!(read_string(&mut runtime, text as usize), "bob of 3");
+}
+
+#[test]
+fn function_types_are_listed_once() {
+    let source = "fn main(): integer {\n    fn twice(f: (integer: integer), x: integer): integer {\n        return f(f(x));\n    }\n    fn inc(x: integer): integer {\n        return x + 1;\n    }\n    fn dec(x: integer): integer {\n        return x - 1;\n    }\n    print $twice(inc, twice(dec, 5));\n    return 0;\n}\n";
+    let wasm_bytes = star::compile(source).unwrap();
+    let mut signatures = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(&wasm_bytes) {
+        if let wasmparser::Payload::TypeSection(reader) = payload.unwrap() {
+            for ty in reader.into_iter_err_on_gc_types() {
+                signatures.push(ty.unwrap());
+            }
+        }
+    }
+    assert!(!signatures.is_empty());
+    for (i, ty) in signatures.iter().enumerate() {
+        assert!(!signatures[..i].contains(ty), "{:?} is listed twice", ty);
+    }
+}
+
+#[test]
+fn drops_dead_functions_and_statements() {
+    let source = "fn main(): integer {\n    fn helper(x: integer): integer {\n        return x + 1;\n    }\n    fn unused(x: integer): integer {\n        return helper(x) * 2;\n    }\n    fn countdown(n: integer): integer {\n        if n == 0 {\n            return 0;\n        }\n        return countdown(n - 1);\n    }\n    fn caller(x: integer): integer {\n        return helper(x);\n    }\n    print $caller(1);\n    return 0;\n    print \"dead\";\n}\n";
+    let typed = star::check(&star::parse(source).unwrap()).unwrap();
+    let ir = star::lower(&typed).unwrap();
+
+    let mut names: Vec<&str> = ir.functions.iter().map(|f| f.name.as_str()).collect();
+    names.sort();
+    // `helper` is inlined into `caller`, which reads its environment.
+    assert_eq!(names, ["caller", "main"]);
+    for (index, func) in ir.functions.iter().enumerate() {
+        assert_eq!(func.func_index, index as u32);
+    }
+    assert!(matches!(ir.functions[0].body.last(), Some(star::ast::IRStmt::Return(_))));
+
+    struct Output(Vec<String>);
+    impl star::host::Io for Output {
+        fn print(&mut self, text: &str) {
+            self.0.push(text.to_string());
+        }
+    }
+    let mut output = Output(vec![]);
+    assert_eq!(star::execute(source, &mut output).unwrap(), 0);
+    assert_eq!(output.0, ["2"]);
+}
+
+#[test]
+fn inlines_small_functions() {
+    let source = "fn main(): integer {\n    fn twice(n: integer): integer {\n        return n * 2;\n    }\n    fn count(n: integer): integer {\n        if n == 0 {\n            return 0;\n        }\n        return count(n - 1) + 1;\n    }\n    let total: integer = 1 + twice(3);\n    print $(total + count(twice(2)));\n    return 0;\n}\n";
+    let typed = star::check(&star::parse(source).unwrap()).unwrap();
+    let ir = star::lower(&typed).unwrap();
+
+    let names: Vec<&str> = ir.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["main", "count"]);
+    assert!(ir.functions[0].local_names.contains(&"twice.n".to_string()));
+
+    struct Output(Vec<String>);
+    impl star::host::Io for Output {
+        fn print(&mut self, text: &str) {
+            self.0.push(text.to_string());
+        }
+    }
+    let mut output = Output(vec![]);
+    assert_eq!(star::execute(source, &mut output).unwrap(), 0);
+    assert_eq!(output.0, ["11"]);
+}
+
+#[test]
+fn registers_only_instantiated_structs() {
+    let registrations = |source: &str| {
+        let wasm_bytes = star::compile(source).unwrap();
+        let mut imports = vec![];
+        let mut count = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm_bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        imports.push((import.module, import.name));
+                    }
+                }
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    for op in body.get_operators_reader().unwrap() {
+                        if let wasmparser::Operator::Call { function_index } = op.unwrap() {
+                            if imports.get(function_index as usize) == Some(&("alloc", "register")) {
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        count
+    };
+    let main = "struct Used {\n    x: integer,\n}\n\nfn main(): integer {\n    let u: Used = new Used { x: 2 };\n    return u.x;\n}\n";
+    let unused = "struct Unused {\n    y: integer,\n}\n\nfn make(): Unused {\n    return new Unused { y: 1 };\n}\n\n";
+    assert_eq!(registrations(main), registrations(&format!("{}{}", unused, main)));
+}
+
+#[test]
+fn calls_known_functions_directly() {
+    let calls = |source: &str| {
+        let wasm_bytes = star::compile(source).unwrap();
+        let (mut direct, mut indirect) = (0, 0);
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm_bytes) {
+            if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                for op in body.get_operators_reader().unwrap() {
+                    match op.unwrap() {
+                        wasmparser::Operator::Call { .. } => direct += 1,
+                        wasmparser::Operator::CallIndirect { .. } => indirect += 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        (direct, indirect)
+    };
+    let known = "fn main(): integer {\n    fn fib(n: integer): integer {\n        if n < 2 {\n            return n;\n        }\n        return fib(n - 1) + fib(n - 2);\n    }\n    return fib(10);\n}\n";
+    assert_eq!(calls(known).1, 0);
+    let passed = "fn main(): integer {\n    fn apply(f: (integer: integer), n: integer): integer {\n        return f(n) + f(n);\n    }\n    fn fib(n: integer): integer {\n        if n < 2 {\n            return n;\n        }\n        return fib(n - 1) + fib(n - 2);\n    }\n    return apply(fib, 10);\n}\n";
+    assert_eq!(calls(passed).1, 2);
+}
+
+#[test]
+fn roots_only_locals_live_across_allocations() {
+    let roots = |source: &str| {
+        let wasm_bytes = star::compile(source).unwrap();
+        let mut imports = vec![];
+        let mut count = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm_bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        imports.push((import.module, import.name));
+                    }
+                }
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    for op in body.get_operators_reader().unwrap() {
+                        if let wasmparser::Operator::Call { function_index } = op.unwrap() {
+                            if imports.get(function_index as usize) == Some(&("shadow", "set")) {
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        count
+    };
+    let program = |last: &str| {
+        format!("struct Point {{\n    x: integer,\n}}\n\nfn main(): integer {{\n    let p: Point = new Point {{ x: 1 }};\n    let q: Point = new Point {{ x: 2 }};\n    return {};\n}}\n", last)
+    };
+    // Reading `p` after `q` is allocated keeps it rooted.
+    assert_eq!(roots(&program("p.x + q.x")), roots(&program("q.x")) + 1);
+}
+
+#[test]
+fn name_section_names_functions_locals_and_types() {
+    let source = "fn main(): integer {\n    let base: integer = 0;\n    fn add(a: integer, b: integer): integer {\n        let sum: integer = a + b;\n        return sum + base;\n    }\n    return add(1, 2);\n}\n";
+    let wasm_bytes = star::compile(source).unwrap();
+    let mut functions = HashMap::new();
+    let mut locals = HashMap::new();
+    let mut types = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(&wasm_bytes) {
+        let wasmparser::Payload::CustomSection(reader) = payload.unwrap() else {
+            continue;
+        };
+        let wasmparser::KnownCustom::Name(names) = reader.as_known() else {
+            continue;
+        };
+        for name in names {
+            match name.unwrap() {
+                wasmparser::Name::Function(map) => {
+                    for naming in map {
+                        let naming = naming.unwrap();
+                        functions.insert(naming.name.to_string(), naming.index);
+                    }
+                }
+                wasmparser::Name::Local(map) => {
+                    for function in map {
+                        let function = function.unwrap();
+                        let names: Vec<_> = function
+                            .names
+                            .into_iter()
+                            .map(|naming| naming.unwrap())
+                            .map(|naming| (naming.index, naming.name.to_string()))
+                            .collect();
+                        locals.insert(function.index, names);
+                    }
+                }
+                wasmparser::Name::Type(map) => {
+                    for naming in map {
+                        types.push(naming.unwrap().name.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    assert!(functions.contains_key("alloc.falloc"));
+    assert!(functions.contains_key("main"));
+    let add = &locals[&functions["add"]];
+    for (index, name) in [(2, "env"), (3, "a"), (4, "b"), (5, "sum")] {
+        assert!(add.contains(&(index, name.to_string())), "{:?}", add);
+    }
+    assert!(types.contains(&"(i32, i64, i32, i64, i64) -> (i64)".to_string()), "{:?}", types);
+}
+
+#[test]
+fn js_glue_wraps_exports_and_externs() {
+    let source = "extern \"console\" fn log(message: string): integer;\n\nexport fn add(a: integer, b: integer): integer {\n    return a + b;\n}\n\nexport fn greet(name: string, loud: boolean): string {\n    return \"hello \" + name;\n}\n\nfn main(): integer {\n    log(\"hi\");\n    return 0;\n}\n";
+    let program = star::parse(source).unwrap();
+    let typed = star::check(&program).unwrap();
+    let ir = star::lower(&typed).unwrap();
+
+    let glue = star::js_glue(&typed, &ir, "greet.wasm", false);
+    assert!(glue.js.contains("const WASM = \"greet.wasm\";"));
+    assert!(glue.js.contains("\"console.log\": (arg0) => need(\"console\", \"log\")(text(arg0)),"));
+    assert!(glue.js.contains("add: (a, b) => instance.exports.add(a, b),"));
+    assert!(glue
+        .js
+        .contains("greet: (name, loud) => text(instance.exports.greet(string(name), (loud ? 1 : 0))),"));
+    assert!(glue.dts.contains("log(arg0: string): bigint;"));
+    assert!(glue.dts.contains("add(a: bigint, b: bigint): bigint;"));
+    assert!(glue.dts.contains("greet(name: string, loud: boolean): string;"));
+
+    let bundled = star::js_glue(&typed, &ir, "greet.wasm", true);
+    assert!(bundled.js.contains("const BUNDLED = true;"));
+}
+
+#[test]
+fn sanitized_builds_check_heap_accesses() {
+    let source = "struct Point {\n    x: integer,\n    y: integer,\n}\n\nfn main(): integer {\n    let points: {Point} = {};\n    let i: integer = 0;\n    while i < 500 {\n        points.push(new Point { x: i, y: 2 * i });\n        i = i + 1;\n    }\n    let last: Point = points[499];\n    print $last.y;\n    print \"done \" + $last.x;\n    return 0;\n}\n";
+    let program = star::parse(source).unwrap();
+    let typed = star::check_with(&program, star::LanguageOptions::default()).unwrap();
+    let ir = star::lower(&typed).unwrap();
+    let options = star::CodegenOptions {
+        sanitize_memory: true,
+        ..Default::default()
+    };
+    let wasm_bytes = star::codegen_with(&ir, options).unwrap();
+
+    let module = wasmtime::Module::new(&wasmtime::Engine::default(), &wasm_bytes).unwrap();
+    let imports: Vec<_> = module.imports().map(|i| (i.module(), i.name())).collect();
+    assert!(imports.contains(&("alloc", "fcheck")));
+    assert!(imports.contains(&("dalloc", "dcheck")));
+
+    assert_eq!(run_wasm(&wasm_bytes).unwrap(), vec!["998", "done 499"]);
+}
+
+#[test]
+fn allocation_policies_agree() {
+    let source = "fn main(): integer {\n    let kept: {string} = {};\n    let i: integer = 0;\n    while i < 2000 {\n        let s: string = \"x\" * (i % 37);\n        if i % 3 == 0 {\n            kept.push(s);\n        }\n        i = i + 1;\n    }\n    print $#kept;\n    print kept[600];\n    return 0;\n}\n";
+    let program = star::parse(source).unwrap();
+    let typed = star::check(&program).unwrap();
+    let ir = star::lower(&typed).unwrap();
+    for alloc_policy in [
+        star::AllocPolicy::FirstFit,
+        star::AllocPolicy::BestFit,
+        star::AllocPolicy::NextFit,
+    ] {
+        let options = star::CodegenOptions {
+            alloc_policy,
+            ..Default::default()
+        };
+        let wasm_bytes = star::codegen_with(&ir, options).unwrap();
+        let expected = vec!["667".to_string(), "x".repeat(1800 % 37)];
+        assert_eq!(run_wasm(&wasm_bytes).unwrap(), expected, "{:?}", alloc_policy);
+    }
+}
+
+#[test]
+fn stressed_builds_collect_at_every_allocation() {
+    let source = "struct Node {\n    value: integer,\n    next: Node?,\n}\n\nfn main(): integer {\n    let head: Node = new Node { value: 0, next: null };\n    let i: integer = 1;\n    while i < 300 {\n        head = new Node { value: i, next: head };\n        i = i + 1;\n    }\n    let names: {string} = {};\n    for j in 0..50 {\n        names.push(\"n\" + $j);\n    }\n    print names[49] + \" \" + $head.value;\n    let stats: GcStats = gcstats();\n    print $(stats.collections >= 350);\n    return 0;\n}\n";
+    let program = star::parse(source).unwrap();
+    let typed = star::check(&program).unwrap();
+    let ir = star::lower(&typed).unwrap();
+    let options = star::CodegenOptions {
+        gc_stress: true,
+        ..Default::default()
+    };
+    let wasm_bytes = star::codegen_with(&ir, options).unwrap();
+    assert_eq!(run_wasm(&wasm_bytes).unwrap(), vec!["n49 299", "true"]);
+}
+
+/// Compiles with `--wasm-gc` and runs the module on its own, or `None` for
+/// programs that use what the backend doesn't support yet, or that
+/// allocate more than the engine's GC heap holds.
+fn run_gc_program(path: &Path) -> Option<Result<Vec<String>, String>> {
+    let program = star::parse_file(path).ok()?;
+    let typed = star::check(&program).ok()?;
+    let ir = star::lower(&typed).ok()?;
+    let options = star::CodegenOptions {
+        wasm_gc: true,
+        ..Default::default()
+    };
+    let wasm_bytes = match star::codegen_with(&ir, options) {
+        Ok(wasm_bytes) => wasm_bytes,
+        Err(e) if e.to_string().contains("isn't supported by the wasm-gc backend") => {
+            return None
+        }
+        Err(e) => return Some(Err(e.to_string())),
+    };
+
+    let run = || -> Result<Vec<String>, String> {
+        // wasmtime 29's reference-counting collector fails its own debug
+        // assertions on these modules, and its heap is fixed at 512 KiB, so
+        // nothing is collected and large programs are left out.
+        let mut config = Config::new();
+        config
+            .wasm_gc(true)
+            .wasm_function_references(true)
+            .collector(Collector::Null);
+        let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+        let mut store = Store::new(&engine, Vec::new());
+        let mut linker = Linker::new(&engine);
+        linker
+            .func_wrap("env", "print", |mut caller: Caller<'_, Vec<String>>, ptr: i32| {
+                let memory = caller
+                    .get_export(star::host::DALLOC_MEMORY_EXPORT)
+                    .and_then(|export| export.into_memory())
+                    .unwrap();
+                let data = memory.data(&caller);
+                let ptr = ptr as usize;
+                let length = u32::from_le_bytes(data[ptr - 4..ptr].try_into().unwrap()) as usize;
+                let line = String::from_utf8_lossy(&data[ptr..ptr + length]).into_owned();
+                caller.data_mut().push(line);
+            })
+            .map_err(|e| e.to_string())?;
+        let module = Module::new(&engine, &wasm_bytes).map_err(|e| e.to_string())?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| e.to_string())?;
+        let main = instance
+            .get_typed_func::<(i32, i64, i32), i64>(&mut store, "main")
+            .map_err(|e| e.to_string())?;
+        main.call(&mut store, (0, 0, 0)).map_err(|e| format!("{:#}", e))?;
+        Ok(store.into_data())
+    };
+    match run() {
+        Err(e) if e.contains("allocation size too large") => None,
+        outcome => Some(outcome),
+    }
+}
+
+#[test]
+fn wasm_gc_runs_program_tests() {
+    let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut failures = Vec::new();
+    let mut ran = 0;
+
+    for entry in fs::read_dir(&test_dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "star") {
+            continue;
+        }
+        let expectation = parse_test_file(&fs::read_to_string(&path).unwrap());
+        let Some(outcome) = run_gc_program(&path) else {
+            continue;
+        };
+        ran += 1;
+        if let Err(e) = check_outcome(outcome, &expectation) {
+            failures.push(format!("--- {} ---\n{}", path.display(), e));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    assert!(ran >= 30, "only {} programs ran under wasm-gc", ran);
+}
+
+#[test]
+fn call_graph_flags_recursion() {
+    let source = "fn main(): integer {\n    fn depth(n: integer): integer {\n        if n == 0 {\n            return 0;\n        }\n        return 1 + depth(n - 1);\n    }\n\n    fn square(x: integer): integer {\n        let y: integer = x * x;\n        return y;\n    }\n\n    fn apply(f: (integer: integer), x: integer): integer {\n        return f(x) + square(x);\n    }\n\n    print $apply(square, 3);\n    return depth(2);\n}\n";
+    let typed = star::check(&star::parse(source).unwrap()).unwrap();
+    let graph = star::call_graph(&typed);
+
+    let node = |name: &str| graph.functions.iter().find(|f| f.name == name).unwrap();
+    let names: Vec<&str> = graph.cycles[0]
+        .iter()
+        .map(|&i| graph.functions[i].name.as_str())
+        .collect();
+    assert_eq!(names, ["main.depth"]);
+    assert!(node("main.depth").recursive);
+    assert_eq!(node("main").stack_bytes, None);
+
+    // Environment, parameter, local and saved frame pointer.
+    assert_eq!(node("main.square").frame_bytes, 32);
+    assert_eq!(node("main.apply").stack_bytes, Some(64));
+    assert!(node("main.apply").calls_indirectly);
+    assert!(graph.to_dot().contains("color=red"));
+}
+
+#[test]
+fn layout_reports_field_offsets() {
+    let source = "struct Node {\n    content: integer,\n    next: Node?,\n}\n\nfn main(): integer {\n    return 0;\n}\n";
+    let typed = star::check(&star::parse(source).unwrap()).unwrap();
+    let layout = star::layout(&star::lower(&typed).unwrap());
+
+    let node = layout.structs.iter().find(|s| s.name == "Node").unwrap();
+    assert_eq!((node.size, node.block_bytes, node.slab_count), (16, 24, 32));
+    let fields: Vec<(&str, u32, bool)> = node
+        .fields
+        .iter()
+        .map(|f| (f.name.as_str(), f.offset, f.boxed))
+        .collect();
+    assert_eq!(fields, [("next", 0, true), ("content", 8, false)]);
+    assert!(layout.report().contains("     8  content: integer\n"));
+
+    // Large structs get fewer to a slab.
+    let fields: Vec<String> = (0..16).map(|i| format!("    f{}: integer,\n", i)).collect();
+    let source = format!("struct Wide {{\n{}}}\n\nfn main(): integer {{\n    return 0;\n}}\n", fields.concat());
+    let typed = star::check(&star::parse(&source).unwrap()).unwrap();
+    let layout = star::layout(&star::lower(&typed).unwrap());
+    let wide = layout.structs.iter().find(|s| s.name == "Wide").unwrap();
+    assert_eq!((wide.size, wide.block_bytes, wide.slab_count), (128, 136, 30));
+}
+
+#[test]
+fn bundles_link_the_runtime_in() {
+    let alloc = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 8))
+            (func (export "falloc") (param $size i32) (result i32)
+                global.get $next
+                global.get $next
+                local.get $size
+                i32.add
+                global.set $next))"#,
+    )
+    .unwrap();
+    let dalloc = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 16) "\05\00\00\00hello"))"#,
+    )
+    .unwrap();
+    let shadow = wat::parse_str(
+        r#"(module
+            (import "alloc" "falloc" (func $falloc (param i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "push") (result i32)
+                i32.const 0
+                i32.const 16
+                call $falloc
+                i32.store
+                i32.const 0
+                i32.load))"#,
+    )
+    .unwrap();
+    let program = wat::parse_str(
+        r#"(module
+            (import "env" "print" (func $print (param i32)))
+            (import "shadow" "push" (func $push (result i32)))
+            (import "alloc" "falloc" (func $falloc (param i32) (result i32)))
+            (func (export "main") (result i32)
+                i32.const 20
+                call $print
+                call $push
+                drop
+                i32.const 8
+                call $falloc))"#,
+    )
+    .unwrap();
+
+    let runtime: [(&str, &[u8]); 3] = [("alloc", &alloc), ("dalloc", &dalloc), ("shadow", &shadow)];
+    let bundled = star::bundle(&program, &runtime).unwrap();
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &bundled).unwrap();
+    let imports: Vec<_> = module.imports().map(|i| (i.module(), i.name())).collect();
+    assert_eq!(imports, [("env", "print")]);
+
+    let mut store = Store::new(&engine, ());
+    let printed = Arc::new(Mutex::new(vec![]));
+    let output = printed.clone();
+    let print = Func::wrap(&mut store, move |mut caller: Caller<'_, ()>, ptr: i32| {
+        let memory = caller
+            .get_export(star::host::DALLOC_MEMORY_EXPORT)
+            .and_then(Extern::into_memory)
+            .unwrap();
+        let ptr = ptr as usize;
+        let text = String::from_utf8_lossy(&memory.data(&caller)[ptr..ptr + 5]).to_string();
+        output.lock().unwrap().push(text);
+    });
+    let instance = Instance::new(&mut store, &module, &[print.into()]).unwrap();
+    let main = instance.get_typed_func::<(), i32>(&mut store, "main").unwrap();
+
+    // The shadow stack's allocation and the program's share alloc's state.
+    assert_eq!(main.call(&mut store, ()).unwrap(), 24);
+    assert_eq!(*printed.lock().unwrap(), ["hello"]);
+
+    let error = star::bundle(&program, &[("alloc", &dalloc)]).unwrap_err();
+    assert!(error.to_string().contains("alloc has no export falloc"));
+}
+
+#[test]
+fn limits_locals_and_frames() {
+    let source = "fn main(): integer {\n    let a: string = \"a\";\n    let b: string = a + \"b\";\n    print b;\n    return 0;\n}\n";
+    let ir = star::lower(&star::check(&star::parse(source).unwrap()).unwrap()).unwrap();
+    let usage = &star::frames(&ir).functions[0];
+    assert_eq!(usage.name, "main");
+    assert_eq!(usage.locals, 5);
+
+    let build = |options: star::CodegenOptions| star::codegen_with(&ir, options);
+    assert!(build(Default::default()).is_ok());
+    let error = build(star::CodegenOptions {
+        max_locals: Some(4),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Function 'main' needs 5 locals, more than the limit of 4"));
+    let error = build(star::CodegenOptions {
+        max_frame_slots: Some(usage.frame_slots - 1),
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(error.to_string().contains("needs a shadow stack frame of"));
+}